@@ -12,7 +12,7 @@ pub mod state;
 pub use manifest::Manifest;
 pub use object::{ObjectState, ObjectStatus};
 pub use operator::Operator;
-pub use runtime::OperatorRuntime;
+pub use runtime::{OperatorRuntime, SyncLoopStatus};
 pub use state::{SharedState, State, Transition, TransitionTo};
 
 #[cfg(feature = "derive")]
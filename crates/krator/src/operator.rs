@@ -49,4 +49,13 @@ pub trait Operator: 'static + Sync + Send {
     ) -> anyhow::Result<()> {
         Ok(())
     }
+
+    /// Called once the object has been deleted and its state machine has finished, before it is
+    /// deregistered. This is called even if the registration hook failed.
+    async fn deregistration_hook(
+        &self,
+        mut _manifest: Manifest<Self::Manifest>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
@@ -1,11 +1,12 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use futures::{StreamExt, TryStreamExt};
 use log::{debug, error, info, warn};
-use tokio::sync::mpsc::Sender;
-use tokio::sync::Notify;
+use tokio::sync::mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{Notify, Semaphore};
 
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use k8s_openapi::Metadata;
@@ -22,16 +23,68 @@ use crate::object::ObjectState;
 use crate::operator::Operator;
 use crate::state::{run_to_completion, SharedState};
 
+/// How many objects can be registered or deregistered at once, unless the runtime is given
+/// another limit.
+const DEFAULT_REGISTRATION_WORKERS: usize = 10;
+
 /// Accepts a type implementing the `Operator` trait and watches
 /// for resources of the associated `Manifest` type, running the
 /// associated state machine for each. Optionally filter by
 /// `kube::api::ListParams`.
+///
+/// Each object's events are queued for a task of its own, which handles them in order, so an
+/// object whose state machine is slow to make progress doesn't hold up any other. Only the
+/// operator's registration and deregistration hooks run on a bounded pool of workers, so that a
+/// burst of new or deleted objects doesn't set them all up or tear them all down at once. State
+/// machines aren't limited by the pool, as an object can stay in a state for as long as it exists.
 pub struct OperatorRuntime<O: Operator> {
     client: Client,
     handlers: HashMap<ObjectKey, Sender<Event<O::Manifest>>>,
     operator: Arc<O>,
     list_params: ListParams,
     signal: Option<Arc<AtomicBool>>,
+    status: SyncLoopStatus,
+    local: Option<UnboundedReceiver<Event<O::Manifest>>>,
+    local_keys: HashSet<ObjectKey>,
+    workers: Arc<Semaphore>,
+}
+
+/// The progress of an [`OperatorRuntime`]'s event loop, which can be checked from elsewhere to
+/// tell whether the loop is stuck.
+#[derive(Clone, Debug, Default)]
+pub struct SyncLoopStatus {
+    inner: Arc<Mutex<SyncLoopState>>,
+}
+
+#[derive(Debug, Default)]
+struct SyncLoopState {
+    last_event: Option<Instant>,
+    failing_since: Option<Instant>,
+}
+
+impl SyncLoopStatus {
+    /// When the loop last finished handling an event, or `None` if it hasn't handled any yet.
+    pub fn last_event(&self) -> Option<Instant> {
+        self.inner.lock().unwrap().last_event
+    }
+
+    /// When watching for object events started failing, or `None` if the watch is healthy.
+    pub fn failing_since(&self) -> Option<Instant> {
+        self.inner.lock().unwrap().failing_since
+    }
+
+    fn event_handled(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.last_event = Some(Instant::now());
+        state.failing_since = None;
+    }
+
+    fn watch_failed(&self) {
+        let mut state = self.inner.lock().unwrap();
+        if state.failing_since.is_none() {
+            state.failing_since = Some(Instant::now());
+        }
+    }
 }
 
 impl<O: Operator> OperatorRuntime<O> {
@@ -45,7 +98,62 @@ impl<O: Operator> OperatorRuntime<O> {
             operator: Arc::new(operator),
             list_params,
             signal: None,
+            status: SyncLoopStatus::default(),
+            local: None,
+            local_keys: HashSet::new(),
+            workers: Arc::new(Semaphore::new(DEFAULT_REGISTRATION_WORKERS)),
+        }
+    }
+
+    /// Limit how many objects can be registered or deregistered at once, which is 10 by default.
+    /// A limit of 0 is treated as 1.
+    pub fn with_registration_workers(mut self, workers: usize) -> Self {
+        self.workers = Arc::new(Semaphore::new(workers.max(1)));
+        self
+    }
+
+    /// Get a sender for objects that don't come from the API server, such as ones read from
+    /// local files. These are run like any other object, but events from the API server for
+    /// objects with the same name and namespace are ignored, so they only stop once a `Deleted`
+    /// event is sent for them. Events sent before [`OperatorRuntime::start`] is called are
+    /// handled before any from the API server.
+    pub fn local_events(&mut self) -> UnboundedSender<Event<O::Manifest>> {
+        let (sender, receiver) = unbounded_channel();
+        self.local = Some(receiver);
+        sender
+    }
+
+    fn is_local(&self, event: &Event<O::Manifest>) -> bool {
+        match event {
+            Event::Applied(object) | Event::Deleted(object) => {
+                self.local_keys.contains(&ObjectKey::from(object))
+            }
+            Event::Restarted(_) => false,
+        }
+    }
+
+    /// Dispatch an event for an object that doesn't come from the API server.
+    async fn dispatch_local(&mut self, event: Event<O::Manifest>) {
+        match &event {
+            Event::Applied(object) => {
+                self.local_keys.insert(object.into());
+            }
+            Event::Deleted(object) => {
+                self.local_keys.remove(&ObjectKey::from(object));
+            }
+            Event::Restarted(_) => {
+                warn!("Got a restarted event for local objects, ignoring it");
+                return;
+            }
         }
+        if let Err(e) = self.dispatch(event).await {
+            warn!("Error dispatching local object event: {}", e);
+        }
+    }
+
+    /// Get a handle on the progress of the event loop run by [`OperatorRuntime::start`].
+    pub fn sync_loop_status(&self) -> SyncLoopStatus {
+        self.status.clone()
     }
 
     /// Dispatch event to the matching resource's task.
@@ -193,6 +301,7 @@ impl<O: Operator> OperatorRuntime<O> {
             object_state,
             deleted,
             Arc::clone(&self.operator),
+            Arc::clone(&self.workers),
         ));
 
         Ok(sender)
@@ -201,10 +310,20 @@ impl<O: Operator> OperatorRuntime<O> {
     /// Resyncs the queue given the list of objects. Objects that exist in
     /// the queue but no longer exist in the list will be deleted
     async fn resync(&mut self, objects: Vec<O::Manifest>) -> anyhow::Result<()> {
+        // Local objects aren't in the list, and are left alone
+        let objects: Vec<O::Manifest> = objects
+            .into_iter()
+            .filter(|obj| !self.local_keys.contains(&ObjectKey::from(obj)))
+            .collect();
+        let current_objects: HashSet<ObjectKey> = objects.iter().map(|obj| obj.into()).collect();
+        let objects_in_state: HashSet<ObjectKey> = self
+            .handlers
+            .keys()
+            .filter(|key| !self.local_keys.contains(key))
+            .cloned()
+            .collect();
         // First reconcile any deleted items we might have missed (if it exists
         // in our map, but not in the list)
-        let current_objects: HashSet<ObjectKey> = objects.iter().map(|obj| obj.into()).collect();
-        let objects_in_state: HashSet<ObjectKey> = self.handlers.keys().cloned().collect();
         for key in objects_in_state.difference(&current_objects) {
             let mut manifest: O::Manifest = Default::default();
             {
@@ -226,8 +345,24 @@ impl<O: Operator> OperatorRuntime<O> {
     pub async fn start(&mut self) {
         let api = Api::<O::Manifest>::all(self.client.clone());
         let mut informer = watcher(api, self.list_params.clone()).boxed();
+        let mut local = self.local.take();
+        if let Some(receiver) = local.as_mut() {
+            while let Ok(event) = receiver.try_recv() {
+                self.dispatch_local(event).await;
+            }
+        }
         loop {
-            match informer.try_next().await {
+            let event = tokio::select! {
+                event = informer.try_next() => event,
+                Some(event) = recv_local(&mut local) => {
+                    self.dispatch_local(event).await;
+                    continue;
+                }
+            };
+            match event {
+                Ok(Some(event)) if self.is_local(&event) => {
+                    debug!("Ignoring API event for local object: {:?}", event);
+                }
                 Ok(Some(event)) => {
                     if let Some(ref signal) = self.signal {
                         if matches!(event, kube_runtime::watcher::Event::Applied(_))
@@ -251,14 +386,25 @@ impl<O: Operator> OperatorRuntime<O> {
                             Err(e) => warn!("Error dispatching object event: {}", e),
                         };
                     }
+                    self.status.event_handled();
                 }
                 Ok(None) => break,
-                Err(e) => warn!("Error streaming object events: {:?}", e),
+                Err(e) => {
+                    warn!("Error streaming object events: {:?}", e);
+                    self.status.watch_failed();
+                }
             }
         }
     }
 }
 
+async fn recv_local<T>(local: &mut Option<UnboundedReceiver<T>>) -> Option<T> {
+    match local {
+        Some(receiver) => receiver.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
 async fn run_object_task<O: Operator>(
     client: Client,
     manifest: Manifest<O::Manifest>,
@@ -266,28 +412,34 @@ async fn run_object_task<O: Operator>(
     mut object_state: O::ObjectState,
     deleted: Arc<Notify>,
     operator: Arc<O>,
+    workers: Arc<Semaphore>,
 ) {
     debug!("Running registration hook.");
     let state: O::InitialState = Default::default();
     let (namespace, name) = {
         let m = manifest.latest();
-        match operator.registration_hook(manifest.clone()).await {
-            Ok(()) => debug!("Running hook complete."),
-            Err(e) => {
-                error!("Operator registration hook failed: {:?}", e);
-                return;
-            }
-        }
         (m.namespace(), m.name())
     };
 
-    tokio::select! {
-        _ = run_to_completion(&client, state, shared.clone(), &mut object_state, manifest.clone()) => (),
-        _ = deleted.notified() => {
-            let state: O::DeletedState = Default::default();
-            debug!("Object {} in namespace {:?} terminated. Jumping to state {:?}.", name, &namespace, state);
-            run_to_completion(&client, state, shared.clone(), &mut object_state, manifest.clone()).await;
+    let registered = {
+        let _worker = workers.acquire().await;
+        operator.registration_hook(manifest.clone()).await
+    };
+    match registered {
+        Ok(()) => {
+            debug!("Running hook complete.");
+            tokio::select! {
+                _ = run_to_completion(&client, state, shared.clone(), &mut object_state, manifest.clone()) => (),
+                _ = deleted.notified() => {
+                    let state: O::DeletedState = Default::default();
+                    debug!("Object {} in namespace {:?} terminated. Jumping to state {:?}.", name, &namespace, state);
+                    run_to_completion(&client, state, shared.clone(), &mut object_state, manifest.clone()).await;
+                }
+            }
         }
+        // The object isn't run, but it is still deregistered once it is deleted, so that its
+        // deletion doesn't wait on the operator forever
+        Err(e) => error!("Operator registration hook failed: {:?}", e),
     }
 
     debug!(
@@ -295,10 +447,14 @@ async fn run_object_task<O: Operator>(
         name, namespace
     );
     deleted.notified().await;
+    let _worker = workers.acquire().await;
     {
         let mut state_writer = shared.write().await;
         object_state.async_drop(&mut state_writer).await;
     }
+    if let Err(e) = operator.deregistration_hook(manifest).await {
+        warn!("Operator deregistration hook failed: {:?}", e);
+    }
 
     let api_client: Api<O::Manifest> = match namespace {
         Some(ref namespace) => kube::Api::namespaced(client, namespace),
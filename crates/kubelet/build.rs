@@ -1,5 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=proto/pluginregistration/v1/pluginregistration.proto");
+    println!("cargo:rerun-if-changed=proto/csi/v1/csi.proto");
+    println!("cargo:rerun-if-changed=proto/deviceplugin/v1beta1/deviceplugin.proto");
 
     let builder = tonic_build::configure()
         .format(true)
@@ -12,8 +14,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // let builder = builder.build_server(false);
 
     builder.compile(
-        &["proto/pluginregistration/v1/pluginregistration.proto"],
-        &["proto/pluginregistration/v1"],
+        &[
+            "proto/pluginregistration/v1/pluginregistration.proto",
+            "proto/csi/v1/csi.proto",
+            "proto/deviceplugin/v1beta1/deviceplugin.proto",
+        ],
+        &[
+            "proto/pluginregistration/v1",
+            "proto/csi/v1",
+            "proto/deviceplugin/v1beta1",
+        ],
     )?;
     Ok(())
 }
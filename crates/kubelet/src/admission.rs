@@ -0,0 +1,631 @@
+//! Admission of new pods to the node, before they are started.
+//!
+//! Each new pod is run through a chain of admit handlers. The first handler to refuse the pod has
+//! it failed with the handler's reason and message, rather than the pod failing part way through
+//! starting. The kubelet's own handlers check that the node isn't cordoned or tainted against the
+//! pod and that its labels match the pod's node selector, that the pod fits in what the pods
+//! already admitted leave of the node's allocatable resources, that there are devices left for
+//! what it requests of device plugins, and that the pod doesn't ask for features or privileges
+//! krustlet doesn't support. Providers can add their own handlers through [`Provider::admit_handlers`].
+//!
+//! [`Provider::admit_handlers`]: crate::provider::Provider::admit_handlers
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::Container as KubeContainer;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use tokio::sync::RwLock;
+
+use crate::device_plugin::DeviceManager;
+use crate::node::taint::SharedNodeTaints;
+use crate::pod::{Pod, PodKey};
+
+/// The reason given when a handler couldn't decide whether to admit a pod.
+pub const UNEXPECTED_ADMISSION_ERROR: &str = "UnexpectedAdmissionError";
+
+/// The resources that pods' requests are checked against the node's allocatable resources for.
+const FIT_RESOURCES: &[&str] = &["cpu", "memory", "ephemeral-storage"];
+
+/// Why a pod was refused.
+#[derive(Debug, PartialEq)]
+pub struct Rejection {
+    /// A short, machine readable reason, such as `OutOfcpu`
+    pub reason: String,
+    /// A message for humans
+    pub message: String,
+}
+
+impl Rejection {
+    /// Create a rejection with the given reason and message.
+    pub fn new(reason: impl Into<String>, message: impl Into<String>) -> Self {
+        Rejection {
+            reason: reason.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Create a rejection for a handler that ran into an error while deciding whether to admit a
+    /// pod.
+    pub fn unexpected(error: anyhow::Error) -> Self {
+        Rejection::new(
+            UNEXPECTED_ADMISSION_ERROR,
+            format!(
+                "Unexpected error while attempting to admit the pod: {}",
+                error
+            ),
+        )
+    }
+}
+
+/// What an admit handler decides on.
+pub struct PodAdmitAttributes<'a> {
+    /// The pod to admit
+    pub pod: &'a Pod,
+    /// The other pods bound to the node that haven't finished
+    pub other_pods: &'a [Pod],
+}
+
+/// A check that new pods must pass before they are started on the node.
+#[async_trait::async_trait]
+pub trait AdmitHandler: Send + Sync {
+    /// Decide whether the pod may run on the node, returning why not if it may not.
+    async fn admit(&self, attributes: &PodAdmitAttributes<'_>) -> Result<(), Rejection>;
+}
+
+/// Admit handlers, which are run in order until one refuses the pod.
+pub(crate) struct AdmitChain {
+    handlers: Vec<Arc<dyn AdmitHandler>>,
+}
+
+impl AdmitChain {
+    pub(crate) fn new(handlers: Vec<Arc<dyn AdmitHandler>>) -> Self {
+        AdmitChain { handlers }
+    }
+
+    /// Run the pod through each handler, stopping at the first to refuse it.
+    pub(crate) async fn admit(&self, attributes: &PodAdmitAttributes<'_>) -> Result<(), Rejection> {
+        for handler in &self.handlers {
+            handler.admit(attributes).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Refuses pods while the node is cordoned, pods that don't tolerate its `NoExecute` taints, and
+/// pods whose node selector doesn't match its labels.
+pub(crate) struct NodeAdmitHandler(pub(crate) SharedNodeTaints);
+
+#[async_trait::async_trait]
+impl AdmitHandler for NodeAdmitHandler {
+    async fn admit(&self, attributes: &PodAdmitAttributes<'_>) -> Result<(), Rejection> {
+        // Static pods are part of the node, so aren't subject to its taints
+        if attributes.pod.is_from_file() {
+            return Ok(());
+        }
+        self.0.read().await.admit(attributes.pod)
+    }
+}
+
+/// The resources requested by the pods admitted to the node, and the node's allocatable
+/// resources that they must fit in. Amounts are in millicores for CPU, and in bytes or units
+/// otherwise.
+#[derive(Debug, Default)]
+pub(crate) struct ResourceAccounting {
+    allocatable: BTreeMap<String, u64>,
+    /// What each admitted pod requests
+    pods: HashMap<PodKey, BTreeMap<String, u64>>,
+}
+
+/// The accounting of admitted pods' resources, shared between admission, the pod operator and
+/// the node status updater.
+pub(crate) type SharedResourceAccounting = Arc<RwLock<ResourceAccounting>>;
+
+impl ResourceAccounting {
+    /// Set the allocatable resources that pods must fit in, as reported in the node's status.
+    pub(crate) fn set_allocatable(
+        &mut self,
+        allocatable: &BTreeMap<String, Quantity>,
+    ) -> anyhow::Result<()> {
+        let mut amounts = BTreeMap::new();
+        for (resource, quantity) in allocatable {
+            if resource == "pods" || FIT_RESOURCES.contains(&resource.as_str()) {
+                amounts.insert(resource.clone(), amount(resource, quantity)?);
+            }
+        }
+        self.allocatable = amounts;
+        Ok(())
+    }
+
+    /// Admit a pod if it fits in what the other admitted pods leave of the node, recording what
+    /// it requests.
+    pub(crate) fn admit(&mut self, pod: &Pod) -> anyhow::Result<Result<(), Rejection>> {
+        let key = PodKey::from(pod);
+        let requests = pod_requests(pod)?;
+        let others = self.pods.iter().filter(|(other, _)| **other != key);
+
+        let count = others.clone().count() as u64;
+        if let Some(max_pods) = self.allocatable.get("pods") {
+            if count >= *max_pods {
+                return Ok(Err(out_of("pods", 1, count, *max_pods)));
+            }
+        }
+        for (resource, requested) in &requests {
+            let capacity = match self.allocatable.get(resource) {
+                Some(capacity) if *requested > 0 => *capacity,
+                _ => continue,
+            };
+            let used: u64 = others
+                .clone()
+                .filter_map(|(_, requests)| requests.get(resource))
+                .sum();
+            if used + requested > capacity {
+                return Ok(Err(out_of(resource, *requested, used, capacity)));
+            }
+        }
+        self.pods.insert(key, requests);
+        Ok(Ok(()))
+    }
+
+    /// Record what a pod requests without checking that it fits, for pods that were admitted
+    /// before, such as ones that were already running when the kubelet restarted.
+    pub(crate) fn record(&mut self, pod: &Pod) -> anyhow::Result<()> {
+        self.pods.insert(PodKey::from(pod), pod_requests(pod)?);
+        Ok(())
+    }
+
+    /// Stop accounting for a pod, once it has been deleted or was refused.
+    pub(crate) fn release(&mut self, pod: &Pod) {
+        self.pods.remove(&PodKey::from(pod));
+    }
+}
+
+/// Refuses pods that don't fit in what the admitted pods leave of the node's allocatable
+/// resources, or that would take the node past its maximum number of pods. Pods that fit are
+/// recorded as admitted.
+pub(crate) struct ResourceFitAdmitHandler(pub(crate) SharedResourceAccounting);
+
+#[async_trait::async_trait]
+impl AdmitHandler for ResourceFitAdmitHandler {
+    async fn admit(&self, attributes: &PodAdmitAttributes<'_>) -> Result<(), Rejection> {
+        self.0
+            .write()
+            .await
+            .admit(attributes.pod)
+            .map_err(Rejection::unexpected)?
+    }
+}
+
+/// Allocates devices to the containers of new pods that request resources managed by device
+/// plugins.
+pub(crate) struct DeviceAdmitHandler(pub(crate) Arc<DeviceManager>);
+
+#[async_trait::async_trait]
+impl AdmitHandler for DeviceAdmitHandler {
+    async fn admit(&self, attributes: &PodAdmitAttributes<'_>) -> Result<(), Rejection> {
+        self.0
+            .allocate(attributes.pod)
+            .await
+            .map_err(Rejection::unexpected)?
+    }
+}
+
+fn out_of(resource: &str, requested: u64, used: u64, capacity: u64) -> Rejection {
+    Rejection::new(
+        format!("OutOf{}", resource),
+        format!(
+            "Node didn't have enough resource: {}, requested: {}, used: {}, capacity: {}",
+            resource, requested, used, capacity
+        ),
+    )
+}
+
+/// How much of each resource a pod requests.
+fn pod_requests(pod: &Pod) -> anyhow::Result<BTreeMap<String, u64>> {
+    let mut requests = BTreeMap::new();
+    for resource in FIT_RESOURCES {
+        requests.insert((*resource).to_owned(), pod_request(pod, resource)?);
+    }
+    Ok(requests)
+}
+
+/// How much of a resource a pod requests, in millicores for CPU and in bytes or units otherwise.
+/// Init containers run one at a time before the others, so a pod needs the most of what any one
+/// of them requests and what its other containers request together, along with the overhead of
+/// its runtime class.
+fn pod_request(pod: &Pod, resource: &str) -> anyhow::Result<u64> {
+    let spec = match pod.as_kube_pod().spec.as_ref() {
+        Some(spec) => spec,
+        None => return Ok(0),
+    };
+    let mut containers = 0;
+    for container in &spec.containers {
+        containers += container_request(container, resource)?;
+    }
+    let mut init_containers = 0;
+    for container in spec.init_containers.iter().flatten() {
+        init_containers = std::cmp::max(init_containers, container_request(container, resource)?);
+    }
+    let overhead = match pod.overhead().and_then(|overhead| overhead.get(resource)) {
+        Some(quantity) => amount(resource, quantity)?,
+        None => 0,
+    };
+    Ok(std::cmp::max(containers, init_containers) + overhead)
+}
+
+/// How much of a resource a container requests. Containers with a limit but no request are taken
+/// to request their limit, as the API server does.
+fn container_request(container: &KubeContainer, resource: &str) -> anyhow::Result<u64> {
+    let resources = match container.resources.as_ref() {
+        Some(resources) => resources,
+        None => return Ok(0),
+    };
+    let quantity = resources
+        .requests
+        .as_ref()
+        .and_then(|requests| requests.get(resource))
+        .or_else(|| {
+            resources
+                .limits
+                .as_ref()
+                .and_then(|limits| limits.get(resource))
+        });
+    match quantity {
+        Some(quantity) => amount(resource, quantity),
+        None => Ok(0),
+    }
+}
+
+fn amount(resource: &str, quantity: &Quantity) -> anyhow::Result<u64> {
+    if resource == "cpu" {
+        crate::resources::cpu_millis(quantity)
+    } else {
+        crate::resources::memory_bytes(quantity)
+    }
+}
+
+/// Refuses pods that ask for features that krustlet doesn't support: sysctls, the host's PID or IPC
+/// namespace, and containers that are privileged or add capabilities. Pods whose containers must
+/// run as a non-root user but are set to run as root are refused too. Providers don't run
+/// workloads as the user a container asks for, so `runAsNonRoot` is otherwise met.
+pub(crate) struct UnsupportedFeaturesAdmitHandler;
+
+#[async_trait::async_trait]
+impl AdmitHandler for UnsupportedFeaturesAdmitHandler {
+    async fn admit(&self, attributes: &PodAdmitAttributes<'_>) -> Result<(), Rejection> {
+        let spec = match attributes.pod.as_kube_pod().spec.as_ref() {
+            Some(spec) => spec,
+            None => return Ok(()),
+        };
+        let sysctls = spec
+            .security_context
+            .as_ref()
+            .and_then(|context| context.sysctls.as_ref());
+        if let Some(sysctl) = sysctls.and_then(|sysctls| sysctls.first()) {
+            return Err(Rejection::new(
+                "SysctlForbidden",
+                format!(
+                    "Pod was rejected: sysctls are not supported, but the pod sets {}",
+                    sysctl.name
+                ),
+            ));
+        }
+        let host_namespace = if spec.host_pid.unwrap_or(false) {
+            Some("PID")
+        } else if spec.host_ipc.unwrap_or(false) {
+            Some("IPC")
+        } else {
+            None
+        };
+        if let Some(namespace) = host_namespace {
+            return Err(Rejection::new(
+                "HostNamespaceNotSupported",
+                format!(
+                    "Pod was rejected: sharing the host's {} namespace is not supported",
+                    namespace
+                ),
+            ));
+        }
+        let containers = spec
+            .init_containers
+            .iter()
+            .flatten()
+            .chain(&spec.containers);
+        for container in containers {
+            let context = match container.security_context.as_ref() {
+                Some(context) => context,
+                None => continue,
+            };
+            if context.privileged.unwrap_or(false) {
+                return Err(Rejection::new(
+                    "PrivilegedNotSupported",
+                    format!(
+                        "Pod was rejected: privileged containers are not supported, but container {} is privileged",
+                        container.name
+                    ),
+                ));
+            }
+            let added = context
+                .capabilities
+                .as_ref()
+                .and_then(|capabilities| capabilities.add.as_ref());
+            if let Some(capability) = added.and_then(|added| added.first()) {
+                return Err(Rejection::new(
+                    "CapabilitiesNotSupported",
+                    format!(
+                        "Pod was rejected: capabilities can't be added to containers, but container {} adds {}",
+                        container.name, capability
+                    ),
+                ));
+            }
+        }
+        let pod_context = spec.security_context.as_ref();
+        let runs_as_root = spec
+            .init_containers
+            .iter()
+            .flatten()
+            .chain(&spec.containers)
+            .find(|container| {
+                let context = container.security_context.as_ref();
+                let run_as_non_root = context
+                    .and_then(|context| context.run_as_non_root)
+                    .or_else(|| pod_context.and_then(|context| context.run_as_non_root))
+                    .unwrap_or(false);
+                let run_as_user = context
+                    .and_then(|context| context.run_as_user)
+                    .or_else(|| pod_context.and_then(|context| context.run_as_user));
+                run_as_non_root && run_as_user == Some(0)
+            });
+        if let Some(container) = runs_as_root {
+            return Err(Rejection::new(
+                "RunAsNonRootViolated",
+                format!(
+                    "Pod was rejected: container {} must run as a non-root user, but is set to run as root",
+                    container.name
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+
+    fn requesting(name: &str, cpu: &str, memory: &str) -> Pod {
+        PodBuilder::new()
+            .name(name)
+            .spec(serde_json::json!({
+                "containers": [{
+                    "name": "app",
+                    "image": "hello:v1",
+                    "resources": { "requests": { "cpu": cpu, "memory": memory } }
+                }]
+            }))
+            .build()
+    }
+
+    fn accounting(cpu: &str, memory: &str, pods: &str) -> ResourceAccounting {
+        let allocatable: BTreeMap<String, Quantity> = vec![
+            ("cpu".to_owned(), Quantity(cpu.to_owned())),
+            ("memory".to_owned(), Quantity(memory.to_owned())),
+            ("pods".to_owned(), Quantity(pods.to_owned())),
+        ]
+        .into_iter()
+        .collect();
+        let mut accounting = ResourceAccounting::default();
+        accounting.set_allocatable(&allocatable).unwrap();
+        accounting
+    }
+
+    async fn admit(
+        handler: &dyn AdmitHandler,
+        pod: &Pod,
+        other_pods: &[Pod],
+    ) -> Result<(), Rejection> {
+        handler.admit(&PodAdmitAttributes { pod, other_pods }).await
+    }
+
+    #[test]
+    fn pods_must_fit_in_what_is_left_of_the_node() {
+        let mut accounting = accounting("2", "1Gi", "3");
+        assert!(accounting
+            .admit(&requesting("a", "1500m", "256Mi"))
+            .unwrap()
+            .is_ok());
+        let rejection = accounting
+            .admit(&requesting("b", "600m", "512Mi"))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(rejection.reason, "OutOfcpu");
+        assert_eq!(
+            rejection.message,
+            "Node didn't have enough resource: cpu, requested: 600, used: 1500, capacity: 2000"
+        );
+        let rejection = accounting
+            .admit(&requesting("b", "100m", "1Gi"))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(rejection.reason, "OutOfmemory");
+        assert!(accounting
+            .admit(&requesting("b", "500m", "512Mi"))
+            .unwrap()
+            .is_ok());
+        // Admitting a pod again doesn't count it twice
+        assert!(accounting
+            .admit(&requesting("b", "500m", "512Mi"))
+            .unwrap()
+            .is_ok());
+
+        assert!(accounting
+            .admit(&requesting("c", "0", "0"))
+            .unwrap()
+            .is_ok());
+        let rejection = accounting
+            .admit(&requesting("d", "0", "0"))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(rejection.reason, "OutOfpods");
+
+        // Pods that are gone no longer count
+        accounting.release(&requesting("a", "0", "0"));
+        assert!(accounting
+            .admit(&requesting("d", "100m", "1Mi"))
+            .unwrap()
+            .is_ok());
+    }
+
+    #[test]
+    fn init_containers_and_limits_count_towards_requests() {
+        let pod = PodBuilder::new().spec(serde_json::json!({
+            "initContainers": [{
+                "name": "init",
+                "image": "init:v1",
+                "resources": { "limits": { "cpu": "2" } }
+            }],
+            "containers": [
+                { "name": "a", "image": "a:v1", "resources": { "requests": { "cpu": "500m" } } },
+                { "name": "b", "image": "b:v1", "resources": { "requests": { "cpu": "500m" } } }
+            ]
+        })).build();
+        assert_eq!(pod_request(&pod, "cpu").unwrap(), 2000);
+        assert_eq!(pod_request(&pod, "memory").unwrap(), 0);
+    }
+
+    #[test]
+    fn runtime_class_overhead_counts_towards_requests() {
+        let pod = PodBuilder::new()
+            .spec(serde_json::json!({
+                "runtimeClassName": "wasm",
+                "overhead": { "cpu": "100m", "memory": "20Mi" },
+                "containers": [
+                    { "name": "a", "image": "a:v1", "resources": { "requests": { "cpu": "500m" } } }
+                ]
+            }))
+            .build();
+        assert_eq!(pod_request(&pod, "cpu").unwrap(), 600);
+        assert_eq!(pod_request(&pod, "memory").unwrap(), 20 * 1024 * 1024);
+
+        let mut accounting = accounting("1", "1Gi", "110");
+        assert!(accounting.admit(&pod).unwrap().is_ok());
+        let rejection = accounting
+            .admit(&requesting("b", "500m", "0"))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(rejection.reason, "OutOfcpu");
+    }
+
+    #[tokio::test]
+    async fn unparseable_requests_are_unexpected_errors() {
+        let handler = ResourceFitAdmitHandler(Arc::new(RwLock::new(accounting("2", "1Gi", "110"))));
+        let rejection = admit(&handler, &requesting("a", "lots", "1Mi"), &[])
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.reason, UNEXPECTED_ADMISSION_ERROR);
+    }
+
+    #[tokio::test]
+    async fn unsupported_features_are_refused() {
+        let sysctls = PodBuilder::new().spec(serde_json::json!({
+            "securityContext": { "sysctls": [{ "name": "kernel.shm_rmid_forced", "value": "0" }] },
+            "containers": [{ "name": "app", "image": "hello:v1" }]
+        })).build();
+        let rejection = admit(&UnsupportedFeaturesAdmitHandler, &sysctls, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.reason, "SysctlForbidden");
+
+        let privileged = PodBuilder::new()
+            .spec(serde_json::json!({
+                "containers": [{
+                    "name": "app",
+                    "image": "hello:v1",
+                    "securityContext": { "privileged": true }
+                }]
+            }))
+            .build();
+        let rejection = admit(&UnsupportedFeaturesAdmitHandler, &privileged, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.reason, "PrivilegedNotSupported");
+
+        let host_pid = PodBuilder::new()
+            .spec(serde_json::json!({
+                "hostPID": true,
+                "containers": [{ "name": "app", "image": "hello:v1" }]
+            }))
+            .build();
+        let rejection = admit(&UnsupportedFeaturesAdmitHandler, &host_pid, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.reason, "HostNamespaceNotSupported");
+        assert_eq!(
+            rejection.message,
+            "Pod was rejected: sharing the host's PID namespace is not supported"
+        );
+
+        let capabilities = PodBuilder::new()
+            .spec(serde_json::json!({
+                "initContainers": [{
+                    "name": "setup",
+                    "image": "hello:v1",
+                    "securityContext": { "capabilities": { "add": ["NET_ADMIN"], "drop": ["ALL"] } }
+                }],
+                "containers": [{ "name": "app", "image": "hello:v1" }]
+            }))
+            .build();
+        let rejection = admit(&UnsupportedFeaturesAdmitHandler, &capabilities, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.reason, "CapabilitiesNotSupported");
+        let plain = requesting("hello", "1", "1Mi");
+        assert!(admit(&UnsupportedFeaturesAdmitHandler, &plain, &[])
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn containers_that_must_not_run_as_root_are_refused_only_if_set_to() {
+        let root = PodBuilder::new()
+            .spec(serde_json::json!({
+                "securityContext": { "runAsNonRoot": true },
+                "containers": [{
+                    "name": "app",
+                    "image": "hello:v1",
+                    "securityContext": { "runAsUser": 0 }
+                }]
+            }))
+            .build();
+        let rejection = admit(&UnsupportedFeaturesAdmitHandler, &root, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.reason, "RunAsNonRootViolated");
+
+        let non_root = PodBuilder::new()
+            .spec(serde_json::json!({
+                "securityContext": { "runAsNonRoot": true, "runAsUser": 0 },
+                "containers": [{
+                    "name": "app",
+                    "image": "hello:v1",
+                    "securityContext": { "runAsUser": 1000, "readOnlyRootFilesystem": true }
+                }]
+            }))
+            .build();
+        assert!(admit(&UnsupportedFeaturesAdmitHandler, &non_root, &[])
+            .await
+            .is_ok());
+        let unset = PodBuilder::new()
+            .spec(serde_json::json!({
+                "containers": [{
+                    "name": "app",
+                    "image": "hello:v1",
+                    "securityContext": { "runAsNonRoot": true }
+                }]
+            }))
+            .build();
+        assert!(admit(&UnsupportedFeaturesAdmitHandler, &unset, &[])
+            .await
+            .is_ok());
+    }
+}
@@ -2,6 +2,10 @@
 //! such as ImagePullBackoff and CrashLoopBackoff.
 use std::time::Duration;
 
+mod retry;
+
+pub use retry::{RetryPolicy, Retryable};
+
 /// Determines how long to back off before performing a retry.
 #[async_trait::async_trait]
 pub trait BackoffStrategy: Send {
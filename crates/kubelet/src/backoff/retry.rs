@@ -0,0 +1,258 @@
+//! Retries of operations that can fail for a moment, such as when the API server or a registry
+//! is briefly unreachable, so that they don't fail a pod outright.
+//!
+//! Only errors that say they are [`Retryable`] are retried, so that errors that will happen again,
+//! such as an image that doesn't exist or a request the API server refused, fail straight away.
+use std::error::Error as StdError;
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+use tracing::warn;
+
+/// The fraction of each delay that is randomized by default.
+const DEFAULT_JITTER: f64 = 0.2;
+
+/// Whether an error is worth retrying, as the operation that failed may well succeed if it is
+/// tried again.
+pub trait Retryable {
+    /// Whether the operation that failed with this error should be tried again.
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for kube::Error {
+    fn is_retryable(&self) -> bool {
+        retryable_in_chain(Some(self))
+    }
+}
+
+impl Retryable for reqwest::Error {
+    fn is_retryable(&self) -> bool {
+        self.is_timeout()
+            || self.is_connect()
+            || self
+                .status()
+                .map_or(false, |status| is_retryable_status(status.as_u16()))
+    }
+}
+
+impl Retryable for hyper::Error {
+    fn is_retryable(&self) -> bool {
+        self.is_connect() || self.is_closed() || self.is_incomplete_message()
+    }
+}
+
+impl Retryable for std::io::Error {
+    fn is_retryable(&self) -> bool {
+        use std::io::ErrorKind::*;
+        matches!(
+            self.kind(),
+            ConnectionRefused
+                | ConnectionReset
+                | ConnectionAborted
+                | NotConnected
+                | BrokenPipe
+                | TimedOut
+                | Interrupted
+                | UnexpectedEof
+        )
+    }
+}
+
+impl Retryable for anyhow::Error {
+    /// Errors are retryable if the first error in their chain that is known to be retryable or
+    /// not is retryable.
+    fn is_retryable(&self) -> bool {
+        retryable_in_chain(Some(self.as_ref()))
+    }
+}
+
+/// Whether responses with the given status code are worth retrying: those that time out, are
+/// throttled or are errors of the server rather than of the request.
+fn is_retryable_status(code: u16) -> bool {
+    code == 408 || code == 429 || code >= 500
+}
+
+/// Whether the first error in the chain whose type is known says it is retryable. Errors of
+/// unknown types aren't retried.
+fn retryable_in_chain(mut error: Option<&(dyn StdError + 'static)>) -> bool {
+    while let Some(e) = error {
+        if let Some(e) = e.downcast_ref::<kube::Error>() {
+            if let kube::Error::Api(response) = e {
+                return is_retryable_status(response.code);
+            }
+        } else if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            return e.is_retryable();
+        } else if let Some(e) = e.downcast_ref::<hyper::Error>() {
+            if e.is_retryable() {
+                return true;
+            }
+        } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return e.is_retryable();
+        }
+        error = e.source();
+    }
+    false
+}
+
+/// How an operation is retried: how many times it is attempted at most, and how long is waited
+/// between attempts. The wait doubles after each attempt, up to a cap, and is shortened by a
+/// random amount so that the retries of many pods don't all happen at once.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+}
+
+impl RetryPolicy {
+    /// A policy that attempts an operation up to `max_attempts` times, waiting `base_delay` after
+    /// the first attempt and at most `max_delay` after any other.
+    pub fn new(max_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            max_delay,
+            jitter: DEFAULT_JITTER,
+        }
+    }
+
+    /// The policy for requests to the API server, such as status patches, which are retried a
+    /// few times in quick succession.
+    pub fn api_request() -> Self {
+        RetryPolicy::new(4, Duration::from_millis(200), Duration::from_secs(2))
+    }
+
+    /// The policy for pulling images, which pods otherwise back off from for much longer.
+    pub fn image_pull() -> Self {
+        RetryPolicy::new(3, Duration::from_secs(1), Duration::from_secs(10))
+    }
+
+    /// Randomize the given fraction of each delay, from 0 for none of it to 1 for all of it.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.max(0.0).min(1.0);
+        self
+    }
+
+    /// How long to wait after the given attempt, counting from 1, failed.
+    fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .checked_mul(1 << exponent)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        delay.mul_f64(1.0 - self.jitter * rand::thread_rng().gen::<f64>())
+    }
+
+    /// Run the operation, trying it again for as long as it fails with a retryable error and has
+    /// attempts left. The error of the last attempt is returned if it never succeeds. `what` says
+    /// what the operation does, for the warnings logged when it is retried.
+    pub async fn retry<T, E, F, Fut>(&self, what: &str, mut operation: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Retryable + std::fmt::Debug,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(e) if attempt < self.max_attempts && e.is_retryable() => {
+                    let delay = self.delay(attempt);
+                    warn!(
+                        "Unable to {} (attempt {} of {}), retrying in {:?}: {:?}",
+                        what, attempt, self.max_attempts, delay, e
+                    );
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(kube::error::ErrorResponse {
+            status: "Failure".to_owned(),
+            message: "".to_owned(),
+            reason: "".to_owned(),
+            code,
+        })
+    }
+
+    #[test]
+    fn delays_double_up_to_their_cap() {
+        let policy = RetryPolicy::new(10, Duration::from_millis(100), Duration::from_secs(1))
+            .with_jitter(0.0);
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+
+        let policy = policy.with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn errors_say_whether_they_are_retryable() {
+        assert!(api_error(503).is_retryable());
+        assert!(api_error(429).is_retryable());
+        assert!(!api_error(404).is_retryable());
+        assert!(!api_error(422).is_retryable());
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(anyhow::Error::from(refused)
+            .context("pulling")
+            .is_retryable());
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert!(!anyhow::Error::from(missing).is_retryable());
+        assert!(!anyhow::anyhow!("image not found").is_retryable());
+    }
+
+    #[tokio::test]
+    async fn only_retryable_errors_are_retried() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(1));
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), kube::Error> = policy
+            .retry("patch", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(api_error(500))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicU32::new(0);
+        let result = policy
+            .retry("patch", || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(api_error(500))
+                } else {
+                    Ok(())
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), kube::Error> = policy
+            .retry("patch", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(api_error(404))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{convert::TryFrom, env, path::Path, str};
+use std::{convert::TryFrom, env, path::Path, str, time::Duration};
 
 use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::certificates::v1beta1::CertificateSigningRequest;
@@ -6,18 +6,23 @@ use kube::api::{Api, ListParams, PostParams};
 use kube::config::Kubeconfig;
 use kube::Config;
 use kube_runtime::watcher::{watcher, Event};
-use log::{debug, info};
 use rcgen::{
     Certificate, CertificateParams, DistinguishedName, DnType, KeyPair, SanType,
     PKCS_ECDSA_P256_SHA256,
 };
 use tokio::fs::{read, write};
+use tokio::sync::watch;
+use tracing::{debug, error, info};
 
 use crate::config::Config as KubeletConfig;
 use crate::kubeconfig::exists as kubeconfig_exists;
 use crate::kubeconfig::KUBECONFIG;
 
 const APPROVED_TYPE: &str = "Approved";
+/// How far through its lifetime the serving certificate is renewed.
+const ROTATION_THRESHOLD: f64 = 0.8;
+/// How long to wait before trying again when renewing the serving certificate fails.
+const ROTATION_RETRY_INTERVAL: Duration = Duration::from_secs(60);
 
 /// bootstrap the cluster with TLS certificates
 pub async fn bootstrap<K: AsRef<Path>>(
@@ -165,10 +170,79 @@ async fn bootstrap_tls(
         return Ok(());
     }
 
-    let cert_bundle = gen_tls_cert(config)?;
-
     let csr_name = format!("{}-tls", config.hostname);
     let client = kube::Client::try_from(kubeconfig)?;
+    request_tls_cert(config, client, &csr_name, notify).await
+}
+
+/// Renew the serving certificate through the CSR API whenever it nears expiry, broadcasting on
+/// `reload` once new certificate files have been written. This runs until nothing is listening
+/// for reloads.
+pub(crate) async fn rotate_tls_cert(
+    config: KubeletConfig,
+    client: kube::Client,
+    reload: watch::Sender<()>,
+) {
+    loop {
+        let deadline = match rotation_deadline(&config.server_config.cert_file).await {
+            Ok(deadline) => deadline,
+            Err(e) => {
+                error!(
+                    "Unable to read serving certificate, renewing it now: {:?}",
+                    e
+                );
+                chrono::Utc::now()
+            }
+        };
+        debug!("Serving certificate will be renewed at {}", deadline);
+        let wait = (deadline - chrono::Utc::now()).to_std().unwrap_or_default();
+        tokio::time::delay_for(wait).await;
+
+        let csr_name = format!("{}-tls-{}", config.hostname, chrono::Utc::now().timestamp());
+        match request_tls_cert(&config, client.clone(), &csr_name, |m| info!("{}", m)).await {
+            Ok(()) => {
+                if reload.broadcast(()).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("Unable to renew serving certificate: {:?}", e);
+                tokio::time::delay_for(ROTATION_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// The time at which the certificate in the given file should be renewed.
+async fn rotation_deadline(cert_file: &Path) -> anyhow::Result<chrono::DateTime<chrono::Utc>> {
+    let pem = read(cert_file).await?;
+    let der = rustls::internal::pemfile::certs(&mut pem.as_slice())
+        .map_err(|_| anyhow::anyhow!("invalid certificate file"))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no certificate in certificate file"))?;
+    let (_, cert) = x509_parser::parse_x509_der(&der.0)
+        .map_err(|e| anyhow::anyhow!("invalid certificate: {:?}", e))?;
+    let validity = &cert.tbs_certificate.validity;
+    let not_before = validity.not_before.timestamp();
+    let lifetime = validity.not_after.timestamp() - not_before;
+    let renew_at = not_before + (lifetime as f64 * ROTATION_THRESHOLD) as i64;
+    Ok(chrono::DateTime::from_utc(
+        chrono::NaiveDateTime::from_timestamp(renew_at, 0),
+        chrono::Utc,
+    ))
+}
+
+/// Request a new serving certificate with a CSR of the given name, wait for it to be approved,
+/// and write it and its private key to the configured files.
+async fn request_tls_cert(
+    config: &KubeletConfig,
+    client: kube::Client,
+    csr_name: &str,
+    notify: impl Fn(String),
+) -> anyhow::Result<()> {
+    let cert_bundle = gen_tls_cert(config)?;
+
     let csrs: Api<CertificateSigningRequest> = Api::all(client);
     let csr_json = serde_json::json!({
         "apiVersion": "certificates.k8s.io/v1beta1",
@@ -192,7 +266,7 @@ async fn bootstrap_tls(
 
     csrs.create(&PostParams::default(), &post_data).await?;
 
-    notify(awaiting_user_csr_approval("TLS", &csr_name));
+    notify(awaiting_user_csr_approval("TLS", csr_name));
 
     // Wait for CSR signing
     let inf = watcher(
@@ -0,0 +1,229 @@
+//! Checkpoints of the pods the kubelet runs, kept in its data directory so that a restarted
+//! kubelet knows which pods it was running.
+//!
+//! A pod's manifest is checkpointed once it has been admitted and set up, and the checkpoint is
+//! removed along with what was set up for the pod once it is deleted. Pods that are deleted while
+//! the kubelet isn't running would otherwise keep their network namespace, DNS configuration and
+//! provider resources for good, so when the kubelet starts, it tears down the pods whose
+//! checkpoints outlived them. Pods that are still bound to the node are run again by the
+//! provider, keeping the network they were given.
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::dns::Dns;
+use crate::network::Network;
+use crate::pod::Pod;
+use crate::provider::{PodRuntimeStatus, Provider};
+
+/// The directory under the kubelet's data directory that checkpoints are kept in.
+const CHECKPOINT_DIR: &str = "checkpoints";
+
+/// What is known about a pod the kubelet ran.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Checkpoint {
+    /// The pod's manifest when it was set up
+    pod: KubePod,
+    /// When the pod was set up
+    created_at: DateTime<Utc>,
+}
+
+/// The checkpoints of the pods the kubelet runs.
+pub(crate) struct Checkpoints {
+    dir: PathBuf,
+}
+
+impl Checkpoints {
+    /// The checkpoints of a kubelet with the given data directory.
+    pub(crate) fn new(data_dir: &Path) -> Self {
+        Checkpoints {
+            dir: data_dir.join(CHECKPOINT_DIR),
+        }
+    }
+
+    /// Checkpoint the pod, replacing any earlier checkpoint of it.
+    pub(crate) async fn save(&self, pod: &Pod) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let checkpoint = Checkpoint {
+            pod: pod.as_kube_pod().clone(),
+            created_at: Utc::now(),
+        };
+        // The checkpoint is written in full before it replaces the old one, so that a kubelet
+        // stopped part way through doesn't leave a broken one behind
+        let path = self.path(pod);
+        let partial = path.with_extension("json.tmp");
+        tokio::fs::write(&partial, serde_json::to_vec(&checkpoint)?).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    /// Remove the pod's checkpoint, if it has one.
+    pub(crate) async fn remove(&self, pod: &Pod) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(self.path(pod)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The pods that have been checkpointed. Checkpoints that can't be read are skipped.
+    async fn load(&self) -> anyhow::Result<Vec<Pod>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut pods = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "json")
+            {
+                continue;
+            }
+            let checkpoint = tokio::fs::read(&path).await.map_err(anyhow::Error::from);
+            match checkpoint.and_then(|c| Ok(serde_json::from_slice::<Checkpoint>(&c)?)) {
+                Ok(checkpoint) => pods.push(Pod::from(checkpoint.pod)),
+                Err(e) => warn!("Unable to read checkpoint {}: {:?}", path.display(), e),
+            }
+        }
+        Ok(pods)
+    }
+
+    fn path(&self, pod: &Pod) -> PathBuf {
+        self.dir
+            .join(format!("{}_{}.json", pod.namespace(), pod.name()))
+    }
+}
+
+/// Tear down the checkpointed pods that were deleted while the kubelet wasn't running, or
+/// replaced by a pod of the same name. Pods whose checkpoints can't be checked against the API
+/// server are left for the next time the kubelet starts.
+pub(crate) async fn remove_orphans<P: Provider>(
+    client: &kube::Client,
+    checkpoints: &Checkpoints,
+    provider: &P,
+    network: Option<&Network>,
+    dns: &Dns,
+) -> anyhow::Result<()> {
+    for pod in checkpoints.load().await? {
+        let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+        let current = match api.get(pod.name()).await {
+            Ok(current) => Some(current),
+            Err(kube::Error::Api(e)) if e.code == 404 => None,
+            Err(e) => {
+                warn!(
+                    "Unable to check whether pod '{}' still exists: {:?}",
+                    pod.name(),
+                    e
+                );
+                continue;
+            }
+        };
+        if current.map_or(false, |current| current.metadata.uid == pod_uid(&pod)) {
+            let status = provider
+                .pod_status(pod.namespace().to_owned(), pod.name().to_owned())
+                .await;
+            if let Ok(PodRuntimeStatus::Running) = status {
+                info!(
+                    "Pod '{}' is still running after the kubelet restarted",
+                    pod.name()
+                );
+            } else {
+                info!(
+                    "Pod '{}' was running before the kubelet restarted, and will be run again",
+                    pod.name()
+                );
+            }
+            continue;
+        }
+        info!(
+            "Tearing down pod '{}', which was deleted while the kubelet wasn't running",
+            pod.name()
+        );
+        if let Err(e) = provider.remove_orphaned_pod(&pod).await {
+            warn!(
+                "Unable to remove what the provider kept for pod '{}': {:?}",
+                pod.name(),
+                e
+            );
+        }
+        if let Err(e) = dns.teardown(&pod).await {
+            warn!("Unable to tear down DNS for pod '{}': {:?}", pod.name(), e);
+        }
+        if let Some(network) = network {
+            if let Err(e) = network.teardown(&pod).await {
+                // The checkpoint is kept, so that tearing the network down is tried again
+                warn!(
+                    "Unable to tear down network for pod '{}': {:?}",
+                    pod.name(),
+                    e
+                );
+                continue;
+            }
+        }
+        checkpoints.remove(&pod).await?;
+        debug!("Removed checkpoint of pod '{}'", pod.name());
+    }
+    Ok(())
+}
+
+fn pod_uid(pod: &Pod) -> Option<String> {
+    pod.as_kube_pod().metadata.uid.clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+
+    fn pod(name: &str) -> Pod {
+        PodBuilder::new()
+            .name(name)
+            .metadata(serde_json::json!({ "uid": format!("{}-uid", name) }))
+            .spec(serde_json::json!({ "containers": [{ "name": "app", "image": "hello:v1" }] }))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn checkpoints_are_saved_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoints = Checkpoints::new(dir.path());
+        assert!(checkpoints.load().await.unwrap().is_empty());
+
+        checkpoints.save(&pod("hello")).await.unwrap();
+        checkpoints.save(&pod("world")).await.unwrap();
+        checkpoints.save(&pod("hello")).await.unwrap();
+        let mut names: Vec<String> = checkpoints
+            .load()
+            .await
+            .unwrap()
+            .iter()
+            .map(|pod| pod.name().to_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["hello", "world"]);
+
+        checkpoints.remove(&pod("hello")).await.unwrap();
+        checkpoints.remove(&pod("hello")).await.unwrap();
+        let pods = checkpoints.load().await.unwrap();
+        assert_eq!(pods.len(), 1);
+        assert_eq!(pod_uid(&pods[0]).as_deref(), Some("world-uid"));
+    }
+
+    #[tokio::test]
+    async fn unreadable_checkpoints_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoints = Checkpoints::new(dir.path());
+        checkpoints.save(&pod("hello")).await.unwrap();
+        tokio::fs::write(dir.path().join(CHECKPOINT_DIR).join("broken.json"), b"{")
+            .await
+            .unwrap();
+        assert_eq!(checkpoints.load().await.unwrap().len(), 1);
+    }
+}
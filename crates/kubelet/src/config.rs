@@ -10,9 +10,14 @@
 //! * [`Config::new_from_file_and_flags`] - use the values specified on the command line
 //!   or in environment variables, but falling back to the specified configuration file
 //!   (requires you to turn on the "cli" feature)
+//!
+//! A kubelet created with a configuration read from a file watches the file while it runs. Changes
+//! to the log level, node status update frequency and eviction thresholds take effect at once;
+//! changes to anything else take effect when the kubelet is restarted.
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[cfg(any(feature = "cli", feature = "docs"))]
 use std::iter::FromIterator;
@@ -22,11 +27,34 @@ use structopt::StructOpt;
 
 use std::collections::HashMap;
 
-use serde::Deserialize;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::features::FeatureGates;
 
 const DEFAULT_PORT: u16 = 3000;
 const DEFAULT_MAX_PODS: u16 = 110;
+const DEFAULT_POD_REGISTRATION_WORKERS: usize = 10;
+const DEFAULT_NODE_STATUS_UPDATE_FREQUENCY_SECONDS: u64 = 10;
+const DEFAULT_NODE_LEASE_DURATION_SECONDS: u64 = 40;
+const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS: u64 = 30;
+const DEFAULT_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS_SECONDS: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 60;
+const DEFAULT_MAX_STREAMS: usize = 100;
+const DEFAULT_CONTAINER_LOG_MAX_SIZE: &str = "10Mi";
+const DEFAULT_CONTAINER_LOG_MAX_FILES: usize = 5;
 const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
+const DEFAULT_CNI_BIN_DIR: &str = "/opt/cni/bin";
+const DEFAULT_RESOLV_CONF: &str = "/etc/resolv.conf";
+
+#[cfg(any(feature = "cli", feature = "docs"))]
+lazy_static::lazy_static! {
+    /// The flags the configuration was first read with, which still take precedence over the
+    /// configuration file when it is reloaded. Binaries may accept flags of their own, so the
+    /// command line can't always be parsed again with the kubelet's flags alone.
+    static ref FLAGS: std::sync::Mutex<Option<Opts>> = std::sync::Mutex::new(None);
+}
 
 /// The configuration needed for a kubelet to run properly.
 ///
@@ -36,9 +64,11 @@ const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
 ///
 /// Use [`Config::default_config`] to generate a config with all
 /// of the default values set.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Config {
     /// The ip address the node is exposed on
+    #[serde(rename = "nodeIP")]
     pub node_ip: IpAddr,
     /// The hostname of the node
     pub hostname: String,
@@ -52,6 +82,49 @@ pub struct Config {
     pub node_labels: HashMap<String, String>,
     /// The maximum pods for this kubelet (reported to apiserver)
     pub max_pods: u16,
+    /// How many pods can be registered or deregistered at once, which admits them and sets up
+    /// their networks, or releases and tears them down. This doesn't limit how many pods' states
+    /// are run at once, as each pod's state machine runs on a task of its own.
+    pub pod_registration_workers: usize,
+    /// How often the node's capacity, conditions and other status are reported to the apiserver
+    #[serde(
+        rename = "nodeStatusUpdateFrequencySeconds",
+        serialize_with = "serialize_seconds"
+    )]
+    pub node_status_update_frequency: Duration,
+    /// Whether the node's liveness is reported by renewing a Lease in the `kube-node-lease`
+    /// namespace. If not, the node status updates are its only heartbeat.
+    #[serde(rename = "enableNodeLease")]
+    pub node_lease: bool,
+    /// How long the node's lease lasts without being renewed. The lease is renewed four times
+    /// in this period.
+    #[serde(
+        rename = "nodeLeaseDurationSeconds",
+        serialize_with = "serialize_seconds"
+    )]
+    pub node_lease_duration: Duration,
+    /// The quantities of `cpu`, `memory` and `ephemeral-storage` reserved for the system, which
+    /// are left out of what the node reports as allocatable to pods
+    pub system_reserved: HashMap<String, String>,
+    /// How little memory, disk space and process IDs may be available before the node reports
+    /// that it is under pressure for them
+    pub eviction_hard: EvictionThresholds,
+    /// How long the node takes to stop its pods when the kubelet is asked to stop, including the
+    /// time given to critical pods
+    #[serde(
+        rename = "shutdownGracePeriodSeconds",
+        serialize_with = "serialize_seconds"
+    )]
+    pub shutdown_grace_period: Duration,
+    /// How much of the shutdown grace period is kept for stopping critical pods, which are
+    /// stopped after all the others
+    #[serde(
+        rename = "shutdownGracePeriodCriticalPodsSeconds",
+        serialize_with = "serialize_seconds"
+    )]
+    pub shutdown_grace_period_critical_pods: Duration,
+    /// A directory of pod manifests that are run as static pods, without the API server
+    pub pod_manifest_path: Option<PathBuf>,
     /// The location of the tls bootstrapping file
     pub bootstrap_file: PathBuf,
     /// Whether to allow modules to be loaded directly from local
@@ -62,9 +135,62 @@ pub struct Config {
     pub insecure_registries: Option<Vec<String>>,
     /// The directory kubelet should watch for new plugin sockets
     pub plugins_dir: PathBuf,
+    /// The directory in which the kubelet serves the device plugin registration socket, and in
+    /// which device plugins serve theirs
+    pub device_plugins_dir: PathBuf,
+    /// The directory of CNI network configurations that pods are attached to the network with,
+    /// in network namespaces of their own. If this is not set, pods use the node's network.
+    pub cni_conf_dir: Option<PathBuf>,
+    /// The directories that CNI plugins are found in
+    pub cni_bin_dirs: Vec<PathBuf>,
+    /// The IPs of the cluster's DNS service, which pods with the `ClusterFirst` DNS policy
+    /// resolve names with. If this is empty, such pods use the node's DNS configuration.
+    pub cluster_dns: Vec<IpAddr>,
+    /// The cluster's domain, which pods search for names in as well as the node's search domains
+    pub cluster_domain: Option<String>,
+    /// The node's resolver configuration, which pods with the `Default` DNS policy are given
+    pub resolv_conf: PathBuf,
+    /// The host directories and files that pods may mount as hostPath volumes, along with
+    /// anything under them. If this is empty, no hostPath volumes are allowed.
+    pub allowed_host_paths: Vec<PathBuf>,
+    /// The most disk space, in bytes, that cached modules may take up. The modules that were
+    /// used least recently are removed to stay within it. If this is not set, cached modules
+    /// are never removed.
+    pub module_cache_size_limit: Option<u64>,
+    /// The PEM files of the cosign public keys that images must be signed with before their
+    /// modules are allowed to run. If this is empty, images aren't verified.
+    pub image_verification_keys: Vec<PathBuf>,
+    /// The directory that the logs of pods' containers are kept in
+    pub pod_log_dir: PathBuf,
+    /// The size, in bytes, a container log file may reach before it is rotated
+    pub container_log_max_size: u64,
+    /// The most log files kept for each run of a container
+    pub container_log_max_files: usize,
+    /// Where the logs of pods' containers are kept
+    pub log_storage: LogStorageKind,
+    /// The URL of a collector that the logs of pods' containers are forwarded to, as well as
+    /// being kept. Syslog servers are given as `udp://host:port` and HTTP collectors as
+    /// `http://` or `https://` URLs.
+    pub log_forward_url: Option<String>,
+    /// The most verbose level that is logged. `RUST_LOG` can narrow what is logged further, and
+    /// decides what is logged if this is not set.
+    #[serde(serialize_with = "serialize_log_level")]
+    pub log_level: Option<LevelFilter>,
+    /// The OTLP endpoint that traces of what the kubelet does are exported to, if any. See
+    /// [`crate::telemetry`].
+    pub otlp_endpoint: Option<String>,
+    /// Which experimental features are turned on or off. See [`crate::features`].
+    pub feature_gates: FeatureGates,
+    /// The configuration file this was read from, which the kubelet watches for changes to the
+    /// settings that can be changed while it runs. This is only set for configurations read with
+    /// [`Config::new_from_file_and_flags`] or [`Config::new_from_file_and_opts`], which the same
+    /// flags can be applied to again.
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
 }
 /// The configuration for the Kubelet server.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ServerConfig {
     /// The ip address the Kubelet server is running on
     pub addr: IpAddr,
@@ -74,6 +200,238 @@ pub struct ServerConfig {
     pub cert_file: PathBuf,
     /// Path to kubelet TLS private key.
     pub private_key_file: PathBuf,
+    /// Whether to serve plain HTTP rather than HTTPS. This is insecure and only meant for
+    /// local development.
+    pub insecure_plaintext: bool,
+    /// The oldest TLS version clients may connect with.
+    pub tls_min_version: TlsVersion,
+    /// Whether to renew the serving certificate through the Kubernetes CSR API as it nears
+    /// expiry.
+    pub rotate_certificates: bool,
+    /// Path to a bundle of CA certificates. If set, clients must present a certificate signed by
+    /// one of them, and are identified by its common name and organizations.
+    #[serde(rename = "clientCAFile")]
+    pub client_ca_file: Option<PathBuf>,
+    /// Whether bearer tokens are authenticated with the Kubernetes TokenReview API.
+    pub authentication_token_webhook: bool,
+    /// How authenticated requests are authorized.
+    pub authorization_mode: AuthorizationMode,
+    /// How long the server may take to start answering a request other than for logs, exec or
+    /// attach. Streaming responses may run for longer once started.
+    #[serde(rename = "requestTimeoutSeconds", serialize_with = "serialize_seconds")]
+    pub request_timeout: Duration,
+    /// How long the server may take to start answering a request for a container's logs.
+    #[serde(
+        rename = "logRequestTimeoutSeconds",
+        serialize_with = "serialize_seconds"
+    )]
+    pub log_request_timeout: Duration,
+    /// How long the server may take to start answering a request to run a command in a
+    /// container.
+    #[serde(
+        rename = "execRequestTimeoutSeconds",
+        serialize_with = "serialize_seconds"
+    )]
+    pub exec_request_timeout: Duration,
+    /// How long the server may take to start answering a request to attach to a container.
+    #[serde(
+        rename = "attachRequestTimeoutSeconds",
+        serialize_with = "serialize_seconds"
+    )]
+    pub attach_request_timeout: Duration,
+    /// The maximum number of log, exec, attach and port forward streams served at once.
+    pub max_streams: usize,
+    /// Whether to serve the `/debug/pprof` profiling endpoints. These need the kubelet to be
+    /// built with the `profiling` feature.
+    pub enable_profiling: bool,
+    /// The file that log, exec, attach and port forward requests are audited to, as a JSON object
+    /// per line.
+    pub audit_log_path: Option<PathBuf>,
+    /// The URL that batches of audited log, exec, attach and port forward requests are posted to
+    /// as JSON.
+    pub audit_webhook_url: Option<String>,
+}
+
+/// A version of TLS.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    #[serde(rename = "VersionTLS12")]
+    Tls12,
+    /// TLS 1.3
+    #[serde(rename = "VersionTLS13")]
+    Tls13,
+}
+
+impl std::str::FromStr for TlsVersion {
+    type Err = anyhow::Error;
+
+    /// Parses the version names used by the Kubernetes kubelet, such as `VersionTLS12`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "VersionTLS12" => Ok(TlsVersion::Tls12),
+            "VersionTLS13" => Ok(TlsVersion::Tls13),
+            "VersionTLS10" | "VersionTLS11" => Err(anyhow::anyhow!(
+                "TLS versions older than 1.2 are not supported"
+            )),
+            _ => Err(anyhow::anyhow!(
+                "unknown TLS version {:?}, expected VersionTLS12 or VersionTLS13",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the logs of pods' containers are kept.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum LogStorageKind {
+    /// In rotated files under the pod log directory.
+    File,
+    /// In memory, with at most the container log maximum size kept for each run of a container.
+    Memory,
+}
+
+impl std::str::FromStr for LogStorageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "File" => Ok(LogStorageKind::File),
+            "Memory" => Ok(LogStorageKind::Memory),
+            _ => Err(anyhow::anyhow!(
+                "unknown log storage {:?}, expected File or Memory",
+                s
+            )),
+        }
+    }
+}
+
+/// How requests to the Kubelet server are authorized.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum AuthorizationMode {
+    /// Allow all requests.
+    AlwaysAllow,
+    /// Authorize requests with the Kubernetes SubjectAccessReview API.
+    Webhook,
+}
+
+impl std::str::FromStr for AuthorizationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "AlwaysAllow" => Ok(AuthorizationMode::AlwaysAllow),
+            "Webhook" => Ok(AuthorizationMode::Webhook),
+            _ => Err(anyhow::anyhow!(
+                "unknown authorization mode {:?}, expected AlwaysAllow or Webhook",
+                s
+            )),
+        }
+    }
+}
+
+/// How little memory, disk space and process IDs may be available before the node reports that it
+/// is under pressure for them. These are the Kubernetes kubelet's `evictionHard` thresholds,
+/// though pods aren't evicted when they are crossed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EvictionThresholds {
+    /// The `memory.available` threshold.
+    pub memory_available: Threshold,
+    /// The `nodefs.available` threshold, for the filesystem the data directory is on.
+    pub nodefs_available: Threshold,
+    /// The `pid.available` threshold.
+    pub pid_available: Threshold,
+}
+
+impl Default for EvictionThresholds {
+    /// The Kubernetes kubelet's default thresholds.
+    fn default() -> Self {
+        EvictionThresholds {
+            memory_available: Threshold::Amount(100 * 1024 * 1024),
+            nodefs_available: Threshold::Percent(10),
+            pid_available: Threshold::Percent(10),
+        }
+    }
+}
+
+impl EvictionThresholds {
+    /// The thresholds given by signal name, with the defaults for signals that aren't given.
+    fn parse(thresholds: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let mut parsed = EvictionThresholds::default();
+        for (signal, value) in thresholds {
+            let threshold = value.parse()?;
+            match signal.as_str() {
+                "memory.available" => parsed.memory_available = threshold,
+                "nodefs.available" => parsed.nodefs_available = threshold,
+                "pid.available" => parsed.pid_available = threshold,
+                _ => anyhow::bail!(
+                    "unknown eviction signal {:?}, expected memory.available, nodefs.available or pid.available",
+                    signal
+                ),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl Serialize for EvictionThresholds {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        s.collect_map(vec![
+            ("memory.available", self.memory_available.to_string()),
+            ("nodefs.available", self.nodefs_available.to_string()),
+            ("pid.available", self.pid_available.to_string()),
+        ])
+    }
+}
+
+/// How little of a resource may be available before the node is under pressure for it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    /// An amount of the resource: bytes of memory or disk space, or a number of process IDs.
+    Amount(u64),
+    /// A percentage of the total amount of the resource.
+    Percent(u64),
+}
+
+impl Threshold {
+    /// Whether less than the threshold is available of a resource that has the given total.
+    pub fn crossed(self, available: u64, total: u64) -> bool {
+        match self {
+            Threshold::Amount(amount) => available < amount,
+            Threshold::Percent(percent) => available.saturating_mul(100) < total * percent,
+        }
+    }
+}
+
+impl std::str::FromStr for Threshold {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse() {
+                Ok(percent) if percent <= 100 => Ok(Threshold::Percent(percent)),
+                _ => Err(anyhow::anyhow!(
+                    "invalid percentage {:?}, expected a whole number from 0 to 100",
+                    s
+                )),
+            },
+            None => Ok(Threshold::Amount(crate::resources::memory_bytes(
+                &Quantity(s.to_owned()),
+            )?)),
+        }
+    }
+}
+
+impl std::fmt::Display for Threshold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Threshold::Amount(amount) => write!(f, "{}", amount),
+            Threshold::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
 }
 
 #[derive(Debug, Default, serde::Deserialize)]
@@ -99,6 +457,24 @@ struct ConfigBuilder {
     pub node_labels: Option<HashMap<String, String>>,
     #[serde(default, rename = "maxPods", deserialize_with = "try_deserialize_u16")]
     pub max_pods: Option<anyhow::Result<u16>>,
+    #[serde(default, rename = "podRegistrationWorkers")]
+    pub pod_registration_workers: Option<usize>,
+    #[serde(default, rename = "nodeStatusUpdateFrequencySeconds")]
+    pub node_status_update_frequency_seconds: Option<u64>,
+    #[serde(default, rename = "enableNodeLease")]
+    pub node_lease: Option<bool>,
+    #[serde(default, rename = "nodeLeaseDurationSeconds")]
+    pub node_lease_duration_seconds: Option<u64>,
+    #[serde(default, rename = "systemReserved")]
+    pub system_reserved: Option<HashMap<String, String>>,
+    #[serde(default, rename = "evictionHard")]
+    pub eviction_hard: Option<HashMap<String, String>>,
+    #[serde(default, rename = "shutdownGracePeriodSeconds")]
+    pub shutdown_grace_period_seconds: Option<u64>,
+    #[serde(default, rename = "shutdownGracePeriodCriticalPodsSeconds")]
+    pub shutdown_grace_period_critical_pods_seconds: Option<u64>,
+    #[serde(default, rename = "podManifestPath")]
+    pub pod_manifest_path: Option<PathBuf>,
     #[serde(
         default,
         rename = "listenerAddress",
@@ -121,6 +497,72 @@ struct ConfigBuilder {
     pub insecure_registries: Option<Vec<String>>,
     #[serde(default, rename = "pluginsDir")]
     pub plugins_dir: Option<PathBuf>,
+    #[serde(default, rename = "devicePluginsDir")]
+    pub device_plugins_dir: Option<PathBuf>,
+    #[serde(default, rename = "cniConfDir")]
+    pub cni_conf_dir: Option<PathBuf>,
+    #[serde(default, rename = "cniBinDir")]
+    pub cni_bin_dirs: Option<Vec<PathBuf>>,
+    #[serde(default, rename = "clusterDNS")]
+    pub cluster_dns: Option<Vec<String>>,
+    #[serde(default, rename = "clusterDomain")]
+    pub cluster_domain: Option<String>,
+    #[serde(default, rename = "resolvConf")]
+    pub resolv_conf: Option<PathBuf>,
+    #[serde(default, rename = "allowedHostPaths")]
+    pub allowed_host_paths: Option<Vec<PathBuf>>,
+    #[serde(default, rename = "moduleCacheSizeLimit")]
+    pub module_cache_size_limit: Option<String>,
+    #[serde(default, rename = "imageVerificationKeys")]
+    pub image_verification_keys: Option<Vec<PathBuf>>,
+    #[serde(default, rename = "podLogDir")]
+    pub pod_log_dir: Option<PathBuf>,
+    #[serde(default, rename = "containerLogMaxSize")]
+    pub container_log_max_size: Option<String>,
+    #[serde(default, rename = "containerLogMaxFiles")]
+    pub container_log_max_files: Option<usize>,
+    #[serde(default, rename = "logStorage")]
+    pub log_storage: Option<String>,
+    #[serde(default, rename = "logForwardUrl")]
+    pub log_forward_url: Option<String>,
+    #[serde(default, rename = "logLevel")]
+    pub log_level: Option<String>,
+    #[serde(default, rename = "otlpEndpoint")]
+    pub otlp_endpoint: Option<String>,
+    #[serde(
+        default,
+        rename = "featureGates",
+        deserialize_with = "try_deserialize_feature_gates"
+    )]
+    pub feature_gates: Option<anyhow::Result<FeatureGates>>,
+    #[serde(default, rename = "insecurePlaintext")]
+    pub insecure_plaintext: Option<bool>,
+    #[serde(default, rename = "tlsMinVersion")]
+    pub tls_min_version: Option<String>,
+    #[serde(default, rename = "rotateServerCertificates")]
+    pub rotate_certificates: Option<bool>,
+    #[serde(default, rename = "clientCAFile")]
+    pub client_ca_file: Option<PathBuf>,
+    #[serde(default, rename = "authenticationTokenWebhook")]
+    pub authentication_token_webhook: Option<bool>,
+    #[serde(default, rename = "authorizationMode")]
+    pub authorization_mode: Option<String>,
+    #[serde(default, rename = "requestTimeoutSeconds")]
+    pub request_timeout_seconds: Option<u64>,
+    #[serde(default, rename = "logRequestTimeoutSeconds")]
+    pub log_request_timeout_seconds: Option<u64>,
+    #[serde(default, rename = "execRequestTimeoutSeconds")]
+    pub exec_request_timeout_seconds: Option<u64>,
+    #[serde(default, rename = "attachRequestTimeoutSeconds")]
+    pub attach_request_timeout_seconds: Option<u64>,
+    #[serde(default, rename = "maxStreams")]
+    pub max_streams: Option<usize>,
+    #[serde(default, rename = "enableProfilingHandler")]
+    pub enable_profiling: Option<bool>,
+    #[serde(default, rename = "auditLogPath")]
+    pub audit_log_path: Option<PathBuf>,
+    #[serde(default, rename = "auditWebhookUrl")]
+    pub audit_webhook_url: Option<String>,
 }
 
 struct ConfigBuilderFallbacks {
@@ -130,6 +572,8 @@ struct ConfigBuilderFallbacks {
     cert_path: fn(data_dir: &PathBuf) -> PathBuf,
     key_path: fn(data_dir: &PathBuf) -> PathBuf,
     plugins_dir: fn(data_dir: &PathBuf) -> PathBuf,
+    device_plugins_dir: fn(data_dir: &PathBuf) -> PathBuf,
+    pod_log_dir: fn(data_dir: &PathBuf) -> PathBuf,
     node_ip: fn(hostname: &mut String, preferred_ip_family: &IpAddr) -> IpAddr,
 }
 
@@ -145,6 +589,8 @@ impl Config {
         let cert_file = default_cert_path(&data_dir);
         let private_key_file = default_key_path(&data_dir);
         let plugins_dir = default_plugins_path(&data_dir);
+        let device_plugins_dir = default_device_plugins_path(&data_dir);
+        let pod_log_dir = default_pod_log_path(&data_dir);
         Ok(Config {
             node_ip: default_node_ip(&mut hostname.clone(), preferred_ip_family)?,
             node_name: sanitize_hostname(&hostname),
@@ -152,10 +598,43 @@ impl Config {
             hostname,
             data_dir,
             max_pods: DEFAULT_MAX_PODS,
+            pod_registration_workers: DEFAULT_POD_REGISTRATION_WORKERS,
+            node_status_update_frequency: Duration::from_secs(
+                DEFAULT_NODE_STATUS_UPDATE_FREQUENCY_SECONDS,
+            ),
+            node_lease: true,
+            node_lease_duration: Duration::from_secs(DEFAULT_NODE_LEASE_DURATION_SECONDS),
+            system_reserved: HashMap::new(),
+            eviction_hard: EvictionThresholds::default(),
+            shutdown_grace_period: Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS),
+            shutdown_grace_period_critical_pods: Duration::from_secs(
+                DEFAULT_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS_SECONDS,
+            ),
+            pod_manifest_path: None,
             bootstrap_file: PathBuf::from(BOOTSTRAP_FILE),
             allow_local_modules: false,
             insecure_registries: None,
             plugins_dir,
+            device_plugins_dir,
+            cni_conf_dir: None,
+            cni_bin_dirs: vec![PathBuf::from(DEFAULT_CNI_BIN_DIR)],
+            cluster_dns: vec![],
+            cluster_domain: None,
+            resolv_conf: PathBuf::from(DEFAULT_RESOLV_CONF),
+            allowed_host_paths: vec![],
+            module_cache_size_limit: None,
+            image_verification_keys: vec![],
+            pod_log_dir,
+            container_log_max_size: crate::resources::memory_bytes(&Quantity(
+                DEFAULT_CONTAINER_LOG_MAX_SIZE.to_owned(),
+            ))?,
+            container_log_max_files: DEFAULT_CONTAINER_LOG_MAX_FILES,
+            log_storage: LogStorageKind::File,
+            log_forward_url: None,
+            log_level: None,
+            otlp_endpoint: None,
+            feature_gates: FeatureGates::default(),
+            config_file: None,
             server_config: ServerConfig {
                 addr: match preferred_ip_family {
                     IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
@@ -164,21 +643,41 @@ impl Config {
                 port: DEFAULT_PORT,
                 cert_file,
                 private_key_file,
+                insecure_plaintext: false,
+                tls_min_version: TlsVersion::Tls12,
+                rotate_certificates: false,
+                client_ca_file: None,
+                authentication_token_webhook: false,
+                authorization_mode: AuthorizationMode::AlwaysAllow,
+                request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+                log_request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+                exec_request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+                attach_request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+                max_streams: DEFAULT_MAX_STREAMS,
+                enable_profiling: false,
+                audit_log_path: None,
+                audit_webhook_url: None,
             },
         })
     }
 
     fn new_from_builder(builder: ConfigBuilder) -> Self {
+        Config::try_new_from_builder(builder).unwrap()
+    }
+
+    fn try_new_from_builder(builder: ConfigBuilder) -> anyhow::Result<Self> {
         let fallbacks = ConfigBuilderFallbacks {
             hostname: || default_hostname().expect("unable to get default hostname"),
             data_dir: || default_data_dir().expect("unable to get default data directory"),
             cert_path: default_cert_path,
             key_path: default_key_path,
             plugins_dir: default_plugins_path,
+            device_plugins_dir: default_device_plugins_path,
+            pod_log_dir: default_pod_log_path,
             node_ip: |hn, ip| default_node_ip(hn, ip).expect("unable to get default node IP"),
             bootstrap_file: || PathBuf::from(BOOTSTRAP_FILE),
         };
-        ConfigBuilder::build(builder, fallbacks).unwrap()
+        ConfigBuilder::build(builder, fallbacks)
     }
 
     /// Parses the specified config file and sets the proper defaults.
@@ -200,10 +699,10 @@ impl Config {
         Config::new_from_builder(builder)
     }
 
-    /// Parses the specified config file (or the default config file if no file is
-    /// specified and the default config file exists) and command line flags and
-    /// sets the proper defaults. The version of your application should be passed
-    /// to set the proper version for CLI flags.
+    /// Parses the specified config file (or the file given by the `--config` flag, or the
+    /// default config file if no file is specified and the default config file exists) and
+    /// command line flags and sets the proper defaults. The version of your application should
+    /// be passed to set the proper version for CLI flags.
     ///
     /// If the config file is specified but does not exist, this function panics.
     /// It is up to callers of the function to ensure any file they specify exists.
@@ -213,31 +712,70 @@ impl Config {
     #[cfg(any(feature = "cli", feature = "docs"))]
     #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
     pub fn new_from_file_and_flags(version: &str, config_file_path: Option<PathBuf>) -> Self {
-        match config_file_path {
+        let app = Opts::clap().version(version);
+        let opts = Opts::from_clap(&app.get_matches());
+        Config::new_from_file_and_opts(opts, config_file_path)
+    }
+
+    /// Like [`Config::new_from_file_and_flags`], but with flags that have already been parsed.
+    /// This lets a binary flatten [`Opts`] into flags of its own, such as those of its provider,
+    /// and hand the kubelet's share of them over here.
+    #[cfg(any(feature = "cli", feature = "docs"))]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
+    pub fn new_from_file_and_opts(opts: Opts, config_file_path: Option<PathBuf>) -> Self {
+        *FLAGS.lock().unwrap() = Some(opts.clone());
+        match config_file_path.or_else(|| opts.config_file.clone()) {
             None => {
                 let default_path = default_config_file_path();
                 if default_path.exists() {
-                    Config::new_from_file_and_flags_impl(version, default_path)
+                    Config::new_from_file_and_flags_impl(opts, default_path)
                 } else {
-                    Config::new_from_flags(version)
+                    Config::new_from_builder(ConfigBuilder::from_opts(opts))
                 }
             }
-            Some(path) => Config::new_from_file_and_flags_impl(version, path),
+            Some(path) => Config::new_from_file_and_flags_impl(opts, path),
         }
     }
 
     #[cfg(any(feature = "cli", feature = "docs"))]
     #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
-    fn new_from_file_and_flags_impl(version: &str, config_file_path: PathBuf) -> Self {
-        // TODO: reduce duplication
-        let app = Opts::clap().version(version);
-        let opts = Opts::from_clap(&app.get_matches());
+    fn new_from_file_and_flags_impl(opts: Opts, config_file_path: PathBuf) -> Self {
         let cli_builder = ConfigBuilder::from_opts(opts);
 
-        let config_file_builder = ConfigBuilder::from_config_file(config_file_path);
+        let config_file_builder = ConfigBuilder::from_config_file(config_file_path.clone());
 
         let builder = config_file_builder.unwrap().with_override(cli_builder); // if the config file is actually malformed then we should halt even if there are CLI values
-        Config::new_from_builder(builder)
+        let mut config = Config::new_from_builder(builder);
+        config.config_file = Some(config_file_path);
+        config
+    }
+
+    /// Reads the configuration again from the file it was read from, with the command line flags
+    /// still taking precedence over it. Unlike when the configuration is first read, errors are
+    /// returned rather than panicking, so that the kubelet can keep running with the
+    /// configuration it has.
+    #[cfg(any(feature = "cli", feature = "docs"))]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
+    pub fn reload(&self) -> anyhow::Result<Self> {
+        let config_file_path = self
+            .config_file
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("configuration was not read from a file"))?;
+        if !config_file_path.exists() {
+            anyhow::bail!(
+                "configuration file {} does not exist",
+                config_file_path.display()
+            );
+        }
+        let opts = match FLAGS.lock().unwrap().clone() {
+            Some(opts) => opts,
+            None => Opts::from_iter_safe(std::env::args_os())?,
+        };
+        let builder = ConfigBuilder::from_config_file(config_file_path.clone())?
+            .with_override(ConfigBuilder::from_opts(opts));
+        let mut config = Config::try_new_from_builder(builder)?;
+        config.config_file = Some(config_file_path);
+        Ok(config)
     }
 }
 
@@ -262,6 +800,16 @@ impl ConfigBuilder {
             .iter()
             .filter_map(|i| split_one_label(i))
             .collect();
+        let system_reserved: Vec<(String, String)> = opts
+            .system_reserved
+            .iter()
+            .filter_map(|i| split_one_label(i))
+            .collect();
+        let eviction_hard: Vec<(String, String)> = opts
+            .eviction_hard
+            .iter()
+            .filter_map(|i| split_one_label(i))
+            .collect();
 
         ConfigBuilder {
             node_ip: ok_result_of(opts.node_ip),
@@ -275,24 +823,89 @@ impl ConfigBuilder {
             hostname: opts.hostname,
             data_dir: opts.data_dir,
             max_pods: ok_result_of(opts.max_pods),
+            pod_registration_workers: opts.pod_registration_workers,
+            node_status_update_frequency_seconds: opts.node_status_update_frequency_seconds,
+            node_lease: opts.node_lease,
+            node_lease_duration_seconds: opts.node_lease_duration_seconds,
+            system_reserved: if system_reserved.is_empty() {
+                None
+            } else {
+                Some(HashMap::from_iter(system_reserved))
+            },
+            eviction_hard: if eviction_hard.is_empty() {
+                None
+            } else {
+                Some(HashMap::from_iter(eviction_hard))
+            },
+            shutdown_grace_period_seconds: opts.shutdown_grace_period_seconds,
+            shutdown_grace_period_critical_pods_seconds: opts
+                .shutdown_grace_period_critical_pods_seconds,
+            pod_manifest_path: opts.pod_manifest_path,
             allow_local_modules: opts.allow_local_modules,
             insecure_registries: opts.insecure_registries.map(parse_comma_separated),
             plugins_dir: opts.plugins_dir,
+            device_plugins_dir: opts.device_plugins_dir,
+            cni_conf_dir: opts.cni_conf_dir,
+            cni_bin_dirs: opts.cni_bin_dir.map(|dirs| {
+                parse_comma_separated(dirs)
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .collect()
+            }),
+            cluster_dns: opts.cluster_dns.map(parse_comma_separated),
+            cluster_domain: opts.cluster_domain,
+            resolv_conf: opts.resolv_conf,
+            allowed_host_paths: opts.allowed_host_paths.map(|paths| {
+                parse_comma_separated(paths)
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .collect()
+            }),
+            module_cache_size_limit: opts.module_cache_size_limit,
+            image_verification_keys: opts.image_verification_keys.map(|paths| {
+                parse_comma_separated(paths)
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .collect()
+            }),
+            pod_log_dir: opts.pod_log_dir,
+            container_log_max_size: opts.container_log_max_size,
+            container_log_max_files: opts.container_log_max_files,
+            log_storage: opts.log_storage,
+            log_forward_url: opts.log_forward_url,
+            log_level: opts.log_level,
+            otlp_endpoint: opts.otlp_endpoint,
+            feature_gates: if opts.feature_gates.is_empty() {
+                None
+            } else {
+                Some(opts.feature_gates.join(",").parse())
+            },
             server_addr: ok_result_of(opts.addr),
             server_port: ok_result_of(opts.port),
             server_tls_cert_file: opts.cert_file,
             server_tls_private_key_file: opts.private_key_file,
+            insecure_plaintext: opts.insecure_plaintext,
+            tls_min_version: opts.tls_min_version,
+            rotate_certificates: opts.rotate_certificates,
+            client_ca_file: opts.client_ca_file,
+            authentication_token_webhook: opts.authentication_token_webhook,
+            authorization_mode: opts.authorization_mode,
+            request_timeout_seconds: opts.request_timeout_seconds,
+            log_request_timeout_seconds: opts.log_request_timeout_seconds,
+            exec_request_timeout_seconds: opts.exec_request_timeout_seconds,
+            attach_request_timeout_seconds: opts.attach_request_timeout_seconds,
+            max_streams: opts.max_streams,
+            enable_profiling: opts.enable_profiling,
+            audit_log_path: opts.audit_log_path,
+            audit_webhook_url: opts.audit_webhook_url,
         }
     }
 
     fn from_config_file(config_file_path: PathBuf) -> anyhow::Result<ConfigBuilder> {
-        if !config_file_path.exists() {
-            return Ok(ConfigBuilder::default());
-        }
-        let config_file = std::fs::File::open(config_file_path)?;
-        ConfigBuilder::from_reader(config_file)
+        read_config_file(&config_file_path)
     }
 
+    #[cfg(test)]
     fn from_reader<R>(reader: R) -> anyhow::Result<ConfigBuilder>
     where
         R: std::io::Read,
@@ -309,6 +922,25 @@ impl ConfigBuilder {
             hostname: other.hostname.or(self.hostname),
             data_dir: other.data_dir.or(self.data_dir),
             max_pods: other.max_pods.or(self.max_pods),
+            pod_registration_workers: other
+                .pod_registration_workers
+                .or(self.pod_registration_workers),
+            node_status_update_frequency_seconds: other
+                .node_status_update_frequency_seconds
+                .or(self.node_status_update_frequency_seconds),
+            node_lease: other.node_lease.or(self.node_lease),
+            node_lease_duration_seconds: other
+                .node_lease_duration_seconds
+                .or(self.node_lease_duration_seconds),
+            system_reserved: other.system_reserved.or(self.system_reserved),
+            eviction_hard: other.eviction_hard.or(self.eviction_hard),
+            shutdown_grace_period_seconds: other
+                .shutdown_grace_period_seconds
+                .or(self.shutdown_grace_period_seconds),
+            shutdown_grace_period_critical_pods_seconds: other
+                .shutdown_grace_period_critical_pods_seconds
+                .or(self.shutdown_grace_period_critical_pods_seconds),
+            pod_manifest_path: other.pod_manifest_path.or(self.pod_manifest_path),
             server_addr: other.server_addr.or(self.server_addr),
             server_port: other.server_port.or(self.server_port),
             server_tls_cert_file: other.server_tls_cert_file.or(self.server_tls_cert_file),
@@ -316,9 +948,56 @@ impl ConfigBuilder {
             allow_local_modules: other.allow_local_modules.or(self.allow_local_modules),
             insecure_registries: other.insecure_registries.or(self.insecure_registries),
             plugins_dir: other.plugins_dir.or(self.plugins_dir),
+            device_plugins_dir: other.device_plugins_dir.or(self.device_plugins_dir),
+            cni_conf_dir: other.cni_conf_dir.or(self.cni_conf_dir),
+            cni_bin_dirs: other.cni_bin_dirs.or(self.cni_bin_dirs),
+            cluster_dns: other.cluster_dns.or(self.cluster_dns),
+            cluster_domain: other.cluster_domain.or(self.cluster_domain),
+            resolv_conf: other.resolv_conf.or(self.resolv_conf),
+            allowed_host_paths: other.allowed_host_paths.or(self.allowed_host_paths),
+            module_cache_size_limit: other
+                .module_cache_size_limit
+                .or(self.module_cache_size_limit),
+            image_verification_keys: other
+                .image_verification_keys
+                .or(self.image_verification_keys),
+            pod_log_dir: other.pod_log_dir.or(self.pod_log_dir),
+            container_log_max_size: other.container_log_max_size.or(self.container_log_max_size),
+            container_log_max_files: other
+                .container_log_max_files
+                .or(self.container_log_max_files),
+            log_storage: other.log_storage.or(self.log_storage),
+            log_forward_url: other.log_forward_url.or(self.log_forward_url),
+            log_level: other.log_level.or(self.log_level),
+            otlp_endpoint: other.otlp_endpoint.or(self.otlp_endpoint),
+            feature_gates: other.feature_gates.or(self.feature_gates),
             server_tls_private_key_file: other
                 .server_tls_private_key_file
                 .or(self.server_tls_private_key_file),
+            insecure_plaintext: other.insecure_plaintext.or(self.insecure_plaintext),
+            tls_min_version: other.tls_min_version.or(self.tls_min_version),
+            rotate_certificates: other.rotate_certificates.or(self.rotate_certificates),
+            client_ca_file: other.client_ca_file.or(self.client_ca_file),
+            authentication_token_webhook: other
+                .authentication_token_webhook
+                .or(self.authentication_token_webhook),
+            authorization_mode: other.authorization_mode.or(self.authorization_mode),
+            request_timeout_seconds: other
+                .request_timeout_seconds
+                .or(self.request_timeout_seconds),
+            log_request_timeout_seconds: other
+                .log_request_timeout_seconds
+                .or(self.log_request_timeout_seconds),
+            exec_request_timeout_seconds: other
+                .exec_request_timeout_seconds
+                .or(self.exec_request_timeout_seconds),
+            attach_request_timeout_seconds: other
+                .attach_request_timeout_seconds
+                .or(self.attach_request_timeout_seconds),
+            max_streams: other.max_streams.or(self.max_streams),
+            enable_profiling: other.enable_profiling.or(self.enable_profiling),
+            audit_log_path: other.audit_log_path.or(self.audit_log_path),
+            audit_webhook_url: other.audit_webhook_url.or(self.audit_webhook_url),
         }
     }
 
@@ -331,6 +1010,12 @@ impl ConfigBuilder {
         let plugins_dir = self
             .plugins_dir
             .unwrap_or_else(|| (fallbacks.plugins_dir)(&data_dir));
+        let device_plugins_dir = self
+            .device_plugins_dir
+            .unwrap_or_else(|| (fallbacks.device_plugins_dir)(&data_dir));
+        let pod_log_dir = self
+            .pod_log_dir
+            .unwrap_or_else(|| (fallbacks.pod_log_dir)(&data_dir));
         let server_addr = self
             .server_addr
             .unwrap_or(Ok(empty_ip_addr))
@@ -356,6 +1041,117 @@ impl ConfigBuilder {
             .max_pods
             .unwrap_or(Ok(DEFAULT_MAX_PODS))
             .map_err(|e| invalid_config_value_error(e, "maximum pods"))?;
+        let pod_registration_workers = self
+            .pod_registration_workers
+            .unwrap_or(DEFAULT_POD_REGISTRATION_WORKERS);
+        if pod_registration_workers < 1 {
+            anyhow::bail!("Invalid pod registration workers: must be at least 1");
+        }
+        let node_status_update_frequency = Duration::from_secs(
+            self.node_status_update_frequency_seconds
+                .unwrap_or(DEFAULT_NODE_STATUS_UPDATE_FREQUENCY_SECONDS),
+        );
+        if node_status_update_frequency.as_secs() < 1 {
+            anyhow::bail!("Invalid node status update frequency: must be at least 1 second");
+        }
+        let node_lease_duration = Duration::from_secs(
+            self.node_lease_duration_seconds
+                .unwrap_or(DEFAULT_NODE_LEASE_DURATION_SECONDS),
+        );
+        if node_lease_duration.as_secs() < 4 {
+            anyhow::bail!("Invalid node lease duration: must be at least 4 seconds");
+        }
+        let system_reserved = self.system_reserved.unwrap_or_default();
+        for (resource, quantity) in system_reserved.iter() {
+            if !["cpu", "memory", "ephemeral-storage"].contains(&resource.as_str()) {
+                anyhow::bail!(
+                    "Invalid system reserved resource {}: must be cpu, memory or ephemeral-storage",
+                    resource
+                );
+            }
+            crate::resources::parse(&Quantity(quantity.clone()))
+                .map_err(|e| invalid_config_value_error(e, "system reserved quantity"))?;
+        }
+        let eviction_hard = self
+            .eviction_hard
+            .map(|thresholds| EvictionThresholds::parse(&thresholds))
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "eviction threshold"))?
+            .unwrap_or_default();
+        let log_level = self
+            .log_level
+            .map(|level| {
+                level.parse::<LevelFilter>().map_err(|_| {
+                    anyhow::anyhow!(
+                        "{} is not a log level: must be off, error, warn, info, debug or trace",
+                        level
+                    )
+                })
+            })
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "log level"))?;
+        let feature_gates = self
+            .feature_gates
+            .unwrap_or_else(|| Ok(FeatureGates::default()))
+            .map_err(|e| invalid_config_value_error(e, "feature gates"))?;
+        let shutdown_grace_period = Duration::from_secs(
+            self.shutdown_grace_period_seconds
+                .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS),
+        );
+        let shutdown_grace_period_critical_pods = Duration::from_secs(
+            self.shutdown_grace_period_critical_pods_seconds
+                .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS_SECONDS),
+        );
+        if shutdown_grace_period_critical_pods > shutdown_grace_period {
+            anyhow::bail!(
+                "Invalid shutdown grace period for critical pods: must be no longer than the shutdown grace period"
+            );
+        }
+        let module_cache_size_limit = self
+            .module_cache_size_limit
+            .map(|limit| crate::resources::memory_bytes(&Quantity(limit)))
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "module cache size limit"))?;
+        let container_log_max_size = crate::resources::memory_bytes(&Quantity(
+            self.container_log_max_size
+                .unwrap_or_else(|| DEFAULT_CONTAINER_LOG_MAX_SIZE.to_owned()),
+        ))
+        .map_err(|e| invalid_config_value_error(e, "container log maximum size"))?;
+        let container_log_max_files = self
+            .container_log_max_files
+            .unwrap_or(DEFAULT_CONTAINER_LOG_MAX_FILES);
+        if container_log_max_files < 1 {
+            anyhow::bail!("Invalid container log maximum files: must be at least 1");
+        }
+        let log_storage = match self.log_storage {
+            Some(storage) => storage
+                .parse()
+                .map_err(|e| invalid_config_value_error(e, "log storage"))?,
+            None => LogStorageKind::File,
+        };
+        let tls_min_version = match self.tls_min_version {
+            Some(version) => version
+                .parse()
+                .map_err(|e| invalid_config_value_error(e, "minimum TLS version"))?,
+            None => TlsVersion::Tls12,
+        };
+        let cluster_dns = self
+            .cluster_dns
+            .unwrap_or_default()
+            .iter()
+            .map(|ip| ip.parse().map_err(anyhow::Error::from))
+            .collect::<anyhow::Result<_>>()
+            .map_err(|e| invalid_config_value_error(e, "cluster DNS IP"))?;
+        let authorization_mode = match self.authorization_mode {
+            Some(mode) => mode
+                .parse()
+                .map_err(|e| invalid_config_value_error(e, "authorization mode"))?,
+            None => AuthorizationMode::AlwaysAllow,
+        };
+        let request_timeout = Duration::from_secs(
+            self.request_timeout_seconds
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+        );
 
         Ok(Config {
             node_ip,
@@ -364,20 +1160,88 @@ impl ConfigBuilder {
             hostname,
             data_dir,
             max_pods,
+            pod_registration_workers,
+            node_status_update_frequency,
+            node_lease: self.node_lease.unwrap_or(true),
+            node_lease_duration,
+            system_reserved,
+            eviction_hard,
+            shutdown_grace_period,
+            shutdown_grace_period_critical_pods,
+            pod_manifest_path: self.pod_manifest_path,
             bootstrap_file,
             allow_local_modules: self.allow_local_modules.unwrap_or(false),
             insecure_registries: self.insecure_registries,
             plugins_dir,
+            device_plugins_dir,
+            cni_conf_dir: self.cni_conf_dir,
+            cni_bin_dirs: self
+                .cni_bin_dirs
+                .unwrap_or_else(|| vec![PathBuf::from(DEFAULT_CNI_BIN_DIR)]),
+            cluster_dns,
+            cluster_domain: self.cluster_domain,
+            resolv_conf: self
+                .resolv_conf
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_RESOLV_CONF)),
+            allowed_host_paths: self.allowed_host_paths.unwrap_or_default(),
+            module_cache_size_limit,
+            image_verification_keys: self.image_verification_keys.unwrap_or_default(),
+            pod_log_dir,
+            container_log_max_size,
+            container_log_max_files,
+            log_storage,
+            log_forward_url: self.log_forward_url,
+            log_level,
+            otlp_endpoint: self.otlp_endpoint,
+            feature_gates,
+            config_file: None,
             server_config: ServerConfig {
                 cert_file: server_tls_cert_file,
                 private_key_file: server_tls_private_key_file,
                 addr: server_addr,
                 port: server_port,
+                insecure_plaintext: self.insecure_plaintext.unwrap_or(false),
+                tls_min_version,
+                rotate_certificates: self.rotate_certificates.unwrap_or(false),
+                client_ca_file: self.client_ca_file,
+                authentication_token_webhook: self.authentication_token_webhook.unwrap_or(false),
+                authorization_mode,
+                request_timeout,
+                log_request_timeout: self
+                    .log_request_timeout_seconds
+                    .map_or(request_timeout, Duration::from_secs),
+                exec_request_timeout: self
+                    .exec_request_timeout_seconds
+                    .map_or(request_timeout, Duration::from_secs),
+                attach_request_timeout: self
+                    .attach_request_timeout_seconds
+                    .map_or(request_timeout, Duration::from_secs),
+                max_streams: self.max_streams.unwrap_or(DEFAULT_MAX_STREAMS),
+                enable_profiling: self.enable_profiling.unwrap_or(false),
+                audit_log_path: self.audit_log_path,
+                audit_webhook_url: self.audit_webhook_url,
             },
         })
     }
 }
 
+fn serialize_seconds<S>(duration: &Duration, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    s.serialize_u64(duration.as_secs())
+}
+
+fn serialize_log_level<S>(level: &Option<LevelFilter>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match level {
+        Some(level) => s.collect_str(level),
+        None => s.serialize_none(),
+    }
+}
+
 fn try_deserialize_ip_addr<'de, D>(d: D) -> Result<Option<anyhow::Result<IpAddr>>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -387,6 +1251,16 @@ where
     Ok(Some(addr))
 }
 
+fn try_deserialize_feature_gates<'de, D>(
+    d: D,
+) -> Result<Option<anyhow::Result<FeatureGates>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let gates = HashMap::<String, bool>::deserialize(d)?;
+    Ok(Some(FeatureGates::from_map(gates)))
+}
+
 fn try_deserialize_u16<'de, D>(d: D) -> Result<Option<anyhow::Result<u16>>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -429,6 +1303,76 @@ pub struct Opts {
     )]
     max_pods: Option<u16>,
 
+    #[structopt(
+        long = "pod-registration-workers",
+        env = "KRUSTLET_POD_REGISTRATION_WORKERS",
+        help = "How many pods can be registered or deregistered at once, which admits them and sets up their networks, or tears them down. It doesn't limit how many pods' states run at once. Defaults to 10"
+    )]
+    pod_registration_workers: Option<usize>,
+
+    #[structopt(
+        long = "node-status-update-frequency",
+        env = "KRUSTLET_NODE_STATUS_UPDATE_FREQUENCY",
+        help = "How often the node's capacity, conditions and other status are reported to the apiserver, in seconds. Defaults to 10"
+    )]
+    node_status_update_frequency_seconds: Option<u64>,
+
+    #[structopt(
+        long = "node-lease",
+        env = "KRUSTLET_NODE_LEASE",
+        help = "Whether the node's liveness is reported by renewing a Lease in the kube-node-lease namespace. If false, node status updates are its only heartbeat. Defaults to true"
+    )]
+    node_lease: Option<bool>,
+
+    #[structopt(
+        long = "node-lease-duration-seconds",
+        env = "KRUSTLET_NODE_LEASE_DURATION_SECONDS",
+        help = "How long the node's lease lasts without being renewed, in seconds. The lease is renewed four times in this period. Defaults to 40"
+    )]
+    node_lease_duration_seconds: Option<u64>,
+
+    #[structopt(
+        long = "system-reserved",
+        env = "KRUSTLET_SYSTEM_RESERVED",
+        use_delimiter = true,
+        help = "Resources reserved for the system, which are left out of what the node reports as allocatable.
+        Reservations must be resource=quantity pairs separated by ',', for cpu, memory or ephemeral-storage,
+        such as cpu=500m,memory=1Gi"
+    )]
+    system_reserved: Vec<String>,
+
+    #[structopt(
+        long = "eviction-hard",
+        env = "KRUSTLET_EVICTION_HARD",
+        use_delimiter = true,
+        help = "How little of a resource may be available before the node reports that it is under pressure.
+        Thresholds must be signal=threshold pairs separated by ',', for memory.available, nodefs.available
+        or pid.available, given as a quantity or a percentage, such as memory.available=500Mi,nodefs.available=15%.
+        Defaults to memory.available=100Mi,nodefs.available=10%,pid.available=10%"
+    )]
+    eviction_hard: Vec<String>,
+
+    #[structopt(
+        long = "shutdown-grace-period-seconds",
+        env = "KRUSTLET_SHUTDOWN_GRACE_PERIOD_SECONDS",
+        help = "How long, in seconds, the node takes to stop its pods when krustlet is asked to stop, including the time given to critical pods. Defaults to 30"
+    )]
+    shutdown_grace_period_seconds: Option<u64>,
+
+    #[structopt(
+        long = "shutdown-grace-period-critical-pods-seconds",
+        env = "KRUSTLET_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS_SECONDS",
+        help = "How much of the shutdown grace period, in seconds, is kept for stopping critical pods after all the others. Defaults to 10"
+    )]
+    shutdown_grace_period_critical_pods_seconds: Option<u64>,
+
+    #[structopt(
+        long = "pod-manifest-path",
+        env = "KRUSTLET_POD_MANIFEST_PATH",
+        help = "A directory of pod manifests to run as static pods, without the API server. Files ending in .yaml, .yml or .json are read, and changes to them are picked up while krustlet runs"
+    )]
+    pod_manifest_path: Option<PathBuf>,
+
     #[structopt(
         long = "cert-file",
         env = "KRUSTLET_CERT_FILE",
@@ -503,6 +1447,135 @@ pub struct Opts {
     )]
     plugins_dir: Option<PathBuf>,
 
+    #[structopt(
+        long = "device-plugins-dir",
+        env = "KRUSTLET_DEVICE_PLUGINS_DIR",
+        help = "The path to the directory in which device plugins register with the kubelet. Defaults to $KRUSTLET_DATA_DIR/device-plugins"
+    )]
+    device_plugins_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "cni-conf-dir",
+        env = "KRUSTLET_CNI_CONF_DIR",
+        help = "The directory of CNI network configurations to attach pods to the network with, each in a network namespace of its own. The first configuration in the directory is used. Defaults to none, in which case pods use the node's network"
+    )]
+    cni_conf_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "cni-bin-dir",
+        env = "KRUSTLET_CNI_BIN_DIR",
+        help = "The directories that CNI plugins are found in (comma separated). Defaults to /opt/cni/bin"
+    )]
+    cni_bin_dir: Option<String>,
+
+    #[structopt(
+        long = "cluster-dns",
+        env = "KRUSTLET_CLUSTER_DNS",
+        help = "The IPs of the cluster's DNS service, which pods with the ClusterFirst DNS policy resolve names with (comma separated). Defaults to none, in which case those pods use the node's DNS configuration"
+    )]
+    cluster_dns: Option<String>,
+
+    #[structopt(
+        long = "cluster-domain",
+        env = "KRUSTLET_CLUSTER_DOMAIN",
+        help = "The cluster's domain, such as cluster.local, which pods search for names in"
+    )]
+    cluster_domain: Option<String>,
+
+    #[structopt(
+        long = "resolv-conf",
+        env = "KRUSTLET_RESOLV_CONF",
+        help = "The node's resolver configuration, which pods with the Default DNS policy are given. Defaults to /etc/resolv.conf"
+    )]
+    resolv_conf: Option<PathBuf>,
+
+    #[structopt(
+        long = "allowed-host-paths",
+        env = "KRUSTLET_ALLOWED_HOST_PATHS",
+        help = "Host paths that pods may mount as hostPath volumes, along with anything under them (comma separated). Defaults to none"
+    )]
+    allowed_host_paths: Option<String>,
+
+    #[structopt(
+        long = "module-cache-size-limit",
+        env = "KRUSTLET_MODULE_CACHE_SIZE_LIMIT",
+        help = "The most disk space cached modules may take up, as a quantity such as 10Gi. The least recently used modules are removed to stay within it. Defaults to no limit"
+    )]
+    module_cache_size_limit: Option<String>,
+
+    #[structopt(
+        long = "image-verification-keys",
+        env = "KRUSTLET_IMAGE_VERIFICATION_KEYS",
+        help = "PEM files of cosign public keys that images must be signed with before they are run (comma separated). Defaults to none, which turns off image verification"
+    )]
+    image_verification_keys: Option<String>,
+
+    #[structopt(
+        long = "pod-log-dir",
+        env = "KRUSTLET_POD_LOG_DIR",
+        help = "The directory that the logs of pods' containers are kept in. Defaults to $DATA_DIR/pod-logs; set it to /var/log/pods for log collectors that read that directory"
+    )]
+    pod_log_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "container-log-max-size",
+        env = "KRUSTLET_CONTAINER_LOG_MAX_SIZE",
+        help = "The size a container log file may reach before it is rotated, as a quantity such as 10Mi. Defaults to 10Mi"
+    )]
+    container_log_max_size: Option<String>,
+
+    #[structopt(
+        long = "container-log-max-files",
+        env = "KRUSTLET_CONTAINER_LOG_MAX_FILES",
+        help = "The most log files kept for each run of a container, including the one being written. Defaults to 5"
+    )]
+    container_log_max_files: Option<usize>,
+
+    #[structopt(
+        long = "log-storage",
+        env = "KRUSTLET_LOG_STORAGE",
+        help = "Where the logs of pods' containers are kept, either File or Memory. Memory keeps at most the container log maximum size for each run of a container. Defaults to File"
+    )]
+    log_storage: Option<String>,
+
+    #[structopt(
+        long = "log-forward-url",
+        env = "KRUSTLET_LOG_FORWARD_URL",
+        help = "The URL of a collector to forward the logs of pods' containers to, as well as keeping them. Use udp://host:port for a syslog server, or an http:// or https:// URL to post lines to as JSON"
+    )]
+    log_forward_url: Option<String>,
+
+    #[structopt(
+        long = "log-level",
+        env = "KRUSTLET_LOG_LEVEL",
+        help = "The most verbose level that is logged: off, error, warn, info, debug or trace. RUST_LOG can narrow what is logged further, and decides what is logged if this is not set"
+    )]
+    log_level: Option<String>,
+
+    #[structopt(
+        long = "otlp-endpoint",
+        env = "KRUSTLET_OTLP_ENDPOINT",
+        help = "The OTLP endpoint to export traces of pods' lifecycles to, such as http://localhost:4317. Requires krustlet to be built with the otlp feature"
+    )]
+    otlp_endpoint: Option<String>,
+
+    #[structopt(
+        long = "config",
+        env = "KRUSTLET_CONFIG",
+        help = "The JSON or YAML configuration file to read settings that aren't given as flags from. Changes to the log level, node status update frequency and eviction thresholds in it take effect while krustlet runs. Defaults to $HOME/.krustlet/config/config.json"
+    )]
+    config_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "feature-gates",
+        env = "KRUSTLET_FEATURE_GATES",
+        use_delimiter = true,
+        help = "Experimental features to turn on or off, as Name=true or Name=false pairs separated by ',',
+        such as WasiSockets=true,StreamingExec=false. Alpha features (CsiVolumes, WasiSockets) are off by
+        default and beta features (StreamingExec) are on"
+    )]
+    feature_gates: Vec<String>,
+
     #[structopt(
         long = "x-allow-local-modules",
         env = "KRUSTLET_ALLOW_LOCAL_MODULES",
@@ -516,6 +1589,104 @@ pub struct Opts {
         help = "Registries that should be accessed over HTTP instead of HTTPS (comma separated)"
     )]
     insecure_registries: Option<String>,
+
+    #[structopt(
+        long = "x-insecure-plaintext",
+        env = "KRUSTLET_INSECURE_PLAINTEXT",
+        help = "(Experimental) Whether to serve plain HTTP instead of HTTPS. This is insecure and only meant for local development"
+    )]
+    insecure_plaintext: Option<bool>,
+
+    #[structopt(
+        long = "tls-min-version",
+        env = "KRUSTLET_TLS_MIN_VERSION",
+        help = "The oldest TLS version clients may connect with, either VersionTLS12 or VersionTLS13. Defaults to VersionTLS12"
+    )]
+    tls_min_version: Option<String>,
+
+    #[structopt(
+        long = "rotate-server-certificates",
+        env = "KRUSTLET_ROTATE_SERVER_CERTIFICATES",
+        help = "Whether to renew the serving certificate through the Kubernetes CSR API as it nears expiry. Defaults to false"
+    )]
+    rotate_certificates: Option<bool>,
+
+    #[structopt(
+        long = "client-ca-file",
+        env = "KRUSTLET_CLIENT_CA_FILE",
+        help = "The path to a bundle of CA certificates. If set, clients must present a certificate signed by one of them"
+    )]
+    client_ca_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "authentication-token-webhook",
+        env = "KRUSTLET_AUTHENTICATION_TOKEN_WEBHOOK",
+        help = "Whether to authenticate bearer tokens with the TokenReview API. Defaults to false"
+    )]
+    authentication_token_webhook: Option<bool>,
+
+    #[structopt(
+        long = "authorization-mode",
+        env = "KRUSTLET_AUTHORIZATION_MODE",
+        help = "How requests to the krustlet server are authorized, either AlwaysAllow or Webhook (which uses the SubjectAccessReview API). Defaults to AlwaysAllow"
+    )]
+    authorization_mode: Option<String>,
+
+    #[structopt(
+        long = "request-timeout-seconds",
+        env = "KRUSTLET_REQUEST_TIMEOUT_SECONDS",
+        help = "How long the krustlet server may take to start answering a request, in seconds. Defaults to 60"
+    )]
+    request_timeout_seconds: Option<u64>,
+
+    #[structopt(
+        long = "log-request-timeout-seconds",
+        env = "KRUSTLET_LOG_REQUEST_TIMEOUT_SECONDS",
+        help = "How long the krustlet server may take to start answering a request for container logs, in seconds. Defaults to the request timeout"
+    )]
+    log_request_timeout_seconds: Option<u64>,
+
+    #[structopt(
+        long = "exec-request-timeout-seconds",
+        env = "KRUSTLET_EXEC_REQUEST_TIMEOUT_SECONDS",
+        help = "How long the krustlet server may take to start answering an exec request, in seconds. Defaults to the request timeout"
+    )]
+    exec_request_timeout_seconds: Option<u64>,
+
+    #[structopt(
+        long = "attach-request-timeout-seconds",
+        env = "KRUSTLET_ATTACH_REQUEST_TIMEOUT_SECONDS",
+        help = "How long the krustlet server may take to start answering an attach request, in seconds. Defaults to the request timeout"
+    )]
+    attach_request_timeout_seconds: Option<u64>,
+
+    #[structopt(
+        long = "max-streams",
+        env = "KRUSTLET_MAX_STREAMS",
+        help = "The maximum number of log, exec, attach and port forward streams served at once. Defaults to 100"
+    )]
+    max_streams: Option<usize>,
+
+    #[structopt(
+        long = "enable-profiling-handler",
+        env = "KRUSTLET_ENABLE_PROFILING_HANDLER",
+        help = "Whether to serve CPU and heap profiles under /debug/pprof. Requires krustlet to be built with the profiling feature. Defaults to false"
+    )]
+    enable_profiling: Option<bool>,
+
+    #[structopt(
+        long = "audit-log-path",
+        env = "KRUSTLET_AUDIT_LOG_PATH",
+        help = "The file to audit log, exec, attach and port forward requests to, as a JSON object per line. If not set, requests aren't audited to a file"
+    )]
+    audit_log_path: Option<PathBuf>,
+
+    #[structopt(
+        long = "audit-webhook-url",
+        env = "KRUSTLET_AUDIT_WEBHOOK_URL",
+        help = "The http:// or https:// URL to post batches of audited log, exec, attach and port forward requests to as JSON. If not set, requests aren't audited to a webhook"
+    )]
+    audit_webhook_url: Option<String>,
 }
 
 fn default_hostname() -> anyhow::Result<String> {
@@ -578,6 +1749,14 @@ fn default_plugins_path(data_dir: &PathBuf) -> PathBuf {
     data_dir.join("plugins")
 }
 
+fn default_device_plugins_path(data_dir: &PathBuf) -> PathBuf {
+    data_dir.join("device-plugins")
+}
+
+fn default_pod_log_path(data_dir: &PathBuf) -> PathBuf {
+    data_dir.join("pod-logs")
+}
+
 #[cfg(any(feature = "cli", feature = "docs"))]
 fn default_config_file_path() -> PathBuf {
     dirs::home_dir()
@@ -585,6 +1764,31 @@ fn default_config_file_path() -> PathBuf {
         .join(".krustlet/config/config.json")
 }
 
+/// Reads settings from a configuration file, which is JSON unless its name ends in `.yaml` or
+/// `.yml`. A file that doesn't exist gives the default settings. Keys that `T` doesn't know are
+/// ignored, so providers can use this to read settings of their own from the kubelet's
+/// configuration file.
+pub fn read_config_file<T: serde::de::DeserializeOwned + Default>(
+    path: &Path,
+) -> anyhow::Result<T> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let file = std::fs::File::open(path)?;
+    if is_yaml(path) {
+        serde_yaml::from_reader(file).map_err(anyhow::Error::new)
+    } else {
+        serde_json::from_reader(file).map_err(anyhow::Error::new)
+    }
+}
+
+fn is_yaml(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml") | Some("yml")
+    )
+}
+
 fn is_same_ip_family(first: &IpAddr, second: &IpAddr) -> bool {
     match first {
         IpAddr::V4(_) => second.is_ipv4(),
@@ -617,6 +1821,7 @@ fn parse_comma_separated(source: String) -> Vec<String> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::features::Feature;
 
     fn builder_from_json_string(json: &str) -> anyhow::Result<ConfigBuilder> {
         ConfigBuilder::from_reader(json.as_bytes())
@@ -630,6 +1835,8 @@ mod test {
             cert_path: |_| PathBuf::from("/fallback/cert/path"),
             key_path: |_| PathBuf::from("/fallback/key/path"),
             plugins_dir: |_| PathBuf::from("/fallback/plugins/dir"),
+            device_plugins_dir: |_| PathBuf::from("/fallback/device-plugins/dir"),
+            pod_log_dir: |_| PathBuf::from("/fallback/pod/logs"),
             bootstrap_file: || PathBuf::from("/fallback/bootstrap_file.txt"),
         }
     }
@@ -643,6 +1850,26 @@ mod test {
             "hostname": "krusty-host",
             "dataDir": "/krusty/data/dir",
             "maxPods": 400,
+            "podRegistrationWorkers": 4,
+            "nodeStatusUpdateFrequencySeconds": 30,
+            "enableNodeLease": false,
+            "nodeLeaseDurationSeconds": 60,
+            "systemReserved": {
+                "cpu": "500m",
+                "memory": "1Gi"
+            },
+            "evictionHard": {
+                "memory.available": "200Mi",
+                "nodefs.available": "15%"
+            },
+            "shutdownGracePeriodSeconds": 60,
+            "shutdownGracePeriodCriticalPodsSeconds": 20,
+            "podManifestPath": "/etc/krustlet/manifests",
+            "cniConfDir": "/etc/cni/net.d",
+            "cniBinDir": ["/opt/cni/bin", "/usr/libexec/cni"],
+            "clusterDNS": ["10.96.0.10"],
+            "clusterDomain": "cluster.local",
+            "resolvConf": "/run/systemd/resolve/resolv.conf",
             "nodeIP": "173.183.193.2",
             "nodeLabels": {
                 "label1": "val1",
@@ -657,7 +1884,30 @@ mod test {
                 "local",
                 "dev"
             ],
-            "pluginsDir": "/some/plugins"
+            "pluginsDir": "/some/plugins",
+            "devicePluginsDir": "/some/device-plugins",
+            "allowedHostPaths": ["/var/data", "/etc/krustlet"],
+            "moduleCacheSizeLimit": "2Gi",
+            "imageVerificationKeys": ["/etc/krustlet/cosign.pub"],
+            "podLogDir": "/var/log/pods",
+            "containerLogMaxSize": "1Mi",
+            "containerLogMaxFiles": 3,
+            "logStorage": "Memory",
+            "logForwardUrl": "udp://syslog.example.com:514",
+            "logLevel": "debug",
+            "otlpEndpoint": "http://collector:4317",
+            "featureGates": { "WasiSockets": true, "StreamingExec": false },
+            "tlsMinVersion": "VersionTLS13",
+            "clientCAFile": "/the/client/ca",
+            "authenticationTokenWebhook": true,
+            "authorizationMode": "Webhook",
+            "requestTimeoutSeconds": 30,
+            "logRequestTimeoutSeconds": 10,
+            "execRequestTimeoutSeconds": 20,
+            "maxStreams": 8,
+            "enableProfilingHandler": true,
+            "auditLogPath": "/var/log/krustlet/audit.log",
+            "auditWebhookUrl": "https://audit.example.com/events"
         }"#,
         );
         let config = config_builder.unwrap().build(fallbacks()).unwrap();
@@ -680,13 +1930,125 @@ mod test {
         assert_eq!(config.data_dir.to_string_lossy(), "/krusty/data/dir");
         assert_eq!(format!("{}", config.node_ip), "173.183.193.2");
         assert_eq!(config.max_pods, 400);
+        assert_eq!(config.pod_registration_workers, 4);
+        assert_eq!(config.node_status_update_frequency, Duration::from_secs(30));
+        assert!(!config.node_lease);
+        assert_eq!(config.node_lease_duration, Duration::from_secs(60));
+        assert_eq!(config.system_reserved.len(), 2);
+        assert_eq!(
+            config.system_reserved.get("memory"),
+            Some(&("1Gi".to_owned()))
+        );
+        assert_eq!(
+            config.eviction_hard,
+            EvictionThresholds {
+                memory_available: Threshold::Amount(200 * 1024 * 1024),
+                nodefs_available: Threshold::Percent(15),
+                pid_available: Threshold::Percent(10),
+            }
+        );
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(60));
+        assert_eq!(
+            config.shutdown_grace_period_critical_pods,
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            config.pod_manifest_path,
+            Some(PathBuf::from("/etc/krustlet/manifests"))
+        );
         assert_eq!(config.allow_local_modules, true);
+        assert_eq!(config.cni_conf_dir, Some(PathBuf::from("/etc/cni/net.d")));
+        assert_eq!(
+            config.cni_bin_dirs,
+            vec![
+                PathBuf::from("/opt/cni/bin"),
+                PathBuf::from("/usr/libexec/cni")
+            ]
+        );
+        assert_eq!(
+            config.cluster_dns,
+            vec!["10.96.0.10".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(config.cluster_domain, Some("cluster.local".to_owned()));
+        assert_eq!(
+            config.resolv_conf,
+            PathBuf::from("/run/systemd/resolve/resolv.conf")
+        );
         assert_eq!(config.node_labels.len(), 2);
         assert_eq!(config.node_labels.get("label1"), Some(&("val1".to_owned())));
         assert_eq!(config.insecure_registries.clone().unwrap().len(), 2);
         assert_eq!(&config.insecure_registries.clone().unwrap()[0], "local");
         assert_eq!(&config.insecure_registries.unwrap()[1], "dev");
         assert_eq!(&config.plugins_dir.to_string_lossy(), "/some/plugins");
+        assert_eq!(
+            &config.device_plugins_dir.to_string_lossy(),
+            "/some/device-plugins"
+        );
+        assert_eq!(
+            config.allowed_host_paths,
+            vec![PathBuf::from("/var/data"), PathBuf::from("/etc/krustlet")]
+        );
+        assert_eq!(config.module_cache_size_limit, Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(
+            config.image_verification_keys,
+            vec![PathBuf::from("/etc/krustlet/cosign.pub")]
+        );
+        assert_eq!(config.pod_log_dir, PathBuf::from("/var/log/pods"));
+        assert_eq!(config.container_log_max_size, 1024 * 1024);
+        assert_eq!(config.container_log_max_files, 3);
+        assert_eq!(config.log_storage, LogStorageKind::Memory);
+        assert_eq!(
+            config.log_forward_url,
+            Some("udp://syslog.example.com:514".to_owned())
+        );
+        assert_eq!(config.log_level, Some(LevelFilter::Debug));
+        assert_eq!(
+            config.otlp_endpoint,
+            Some("http://collector:4317".to_owned())
+        );
+        assert!(config.feature_gates.enabled(Feature::WasiSockets));
+        assert!(!config.feature_gates.enabled(Feature::StreamingExec));
+        assert!(!config.feature_gates.enabled(Feature::CsiVolumes));
+        assert_eq!(config.server_config.tls_min_version, TlsVersion::Tls13);
+        assert_eq!(
+            config
+                .server_config
+                .client_ca_file
+                .unwrap()
+                .to_string_lossy(),
+            "/the/client/ca"
+        );
+        assert!(config.server_config.authentication_token_webhook);
+        assert_eq!(
+            config.server_config.authorization_mode,
+            AuthorizationMode::Webhook
+        );
+        assert_eq!(
+            config.server_config.request_timeout,
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            config.server_config.log_request_timeout,
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            config.server_config.exec_request_timeout,
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            config.server_config.attach_request_timeout,
+            Duration::from_secs(30)
+        );
+        assert_eq!(config.server_config.max_streams, 8);
+        assert!(config.server_config.enable_profiling);
+        assert_eq!(
+            config.server_config.audit_log_path,
+            Some(PathBuf::from("/var/log/krustlet/audit.log"))
+        );
+        assert_eq!(
+            config.server_config.audit_webhook_url,
+            Some("https://audit.example.com/events".to_owned())
+        );
     }
 
     #[test]
@@ -723,6 +2085,25 @@ mod test {
         );
     }
 
+    #[test]
+    fn config_serializes_with_config_file_names() {
+        let config = builder_from_json_string(
+            r#"{
+            "nodeIP": "173.183.193.2",
+            "tlsMinVersion": "VersionTLS13",
+            "requestTimeoutSeconds": 30
+        }"#,
+        )
+        .unwrap()
+        .build(fallbacks())
+        .unwrap();
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["nodeIP"], "173.183.193.2");
+        assert_eq!(json["serverConfig"]["tlsMinVersion"], "VersionTLS13");
+        assert_eq!(json["serverConfig"]["requestTimeoutSeconds"], 30);
+        assert_eq!(json["serverConfig"]["authorizationMode"], "AlwaysAllow");
+    }
+
     #[test]
     fn defaults_are_respected() {
         let config_builder = builder_from_json_string(
@@ -732,6 +2113,34 @@ mod test {
         let config = config_builder.unwrap().build(fallbacks()).unwrap();
         assert_eq!(config.server_config.port, 3000);
         assert_eq!(config.max_pods, 110);
+        assert_eq!(config.pod_registration_workers, 10);
+        assert_eq!(config.node_status_update_frequency, Duration::from_secs(10));
+        assert!(config.node_lease);
+        assert_eq!(config.node_lease_duration, Duration::from_secs(40));
+        assert!(config.system_reserved.is_empty());
+        assert_eq!(config.eviction_hard, EvictionThresholds::default());
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(30));
+        assert_eq!(
+            config.shutdown_grace_period_critical_pods,
+            Duration::from_secs(10)
+        );
+        assert_eq!(config.pod_manifest_path, None);
+        assert_eq!(config.cni_conf_dir, None);
+        assert_eq!(config.cni_bin_dirs, vec![PathBuf::from("/opt/cni/bin")]);
+        assert!(config.cluster_dns.is_empty());
+        assert_eq!(config.cluster_domain, None);
+        assert_eq!(config.resolv_conf, PathBuf::from("/etc/resolv.conf"));
+        assert_eq!(
+            config.server_config.request_timeout,
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            config.server_config.log_request_timeout,
+            Duration::from_secs(60)
+        );
+        assert_eq!(config.server_config.max_streams, 100);
+        assert_eq!(config.server_config.audit_log_path, None);
+        assert_eq!(config.server_config.audit_webhook_url, None);
         assert_eq!(format!("{}", config.server_config.addr), "0.0.0.0");
         assert_eq!(
             config.server_config.cert_file.to_string_lossy(),
@@ -747,11 +2156,26 @@ mod test {
         assert_eq!(format!("{}", config.node_ip), "4.4.4.4");
         assert_eq!(config.allow_local_modules, false);
         assert_eq!(config.insecure_registries, None);
+        assert!(config.allowed_host_paths.is_empty());
+        assert_eq!(config.module_cache_size_limit, None);
+        assert!(config.image_verification_keys.is_empty());
+        assert_eq!(config.pod_log_dir, PathBuf::from("/fallback/pod/logs"));
+        assert_eq!(config.container_log_max_size, 10 * 1024 * 1024);
+        assert_eq!(config.container_log_max_files, 5);
+        assert_eq!(config.log_storage, LogStorageKind::File);
+        assert_eq!(config.log_forward_url, None);
+        assert_eq!(config.log_level, None);
+        assert_eq!(config.otlp_endpoint, None);
+        assert_eq!(config.feature_gates, FeatureGates::default());
         assert_eq!(config.node_labels.len(), 0);
         assert_eq!(
             &config.plugins_dir.to_string_lossy(),
             "/fallback/plugins/dir"
         );
+        assert_eq!(
+            &config.device_plugins_dir.to_string_lossy(),
+            "/fallback/device-plugins/dir"
+        );
     }
 
     #[test]
@@ -959,6 +2383,77 @@ mod test {
         );
     }
 
+    #[test]
+    fn invalid_system_reservations_are_reported() {
+        let error = builder_from_json_string(r#"{ "systemReserved": { "gpu": "1" } }"#)
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(error.to_string().contains("gpu"), error.to_string());
+        let error = builder_from_json_string(r#"{ "systemReserved": { "memory": "lots" } }"#)
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("system reserved"),
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn invalid_eviction_thresholds_are_reported() {
+        for thresholds in &[
+            r#"{ "memory.free": "1Gi" }"#,
+            r#"{ "nodefs.available": "150%" }"#,
+            r#"{ "memory.available": "lots" }"#,
+        ] {
+            let json = format!(r#"{{ "evictionHard": {} }}"#, thresholds);
+            let error = builder_from_json_string(&json)
+                .unwrap()
+                .build(fallbacks())
+                .expect_err("Expected config error but was okay");
+            assert!(
+                error.to_string().contains("eviction threshold"),
+                error.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn thresholds_are_crossed_below_their_amount_or_percentage() {
+        assert!(Threshold::Amount(100).crossed(99, 1000));
+        assert!(!Threshold::Amount(100).crossed(100, 1000));
+        assert!(Threshold::Percent(10).crossed(99, 1000));
+        assert!(!Threshold::Percent(10).crossed(100, 1000));
+    }
+
+    #[test]
+    fn yaml_config_files_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            "nodeName: krusty-node\nlogLevel: warn\nevictionHard:\n  pid.available: 5%\n",
+        )
+        .unwrap();
+        let config = ConfigBuilder::from_config_file(path)
+            .unwrap()
+            .build(fallbacks())
+            .unwrap();
+        assert_eq!(config.node_name, "krusty-node");
+        assert_eq!(config.log_level, Some(LevelFilter::Warn));
+        assert_eq!(config.eviction_hard.pid_available, Threshold::Percent(5));
+    }
+
+    #[test]
+    fn unrecognized_feature_gates_are_reported() {
+        let error = builder_from_json_string(r#"{ "featureGates": { "TimeTravel": true } }"#)
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(error.to_string().contains("TimeTravel"), error.to_string());
+    }
+
     #[test]
     fn if_invalid_config_value_is_overridden_by_valid_one_it_is_not_an_error() {
         let config_builder_1 = builder_from_json_string(
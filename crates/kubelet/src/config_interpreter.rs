@@ -30,7 +30,34 @@ mod test {
             hostname: "nope".to_owned(),
             insecure_registries: None,
             plugins_dir: std::path::PathBuf::from("/nope"),
+            device_plugins_dir: std::path::PathBuf::from("/nope"),
+            cni_conf_dir: None,
+            cni_bin_dirs: vec![],
+            cluster_dns: vec![],
+            cluster_domain: None,
+            resolv_conf: std::path::PathBuf::from("/etc/resolv.conf"),
+            allowed_host_paths: vec![],
+            module_cache_size_limit: None,
+            image_verification_keys: vec![],
+            pod_log_dir: std::path::PathBuf::from("/nope"),
+            container_log_max_size: 10 * 1024 * 1024,
+            container_log_max_files: 5,
+            log_storage: crate::config::LogStorageKind::File,
+            log_forward_url: None,
+            log_level: None,
+            otlp_endpoint: None,
+            feature_gates: Default::default(),
+            config_file: None,
             max_pods: 0,
+            pod_registration_workers: 10,
+            node_status_update_frequency: std::time::Duration::from_secs(10),
+            node_lease: true,
+            node_lease_duration: std::time::Duration::from_secs(40),
+            shutdown_grace_period: std::time::Duration::from_secs(30),
+            shutdown_grace_period_critical_pods: std::time::Duration::from_secs(10),
+            pod_manifest_path: None,
+            system_reserved: std::collections::HashMap::new(),
+            eviction_hard: Default::default(),
             node_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             node_labels: std::collections::HashMap::new(),
             node_name: "nope".to_owned(),
@@ -39,6 +66,20 @@ mod test {
                 port: 0,
                 cert_file: std::path::PathBuf::from("/nope"),
                 private_key_file: std::path::PathBuf::from("/nope"),
+                insecure_plaintext: false,
+                tls_min_version: crate::config::TlsVersion::Tls12,
+                rotate_certificates: false,
+                client_ca_file: None,
+                authentication_token_webhook: false,
+                authorization_mode: crate::config::AuthorizationMode::AlwaysAllow,
+                request_timeout: std::time::Duration::from_secs(60),
+                log_request_timeout: std::time::Duration::from_secs(60),
+                exec_request_timeout: std::time::Duration::from_secs(60),
+                attach_request_timeout: std::time::Duration::from_secs(60),
+                max_streams: 100,
+                enable_profiling: false,
+                audit_log_path: None,
+                audit_webhook_url: None,
             },
         }
     }
@@ -0,0 +1,220 @@
+//! Applying changes to the kubelet's configuration file while it runs.
+//!
+//! Only the [`LiveSettings`] take effect straight away: the log level, how often the node's status
+//! is updated and the thresholds the node reports pressure by. Changes to anything else are
+//! logged, and take effect once the kubelet is restarted. A file that can't be read or is invalid
+//! is logged and otherwise ignored, so that the kubelet keeps running with the settings it has.
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::LevelFilter;
+use tokio::sync::RwLock;
+
+use crate::config::{Config, EvictionThresholds};
+
+/// The names in the configuration file of the settings that take effect while the kubelet runs.
+#[cfg(any(feature = "cli", feature = "docs", test))]
+const LIVE_SETTINGS: &[&str] = &[
+    "logLevel",
+    "nodeStatusUpdateFrequencySeconds",
+    "evictionHard",
+];
+
+/// The settings that can be changed while the kubelet runs.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LiveSettings {
+    log_level: Option<LevelFilter>,
+    node_status_update_frequency: Duration,
+    eviction_hard: EvictionThresholds,
+}
+
+/// The live settings, shared by the tasks that use them.
+pub(crate) type SharedLiveSettings = Arc<RwLock<LiveSettings>>;
+
+impl LiveSettings {
+    pub(crate) fn of(config: &Config) -> Self {
+        LiveSettings {
+            log_level: config.log_level,
+            node_status_update_frequency: config.node_status_update_frequency,
+            eviction_hard: config.eviction_hard,
+        }
+    }
+
+    /// Use these settings in place of those of a copy of the config.
+    pub(crate) fn apply_to(&self, config: &mut Config) {
+        config.log_level = self.log_level;
+        config.node_status_update_frequency = self.node_status_update_frequency;
+        config.eviction_hard = self.eviction_hard;
+    }
+
+    /// Set the most verbose level that is logged, if there is one.
+    pub(crate) fn apply_log_level(&self) {
+        if let Some(level) = self.log_level {
+            crate::telemetry::set_level(level);
+        }
+    }
+}
+
+/// Watch the file the config was read from, updating the live settings whenever it changes. This
+/// runs until the kubelet stops, and does nothing for configs that weren't read from a file.
+#[cfg(any(feature = "cli", feature = "docs"))]
+pub(crate) async fn watch(config: Config, settings: SharedLiveSettings) {
+    use futures::{FutureExt, StreamExt};
+    use tracing::{debug, info, warn};
+
+    use crate::fs_watch::FileSystemWatcher;
+
+    /// How long after the file changes it is read, so that an editor is done writing it by then.
+    const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+    let path = match &config.config_file {
+        Some(path) => path.clone(),
+        None => return,
+    };
+    // Editors often replace the file rather than write to it, so its directory is watched
+    let dir = match path.parent().map(std::fs::canonicalize) {
+        Some(Ok(dir)) => dir,
+        Some(Err(e)) => {
+            warn!(
+                "Unable to watch configuration file {}, changes to it take effect once krustlet is restarted: {:?}",
+                path.display(),
+                e
+            );
+            return;
+        }
+        None => return,
+    };
+    let canonical_path = dir.join(path.file_name().unwrap_or_default());
+    let mut watcher = match FileSystemWatcher::new(&dir) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!(
+                "Unable to watch configuration file {}, changes to it take effect once krustlet is restarted: {:?}",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+
+    let mut current = config;
+    while let Some(event) = watcher.next().await {
+        match event {
+            Ok(event) if event.paths.iter().any(|p| p == &canonical_path) => {
+                debug!("Configuration file changed: {:?}", event)
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Error watching configuration file: {:?}", e);
+                continue;
+            }
+        }
+        tokio::time::delay_for(SETTLE_DELAY).await;
+        // The events of the same change are all handled by reading the file once
+        while let Some(Some(_)) = watcher.next().now_or_never() {}
+
+        let reloaded = match current.reload() {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                warn!(
+                    "Unable to reload configuration file {}, keeping the current settings: {:?}",
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        let live = LiveSettings::of(&reloaded);
+        if live != LiveSettings::of(&current) {
+            info!("Applying live settings from {}: {:?}", path.display(), live);
+            live.apply_log_level();
+            *settings.write().await = live;
+        }
+        let restart_needed = settings_needing_restart(
+            &serde_json::to_value(&current).unwrap_or_default(),
+            &serde_json::to_value(&reloaded).unwrap_or_default(),
+        );
+        if !restart_needed.is_empty() {
+            warn!(
+                "Changes to {} in {} take effect once krustlet is restarted",
+                restart_needed.join(", "),
+                path.display()
+            );
+        }
+        current = reloaded;
+    }
+}
+
+/// The names of the settings that differ between two serialized configs and only take effect once
+/// the kubelet is restarted.
+#[cfg(any(feature = "cli", feature = "docs", test))]
+fn settings_needing_restart(
+    current: &serde_json::Value,
+    reloaded: &serde_json::Value,
+) -> Vec<String> {
+    let mut changed = changed_settings(current, reloaded, "");
+    changed.retain(|name| {
+        let setting = name.split('.').next().unwrap_or_default();
+        !LIVE_SETTINGS.contains(&setting)
+    });
+    changed
+}
+
+/// The names of the settings that differ between two serialized configs, with those of nested
+/// settings prefixed by the name of the setting they are in.
+#[cfg(any(feature = "cli", feature = "docs", test))]
+fn changed_settings(
+    current: &serde_json::Value,
+    reloaded: &serde_json::Value,
+    prefix: &str,
+) -> Vec<String> {
+    let (current, reloaded) = match (current.as_object(), reloaded.as_object()) {
+        (Some(current), Some(reloaded)) => (current, reloaded),
+        _ => return vec![],
+    };
+    let mut names: Vec<&String> = current.keys().chain(reloaded.keys()).collect();
+    names.sort();
+    names.dedup();
+    let mut changed = vec![];
+    for name in names {
+        let (old, new) = (current.get(name), reloaded.get(name));
+        if old == new {
+            continue;
+        }
+        match (old, new) {
+            (Some(old), Some(new)) if old.is_object() && new.is_object() => {
+                changed.extend(changed_settings(old, new, &format!("{}{}.", prefix, name)))
+            }
+            _ => changed.push(format!("{}{}", prefix, name)),
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn only_settings_that_are_not_live_need_a_restart() {
+        let current = json!({
+            "logLevel": null,
+            "maxPods": 110,
+            "evictionHard": { "memory.available": "104857600" },
+            "serverConfig": { "addr": "0.0.0.0", "port": 3000 }
+        });
+        let mut reloaded = current.clone();
+        reloaded["logLevel"] = json!("debug");
+        reloaded["evictionHard"]["memory.available"] = json!("1024");
+        assert!(settings_needing_restart(&current, &reloaded).is_empty());
+
+        reloaded["maxPods"] = json!(20);
+        reloaded["serverConfig"]["port"] = json!(3001);
+        reloaded["clusterDomain"] = json!("cluster.local");
+        assert_eq!(
+            settings_needing_restart(&current, &reloaded),
+            vec!["clusterDomain", "maxPods", "serverConfig.port"]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! The actions that probes and lifecycle hooks take against a container: running a command in
+//! it, making an HTTP request to it, or opening a TCP connection to it.
+use std::convert::TryFrom;
+use std::future::Future;
+
+use k8s_openapi::api::core::v1::{ExecAction, HTTPGetAction, TCPSocketAction};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+
+use crate::container::Container;
+use crate::exec::{CommandOptions, ExecResult};
+use crate::pod::Pod;
+use crate::provider::ProviderError;
+
+/// An action to take against a container.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Action {
+    Exec(Vec<String>),
+    HttpGet {
+        url: String,
+        headers: Vec<(String, String)>,
+    },
+    TcpSocket {
+        host: String,
+        port: u16,
+    },
+}
+
+impl Action {
+    /// Build an action from whichever of the given handlers is set. HTTP and TCP actions go to
+    /// the pod's IP address unless they name a host.
+    pub(crate) fn new(
+        exec: Option<&ExecAction>,
+        http_get: Option<&HTTPGetAction>,
+        tcp_socket: Option<&TCPSocketAction>,
+        pod: &Pod,
+        container: &Container,
+    ) -> anyhow::Result<Self> {
+        let default_host = pod.pod_ip().unwrap_or("127.0.0.1");
+        if let Some(exec) = exec {
+            Ok(Action::Exec(exec.command.clone().unwrap_or_default()))
+        } else if let Some(http_get) = http_get {
+            let scheme = http_get.scheme.as_deref().unwrap_or("HTTP").to_lowercase();
+            let host = http_get.host.as_deref().unwrap_or(default_host);
+            let port = resolve_port(&http_get.port, container)?;
+            let path = http_get.path.as_deref().unwrap_or("/");
+            let path = path.trim_start_matches('/');
+            Ok(Action::HttpGet {
+                url: format!("{}://{}:{}/{}", scheme, host, port, path),
+                headers: http_get
+                    .http_headers
+                    .iter()
+                    .flatten()
+                    .map(|header| (header.name.clone(), header.value.clone()))
+                    .collect(),
+            })
+        } else if let Some(tcp_socket) = tcp_socket {
+            Ok(Action::TcpSocket {
+                host: tcp_socket
+                    .host
+                    .as_deref()
+                    .unwrap_or(default_host)
+                    .to_owned(),
+                port: resolve_port(&tcp_socket.port, container)?,
+            })
+        } else {
+            Err(anyhow::anyhow!("no action is set"))
+        }
+    }
+
+    /// Take the action, using `exec` to run commands in the container. Commands that exit with a
+    /// non-zero code and HTTP responses other than successes and redirects are errors.
+    pub(crate) async fn run<E, Fut>(&self, http: &reqwest::Client, exec: E) -> anyhow::Result<()>
+    where
+        E: FnOnce(CommandOptions) -> Fut,
+        Fut: Future<Output = Result<ExecResult, ProviderError>>,
+    {
+        match self {
+            Action::Exec(command) => {
+                let options = CommandOptions {
+                    command: command.clone(),
+                    stdin: false,
+                    stdout: true,
+                    stderr: true,
+                    tty: false,
+                };
+                let result = exec(options).await?;
+                if result.exit_code != 0 {
+                    anyhow::bail!(
+                        "command exited with code {}: {}",
+                        result.exit_code,
+                        result.output
+                    );
+                }
+            }
+            Action::HttpGet { url, headers } => {
+                let mut request = http.get(url);
+                for (name, value) in headers {
+                    request = request.header(name.as_str(), value.as_str());
+                }
+                let status = request.send().await?.status();
+                if !(status.is_success() || status.is_redirection()) {
+                    anyhow::bail!("HTTP request returned status {}", status);
+                }
+            }
+            Action::TcpSocket { host, port } => {
+                tokio::net::TcpStream::connect((host.as_str(), *port)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The HTTP client for actions. Like the Kubernetes kubelet, it doesn't verify the certificates
+/// of HTTPS endpoints, as containers typically serve self-signed ones.
+pub(crate) fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default()
+}
+
+/// Resolve an action's port, which may name one of the container's ports.
+fn resolve_port(port: &IntOrString, container: &Container) -> anyhow::Result<u16> {
+    let number = match port {
+        IntOrString::Int(number) => *number,
+        IntOrString::String(name) => container
+            .ports()
+            .iter()
+            .flatten()
+            .find(|port| port.name.as_deref() == Some(name.as_str()))
+            .map(|port| port.container_port)
+            .or_else(|| name.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("container has no port named {}", name))?,
+    };
+    u16::try_from(number).map_err(|_| anyhow::anyhow!("invalid port {}", number))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+    use k8s_openapi::api::core::v1::Container as KubeContainer;
+
+    #[test]
+    fn named_ports_are_resolved() {
+        let container: KubeContainer = serde_json::from_value(serde_json::json!({
+            "name": "web",
+            "ports": [{ "name": "http", "containerPort": 8080 }]
+        }))
+        .unwrap();
+        let container = Container::new(&container);
+        let port = IntOrString::String("http".to_owned());
+        assert_eq!(resolve_port(&port, &container).unwrap(), 8080);
+        let port = IntOrString::String("9090".to_owned());
+        assert_eq!(resolve_port(&port, &container).unwrap(), 9090);
+        let port = IntOrString::String("metrics".to_owned());
+        assert!(resolve_port(&port, &container).is_err());
+    }
+
+    #[test]
+    fn http_actions_default_to_the_pod_ip() {
+        let container: KubeContainer =
+            serde_json::from_value(serde_json::json!({ "name": "web" })).unwrap();
+        let container = Container::new(&container);
+        let pod = PodBuilder::new()
+            .name("web")
+            .status(serde_json::json!({ "podIP": "10.0.0.5" }))
+            .build();
+        let http_get: HTTPGetAction = serde_json::from_value(serde_json::json!({
+            "path": "/healthz",
+            "port": 8080
+        }))
+        .unwrap();
+        assert_eq!(
+            Action::new(None, Some(&http_get), None, &pod, &container).unwrap(),
+            Action::HttpGet {
+                url: "http://10.0.0.5:8080/healthz".to_owned(),
+                headers: vec![],
+            }
+        );
+    }
+}
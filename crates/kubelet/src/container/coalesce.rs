@@ -0,0 +1,113 @@
+//! Coalescing of container status updates, so that a burst of them for the containers of a pod,
+//! such as when many of its modules finish at once, is patched in a single request rather than
+//! one request each.
+//!
+//! Statuses are queued with [`queue_container_status`], and patched once
+//! [`COALESCE_INTERVAL`] has passed since the first of them was queued. Only the latest status
+//! queued for each container is patched. The statuses of a pod are patched in the order they
+//! were queued in, and only so many pods are patched at once, so that the API server isn't
+//! flooded with requests when many pods change at the same time.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::Api;
+use lazy_static::lazy_static;
+use tokio::sync::Semaphore;
+use tracing::{info_span, warn};
+use tracing_futures::Instrument;
+
+use super::status::patch_container_statuses;
+use super::{ContainerKey, Status};
+use crate::pod::{Pod, PodKey};
+
+/// How long statuses are gathered for before they are patched.
+const COALESCE_INTERVAL: Duration = Duration::from_millis(500);
+/// The most pods whose statuses are patched at once.
+const MAX_CONCURRENT_PATCHES: usize = 8;
+
+lazy_static! {
+    /// The statuses waiting to be patched for each pod. A pod is in the map for as long as a task
+    /// is patching its statuses, which is how each pod only ever has one.
+    static ref PENDING: Mutex<HashMap<PodKey, Pending>> = Mutex::new(HashMap::new());
+    static ref PATCHES: Semaphore = Semaphore::new(MAX_CONCURRENT_PATCHES);
+}
+
+/// The statuses waiting to be patched for a pod, and what to patch them with.
+struct Pending {
+    api: Api<KubePod>,
+    /// The latest manifest of the pod that statuses were queued with
+    pod: Pod,
+    statuses: Vec<(ContainerKey, Status)>,
+}
+
+impl Pending {
+    /// Add a status to be patched, replacing any that was queued for the container before.
+    fn push(&mut self, key: &ContainerKey, status: Status) {
+        self.statuses.retain(|(queued, _)| queued != key);
+        self.statuses.push((key.clone(), status));
+    }
+}
+
+/// Queue a container's status to be patched along with any others of the pod's containers that
+/// change in the meantime. This returns straight away, and any error patching the status is
+/// logged rather than returned. Use [`super::patch_container_status`] to patch it right away.
+pub fn queue_container_status(api: &Api<KubePod>, pod: &Pod, key: &ContainerKey, status: Status) {
+    let pod_key = PodKey::from(pod);
+    let mut pending = PENDING.lock().unwrap();
+    if let Some(queued) = pending.get_mut(&pod_key) {
+        queued.pod = pod.clone();
+        queued.push(key, status);
+        return;
+    }
+    let mut queued = Pending {
+        api: api.clone(),
+        pod: pod.clone(),
+        statuses: vec![],
+    };
+    queued.push(key, status);
+    pending.insert(pod_key.clone(), queued);
+    tokio::spawn(patch_queued(pod_key));
+}
+
+/// Patch the statuses queued for a pod until there are none left.
+async fn patch_queued(pod_key: PodKey) {
+    loop {
+        tokio::time::delay_for(COALESCE_INTERVAL).await;
+        let _permit = PATCHES.acquire().await;
+        // The pod stays in the map while its statuses are patched, so that statuses queued in the
+        // meantime are patched by this task once they are done, rather than racing them
+        let (api, pod, statuses) = match PENDING.lock().unwrap().get_mut(&pod_key) {
+            Some(queued) => (
+                queued.api.clone(),
+                queued.pod.clone(),
+                std::mem::take(&mut queued.statuses),
+            ),
+            None => return,
+        };
+        let span = info_span!(
+            parent: &crate::telemetry::pod_span(pod.namespace(), pod.name()),
+            "status_patch",
+            containers = statuses.len()
+        );
+        if let Err(e) = patch_container_statuses(&api, &pod, &statuses)
+            .instrument(span)
+            .await
+        {
+            warn!(
+                "Pod {} container status patch request returned error: {:?}",
+                pod.name(),
+                e
+            );
+        }
+        let mut pending = PENDING.lock().unwrap();
+        if pending
+            .get(&pod_key)
+            .map_or(true, |queued| queued.statuses.is_empty())
+        {
+            pending.remove(&pod_key);
+            return;
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Expansion of `$(VAR_NAME)` references in container commands, arguments and environment
+//! variables, following the rules the Kubernetes kubelet uses.
+
+use std::collections::HashMap;
+
+/// Expand the `$(VAR_NAME)` references in a string using the given environment.
+///
+/// References to variables that aren't in the environment are left as they are, as are
+/// references that are never closed. `$$` escapes a reference, so `$$(VAR_NAME)` becomes the
+/// literal `$(VAR_NAME)`.
+pub fn expand(input: &str, env: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(index) = rest.find('$') {
+        output.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            output.push('$');
+            rest = after;
+        } else if let (Some(reference), Some(end)) = (after.strip_prefix('('), after.find(')')) {
+            let name = &reference[..end - 1];
+            match env.get(name) {
+                Some(value) => output.push_str(value),
+                None => output.push_str(&rest[index..index + end + 2]),
+            }
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = after;
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn env() -> HashMap<String, String> {
+        vec![("NAME", "world"), ("EMPTY", "")]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn references_are_expanded() {
+        assert_eq!(expand("hello $(NAME)", &env()), "hello world");
+        assert_eq!(expand("$(NAME)$(NAME)", &env()), "worldworld");
+        assert_eq!(expand("[$(EMPTY)]", &env()), "[]");
+        assert_eq!(expand("no references", &env()), "no references");
+    }
+
+    #[test]
+    fn unknown_and_unclosed_references_are_kept() {
+        assert_eq!(expand("$(MISSING)", &env()), "$(MISSING)");
+        assert_eq!(expand("$(NAME", &env()), "$(NAME");
+        assert_eq!(expand("$()", &env()), "$()");
+        assert_eq!(expand("cost: $5", &env()), "cost: $5");
+        assert_eq!(expand("trailing $", &env()), "trailing $");
+    }
+
+    #[test]
+    fn double_dollars_escape_references() {
+        assert_eq!(expand("$$(NAME)", &env()), "$(NAME)");
+        assert_eq!(expand("$$$(NAME)", &env()), "$world");
+        assert_eq!(expand("$$", &env()), "$");
+    }
+}
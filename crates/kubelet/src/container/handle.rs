@@ -1,10 +1,22 @@
 use std::io::SeekFrom;
+use std::path::Path;
+use std::time::Duration;
 
-use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tracing::{debug, warn};
 
-use crate::container::ContainerMap;
-use crate::handle::StopHandler;
-use crate::log::{stream, HandleFactory, Sender};
+use crate::container::{ContainerMap, Lifecycle};
+use crate::exec::copy::CopyRequest;
+use crate::exec::{CommandOptions, ExecResult, Sender as ExecSender, Stdin};
+use crate::features::{self, Feature};
+use crate::handle::{CheckpointHandler, ExecHandler, StopHandler};
+use crate::log::{
+    stream, tail, take_lines, HandleFactory, LogWatch, Sender, FOLLOW_CLIENT_CHECK_INTERVAL,
+    FOLLOW_POLL_INTERVAL,
+};
+use crate::provider::ProviderError;
 
 /// Represents a handle to a running "container" (whatever that might be). This
 /// can be used on its own, however, it is generally better to use it as a part
@@ -13,6 +25,11 @@ use crate::log::{stream, HandleFactory, Sender};
 pub struct Handle<H, F> {
     handle: H,
     handle_factory: F,
+    // Log reader for the previous run of this container, if it has been restarted
+    previous: Option<F>,
+    lifecycle: Option<Lifecycle>,
+    // The ID of this run of the container, if the provider gives its runs IDs
+    id: Option<String>,
 }
 
 impl<H, F> std::fmt::Debug for Handle<H, F> {
@@ -28,34 +45,287 @@ impl<H: StopHandler, F> Handle<H, F> {
         Self {
             handle,
             handle_factory,
+            previous: None,
+            lifecycle: None,
+            id: None,
         }
     }
 
-    /// Signal the running instance to stop. Use [`Handle::wait`] to wait for the process to
-    /// exit. This uses the underlying [`StopHandler`] implementation passed to the constructor
-    pub async fn stop(&mut self) -> anyhow::Result<()> {
+    /// Identify this run of the container by the given ID, which it is reported with and which
+    /// requests for the container can name it by.
+    pub fn with_id(mut self, id: String) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// The ID of this run of the container, if it has one.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Run the given lifecycle hooks: the postStart hook when [`Handle::post_start`] is called
+    /// and the preStop hook before the instance is stopped.
+    pub fn with_lifecycle(mut self, lifecycle: Lifecycle) -> Self {
+        self.lifecycle = Some(lifecycle);
+        self
+    }
+
+    /// Keep the logs of the run of this container that this handle replaces, so that they can
+    /// still be read after a restart.
+    pub(crate) fn retain_previous(&mut self, previous: Handle<H, F>) {
+        self.previous = Some(previous.handle_factory);
+    }
+
+    /// Whether the logs of a previous run of this container are available.
+    pub(crate) fn has_previous(&self) -> bool {
+        self.previous.is_some()
+    }
+
+    /// Signal the running instance to stop, after running its preStop hook if it has one. Use
+    /// [`Handle::wait`] to wait for the process to exit. This uses the underlying
+    /// [`StopHandler`] implementation passed to the constructor
+    pub async fn stop(&mut self) -> Result<(), ProviderError>
+    where
+        H: ExecHandler,
+    {
+        // The hook only runs the first time the instance is stopped
+        if let Some(lifecycle) = self.lifecycle.take() {
+            lifecycle.pre_stop(&self.handle).await;
+        }
         self.handle.stop().await
     }
 
-    /// Streams output from the running process into the given sender.
+    /// Streams output from the running process, or from its previous run if the sender asks for
+    /// it, into the given sender.
     /// Optionally tails the output and/or continues to watch the file and stream changes.
-    pub(crate) async fn output<R>(&mut self, sender: Sender) -> anyhow::Result<()>
+    pub(crate) async fn output<R>(&mut self, sender: Sender) -> Result<(), ProviderError>
     where
         R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
         F: HandleFactory<R>,
     {
-        let mut handle = self.handle_factory.new_handle();
+        let handle_factory = if sender.previous() {
+            match self.previous.as_ref() {
+                Some(previous) => previous,
+                None => {
+                    return Err(ProviderError::Other(anyhow::anyhow!(
+                        "previous terminated container not found"
+                    )))
+                }
+            }
+        } else {
+            &self.handle_factory
+        };
+        let mut handle = handle_factory.new_handle();
         handle.seek(SeekFrom::Start(0)).await?;
-        tokio::spawn(stream(handle, sender));
+        let watch = if sender.follow() {
+            handle_factory.watch()
+        } else {
+            None
+        };
+        tokio::spawn(stream(handle, sender, watch));
         Ok(())
     }
 
+    /// Reads the last lines of the output of the running process. See [`crate::log::tail`].
+    pub(crate) async fn log_tail<R>(
+        &self,
+        lines: usize,
+        max_bytes: usize,
+    ) -> Result<String, ProviderError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+        F: HandleFactory<R>,
+    {
+        Ok(tail(self.handle_factory.new_handle(), lines, max_bytes).await?)
+    }
+
+    /// Whether the running process has exited, if the underlying [`StopHandler`] can tell.
+    pub fn has_exited(&self) -> Option<bool> {
+        self.handle.has_exited()
+    }
+
     /// Wait for the running process to complete. Generally speaking,
     /// [`Handle::stop`] should be called first. This uses the underlying
     /// [`StopHandler`] implementation passed to the constructor
-    pub async fn wait(&mut self) -> anyhow::Result<()> {
+    pub async fn wait(&mut self) -> Result<(), ProviderError> {
         self.handle.wait().await
     }
+
+    /// Wait for the running process to complete until the deadline, and forcibly stop it if it
+    /// hasn't by then. [`Handle::stop`] should be called first.
+    pub async fn wait_or_kill(
+        &mut self,
+        deadline: tokio::time::Instant,
+    ) -> Result<(), ProviderError> {
+        match tokio::time::timeout_at(deadline, self.handle.wait()).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("Process did not stop within its grace period, killing it");
+                self.handle.kill().await
+            }
+        }
+    }
+
+    /// Stop the running process gracefully, killing it if it hasn't completed once the grace
+    /// period is over.
+    pub async fn terminate(&mut self, grace_period: Duration) -> Result<(), ProviderError>
+    where
+        H: ExecHandler,
+    {
+        let deadline = tokio::time::Instant::now() + grace_period;
+        self.stop().await?;
+        self.wait_or_kill(deadline).await
+    }
+}
+
+impl<H: ExecHandler, F> Handle<H, F> {
+    /// Runs the postStart hook of the instance, if it has one, which should be done as soon as
+    /// it has started. If the hook fails, the instance should be stopped.
+    pub async fn post_start(&self) -> anyhow::Result<()> {
+        match &self.lifecycle {
+            Some(lifecycle) => lifecycle.post_start(&self.handle).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Runs a command against the running process to completion, returning its output and exit
+    /// code. This uses the underlying [`ExecHandler`] implementation passed to the constructor
+    pub(crate) async fn exec_command(
+        &self,
+        options: CommandOptions,
+    ) -> Result<ExecResult, ProviderError> {
+        self.handle.exec(options).await
+    }
+
+    /// Runs a command against the running process, feeding it data from `stdin` and streaming
+    /// its output and exit status into the given sender. This uses the underlying [`ExecHandler`] implementation
+    /// passed to the constructor
+    ///
+    /// The command is started while the handle is borrowed, and its output is streamed by the
+    /// returned [`Session`], which doesn't borrow it.
+    ///
+    /// The `tar` commands `kubectl cp` runs are handed to the [`ExecHandler`]'s copy methods
+    /// instead, unless it doesn't implement them. Unless the `StreamingExec` feature is
+    /// enabled, the command is run to completion without input and its output sent once it is
+    /// done. Either way, the returned session has nothing left to do.
+    pub(crate) async fn exec(
+        &self,
+        options: CommandOptions,
+        mut stdin: Stdin,
+        mut sender: ExecSender,
+    ) -> Result<Session, ProviderError> {
+        if let Some(request) = CopyRequest::parse(&options.command) {
+            let copied = match &request {
+                CopyRequest::From(path) => self.handle.copy_from(path, sender.clone()).await,
+                CopyRequest::To(dir) => self.handle.copy_to(dir, &mut stdin).await,
+            };
+            match copied {
+                Err(ProviderError::Unsupported) => (),
+                Ok(()) => {
+                    sender.exit(0).await?;
+                    return Ok(finished());
+                }
+                Err(e) => {
+                    debug!("Unable to copy with {:?}: {}", request, e);
+                    // Like tar, report what went wrong on stderr rather than failing the session
+                    sender.stderr(format!("tar: {}\n", e)).await?;
+                    sender.exit(2).await?;
+                    return Ok(finished());
+                }
+            }
+        }
+        if !features::enabled(Feature::StreamingExec) {
+            let result = self.handle.exec(options).await?;
+            sender.stdout(result.output).await?;
+            sender.exit(result.exit_code).await?;
+            return Ok(finished());
+        }
+        let mut output = self.handle.exec_streaming(options, stdin).await?;
+        Ok(async move {
+            while let Some(chunk) = output.next().await {
+                sender.send(chunk).await?;
+            }
+            Ok::<_, ProviderError>(())
+        }
+        .boxed())
+    }
+
+    /// Attaches to the running process, feeding it data from `stdin` and streaming any output it
+    /// writes from now on into the given sender. The returned [`Session`] streams the output until
+    /// the client goes away, without borrowing the handle.
+    pub(crate) async fn attach<R>(
+        &self,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<Session, ProviderError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+        F: HandleFactory<R>,
+    {
+        let mut output = self.handle_factory.new_handle();
+        output.seek(SeekFrom::End(0)).await?;
+        let watch = self.handle_factory.watch();
+        // Output can still be streamed if the process can't take input
+        if let Err(e) = self.handle.attach_stdin(stdin).await {
+            debug!("Unable to attach to process stdin: {}", e);
+        }
+        Ok(async move { Ok::<_, ProviderError>(follow(output, watch, sender).await?) }.boxed())
+    }
+}
+
+impl<H: CheckpointHandler, F> Handle<H, F> {
+    /// Writes a snapshot of the running process into `dir`. This uses the underlying
+    /// [`CheckpointHandler`] implementation passed to the constructor
+    pub(crate) async fn checkpoint(&self, dir: &Path) -> Result<(), ProviderError> {
+        self.handle.checkpoint(dir).await
+    }
+}
+
+/// The part of an exec or attach session that streams output to the client once it has been
+/// started. It doesn't borrow the container's handle, so the container can be stopped or replaced
+/// while the client is still connected.
+pub(crate) type Session = BoxFuture<'static, Result<(), ProviderError>>;
+
+/// A session that has nothing left to stream.
+fn finished() -> Session {
+    futures::future::ready(Ok(())).boxed()
+}
+
+/// Streams each line written to `output` into the sender until the client goes away. New output
+/// is read whenever `watch` signals a change, or polled for if there is no watch.
+async fn follow<R: AsyncRead + Unpin>(
+    mut output: R,
+    mut watch: Option<LogWatch>,
+    mut sender: ExecSender,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; 1024];
+    let mut pending = Vec::new();
+    loop {
+        let n = output.read(&mut buf).await?;
+        if n > 0 {
+            pending.extend_from_slice(&buf[..n]);
+            for chunk in take_lines(&mut pending) {
+                sender.send(chunk).await?;
+            }
+            continue;
+        }
+
+        match watch.as_mut() {
+            Some(w) => {
+                tokio::select! {
+                    change = w.next() => if change.is_none() {
+                        debug!("Log watch ended, falling back to polling");
+                        watch = None;
+                    },
+                    _ = tokio::time::delay_for(FOLLOW_CLIENT_CHECK_INTERVAL) => (),
+                }
+            }
+            None => tokio::time::delay_for(FOLLOW_POLL_INTERVAL).await,
+        }
+        if sender.is_closed().await {
+            return Ok(());
+        }
+    }
 }
 
 /// A map from containers to container handles.
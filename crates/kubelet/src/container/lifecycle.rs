@@ -0,0 +1,124 @@
+//! The `postStart` and `preStop` lifecycle hooks of a container.
+use k8s_openapi::api::core::v1::Handler;
+use tracing::warn;
+
+use crate::container::action::{self, Action};
+use crate::container::Container;
+use crate::handle::ExecHandler;
+use crate::pod::{record_event, EventType, Pod};
+
+/// The lifecycle hooks of a container. Hooks that fail are recorded as events on the pod.
+pub struct Lifecycle {
+    client: kube::Client,
+    pod: Pod,
+    container_name: String,
+    post_start: Option<Action>,
+    pre_stop: Option<Action>,
+    http: reqwest::Client,
+}
+
+impl Lifecycle {
+    /// Read the lifecycle hooks of a container, returning `None` if it has none.
+    pub fn new(client: kube::Client, pod: &Pod, container: &Container) -> Option<Self> {
+        let lifecycle = container.lifecycle()?;
+        let hook = |handler: Option<&Handler>, name: &str| {
+            let handler = handler?;
+            match Action::new(
+                handler.exec.as_ref(),
+                handler.http_get.as_ref(),
+                handler.tcp_socket.as_ref(),
+                pod,
+                container,
+            ) {
+                Ok(action) => Some(action),
+                Err(e) => {
+                    warn!(
+                        "Pod {} container {} has an invalid {} hook: {:?}",
+                        pod.name(),
+                        container.name(),
+                        name,
+                        e
+                    );
+                    None
+                }
+            }
+        };
+        let post_start = hook(lifecycle.post_start.as_ref(), "postStart");
+        let pre_stop = hook(lifecycle.pre_stop.as_ref(), "preStop");
+        if post_start.is_none() && pre_stop.is_none() {
+            return None;
+        }
+        Some(Lifecycle {
+            client,
+            pod: pod.clone(),
+            container_name: container.name().to_owned(),
+            post_start,
+            pre_stop,
+            http: action::http_client(),
+        })
+    }
+
+    /// Run the postStart hook, if there is one, against the container's process.
+    pub(crate) async fn post_start<H: ExecHandler>(&self, handle: &H) -> anyhow::Result<()> {
+        let hook = match &self.post_start {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+        if let Err(e) = hook.run(&self.http, |options| handle.exec(options)).await {
+            self.record_failure("FailedPostStartHook", "PostStart", &e)
+                .await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Run the preStop hook, if there is one, against the container's process. The hook is
+    /// given the pod's termination grace period to finish.
+    pub(crate) async fn pre_stop<H: ExecHandler>(&self, handle: &H) {
+        let hook = match &self.pre_stop {
+            Some(hook) => hook,
+            None => return,
+        };
+        let grace_period = self.pod.termination_grace_period();
+        let result = match tokio::time::timeout(
+            grace_period,
+            hook.run(&self.http, |options| handle.exec(options)),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!(
+                "hook did not finish within the termination grace period of {:?}",
+                grace_period
+            )),
+        };
+        if let Err(e) = result {
+            self.record_failure("FailedPreStopHook", "PreStop", &e)
+                .await;
+        }
+    }
+
+    async fn record_failure(&self, reason: &str, hook: &str, error: &anyhow::Error) {
+        let message = format!(
+            "{} hook for container {} failed: {}",
+            hook, self.container_name, error
+        );
+        warn!("Pod {}: {}", self.pod.name(), message);
+        if let Err(e) = record_event(
+            &self.client,
+            &self.pod,
+            EventType::Warning,
+            reason,
+            &message,
+        )
+        .await
+        {
+            warn!(
+                "Unable to record {} event for pod {}: {:?}",
+                reason,
+                self.pod.name(),
+                e
+            );
+        }
+    }
+}
@@ -5,12 +5,24 @@ use oci_distribution::Reference;
 use std::convert::TryInto;
 use std::fmt::Display;
 
+pub(crate) mod action;
+mod coalesce;
+mod expansion;
 mod handle;
+mod lifecycle;
 pub mod state;
 mod status;
+pub mod termination;
 
+pub use coalesce::queue_container_status;
+pub use expansion::expand;
 pub use handle::{Handle, HandleMap};
-pub use status::{make_initial_container_status, patch_container_status, Status};
+pub use lifecycle::Lifecycle;
+pub use status::{
+    make_initial_container_status, patch_container_ids, patch_container_ready,
+    patch_container_restart_count, patch_container_started, patch_container_status,
+    patch_container_statuses, Status,
+};
 
 /// Specifies how the store should check for module updates
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -68,13 +80,35 @@ pub enum ContainerKey {
     Init(String),
     /// An application container with the given name
     App(String),
+    /// An ephemeral container with the given name, which is added to a running pod, such as by
+    /// `kubectl debug`, and is never restarted
+    Ephemeral(String),
 }
 
 impl ContainerKey {
     /// Gets the container name
     pub fn name(&self) -> String {
         match self {
-            Self::Init(name) | Self::App(name) => name.to_string(),
+            Self::Init(name) | Self::App(name) | Self::Ephemeral(name) => name.to_string(),
+        }
+    }
+
+    /// The keys a container with the given name could have, in the order they are looked up by
+    /// name.
+    pub(crate) fn all(name: &str) -> [Self; 3] {
+        [
+            Self::App(name.to_owned()),
+            Self::Init(name.to_owned()),
+            Self::Ephemeral(name.to_owned()),
+        ]
+    }
+
+    /// The field of a pod's status that holds the status of the container.
+    pub(crate) fn status_field(&self) -> &'static str {
+        match self {
+            Self::Init(_) => "initContainerStatuses",
+            Self::App(_) => "containerStatuses",
+            Self::Ephemeral(_) => "ephemeralContainerStatuses",
         }
     }
 
@@ -87,6 +121,11 @@ impl ContainerKey {
     pub fn is_init(&self) -> bool {
         matches!(self, Self::Init(_))
     }
+
+    /// Whether the key identifies an ephemeral container
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, Self::Ephemeral(_))
+    }
 }
 
 impl Display for ContainerKey {
@@ -103,6 +142,8 @@ pub trait ContainerMapByName<V> {
     /// Gets a mutable reference to the value associated with the container
     /// with the given name.
     fn get_mut_by_name(&mut self, name: String) -> Option<&mut V>;
+    /// Get an entry from the ContainerMap by container name.
+    fn get_by_name(&self, name: &str) -> Option<&V>;
     /// Whether the map contains a `ContainerKey` with the given name.
     fn contains_key_name(&self, name: &str) -> bool;
 }
@@ -110,17 +151,21 @@ pub trait ContainerMapByName<V> {
 impl<V> ContainerMapByName<V> for ContainerMap<V> {
     fn get_mut_by_name(&mut self, name: String) -> Option<&mut V> {
         // TODO: borrow checker objected to any of the more natural forms
-        let app_key = ContainerKey::App(name.clone());
-        if self.contains_key(&app_key) {
-            self.get_mut(&app_key)
-        } else {
-            self.get_mut(&ContainerKey::Init(name))
-        }
+        let key = ContainerKey::all(&name)
+            .iter()
+            .find(|key| self.contains_key(key))
+            .cloned()?;
+        self.get_mut(&key)
+    }
+
+    fn get_by_name(&self, name: &str) -> Option<&V> {
+        ContainerKey::all(name).iter().find_map(|key| self.get(key))
     }
 
     fn contains_key_name(&self, name: &str) -> bool {
-        self.contains_key(&ContainerKey::App(name.to_owned()))
-            || self.contains_key(&ContainerKey::Init(name.to_owned()))
+        ContainerKey::all(name)
+            .iter()
+            .any(|key| self.contains_key(key))
     }
 }
 
@@ -1,5 +1,5 @@
 //! Functions for running Container state machines.
-use crate::container::{patch_container_status, Status};
+use crate::container::{queue_container_status, Status};
 use crate::container::{Container, ContainerKey};
 use crate::pod::Pod;
 use chrono::Utc;
@@ -7,7 +7,8 @@ use futures::StreamExt;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use krator::{Manifest, ObjectState, SharedState, State, Transition};
 use kube::api::Api;
-use log::{debug, error, warn};
+use tracing::{debug, error, info_span, warn};
+use tracing_futures::Instrument;
 
 /// Prelude for Pod state machines.
 pub mod prelude {
@@ -15,8 +16,39 @@ pub mod prelude {
     pub use krator::{Manifest, ObjectState, SharedState, State, Transition, TransitionTo};
 }
 
-/// Iteratively evaluate state machine until it returns Complete.
+/// Iteratively evaluate state machine until it returns Complete. This is traced in a span for the
+/// container, under that of its pod.
 pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Status>>(
+    client: &kube::Client,
+    initial_state: impl State<S>,
+    shared: SharedState<S::SharedState>,
+    container_state: S,
+    pod: Manifest<Pod>,
+    container_name: ContainerKey,
+) -> anyhow::Result<()> {
+    let span = {
+        let pod = pod.latest();
+        info_span!(
+            parent: &crate::telemetry::pod_span(pod.namespace(), pod.name()),
+            "container",
+            pod = pod.name(),
+            namespace = pod.namespace(),
+            container = %container_name
+        )
+    };
+    run(
+        client,
+        initial_state,
+        shared,
+        container_state,
+        pod,
+        container_name,
+    )
+    .instrument(span)
+    .await
+}
+
+async fn run<S: ObjectState<Manifest = Container, Status = Status>>(
     client: &kube::Client,
     initial_state: impl State<S>,
     shared: SharedState<S::SharedState>,
@@ -77,18 +109,10 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
         let latest_pod = pod.latest();
         let latest_container = latest_pod.find_container(&container_name).unwrap();
 
+        // Statuses are patched along with those of the pod's other containers that change at about
+        // the same time
         match state.status(&mut container_state, &latest_container).await {
-            Ok(status) => {
-                match patch_container_status(&api, &latest_pod, &container_name, &status).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        warn!(
-                            "Pod {} container {} status patch request returned error: {:?}",
-                            &pod_name, container_name, e
-                        );
-                    }
-                }
-            }
+            Ok(status) => queue_container_status(&api, &latest_pod, &container_name, status),
             Err(e) => {
                 warn!(
                     "Pod {} container {} status patch returned error: {:?}",
@@ -134,9 +158,7 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
                         message: format!("Container exited with error: {:?}.", e),
                         failed: true,
                     };
-                    patch_container_status(&api, &latest_pod, &container_name, &status)
-                        .await
-                        .unwrap();
+                    queue_container_status(&api, &latest_pod, &container_name, status);
 
                     break result;
                 }
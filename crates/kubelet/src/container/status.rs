@@ -1,3 +1,4 @@
+use crate::backoff::RetryPolicy;
 use crate::container::{Container, ContainerKey};
 use crate::pod::Pod;
 use chrono::{DateTime, Utc};
@@ -6,7 +7,7 @@ use k8s_openapi::api::core::v1::{
     ContainerStatus as KubeContainerStatus, Pod as KubePod,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
-use log::{debug, warn};
+use tracing::{debug, warn};
 
 /// Status is a simplified version of the Kubernetes container status
 /// for use in providers. It allows for simple creation of the current status of
@@ -95,11 +96,11 @@ impl Status {
         KubeContainerStatus {
             state: Some(state),
             name: container_name.to_string(),
-            // Right now we don't have a way to probe, so just set to ready if
-            // in a running state
+            // Running containers are ready unless they have a readiness probe,
+            // in which case the prober patches their readiness once it has run
             ready,
-            // This is always true if startupProbe is not defined. When we
-            // handle probes, this should be updated accordingly
+            // Likewise, containers with a startup probe are marked as not started
+            // by the prober until the probe passes
             started: Some(true),
             // The rest of the items in status (see docs here:
             // https://kubernetes.io/docs/reference/generated/kubernetes-api/v1.17/#containerstatus-v1-core)
@@ -117,74 +118,229 @@ pub async fn patch_container_status(
     key: &ContainerKey,
     status: &Status,
 ) -> anyhow::Result<()> {
-    match pod.find_container(&key) {
-        Some(container) => {
-            let kube_status = status.to_kubernetes(container.name());
-
-            let patches = match pod.container_status_index(&key) {
-                Some(idx) => {
-                    let path_prefix = if key.is_init() {
-                        format!("/status/initContainerStatuses/{}", idx)
-                    } else {
-                        format!("/status/containerStatuses/{}", idx)
-                    };
-
-                    vec![
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/state", path_prefix),
-                            value: serde_json::json!(kube_status.state.unwrap()),
-                        }),
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/ready", path_prefix),
-                            value: serde_json::json!(kube_status.ready),
-                        }),
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/started", path_prefix),
-                            value: serde_json::json!(true),
-                        }),
-                    ]
-                }
-                None => {
-                    let path = if key.is_init() {
-                        "/status/initContainerStatuses/-".to_string()
-                    } else {
-                        "/status/containerStatuses/-".to_string()
-                    };
-
-                    vec![json_patch::PatchOperation::Add(json_patch::AddOperation {
-                        path,
-                        value: serde_json::json!(kube_status),
-                    })]
-                }
-            };
-
-            let patch = json_patch::Patch(patches);
-            let params = kube::api::PatchParams {
-                patch_strategy: kube::api::PatchStrategy::JSON,
-                ..Default::default()
-            };
-            let patch_data = serde_json::to_vec(&patch)?;
-            debug!(
-                "Patching container status {} {}: '{}'",
-                pod.name(),
-                container.name(),
-                std::str::from_utf8(&patch_data).unwrap()
-            );
-            client.patch_status(pod.name(), &params, patch_data).await?;
-            Ok(())
-        }
-        None => {
-            warn!(
-                "Container status update for unknown container {}.",
-                key.name()
-            );
-            Ok(())
+    patch_container_statuses(client, pod, &[(key.clone(), status.clone())]).await
+}
+
+/// Patch the statuses of several of a pod's containers in a single request. Containers the pod
+/// doesn't have are skipped with a warning.
+pub async fn patch_container_statuses(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    statuses: &[(ContainerKey, Status)],
+) -> anyhow::Result<()> {
+    // Ephemeral containers only get a status once they start, which may have been added since
+    // the pod was last seen
+    let current;
+    let pod = if statuses.iter().all(|(key, _)| {
+        pod.find_container(key).is_none() || pod.container_status_index(key).is_some()
+    }) {
+        pod
+    } else {
+        current = Pod::from(client.get(pod.name()).await?);
+        &current
+    };
+    let patches = container_status_patches(pod, statuses);
+    if patches.is_empty() {
+        return Ok(());
+    }
+
+    let patch = json_patch::Patch(patches);
+    let params = kube::api::PatchParams {
+        patch_strategy: kube::api::PatchStrategy::JSON,
+        ..Default::default()
+    };
+    let patch_data = serde_json::to_vec(&patch)?;
+    debug!(
+        "Patching container statuses of pod {}: '{}'",
+        pod.name(),
+        std::str::from_utf8(&patch_data).unwrap()
+    );
+    let patched = RetryPolicy::api_request()
+        .retry("patch container status", || {
+            client.patch_status(pod.name(), &params, patch_data.clone())
+        })
+        .await?;
+    crate::pod::update_pod_conditions(client, &patched).await
+}
+
+/// The operations that set the given statuses of the pod's containers. Containers that already
+/// have a status have its state replaced, and the others have one added, starting the list of
+/// statuses of their kind if the pod has none yet.
+fn container_status_patches(
+    pod: &Pod,
+    statuses: &[(ContainerKey, Status)],
+) -> Vec<json_patch::PatchOperation> {
+    let mut patches = vec![];
+    // Statuses for lists the pod doesn't have yet, which are added whole once all are known
+    let mut new_lists: Vec<(String, Vec<KubeContainerStatus>)> = vec![];
+    for (key, status) in statuses {
+        let container = match pod.find_container(key) {
+            Some(container) => container,
+            None => {
+                warn!(
+                    "Container status update for unknown container {}.",
+                    key.name()
+                );
+                continue;
+            }
+        };
+        let kube_status = status.to_kubernetes(container.name());
+        let statuses_path = format!("/status/{}", key.status_field());
+        match pod.container_status_index(key) {
+            Some(idx) => {
+                let path_prefix = format!("{}/{}", statuses_path, idx);
+                patches.extend(vec![
+                    json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                        path: format!("{}/state", path_prefix),
+                        value: serde_json::json!(kube_status.state.unwrap()),
+                    }),
+                    json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                        path: format!("{}/ready", path_prefix),
+                        value: serde_json::json!(kube_status.ready),
+                    }),
+                    json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                        path: format!("{}/started", path_prefix),
+                        value: serde_json::json!(true),
+                    }),
+                ]);
+            }
+            None if pod.container_statuses(key).is_some() => {
+                patches.push(json_patch::PatchOperation::Add(json_patch::AddOperation {
+                    path: format!("{}/-", statuses_path),
+                    value: serde_json::json!(kube_status),
+                }))
+            }
+            None => match new_lists
+                .iter_mut()
+                .find(|(path, _)| *path == statuses_path)
+            {
+                Some((_, list)) => list.push(kube_status),
+                None => new_lists.push((statuses_path, vec![kube_status])),
+            },
         }
     }
+    patches.extend(new_lists.into_iter().map(|(path, list)| {
+        json_patch::PatchOperation::Add(json_patch::AddOperation {
+            path,
+            value: serde_json::json!(list),
+        })
+    }));
+    patches
+}
+
+/// Patch the number of times a container has been restarted
+pub async fn patch_container_restart_count(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    restart_count: i32,
+) -> anyhow::Result<()> {
+    patch_container_status_field(
+        client,
+        pod,
+        key,
+        "restartCount",
+        serde_json::json!(restart_count),
+    )
+    .await
+}
+
+/// Patch whether a container is ready, such as when its readiness probe passes or fails
+pub async fn patch_container_ready(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    ready: bool,
+) -> anyhow::Result<()> {
+    patch_container_status_field(client, pod, key, "ready", serde_json::json!(ready)).await
+}
+
+/// Patch whether a container has started, such as when its startup probe passes
+pub async fn patch_container_started(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    started: bool,
+) -> anyhow::Result<()> {
+    patch_container_status_field(client, pod, key, "started", serde_json::json!(started)).await
+}
+
+/// Patch the IDs a container is reported with: the ID of the run of the container that was just
+/// started, and the ID of the image it runs, such as one made by [`crate::store::image_id`].
+pub async fn patch_container_ids(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    container_id: &str,
+    image_id: &str,
+) -> anyhow::Result<()> {
+    patch_container_status_fields(
+        client,
+        pod,
+        key,
+        vec![
+            ("containerID", serde_json::json!(container_id)),
+            ("imageID", serde_json::json!(image_id)),
+        ],
+    )
+    .await
+}
+
+async fn patch_container_status_field(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    field: &str,
+    value: serde_json::Value,
+) -> anyhow::Result<()> {
+    patch_container_status_fields(client, pod, key, vec![(field, value)]).await
 }
 
-/// Create inital container status for registering pod.
+async fn patch_container_status_fields(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    fields: Vec<(&str, serde_json::Value)>,
+) -> anyhow::Result<()> {
+    let idx = pod
+        .container_status_index(&key)
+        .ok_or_else(|| anyhow::anyhow!("Pod {} has no status for container {}", pod.name(), key))?;
+    let prefix = format!("/status/{}/{}", key.status_field(), idx);
+    // Fields that aren't set yet are left out of the status, so they are added rather than
+    // replaced, which sets them either way
+    let patch = json_patch::Patch(
+        fields
+            .into_iter()
+            .map(|(field, value)| {
+                json_patch::PatchOperation::Add(json_patch::AddOperation {
+                    path: format!("{}/{}", prefix, field),
+                    value,
+                })
+            })
+            .collect(),
+    );
+    let params = kube::api::PatchParams {
+        patch_strategy: kube::api::PatchStrategy::JSON,
+        ..Default::default()
+    };
+    let patch_data = serde_json::to_vec(&patch)?;
+    let patched = RetryPolicy::api_request()
+        .retry("patch container status", || {
+            client.patch_status(pod.name(), &params, patch_data.clone())
+        })
+        .await?;
+    crate::pod::update_pod_conditions(client, &patched).await
+}
+
+/// Create inital container status for registering pod. The image is reported as the image ID
+/// too, as modules are only known by the digest of their image once they have been pulled.
 pub fn make_initial_container_status(container: &Container) -> KubeContainerStatus {
+    let image = container
+        .image()
+        .ok()
+        .flatten()
+        .map(|image| image.whole())
+        .unwrap_or_default();
     let state = ContainerState {
         waiting: Some(ContainerStateWaiting {
             message: Some("Registered".to_string()),
@@ -197,6 +353,76 @@ pub fn make_initial_container_status(container: &Container) -> KubeContainerStat
         ready: false,
         started: Some(false),
         state: Some(state),
+        image_id: image.clone(),
+        image,
+        restart_count: 0,
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+
+    fn paths(patches: &[json_patch::PatchOperation]) -> Vec<String> {
+        patches
+            .iter()
+            .map(|patch| match patch {
+                json_patch::PatchOperation::Add(op) => format!("add {}", op.path),
+                json_patch::PatchOperation::Replace(op) => format!("replace {}", op.path),
+                other => panic!("unexpected patch {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn statuses_of_several_containers_are_patched_together() {
+        let pod = PodBuilder::new()
+            .spec(serde_json::json!({
+                "initContainers": [
+                    { "name": "setup", "image": "hello:v1" },
+                    { "name": "migrate", "image": "hello:v1" },
+                ],
+                "containers": [
+                    { "name": "first", "image": "hello:v1" },
+                    { "name": "second", "image": "hello:v1" },
+                ],
+            }))
+            .status(serde_json::json!({
+                "containerStatuses": [{
+                    "name": "first",
+                    "image": "hello:v1",
+                    "imageID": "hello:v1",
+                    "ready": false,
+                    "restartCount": 0,
+                }],
+            }))
+            .build();
+        let statuses = vec![
+            (ContainerKey::Init("setup".to_owned()), Status::running()),
+            (ContainerKey::App("first".to_owned()), Status::running()),
+            (ContainerKey::App("second".to_owned()), Status::running()),
+            (ContainerKey::Init("migrate".to_owned()), Status::running()),
+            (ContainerKey::App("missing".to_owned()), Status::running()),
+        ];
+        let patches = container_status_patches(&pod, &statuses);
+        assert_eq!(
+            paths(&patches),
+            vec![
+                "replace /status/containerStatuses/0/state",
+                "replace /status/containerStatuses/0/ready",
+                "replace /status/containerStatuses/0/started",
+                "add /status/containerStatuses/-",
+                "add /status/initContainerStatuses",
+            ]
+        );
+        match patches.last() {
+            Some(json_patch::PatchOperation::Add(op)) => {
+                assert_eq!(op.value[0]["name"], "setup");
+                assert_eq!(op.value[1]["name"], "migrate");
+            }
+            other => panic!("unexpected patch {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Termination messages: what a container writes to its `terminationMessagePath` before it
+//! exits, which is reported in its terminated state so that the reason it terminated shows up in
+//! `kubectl describe`.
+//!
+//! The file is kept on the host, in a directory under the kubelet's data directory that providers
+//! mount into the container at the parent of the message path. Containers whose policy is
+//! `FallbackToLogsOnError` have the last lines of their log reported instead if they fail without
+//! writing a message.
+use std::path::{Path, PathBuf};
+
+use tokio::io::AsyncReadExt;
+use tracing::debug;
+
+use super::Container;
+use crate::pod::Pod;
+
+/// The path in the container that the termination message is read from if the container doesn't
+/// set one.
+pub const DEFAULT_PATH: &str = "/dev/termination-log";
+/// How many lines of a container's log are reported if it falls back to its logs.
+pub const FALLBACK_LOG_LINES: usize = 80;
+/// The most bytes of a container's log that are reported if it falls back to its logs.
+pub const MAX_FALLBACK_LOG_BYTES: usize = 2048;
+
+/// The most bytes of a termination message that are reported.
+const MAX_MESSAGE_BYTES: usize = 4096;
+/// The directory under the kubelet's data directory that termination messages are kept in.
+const TERMINATION_DIR: &str = "termination";
+/// The policy that reports the container's logs if it fails without writing a message.
+const FALLBACK_TO_LOGS_ON_ERROR: &str = "FallbackToLogsOnError";
+
+/// Where a container's termination message is written, and what is reported without one.
+#[derive(Clone, Debug)]
+pub struct TerminationMessage {
+    /// The file on the host that the message is read from
+    host_path: PathBuf,
+    /// The path of the file in the container
+    path: PathBuf,
+    fallback_to_logs: bool,
+}
+
+impl TerminationMessage {
+    /// The termination message of the pod's container, for a kubelet with the given data
+    /// directory.
+    pub fn new(data_dir: &Path, pod: &Pod, container: &Container) -> Self {
+        let path = PathBuf::from(
+            container
+                .termination_message_path()
+                .map(String::as_str)
+                .filter(|path| !path.is_empty())
+                .unwrap_or(DEFAULT_PATH),
+        );
+        let file_name = path
+            .file_name()
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| "termination-log".into());
+        let host_path = pod_dir(data_dir, pod)
+            .join(container.name())
+            .join(file_name);
+        TerminationMessage {
+            host_path,
+            path,
+            fallback_to_logs: container.termination_message_policy().map(String::as_str)
+                == Some(FALLBACK_TO_LOGS_ON_ERROR),
+        }
+    }
+
+    /// Create an empty message file for the container to write to, replacing any message from
+    /// an earlier run of the container.
+    pub async fn prepare(&self) -> std::io::Result<()> {
+        if let Some(dir) = self.host_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&self.host_path, b"").await
+    }
+
+    /// The directory on the host that holds the message file.
+    pub fn host_dir(&self) -> Option<&Path> {
+        self.host_path.parent()
+    }
+
+    /// The directory in the container that the host directory is mounted at.
+    pub fn container_dir(&self) -> Option<&Path> {
+        self.path.parent()
+    }
+
+    /// The message the container wrote, if it wrote one. Messages longer than the limit are cut
+    /// short.
+    pub async fn read(&self) -> Option<String> {
+        let file = match tokio::fs::File::open(&self.host_path).await {
+            Ok(file) => file,
+            Err(e) => {
+                debug!(
+                    "Unable to open termination message {}: {}",
+                    self.host_path.display(),
+                    e
+                );
+                return None;
+            }
+        };
+        let mut message = Vec::new();
+        if let Err(e) = file
+            .take(MAX_MESSAGE_BYTES as u64)
+            .read_to_end(&mut message)
+            .await
+        {
+            debug!(
+                "Unable to read termination message {}: {}",
+                self.host_path.display(),
+                e
+            );
+            return None;
+        }
+        non_empty(String::from_utf8_lossy(&message).into_owned())
+    }
+
+    /// Whether the container's logs are reported if it terminated without writing a message.
+    pub fn falls_back_to_logs(&self, failed: bool) -> bool {
+        self.fallback_to_logs && failed
+    }
+}
+
+/// Remove the termination messages of the pod's containers, once the pod is gone.
+pub async fn remove_pod(data_dir: &Path, pod: &Pod) -> std::io::Result<()> {
+    match tokio::fs::remove_dir_all(pod_dir(data_dir, pod)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The message to report for a container, or `None` if it is only whitespace.
+fn non_empty(message: String) -> Option<String> {
+    if message.trim().is_empty() {
+        None
+    } else {
+        Some(message)
+    }
+}
+
+fn pod_dir(data_dir: &Path, pod: &Pod) -> PathBuf {
+    data_dir
+        .join(TERMINATION_DIR)
+        .join(format!("{}_{}", pod.namespace(), pod.name()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+    use k8s_openapi::api::core::v1::Container as KubeContainer;
+
+    fn container(path: Option<&str>, policy: Option<&str>) -> Container {
+        Container::new(&KubeContainer {
+            name: "app".to_owned(),
+            termination_message_path: path.map(str::to_owned),
+            termination_message_policy: policy.map(str::to_owned),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn message_is_kept_per_container() {
+        let message = TerminationMessage::new(
+            Path::new("/data"),
+            &PodBuilder::new().build(),
+            &container(None, None),
+        );
+        assert_eq!(
+            message.host_path,
+            Path::new("/data/termination/default_hello/app/termination-log")
+        );
+        assert_eq!(message.container_dir(), Some(Path::new("/dev")));
+
+        let message = TerminationMessage::new(
+            Path::new("/data"),
+            &PodBuilder::new().build(),
+            &container(Some("/tmp/status/reason"), None),
+        );
+        assert_eq!(
+            message.host_dir(),
+            Some(Path::new("/data/termination/default_hello/app"))
+        );
+        assert_eq!(message.container_dir(), Some(Path::new("/tmp/status")));
+    }
+
+    #[test]
+    fn only_failed_containers_fall_back_to_logs() {
+        let message = TerminationMessage::new(
+            Path::new("/data"),
+            &PodBuilder::new().build(),
+            &container(None, Some("FallbackToLogsOnError")),
+        );
+        assert!(message.falls_back_to_logs(true));
+        assert!(!message.falls_back_to_logs(false));
+
+        let message = TerminationMessage::new(
+            Path::new("/data"),
+            &PodBuilder::new().build(),
+            &container(None, Some("File")),
+        );
+        assert!(!message.falls_back_to_logs(true));
+    }
+
+    #[tokio::test]
+    async fn written_message_is_read_and_cut_short() {
+        let dir = tempfile::tempdir().unwrap();
+        let message = TerminationMessage::new(
+            dir.path(),
+            &PodBuilder::new().build(),
+            &container(None, None),
+        );
+        message.prepare().await.unwrap();
+        assert_eq!(message.read().await, None);
+
+        tokio::fs::write(&message.host_path, "x".repeat(MAX_MESSAGE_BYTES + 10))
+            .await
+            .unwrap();
+        assert_eq!(message.read().await.unwrap().len(), MAX_MESSAGE_BYTES);
+
+        message.prepare().await.unwrap();
+        assert_eq!(message.read().await, None);
+
+        remove_pod(dir.path(), &PodBuilder::new().build())
+            .await
+            .unwrap();
+        assert!(!dir
+            .path()
+            .join(TERMINATION_DIR)
+            .join("default_hello")
+            .exists());
+    }
+}
@@ -0,0 +1,671 @@
+//! Support for [device
+//! plugins](https://kubernetes.io/docs/concepts/extend-kubernetes/compute-storage-net/device-plugins/),
+//! which advertise hardware such as GPUs to the node as extended resources and prepare it for the
+//! containers it is allocated to.
+//!
+//! Device plugins register with the kubelet through a socket that the [`DeviceManager`] serves
+//! in the device plugin directory, or are discovered in the plugin registration directory by a
+//! [`PluginRegistry`](crate::plugin_watcher::PluginRegistry) that hands them to the manager. The
+//! manager then keeps track of each plugin's devices through
+//! `ListAndWatch`, reports them on the node, and allocates them to the containers of new pods
+//! through `Allocate`.
+use crate::admission::{Rejection, UNEXPECTED_ADMISSION_ERROR};
+use crate::container::Container;
+use crate::device_plugin_api::v1beta1::{
+    device_plugin_client::DevicePluginClient,
+    registration_server::{Registration, RegistrationServer},
+    AllocateRequest, ContainerAllocateRequest, ContainerAllocateResponse, Empty, RegisterRequest,
+    API_VERSION,
+};
+use crate::grpc_sock;
+use crate::pod::{Pod, PodKey};
+
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::api::{Api, PatchParams};
+use tokio::fs::create_dir_all;
+use tokio::sync::RwLock;
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info, warn};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The name of the socket in the device plugin directory that device plugins register through.
+const KUBELET_SOCKET: &str = "kubelet.sock";
+/// The health of a device that may be allocated.
+const HEALTHY: &str = "Healthy";
+
+/// A host path that is made available to a container at another path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceMount {
+    /// The path on the host.
+    pub host_path: PathBuf,
+    /// The path within the container.
+    pub container_path: PathBuf,
+    /// Whether the container may only read from the path.
+    pub read_only: bool,
+}
+
+/// What a container needs in order to use the devices allocated to it, as directed by their
+/// device plugins.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerDevices {
+    /// Environment variables to set in the container.
+    pub env: HashMap<String, String>,
+    /// Host directories to mount into the container, such as those holding device libraries.
+    pub mounts: Vec<DeviceMount>,
+    /// Host device nodes to make available to the container.
+    pub devices: Vec<DeviceMount>,
+}
+
+impl ContainerDevices {
+    fn extend(&mut self, response: ContainerAllocateResponse) {
+        self.env.extend(response.envs);
+        self.mounts
+            .extend(response.mounts.into_iter().map(|mount| DeviceMount {
+                host_path: PathBuf::from(mount.host_path),
+                container_path: PathBuf::from(mount.container_path),
+                read_only: mount.read_only,
+            }));
+        self.devices
+            .extend(response.devices.into_iter().map(|device| DeviceMount {
+                host_path: PathBuf::from(device.host_path),
+                container_path: PathBuf::from(device.container_path),
+                read_only: !device.permissions.contains('w'),
+            }));
+    }
+}
+
+/// A registered device plugin and the devices it last listed.
+struct Plugin {
+    /// Which registration this is, so that the end of a plugin's previous connection doesn't
+    /// remove it once it has registered again.
+    generation: u64,
+    client: DevicePluginClient<Channel>,
+    /// Whether each device is healthy, by device ID.
+    devices: HashMap<String, bool>,
+}
+
+/// The devices allocated to a pod.
+#[derive(Default)]
+struct PodDevices {
+    /// The IDs of the devices allocated to the pod, by resource name.
+    ids: HashMap<String, HashSet<String>>,
+    /// What each container needs in order to use its devices, by container name.
+    containers: HashMap<String, ContainerDevices>,
+}
+
+/// Keeps track of the device plugins registered with the kubelet, reports their devices on the
+/// node as extended resources, and allocates them to the containers of new pods. This implements
+/// much of the same functionality as the [device
+/// manager](https://github.com/kubernetes/kubernetes/tree/v1.19.2/pkg/kubelet/cm/devicemanager)
+/// in kubelet.
+pub struct DeviceManager {
+    plugin_dir: PathBuf,
+    client: kube::Client,
+    node_name: String,
+    plugins: RwLock<HashMap<String, Plugin>>,
+    allocated: RwLock<HashMap<PodKey, PodDevices>>,
+    generations: AtomicU64,
+}
+
+impl DeviceManager {
+    /// Returns a new device manager that serves device plugin registration in the given
+    /// directory, and reports devices on the given node.
+    pub fn new<P: AsRef<Path>>(plugin_dir: P, client: kube::Client, node_name: &str) -> Self {
+        DeviceManager {
+            plugin_dir: PathBuf::from(plugin_dir.as_ref()),
+            client,
+            node_name: node_name.to_owned(),
+            plugins: RwLock::new(HashMap::new()),
+            allocated: RwLock::new(HashMap::new()),
+            generations: AtomicU64::new(0),
+        }
+    }
+
+    /// Serves device plugin registration. This will block indefinitely or until the server
+    /// stops. Device plugins watch for the socket being created, so register again whenever
+    /// this starts.
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        create_dir_all(&self.plugin_dir).await?;
+
+        // A socket left behind by an earlier run has to be removed before it can be bound again
+        let socket_path = self.plugin_dir.join(KUBELET_SOCKET);
+        match tokio::fs::remove_file(&socket_path).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        let socket = grpc_sock::server::Socket::new(&socket_path)?;
+
+        Server::builder()
+            .add_service(RegistrationServer::new(Registrar(self)))
+            .serve_with_incoming(socket)
+            .await?;
+        Ok(())
+    }
+
+    /// Gets what each of the pod's containers needs in order to use the devices allocated to it,
+    /// by container name. Containers that weren't allocated any devices are left out.
+    pub async fn pod_devices(&self, pod: &Pod) -> HashMap<String, ContainerDevices> {
+        self.allocated
+            .read()
+            .await
+            .get(&PodKey::from(pod))
+            .map(|devices| devices.containers.clone())
+            .unwrap_or_default()
+    }
+
+    /// Allocates devices to the containers of a new pod that request resources managed by device
+    /// plugins, rejecting the pod if there aren't enough healthy devices left for it. Allocating
+    /// devices to a pod that already has them does nothing.
+    pub(crate) async fn allocate(&self, pod: &Pod) -> anyhow::Result<Result<(), Rejection>> {
+        let key = PodKey::from(pod);
+        let mut allocated = self.allocated.write().await;
+        if allocated.contains_key(&key) {
+            return Ok(Ok(()));
+        }
+        let plugins = self.plugins.read().await;
+
+        let mut pod_devices = PodDevices::default();
+        for container in pod.all_containers() {
+            for (resource, count) in device_requests(&container, &plugins)? {
+                let plugin = &plugins[&resource];
+                let in_use: HashSet<&String> = allocated
+                    .values()
+                    .chain(std::iter::once(&pod_devices))
+                    .filter_map(|devices| devices.ids.get(&resource))
+                    .flatten()
+                    .collect();
+                let mut available: Vec<String> = plugin
+                    .devices
+                    .iter()
+                    .filter(|(id, healthy)| **healthy && !in_use.contains(id))
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                if (available.len() as u64) < count {
+                    return Ok(Err(Rejection::new(
+                        UNEXPECTED_ADMISSION_ERROR,
+                        format!(
+                            "Allocate failed due to requested number of devices unavailable for {}. Requested: {}, Available: {}",
+                            resource,
+                            count,
+                            available.len()
+                        ),
+                    )));
+                }
+                available.sort();
+                available.truncate(count as usize);
+
+                debug!(
+                    "Allocating {} devices {:?} to container '{}' of pod '{}'",
+                    resource,
+                    available,
+                    container.name(),
+                    pod.name()
+                );
+                let request = Request::new(AllocateRequest {
+                    container_requests: vec![ContainerAllocateRequest {
+                        devices_i_ds: available.clone(),
+                    }],
+                });
+                let response = plugin
+                    .client
+                    .clone()
+                    .allocate(request)
+                    .await
+                    .map_err(|status| {
+                        anyhow::anyhow!(
+                            "Allocate call to the device plugin for {} failed with error code {} and message {}",
+                            resource,
+                            status.code(),
+                            status.message()
+                        )
+                    })?
+                    .into_inner();
+
+                let container_devices = pod_devices
+                    .containers
+                    .entry(container.name().to_owned())
+                    .or_default();
+                for response in response.container_responses {
+                    container_devices.extend(response);
+                }
+                pod_devices
+                    .ids
+                    .entry(resource)
+                    .or_default()
+                    .extend(available);
+            }
+        }
+
+        if !pod_devices.ids.is_empty() {
+            allocated.insert(key, pod_devices);
+        }
+        Ok(Ok(()))
+    }
+
+    /// Frees the devices allocated to a pod, so that they may be allocated to others.
+    pub(crate) async fn release(&self, pod: &Pod) {
+        self.allocated.write().await.remove(&PodKey::from(pod));
+    }
+
+    /// Checks that a device plugin registering itself can be used.
+    fn validate(&self, request: &RegisterRequest) -> anyhow::Result<()> {
+        if request.version != API_VERSION {
+            anyhow::bail!(
+                "Device plugin API version {} is not supported, only {} is",
+                request.version,
+                API_VERSION
+            );
+        }
+        validate_resource_name(&request.resource_name)
+    }
+
+    /// Checks that a device plugin discovered in the plugin registration directory, which
+    /// registers under the name of its resource, can be used.
+    pub(crate) fn validate_discovered(
+        &self,
+        resource_name: &str,
+        supported_versions: &[String],
+    ) -> anyhow::Result<()> {
+        if !supported_versions.iter().any(|v| v == API_VERSION) {
+            anyhow::bail!(
+                "Device plugin doesn't support version {}, only {:?}",
+                API_VERSION,
+                supported_versions
+            );
+        }
+        validate_resource_name(resource_name)
+    }
+
+    /// Starts keeping track of the devices of a plugin discovered in the plugin registration
+    /// directory, which serves the device plugin API at the given endpoint.
+    pub(crate) fn register_discovered(self: &Arc<Self>, resource_name: &str, endpoint: &Path) {
+        tokio::spawn(
+            self.clone()
+                .watch(resource_name.to_owned(), endpoint.to_owned()),
+        );
+    }
+
+    /// Keeps track of a registered device plugin's devices for as long as it is connected, then
+    /// removes it.
+    async fn watch(self: Arc<Self>, resource: String, endpoint: PathBuf) {
+        let generation = self.generations.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.list_and_watch(&resource, &endpoint, generation).await {
+            warn!(
+                "Lost connection to device plugin for {} at {}: {:?}",
+                resource,
+                endpoint.display(),
+                e
+            );
+        }
+
+        let removed = {
+            let mut plugins = self.plugins.write().await;
+            match plugins.get(&resource) {
+                Some(plugin) if plugin.generation == generation => {
+                    plugins.remove(&resource);
+                    true
+                }
+                _ => false,
+            }
+        };
+        if removed {
+            info!("Removed device plugin for {}", resource);
+            self.update_node(&resource).await;
+        }
+    }
+
+    async fn list_and_watch(
+        &self,
+        resource: &str,
+        endpoint: &Path,
+        generation: u64,
+    ) -> anyhow::Result<()> {
+        let channel = grpc_sock::client::socket_channel(endpoint).await?;
+        let mut client = DevicePluginClient::new(channel);
+        let mut stream = client
+            .list_and_watch(Request::new(Empty {}))
+            .await?
+            .into_inner();
+        self.plugins.write().await.insert(
+            resource.to_owned(),
+            Plugin {
+                generation,
+                client,
+                devices: HashMap::new(),
+            },
+        );
+        info!(
+            "Registered device plugin for {} at {}",
+            resource,
+            endpoint.display()
+        );
+
+        while let Some(response) = stream.message().await? {
+            let devices = response
+                .devices
+                .into_iter()
+                .map(|device| (device.id, device.health == HEALTHY))
+                .collect();
+            match self.plugins.write().await.get_mut(resource) {
+                Some(plugin) if plugin.generation == generation => plugin.devices = devices,
+                // The plugin has registered again, so this connection is no longer used
+                _ => return Ok(()),
+            }
+            self.update_node(resource).await;
+        }
+        Ok(())
+    }
+
+    /// Reports how many of the resource's devices the node has, and how many of those are healthy
+    /// enough to be allocated.
+    async fn update_node(&self, resource: &str) {
+        let (capacity, allocatable) = match self.plugins.read().await.get(resource) {
+            Some(plugin) => (
+                plugin.devices.len(),
+                plugin.devices.values().filter(|healthy| **healthy).count(),
+            ),
+            None => (0, 0),
+        };
+        debug!(
+            "Node '{}' has {} {} devices, {} of them allocatable",
+            self.node_name, capacity, resource, allocatable
+        );
+        let patch = serde_json::json!({
+            "status": {
+                "capacity": { resource: capacity.to_string() },
+                "allocatable": { resource: allocatable.to_string() },
+            }
+        });
+        let api: Api<KubeNode> = Api::all(self.client.clone());
+        let result = match serde_json::to_vec(&patch) {
+            Ok(data) => api
+                .patch_status(&self.node_name, &PatchParams::default(), data)
+                .await
+                .map(|_| ())
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Unable to report {} devices on node '{}': {:?}",
+                resource, self.node_name, e
+            );
+        }
+    }
+}
+
+/// Serves device plugin registration for a device manager.
+struct Registrar(Arc<DeviceManager>);
+
+#[tonic::async_trait]
+impl Registration for Registrar {
+    async fn register(&self, request: Request<RegisterRequest>) -> Result<Response<Empty>, Status> {
+        let request = request.into_inner();
+        if let Err(e) = self.0.validate(&request) {
+            error!(
+                "Refusing registration of device plugin for {}: {}",
+                request.resource_name, e
+            );
+            return Err(Status::invalid_argument(e.to_string()));
+        }
+        // The plugin is connected to once it has heard back that it is registered
+        let endpoint = self.0.plugin_dir.join(&request.endpoint);
+        tokio::spawn(self.0.clone().watch(request.resource_name, endpoint));
+        Ok(Response::new(Empty {}))
+    }
+}
+
+/// Checks that a device plugin's resource is an extended resource.
+fn validate_resource_name(resource_name: &str) -> anyhow::Result<()> {
+    if !is_extended_resource(resource_name) {
+        anyhow::bail!(
+            "Resource name {} is not an extended resource name, of the form <domain>/<name> outside of the kubernetes.io domain",
+            resource_name
+        );
+    }
+    Ok(())
+}
+
+/// How many devices of each resource managed by a device plugin a container requests. Extended
+/// resources can't be overcommitted, so are requested through their limits.
+fn device_requests(
+    container: &Container,
+    plugins: &HashMap<String, Plugin>,
+) -> anyhow::Result<Vec<(String, u64)>> {
+    let empty = BTreeMap::new();
+    let resources = container.resources();
+    let limits = resources
+        .and_then(|resources| resources.limits.as_ref())
+        .or_else(|| resources.and_then(|resources| resources.requests.as_ref()))
+        .unwrap_or(&empty);
+    limits
+        .iter()
+        .filter(|(resource, _)| plugins.contains_key(*resource))
+        .map(|(resource, quantity)| {
+            let count = quantity.0.parse::<u64>().map_err(|_| {
+                anyhow::anyhow!(
+                    "Container '{}' requests {} {}, which isn't a whole number of devices",
+                    container.name(),
+                    quantity.0,
+                    resource
+                )
+            })?;
+            Ok((resource.clone(), count))
+        })
+        .filter(|request| !matches!(request, Ok((_, 0))))
+        .collect()
+}
+
+/// Whether the resource is an extended resource, rather than one native to Kubernetes.
+fn is_extended_resource(name: &str) -> bool {
+    let mut parts = name.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(domain), Some(name)) => {
+            !domain.is_empty()
+                && !name.is_empty()
+                && domain != "kubernetes.io"
+                && !domain.ends_with(".kubernetes.io")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device_plugin_api::v1beta1::{
+        device_plugin_server::{DevicePlugin, DevicePluginServer},
+        registration_client::RegistrationClient,
+        AllocateResponse, Device, DevicePluginOptions, ListAndWatchResponse, Mount,
+        PreStartContainerRequest, PreStartContainerResponse, PreferredAllocationRequest,
+        PreferredAllocationResponse,
+    };
+    use crate::pod::builder::PodBuilder;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    const RESOURCE: &str = "example.com/gpu";
+
+    fn mock_client() -> kube::Client {
+        kube::Client::new(kube::Config::new(
+            reqwest::Url::parse("http://127.0.0.1:8080").unwrap(),
+        ))
+    }
+
+    /// A device plugin with two healthy GPUs and a broken one.
+    struct TestDevicePlugin;
+
+    #[tonic::async_trait]
+    impl DevicePlugin for TestDevicePlugin {
+        async fn get_device_plugin_options(
+            &self,
+            _request: Request<Empty>,
+        ) -> Result<Response<DevicePluginOptions>, Status> {
+            Ok(Response::new(DevicePluginOptions::default()))
+        }
+
+        type ListAndWatchStream = mpsc::Receiver<Result<ListAndWatchResponse, Status>>;
+
+        async fn list_and_watch(
+            &self,
+            _request: Request<Empty>,
+        ) -> Result<Response<Self::ListAndWatchStream>, Status> {
+            let (mut tx, rx) = mpsc::channel(1);
+            tokio::spawn(async move {
+                let device = |id: &str, health: &str| Device {
+                    id: id.to_owned(),
+                    health: health.to_owned(),
+                    topology: None,
+                };
+                let devices = vec![
+                    device("gpu-0", HEALTHY),
+                    device("gpu-1", HEALTHY),
+                    device("gpu-2", "Unhealthy"),
+                ];
+                tx.send(Ok(ListAndWatchResponse { devices })).await.ok();
+                // Keep the stream open, as a plugin that goes away is removed
+                futures::future::pending::<()>().await;
+            });
+            Ok(Response::new(rx))
+        }
+
+        async fn get_preferred_allocation(
+            &self,
+            _request: Request<PreferredAllocationRequest>,
+        ) -> Result<Response<PreferredAllocationResponse>, Status> {
+            Err(Status::unimplemented("no preferred allocation"))
+        }
+
+        async fn allocate(
+            &self,
+            request: Request<AllocateRequest>,
+        ) -> Result<Response<AllocateResponse>, Status> {
+            let container_responses = request
+                .into_inner()
+                .container_requests
+                .into_iter()
+                .map(|request| ContainerAllocateResponse {
+                    envs: vec![("GPUS".to_owned(), request.devices_i_ds.join(","))]
+                        .into_iter()
+                        .collect(),
+                    mounts: vec![Mount {
+                        container_path: "/gpu".to_owned(),
+                        host_path: "/opt/gpu".to_owned(),
+                        read_only: true,
+                    }],
+                    ..Default::default()
+                })
+                .collect();
+            Ok(Response::new(AllocateResponse {
+                container_responses,
+            }))
+        }
+
+        async fn pre_start_container(
+            &self,
+            _request: Request<PreStartContainerRequest>,
+        ) -> Result<Response<PreStartContainerResponse>, Status> {
+            Ok(Response::new(PreStartContainerResponse {}))
+        }
+    }
+
+    fn pod(name: &str, gpus: &str) -> Pod {
+        PodBuilder::new()
+            .name(name)
+            .spec(serde_json::json!({
+                "containers": [{
+                    "name": "app",
+                    "image": "hello:v1",
+                    "resources": { "limits": { RESOURCE: gpus } }
+                }]
+            }))
+            .build()
+    }
+
+    /// Starts a device manager and a test device plugin in a temporary directory, and registers
+    /// the plugin with the manager.
+    async fn setup() -> (tempfile::TempDir, Arc<DeviceManager>) {
+        let tempdir = tempfile::tempdir().expect("should be able to create tempdir");
+        let manager = Arc::new(DeviceManager::new(&tempdir, mock_client(), "node"));
+        tokio::spawn(manager.clone().run());
+
+        let socket = grpc_sock::server::Socket::new(&tempdir.path().join("gpu.sock"))
+            .expect("unable to setup server listening on socket");
+        tokio::spawn(
+            Server::builder()
+                .add_service(DevicePluginServer::new(TestDevicePlugin))
+                .serve_with_incoming(socket),
+        );
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+
+        let channel = grpc_sock::client::socket_channel(tempdir.path().join(KUBELET_SOCKET))
+            .await
+            .expect("should be able to connect to the kubelet socket");
+        RegistrationClient::new(channel)
+            .register(Request::new(RegisterRequest {
+                version: API_VERSION.to_owned(),
+                endpoint: "gpu.sock".to_owned(),
+                resource_name: RESOURCE.to_owned(),
+                options: None,
+            }))
+            .await
+            .expect("registration should succeed");
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+
+        (tempdir, manager)
+    }
+
+    #[tokio::test]
+    async fn healthy_devices_are_allocated_to_the_containers_requesting_them() {
+        let (_tempdir, manager) = setup().await;
+
+        let first = pod("first", "1");
+        manager.allocate(&first).await.unwrap().unwrap();
+        let devices = manager.pod_devices(&first).await;
+        assert_eq!(devices["app"].env["GPUS"], "gpu-0");
+        assert_eq!(
+            devices["app"].mounts[0].host_path,
+            PathBuf::from("/opt/gpu")
+        );
+
+        // Only one healthy device is left
+        let rejection = manager
+            .allocate(&pod("second", "2"))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(rejection.reason, UNEXPECTED_ADMISSION_ERROR);
+
+        manager.release(&first).await;
+        let second = pod("second", "2");
+        manager.allocate(&second).await.unwrap().unwrap();
+        assert_eq!(
+            manager.pod_devices(&second).await["app"].env["GPUS"],
+            "gpu-0,gpu-1"
+        );
+        assert!(manager.pod_devices(&first).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn plugins_must_use_a_supported_version_and_resource_name() {
+        let manager = DeviceManager::new("/nope", mock_client(), "node");
+        let request = |version: &str, resource_name: &str| RegisterRequest {
+            version: version.to_owned(),
+            endpoint: "gpu.sock".to_owned(),
+            resource_name: resource_name.to_owned(),
+            options: None,
+        };
+        assert!(manager.validate(&request(API_VERSION, RESOURCE)).is_ok());
+        assert!(manager.validate(&request("v1alpha", RESOURCE)).is_err());
+        assert!(manager.validate(&request(API_VERSION, "gpu")).is_err());
+        assert!(manager
+            .validate(&request(API_VERSION, "kubernetes.io/gpu"))
+            .is_err());
+    }
+}
@@ -0,0 +1,375 @@
+//! DNS configuration for pods.
+//!
+//! Each pod is given a `resolv.conf` file worked out from its `dnsPolicy` and `dnsConfig`, as
+//! the Kubernetes kubelet does:
+//!
+//! * `ClusterFirst`, the default, resolves names with the cluster's DNS service, searching the
+//!   pod's namespace, the cluster's services and the cluster's domain before the node's search
+//!   domains. Pods using the host's network use the node's configuration instead.
+//! * `ClusterFirstWithHostNet` is the same, but applies to pods using the host's network too.
+//! * `Default` uses the node's configuration.
+//! * `None` uses nothing but the pod's `dnsConfig`.
+//!
+//! The pod's `dnsConfig` is added to what its policy gives it. The file is kept in the kubelet's
+//! data directory for as long as the pod is, and providers give it to the pod's containers from
+//! there, which they find with [`resolv_conf_path`].
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use k8s_openapi::api::core::v1::PodDNSConfig;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::pod::Pod;
+
+/// The environment variable that tells a container where its `resolv.conf` file is.
+pub const RESOLV_CONF_ENV: &str = "RESOLV_CONF";
+
+/// The directory under the kubelet's data directory that pods' DNS configuration is kept in.
+const DNS_DIR: &str = "dns";
+const RESOLV_CONF_FILE: &str = "resolv.conf";
+
+/// The most nameservers and search domains resolvers take, beyond which they are ignored.
+const MAX_NAMESERVERS: usize = 3;
+const MAX_SEARCHES: usize = 6;
+
+/// How many dots a name needs before it is tried as it is before the search domains, which is
+/// high enough that names like `service.namespace.svc` are searched for in the cluster first.
+const CLUSTER_NDOTS: &str = "5";
+
+/// The contents of a `resolv.conf` file.
+#[derive(Debug, Default, PartialEq)]
+struct ResolvConf {
+    nameservers: Vec<String>,
+    searches: Vec<String>,
+    /// Options by name, with their values if they have one
+    options: Vec<(String, Option<String>)>,
+}
+
+impl ResolvConf {
+    fn parse(contents: &str) -> Self {
+        let mut conf = ResolvConf::default();
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("nameserver") => conf.nameservers.extend(fields.next().map(str::to_owned)),
+                // The last search or domain line wins, as it does for resolvers
+                Some("search") | Some("domain") => {
+                    conf.searches = fields.map(str::to_owned).collect();
+                }
+                Some("options") => {
+                    for option in fields {
+                        let mut parts = option.splitn(2, ':');
+                        let name = parts.next().unwrap_or_default();
+                        conf.set_option(name, parts.next());
+                    }
+                }
+                _ => (),
+            }
+        }
+        conf
+    }
+
+    fn set_option(&mut self, name: &str, value: Option<&str>) {
+        let value = value.map(str::to_owned);
+        match self
+            .options
+            .iter_mut()
+            .find(|(existing, _)| existing == name)
+        {
+            Some(option) => option.1 = value,
+            None => self.options.push((name.to_owned(), value)),
+        }
+    }
+
+    /// Add a pod's own DNS configuration, whose options replace any of the same name.
+    fn merge(&mut self, dns_config: &PodDNSConfig) {
+        for nameserver in dns_config.nameservers.iter().flatten() {
+            if !self.nameservers.contains(nameserver) {
+                self.nameservers.push(nameserver.clone());
+            }
+        }
+        for search in dns_config.searches.iter().flatten() {
+            if !self.searches.contains(search) {
+                self.searches.push(search.clone());
+            }
+        }
+        for option in dns_config.options.iter().flatten() {
+            match option.name.as_deref() {
+                Some(name) if !name.is_empty() => self.set_option(name, option.value.as_deref()),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl fmt::Display for ResolvConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for nameserver in &self.nameservers {
+            writeln!(f, "nameserver {}", nameserver)?;
+        }
+        if !self.searches.is_empty() {
+            writeln!(f, "search {}", self.searches.join(" "))?;
+        }
+        if !self.options.is_empty() {
+            let options: Vec<String> = self
+                .options
+                .iter()
+                .map(|(name, value)| match value {
+                    Some(value) => format!("{}:{}", name, value),
+                    None => name.clone(),
+                })
+                .collect();
+            writeln!(f, "options {}", options.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes and removes the DNS configuration of pods.
+pub(crate) struct Dns {
+    cluster_dns: Vec<IpAddr>,
+    cluster_domain: Option<String>,
+    resolv_conf: PathBuf,
+    dir: PathBuf,
+}
+
+impl Dns {
+    pub(crate) fn new(config: &Config) -> Self {
+        Dns {
+            cluster_dns: config.cluster_dns.clone(),
+            cluster_domain: config.cluster_domain.clone(),
+            resolv_conf: config.resolv_conf.clone(),
+            dir: config.data_dir.join(DNS_DIR),
+        }
+    }
+
+    /// Write the pod's `resolv.conf` file, replacing any it already has.
+    pub(crate) async fn setup(&self, pod: &Pod) -> anyhow::Result<()> {
+        // A node without a resolver configuration leaves pods with only what they ask for
+        let host = match tokio::fs::read_to_string(&self.resolv_conf).await {
+            Ok(contents) => ResolvConf::parse(&contents),
+            Err(e) => {
+                debug!(
+                    "Unable to read node resolver configuration {}: {}",
+                    self.resolv_conf.display(),
+                    e
+                );
+                ResolvConf::default()
+            }
+        };
+        let conf = self.for_pod(pod, host);
+        let dir = self.dir.join(pod_dir_name(pod));
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(dir.join(RESOLV_CONF_FILE), conf.to_string()).await?;
+        Ok(())
+    }
+
+    /// Remove the pod's `resolv.conf` file, if it has one.
+    pub(crate) async fn teardown(&self, pod: &Pod) -> anyhow::Result<()> {
+        match tokio::fs::remove_dir_all(self.dir.join(pod_dir_name(pod))).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn for_pod(&self, pod: &Pod, host: ResolvConf) -> ResolvConf {
+        let spec = pod.as_kube_pod().spec.as_ref();
+        let policy = spec
+            .and_then(|spec| spec.dns_policy.as_deref())
+            .unwrap_or("ClusterFirst");
+        let mut conf = match policy {
+            "None" => ResolvConf::default(),
+            "Default" => host,
+            "ClusterFirstWithHostNet" => self.cluster(pod, host),
+            _ if pod.host_network() => host,
+            _ => self.cluster(pod, host),
+        };
+        if let Some(dns_config) = spec.and_then(|spec| spec.dns_config.as_ref()) {
+            conf.merge(dns_config);
+        }
+        if conf.nameservers.len() > MAX_NAMESERVERS || conf.searches.len() > MAX_SEARCHES {
+            warn!(
+                "Pod {} has more than {} nameservers or {} search domains, so the rest are left out",
+                pod.name(),
+                MAX_NAMESERVERS,
+                MAX_SEARCHES
+            );
+            conf.nameservers.truncate(MAX_NAMESERVERS);
+            conf.searches.truncate(MAX_SEARCHES);
+        }
+        conf
+    }
+
+    fn cluster(&self, pod: &Pod, host: ResolvConf) -> ResolvConf {
+        if self.cluster_dns.is_empty() {
+            warn!(
+                "Pod {} resolves names with the cluster's DNS service, but none is configured, so it uses the node's DNS configuration",
+                pod.name()
+            );
+            return host;
+        }
+        let mut searches = match &self.cluster_domain {
+            Some(domain) => vec![
+                format!("{}.svc.{}", pod.namespace(), domain),
+                format!("svc.{}", domain),
+                domain.clone(),
+            ],
+            None => vec![],
+        };
+        for search in host.searches {
+            if !searches.contains(&search) {
+                searches.push(search);
+            }
+        }
+        ResolvConf {
+            nameservers: self.cluster_dns.iter().map(ToString::to_string).collect(),
+            searches,
+            options: vec![("ndots".to_owned(), Some(CLUSTER_NDOTS.to_owned()))],
+        }
+    }
+}
+
+/// The pod's `resolv.conf` file, if it has been given one, for a kubelet with the given data
+/// directory. The file is alone in its directory, so that the directory can be given to the
+/// pod's containers as their `/etc`.
+pub fn resolv_conf_path(data_dir: &Path, pod: &Pod) -> Option<PathBuf> {
+    let path = data_dir
+        .join(DNS_DIR)
+        .join(pod_dir_name(pod))
+        .join(RESOLV_CONF_FILE);
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn pod_dir_name(pod: &Pod) -> String {
+    format!("{}_{}", pod.namespace(), pod.name())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+
+    fn dns(cluster_dns: &[&str]) -> Dns {
+        Dns {
+            cluster_dns: cluster_dns.iter().map(|ip| ip.parse().unwrap()).collect(),
+            cluster_domain: Some("cluster.local".to_owned()),
+            resolv_conf: PathBuf::new(),
+            dir: PathBuf::new(),
+        }
+    }
+
+    fn host() -> ResolvConf {
+        ResolvConf::parse(
+            "# generated\nnameserver 192.168.0.1\nnameserver 192.168.0.2\nsearch lan\noptions edns0 timeout:2\n",
+        )
+    }
+
+    #[test]
+    fn node_resolver_configuration_is_parsed() {
+        assert_eq!(
+            host(),
+            ResolvConf {
+                nameservers: vec!["192.168.0.1".to_owned(), "192.168.0.2".to_owned()],
+                searches: vec!["lan".to_owned()],
+                options: vec![
+                    ("edns0".to_owned(), None),
+                    ("timeout".to_owned(), Some("2".to_owned()))
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn cluster_first_pods_resolve_with_the_cluster_dns_service() {
+        let conf =
+            dns(&["10.96.0.10"]).for_pod(&PodBuilder::new().namespace("web").build(), host());
+        assert_eq!(
+            conf.to_string(),
+            "nameserver 10.96.0.10\nsearch web.svc.cluster.local svc.cluster.local cluster.local lan\noptions ndots:5\n"
+        );
+    }
+
+    #[test]
+    fn host_network_pods_use_the_node_configuration_unless_they_ask_for_the_cluster() {
+        let dns = dns(&["10.96.0.10"]);
+        let conf = dns.for_pod(
+            &PodBuilder::new()
+                .namespace("web")
+                .spec(serde_json::json!({ "hostNetwork": true }))
+                .build(),
+            host(),
+        );
+        assert_eq!(conf, host());
+
+        let conf = dns.for_pod(
+            &PodBuilder::new()
+                .namespace("web")
+                .spec(serde_json::json!({
+                    "hostNetwork": true,
+                    "dnsPolicy": "ClusterFirstWithHostNet"
+                }))
+                .build(),
+            host(),
+        );
+        assert_eq!(conf.nameservers, vec!["10.96.0.10".to_owned()]);
+    }
+
+    #[test]
+    fn cluster_first_pods_use_the_node_configuration_without_a_cluster_dns_service() {
+        let conf = dns(&[]).for_pod(&PodBuilder::new().namespace("web").build(), host());
+        assert_eq!(conf, host());
+    }
+
+    #[test]
+    fn pod_dns_config_is_added_to_the_policy() {
+        let conf = dns(&["10.96.0.10"]).for_pod(
+            &PodBuilder::new()
+                .namespace("web")
+                .spec(serde_json::json!({
+                    "dnsPolicy": "None",
+                    "dnsConfig": {
+                        "nameservers": ["1.1.1.1"],
+                        "searches": ["example.com"],
+                        "options": [{ "name": "ndots", "value": "2" }, { "name": "edns0" }]
+                    }
+                }))
+                .build(),
+            host(),
+        );
+        assert_eq!(
+            conf.to_string(),
+            "nameserver 1.1.1.1\nsearch example.com\noptions ndots:2 edns0\n"
+        );
+
+        let conf = dns(&["10.96.0.10"]).for_pod(
+            &PodBuilder::new()
+                .namespace("web")
+                .spec(serde_json::json!({
+                    "dnsConfig": {
+                        "nameservers": ["1.1.1.1", "8.8.8.8", "9.9.9.9"],
+                        "options": [{ "name": "ndots", "value": "1" }]
+                    }
+                }))
+                .build(),
+            host(),
+        );
+        assert_eq!(
+            conf.nameservers,
+            vec![
+                "10.96.0.10".to_owned(),
+                "1.1.1.1".to_owned(),
+                "8.8.8.8".to_owned()
+            ]
+        );
+        assert_eq!(
+            conf.options,
+            vec![("ndots".to_owned(), Some("1".to_owned()))]
+        );
+    }
+}
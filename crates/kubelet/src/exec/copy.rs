@@ -0,0 +1,304 @@
+//! Copying files into and out of containers with `kubectl cp`.
+//!
+//! `kubectl cp` runs `tar` in the container through exec, writing an archive of the files to
+//! copy into its stdin or reading one from its stdout. Containers that have no `tar` to run can
+//! still be copied to and from by implementing [`ExecHandler::copy_from`] and
+//! [`ExecHandler::copy_to`], which are called in place of running the commands `kubectl cp`
+//! sends, as recognized by [`CopyRequest::parse`]. The helpers here archive and extract files
+//! of the host directories that back the container's volumes.
+//!
+//! [`ExecHandler::copy_from`]: crate::handle::ExecHandler::copy_from
+//! [`ExecHandler::copy_to`]: crate::handle::ExecHandler::copy_to
+use std::io::{BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use tokio::sync::mpsc;
+
+use super::{Sender, Stdin};
+use crate::provider::ProviderError;
+
+/// How many bytes of an archive are sent to the client at a time.
+const ARCHIVE_CHUNK_SIZE: usize = 32 * 1024;
+/// How many chunks of an archive from the client are buffered before it is read from the
+/// client no faster than it is extracted.
+const EXTRACT_BUFFER_SIZE: usize = 16;
+
+/// A copy that `kubectl cp` asked for by running `tar` in a container.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CopyRequest {
+    /// Copy the file or directory at this path out of the container, as an archive written to
+    /// stdout. This is what `tar cf - <path>` does.
+    From(PathBuf),
+    /// Copy the files of an archive read from stdin into this directory of the container. This
+    /// is what `tar xf - [-C <dir>]` does, extracting into the root if no directory is given.
+    To(PathBuf),
+}
+
+impl CopyRequest {
+    /// The copy the command asks for, if it is one of the `tar` commands that `kubectl cp` runs.
+    /// Other commands, including `tar` commands with options it doesn't use, give `None`.
+    pub fn parse(command: &[String]) -> Option<Self> {
+        let (program, args) = command.split_first()?;
+        let (flags, args) = args.split_first()?;
+        let (file, args) = args.split_first()?;
+        let flags = flags.trim_start_matches('-');
+        // The archive is always read from stdin or written to stdout
+        if program != "tar"
+            || file != "-"
+            || !flags.ends_with('f')
+            || !flags.chars().all(|flag| "cxmf".contains(flag))
+        {
+            return None;
+        }
+        match (flags.contains('c'), flags.contains('x'), args) {
+            (true, false, [path]) => Some(CopyRequest::From(PathBuf::from(path))),
+            (false, true, []) => Some(CopyRequest::To(PathBuf::from("/"))),
+            (false, true, [option, dir]) if option == "-C" => {
+                Some(CopyRequest::To(PathBuf::from(dir)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A directory of the host that a container sees at another path, such as one of its volumes.
+#[derive(Clone, Debug)]
+pub struct Mount {
+    /// Where the directory is on the host.
+    pub host_path: PathBuf,
+    /// Where the container sees the directory.
+    pub container_path: PathBuf,
+    /// Whether the container may only read from the directory.
+    pub read_only: bool,
+}
+
+/// Where on the host a path in the container is, along with the mount it is in. Relative paths
+/// are taken to be relative to the root of the container. Paths that go up a directory, or that
+/// aren't in any of the mounts, are refused.
+pub fn resolve<'a>(
+    mounts: &'a [Mount],
+    path: &Path,
+) -> Result<(PathBuf, &'a Mount), ProviderError> {
+    let path = normalize(path)?;
+    mounts
+        .iter()
+        .filter_map(|mount| {
+            path.strip_prefix(&mount.container_path)
+                .ok()
+                .map(|rest| (mount, rest))
+        })
+        .max_by_key(|(mount, _)| mount.container_path.components().count())
+        .map(|(mount, rest)| (mount.host_path.join(rest), mount))
+        .ok_or_else(|| {
+            ProviderError::InvalidRequest(format!(
+                "{} is not in any of the container's volumes",
+                path.display()
+            ))
+        })
+}
+
+/// The path as an absolute path with no `.` components.
+fn normalize(path: &Path) -> Result<PathBuf, ProviderError> {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(ProviderError::InvalidRequest(format!(
+                    "unable to copy {}, paths may not go up a directory",
+                    path.display()
+                )))
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+/// Send an archive of the file or directory at `host_path` to the client's stdout, as `tar`
+/// would archive it at `path` in the container: named by its path without the leading `/`.
+pub async fn send_archive(host_path: PathBuf, path: &Path, sender: Sender) -> anyhow::Result<()> {
+    let name: PathBuf = normalize(path)?.components().skip(1).collect();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let writer = BufWriter::with_capacity(ARCHIVE_CHUNK_SIZE, SenderWriter(sender));
+        let mut archive = tar::Builder::new(writer);
+        if host_path.is_dir() {
+            archive.append_dir_all(&name, &host_path)?;
+        } else {
+            archive.append_path_with_name(&host_path, &name)?;
+        }
+        archive.into_inner()?.flush()?;
+        Ok(())
+    })
+    .await?
+}
+
+/// Extract the archive the client writes to stdin into the directory at `host_dir`. Entries that
+/// would be extracted outside of the directory are skipped.
+pub async fn receive_archive(stdin: &mut Stdin, host_dir: PathBuf) -> anyhow::Result<()> {
+    let (mut tx, rx) = mpsc::channel(EXTRACT_BUFFER_SIZE);
+    let mut extract = tokio::task::spawn_blocking(move || {
+        tar::Archive::new(ReceiverReader {
+            receiver: rx,
+            chunk: Vec::new(),
+            read: 0,
+        })
+        .unpack(&host_dir)
+    });
+    loop {
+        tokio::select! {
+            // The archive ends before the client closes stdin, if it closes it at all
+            result = &mut extract => return Ok(result??),
+            chunk = stdin.recv() => match chunk {
+                Some(chunk) => {
+                    if tx.send(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+    drop(tx);
+    Ok(extract.await??)
+}
+
+/// Writes to the client's stdout from a blocking thread.
+struct SenderWriter(Sender);
+
+impl Write for SenderWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        futures::executor::block_on(self.0.stdout(buf))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e.to_string()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads chunks of data from a channel on a blocking thread.
+struct ReceiverReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    /// How much of the chunk has been read
+    read: usize,
+}
+
+impl Read for ReceiverReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.read == self.chunk.len() {
+            match futures::executor::block_on(self.receiver.recv()) {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.read = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.read);
+        buf[..n].copy_from_slice(&self.chunk[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exec::ExecOutput;
+
+    fn command(command: &str) -> Vec<String> {
+        command.split(' ').map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn kubectl_cp_commands_are_recognized() {
+        assert_eq!(
+            CopyRequest::parse(&command("tar cf - /data/file")),
+            Some(CopyRequest::From(PathBuf::from("/data/file")))
+        );
+        assert_eq!(
+            CopyRequest::parse(&command("tar -xmf - -C /data")),
+            Some(CopyRequest::To(PathBuf::from("/data")))
+        );
+        assert_eq!(
+            CopyRequest::parse(&command("tar xf -")),
+            Some(CopyRequest::To(PathBuf::from("/")))
+        );
+        assert_eq!(CopyRequest::parse(&command("tar czf - /data")), None);
+        assert_eq!(
+            CopyRequest::parse(&command("tar cf archive.tar /data")),
+            None
+        );
+        assert_eq!(CopyRequest::parse(&command("ls /data")), None);
+    }
+
+    #[test]
+    fn paths_resolve_to_the_volume_they_are_in() {
+        let mounts = vec![
+            Mount {
+                host_path: PathBuf::from("/var/lib/volumes/data"),
+                container_path: PathBuf::from("/data"),
+                read_only: false,
+            },
+            Mount {
+                host_path: PathBuf::from("/var/lib/volumes/config"),
+                container_path: PathBuf::from("/data/config"),
+                read_only: true,
+            },
+        ];
+        let (host_path, mount) = resolve(&mounts, Path::new("/data/./logs/app.log")).unwrap();
+        assert_eq!(host_path, Path::new("/var/lib/volumes/data/logs/app.log"));
+        assert!(!mount.read_only);
+        let (host_path, mount) = resolve(&mounts, Path::new("data/config")).unwrap();
+        assert_eq!(host_path, Path::new("/var/lib/volumes/config"));
+        assert!(mount.read_only);
+        assert!(resolve(&mounts, Path::new("/data/../etc/passwd")).is_err());
+        assert!(resolve(&mounts, Path::new("/etc")).is_err());
+    }
+
+    #[tokio::test]
+    async fn archives_are_extracted_where_they_were_sent_from() {
+        let from = tempfile::tempdir().unwrap();
+        std::fs::create_dir(from.path().join("logs")).unwrap();
+        std::fs::write(from.path().join("logs/app.log"), "hello\n").unwrap();
+
+        let (output_tx, mut output_rx) = mpsc::channel(1);
+        let send = send_archive(
+            from.path().join("logs"),
+            Path::new("/data/logs"),
+            Sender::new(output_tx),
+        );
+        let collect = async {
+            let mut archive = vec![];
+            while let Some(output) = output_rx.recv().await {
+                match output {
+                    ExecOutput::Stdout(data) => archive.extend(data),
+                    other => panic!("unexpected output {:?}", other),
+                }
+            }
+            archive
+        };
+        let (sent, archive) = tokio::join!(send, collect);
+        sent.unwrap();
+
+        let to = tempfile::tempdir().unwrap();
+        // The archive is extracted once its end is read, which isn't the end of what was sent
+        let (mut stdin_tx, stdin_rx) = mpsc::channel(archive.len() / 100 + 1);
+        let mut stdin = Stdin::new(stdin_rx);
+        let receive = receive_archive(&mut stdin, to.path().to_owned());
+        let write = async move {
+            for chunk in archive.chunks(100) {
+                stdin_tx.send(chunk.to_vec()).await.ok();
+            }
+        };
+        let (received, _) = tokio::join!(receive, write);
+        received.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(to.path().join("data/logs/app.log")).unwrap(),
+            "hello\n"
+        );
+    }
+}
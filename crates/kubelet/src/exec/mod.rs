@@ -0,0 +1,119 @@
+//! `exec` contains types for running commands in a workload and streaming their input and output
+//! to and from a client.
+use std::pin::Pin;
+use std::task::Poll;
+
+use futures::Stream;
+use tokio::sync::mpsc;
+
+pub mod copy;
+
+/// Options describing a command a client has asked to execute.
+#[derive(Clone, Debug, Default)]
+pub struct CommandOptions {
+    /// The command and its arguments.
+    pub command: Vec<String>,
+    /// Whether the client will stream data to the command's stdin.
+    pub stdin: bool,
+    /// Whether the client wants the command's stdout.
+    pub stdout: bool,
+    /// Whether the client wants the command's stderr.
+    pub stderr: bool,
+    /// Whether the command should be run in a TTY. When set, stderr is merged into stdout.
+    pub tty: bool,
+}
+
+/// The result of a command that ran to completion.
+#[derive(Clone, Debug, Default)]
+pub struct ExecResult {
+    /// Everything the command wrote to its output.
+    pub output: String,
+    /// The code the command exited with.
+    pub exit_code: i32,
+}
+
+/// A chunk of output produced by a command.
+#[derive(Debug, PartialEq)]
+pub enum ExecOutput {
+    /// Data written to stdout.
+    Stdout(Vec<u8>),
+    /// Data written to stderr.
+    Stderr(Vec<u8>),
+    /// The command exited with the given exit code. This is the last item sent for a command.
+    Exit(i32),
+}
+
+/// A stream of output from a running command, ending with its [`ExecOutput::Exit`] status.
+pub type ExecStream = Pin<Box<dyn Stream<Item = ExecOutput> + Send>>;
+
+/// Receiver for data the client writes to a command's stdin.
+pub struct Stdin {
+    receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Stdin {
+    /// Create new `Stdin` from the receiving half of a channel.
+    pub fn new(receiver: mpsc::Receiver<Vec<u8>>) -> Self {
+        Stdin { receiver }
+    }
+
+    /// Receive the next chunk of data from the client. Returns `None` once the client has closed
+    /// its stdin stream.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.receiver.recv().await
+    }
+}
+
+/// Sender for streaming command output to the client.
+#[derive(Clone)]
+pub struct Sender {
+    sender: mpsc::Sender<ExecOutput>,
+}
+
+impl Sender {
+    /// Create new `Sender` from the sending half of a channel.
+    pub fn new(sender: mpsc::Sender<ExecOutput>) -> Self {
+        Sender { sender }
+    }
+
+    /// Async send some data to the client's stdout stream.
+    pub async fn stdout<D: Into<Vec<u8>>>(&mut self, data: D) -> anyhow::Result<()> {
+        self.send(ExecOutput::Stdout(data.into())).await
+    }
+
+    /// Async send some data to the client's stderr stream.
+    pub async fn stderr<D: Into<Vec<u8>>>(&mut self, data: D) -> anyhow::Result<()> {
+        self.send(ExecOutput::Stderr(data.into())).await
+    }
+
+    /// Async send the exit code of the command to the client.
+    pub async fn exit(&mut self, code: i32) -> anyhow::Result<()> {
+        self.send(ExecOutput::Exit(code)).await
+    }
+
+    /// Async send a chunk of output to the client.
+    pub async fn send(&mut self, output: ExecOutput) -> anyhow::Result<()> {
+        self.sender
+            .send(output)
+            .await
+            .map_err(|_| anyhow::anyhow!("exec session was closed by the client"))
+    }
+
+    /// Whether the client has gone away.
+    pub async fn is_closed(&mut self) -> bool {
+        let sender = &mut self.sender;
+        futures::future::poll_fn(|cx| {
+            let closed = match sender.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    // Give back the slot polling reserved, so a later poll checks again
+                    sender.disarm();
+                    false
+                }
+                Poll::Ready(Err(_)) => true,
+                Poll::Pending => false,
+            };
+            Poll::Ready(closed)
+        })
+        .await
+    }
+}
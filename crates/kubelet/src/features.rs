@@ -0,0 +1,209 @@
+//! Feature gates, which turn kubelet functionality that is still experimental on or off.
+//!
+//! Each [`Feature`] is at a [`Stage`]: alpha features are disabled unless they are turned on,
+//! and beta features are enabled unless they are turned off. Gates are set with
+//! `--feature-gates=Name=true,Other=false`, or the `featureGates` map of the configuration file,
+//! and apply to the whole kubelet once it is created. Code that branches into gated
+//! functionality checks [`enabled`] first.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// Functionality that can be turned on or off with a feature gate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Feature {
+    /// Publishing volumes for PersistentVolumeClaims through CSI node plugins.
+    CsiVolumes,
+    /// Streaming the input and output of commands run with exec as they run. When disabled,
+    /// commands get no input and their output is sent once they have completed.
+    StreamingExec,
+    /// Letting wasi modules open sockets to the destinations a pod allows, through the
+    /// sockets shim.
+    WasiSockets,
+}
+
+/// How mature a feature is, which decides whether it is enabled by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Experimental, and disabled unless turned on.
+    Alpha,
+    /// Well tested, and enabled unless turned off.
+    Beta,
+}
+
+impl Feature {
+    /// Every feature that has a gate.
+    pub const ALL: &'static [Feature] = &[
+        Feature::CsiVolumes,
+        Feature::StreamingExec,
+        Feature::WasiSockets,
+    ];
+
+    /// The name the feature's gate is set by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::CsiVolumes => "CsiVolumes",
+            Feature::StreamingExec => "StreamingExec",
+            Feature::WasiSockets => "WasiSockets",
+        }
+    }
+
+    /// How mature the feature is.
+    pub fn stage(self) -> Stage {
+        match self {
+            Feature::CsiVolumes => Stage::Alpha,
+            Feature::StreamingExec => Stage::Beta,
+            Feature::WasiSockets => Stage::Alpha,
+        }
+    }
+
+    /// Whether the feature is enabled when its gate isn't set.
+    pub fn enabled_by_default(self) -> bool {
+        self.stage() == Stage::Beta
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Feature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Feature::ALL
+            .iter()
+            .copied()
+            .find(|feature| feature.name() == s)
+            .ok_or_else(|| {
+                let known: Vec<&str> = Feature::ALL.iter().map(|f| f.name()).collect();
+                anyhow::anyhow!(
+                    "unrecognized feature gate {}, known gates are {}",
+                    s,
+                    known.join(", ")
+                )
+            })
+    }
+}
+
+/// Which features have been turned on or off. Features whose gates aren't set are enabled if
+/// they are [enabled by default](Feature::enabled_by_default).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FeatureGates {
+    gates: BTreeMap<Feature, bool>,
+}
+
+impl FeatureGates {
+    /// Turn a feature on or off.
+    pub fn set(&mut self, feature: Feature, enabled: bool) {
+        self.gates.insert(feature, enabled);
+    }
+
+    /// Whether a feature is enabled.
+    pub fn enabled(&self, feature: Feature) -> bool {
+        self.gates
+            .get(&feature)
+            .copied()
+            .unwrap_or_else(|| feature.enabled_by_default())
+    }
+
+    /// The gates set in a map from feature names to whether they are enabled, as in the
+    /// configuration file.
+    pub fn from_map(gates: HashMap<String, bool>) -> anyhow::Result<Self> {
+        let mut feature_gates = FeatureGates::default();
+        for (name, enabled) in gates {
+            feature_gates.set(name.parse()?, enabled);
+        }
+        Ok(feature_gates)
+    }
+}
+
+impl FromStr for FeatureGates {
+    type Err = anyhow::Error;
+
+    /// Parses gates given as `Name=bool` pairs separated by `,`, as in `--feature-gates`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut feature_gates = FeatureGates::default();
+        for gate in s.split(',').map(str::trim).filter(|gate| !gate.is_empty()) {
+            let mut parts = gate.splitn(2, '=');
+            let feature = parts.next().unwrap_or_default().trim().parse()?;
+            let enabled = match parts.next().map(str::trim) {
+                Some(value) => value.parse().map_err(|_| {
+                    anyhow::anyhow!(
+                        "invalid value {} for feature gate {}, must be true or false",
+                        value,
+                        feature
+                    )
+                })?,
+                None => anyhow::bail!("missing value for feature gate {}", feature),
+            };
+            feature_gates.set(feature, enabled);
+        }
+        Ok(feature_gates)
+    }
+}
+
+impl Serialize for FeatureGates {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.gates
+                .iter()
+                .map(|(feature, enabled)| (feature.name(), enabled)),
+        )
+    }
+}
+
+lazy_static! {
+    static ref GATES: RwLock<FeatureGates> = RwLock::new(FeatureGates::default());
+}
+
+/// Set the gates that [`enabled`] checks. This is done when the kubelet is created, from its
+/// configuration.
+pub fn set_gates(gates: FeatureGates) {
+    *GATES.write().unwrap() = gates;
+}
+
+/// Whether a feature is enabled by the gates the kubelet was configured with.
+pub fn enabled(feature: Feature) -> bool {
+    GATES.read().unwrap().enabled(feature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gates_fall_back_to_their_stage() {
+        let gates = FeatureGates::default();
+        assert!(!gates.enabled(Feature::WasiSockets));
+        assert!(gates.enabled(Feature::StreamingExec));
+    }
+
+    #[test]
+    fn gates_are_parsed_from_flags() {
+        let gates: FeatureGates = "WasiSockets=true, StreamingExec=false,".parse().unwrap();
+        assert!(gates.enabled(Feature::WasiSockets));
+        assert!(!gates.enabled(Feature::StreamingExec));
+        assert!(!gates.enabled(Feature::CsiVolumes));
+
+        assert!("Sockets=true".parse::<FeatureGates>().is_err());
+        assert!("WasiSockets=yes".parse::<FeatureGates>().is_err());
+        assert!("WasiSockets".parse::<FeatureGates>().is_err());
+    }
+
+    #[test]
+    fn gates_are_serialized_by_name() {
+        let mut gates = FeatureGates::default();
+        gates.set(Feature::CsiVolumes, true);
+        assert_eq!(
+            serde_json::to_value(&gates).unwrap(),
+            serde_json::json!({ "CsiVolumes": true })
+        );
+    }
+}
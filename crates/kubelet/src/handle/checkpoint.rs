@@ -0,0 +1,17 @@
+use std::path::Path;
+
+use crate::provider::ProviderError;
+
+/// A [`CheckpointHandler`] is used to snapshot a running process, so that it can be restored
+/// later.
+#[async_trait::async_trait]
+pub trait CheckpointHandler {
+    /// Writes a snapshot of the running process into `dir`, which is created if it doesn't exist.
+    /// Implementors that can't snapshot a process while it runs may stop it in order to do so.
+    ///
+    /// The default implementation of this returns [`ProviderError::Unsupported`]. Override this
+    /// only when there is an implementation.
+    async fn checkpoint(&self, _dir: &Path) -> Result<(), ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use crate::exec::{CommandOptions, ExecOutput, ExecResult, ExecStream, Sender, Stdin};
+use crate::provider::ProviderError;
+
+/// An [`ExecHandler`] is used to run commands against a running process.
+#[async_trait::async_trait]
+pub trait ExecHandler {
+    /// Runs the given command against the running process and returns its output and exit code
+    /// once it has completed. An error means the command could not be run at all.
+    async fn exec(&self, options: CommandOptions) -> Result<ExecResult, ProviderError>;
+
+    /// Runs the given command against the running process, feeding it data from `stdin` as the
+    /// client sends it. The returned stream yields output as the command produces it and ends
+    /// with the command's exit status.
+    ///
+    /// The default implementation ignores stdin and yields the result of [`ExecHandler::exec`]
+    /// as a single chunk. Override this when the implementor can do better.
+    async fn exec_streaming(
+        &self,
+        options: CommandOptions,
+        _stdin: Stdin,
+    ) -> Result<ExecStream, ProviderError> {
+        let output = match self.exec(options).await {
+            Ok(result) => vec![
+                ExecOutput::Stdout(result.output.into_bytes()),
+                ExecOutput::Exit(result.exit_code),
+            ],
+            Err(e) => vec![
+                ExecOutput::Stderr(e.to_string().into_bytes()),
+                ExecOutput::Exit(1),
+            ],
+        };
+        Ok(Box::pin(futures::stream::iter(output)))
+    }
+
+    /// Starts feeding data from `stdin` to the running process's standard input until the client
+    /// closes it. This should return as soon as the process is ready to take input, rather than
+    /// once the client is done, as the container's handle is borrowed until it returns.
+    ///
+    /// The default implementation of this returns [`ProviderError::Unsupported`]. Override this
+    /// only when there is an implementation.
+    async fn attach_stdin(&self, _stdin: Stdin) -> Result<(), ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Copies the file or directory at `path` in the container out of it, sending a tar archive
+    /// of it to the client's stdout. This is run in place of the `tar` command `kubectl cp` runs
+    /// to copy from the container. See [`crate::exec::copy`] for helpers.
+    ///
+    /// The default implementation of this returns [`ProviderError::Unsupported`], in which case
+    /// the command is run as any other. Implementations must not send anything before returning
+    /// that error.
+    async fn copy_from(&self, _path: &Path, _sender: Sender) -> Result<(), ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Copies files into the directory at `dir` in the container, extracting them from the tar
+    /// archive the client writes to stdin. This is run in place of the `tar` command `kubectl cp`
+    /// runs to copy to the container.
+    ///
+    /// The default implementation of this returns [`ProviderError::Unsupported`], in which case
+    /// the command is run as any other. Implementations must not read from stdin before
+    /// returning that error.
+    async fn copy_to(&self, _dir: &Path, _stdin: &mut Stdin) -> Result<(), ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+}
@@ -2,7 +2,11 @@
 //!
 //! A collection of handle types for use in providers. These are entirely
 //! optional, but abstract away much of the logic around managing logging,
-//! status updates, and stopping pods
+//! status updates, executing commands, checkpointing, and stopping pods
+mod checkpoint;
+mod exec;
 mod stopper;
 
+pub use checkpoint::CheckpointHandler;
+pub use exec::ExecHandler;
 pub use stopper::StopHandler;
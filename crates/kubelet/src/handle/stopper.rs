@@ -1,12 +1,29 @@
+use crate::provider::ProviderError;
+
 /// A [`StopHandler`] is used to handle stopping running processes.
 #[async_trait::async_trait]
 pub trait StopHandler {
-    /// Calling stop should sends a signal for anything running under the implementor to stop.
+    /// Calling stop should send a signal for anything running under the implementor to stop,
+    /// giving it the chance to finish what it is doing.
     ///
-    /// This is considered an ungraceful stop, and the caller should not wait for the
-    /// underlying handle to complete. Instead they should call wait() to wait for anything running
-    /// to stop.
-    async fn stop(&mut self) -> anyhow::Result<()>;
+    /// The caller should not expect the underlying handle to have completed. Instead they should
+    /// call wait() to wait for anything running to stop, and kill() if it doesn't stop within the
+    /// pod's termination grace period.
+    async fn stop(&mut self) -> Result<(), ProviderError>;
+    /// Forcibly stop anything running under the implementor, after a call to stop() has not
+    /// stopped it in time.
+    ///
+    /// The default implementation calls stop() again, which suits implementors that can only
+    /// stop things ungracefully. Override this when stop() is graceful.
+    async fn kill(&mut self) -> Result<(), ProviderError> {
+        self.stop().await
+    }
+    /// Whether anything running under the implementor has exited, without waiting for it.
+    ///
+    /// The default implementation returns `None`, for implementors that can't tell.
+    fn has_exited(&self) -> Option<bool> {
+        None
+    }
     /// Wait for the implementor to stop anything it considers in the running state.
-    async fn wait(&mut self) -> anyhow::Result<()>;
+    async fn wait(&mut self) -> Result<(), ProviderError>;
 }
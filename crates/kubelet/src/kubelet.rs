@@ -1,18 +1,31 @@
 ///! This library contains code for running a kubelet. Use this to create a new
 ///! Kubelet with a specific handler (called a `Provider`)
+use crate::admission::{
+    AdmitChain, AdmitHandler, DeviceAdmitHandler, NodeAdmitHandler, ResourceFitAdmitHandler,
+    SharedResourceAccounting, UnsupportedFeaturesAdmitHandler,
+};
+use crate::checkpoint::{self, Checkpoints};
 use crate::config::Config;
+use crate::config_watcher::{LiveSettings, SharedLiveSettings};
+use crate::dns::Dns;
+use crate::network::Network;
 use crate::node;
+use crate::node::taint::SharedNodeTaints;
+use crate::node::NodeProps;
 use crate::operator::PodOperator;
 use crate::plugin_watcher::PluginRegistry;
 use crate::provider::Provider;
+use crate::shutdown;
+use crate::static_pod::StaticPods;
 use crate::webserver::start as start_webserver;
 
 use futures::future::FutureExt;
 use kube::api::ListParams;
-use log::{error, info, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::signal::ctrl_c;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
 
 use krator::OperatorRuntime;
 
@@ -42,6 +55,8 @@ impl<P: Provider> Kubelet<P> {
         kube_config: kube::Config,
         config: Config,
     ) -> anyhow::Result<Self> {
+        crate::features::set_gates(config.feature_gates.clone());
+        LiveSettings::of(&config).apply_log_level();
         Ok(Self {
             provider: Arc::new(provider),
             kube_config,
@@ -59,25 +74,140 @@ impl<P: Provider> Kubelet<P> {
         let client = kube::Client::new(self.kube_config.clone());
 
         // Create the node. If it already exists, this will exit
-        node::create(&client, &self.config, self.provider.clone()).await;
+        let node_props = self.provider.node_props();
+        node::create(&client, &self.config, &node_props, self.provider.clone()).await;
 
         // Flag to indicate graceful shutdown has started.
         let signal = Arc::new(AtomicBool::new(false));
         let signal_task = start_signal_task(Arc::clone(&signal)).fuse().boxed();
 
-        let plugin_registrar = PluginRegistry::new(&self.config.plugins_dir);
+        // Device plugins may register through either the plugin registration directory or the
+        // device plugin directory, if the provider can use devices
+        let device_manager = self.provider.device_manager();
+        let plugin_registrar = self.provider.plugin_registry().unwrap_or_else(|| {
+            let registry = PluginRegistry::new(&self.config.plugins_dir);
+            Arc::new(match &device_manager {
+                Some(device_manager) => registry.with_device_manager(device_manager.clone()),
+                None => registry,
+            })
+        });
 
         let registrar = plugin_registrar.run().fuse().boxed();
 
+        let device_registrar = match &device_manager {
+            Some(device_manager) => device_manager.clone().run().boxed(),
+            None => futures::future::pending().boxed(),
+        }
+        .fuse();
+
+        // Keep track of the node being cordoned or tainted, refusing and evicting pods as needed
+        let node_taints = SharedNodeTaints::default();
+        let taint_watcher = node::taint::watch(
+            client.clone(),
+            self.config.node_name.clone(),
+            node_taints.clone(),
+        )
+        .fuse()
+        .boxed();
+
+        // Keep track of what admitted pods request, so that the node isn't overloaded
+        let accounting = SharedResourceAccounting::default();
+        set_allocatable(&accounting, &self.config, &node_props).await;
+
+        // New pods are admitted by the kubelet's own checks, then the provider's
+        let mut admit_handlers: Vec<Arc<dyn AdmitHandler>> = vec![
+            Arc::new(NodeAdmitHandler(node_taints)),
+            Arc::new(ResourceFitAdmitHandler(accounting.clone())),
+            Arc::new(UnsupportedFeaturesAdmitHandler),
+        ];
+        if let Some(device_manager) = &device_manager {
+            admit_handlers.push(Arc::new(DeviceAdmitHandler(device_manager.clone())));
+        }
+        admit_handlers.extend(self.provider.admit_handlers());
+
+        // Pods deleted while the kubelet wasn't running are torn down before any are run
+        let network = Network::new(&self.config).map(Arc::new);
+        let dns = Arc::new(Dns::new(&self.config));
+        let checkpoints = Arc::new(Checkpoints::new(&self.config.data_dir));
+        if let Err(e) = checkpoint::remove_orphans(
+            &client,
+            &checkpoints,
+            self.provider.as_ref(),
+            network.as_deref(),
+            &dns,
+        )
+        .await
+        {
+            error!(
+                "Unable to tear down pods deleted while the kubelet wasn't running: {:?}",
+                e
+            );
+        }
+
+        let operator = PodOperator::new(
+            Arc::clone(&self.provider),
+            client.clone(),
+            self.config.node_ip,
+            AdmitChain::new(admit_handlers),
+            accounting.clone(),
+            device_manager,
+            network,
+            dns,
+            checkpoints,
+        );
+        let node_selector = format!("spec.nodeName={}", &self.config.node_name);
+        let params = ListParams {
+            field_selector: Some(node_selector),
+            ..Default::default()
+        };
+        let mut operator_runtime = OperatorRuntime::new(&self.kube_config, operator, Some(params))
+            .with_registration_workers(self.config.pod_registration_workers);
+
+        // Run the static pods in the pod manifest directory, if there is one. They are started
+        // before the operator so that it doesn't mistake their mirror pods for other pods.
+        let static_pods = match &self.config.pod_manifest_path {
+            Some(dir) => {
+                let mut static_pods = StaticPods::new(
+                    dir.clone(),
+                    self.config.node_name.clone(),
+                    client.clone(),
+                    operator_runtime.local_events(),
+                );
+                static_pods.start().await;
+                static_pods.run().boxed()
+            }
+            None => futures::future::pending().boxed(),
+        }
+        .fuse();
+
         // Start the webserver
-        let webserver = start_webserver(self.provider.clone(), &self.config.server_config)
-            .fuse()
-            .boxed();
+        let webserver = start_webserver(
+            self.provider.clone(),
+            &self.config,
+            client.clone(),
+            operator_runtime.sync_loop_status(),
+        )
+        .fuse()
+        .boxed();
+
+        // Apply changes to the configuration file's live settings as it changes
+        let live_settings = Arc::new(RwLock::new(LiveSettings::of(&self.config)));
+        #[cfg(any(feature = "cli", feature = "docs"))]
+        tokio::spawn(crate::config_watcher::watch(
+            (*self.config).clone(),
+            live_settings.clone(),
+        ));
 
         // Start updating the node lease and status periodically
-        let node_updater = start_node_updater(client.clone(), self.config.node_name.clone())
-            .fuse()
-            .boxed();
+        let node_updater = start_node_updater(
+            client.clone(),
+            (*self.config).clone(),
+            node_props,
+            accounting,
+            live_settings,
+        )
+        .fuse()
+        .boxed();
 
         // If any of these tasks fail, we can initiate graceful shutdown.
         let services = Box::pin(async {
@@ -91,6 +221,15 @@ impl<P: Provider> Kubelet<P> {
                 },
                 res = registrar => if let Err(e) = res {
                     error!("Registrar task completed with error {:?}", &e);
+                },
+                res = device_registrar => if let Err(e) = res {
+                    error!("Device plugin registrar task completed with error {:?}", &e);
+                },
+                res = taint_watcher => if let Err(e) = res {
+                    error!("Node taint watcher task completed with error {:?}", &e);
+                },
+                res = static_pods => if let Err(e) = res {
+                    error!("Static pod task completed with error {:?}", &e);
                 }
             };
             // Use relaxed ordering because we just need other tasks to eventually catch the signal.
@@ -102,18 +241,12 @@ impl<P: Provider> Kubelet<P> {
         let signal_handler = start_signal_handler(
             Arc::clone(&signal),
             client.clone(),
-            self.config.node_name.clone(),
+            &self.config,
+            self.provider.clone(),
         )
         .fuse()
         .boxed();
 
-        let operator = PodOperator::new(Arc::clone(&self.provider), client.clone());
-        let node_selector = format!("spec.nodeName={}", &self.config.node_name);
-        let params = ListParams {
-            field_selector: Some(node_selector),
-            ..Default::default()
-        };
-        let mut operator_runtime = OperatorRuntime::new(&self.kube_config, operator, Some(params));
         let operator_task = operator_runtime.start().fuse().boxed();
 
         // These must all be running for graceful shutdown. An error here exits ungracefully.
@@ -150,34 +283,89 @@ impl<P> Clone for Kubelet<P> {
     }
 }
 
-/// Awaits SIGINT and sets graceful shutdown flag if detected.
+/// Awaits SIGINT or SIGTERM and sets graceful shutdown flag if detected.
 async fn start_signal_task(signal: Arc<AtomicBool>) -> anyhow::Result<()> {
-    ctrl_c().await?;
-    warn!("Caught keyboard interrupt.");
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal as unix_signal, SignalKind};
+        let mut terminate = unix_signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = ctrl_c() => {
+                res?;
+                warn!("Caught keyboard interrupt.");
+            }
+            _ = terminate.recv() => warn!("Caught SIGTERM."),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        ctrl_c().await?;
+        warn!("Caught keyboard interrupt.");
+    }
     signal.store(true, Ordering::Relaxed);
     Ok(())
 }
 
-/// Periodically renew node lease and status. Exits if signal is caught.
-async fn start_node_updater(client: kube::Client, node_name: String) -> anyhow::Result<()> {
-    let sleep_interval = std::time::Duration::from_secs(10);
-    loop {
-        node::update(&client, &node_name).await;
-        tokio::time::delay_for(sleep_interval).await;
+/// Periodically renew node lease and status, with the live settings as they are each time. Exits
+/// if signal is caught.
+async fn start_node_updater(
+    client: kube::Client,
+    config: Config,
+    node_props: NodeProps,
+    accounting: SharedResourceAccounting,
+    live_settings: SharedLiveSettings,
+) -> anyhow::Result<()> {
+    let lease = async {
+        if !config.node_lease {
+            // Without a lease, the status updates are the node's heartbeat
+            return futures::future::pending::<()>().await;
+        }
+        let sleep_interval = config.node_lease_duration / 4;
+        loop {
+            node::update(&client, &config).await;
+            tokio::time::delay_for(sleep_interval).await;
+        }
+    };
+    let status = async {
+        let mut config = config.clone();
+        loop {
+            live_settings.read().await.apply_to(&mut config);
+            set_allocatable(&accounting, &config, &node_props).await;
+            node::update_status(&client, &config, &node_props).await;
+            tokio::time::delay_for(config.node_status_update_frequency).await;
+        }
+    };
+    futures::future::join(lease, status).await;
+    Ok(())
+}
+
+/// Admit pods against the allocatable resources that the node reports in its status.
+async fn set_allocatable(
+    accounting: &SharedResourceAccounting,
+    config: &Config,
+    node_props: &NodeProps,
+) {
+    let (_, allocatable) = node::status::resources(config, node_props);
+    if let Err(e) = accounting.write().await.set_allocatable(&allocatable) {
+        warn!(
+            "Unable to account for the node's allocatable resources: {:?}",
+            e
+        );
     }
 }
 
-/// Checks for shutdown signal and cleans up resources gracefully.
-async fn start_signal_handler(
+/// Checks for shutdown signal and shuts the node down gracefully.
+async fn start_signal_handler<P: Provider>(
     signal: Arc<AtomicBool>,
     client: kube::Client,
-    node_name: String,
+    config: &Config,
+    provider: Arc<P>,
 ) -> anyhow::Result<()> {
     let duration = std::time::Duration::from_millis(100);
     loop {
         if signal.load(Ordering::Relaxed) {
             info!("Signal caught.");
-            node::drain(&client, &node_name).await?;
+            shutdown::shutdown(&client, config, provider).await?;
             break Ok(());
         }
         tokio::time::delay_for(duration).await;
@@ -189,10 +377,12 @@ mod test {
     use super::*;
     use crate::container::Container;
     use crate::pod::{Pod, Status};
+    use crate::provider::ProviderError;
     use k8s_openapi::api::core::v1::{
         Container as KubeContainer, EnvVar, EnvVarSource, ObjectFieldSelector, Pod as KubePod,
-        PodSpec, PodStatus,
+        PodSpec, PodStatus, ResourceFieldSelector, ResourceRequirements,
     };
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
     use krator::ObjectState;
     use kube::api::ObjectMeta;
     use std::collections::BTreeMap;
@@ -226,7 +416,7 @@ mod test {
 
         const ARCH: &'static str = "mock";
 
-        async fn initialize_pod_state(&self, _pod: &Pod) -> anyhow::Result<Self::PodState> {
+        async fn initialize_pod_state(&self, _pod: &Pod) -> Result<Self::PodState, ProviderError> {
             Ok(PodState)
         }
 
@@ -240,7 +430,7 @@ mod test {
             _pod: String,
             _container: String,
             _sender: crate::log::Sender,
-        ) -> anyhow::Result<()> {
+        ) -> Result<(), ProviderError> {
             Ok(())
         }
     }
@@ -254,6 +444,11 @@ mod test {
                     value: Some("value".into()),
                     ..Default::default()
                 },
+                EnvVar {
+                    name: "expanded".into(),
+                    value: Some("$(first) and $$(first)".into()),
+                    ..Default::default()
+                },
                 EnvVar {
                     name: "second".into(),
                     value_from: Some(EnvVarSource {
@@ -320,7 +515,76 @@ mod test {
                     }),
                     ..Default::default()
                 },
+                EnvVar {
+                    name: "NODE_NAME".into(),
+                    value_from: Some(EnvVarSource {
+                        field_ref: Some(ObjectFieldSelector {
+                            field_path: "spec.nodeName".into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                EnvVar {
+                    name: "LABEL".into(),
+                    value_from: Some(EnvVarSource {
+                        field_ref: Some(ObjectFieldSelector {
+                            field_path: "metadata.labels['label']".into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                EnvVar {
+                    name: "MEMORY_LIMIT".into(),
+                    value_from: Some(EnvVarSource {
+                        resource_field_ref: Some(ResourceFieldSelector {
+                            resource: "limits.memory".into(),
+                            divisor: Some(Quantity("1Mi".into())),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                EnvVar {
+                    name: "CPU_REQUEST".into(),
+                    value_from: Some(EnvVarSource {
+                        resource_field_ref: Some(ResourceFieldSelector {
+                            resource: "requests.cpu".into(),
+                            divisor: Some(Quantity("1m".into())),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                EnvVar {
+                    name: "MEMORY_REQUEST".into(),
+                    value_from: Some(EnvVarSource {
+                        resource_field_ref: Some(ResourceFieldSelector {
+                            resource: "requests.memory".into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
             ]),
+            resources: Some(ResourceRequirements {
+                limits: Some(
+                    vec![("memory".to_string(), Quantity("128Mi".into()))]
+                        .into_iter()
+                        .collect(),
+                ),
+                requests: Some(
+                    vec![("cpu".to_string(), Quantity("250m".into()))]
+                        .into_iter()
+                        .collect(),
+                ),
+            }),
             ..Default::default()
         });
         let name = "my-name".to_string();
@@ -339,6 +603,7 @@ mod test {
             },
             spec: Some(PodSpec {
                 service_account_name: Some("svc".to_string()),
+                node_name: Some("krustlet".to_string()),
                 ..Default::default()
             }),
             status: Some(PodStatus {
@@ -347,13 +612,21 @@ mod test {
                 ..Default::default()
             }),
         });
-        let env = MockProvider::env_vars(&container, &pod, &mock_client()).await;
+        let env = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .unwrap();
 
         assert_eq!(
             "value",
             env.get("first").expect("key first should exist").as_str()
         );
 
+        assert_eq!(
+            "value and $(first)",
+            env.get("expanded")
+                .expect("key expanded should exist")
+                .as_str()
+        );
         assert_eq!(
             "value",
             env.get("second").expect("metadata.labels.label").as_str()
@@ -371,5 +644,25 @@ mod test {
         );
         assert_eq!("10.21.77.2", env.get("POD_IP").expect("pod_ip").as_str());
         assert_eq!("10.21.77.1", env.get("HOST_IP").expect("host_ip").as_str());
+        assert_eq!(
+            "krustlet",
+            env.get("NODE_NAME").expect("spec.nodeName").as_str()
+        );
+        assert_eq!(
+            "value",
+            env.get("LABEL").expect("metadata.labels['label']").as_str()
+        );
+        assert_eq!(
+            "128",
+            env.get("MEMORY_LIMIT").expect("limits.memory").as_str()
+        );
+        assert_eq!(
+            "250",
+            env.get("CPU_REQUEST").expect("requests.cpu").as_str()
+        );
+        assert_eq!(
+            "0",
+            env.get("MEMORY_REQUEST").expect("requests.memory").as_str()
+        );
     }
 }
@@ -9,7 +9,7 @@
 //! use kubelet::Kubelet;
 //! use kubelet::config::Config;
 //! use kubelet::pod::Pod;
-//! use kubelet::provider::Provider;
+//! use kubelet::provider::{Provider, ProviderError};
 //! use std::sync::Arc;
 //! use tokio::sync::RwLock;
 //! use kubelet::pod::state::prelude::*;
@@ -44,11 +44,11 @@
 //!         Arc::new(RwLock::new(ProviderState {}))
 //!     }
 //!    
-//!     async fn initialize_pod_state(&self, _pod: &Pod) -> anyhow::Result<Self::PodState> {
+//!     async fn initialize_pod_state(&self, _pod: &Pod) -> Result<Self::PodState, ProviderError> {
 //!         Ok(PodState)
 //!     }
 //!
-//!     async fn logs(&self, namespace: String, pod: String, container: String, sender: kubelet::log::Sender) -> anyhow::Result<()> { todo!() }
+//!     async fn logs(&self, namespace: String, pod: String, container: String, sender: kubelet::log::Sender) -> Result<(), ProviderError> { todo!() }
 //! }
 //!
 //! async {
@@ -71,9 +71,13 @@
 #![cfg_attr(feature = "docs", feature(doc_cfg))]
 
 mod bootstrapping;
+mod checkpoint;
 mod config_interpreter;
+mod config_watcher;
 mod kubelet;
 mod operator;
+mod shutdown;
+mod static_pod;
 
 pub(crate) mod kubeconfig;
 pub(crate) mod webserver;
@@ -84,24 +88,48 @@ pub(crate) mod plugin_registration_api {
         tonic::include_proto!("pluginregistration.v1");
     }
 }
+pub(crate) mod csi_api {
+    pub(crate) mod v1 {
+        tonic::include_proto!("csi.v1");
+    }
+}
+pub(crate) mod device_plugin_api {
+    pub(crate) mod v1beta1 {
+        pub const API_VERSION: &str = "v1beta1";
+
+        tonic::include_proto!("v1beta1");
+    }
+}
 pub(crate) mod fs_watch;
 pub(crate) mod grpc_sock;
 #[cfg(target_family = "windows")]
 #[allow(dead_code)]
 pub(crate) mod mio_uds_windows;
-pub(crate) mod plugin_watcher;
 
+pub mod admission;
 pub mod backoff;
 pub mod config;
 pub mod container;
+pub mod device_plugin;
+pub mod dns;
+pub mod exec;
+pub mod features;
 pub mod handle;
 pub mod log;
+pub mod metrics;
+pub mod network;
 pub mod node;
+pub mod plugin_watcher;
 pub mod pod;
+pub mod port_forward;
+pub mod probe;
 pub mod provider;
+pub mod resources;
 pub mod secret;
 pub mod state;
+pub mod stats;
 pub mod store;
+pub mod telemetry;
 pub mod volume;
 
 pub use self::kubelet::Kubelet;
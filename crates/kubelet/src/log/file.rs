@@ -0,0 +1,300 @@
+//! Container logs kept in files, laid out the way the Kubernetes kubelet lays them out so that
+//! log collectors that read `/var/log/pods` can read them too.
+//!
+//! The logs of a container are kept in `<root>/<namespace>_<pod>_<pod uid>/<container>/`, with
+//! each run of the container writing to `<run>.log`, where `<run>` counts up from 0. Only the logs
+//! of the current and previous runs are kept. Once a log file reaches the maximum size, it is
+//! renamed to `<run>.log.<timestamp>` and a new one is started, and the oldest of the renamed
+//! files are removed so that no more than the maximum number of files are kept for a run.
+//!
+//! A client following a log keeps reading the file it started with, so it stops seeing new output
+//! once that file is rotated and has to ask for the logs again.
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use futures::StreamExt;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tracing::{debug, warn};
+
+use super::storage::{ContainerLog, LogHandleFactory, LogReader, LogSink, LogStorage};
+use super::{HandleFactory, LogWatch};
+use crate::pod::Pod;
+
+/// The format of the timestamp a rotated log file is renamed with. This is precise enough that
+/// files rotated in quick succession don't replace each other.
+const ROTATED_TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S%.9f";
+
+/// When container log files are rotated and how many are kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogRotation {
+    /// The size, in bytes, a log file may reach before it is rotated
+    pub max_size: u64,
+    /// The most log files kept for each run of a container, including the one being written
+    pub max_files: usize,
+}
+
+/// The directory the logs of a pod's containers are kept in, under the given root.
+pub fn pod_log_dir(root: &Path, pod: &Pod) -> PathBuf {
+    let uid = pod
+        .as_kube_pod()
+        .metadata
+        .uid
+        .as_deref()
+        .unwrap_or_default();
+    root.join(format!("{}_{}_{}", pod.namespace(), pod.name(), uid))
+}
+
+/// The directory the logs of one of a pod's containers are kept in, under the given root.
+pub fn container_log_dir(root: &Path, pod: &Pod, container_name: &str) -> PathBuf {
+    pod_log_dir(root, pod).join(container_name)
+}
+
+/// Keeps container logs in files under a root directory.
+pub struct FileStorage {
+    root: PathBuf,
+    rotation: LogRotation,
+}
+
+impl FileStorage {
+    /// Create a storage that keeps logs under the given root, rotating them as given.
+    pub fn new<P: AsRef<Path>>(root: P, rotation: LogRotation) -> Self {
+        FileStorage {
+            root: root.as_ref().to_owned(),
+            rotation,
+        }
+    }
+}
+
+impl LogStorage for FileStorage {
+    fn create(&self, pod: &Pod, container_name: &str) -> std::io::Result<ContainerLog> {
+        let writer = LogWriter::create(
+            &container_log_dir(&self.root, pod, container_name),
+            self.rotation,
+        )?;
+        let handles = LogHandleFactory::new(FileHandleFactory {
+            path: writer.path().to_owned(),
+        });
+        Ok(ContainerLog {
+            sink: Box::new(writer),
+            handles,
+        })
+    }
+
+    fn remove_pod(&self, pod: &Pod) -> std::io::Result<()> {
+        match std::fs::remove_dir_all(pod_log_dir(&self.root, pod)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Opens the log file of a run of a container.
+struct FileHandleFactory {
+    path: PathBuf,
+}
+
+impl HandleFactory<LogReader> for FileHandleFactory {
+    fn new_handle(&self) -> LogReader {
+        Box::new(tokio::fs::File::from_std(
+            std::fs::File::open(&self.path).unwrap(),
+        ))
+    }
+
+    /// Watches the log file for writes.
+    fn watch(&self) -> Option<LogWatch> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let watcher: notify::Result<RecommendedWatcher> =
+            Watcher::new_immediate(move |res: notify::Result<notify::Event>| match res {
+                Ok(event) if event.kind.is_modify() => {
+                    // The follower has gone away if this fails, so there is nobody to tell
+                    let _ = tx.send(());
+                }
+                Ok(_) => (),
+                Err(e) => debug!("Error watching log file: {:?}", e),
+            });
+        let mut watcher = match watcher {
+            Ok(w) => w,
+            Err(e) => {
+                warn!("Unable to watch log file, falling back to polling: {:?}", e);
+                return None;
+            }
+        };
+        if let Err(e) = watcher.watch(&self.path, RecursiveMode::NonRecursive) {
+            warn!("Unable to watch log file, falling back to polling: {:?}", e);
+            return None;
+        }
+        // The watcher stops when it is dropped, so it lives as long as the stream does
+        Some(Box::pin(rx.map(move |_| {
+            let _watcher = &watcher;
+        })))
+    }
+}
+
+/// Writes the log of one run of a container, rotating the file once it gets too big.
+pub struct LogWriter {
+    path: PathBuf,
+    file: std::fs::File,
+    size: u64,
+    rotation: LogRotation,
+}
+
+impl LogWriter {
+    /// Create the log file for the next run of a container in the given directory, removing the
+    /// logs of any runs before the previous one.
+    ///
+    /// This blocks, so it should be called from a blocking task.
+    pub fn create(dir: &Path, rotation: LogRotation) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut runs = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            if let Some(run) = run_of(&name.to_string_lossy()) {
+                runs.push((run, name));
+            }
+        }
+        let run = runs.iter().map(|(run, _)| run + 1).max().unwrap_or(0);
+        for (_, name) in runs.iter().filter(|(old, _)| old + 1 < run) {
+            let path = dir.join(name);
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Unable to remove old log file {:?}: {}", path, e);
+            }
+        }
+        let path = dir.join(format!("{}.log", run));
+        let file = open(&path)?;
+        Ok(LogWriter {
+            path,
+            file,
+            size: 0,
+            rotation,
+        })
+    }
+
+    /// The file the log is written to. The file at this path is replaced whenever the log is
+    /// rotated, so it should be opened again to read new output.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let timestamp = chrono::Utc::now().format(ROTATED_TIMESTAMP_FORMAT);
+        let file_name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let rotated = self
+            .path
+            .with_file_name(format!("{}.{}", file_name, timestamp));
+        std::fs::rename(&self.path, &rotated)?;
+        debug!("Rotated log file {:?} to {:?}", self.path, rotated);
+        self.file = open(&self.path)?;
+        self.size = 0;
+
+        // Rotated files sort oldest first, as their names end with when they were rotated
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let prefix = format!("{}.", file_name);
+        let mut rotated_files: Vec<_> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(&prefix))
+            .collect();
+        rotated_files.sort();
+        let keep = self.rotation.max_files.saturating_sub(1);
+        let excess = rotated_files.len().saturating_sub(keep);
+        for name in &rotated_files[..excess] {
+            let path = dir.join(name);
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Unable to remove rotated log file {:?}: {}", path, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LogSink for LogWriter {
+    /// Write a record to the log. A record is never split across files, so the log is rotated
+    /// first if the record would take it over its maximum size.
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<()> {
+        if self.size > 0 && self.size + record.len() as u64 > self.rotation.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+/// The run a log file, or a rotated log file, was written by.
+fn run_of(file_name: &str) -> Option<u64> {
+    let (run, rest) = file_name.split_at(file_name.find('.')?);
+    if rest == ".log" || rest.starts_with(".log.") {
+        run.parse().ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn only_current_and_previous_runs_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let rotation = LogRotation {
+            max_size: 1024,
+            max_files: 5,
+        };
+        std::fs::write(dir.path().join("0.log.20201001-100000"), "").unwrap();
+        for _ in 0..3 {
+            LogWriter::create(dir.path(), rotation).unwrap();
+        }
+        assert_eq!(file_names(dir.path()), vec!["2.log", "3.log"]);
+    }
+
+    #[test]
+    fn logs_are_rotated_once_they_reach_the_maximum_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = LogWriter::create(
+            dir.path(),
+            LogRotation {
+                max_size: 8,
+                max_files: 2,
+            },
+        )
+        .unwrap();
+        writer.write_record(b"one\n").unwrap();
+        writer.write_record(b"two\n").unwrap();
+        writer.write_record(b"three\n").unwrap();
+        assert_eq!(std::fs::read(writer.path()).unwrap(), b"three\n".to_vec());
+        let names = file_names(dir.path());
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0], "0.log");
+        assert!(names[1].starts_with("0.log."));
+        assert_eq!(
+            std::fs::read(dir.path().join(&names[1])).unwrap(),
+            b"one\ntwo\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn run_is_parsed_from_log_file_names() {
+        assert_eq!(run_of("0.log"), Some(0));
+        assert_eq!(run_of("12.log.20201001-100000"), Some(12));
+        assert_eq!(run_of("x.log"), None);
+        assert_eq!(run_of("1.txt"), None);
+        assert_eq!(run_of("log"), None);
+    }
+}
@@ -0,0 +1,246 @@
+//! Forwarding of container logs to a remote collector, as well as keeping them in another storage
+//! so that they can still be read through the kubelet.
+//!
+//! Logs are forwarded to a syslog server over UDP when the collector's URL is `udp://host:port`,
+//! with each line sent as an RFC 5424 message, or posted to an HTTP collector in batches of JSON
+//! objects when it is an `http://` or `https://` URL. Forwarding is best effort: lines that can't
+//! be forwarded are dropped with a warning, and never hold up the container writing them.
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, warn};
+use url::Url;
+
+use super::storage::{ContainerLog, LogSink, LogStorage};
+use super::{LogStream, Record};
+use crate::pod::Pod;
+
+/// The port syslog servers listen on when the URL doesn't give one.
+const DEFAULT_SYSLOG_PORT: u16 = 514;
+/// The most lines posted to an HTTP collector at once.
+const MAX_HTTP_BATCH: usize = 100;
+/// The syslog facility lines are sent with, which is user-level messages.
+const SYSLOG_FACILITY: u8 = 1;
+
+/// Keeps logs in another storage, and forwards each line written to a remote collector.
+pub struct ForwardingStorage {
+    inner: Arc<dyn LogStorage>,
+    target: Target,
+}
+
+#[derive(Clone)]
+enum Target {
+    Syslog(Arc<UdpSocket>),
+    Http(UnboundedSender<ForwardedLine>),
+}
+
+impl ForwardingStorage {
+    /// Create a storage that keeps logs in `inner` and forwards them to the collector at `url`.
+    /// Forwarding to an HTTP collector starts a task, so this must be called from within a Tokio
+    /// runtime.
+    pub fn new(inner: Arc<dyn LogStorage>, url: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(url)
+            .map_err(|e| anyhow::anyhow!("invalid log forwarding URL {}: {}", url, e))?;
+        let target = match url.scheme() {
+            "udp" => {
+                let host = url
+                    .host_str()
+                    .ok_or_else(|| anyhow::anyhow!("log forwarding URL {} has no host", url))?;
+                let addr = (host, url.port().unwrap_or(DEFAULT_SYSLOG_PORT))
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("unable to resolve syslog server {}", host))?;
+                let socket = if addr.is_ipv4() {
+                    UdpSocket::bind("0.0.0.0:0")?
+                } else {
+                    UdpSocket::bind("[::]:0")?
+                };
+                socket.connect(addr)?;
+                Target::Syslog(Arc::new(socket))
+            }
+            "http" | "https" => {
+                let (sender, receiver) = unbounded_channel();
+                tokio::spawn(post_lines(reqwest::Client::new(), url, receiver));
+                Target::Http(sender)
+            }
+            other => anyhow::bail!(
+                "unsupported log forwarding scheme {}, expected udp, http or https",
+                other
+            ),
+        };
+        Ok(ForwardingStorage { inner, target })
+    }
+}
+
+impl LogStorage for ForwardingStorage {
+    fn create(&self, pod: &Pod, container_name: &str) -> std::io::Result<ContainerLog> {
+        let log = self.inner.create(pod, container_name)?;
+        Ok(ContainerLog {
+            sink: Box::new(ForwardingSink {
+                inner: log.sink,
+                target: self.target.clone(),
+                namespace: pod.namespace().to_owned(),
+                pod: pod.name().to_owned(),
+                container: container_name.to_owned(),
+            }),
+            handles: log.handles,
+        })
+    }
+
+    fn remove_pod(&self, pod: &Pod) -> std::io::Result<()> {
+        self.inner.remove_pod(pod)
+    }
+}
+
+struct ForwardingSink {
+    inner: Box<dyn LogSink>,
+    target: Target,
+    namespace: String,
+    pod: String,
+    container: String,
+}
+
+impl LogSink for ForwardingSink {
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<()> {
+        self.inner.write_record(record)?;
+        // The record ends with a newline, which isn't part of the line
+        let line = match record.split_last() {
+            Some((b'\n', line)) => line,
+            _ => record,
+        };
+        let parsed = Record::parse(line);
+        let stream = parsed.stream.unwrap_or(LogStream::Stdout);
+        let timestamp = String::from_utf8_lossy(parsed.raw_timestamp);
+        match &self.target {
+            Target::Syslog(socket) => {
+                let message = syslog_message(
+                    stream,
+                    &timestamp,
+                    &format!("{}/{}", self.namespace, self.pod),
+                    &self.container,
+                    parsed.content,
+                );
+                if let Err(e) = socket.send(&message) {
+                    debug!("Unable to forward log line to syslog server: {}", e);
+                }
+            }
+            Target::Http(sender) => {
+                let line = ForwardedLine {
+                    timestamp: timestamp.into_owned(),
+                    stream: stream.as_str(),
+                    namespace: self.namespace.clone(),
+                    pod: self.pod.clone(),
+                    container: self.container.clone(),
+                    log: String::from_utf8_lossy(parsed.content).into_owned(),
+                };
+                // The task only stops when the kubelet does, so there is nowhere else to send it
+                let _ = sender.send(line);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format a line as an RFC 5424 syslog message. Lines written to stderr are sent as errors, and
+/// lines written to stdout as informational messages.
+fn syslog_message(
+    stream: LogStream,
+    timestamp: &str,
+    pod: &str,
+    container: &str,
+    content: &[u8],
+) -> Vec<u8> {
+    let severity = match stream {
+        LogStream::Stdout => 6,
+        LogStream::Stderr => 3,
+    };
+    let timestamp = if timestamp.is_empty() { "-" } else { timestamp };
+    let mut message = format!(
+        "<{}>1 {} {} {} - - - ",
+        SYSLOG_FACILITY * 8 + severity,
+        timestamp,
+        pod,
+        container
+    )
+    .into_bytes();
+    message.extend_from_slice(content);
+    message
+}
+
+/// A line posted to an HTTP collector.
+#[derive(Debug, Serialize)]
+struct ForwardedLine {
+    timestamp: String,
+    stream: &'static str,
+    namespace: String,
+    pod: String,
+    container: String,
+    log: String,
+}
+
+/// Posts lines to an HTTP collector as they are written, batching up any that are written while a
+/// batch is being posted.
+async fn post_lines(
+    client: reqwest::Client,
+    url: Url,
+    mut receiver: UnboundedReceiver<ForwardedLine>,
+) {
+    while let Some(line) = receiver.recv().await {
+        let mut batch = vec![line];
+        while batch.len() < MAX_HTTP_BATCH {
+            match receiver.try_recv() {
+                Ok(line) => batch.push(line),
+                Err(_) => break,
+            }
+        }
+        let result = client
+            .post(url.clone())
+            .json(&batch)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            warn!(
+                "Unable to forward {} log lines to {}: {}",
+                batch.len(),
+                url,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn syslog_messages_carry_severity_and_source() {
+        let message = syslog_message(
+            LogStream::Stderr,
+            "2020-10-01T10:00:00Z",
+            "default/hello",
+            "app",
+            b"oops",
+        );
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "<11>1 2020-10-01T10:00:00Z default/hello app - - - oops"
+        );
+        let message = syslog_message(LogStream::Stdout, "", "default/hello", "app", b"hi");
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "<14>1 - default/hello app - - - hi"
+        );
+    }
+
+    #[test]
+    fn only_supported_schemes_are_accepted() {
+        let inner: Arc<dyn LogStorage> = Arc::new(super::super::memory::MemoryStorage::new(1024));
+        assert!(ForwardingStorage::new(inner.clone(), "ftp://collector").is_err());
+        assert!(ForwardingStorage::new(inner.clone(), "not a url").is_err());
+        assert!(ForwardingStorage::new(inner, "udp://127.0.0.1:5514").is_ok());
+    }
+}
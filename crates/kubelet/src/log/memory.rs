@@ -0,0 +1,219 @@
+//! Container logs kept in memory, for nodes without disk to spare for them.
+//!
+//! The log of each run of a container is a ring buffer of records. Once it reaches its maximum
+//! size, the oldest records are dropped to make room for new ones. Logs only last as long as
+//! something holds on to them, which is usually the handle of the container that wrote them.
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek};
+
+use super::storage::{ContainerLog, LogHandleFactory, LogReader, LogSink, LogStorage};
+use super::HandleFactory;
+use crate::pod::Pod;
+
+/// Keeps container logs in memory, with at most the given number of bytes for each run of a
+/// container.
+pub struct MemoryStorage {
+    max_size: u64,
+}
+
+impl MemoryStorage {
+    /// Create a storage that keeps at most `max_size` bytes of each log.
+    pub fn new(max_size: u64) -> Self {
+        MemoryStorage { max_size }
+    }
+}
+
+impl LogStorage for MemoryStorage {
+    fn create(&self, _pod: &Pod, _container_name: &str) -> std::io::Result<ContainerLog> {
+        let log = Arc::new(Mutex::new(MemoryLog {
+            data: VecDeque::new(),
+            start: 0,
+            max_size: self.max_size,
+        }));
+        Ok(ContainerLog {
+            sink: Box::new(MemorySink(log.clone())),
+            handles: LogHandleFactory::new(MemoryHandleFactory(log)),
+        })
+    }
+
+    fn remove_pod(&self, _pod: &Pod) -> std::io::Result<()> {
+        // The logs go away with the handles of the pod's containers
+        Ok(())
+    }
+}
+
+struct MemoryLog {
+    data: VecDeque<u8>,
+    /// How many bytes have been dropped from the start of the log
+    start: u64,
+    max_size: u64,
+}
+
+impl MemoryLog {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+}
+
+struct MemorySink(Arc<Mutex<MemoryLog>>);
+
+impl LogSink for MemorySink {
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<()> {
+        let mut log = self.0.lock().unwrap();
+        log.data.extend(record);
+        // Whole records are dropped, so that readers never see part of one
+        while log.data.len() as u64 > log.max_size {
+            let dropped = log
+                .data
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(log.data.len(), |i| i + 1);
+            log.data.drain(..dropped);
+            log.start += dropped as u64;
+        }
+        Ok(())
+    }
+}
+
+struct MemoryHandleFactory(Arc<Mutex<MemoryLog>>);
+
+impl HandleFactory<LogReader> for MemoryHandleFactory {
+    fn new_handle(&self) -> LogReader {
+        let base = self.0.lock().unwrap().start;
+        Box::new(MemoryReader {
+            log: self.0.clone(),
+            base,
+            pos: 0,
+        })
+    }
+}
+
+/// Reads a log kept in memory. Positions count from the start of the log when the reader was
+/// created, and reading from a position whose records have since been dropped carries on from the
+/// oldest record still kept.
+struct MemoryReader {
+    log: Arc<Mutex<MemoryLog>>,
+    base: u64,
+    pos: u64,
+}
+
+impl AsyncRead for MemoryReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let log = this.log.lock().unwrap();
+        let at = std::cmp::max(this.base + this.pos, log.start);
+        let offset = std::cmp::min(at - log.start, log.data.len() as u64) as usize;
+        let (front, back) = log.data.as_slices();
+        let available = if offset < front.len() {
+            &front[offset..]
+        } else {
+            &back[offset - front.len()..]
+        };
+        let n = std::cmp::min(available.len(), buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        this.pos = at + n as u64 - this.base;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncSeek for MemoryReader {
+    fn start_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        position: SeekFrom,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let end = this.log.lock().unwrap().end() - this.base;
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => offset(end, delta),
+            SeekFrom::Current(delta) => offset(this.pos, delta),
+        };
+        match pos {
+            Some(pos) => {
+                this.pos = pos;
+                Poll::Ready(Ok(()))
+            }
+            None => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ))),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+fn offset(pos: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        // Negating the most negative delta wraps back to it, which is still its magnitude as a u64
+        pos.checked_sub(delta.wrapping_neg() as u64)
+    } else {
+        pos.checked_add(delta as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    fn memory_log(max_size: u64) -> (Box<dyn LogSink>, LogHandleFactory) {
+        let log = MemoryStorage::new(max_size)
+            .create(&Pod::default(), "container")
+            .unwrap();
+        (log.sink, log.handles)
+    }
+
+    async fn read_all(handles: &LogHandleFactory) -> String {
+        let mut contents = String::new();
+        handles
+            .new_handle()
+            .read_to_string(&mut contents)
+            .await
+            .unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn oldest_records_are_dropped() {
+        let (mut sink, handles) = memory_log(10);
+        sink.write_record(b"one\n").unwrap();
+        sink.write_record(b"two\n").unwrap();
+        assert_eq!(read_all(&handles).await, "one\ntwo\n");
+        sink.write_record(b"three\n").unwrap();
+        assert_eq!(read_all(&handles).await, "two\nthree\n");
+        sink.write_record(b"a record too big to keep\n").unwrap();
+        assert_eq!(read_all(&handles).await, "");
+    }
+
+    #[tokio::test]
+    async fn readers_see_new_records_and_skip_dropped_ones() {
+        let (mut sink, handles) = memory_log(10);
+        sink.write_record(b"one\n").unwrap();
+        let mut reader = handles.new_handle();
+        assert_eq!(reader.seek(SeekFrom::End(0)).await.unwrap(), 4);
+        sink.write_record(b"two\n").unwrap();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "two\n");
+
+        sink.write_record(b"three\n").unwrap();
+        sink.write_record(b"four\n").unwrap();
+        reader.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "four\n");
+    }
+}
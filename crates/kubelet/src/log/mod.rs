@@ -1,14 +1,53 @@
 //! `log` contains convenient wrappers around fetching logs from the Kubernetes API.
+//!
+//! Logs may be written in the [CRI log format], where each line is recorded as
+//! `<timestamp> <stream> <tag> <content>`, so that lines written to stdout and stderr can be
+//! told apart. The tag is `F` for a full line and `P` for part of a line that continues in the
+//! next record. Lines that only start with a timestamp, or have no prefix at all, are also read.
+//!
+//! [CRI log format]: https://github.com/kubernetes/community/blob/master/contributors/design-proposals/node/kubelet-cri-logging.md
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
 use anyhow::bail;
-use log::{debug, error};
-use serde::Deserialize;
-use tokio::io::{AsyncBufReadExt, AsyncRead};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Deserializer};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tracing::{debug, error, warn};
+
+use crate::exec::ExecOutput;
+
+pub mod file;
+pub mod forward;
+pub mod memory;
+pub mod storage;
+
+/// How often logs are checked for new output when following them without a [`LogWatch`].
+pub(crate) const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often a follower waiting on a [`LogWatch`] checks whether the client has gone away.
+pub(crate) const FOLLOW_CLIENT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How many bytes are read at a time while searching backwards for the start of the tail.
+const TAIL_CHUNK_SIZE: usize = 4096;
+/// How many bytes of log output are buffered for a client by default before waiting for it.
+const DEFAULT_MAX_BUFFERED_BYTES: usize = 64 * 1024;
+/// How long a client is waited for by default before it is treated as too slow.
+const DEFAULT_SLOW_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A stream that yields whenever new log output may be available.
+pub type LogWatch = Pin<Box<dyn Stream<Item = ()> + Send>>;
 
 /// Possible errors sending log data.
 #[derive(Debug)]
 pub enum SendError {
     /// Client has disconnected.
     ChannelClosed,
+    /// The number of bytes requested by the client has been sent.
+    LimitReached,
+    /// The client didn't read the log output fast enough, and was disconnected.
+    SlowClient,
     /// An unexpected error occured.
     Abnormal(anyhow::Error),
 }
@@ -23,6 +62,8 @@ impl std::fmt::Display for SendError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             SendError::ChannelClosed => write!(f, "ChannelClosed"),
+            SendError::LimitReached => write!(f, "LimitReached"),
+            SendError::SlowClient => write!(f, "SlowClient"),
             SendError::Abnormal(e) => write!(f, "{}", e),
         }
     }
@@ -31,12 +72,31 @@ impl std::fmt::Display for SendError {
 impl std::error::Error for SendError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            SendError::ChannelClosed => None,
+            SendError::ChannelClosed | SendError::LimitReached | SendError::SlowClient => None,
             SendError::Abnormal(e) => Some(e.root_cause()),
         }
     }
 }
 
+/// The output stream a line of a container's log was written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum LogStream {
+    /// The container's standard output.
+    Stdout,
+    /// The container's standard error.
+    Stderr,
+}
+
+impl LogStream {
+    /// The name the stream is recorded under in the CRI log format.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 /// Client options for fetching logs.
 pub struct Options {
@@ -46,18 +106,113 @@ pub struct Options {
     /// determines whether the stream should stay open after tailing until the channel has closed.
     #[serde(default)]
     pub follow: bool,
+    /// only return lines logged within this many seconds before the request.
+    #[serde(rename = "sinceSeconds")]
+    pub since_seconds: Option<i64>,
+    /// only return lines logged at or after this time. Takes precedence over `since_seconds`.
+    #[serde(rename = "sinceTime")]
+    pub since_time: Option<DateTime<Utc>>,
+    /// the maximum number of bytes to stream back to the client.
+    #[serde(rename = "limitBytes")]
+    pub limit_bytes: Option<usize>,
+    /// determines whether the logs of the previous run of a restarted container should be returned.
+    #[serde(default)]
+    pub previous: bool,
+    /// determines whether each line should be prefixed with the RFC3339 timestamp it was logged at.
+    #[serde(default)]
+    pub timestamps: bool,
+    /// only return lines written to this stream. `All`, or no stream, returns lines from both.
+    #[serde(default, deserialize_with = "deserialize_stream")]
+    pub stream: Option<LogStream>,
+}
+
+fn deserialize_stream<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<LogStream>, D::Error> {
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("All") => Ok(None),
+        Some("Stdout") => Ok(Some(LogStream::Stdout)),
+        Some("Stderr") => Ok(Some(LogStream::Stderr)),
+        Some(other) => Err(serde::de::Error::unknown_variant(
+            other,
+            &["All", "Stdout", "Stderr"],
+        )),
+    }
+}
+
+/// What happens to log output a client doesn't read in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Drop the output the client didn't read, and carry on streaming to it.
+    Drop,
+    /// Drop the output the client didn't read, and end its stream with an error.
+    Disconnect,
+}
+
+/// How much log output is buffered for a client, and what happens when the client falls behind.
+#[derive(Clone, Debug)]
+pub struct Backpressure {
+    /// The most bytes buffered before waiting for the client to read them.
+    pub max_buffered_bytes: usize,
+    /// How long the client is waited for before the buffered output is dropped.
+    pub slow_client_timeout: Duration,
+    /// What happens once the client has been waited for too long.
+    pub policy: SlowClientPolicy,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Backpressure {
+            max_buffered_bytes: DEFAULT_MAX_BUFFERED_BYTES,
+            slow_client_timeout: DEFAULT_SLOW_CLIENT_TIMEOUT,
+            policy: SlowClientPolicy::Disconnect,
+        }
+    }
 }
 
 /// Sender for streaming logs to client.
+///
+/// Data is buffered until [`Sender::flush`] is called, or until more than the buffer limit of its
+/// [`Backpressure`] has been sent, at which point sending waits for the client to read it. A
+/// client that doesn't read it in time has the buffered data dropped, so that a slow or stalled
+/// client can't hold up the stream or have its output pile up in memory.
 pub struct Sender {
-    sender: hyper::body::Sender,
+    // Taken when the client is disconnected for being too slow
+    sender: Option<hyper::body::Sender>,
     opts: Options,
+    since: Option<DateTime<Utc>>,
+    // Whether the most recent timestamped line was recent enough, and from the right stream, to
+    // send. Lines without a timestamp are continuations of the line before them.
+    sending: bool,
+    remaining_bytes: Option<usize>,
+    backpressure: Backpressure,
+    // Data that hasn't been handed to the client yet
+    pending: Vec<u8>,
 }
 
 impl Sender {
     /// Create new `Sender` from `hyper::body::Sender`.
     pub fn new(sender: hyper::body::Sender, opts: Options) -> Self {
-        Sender { sender, opts }
+        let since = opts.since_time.or_else(|| {
+            opts.since_seconds
+                .map(|secs| Utc::now() - chrono::Duration::seconds(secs))
+        });
+        let remaining_bytes = opts.limit_bytes;
+        Sender {
+            sender: Some(sender),
+            opts,
+            since,
+            sending: true,
+            remaining_bytes,
+            backpressure: Backpressure::default(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Set how much data is buffered for the client, and what happens when it falls behind.
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
     }
 
     /// The tail flag indicated by the request if present.
@@ -65,53 +220,329 @@ impl Sender {
         self.opts.tail
     }
 
-    /// The follow flag indicated by the request, or `false` if absent.
+    /// The follow flag indicated by the request, or `false` if absent. The logs of a previous
+    /// run of a container are never followed, as it has already exited.
     pub fn follow(&self) -> bool {
-        self.opts.follow
+        self.opts.follow && !self.opts.previous
+    }
+
+    /// The previous flag indicated by the request, or `false` if absent.
+    pub fn previous(&self) -> bool {
+        self.opts.previous
+    }
+
+    /// Whether the client has gone away.
+    pub async fn is_closed(&mut self) -> bool {
+        let sender = match self.sender.as_mut() {
+            Some(sender) => sender,
+            None => return true,
+        };
+        futures::future::poll_fn(|cx| {
+            Poll::Ready(matches!(sender.poll_ready(cx), Poll::Ready(Err(_))))
+        })
+        .await
+    }
+
+    /// The timestamps flag indicated by the request, or `false` if absent.
+    pub fn timestamps(&self) -> bool {
+        self.opts.timestamps
+    }
+
+    /// The earliest time logs should be returned from, computed from the `sinceTime` or
+    /// `sinceSeconds` flags indicated by the request if present.
+    pub fn since(&self) -> Option<DateTime<Utc>> {
+        self.since
+    }
+
+    /// The stream indicated by the request, or `None` if lines from both streams are wanted.
+    pub fn stream(&self) -> Option<LogStream> {
+        self.opts.stream
     }
 
-    /// Async send some data to a client.
+    /// Async send some data to a client. Once the number of bytes requested by the client has been
+    /// sent, the data is truncated and `SendError::LimitReached` is returned from then on.
+    ///
+    /// The data is buffered, and only waits to be read by the client once the buffer is full.
     pub async fn send(&mut self, data: String) -> Result<(), SendError> {
-        let b: hyper::body::Bytes = data.into();
-        self.sender.send_data(b).await.map_err(|e| {
-            if e.is_closed() {
+        let mut b = data.into_bytes();
+        if let Some(remaining) = self.remaining_bytes.as_mut() {
+            if *remaining == 0 {
+                return Err(SendError::LimitReached);
+            }
+            b.truncate(*remaining);
+            *remaining -= b.len();
+        }
+        self.pending.extend_from_slice(&b);
+        if self.pending.len() >= self.backpressure.max_buffered_bytes {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Hand the buffered data to the client, waiting for it to be read for as long as the
+    /// [`Backpressure`] allows. If the client doesn't read it in time, the data is dropped and,
+    /// unless slow clients are only dropped from, `SendError::SlowClient` is returned.
+    pub async fn flush(&mut self) -> Result<(), SendError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let sender = self.sender.as_mut().ok_or(SendError::SlowClient)?;
+        let data: hyper::body::Bytes = std::mem::take(&mut self.pending).into();
+        let len = data.len();
+        match tokio::time::timeout(
+            self.backpressure.slow_client_timeout,
+            sender.send_data(data),
+        )
+        .await
+        {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) if e.is_closed() => {
                 debug!("channel closed.");
-                SendError::ChannelClosed
-            } else {
+                Err(SendError::ChannelClosed)
+            }
+            Ok(Err(e)) => {
                 error!("channel error: {}", e);
-                SendError::Abnormal(anyhow::Error::new(e))
+                Err(SendError::Abnormal(anyhow::Error::new(e)))
             }
-        })
+            Err(_) => {
+                crate::metrics::LOG_DROPPED_BYTES.inc_by(len as u64);
+                match self.backpressure.policy {
+                    SlowClientPolicy::Drop => {
+                        warn!(
+                            "Log client hasn't read any output in {:?}, dropped {} bytes",
+                            self.backpressure.slow_client_timeout, len
+                        );
+                        Ok(())
+                    }
+                    SlowClientPolicy::Disconnect => {
+                        warn!(
+                            "Log client hasn't read any output in {:?}, disconnecting it and \
+                             dropping {} bytes",
+                            self.backpressure.slow_client_timeout, len
+                        );
+                        // Aborting the body tells the client its stream ended in an error,
+                        // rather than with the end of the log
+                        if let Some(sender) = self.sender.take() {
+                            sender.abort();
+                        }
+                        Err(SendError::SlowClient)
+                    }
+                }
+            }
+        }
     }
 }
 
-/// Stream last `n` lines.
-async fn tail<R: AsyncRead + std::marker::Unpin>(
-    lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
-    sender: &mut Sender,
-    n: usize,
-) -> Result<(), SendError> {
-    let mut line_buf = std::collections::VecDeque::with_capacity(n);
+/// Send a line of the log, unless it was logged before the time requested by the client or was
+/// written to a stream the client didn't ask for.
+///
+/// Lines are filtered on the RFC3339 timestamp and stream they start with. Lines without a
+/// timestamp are treated as part of the line before them, and are always sent if there is no line
+/// before them. Lines without a stream are sent whichever stream was asked for. The stream is
+/// always removed, and the timestamp is removed unless the client asked for timestamps.
+async fn send_line(sender: &mut Sender, line: String) -> Result<(), SendError> {
+    let record = Record::parse(line.as_bytes());
+    if let Some(timestamp) = record.timestamp {
+        let recent = sender.since.map_or(true, |since| timestamp >= since);
+        let wanted = match (sender.stream(), record.stream) {
+            (Some(wanted), Some(stream)) => wanted == stream,
+            _ => true,
+        };
+        sender.sending = recent && wanted;
+    }
+    if !sender.sending {
+        return Ok(());
+    }
+    let mut data = Vec::with_capacity(line.len() + 1);
+    if record.timestamp.is_some() && sender.timestamps() {
+        data.extend_from_slice(record.raw_timestamp);
+        data.push(b' ');
+    }
+    data.extend_from_slice(record.content);
+    if !record.partial {
+        data.push(b'\n');
+    }
+    sender
+        .send(String::from_utf8_lossy(&data).into_owned())
+        .await
+}
 
-    while let Some(line) = match lines.next_line().await {
-        Ok(line) => line,
-        Err(e) => {
-            let err = format!("Error reading from log: {:?}", e);
-            error!("{}", &err);
-            sender.send(err).await?;
-            return Err(e.into());
+/// A line of a log, split into the prefix it was recorded with and its content.
+struct Record<'a> {
+    /// When the line was written, if it was recorded with a timestamp
+    timestamp: Option<DateTime<Utc>>,
+    /// The timestamp as it was recorded
+    raw_timestamp: &'a [u8],
+    /// The stream the line was written to, if it was recorded with one
+    stream: Option<LogStream>,
+    /// Whether the line continues in the next record
+    partial: bool,
+    /// The line as it was written
+    content: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    fn parse(line: &'a [u8]) -> Self {
+        let unprefixed = Record {
+            timestamp: None,
+            raw_timestamp: &[],
+            stream: None,
+            partial: false,
+            content: line,
+        };
+        let end = match line.iter().position(|b| *b == b' ') {
+            Some(end) => end,
+            None => return unprefixed,
+        };
+        let timestamp = match std::str::from_utf8(&line[..end])
+            .ok()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        {
+            Some(timestamp) => timestamp.with_timezone(&Utc),
+            None => return unprefixed,
+        };
+        let rest = &line[end + 1..];
+        let (stream, rest) = if rest.starts_with(b"stdout ") {
+            (Some(LogStream::Stdout), &rest[7..])
+        } else if rest.starts_with(b"stderr ") {
+            (Some(LogStream::Stderr), &rest[7..])
+        } else {
+            (None, rest)
+        };
+        let (partial, content) = match stream {
+            Some(_) if rest.starts_with(b"F ") || rest == b"F" => (false, rest.get(2..)),
+            Some(_) if rest.starts_with(b"P ") || rest == b"P" => (true, rest.get(2..)),
+            _ => (false, Some(rest)),
+        };
+        Record {
+            timestamp: Some(timestamp),
+            raw_timestamp: &line[..end],
+            stream,
+            partial,
+            content: content.unwrap_or_default(),
         }
-    } {
-        if line_buf.len() == n {
-            line_buf.pop_front();
+    }
+}
+
+/// Format a line written to the given stream as a record of the CRI log format. The line should
+/// not include the newline that ended it.
+pub fn format_record(stream: LogStream, timestamp: DateTime<Utc>, line: &[u8]) -> Vec<u8> {
+    let timestamp = timestamp.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true);
+    let mut record = Vec::with_capacity(timestamp.len() + 10 + line.len() + 1);
+    record.extend_from_slice(timestamp.as_bytes());
+    record.push(b' ');
+    record.extend_from_slice(stream.as_str().as_bytes());
+    record.extend_from_slice(b" F ");
+    record.extend_from_slice(line);
+    record.push(b'\n');
+    record
+}
+
+/// Take the complete lines from the start of `buf` and remove their timestamps and streams,
+/// grouping consecutive lines written to the same stream into one chunk of output. Lines recorded
+/// without a stream are treated as stdout. Any incomplete line at the end of `buf` is left there
+/// until the rest of it has been read.
+///
+/// This is useful when streaming raw log output to a client, such as when attaching to a
+/// container.
+pub fn take_lines(buf: &mut Vec<u8>) -> Vec<ExecOutput> {
+    let end = match buf.iter().rposition(|b| *b == b'\n') {
+        Some(i) => i + 1,
+        None => return Vec::new(),
+    };
+    let rest = buf.split_off(end);
+    let complete = std::mem::replace(buf, rest);
+    let mut chunks = Vec::new();
+    let mut current: Option<(LogStream, Vec<u8>)> = None;
+    // The last line ends with a newline, so skip it to avoid splitting off an empty line after it
+    for line in complete[..end - 1].split(|b| *b == b'\n') {
+        let record = Record::parse(line);
+        let stream = record.stream.unwrap_or(LogStream::Stdout);
+        match current.as_mut() {
+            Some((current_stream, _)) if *current_stream == stream => (),
+            _ => chunks.extend(current.replace((stream, Vec::new())).map(into_output)),
+        }
+        if let Some((_, data)) = current.as_mut() {
+            data.extend_from_slice(record.content);
+            if !record.partial {
+                data.push(b'\n');
+            }
+        }
+    }
+    chunks.extend(current.map(into_output));
+    chunks
+}
+
+fn into_output((stream, data): (LogStream, Vec<u8>)) -> ExecOutput {
+    match stream {
+        LogStream::Stdout => ExecOutput::Stdout(data),
+        LogStream::Stderr => ExecOutput::Stderr(data),
+    }
+}
+
+/// Read the content of the last `n` lines of the log, without their timestamps and streams,
+/// keeping at most `max_bytes` from the end of them.
+///
+/// This is useful for reporting what a container last wrote, such as when it fails without
+/// saying why.
+pub async fn tail<R: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    mut handle: R,
+    n: usize,
+    max_bytes: usize,
+) -> std::io::Result<String> {
+    seek_to_tail(&mut handle, n).await?;
+    let mut buf = Vec::new();
+    handle.read_to_end(&mut buf).await?;
+    if n == 0 || buf.is_empty() {
+        return Ok(String::new());
+    }
+    if buf.last() != Some(&b'\n') {
+        buf.push(b'\n');
+    }
+    let mut content = Vec::with_capacity(buf.len());
+    for line in buf[..buf.len() - 1].split(|b| *b == b'\n') {
+        let record = Record::parse(line);
+        content.extend_from_slice(record.content);
+        if !record.partial {
+            content.push(b'\n');
         }
-        line_buf.push_back(line);
     }
+    let start = content.len().saturating_sub(max_bytes);
+    Ok(String::from_utf8_lossy(&content[start..]).into_owned())
+}
 
-    for mut line in line_buf {
-        line.push('\n');
-        sender.send(line).await?;
+/// Seek to the start of the last `n` lines, searching backwards from the end of the log.
+async fn seek_to_tail<R: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    handle: &mut R,
+    n: usize,
+) -> std::io::Result<()> {
+    let end = handle.seek(SeekFrom::End(0)).await?;
+    if n == 0 {
+        return Ok(());
     }
+    let mut buf = vec![0u8; TAIL_CHUNK_SIZE];
+    let mut pos = end;
+    let mut newlines = 0;
+    while pos > 0 {
+        let len = std::cmp::min(pos, TAIL_CHUNK_SIZE as u64) as usize;
+        pos -= len as u64;
+        handle.seek(SeekFrom::Start(pos)).await?;
+        handle.read_exact(&mut buf[..len]).await?;
+        for (i, byte) in buf[..len].iter().enumerate().rev() {
+            let line_start = pos + i as u64 + 1;
+            // The newline ending the log terminates the last line rather than starting a new one
+            if *byte != b'\n' || line_start == end {
+                continue;
+            }
+            newlines += 1;
+            if newlines == n {
+                handle.seek(SeekFrom::Start(line_start)).await?;
+                return Ok(());
+            }
+        }
+    }
+    // The log has fewer than `n` lines
+    handle.seek(SeekFrom::Start(0)).await?;
     Ok(())
 }
 
@@ -120,7 +551,7 @@ async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
     lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
     sender: &mut Sender,
 ) -> Result<(), SendError> {
-    while let Some(mut line) = match lines.next_line().await {
+    while let Some(line) = match lines.next_line().await {
         Ok(line) => line,
         Err(e) => {
             let err = format!("Error reading from log: {:?}", e);
@@ -129,47 +560,57 @@ async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
             return Err(e.into());
         }
     } {
-        line.push('\n');
-        sender.send(line).await?;
+        send_line(sender, line).await?;
     }
     Ok(())
 }
 
 /// Future that streams logs from provided `AsyncRead` to provided `Sender`.
-pub async fn stream<R: AsyncRead + std::marker::Unpin>(
-    handle: R,
+///
+/// The log is streamed from the start of the handle, or from the start of its last lines if the
+/// client asked for a tail. When following logs, new output is streamed whenever `watch` signals a
+/// change. If there is no watch, the log is polled for new output instead.
+pub async fn stream<R: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    mut handle: R,
     mut sender: Sender,
+    mut watch: Option<LogWatch>,
 ) -> anyhow::Result<()> {
+    if let Some(n) = sender.tail() {
+        seek_to_tail(&mut handle, n).await?;
+    }
     let buf = tokio::io::BufReader::new(handle);
     let mut lines = buf.lines();
 
-    if let Some(n) = sender.tail() {
-        match tail(&mut lines, &mut sender, n).await {
+    loop {
+        // What was read is handed to the client even if the limit was reached while reading it
+        let result = stream_to_end(&mut lines, &mut sender).await;
+        match result.and(sender.flush().await) {
             Ok(_) => (),
-            Err(SendError::ChannelClosed) => return Ok(()),
+            Err(SendError::ChannelClosed)
+            | Err(SendError::LimitReached)
+            | Err(SendError::SlowClient) => return Ok(()),
             Err(SendError::Abnormal(e)) => bail!(e),
         }
-    } else {
-        match stream_to_end(&mut lines, &mut sender).await {
-            Ok(_) => (),
-            Err(SendError::ChannelClosed) => return Ok(()),
-            Err(SendError::Abnormal(e)) => bail!(e),
+        if !sender.follow() {
+            return Ok(());
         }
-    }
 
-    if sender.follow() {
-        loop {
-            match stream_to_end(&mut lines, &mut sender).await {
-                Ok(_) => (),
-                Err(SendError::ChannelClosed) => return Ok(()),
-                Err(SendError::Abnormal(e)) => bail!(e),
+        match watch.as_mut() {
+            Some(w) => {
+                tokio::select! {
+                    change = w.next() => if change.is_none() {
+                        debug!("Log watch ended, falling back to polling");
+                        watch = None;
+                    },
+                    _ = tokio::time::delay_for(FOLLOW_CLIENT_CHECK_INTERVAL) => (),
+                }
             }
-
-            tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+            None => tokio::time::delay_for(FOLLOW_POLL_INTERVAL).await,
+        }
+        if sender.is_closed().await {
+            return Ok(());
         }
     }
-
-    Ok(())
 }
 
 // TODO: Both providers make a handle containing a tempfile. If this is a common pattern,
@@ -179,4 +620,205 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
 pub trait HandleFactory<R>: Sync + Send {
     /// Create new log reader.
     fn new_handle(&self) -> R;
+
+    /// Watch the log for new output, so that following it doesn't require polling.
+    ///
+    /// The default implementation returns `None`, in which case followers poll for new output.
+    fn watch(&self) -> Option<LogWatch> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options() -> Options {
+        Options {
+            tail: None,
+            follow: false,
+            since_seconds: None,
+            since_time: None,
+            limit_bytes: None,
+            previous: false,
+            timestamps: false,
+            stream: None,
+        }
+    }
+
+    async fn read_log(log: &str, opts: Options) -> String {
+        let (sender, body) = hyper::Body::channel();
+        let handle = std::io::Cursor::new(log.as_bytes().to_vec());
+        let (result, body) = tokio::join!(
+            stream(handle, Sender::new(sender, opts), None),
+            hyper::body::to_bytes(body)
+        );
+        result.unwrap();
+        String::from_utf8(body.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tail_returns_last_lines() {
+        let log = "one\ntwo\nthree\n";
+        let tail = |n| Options {
+            tail: Some(n),
+            ..options()
+        };
+        assert_eq!(read_log(log, tail(2)).await, "two\nthree\n");
+        assert_eq!(read_log(log, tail(0)).await, "");
+        assert_eq!(read_log(log, tail(5)).await, log);
+        assert_eq!(read_log("one\ntwo", tail(1)).await, "two\n");
+    }
+
+    #[tokio::test]
+    async fn tail_reads_content_of_last_lines() {
+        let log = "2020-10-01T10:00:00Z stdout F one\n2020-10-01T10:00:01Z stderr P tw\n\
+                   2020-10-01T10:00:01Z stderr F o\nthree\n";
+        let handle = || std::io::Cursor::new(log.as_bytes().to_vec());
+        assert_eq!(tail(handle(), 3, 100).await.unwrap(), "two\nthree\n");
+        assert_eq!(tail(handle(), 10, 100).await.unwrap(), "one\ntwo\nthree\n");
+        assert_eq!(tail(handle(), 10, 4).await.unwrap(), "ree\n");
+        assert_eq!(tail(handle(), 0, 100).await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn since_time_skips_older_lines() {
+        let log = "2020-10-01T10:00:00Z old\ncontinued\n2020-10-01T12:00:00Z new\nalso new\n";
+        let opts = Options {
+            since_time: Some("2020-10-01T11:00:00Z".parse().unwrap()),
+            timestamps: true,
+            ..options()
+        };
+        assert_eq!(
+            read_log(log, opts).await,
+            "2020-10-01T12:00:00Z new\nalso new\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn timestamps_are_removed_unless_requested() {
+        let log = "2020-10-01T10:00:00.123456789Z hello world\nno timestamp\n";
+        assert_eq!(
+            read_log(log, options()).await,
+            "hello world\nno timestamp\n"
+        );
+        let opts = Options {
+            timestamps: true,
+            ..options()
+        };
+        assert_eq!(read_log(log, opts).await, log);
+    }
+
+    #[test]
+    fn take_lines_leaves_incomplete_line() {
+        let mut buf = b"2020-10-01T10:00:00Z one\ntwo\n2020-10-01T10:00:01Z thr".to_vec();
+        assert_eq!(
+            take_lines(&mut buf),
+            vec![ExecOutput::Stdout(b"one\ntwo\n".to_vec())]
+        );
+        assert_eq!(buf, b"2020-10-01T10:00:01Z thr".to_vec());
+        buf.extend_from_slice(b"ee\n");
+        assert_eq!(
+            take_lines(&mut buf),
+            vec![ExecOutput::Stdout(b"three\n".to_vec())]
+        );
+        assert!(buf.is_empty());
+    }
+
+    const CRI_LOG: &str = "2020-10-01T10:00:00Z stdout F one\n\
+                           2020-10-01T10:00:01Z stderr F oops\n\
+                           2020-10-01T10:00:02Z stdout P tw\n\
+                           2020-10-01T10:00:02Z stdout F o\n";
+
+    #[test]
+    fn take_lines_groups_lines_by_stream() {
+        let mut buf = CRI_LOG.as_bytes().to_vec();
+        assert_eq!(
+            take_lines(&mut buf),
+            vec![
+                ExecOutput::Stdout(b"one\n".to_vec()),
+                ExecOutput::Stderr(b"oops\n".to_vec()),
+                ExecOutput::Stdout(b"two\n".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn cri_records_are_filtered_by_stream() {
+        assert_eq!(read_log(CRI_LOG, options()).await, "one\noops\ntwo\n");
+        let stream = |stream| Options {
+            stream: Some(stream),
+            ..options()
+        };
+        assert_eq!(
+            read_log(CRI_LOG, stream(LogStream::Stdout)).await,
+            "one\ntwo\n"
+        );
+        assert_eq!(read_log(CRI_LOG, stream(LogStream::Stderr)).await, "oops\n");
+        let opts = Options {
+            timestamps: true,
+            ..stream(LogStream::Stderr)
+        };
+        assert_eq!(read_log(CRI_LOG, opts).await, "2020-10-01T10:00:01Z oops\n");
+    }
+
+    #[test]
+    fn records_are_formatted_for_the_cri_log_format() {
+        let timestamp = "2020-10-01T10:00:00Z".parse().unwrap();
+        let record = format_record(LogStream::Stderr, timestamp, b"oops");
+        assert_eq!(
+            record,
+            b"2020-10-01T10:00:00.000000000Z stderr F oops\n".to_vec()
+        );
+        let parsed = Record::parse(&record[..record.len() - 1]);
+        assert_eq!(parsed.timestamp, Some(timestamp));
+        assert_eq!(parsed.stream, Some(LogStream::Stderr));
+        assert_eq!(parsed.content, b"oops");
+    }
+
+    #[tokio::test]
+    async fn slow_clients_are_disconnected() {
+        let (sender, mut body) = hyper::Body::channel();
+        let mut sender = Sender::new(sender, options()).with_backpressure(Backpressure {
+            max_buffered_bytes: 4,
+            slow_client_timeout: Duration::from_millis(10),
+            policy: SlowClientPolicy::Disconnect,
+        });
+        // The body's channel has room for one chunk that hasn't been read
+        sender.send("one\n".to_owned()).await.unwrap();
+        let dropped = crate::metrics::LOG_DROPPED_BYTES.get();
+        assert!(matches!(
+            sender.send("two\n".to_owned()).await,
+            Err(SendError::SlowClient)
+        ));
+        assert!(crate::metrics::LOG_DROPPED_BYTES.get() >= dropped + 4);
+        assert!(sender.is_closed().await);
+
+        assert_eq!(body.next().await.unwrap().unwrap(), "one\n");
+        assert!(body.next().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn output_is_dropped_for_slow_clients_when_asked() {
+        let (sender, mut body) = hyper::Body::channel();
+        let mut sender = Sender::new(sender, options()).with_backpressure(Backpressure {
+            max_buffered_bytes: 4,
+            slow_client_timeout: Duration::from_millis(10),
+            policy: SlowClientPolicy::Drop,
+        });
+        sender.send("one\n".to_owned()).await.unwrap();
+        sender.send("two\n".to_owned()).await.unwrap();
+        assert_eq!(body.next().await.unwrap().unwrap(), "one\n");
+        sender.send("three\n".to_owned()).await.unwrap();
+        assert_eq!(body.next().await.unwrap().unwrap(), "three\n");
+    }
+
+    #[tokio::test]
+    async fn limit_bytes_truncates_output() {
+        let opts = Options {
+            limit_bytes: Some(6),
+            ..options()
+        };
+        assert_eq!(read_log("one\ntwo\nthree\n", opts).await, "one\ntw");
+    }
 }
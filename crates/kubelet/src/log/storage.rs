@@ -0,0 +1,96 @@
+//! Where container logs are kept.
+//!
+//! Providers write the records of each run of a container to a [`LogSink`] and read them back
+//! through a [`LogHandleFactory`], both of which come from a [`LogStorage`]. Which storage is used
+//! is chosen in the kubelet config, and [`from_config`] creates it.
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncSeek};
+
+use super::file::{FileStorage, LogRotation};
+use super::forward::ForwardingStorage;
+use super::memory::MemoryStorage;
+use super::{HandleFactory, LogWatch};
+use crate::config::{Config, LogStorageKind};
+use crate::pod::Pod;
+
+/// A reader of a container's log.
+pub trait AsyncReadSeek: AsyncRead + AsyncSeek + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncSeek + Send + Unpin> AsyncReadSeek for T {}
+
+/// A reader of a container's log, whichever storage it is kept in.
+pub type LogReader = Box<dyn AsyncReadSeek>;
+
+/// Takes the records written to the log of one run of a container.
+pub trait LogSink: Send {
+    /// Write a record, in the CRI log format, to the log. This may block, so it should be called
+    /// from a blocking task or a thread of its own.
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<()>;
+}
+
+/// Keeps the logs of containers.
+pub trait LogStorage: Send + Sync {
+    /// Start the log of a new run of one of a pod's containers. The logs of runs before the
+    /// previous one may be discarded.
+    ///
+    /// This may block, so it should be called from a blocking task.
+    fn create(&self, pod: &Pod, container_name: &str) -> std::io::Result<ContainerLog>;
+
+    /// Discard the logs of a pod that has been removed.
+    ///
+    /// This may block, so it should be called from a blocking task.
+    fn remove_pod(&self, pod: &Pod) -> std::io::Result<()>;
+}
+
+/// The log of one run of a container.
+pub struct ContainerLog {
+    /// Where the records of the run are written
+    pub sink: Box<dyn LogSink>,
+    /// How the records of the run are read back
+    pub handles: LogHandleFactory,
+}
+
+/// Opens readers of the log of one run of a container, whichever storage it is kept in.
+#[derive(Clone)]
+pub struct LogHandleFactory {
+    inner: Arc<dyn HandleFactory<LogReader>>,
+}
+
+impl LogHandleFactory {
+    /// Wrap a storage's way of opening readers of a log.
+    pub fn new<F: HandleFactory<LogReader> + 'static>(handle_factory: F) -> Self {
+        LogHandleFactory {
+            inner: Arc::new(handle_factory),
+        }
+    }
+}
+
+impl HandleFactory<LogReader> for LogHandleFactory {
+    fn new_handle(&self) -> LogReader {
+        self.inner.new_handle()
+    }
+
+    fn watch(&self) -> Option<LogWatch> {
+        self.inner.watch()
+    }
+}
+
+/// Create the log storage the kubelet config asks for. Forwarding starts a task, so this must be
+/// called from within a Tokio runtime.
+pub fn from_config(config: &Config) -> anyhow::Result<Arc<dyn LogStorage>> {
+    let storage: Arc<dyn LogStorage> = match config.log_storage {
+        LogStorageKind::File => Arc::new(FileStorage::new(
+            &config.pod_log_dir,
+            LogRotation {
+                max_size: config.container_log_max_size,
+                max_files: config.container_log_max_files,
+            },
+        )),
+        LogStorageKind::Memory => Arc::new(MemoryStorage::new(config.container_log_max_size)),
+    };
+    match &config.log_forward_url {
+        Some(url) => Ok(Arc::new(ForwardingStorage::new(storage, url)?)),
+        None => Ok(storage),
+    }
+}
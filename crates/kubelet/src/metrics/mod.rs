@@ -0,0 +1,123 @@
+//! `metrics` contains the metrics the kubelet exports in Prometheus format from `/metrics`.
+//!
+//! Providers can export their own metrics alongside these by passing them to [`register`].
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use lazy_static::lazy_static;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::pod::Pod;
+
+lazy_static! {
+    /// The number of pods the kubelet is currently managing.
+    pub static ref PODS: IntGauge =
+        IntGauge::new("krustlet_pods", "Number of pods managed by the kubelet").unwrap();
+    /// How long pods took to start running, measured from when they were created.
+    pub static ref POD_START_DURATION: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "krustlet_pod_start_duration_seconds",
+            "Time from pod creation until the pod started running",
+        )
+        .buckets(vec![0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0])
+    )
+    .unwrap();
+    /// The number of requests served by the kubelet webserver, labelled by endpoint.
+    pub static ref SERVER_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "krustlet_server_requests_total",
+            "Number of requests served by the kubelet webserver",
+        ),
+        &["endpoint"],
+    )
+    .unwrap();
+    /// How long it took to pull the images for a pod.
+    pub static ref IMAGE_PULL_DURATION: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "krustlet_image_pull_duration_seconds",
+            "Time taken to pull the images for a pod",
+        )
+        .buckets(vec![0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0])
+    )
+    .unwrap();
+    /// The number of bytes of log output dropped because the client streaming them was too slow.
+    pub static ref LOG_DROPPED_BYTES: IntCounter = IntCounter::new(
+        "krustlet_log_dropped_bytes_total",
+        "Number of bytes of log output dropped for clients that didn't read them in time",
+    )
+    .unwrap();
+    /// The number of failures of the WebAssembly runtime reported, labelled by problem.
+    pub static ref RUNTIME_FAILURES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "krustlet_runtime_failures_total",
+            "Number of failures of the WebAssembly runtime that point to a problem with the node",
+        ),
+        &["problem"],
+    )
+    .unwrap();
+    static ref REGISTRY: Registry = {
+        let registry = Registry::new();
+        registry.register(Box::new(PODS.clone())).unwrap();
+        registry
+            .register(Box::new(POD_START_DURATION.clone()))
+            .unwrap();
+        registry.register(Box::new(SERVER_REQUESTS.clone())).unwrap();
+        registry
+            .register(Box::new(IMAGE_PULL_DURATION.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(LOG_DROPPED_BYTES.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(RUNTIME_FAILURES.clone()))
+            .unwrap();
+        registry
+    };
+}
+
+/// Register a collector so that its metrics are exported alongside the kubelet's own.
+pub fn register(collector: Box<dyn Collector>) -> anyhow::Result<()> {
+    Ok(REGISTRY.register(collector)?)
+}
+
+/// Record that a pod has started running. Providers should call this once per pod, when the pod
+/// enters its running state.
+pub fn observe_pod_start(pod: &Pod) {
+    if let Some(Time(created)) = &pod.as_kube_pod().metadata.creation_timestamp {
+        let elapsed = chrono::Utc::now().signed_duration_since(*created);
+        if let Ok(elapsed) = elapsed.to_std() {
+            POD_START_DURATION.observe(elapsed.as_secs_f64());
+        }
+    }
+}
+
+/// Render all registered metrics in the Prometheus text exposition format.
+pub(crate) fn gather() -> anyhow::Result<(String, Vec<u8>)> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok((encoder.format_type().to_owned(), buffer))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gathers_registered_metrics() {
+        SERVER_REQUESTS.with_label_values(&["test"]).inc();
+        let custom =
+            prometheus::IntCounter::new("provider_custom_total", "A custom metric").unwrap();
+        custom.inc();
+        register(Box::new(custom)).unwrap();
+
+        let (content_type, body) = gather().unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(content_type.starts_with("text/plain"));
+        assert!(body.contains("krustlet_server_requests_total{endpoint=\"test\"}"));
+        assert!(body.contains("provider_custom_total 1"));
+        assert!(body.contains("krustlet_pods"));
+    }
+}
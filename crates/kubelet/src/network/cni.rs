@@ -0,0 +1,324 @@
+//! Running CNI plugins as the CNI specification describes, to attach network namespaces to a
+//! network and detach them from it.
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The name of the interface that plugins create in the network namespace.
+const INTERFACE_NAME: &str = "eth0";
+
+/// A network configuration: the plugins that are run, in order, to attach a network namespace to
+/// the network.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NetworkConfig {
+    name: String,
+    cni_version: String,
+    plugins: Vec<Value>,
+}
+
+impl NetworkConfig {
+    /// Load the first network configuration in the directory, in order of file name, as the
+    /// Kubernetes kubelet does. Configurations are either lists of plugins, in `.conflist` files,
+    /// or a single plugin, in `.conf` or `.json` files.
+    pub(crate) async fn load(dir: &Path) -> anyhow::Result<Self> {
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        let mut files = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("conflist") | Some("conf") | Some("json") => files.push(path),
+                _ => (),
+            }
+        }
+        files.sort();
+        let path = files.first().ok_or_else(|| {
+            anyhow::anyhow!("no network configuration found in {}", dir.display())
+        })?;
+        let config = serde_json::from_slice(&tokio::fs::read(path).await?).map_err(|e| {
+            anyhow::anyhow!("invalid network configuration {}: {}", path.display(), e)
+        })?;
+        let is_list = path
+            .extension()
+            .map_or(false, |extension| extension == "conflist");
+        NetworkConfig::parse(config, is_list)
+            .map_err(|e| anyhow::anyhow!("invalid network configuration {}: {}", path.display(), e))
+    }
+
+    fn parse(config: Value, is_list: bool) -> anyhow::Result<Self> {
+        let field = |name: &str| {
+            config
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+                .ok_or_else(|| anyhow::anyhow!("missing {}", name))
+        };
+        let name = field("name")?;
+        let cni_version = field("cniVersion")?;
+        let plugins = if is_list {
+            config
+                .get("plugins")
+                .and_then(Value::as_array)
+                .filter(|plugins| !plugins.is_empty())
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("missing plugins"))?
+        } else {
+            vec![config]
+        };
+        if let Some(plugin) = plugins.iter().find(|plugin| plugin.get("type").is_none()) {
+            anyhow::bail!("plugin {} has no type", plugin);
+        }
+        Ok(NetworkConfig {
+            name,
+            cni_version,
+            plugins,
+        })
+    }
+}
+
+/// How plugins are run for a network namespace.
+pub(crate) struct Invocation {
+    pub(crate) bin_dirs: Vec<PathBuf>,
+    /// The ID that plugins know the attachment by, which must be the same when it is deleted
+    pub(crate) container_id: String,
+    pub(crate) netns: PathBuf,
+    /// Arguments that plugins may act on, such as the namespace and name of the pod
+    pub(crate) args: Vec<(String, String)>,
+}
+
+impl Invocation {
+    /// Attach the network namespace to the network, returning the result of the last plugin.
+    pub(crate) async fn add(&self, config: &NetworkConfig) -> anyhow::Result<Value> {
+        let mut result: Option<Value> = None;
+        for plugin in &config.plugins {
+            // Plugins that only act on the result of earlier plugins may not return one
+            if let Some(plugin_result) = self.exec("ADD", config, plugin, result.as_ref()).await? {
+                result = Some(plugin_result);
+            }
+        }
+        result.ok_or_else(|| anyhow::anyhow!("network plugins returned no result"))
+    }
+
+    /// Detach the network namespace from the network, running the plugins in reverse order with
+    /// the result of attaching it, if there was one. Every plugin is run, even if one fails.
+    pub(crate) async fn del(
+        &self,
+        config: &NetworkConfig,
+        result: Option<&Value>,
+    ) -> anyhow::Result<()> {
+        let mut failure = None;
+        for plugin in config.plugins.iter().rev() {
+            if let Err(e) = self.exec("DEL", config, plugin, result).await {
+                failure.get_or_insert(e);
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    async fn exec(
+        &self,
+        command: &str,
+        config: &NetworkConfig,
+        plugin: &Value,
+        prev_result: Option<&Value>,
+    ) -> anyhow::Result<Option<Value>> {
+        let plugin_type = plugin
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let binary = self
+            .bin_dirs
+            .iter()
+            .map(|dir| dir.join(plugin_type))
+            .find(|binary| binary.is_file())
+            .ok_or_else(|| anyhow::anyhow!("network plugin {} was not found", plugin_type))?;
+
+        let mut stdin = plugin.clone();
+        stdin["name"] = config.name.clone().into();
+        stdin["cniVersion"] = config.cni_version.clone().into();
+        if let Some(prev_result) = prev_result {
+            stdin["prevResult"] = prev_result.clone();
+        }
+        let stdin = serde_json::to_vec(&stdin)?;
+        let mut plugin_command = Command::new(&binary);
+        plugin_command
+            .env("CNI_COMMAND", command)
+            .env("CNI_CONTAINERID", &self.container_id)
+            .env("CNI_NETNS", &self.netns)
+            .env("CNI_IFNAME", INTERFACE_NAME)
+            .env("CNI_ARGS", cni_args(&self.args))
+            .env("CNI_PATH", std::env::join_paths(&self.bin_dirs)?)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = tokio::task::spawn_blocking(move || {
+            let mut child = plugin_command.spawn()?;
+            if let Some(mut plugin_stdin) = child.stdin.take() {
+                plugin_stdin.write_all(&stdin)?;
+            }
+            child.wait_with_output()
+        })
+        .await??;
+
+        if !output.status.success() {
+            // Plugins report errors as JSON on stdout
+            let message = serde_json::from_slice::<Value>(&output.stdout)
+                .ok()
+                .and_then(|error| error.get("msg").and_then(Value::as_str).map(str::to_owned))
+                .unwrap_or_else(|| String::from_utf8_lossy(&output.stderr).trim().to_owned());
+            anyhow::bail!(
+                "network plugin {} failed to {}: {}",
+                plugin_type,
+                command,
+                message
+            );
+        }
+        if output.stdout.iter().all(u8::is_ascii_whitespace) {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&output.stdout).map_err(
+            |e| {
+                anyhow::anyhow!(
+                    "network plugin {} returned an invalid result: {}",
+                    plugin_type,
+                    e
+                )
+            },
+        )?))
+    }
+}
+
+/// Arguments in the form plugins take them, as `key=value` pairs separated by semicolons.
+/// Plugins are told to ignore arguments they don't know, as most only know some of them.
+fn cni_args(args: &[(String, String)]) -> String {
+    std::iter::once("IgnoreUnknown=1".to_owned())
+        .chain(args.iter().map(|(key, value)| format!("{}={}", key, value)))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// The IPs that plugins gave the network namespace, from their result.
+pub(crate) fn ips(result: &Value) -> anyhow::Result<Vec<IpAddr>> {
+    // Results from version 0.3.0 of the specification on list addresses under `ips`, while
+    // earlier ones have an address for each family
+    let addresses: Vec<&str> = match result.get("ips").and_then(Value::as_array) {
+        Some(ips) => ips
+            .iter()
+            .filter_map(|ip| ip.get("address").and_then(Value::as_str))
+            .collect(),
+        None => ["ip4", "ip6"]
+            .iter()
+            .filter_map(|family| result.get(family)?.get("ip")?.as_str())
+            .collect(),
+    };
+    if addresses.is_empty() {
+        anyhow::bail!("network plugins didn't give an IP");
+    }
+    addresses
+        .into_iter()
+        .map(|address| {
+            // Addresses are given with the prefix length of their subnet
+            address
+                .split('/')
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid IP {:?} in network result: {}", address, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn network_config_lists_and_single_plugins_are_parsed() {
+        let config = NetworkConfig::parse(
+            json!({
+                "cniVersion": "0.4.0",
+                "name": "cluster",
+                "plugins": [
+                    { "type": "bridge", "bridge": "cni0" },
+                    { "type": "portmap", "capabilities": { "portMappings": true } }
+                ]
+            }),
+            true,
+        )
+        .unwrap();
+        assert_eq!(config.name, "cluster");
+        assert_eq!(config.plugins.len(), 2);
+
+        let config = NetworkConfig::parse(
+            json!({ "cniVersion": "0.3.1", "name": "cluster", "type": "bridge" }),
+            false,
+        )
+        .unwrap();
+        assert_eq!(config.cni_version, "0.3.1");
+        assert_eq!(config.plugins[0]["type"], "bridge");
+    }
+
+    #[test]
+    fn invalid_network_configs_are_rejected() {
+        assert!(
+            NetworkConfig::parse(json!({ "name": "cluster", "type": "bridge" }), false).is_err()
+        );
+        assert!(NetworkConfig::parse(
+            json!({ "cniVersion": "0.4.0", "name": "cluster", "plugins": [] }),
+            true
+        )
+        .is_err());
+        assert!(NetworkConfig::parse(
+            json!({ "cniVersion": "0.4.0", "name": "cluster", "plugins": [{ "bridge": "cni0" }] }),
+            true
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn ips_are_read_from_results_of_any_version() {
+        let result = json!({
+            "cniVersion": "0.4.0",
+            "interfaces": [{ "name": "eth0" }],
+            "ips": [
+                { "version": "4", "address": "10.244.1.5/24", "gateway": "10.244.1.1" },
+                { "version": "6", "address": "fd00:10:244:1::5/64" }
+            ]
+        });
+        assert_eq!(
+            ips(&result).unwrap(),
+            vec![
+                "10.244.1.5".parse::<IpAddr>().unwrap(),
+                "fd00:10:244:1::5".parse::<IpAddr>().unwrap()
+            ]
+        );
+
+        let result = json!({ "cniVersion": "0.2.0", "ip4": { "ip": "10.244.1.6/24" } });
+        assert_eq!(
+            ips(&result).unwrap(),
+            vec!["10.244.1.6".parse::<IpAddr>().unwrap()]
+        );
+
+        assert!(ips(&json!({ "cniVersion": "0.4.0", "ips": [] })).is_err());
+    }
+
+    #[test]
+    fn args_are_joined_for_plugins() {
+        let args = vec![
+            ("K8S_POD_NAMESPACE".to_owned(), "default".to_owned()),
+            ("K8S_POD_NAME".to_owned(), "hello".to_owned()),
+        ];
+        assert_eq!(
+            cni_args(&args),
+            "IgnoreUnknown=1;K8S_POD_NAMESPACE=default;K8S_POD_NAME=hello"
+        );
+    }
+}
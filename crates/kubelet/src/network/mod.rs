@@ -0,0 +1,163 @@
+//! Pod networking through CNI plugins.
+//!
+//! When the kubelet is given a CNI configuration directory, each pod that doesn't use the host's
+//! network gets a network namespace of its own, which the plugins of the first network
+//! configuration in the directory attach to the cluster's network. The IPs the plugins give the
+//! namespace are reported as the pod's IPs, and providers run the pod's containers in the
+//! namespace, which they find with [`namespace_path`]. The namespace is detached and destroyed
+//! once the pod is deleted.
+//!
+//! What the plugins returned is kept alongside the namespace in the kubelet's data directory, so
+//! that a pod keeps its IPs when the kubelet restarts, and so that the plugins are given it when
+//! the namespace is detached, as they expect to be.
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::pod::Pod;
+
+mod cni;
+mod namespace;
+
+use cni::{Invocation, NetworkConfig};
+
+/// The directory under the kubelet's data directory that network namespaces are kept in.
+const NETWORK_DIR: &str = "network";
+
+/// What attaching a pod's network namespace to the network did, which is needed to detach it.
+#[derive(Deserialize, Serialize)]
+struct Attachment {
+    network: NetworkConfig,
+    result: Value,
+}
+
+/// Sets up and tears down the networks of pods.
+pub(crate) struct Network {
+    conf_dir: PathBuf,
+    bin_dirs: Vec<PathBuf>,
+    dir: PathBuf,
+}
+
+impl Network {
+    /// The pod network for the kubelet's configuration, unless it doesn't set one up.
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        let conf_dir = config.cni_conf_dir.clone()?;
+        Some(Network {
+            conf_dir,
+            bin_dirs: config.cni_bin_dirs.clone(),
+            dir: config.data_dir.join(NETWORK_DIR),
+        })
+    }
+
+    /// Give the pod a network namespace attached to the network, returning the pod's IPs. A pod
+    /// whose namespace is already attached keeps the IPs it was given.
+    pub(crate) async fn setup(&self, pod: &Pod) -> anyhow::Result<Vec<IpAddr>> {
+        let attachment_path = self.dir.join(attachment_file(pod));
+        if let Ok(attachment) = tokio::fs::read(&attachment_path).await {
+            if let Ok(attachment) = serde_json::from_slice::<Attachment>(&attachment) {
+                debug!("Reusing network of pod {}", pod.name());
+                return cni::ips(&attachment.result);
+            }
+        }
+
+        let network = NetworkConfig::load(&self.conf_dir).await?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let netns = self.dir.join(namespace_file(pod));
+        namespace::create(&netns).await?;
+        let invocation = self.invocation(pod, netns.clone());
+        let attached = match invocation.add(&network).await {
+            Ok(result) => cni::ips(&result).map(|ips| (result, ips)),
+            Err(e) => Err(e),
+        };
+        let (result, ips) = match attached {
+            Ok(attached) => attached,
+            Err(e) => {
+                // Plugins that succeeded may have allocated addresses, which are given back
+                if let Err(e) = invocation.del(&network, None).await {
+                    warn!("Unable to detach network of pod {}: {}", pod.name(), e);
+                }
+                if let Err(e) = namespace::remove(&netns).await {
+                    warn!(
+                        "Unable to remove network namespace of pod {}: {}",
+                        pod.name(),
+                        e
+                    );
+                }
+                return Err(e);
+            }
+        };
+        let attachment = Attachment { network, result };
+        tokio::fs::write(&attachment_path, serde_json::to_vec(&attachment)?).await?;
+        debug!("Attached pod {} to network with IPs {:?}", pod.name(), ips);
+        Ok(ips)
+    }
+
+    /// Detach the pod's network namespace from the network and destroy it, if it has one.
+    pub(crate) async fn teardown(&self, pod: &Pod) -> anyhow::Result<()> {
+        let attachment_path = self.dir.join(attachment_file(pod));
+        let attachment = match tokio::fs::read(&attachment_path).await {
+            Ok(attachment) => attachment,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let attachment: Attachment = serde_json::from_slice(&attachment)?;
+        let netns = self.dir.join(namespace_file(pod));
+        self.invocation(pod, netns.clone())
+            .del(&attachment.network, Some(&attachment.result))
+            .await?;
+        namespace::remove(&netns).await?;
+        tokio::fs::remove_file(&attachment_path).await?;
+        debug!("Detached pod {} from network", pod.name());
+        Ok(())
+    }
+
+    fn invocation(&self, pod: &Pod, netns: PathBuf) -> Invocation {
+        let container_id = namespace_file(pod);
+        Invocation {
+            bin_dirs: self.bin_dirs.clone(),
+            args: vec![
+                ("K8S_POD_NAMESPACE".to_owned(), pod.namespace().to_owned()),
+                ("K8S_POD_NAME".to_owned(), pod.name().to_owned()),
+                (
+                    "K8S_POD_INFRA_CONTAINER_ID".to_owned(),
+                    container_id.clone(),
+                ),
+            ],
+            container_id,
+            netns,
+        }
+    }
+}
+
+/// The pod's network namespace, if it has been given one, for a kubelet with the given data
+/// directory.
+pub fn namespace_path(data_dir: &Path, pod: &Pod) -> Option<PathBuf> {
+    let path = data_dir.join(NETWORK_DIR).join(namespace_file(pod));
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Run a function on a thread in the network namespace at the given path, such as to listen on a
+/// socket that is reachable on the pod's IPs.
+pub fn in_namespace<T, F>(netns: &Path, f: F) -> std::io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+{
+    namespace::enter(netns, f)
+}
+
+fn namespace_file(pod: &Pod) -> String {
+    format!("{}_{}", pod.namespace(), pod.name())
+}
+
+fn attachment_file(pod: &Pod) -> String {
+    format!("{}.json", namespace_file(pod))
+}
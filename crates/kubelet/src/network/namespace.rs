@@ -0,0 +1,129 @@
+//! Network namespaces, kept alive by bind mounting them onto a file as `ip netns` does, so that
+//! they outlive the thread that created them and can be entered by path.
+use std::path::Path;
+
+/// Create a network namespace, bind mounted onto the file at the given path.
+#[cfg(target_os = "linux")]
+pub(super) async fn create(path: &Path) -> anyhow::Result<()> {
+    tokio::fs::File::create(path).await?;
+    let target = path.to_owned();
+    let created = tokio::task::spawn_blocking(move || {
+        // The namespace is created on a thread of its own, as unsharing moves the thread that
+        // does it into the new namespace for good
+        std::thread::spawn(move || unshare_onto(&target))
+            .join()
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "thread creating network namespace panicked",
+                ))
+            })
+    })
+    .await?;
+    if let Err(e) = created {
+        tokio::fs::remove_file(path).await.ok();
+        anyhow::bail!("unable to create network namespace: {}", e);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn unshare_onto(target: &Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let source = CString::new("/proc/thread-self/ns/net")?;
+    let target = CString::new(target.as_os_str().as_bytes())?;
+    // SAFETY: only this thread is moved into the new namespace, and it exits once it has been
+    // mounted
+    if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: both paths are valid C strings that outlive the call
+    let mounted = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            std::ptr::null(),
+            libc::MS_BIND,
+            std::ptr::null(),
+        )
+    };
+    if mounted != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) async fn create(_path: &Path) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "network namespaces can only be created on Linux"
+    ))
+}
+
+/// Unmount a network namespace from the file at the given path and remove the file, which
+/// destroys the namespace once nothing else is in it.
+#[cfg(target_os = "linux")]
+pub(super) async fn remove(path: &Path) -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let target = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: the path is a valid C string that outlives the call
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        let e = std::io::Error::last_os_error();
+        // The namespace may not have been mounted if creating it failed part way
+        if e.raw_os_error() != Some(libc::EINVAL) {
+            anyhow::bail!("unable to unmount network namespace: {}", e);
+        }
+    }
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) async fn remove(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Run a function on a thread that has joined the network namespace bound to the file at the
+/// given path, such as to listen on a socket in it.
+#[cfg(target_os = "linux")]
+pub(super) fn enter<T, F>(path: &Path, f: F) -> std::io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+{
+    use std::os::unix::io::AsRawFd;
+
+    let namespace = std::fs::File::open(path)?;
+    std::thread::spawn(move || {
+        // SAFETY: the file is a network namespace, which only this thread joins
+        if unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        f()
+    })
+    .join()
+    .unwrap_or_else(|_| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "thread in network namespace panicked",
+        ))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn enter<T, F>(_path: &Path, _f: F) -> std::io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+{
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "network namespaces can only be entered on Linux",
+    ))
+}
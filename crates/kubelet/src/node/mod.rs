@@ -2,7 +2,10 @@
 //! nodes operating within the cluster.
 use crate::config::Config;
 use crate::container::Status as ContainerStatus;
-use crate::pod::{Phase, Pod};
+use crate::pod::{
+    disruption_target_conditions, mark_disruption_target, EventType, Phase, Pod,
+    TERMINATION_BY_KUBELET,
+};
 use crate::provider::Provider;
 use chrono::prelude::*;
 use futures::{StreamExt, TryStreamExt};
@@ -10,15 +13,23 @@ use k8s_openapi::api::coordination::v1::Lease;
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Node as KubeNode;
 use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference, Taint};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::api::{Api, ListParams, ObjectMeta, PatchParams, PostParams};
 use kube::error::ErrorResponse;
 use kube::Error;
-use log::{debug, error, info, warn};
 use std::collections::BTreeMap;
 use std::sync::Arc;
+use tracing::{debug, error, info, warn};
+
+pub mod problem;
+pub(crate) mod status;
+pub(crate) mod taint;
 
 const KUBELET_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The namespace that node leases are kept in.
+const NODE_LEASE_NAMESPACE: &str = "kube-node-lease";
 
 macro_rules! retry {
     ($action:expr, times: $num_times:expr, error: $on_err:expr) => {{
@@ -60,12 +71,64 @@ macro_rules! retry {
     };
 }
 
+/// The properties of a node that depend on the provider running its pods, which the provider gives
+/// through [`Provider::node_props`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NodeProps {
+    /// The architecture of the node's `kubernetes.io/arch` label, such as `wasm32-wasi`.
+    pub arch: String,
+    /// The operating system of the node's `kubernetes.io/os` label.
+    pub os: String,
+    /// The taints the node is created with, which pods have to tolerate to run on it.
+    pub taints: Vec<Taint>,
+    /// Resources other than CPU, memory and storage that the node has, such as
+    /// `example.com/gpu`, which are reported as both capacity and allocatable.
+    pub extended_resources: BTreeMap<String, Quantity>,
+    /// The most pods the provider can run. The node reports the lower of this and the configured
+    /// max pods.
+    pub max_pods: Option<u16>,
+}
+
+impl NodeProps {
+    /// The properties of a node reporting the given architecture, and the same as its operating
+    /// system, without taints or extended resources.
+    pub fn new(arch: &str) -> Self {
+        NodeProps {
+            arch: arch.to_owned(),
+            os: arch.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Add a taint to the node.
+    pub fn add_taint(&mut self, effect: &str, key: &str, value: &str) {
+        self.taints.push(Taint {
+            effect: effect.to_owned(),
+            key: key.to_owned(),
+            value: Some(value.to_owned()),
+            time_added: None,
+        });
+    }
+
+    /// Add an extended resource to the node.
+    pub fn add_extended_resource(&mut self, name: &str, quantity: &str) {
+        self.extended_resources
+            .insert(name.to_owned(), Quantity(quantity.to_owned()));
+    }
+}
+
 /// Create a node
 ///
-/// A node comes with a lease, and we maintain the lease to tell Kubernetes that the
-/// node remains alive and functional. Note that this will not work in
+/// The node is labelled, tainted and given capacity as the provider's [`NodeProps`] say. Unless
+/// node leases are disabled in the config, a node comes with a lease, and we maintain the lease to
+/// tell Kubernetes that the node remains alive and functional. Note that this will not work in
 /// versions of Kubernetes prior to 1.14.
-pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provider: Arc<P>) {
+pub async fn create<P: Provider>(
+    client: &kube::Client,
+    config: &Config,
+    props: &NodeProps,
+    provider: Arc<P>,
+) {
     let node_client: Api<KubeNode> = Api::all(client.clone());
 
     match retry!(node_client.get(&config.node_name).await, times: 4, break_on: &Error::Api(ErrorResponse { code: 404, .. }))
@@ -94,32 +157,33 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
         "true",
     );
 
-    node_labels_definition(P::ARCH, &config, &mut builder);
-
-    // TODO Do we want to detect this?
-    builder.add_capacity("cpu", "4");
-    builder.add_capacity("ephemeral-storage", "61255492Ki");
-    builder.add_capacity("hugepages-1Gi", "0");
-    builder.add_capacity("hugepages-2Mi", "0");
-    builder.add_capacity("memory", "4032800Ki");
-    builder.add_capacity("pods", &config.max_pods.to_string());
+    node_labels_definition(props, &config, &mut builder);
+    for taint in &props.taints {
+        builder.add_taint(
+            &taint.effect,
+            &taint.key,
+            taint.value.as_deref().unwrap_or_default(),
+        );
+    }
 
-    builder.add_allocatable("cpu", "4");
-    builder.add_allocatable("ephemeral-storage", "61255492Ki");
-    builder.add_allocatable("hugepages-1Gi", "0");
-    builder.add_allocatable("hugepages-2Mi", "0");
-    builder.add_allocatable("memory", "4032800Ki");
-    builder.add_allocatable("pods", &config.max_pods.to_string());
+    let (capacity, allocatable) = status::resources(config, props);
+    for (key, value) in capacity.iter() {
+        builder.add_capacity(key, &value.0);
+    }
+    for (key, value) in allocatable.iter() {
+        builder.add_allocatable(key, &value.0);
+    }
 
     let ts = Utc::now();
-    builder.add_condition("Ready", "True", &ts, "KubeletReady", "kubelet is ready");
-    builder.add_condition(
-        "OutOfDisk",
-        "False",
-        &ts,
-        "KubeletHasSufficientDisk",
-        "kubelet has sufficient disk space available",
-    );
+    for condition in status::conditions(config, &[]) {
+        builder.add_condition(
+            &condition.type_,
+            &condition.status,
+            &ts,
+            condition.reason.as_deref().unwrap_or_default(),
+            condition.message.as_deref().unwrap_or_default(),
+        );
+    }
 
     builder.add_address("InternalIP", &format!("{}", config.node_ip));
     builder.add_address("Hostname", &config.hostname);
@@ -135,9 +199,18 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
     match retry!(node_client.create(&PostParams::default(), &node).await, times: 4) {
         Ok(node) => {
             let node_uid = node.metadata.uid.unwrap();
-            if let Err(e) = create_lease(&node_uid, &config.node_name, &client).await {
-                error!("Failed to create lease: {}", e);
-                return;
+            if config.node_lease {
+                if let Err(e) = create_lease(
+                    &node_uid,
+                    &config.node_name,
+                    config.node_lease_duration,
+                    &client,
+                )
+                .await
+                {
+                    error!("Failed to create lease: {}", e);
+                    return;
+                }
             }
         }
         Err(e) => {
@@ -173,8 +246,66 @@ pub async fn uid(client: &kube::Client, node_name: &str) -> anyhow::Result<Strin
     }
 }
 
+/// Record an event about the node, which shows up in `kubectl describe node`. Like the
+/// Kubernetes kubelet, events about nodes are kept in the default namespace.
+pub(crate) async fn record_event(
+    client: &kube::Client,
+    node_name: &str,
+    event_type: EventType,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let now = Time(Utc::now());
+    let event = Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}.", node_name)),
+            namespace: Some("default".to_owned()),
+            ..Default::default()
+        },
+        involved_object: ObjectReference {
+            kind: Some("Node".to_owned()),
+            name: Some(node_name.to_owned()),
+            // Nodes are referred to by name in their events
+            uid: Some(node_name.to_owned()),
+            ..Default::default()
+        },
+        reason: Some(reason.to_owned()),
+        message: Some(message.to_owned()),
+        type_: Some(format!("{:?}", event_type)),
+        first_timestamp: Some(now.clone()),
+        last_timestamp: Some(now),
+        count: Some(1),
+        reporting_component: Some("kubelet".to_owned()),
+        reporting_instance: Some(node_name.to_owned()),
+        source: Some(EventSource {
+            component: Some("kubelet".to_owned()),
+            host: Some(node_name.to_owned()),
+        }),
+        ..Default::default()
+    };
+    let events: Api<Event> = Api::namespaced(client.clone(), "default");
+    events.create(&PostParams::default(), &event).await?;
+    Ok(())
+}
+
+/// Marks the node unschedulable, so that no new pods are scheduled to it.
+pub async fn cordon(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    let patch = serde_json::json!({ "spec": { "unschedulable": true } });
+    node_client
+        .patch(
+            node_name,
+            &PatchParams::default(),
+            serde_json::to_vec(&patch)?,
+        )
+        .await?;
+    info!("Cordoned node '{}'", node_name);
+    Ok(())
+}
+
 /// Cordons node and evicts all pods.
 pub async fn drain(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    cordon(client, node_name).await?;
     evict_pods(client, node_name).await?;
     Ok(())
 }
@@ -196,11 +327,16 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
 
     info!("Evicting {} pods.", pods.len());
 
+    // Lower priority pods are evicted first
+    let mut pods: Vec<Pod> = pods.into_iter().map(Pod::from).collect();
+    pods.sort_by_key(Pod::priority);
     for pod in pods {
-        let pod = Pod::from(pod);
         if pod.is_daemonset() {
             info!("Skipping eviction of DaemonSet '{}'", pod.name());
             continue;
+        } else if pod.is_critical_static() {
+            info!("Skipping eviction of critical static pod '{}'", pod.name());
+            continue;
         } else if pod.is_static() {
             let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
             let patch = serde_json::json!(
@@ -211,6 +347,11 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
                     "status": {
                         "phase": Phase::Succeeded,
                         "reason": "Pod terminated on node shutdown.",
+                        "conditions": disruption_target_conditions(
+                            &pod,
+                            TERMINATION_BY_KUBELET,
+                            "Pod terminated on node shutdown.",
+                        ),
                         "containerStatuses": pod.all_containers().iter().map(|container| {
                             ContainerStatus::Terminated {
                                 timestamp: Utc::now(),
@@ -228,6 +369,21 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
             info!("Marked static pod as terminated.");
             continue;
         } else {
+            let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+            if let Err(e) = mark_disruption_target(
+                &api,
+                &pod,
+                TERMINATION_BY_KUBELET,
+                "Evicted on node shutdown",
+            )
+            .await
+            {
+                warn!(
+                    "Unable to mark pod '{}' as a disruption target: {:?}",
+                    pod.name(),
+                    e
+                );
+            }
             match evict_pod(&client, pod.name(), pod.namespace(), &mut stream).await {
                 Ok(_) => (),
                 Err(e) => {
@@ -276,41 +432,76 @@ async fn evict_pod(
     Ok(())
 }
 
-/// Update the timestamps on the Node object.
+/// Renew the node's lease, creating it if it doesn't exist.
 ///
 /// This is how we report liveness to the upstream.
-/// If we are unable to update the node after several retries we panic, as we could be in an
+/// If we are unable to update the lease after several retries we panic, as we could be in an
 /// inconsistent state
-pub async fn update(client: &kube::Client, node_name: &str) {
+pub async fn update(client: &kube::Client, config: &Config) {
+    let node_name = &config.node_name;
     debug!("Updating node '{}'", node_name);
     if let Ok(uid) = uid(client, node_name).await {
         debug!("Node to update '{}' fetched.", node_name);
-        retry!(update_lease(&uid, node_name, client).await, times: 4)
-            .expect("Could not update lease");
-        retry!(update_status(node_name, client).await, times: 4)
-            .expect("Could not update node status");
+        retry!(
+            update_lease(&uid, node_name, config.node_lease_duration, client).await,
+            times: 4
+        )
+        .expect("Could not update lease");
+    }
+}
+
+/// Update the status of the Node object: the capacity and allocatable resources of the machine,
+/// its conditions, what it runs and how to reach it.
+///
+/// Failing to update the status is logged rather than fatal, as the lease is what tells the
+/// upstream the node is alive, and the status is updated again on the next attempt.
+pub async fn update_status(client: &kube::Client, config: &Config, props: &NodeProps) {
+    debug!("Updating status of node '{}'", config.node_name);
+    if let Err(e) = retry!(patch_status(client, config, props).await, times: 4) {
+        error!(
+            "Unable to update status of node '{}': {}",
+            config.node_name, e
+        );
     }
 }
 
-async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result<()> {
-    // TODO: Update the lastTransitionTime properly
+async fn patch_status(
+    client: &kube::Client,
+    config: &Config,
+    props: &NodeProps,
+) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    let node = node_client.get(&config.node_name).await?;
+    let existing = node
+        .status
+        .and_then(|status| status.conditions)
+        .unwrap_or_default();
+    let (capacity, allocatable) = status::resources(config, props);
+    let conditions = status::conditions(config, &existing);
+    if let Some(runtime) = conditions
+        .iter()
+        .find(|condition| condition.type_ == problem::CONDITION_TYPE)
+    {
+        problem::record_change(client, &config.node_name, &existing, runtime).await;
+    }
     let status_patch = serde_json::json!({
         "status": {
-            "conditions": [
-                {
-                    "lastHeartbeatTime": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
-                    "message": "kubelet is posting ready status",
-                    "reason": "KubeletReady",
-                    "status": "True",
-                    "type": "Ready"
-                }
+            "capacity": capacity,
+            "allocatable": allocatable,
+            "conditions": conditions,
+            "nodeInfo": status::node_info(),
+            "addresses": [
+                { "type": "InternalIP", "address": config.node_ip.to_string() },
+                { "type": "Hostname", "address": config.hostname },
             ],
+            "daemonEndpoints": {
+                "kubeletEndpoint": { "Port": config.server_config.port },
+            },
         }
     });
-    let node_client: Api<KubeNode> = Api::all(client.clone());
     let _node = node_client
         .patch_status(
-            node_name,
+            &config.node_name,
             &PatchParams::default(),
             serde_json::to_vec(&status_patch)?,
         )
@@ -327,11 +518,16 @@ async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result
 ///
 /// As far as I can tell, leases ALWAYS go in the 'kube-node-lease'
 /// namespace, no exceptions.
-async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) -> Result<(), Error> {
+async fn create_lease(
+    node_uid: &str,
+    node_name: &str,
+    duration: std::time::Duration,
+    client: &kube::Client,
+) -> Result<(), Error> {
     debug!("Creating lease for node '{}'", node_name);
-    let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
+    let leases: Api<Lease> = Api::namespaced(client.clone(), NODE_LEASE_NAMESPACE);
 
-    let lease = lease_definition(node_uid, node_name);
+    let lease = lease_definition(node_uid, node_name, duration);
     let lease = serde_json::from_value(lease)
         .expect("failed to deserialize lease from lease definition JSON");
 
@@ -363,28 +559,39 @@ async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) ->
 /// Update the Kubernetes node lease, essentially requesting that we keep
 /// the lease for another period.
 ///
-/// TODO: Our patch is overzealous right now. We just need to update the
-/// timestamp.
+/// Only the renewal time and who holds the lease are patched, so the time
+/// the lease was first acquired is kept. If the lease has gone, for example
+/// because the node was registered with leases disabled, it is created.
 async fn update_lease(
     node_uid: &str,
     node_name: &str,
+    duration: std::time::Duration,
     client: &kube::Client,
-) -> Result<Lease, Error> {
+) -> Result<(), Error> {
     debug!("Updating lease for node '{}'...", node_name);
-    let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
+    let leases: Api<Lease> = Api::namespaced(client.clone(), NODE_LEASE_NAMESPACE);
 
-    let lease = lease_definition(node_uid, node_name);
+    let renewal = serde_json::json!({ "spec": lease_renewal_definition(node_name, duration) });
     let lease_data =
-        serde_json::to_vec(&lease).expect("Lease should always be serializable to JSON");
+        serde_json::to_vec(&renewal).expect("Lease should always be serializable to JSON");
 
     let resp = leases
         .patch(node_name, &PatchParams::default(), lease_data)
         .await;
-    match &resp {
-        Ok(_) => debug!("Lease updated for '{}'", node_name),
-        Err(e) => error!("Failed to update lease for '{}': {}", node_name, e),
+    match resp {
+        Ok(_) => {
+            debug!("Lease updated for '{}'", node_name);
+            Ok(())
+        }
+        Err(Error::Api(ErrorResponse { code: 404, .. })) => {
+            info!("Lease for '{}' not found, creating it", node_name);
+            create_lease(node_uid, node_name, duration, client).await
+        }
+        Err(e) => {
+            error!("Failed to update lease for '{}': {}", node_name, e);
+            Err(e)
+        }
     }
-    resp
 }
 
 /// Define a new coordination.Lease object for Kubernetes
@@ -392,7 +599,11 @@ async fn update_lease(
 /// The lease tells Kubernetes that we want to claim the node for a while
 /// longer. And then tells Kubernetes how long it should wait before
 /// expecting a new lease.
-fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
+fn lease_definition(
+    node_uid: &str,
+    node_name: &str,
+    duration: std::time::Duration,
+) -> serde_json::Value {
     serde_json::json!(
         {
             "apiVersion": "coordination.k8s.io/v1",
@@ -408,7 +619,7 @@ fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
                     }
                 ]
             },
-            "spec": lease_spec_definition(node_name)
+            "spec": lease_spec_definition(node_name, duration)
         }
     )
 }
@@ -416,7 +627,17 @@ fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
 /// Defines a new coordiation lease for Kubernetes
 ///
 /// We set the lease times, the lease duration, and the node name.
-fn lease_spec_definition(node_name: &str) -> serde_json::Value {
+fn lease_spec_definition(node_name: &str, duration: std::time::Duration) -> serde_json::Value {
+    let mut spec = lease_renewal_definition(node_name, duration);
+    spec["acquireTime"] = spec["renewTime"].clone();
+    spec
+}
+
+/// Defines the parts of a lease's spec that change when it is renewed
+///
+/// The node controller considers the node unhealthy once the renewal time is
+/// more than the lease duration ago.
+fn lease_renewal_definition(node_name: &str, duration: std::time::Duration) -> serde_json::Value {
     // Workaround for https://github.com/deislabs/krustlet/issues/5
     // In the future, use LeaseSpec rather than a JSON value
     let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
@@ -424,24 +645,24 @@ fn lease_spec_definition(node_name: &str) -> serde_json::Value {
     serde_json::json!(
         {
             "holderIdentity": node_name,
-            "acquireTime": now,
             "renewTime": now,
-            "leaseDurationSeconds": 300
+            "leaseDurationSeconds": duration.as_secs()
         }
     )
 }
 
 /// Defines the labels that will be applied to this node
 ///
-/// Default values and passed node-labels arguments are injected by config.
-fn node_labels_definition(arch: &str, config: &Config, builder: &mut Builder) {
+/// The architecture and OS labels are those of the provider's node properties. Default values and
+/// passed node-labels arguments are injected by config.
+fn node_labels_definition(props: &NodeProps, config: &Config, builder: &mut Builder) {
     // Add mandatory static labels
-    builder.add_label("beta.kubernetes.io/os", arch);
-    builder.add_label("kubernetes.io/os", arch);
+    builder.add_label("beta.kubernetes.io/os", &props.os);
+    builder.add_label("kubernetes.io/os", &props.os);
     builder.add_label("type", "krustlet");
     // add the mandatory labels that are dependent on injected values
-    builder.add_label("beta.kubernetes.io/arch", arch);
-    builder.add_label("kubernetes.io/arch", arch);
+    builder.add_label("beta.kubernetes.io/arch", &props.arch);
+    builder.add_label("kubernetes.io/arch", &props.arch);
     builder.add_label("kubernetes.io/hostname", &config.hostname);
 
     let k8s_namespace = "kubernetes.io";
@@ -752,18 +973,64 @@ mod test {
                 port: 8080,
                 cert_file: PathBuf::new(),
                 private_key_file: PathBuf::new(),
+                insecure_plaintext: false,
+                tls_min_version: crate::config::TlsVersion::Tls12,
+                rotate_certificates: false,
+                client_ca_file: None,
+                authentication_token_webhook: false,
+                authorization_mode: crate::config::AuthorizationMode::AlwaysAllow,
+                request_timeout: std::time::Duration::from_secs(60),
+                log_request_timeout: std::time::Duration::from_secs(60),
+                exec_request_timeout: std::time::Duration::from_secs(60),
+                attach_request_timeout: std::time::Duration::from_secs(60),
+                max_streams: 100,
+                enable_profiling: false,
+                audit_log_path: None,
+                audit_webhook_url: None,
             },
             bootstrap_file: "doesnt/matter".into(),
             allow_local_modules: false,
             insecure_registries: None,
             data_dir: PathBuf::new(),
             plugins_dir: PathBuf::new(),
+            device_plugins_dir: PathBuf::new(),
+            cni_conf_dir: None,
+            cni_bin_dirs: vec![],
+            cluster_dns: vec![],
+            cluster_domain: None,
+            resolv_conf: PathBuf::new(),
+            allowed_host_paths: vec![],
+            module_cache_size_limit: None,
+            image_verification_keys: vec![],
+            pod_log_dir: PathBuf::new(),
+            container_log_max_size: 10 * 1024 * 1024,
+            container_log_max_files: 5,
+            log_storage: crate::config::LogStorageKind::File,
+            log_forward_url: None,
+            log_level: None,
+            otlp_endpoint: None,
+            feature_gates: Default::default(),
+            config_file: None,
             node_labels,
             max_pods: 110,
+            pod_registration_workers: 10,
+            node_status_update_frequency: std::time::Duration::from_secs(10),
+            node_lease: true,
+            node_lease_duration: std::time::Duration::from_secs(40),
+            shutdown_grace_period: std::time::Duration::from_secs(30),
+            shutdown_grace_period_critical_pods: std::time::Duration::from_secs(10),
+            pod_manifest_path: None,
+            system_reserved: HashMap::new(),
+            eviction_hard: Default::default(),
         };
 
+        let props = NodeProps {
+            arch: "wasm32-wasi".to_owned(),
+            os: "linux".to_owned(),
+            ..Default::default()
+        };
         let mut builder = Node::builder();
-        node_labels_definition("linux", &config, &mut builder);
+        node_labels_definition(&props, &config, &mut builder);
 
         let result = builder.labels;
 
@@ -773,5 +1040,22 @@ mod test {
         assert!(result.contains_key("kubernetes.io/instance-type"));
         assert!(!result.get("beta.kubernetes.io/os").unwrap().eq("managed"));
         assert!(result.get("beta.kubernetes.io/os").unwrap().eq("linux"));
+        assert!(result.get("kubernetes.io/arch").unwrap().eq("wasm32-wasi"));
+    }
+
+    #[test]
+    fn test_lease_definitions() {
+        let duration = std::time::Duration::from_secs(40);
+        let lease: Lease =
+            serde_json::from_value(lease_definition("node-uid", "bar", duration)).unwrap();
+        let spec = lease.spec.unwrap();
+        assert_eq!(lease.metadata.name.as_deref(), Some("bar"));
+        assert_eq!(spec.holder_identity.as_deref(), Some("bar"));
+        assert_eq!(spec.lease_duration_seconds, Some(40));
+        assert_eq!(spec.acquire_time, spec.renew_time);
+
+        let renewal = lease_renewal_definition("bar", duration);
+        assert_eq!(renewal["leaseDurationSeconds"], 40);
+        assert!(renewal.get("acquireTime").is_none());
     }
 }
@@ -0,0 +1,364 @@
+//! Detection of recurring failures of the node's WebAssembly runtime, which are reported through
+//! the node's `WasmRuntimeUnhealthy` condition so that cluster automation can cordon or replace a
+//! node whose runtime keeps failing.
+//!
+//! Providers and the kubelet [`report`] each failure as it happens. A [`Problem`] is detected once
+//! it has been reported a number of times within its window, and the condition is true for as long
+//! as it stays that way. Failures are forgotten once they are older than the window, and problems
+//! that can be seen to be over, such as a registry that can be reached again, can be [`resolve`]d
+//! straight away. A warning event is recorded against the node when the condition becomes true,
+//! and a normal one when it becomes false again.
+use std::collections::{HashMap, VecDeque};
+use std::error::Error as StdError;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::NodeCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use lazy_static::lazy_static;
+use tracing::warn;
+
+/// The type of the node condition that problems are reported through.
+pub const CONDITION_TYPE: &str = "WasmRuntimeUnhealthy";
+/// The reason of the condition, and of the event recorded, when no problems are detected.
+pub const HEALTHY_REASON: &str = "WasmRuntimeHealthy";
+
+lazy_static! {
+    static ref DETECTOR: Mutex<Detector> = Mutex::new(Detector::default());
+}
+
+/// A kind of failure of the runtime that is a sign of a problem with the node when it recurs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Problem {
+    /// A module trapped, other than by exiting or by running out of the memory it was limited to.
+    Trap,
+    /// A module couldn't be compiled because the node ran out of memory.
+    CompileOutOfMemory,
+    /// The registry a module is pulled from couldn't be reached.
+    RegistryUnreachable,
+}
+
+impl Problem {
+    /// Every kind of problem that is detected.
+    pub const ALL: &'static [Problem] = &[
+        Problem::Trap,
+        Problem::CompileOutOfMemory,
+        Problem::RegistryUnreachable,
+    ];
+
+    /// The reason the condition and event are given when the problem is detected.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Problem::Trap => "TrapStorm",
+            Problem::CompileOutOfMemory => "CompilationOutOfMemory",
+            Problem::RegistryUnreachable => "RegistryUnreachable",
+        }
+    }
+
+    /// How many times the problem must be reported, within how long, for it to be detected.
+    /// Modules trap for reasons of their own, so it takes a storm of traps, whereas pulls are
+    /// only reported once they have been retried.
+    fn threshold(self) -> (usize, Duration) {
+        match self {
+            Problem::Trap => (10, Duration::from_secs(300)),
+            Problem::CompileOutOfMemory => (3, Duration::from_secs(600)),
+            Problem::RegistryUnreachable => (3, Duration::from_secs(600)),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Problem::Trap => "trap",
+            Problem::CompileOutOfMemory => "compile_out_of_memory",
+            Problem::RegistryUnreachable => "registry_unreachable",
+        }
+    }
+}
+
+/// Report a failure of the runtime, with a message saying what failed.
+pub fn report(problem: Problem, message: &str) {
+    crate::metrics::RUNTIME_FAILURES
+        .with_label_values(&[problem.label()])
+        .inc();
+    DETECTOR
+        .lock()
+        .unwrap()
+        .report(problem, message, Instant::now());
+}
+
+/// Forget the failures reported of a kind of problem that is known to be over.
+pub fn resolve(problem: Problem) {
+    DETECTOR.lock().unwrap().resolve(problem);
+}
+
+/// Whether an error is one of failing to connect to a server, or of the server not answering in
+/// time, such as when pulling from a registry that can't be reached.
+pub fn is_unreachable(error: &anyhow::Error) -> bool {
+    let mut source: Option<&(dyn StdError + 'static)> = Some(error.as_ref());
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            if e.is_connect() || e.is_timeout() {
+                return true;
+            }
+        } else if let Some(e) = e.downcast_ref::<hyper::Error>() {
+            if e.is_connect() {
+                return true;
+            }
+        } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            if matches!(e.kind(), ConnectionRefused | TimedOut | AddrNotAvailable) {
+                return true;
+            }
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Whether an error is one of the node running out of memory, such as compiling a module can
+/// fail with. Allocations that fail are told apart by their message, as that is all the compiler
+/// passes on of them.
+pub fn is_out_of_memory(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        let message = e.to_string().to_lowercase();
+        message.contains("out of memory") || message.contains("cannot allocate memory")
+    })
+}
+
+/// The node's `WasmRuntimeUnhealthy` condition, which keeps the time it last changed if it is the
+/// same as the existing one.
+pub(crate) fn condition(existing: &[NodeCondition]) -> NodeCondition {
+    let detected = DETECTOR.lock().unwrap().detected(Instant::now());
+    condition_for(&detected, existing, Utc::now())
+}
+
+/// A problem that has been reported enough times to be detected.
+#[derive(Clone, Debug, PartialEq)]
+struct Detected {
+    problem: Problem,
+    count: usize,
+    window: Duration,
+    last_message: String,
+}
+
+fn condition_for(
+    detected: &[Detected],
+    existing: &[NodeCondition],
+    now: DateTime<Utc>,
+) -> NodeCondition {
+    let (status, reason, message) = if detected.is_empty() {
+        (
+            "False",
+            HEALTHY_REASON.to_owned(),
+            "wasm runtime has no recurring failures".to_owned(),
+        )
+    } else {
+        let reason = detected
+            .iter()
+            .map(|d| d.problem.reason())
+            .collect::<Vec<_>>()
+            .join(",");
+        let message = detected
+            .iter()
+            .map(|d| {
+                format!(
+                    "{} failures in the last {}s, the last of which was: {}",
+                    d.count,
+                    d.window.as_secs(),
+                    d.last_message
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        ("True", reason, message)
+    };
+    let last_transition_time = existing
+        .iter()
+        .find(|condition| condition.type_ == CONDITION_TYPE && condition.status == status)
+        .and_then(|condition| condition.last_transition_time.clone())
+        .unwrap_or_else(|| Time(now));
+    NodeCondition {
+        type_: CONDITION_TYPE.to_owned(),
+        status: status.to_owned(),
+        reason: Some(reason),
+        message: Some(message),
+        last_heartbeat_time: Some(Time(now)),
+        last_transition_time: Some(last_transition_time),
+    }
+}
+
+/// The failures reported of each kind of problem, and what they said, oldest first.
+#[derive(Default)]
+struct Detector {
+    failures: HashMap<Problem, VecDeque<(Instant, String)>>,
+}
+
+impl Detector {
+    fn report(&mut self, problem: Problem, message: &str, now: Instant) {
+        let failures = self.failures.entry(problem).or_default();
+        failures.push_back((now, message.to_owned()));
+        // Only as many failures as it takes to detect the problem are needed
+        let (count, _) = problem.threshold();
+        while failures.len() > count {
+            failures.pop_front();
+        }
+    }
+
+    fn resolve(&mut self, problem: Problem) {
+        self.failures.remove(&problem);
+    }
+
+    /// The problems that have been reported enough times within their windows as of `now`,
+    /// forgetting failures that are older than that.
+    fn detected(&mut self, now: Instant) -> Vec<Detected> {
+        let mut detected = vec![];
+        for problem in Problem::ALL {
+            let (count, window) = problem.threshold();
+            let failures = match self.failures.get_mut(problem) {
+                Some(failures) => failures,
+                None => continue,
+            };
+            while let Some((at, _)) = failures.front() {
+                if now.saturating_duration_since(*at) <= window {
+                    break;
+                }
+                failures.pop_front();
+            }
+            if failures.len() >= count {
+                detected.push(Detected {
+                    problem: *problem,
+                    count: failures.len(),
+                    window,
+                    last_message: failures.back().map(|(_, m)| m.clone()).unwrap_or_default(),
+                });
+            }
+        }
+        detected
+    }
+}
+
+/// Record an event against the node if its `WasmRuntimeUnhealthy` condition has changed from the
+/// existing one.
+pub(crate) async fn record_change(
+    client: &kube::Client,
+    node_name: &str,
+    existing: &[NodeCondition],
+    condition: &NodeCondition,
+) {
+    let was_unhealthy = existing
+        .iter()
+        .any(|c| c.type_ == CONDITION_TYPE && c.status == "True");
+    let unhealthy = condition.status == "True";
+    if was_unhealthy == unhealthy {
+        return;
+    }
+    let event_type = if unhealthy {
+        crate::pod::EventType::Warning
+    } else {
+        crate::pod::EventType::Normal
+    };
+    let reason = condition.reason.as_deref().unwrap_or(HEALTHY_REASON);
+    let message = condition.message.as_deref().unwrap_or_default();
+    if unhealthy {
+        warn!("Wasm runtime is unhealthy: {}", message);
+    }
+    if let Err(e) = super::record_event(client, node_name, event_type, reason, message).await {
+        warn!("Unable to record event {} for node: {:?}", reason, e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn problems_are_detected_once_they_recur_within_their_window() {
+        let mut detector = Detector::default();
+        let start = Instant::now();
+        for i in 0..9 {
+            detector.report(Problem::Trap, "unreachable", start + Duration::from_secs(i));
+        }
+        assert!(detector
+            .detected(start + Duration::from_secs(10))
+            .is_empty());
+
+        detector.report(
+            Problem::Trap,
+            "out of bounds",
+            start + Duration::from_secs(10),
+        );
+        let detected = detector.detected(start + Duration::from_secs(10));
+        assert_eq!(detected.len(), 1);
+        assert_eq!(detected[0].problem, Problem::Trap);
+        assert_eq!(detected[0].count, 10);
+        assert_eq!(detected[0].last_message, "out of bounds");
+
+        // The first failures are forgotten once they are older than the window
+        assert!(detector
+            .detected(start + Duration::from_secs(305))
+            .is_empty());
+    }
+
+    #[test]
+    fn resolved_problems_are_no_longer_detected() {
+        let mut detector = Detector::default();
+        let now = Instant::now();
+        for _ in 0..3 {
+            detector.report(Problem::RegistryUnreachable, "connection refused", now);
+        }
+        assert_eq!(detector.detected(now).len(), 1);
+        detector.resolve(Problem::RegistryUnreachable);
+        assert!(detector.detected(now).is_empty());
+    }
+
+    #[test]
+    fn condition_reports_detected_problems() {
+        let now = Utc::now();
+        let healthy = condition_for(&[], &[], now);
+        assert_eq!(healthy.type_, CONDITION_TYPE);
+        assert_eq!(healthy.status, "False");
+        assert_eq!(healthy.reason.as_deref(), Some(HEALTHY_REASON));
+
+        let detected = vec![
+            Detected {
+                problem: Problem::Trap,
+                count: 10,
+                window: Duration::from_secs(300),
+                last_message: "unreachable".to_owned(),
+            },
+            Detected {
+                problem: Problem::CompileOutOfMemory,
+                count: 3,
+                window: Duration::from_secs(600),
+                last_message: "out of memory".to_owned(),
+            },
+        ];
+        let later = now + chrono::Duration::seconds(10);
+        let unhealthy = condition_for(&detected, &[healthy.clone()], later);
+        assert_eq!(unhealthy.status, "True");
+        assert_eq!(
+            unhealthy.reason.as_deref(),
+            Some("TrapStorm,CompilationOutOfMemory")
+        );
+        assert_eq!(unhealthy.last_transition_time, Some(Time(later)));
+
+        let still_healthy = condition_for(&[], &[healthy], later);
+        assert_eq!(still_healthy.last_transition_time, Some(Time(now)));
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        let refused = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        assert!(is_unreachable(
+            &anyhow::Error::new(refused).context("unable to pull module")
+        ));
+        assert!(!is_unreachable(&anyhow::anyhow!("manifest unknown")));
+
+        assert!(is_out_of_memory(
+            &anyhow::anyhow!("Cannot allocate memory (os error 12)")
+                .context("unable to compile module")
+        ));
+        assert!(!is_out_of_memory(&anyhow::anyhow!("invalid wasm magic")));
+    }
+}
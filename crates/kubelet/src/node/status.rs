@@ -0,0 +1,550 @@
+//! The node's status: the resources of the machine it runs on, how much of them pods may use and
+//! whether the machine is running short of any of them.
+//!
+//! Resources are read from `/proc` and the filesystem the data directory is on. On platforms
+//! where they can't be read, fixed defaults are reported instead, and the node is never reported
+//! as under pressure.
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::NodeCondition;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+use super::{NodeProps, KUBELET_VERSION};
+use crate::config::{Config, EvictionThresholds};
+
+/// The CPUs reported when they can't be counted.
+const DEFAULT_CPUS: u64 = 4;
+/// The memory reported when it can't be read.
+const DEFAULT_MEMORY_BYTES: u64 = 4032800 * 1024;
+/// The ephemeral storage reported when it can't be read.
+const DEFAULT_STORAGE_BYTES: u64 = 61255492 * 1024;
+
+/// The resources of the machine the node runs on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Machine {
+    cpus: u64,
+    memory_bytes: u64,
+    storage_bytes: u64,
+}
+
+impl Machine {
+    fn detect(data_dir: &Path) -> Self {
+        Machine {
+            cpus: cpu_count().unwrap_or(DEFAULT_CPUS),
+            memory_bytes: meminfo()
+                .and_then(|info| info.total_bytes)
+                .unwrap_or(DEFAULT_MEMORY_BYTES),
+            storage_bytes: filesystem_stats(data_dir)
+                .map(|stats| stats.total_bytes)
+                .unwrap_or(DEFAULT_STORAGE_BYTES),
+        }
+    }
+}
+
+/// How much of the machine's resources are still free, as far as can be told.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Usage {
+    memory_total_bytes: Option<u64>,
+    memory_available_bytes: Option<u64>,
+    storage: Option<FilesystemStats>,
+    pids: Option<PidStats>,
+}
+
+impl Usage {
+    fn detect(data_dir: &Path) -> Self {
+        let memory = meminfo().unwrap_or_default();
+        Usage {
+            memory_total_bytes: memory.total_bytes,
+            memory_available_bytes: memory.available_bytes,
+            storage: filesystem_stats(data_dir),
+            pids: pid_stats(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct MemInfo {
+    total_bytes: Option<u64>,
+    available_bytes: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FilesystemStats {
+    total_bytes: u64,
+    available_bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PidStats {
+    max: u64,
+    used: u64,
+}
+
+/// The capacity of the node, along with the provider's extended resources, and how much of it is
+/// allocatable to pods once the resources reserved for the system in the config are taken out.
+pub(crate) fn resources(
+    config: &Config,
+    props: &NodeProps,
+) -> (BTreeMap<String, Quantity>, BTreeMap<String, Quantity>) {
+    let max_pods = match props.max_pods {
+        Some(max_pods) => std::cmp::min(max_pods, config.max_pods),
+        None => config.max_pods,
+    };
+    resources_of(
+        &Machine::detect(&config.data_dir),
+        max_pods,
+        &props.extended_resources,
+        &config.system_reserved,
+    )
+}
+
+fn resources_of(
+    machine: &Machine,
+    max_pods: u16,
+    extended: &BTreeMap<String, Quantity>,
+    reserved: &HashMap<String, String>,
+) -> (BTreeMap<String, Quantity>, BTreeMap<String, Quantity>) {
+    // Reservations are checked when the config is loaded, so any that can't be parsed here are
+    // for resources that aren't reported
+    let reserved_bytes = |name: &str| {
+        reserved
+            .get(name)
+            .and_then(|q| crate::resources::memory_bytes(&Quantity(q.clone())).ok())
+            .unwrap_or(0)
+    };
+    let reserved_cpu_millis = reserved
+        .get("cpu")
+        .and_then(|q| crate::resources::cpu_millis(&Quantity(q.clone())).ok())
+        .unwrap_or(0);
+
+    let mut capacity = BTreeMap::new();
+    capacity.insert("cpu".to_owned(), Quantity(machine.cpus.to_string()));
+    capacity.insert("memory".to_owned(), kibibytes(machine.memory_bytes));
+    capacity.insert(
+        "ephemeral-storage".to_owned(),
+        kibibytes(machine.storage_bytes),
+    );
+    capacity.insert("hugepages-1Gi".to_owned(), Quantity("0".to_owned()));
+    capacity.insert("hugepages-2Mi".to_owned(), Quantity("0".to_owned()));
+    capacity.insert("pods".to_owned(), Quantity(max_pods.to_string()));
+    for (name, quantity) in extended {
+        // The kubelet's own resources can't be overridden
+        capacity
+            .entry(name.clone())
+            .or_insert_with(|| quantity.clone());
+    }
+
+    let mut allocatable = capacity.clone();
+    allocatable.insert(
+        "cpu".to_owned(),
+        Quantity(format!(
+            "{}m",
+            (machine.cpus * 1000).saturating_sub(reserved_cpu_millis)
+        )),
+    );
+    allocatable.insert(
+        "memory".to_owned(),
+        kibibytes(
+            machine
+                .memory_bytes
+                .saturating_sub(reserved_bytes("memory")),
+        ),
+    );
+    allocatable.insert(
+        "ephemeral-storage".to_owned(),
+        kibibytes(
+            machine
+                .storage_bytes
+                .saturating_sub(reserved_bytes("ephemeral-storage")),
+        ),
+    );
+    (capacity, allocatable)
+}
+
+fn kibibytes(bytes: u64) -> Quantity {
+    Quantity(format!("{}Ki", bytes / 1024))
+}
+
+/// The node's `MemoryPressure`, `DiskPressure`, `PIDPressure` and `Ready` conditions, with the
+/// node under pressure for resources that less is available of than the config's eviction
+/// thresholds, along with its `WasmRuntimeUnhealthy` condition. Conditions of other types are kept
+/// as they are, and conditions that haven't changed keep the time they last changed.
+pub(crate) fn conditions(config: &Config, existing: &[NodeCondition]) -> Vec<NodeCondition> {
+    let mut conditions = conditions_for(
+        &Usage::detect(&config.data_dir),
+        &config.eviction_hard,
+        existing,
+        Utc::now(),
+    );
+    let runtime = super::problem::condition(existing);
+    conditions.retain(|condition| condition.type_ != runtime.type_);
+    conditions.push(runtime);
+    conditions
+}
+
+fn conditions_for(
+    usage: &Usage,
+    thresholds: &EvictionThresholds,
+    existing: &[NodeCondition],
+    now: DateTime<Utc>,
+) -> Vec<NodeCondition> {
+    let memory_pressure = usage.memory_available_bytes.map_or(false, |available| {
+        let total = usage.memory_total_bytes.unwrap_or(0);
+        thresholds.memory_available.crossed(available, total)
+    });
+    let disk_pressure = usage.storage.map_or(false, |stats| {
+        thresholds
+            .nodefs_available
+            .crossed(stats.available_bytes, stats.total_bytes)
+    });
+    let pid_pressure = usage.pids.map_or(false, |stats| {
+        thresholds
+            .pid_available
+            .crossed(stats.max.saturating_sub(stats.used), stats.max)
+    });
+
+    let condition = |type_: &str, status: bool, reason: &str, message: &str| {
+        let status = if status { "True" } else { "False" };
+        let last_transition_time = existing
+            .iter()
+            .find(|condition| condition.type_ == type_ && condition.status == status)
+            .and_then(|condition| condition.last_transition_time.clone())
+            .unwrap_or_else(|| Time(now));
+        NodeCondition {
+            type_: type_.to_owned(),
+            status: status.to_owned(),
+            reason: Some(reason.to_owned()),
+            message: Some(message.to_owned()),
+            last_heartbeat_time: Some(Time(now)),
+            last_transition_time: Some(last_transition_time),
+        }
+    };
+    let mut conditions = vec![
+        if memory_pressure {
+            condition(
+                "MemoryPressure",
+                true,
+                "KubeletHasInsufficientMemory",
+                "kubelet has insufficient memory available",
+            )
+        } else {
+            condition(
+                "MemoryPressure",
+                false,
+                "KubeletHasSufficientMemory",
+                "kubelet has sufficient memory available",
+            )
+        },
+        if disk_pressure {
+            condition(
+                "DiskPressure",
+                true,
+                "KubeletHasDiskPressure",
+                "kubelet has disk pressure",
+            )
+        } else {
+            condition(
+                "DiskPressure",
+                false,
+                "KubeletHasNoDiskPressure",
+                "kubelet has no disk pressure",
+            )
+        },
+        if pid_pressure {
+            condition(
+                "PIDPressure",
+                true,
+                "KubeletHasInsufficientPID",
+                "kubelet has insufficient PID available",
+            )
+        } else {
+            condition(
+                "PIDPressure",
+                false,
+                "KubeletHasSufficientPID",
+                "kubelet has sufficient PID available",
+            )
+        },
+        condition(
+            "Ready",
+            true,
+            "KubeletReady",
+            "kubelet is posting ready status",
+        ),
+    ];
+    // OutOfDisk was replaced by DiskPressure, but nodes registered by older versions still have it
+    let others: Vec<NodeCondition> = existing
+        .iter()
+        .filter(|condition| condition.type_ != "OutOfDisk")
+        .filter(|condition| !conditions.iter().any(|c| c.type_ == condition.type_))
+        .cloned()
+        .collect();
+    conditions.extend(others);
+    conditions
+}
+
+/// What the node reports about the machine it runs on and the kubelet. The architecture isn't
+/// included, as it is the one set by the provider when the node was registered.
+pub(crate) fn node_info() -> serde_json::Value {
+    let mut info = serde_json::Map::new();
+    info.insert("kubeletVersion".to_owned(), KUBELET_VERSION.into());
+    info.insert("operatingSystem".to_owned(), std::env::consts::OS.into());
+    let files = [
+        ("kernelVersion", "/proc/sys/kernel/osrelease"),
+        ("machineID", "/etc/machine-id"),
+        ("bootID", "/proc/sys/kernel/random/boot_id"),
+    ];
+    for (field, path) in files.iter() {
+        if let Ok(value) = std::fs::read_to_string(path) {
+            info.insert((*field).to_owned(), value.trim().into());
+        }
+    }
+    if let Some(os_image) = std::fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|release| os_image(&release))
+    {
+        info.insert("osImage".to_owned(), os_image.into());
+    }
+    serde_json::Value::Object(info)
+}
+
+/// The name of the OS from the contents of `/etc/os-release`.
+fn os_image(os_release: &str) -> Option<String> {
+    os_release
+        .lines()
+        .find(|line| line.starts_with("PRETTY_NAME="))
+        .map(|line| line["PRETTY_NAME=".len()..].trim_matches('"').to_owned())
+}
+
+#[cfg(unix)]
+fn cpu_count() -> Option<u64> {
+    // Safety: sysconf only reads system configuration
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus > 0 {
+        Some(cpus as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn cpu_count() -> Option<u64> {
+    None
+}
+
+fn meminfo() -> Option<MemInfo> {
+    std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .map(|meminfo| parse_meminfo(&meminfo))
+}
+
+fn parse_meminfo(meminfo: &str) -> MemInfo {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find(|line| line.starts_with(name) && line[name.len()..].starts_with(':'))
+            .and_then(|line| {
+                line[name.len() + 1..]
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse()
+                    .ok()
+            })
+            .map(|kb: u64| kb * 1024)
+    };
+    MemInfo {
+        total_bytes: field("MemTotal"),
+        available_bytes: field("MemAvailable"),
+    }
+}
+
+#[cfg(unix)]
+fn filesystem_stats(path: &Path) -> Option<FilesystemStats> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // Safety: statvfs only writes to the struct it is given, and the path is null terminated
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    let fragment_size = stats.f_frsize as u64;
+    Some(FilesystemStats {
+        total_bytes: stats.f_blocks as u64 * fragment_size,
+        available_bytes: stats.f_bavail as u64 * fragment_size,
+    })
+}
+
+#[cfg(not(unix))]
+fn filesystem_stats(_path: &Path) -> Option<FilesystemStats> {
+    None
+}
+
+fn pid_stats() -> Option<PidStats> {
+    let max = std::fs::read_to_string("/proc/sys/kernel/pid_max").ok()?;
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    Some(PidStats {
+        max: max.trim().parse().ok()?,
+        used: threads_from_loadavg(&loadavg)?,
+    })
+}
+
+/// The number of threads on the machine, each of which has a process ID, from the contents of
+/// `/proc/loadavg`.
+fn threads_from_loadavg(loadavg: &str) -> Option<u64> {
+    let entities = loadavg.split_whitespace().nth(3)?;
+    entities.split('/').nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Threshold;
+
+    fn condition<'a>(conditions: &'a [NodeCondition], type_: &str) -> &'a NodeCondition {
+        conditions.iter().find(|c| c.type_ == type_).unwrap()
+    }
+
+    #[test]
+    fn allocatable_leaves_out_system_reserved() {
+        let machine = Machine {
+            cpus: 2,
+            memory_bytes: 4 * 1024 * 1024 * 1024,
+            storage_bytes: 10 * 1024 * 1024 * 1024,
+        };
+        let mut reserved = HashMap::new();
+        reserved.insert("cpu".to_owned(), "500m".to_owned());
+        reserved.insert("memory".to_owned(), "1Gi".to_owned());
+        let mut extended = BTreeMap::new();
+        extended.insert("example.com/gpu".to_owned(), Quantity("2".to_owned()));
+        let (capacity, allocatable) = resources_of(&machine, 110, &extended, &reserved);
+        assert_eq!(capacity["cpu"], Quantity("2".to_owned()));
+        assert_eq!(capacity["memory"], Quantity("4194304Ki".to_owned()));
+        assert_eq!(capacity["pods"], Quantity("110".to_owned()));
+        assert_eq!(allocatable["cpu"], Quantity("1500m".to_owned()));
+        assert_eq!(allocatable["memory"], Quantity("3145728Ki".to_owned()));
+        assert_eq!(
+            allocatable["ephemeral-storage"],
+            capacity["ephemeral-storage"]
+        );
+        assert_eq!(allocatable["example.com/gpu"], Quantity("2".to_owned()));
+    }
+
+    #[test]
+    fn pressure_is_reported_when_resources_run_low() {
+        let now = Utc::now();
+        let healthy = Usage {
+            memory_total_bytes: Some(4 * 1024 * 1024 * 1024),
+            memory_available_bytes: Some(1024 * 1024 * 1024),
+            storage: Some(FilesystemStats {
+                total_bytes: 100,
+                available_bytes: 50,
+            }),
+            pids: Some(PidStats {
+                max: 1000,
+                used: 100,
+            }),
+        };
+        let thresholds = EvictionThresholds::default();
+        let conditions = conditions_for(&healthy, &thresholds, &[], now);
+        assert_eq!(condition(&conditions, "MemoryPressure").status, "False");
+        assert_eq!(condition(&conditions, "DiskPressure").status, "False");
+        assert_eq!(condition(&conditions, "PIDPressure").status, "False");
+        assert_eq!(condition(&conditions, "Ready").status, "True");
+
+        let starved = Usage {
+            memory_total_bytes: Some(4 * 1024 * 1024 * 1024),
+            memory_available_bytes: Some(1024),
+            storage: Some(FilesystemStats {
+                total_bytes: 100,
+                available_bytes: 5,
+            }),
+            pids: Some(PidStats {
+                max: 1000,
+                used: 950,
+            }),
+        };
+        let later = now + chrono::Duration::seconds(10);
+        let updated = conditions_for(&starved, &thresholds, &conditions, later);
+        let memory = condition(&updated, "MemoryPressure");
+        assert_eq!(memory.status, "True");
+        assert_eq!(memory.last_transition_time, Some(Time(later)));
+        assert_eq!(condition(&updated, "DiskPressure").status, "True");
+        assert_eq!(condition(&updated, "PIDPressure").status, "True");
+        let ready = condition(&updated, "Ready");
+        assert_eq!(ready.last_transition_time, Some(Time(now)));
+        assert_eq!(ready.last_heartbeat_time, Some(Time(later)));
+    }
+
+    #[test]
+    fn pressure_follows_the_configured_thresholds() {
+        let usage = Usage {
+            memory_total_bytes: Some(4 * 1024 * 1024 * 1024),
+            memory_available_bytes: Some(1024 * 1024 * 1024),
+            storage: Some(FilesystemStats {
+                total_bytes: 100,
+                available_bytes: 50,
+            }),
+            pids: None,
+        };
+        let thresholds = EvictionThresholds {
+            memory_available: Threshold::Percent(30),
+            nodefs_available: Threshold::Amount(10),
+            ..EvictionThresholds::default()
+        };
+        let conditions = conditions_for(&usage, &thresholds, &[], Utc::now());
+        assert_eq!(condition(&conditions, "MemoryPressure").status, "True");
+        assert_eq!(condition(&conditions, "DiskPressure").status, "False");
+    }
+
+    #[test]
+    fn unknown_usage_is_not_pressure_and_other_conditions_are_kept() {
+        let existing = vec![
+            NodeCondition {
+                type_: "NetworkUnavailable".to_owned(),
+                status: "False".to_owned(),
+                ..Default::default()
+            },
+            NodeCondition {
+                type_: "OutOfDisk".to_owned(),
+                status: "False".to_owned(),
+                ..Default::default()
+            },
+        ];
+        let conditions = conditions_for(
+            &Usage::default(),
+            &EvictionThresholds::default(),
+            &existing,
+            Utc::now(),
+        );
+        assert_eq!(conditions.len(), 5);
+        assert_eq!(condition(&conditions, "MemoryPressure").status, "False");
+        assert_eq!(condition(&conditions, "NetworkUnavailable").status, "False");
+        assert!(conditions.iter().all(|c| c.type_ != "OutOfDisk"));
+    }
+
+    #[test]
+    fn machine_files_are_parsed() {
+        let meminfo = "MemTotal:       16318480 kB\n\
+                       MemFree:         1000000 kB\n\
+                       MemAvailable:    8000000 kB\n";
+        assert_eq!(
+            parse_meminfo(meminfo),
+            MemInfo {
+                total_bytes: Some(16318480 * 1024),
+                available_bytes: Some(8000000 * 1024),
+            }
+        );
+        assert_eq!(
+            threads_from_loadavg("0.10 0.20 0.30 2/812 12345\n"),
+            Some(812)
+        );
+        assert_eq!(
+            os_image("NAME=\"Ubuntu\"\nPRETTY_NAME=\"Ubuntu 20.04.1 LTS\"\n"),
+            Some("Ubuntu 20.04.1 LTS".to_owned())
+        );
+    }
+}
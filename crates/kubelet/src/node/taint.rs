@@ -0,0 +1,416 @@
+//! Awareness of the node being cordoned or tainted.
+//!
+//! The Node object is watched for it being marked unschedulable, as `kubectl cordon` and
+//! `kubectl drain` do, for `NoExecute` taints, and for its labels, which new pods' node selectors
+//! must match. While the node is unschedulable, new pods that
+//! don't tolerate the `node.kubernetes.io/unschedulable` taint are refused, and new pods that
+//! don't tolerate a `NoExecute` taint are always refused. Running pods that don't tolerate a
+//! `NoExecute` taint are evicted, either at once or once their toleration period has passed.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::{Node as KubeNode, Pod as KubePod, Taint, Toleration};
+use kube::api::{Api, DeleteParams, ListParams};
+use kube_runtime::watcher::{watcher, Event};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use crate::admission::Rejection;
+use crate::pod::{mark_disruption_target, Pod, DELETION_BY_TAINT_MANAGER};
+
+/// The taint that stands for the node being unschedulable.
+const UNSCHEDULABLE_TAINT: &str = "node.kubernetes.io/unschedulable";
+const NO_SCHEDULE: &str = "NoSchedule";
+const NO_EXECUTE: &str = "NoExecute";
+/// The longest time between checks for pods to evict, so that pods that arrive while the node
+/// has `NoExecute` taints are still evicted once their toleration period has passed.
+const EVICTION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether the node is unschedulable, the taints on it, and its labels.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct NodeTaints {
+    unschedulable: bool,
+    taints: Vec<Taint>,
+    /// The node's labels, or `None` if the node hasn't been seen yet
+    labels: Option<BTreeMap<String, String>>,
+}
+
+/// The node's taints as last seen, shared between the watcher and the pod operator.
+pub(crate) type SharedNodeTaints = Arc<RwLock<NodeTaints>>;
+
+impl From<&KubeNode> for NodeTaints {
+    fn from(node: &KubeNode) -> Self {
+        let spec = node.spec.clone().unwrap_or_default();
+        NodeTaints {
+            unschedulable: spec.unschedulable.unwrap_or(false),
+            taints: spec.taints.unwrap_or_default(),
+            labels: Some(node.metadata.labels.clone().unwrap_or_default()),
+        }
+    }
+}
+
+impl NodeTaints {
+    /// Whether the node may start a new pod.
+    pub(crate) fn admit(&self, pod: &Pod) -> Result<(), Rejection> {
+        let tolerations = tolerations(pod);
+        let unschedulable = Taint {
+            key: UNSCHEDULABLE_TAINT.to_owned(),
+            effect: NO_SCHEDULE.to_owned(),
+            ..Default::default()
+        };
+        if self.unschedulable && !tolerations.iter().any(|t| tolerates(t, &unschedulable)) {
+            return Err(Rejection::new(
+                "NodeUnschedulable",
+                "Pod was rejected: the node is unschedulable",
+            ));
+        }
+        if let Some((key, value)) = self.unmatched_selector(pod) {
+            return Err(Rejection::new(
+                "NodeAffinity",
+                format!(
+                    "Pod was rejected: the node does not have the label {}={} from the pod's node selector",
+                    key, value
+                ),
+            ));
+        }
+        if let Some(taint) = self
+            .no_execute_taints()
+            .find(|taint| !tolerations.iter().any(|t| tolerates(t, taint)))
+        {
+            return Err(Rejection::new(
+                "TaintToleration",
+                format!(
+                    "Pod was rejected: the pod does not tolerate the node's taint {}",
+                    describe(taint)
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The first entry of the pod's node selector that the node's labels don't have.
+    fn unmatched_selector<'a>(&self, pod: &'a Pod) -> Option<(&'a String, &'a String)> {
+        let labels = self.labels.as_ref()?;
+        pod.node_selector()?
+            .iter()
+            .find(|(key, value)| labels.get(*key) != Some(*value))
+    }
+
+    /// How long until a running pod should be evicted because of the node's `NoExecute` taints,
+    /// or `None` if it tolerates them all for good.
+    fn eviction_delay(&self, pod: &Pod, now: DateTime<Utc>) -> Option<Duration> {
+        let tolerations = tolerations(pod);
+        self.no_execute_taints()
+            .map(|taint| {
+                let tolerated_for = tolerations
+                    .iter()
+                    .filter(|t| tolerates(t, taint))
+                    .map(|t| t.toleration_seconds)
+                    .min_by_key(|seconds| seconds.unwrap_or(i64::MAX));
+                match tolerated_for {
+                    None => Some(Duration::from_secs(0)),
+                    Some(None) => None,
+                    Some(Some(seconds)) => {
+                        let added = taint.time_added.as_ref().map_or(now, |time| time.0);
+                        let elapsed = (now - added).num_seconds().max(0);
+                        Some(Duration::from_secs((seconds - elapsed).max(0) as u64))
+                    }
+                }
+            })
+            .flatten()
+            .min()
+    }
+
+    fn no_execute_taints(&self) -> impl Iterator<Item = &Taint> {
+        self.taints
+            .iter()
+            .filter(|taint| taint.effect == NO_EXECUTE)
+    }
+}
+
+fn tolerations(pod: &Pod) -> Vec<Toleration> {
+    pod.as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.tolerations.clone())
+        .unwrap_or_default()
+}
+
+/// Whether a toleration matches a taint, following the rules Kubernetes uses: an empty effect
+/// matches any effect, an empty key with the `Exists` operator matches any key, and otherwise the
+/// keys must match along with, for the `Equal` operator, the values.
+fn tolerates(toleration: &Toleration, taint: &Taint) -> bool {
+    if let Some(effect) = toleration.effect.as_deref() {
+        if !effect.is_empty() && effect != taint.effect {
+            return false;
+        }
+    }
+    let key = toleration.key.as_deref().unwrap_or_default();
+    match toleration.operator.as_deref() {
+        Some("Exists") => key.is_empty() || key == taint.key,
+        None | Some("") | Some("Equal") => {
+            key == taint.key
+                && toleration.value.as_deref().unwrap_or_default()
+                    == taint.value.as_deref().unwrap_or_default()
+        }
+        Some(_) => false,
+    }
+}
+
+fn describe(taint: &Taint) -> String {
+    match taint.value.as_deref() {
+        Some(value) if !value.is_empty() => {
+            format!("{}={}:{}", taint.key, value, taint.effect)
+        }
+        _ => format!("{}:{}", taint.key, taint.effect),
+    }
+}
+
+/// Watch the node's taints, keeping the shared copy of them up to date and evicting pods that
+/// don't tolerate its `NoExecute` taints.
+pub(crate) async fn watch(
+    client: kube::Client,
+    node_name: String,
+    taints: SharedNodeTaints,
+) -> anyhow::Result<()> {
+    let nodes: Api<KubeNode> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("metadata.name={}", node_name));
+    let mut events = watcher(nodes, params).boxed();
+    let mut next_check = Duration::from_secs(0);
+    loop {
+        tokio::select! {
+            event = events.try_next() => match event {
+                Ok(Some(Event::Applied(node))) => {
+                    *taints.write().await = NodeTaints::from(&node);
+                }
+                Ok(Some(Event::Restarted(nodes))) => {
+                    if let Some(node) = nodes.first() {
+                        *taints.write().await = NodeTaints::from(node);
+                    }
+                }
+                Ok(Some(Event::Deleted(_))) => {
+                    warn!("Node '{}' was deleted", node_name);
+                    *taints.write().await = NodeTaints::default();
+                }
+                Ok(None) => anyhow::bail!("Watch of node '{}' ended", node_name),
+                Err(e) => {
+                    warn!("Error watching node '{}': {:?}", node_name, e);
+                    tokio::time::delay_for(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = tokio::time::delay_for(next_check) => (),
+        }
+        let current = taints.read().await.clone();
+        next_check = match evict_pods(&client, &node_name, &current).await {
+            Ok(Some(delay)) => std::cmp::min(delay, EVICTION_CHECK_INTERVAL),
+            Ok(None) => EVICTION_CHECK_INTERVAL,
+            Err(e) => {
+                warn!("Unable to evict pods from tainted node: {:?}", e);
+                EVICTION_CHECK_INTERVAL
+            }
+        };
+    }
+}
+
+/// Evict the pods on the node that no longer tolerate its `NoExecute` taints, returning how long
+/// until the next pod should be evicted.
+async fn evict_pods(
+    client: &kube::Client,
+    node_name: &str,
+    taints: &NodeTaints,
+) -> anyhow::Result<Option<Duration>> {
+    if taints.no_execute_taints().next().is_none() {
+        return Ok(None);
+    }
+    let pods: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    let now = Utc::now();
+    let mut next = None;
+    let mut pods: Vec<Pod> = pods
+        .list(&params)
+        .await?
+        .items
+        .into_iter()
+        .map(Pod::from)
+        .collect();
+    // Lower priority pods are evicted first
+    pods.sort_by_key(Pod::priority);
+    for pod in pods {
+        // Static pods only stop when their manifest is removed, so their mirror pods are left be
+        if pod.deletion_timestamp().is_some() || pod.is_mirror() {
+            continue;
+        }
+        match taints.eviction_delay(&pod, now) {
+            Some(delay) if delay == Duration::from_secs(0) => {
+                info!(
+                    "Evicting pod '{}' as it does not tolerate the node's taints",
+                    pod.name()
+                );
+                let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+                let message = "Taint manager: deleting due to NoExecute taint";
+                if let Err(e) =
+                    mark_disruption_target(&api, &pod, DELETION_BY_TAINT_MANAGER, message).await
+                {
+                    warn!(
+                        "Unable to mark pod '{}' as a disruption target: {:?}",
+                        pod.name(),
+                        e
+                    );
+                }
+                if let Err(e) = api.delete(pod.name(), &DeleteParams::default()).await {
+                    warn!("Unable to evict pod '{}': {:?}", pod.name(), e);
+                }
+            }
+            Some(delay) => {
+                debug!("Pod '{}' will be evicted in {:?}", pod.name(), delay);
+                next = Some(next.map_or(delay, |next: Duration| next.min(delay)));
+            }
+            None => (),
+        }
+    }
+    Ok(next)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    fn pod_with_tolerations(tolerations: serde_json::Value) -> Pod {
+        PodBuilder::new()
+            .spec(serde_json::json!({
+                "containers": [{ "name": "app", "image": "hello:v1" }],
+                "tolerations": tolerations
+            }))
+            .build()
+    }
+
+    fn no_execute(key: &str, time_added: DateTime<Utc>) -> Taint {
+        Taint {
+            key: key.to_owned(),
+            effect: NO_EXECUTE.to_owned(),
+            time_added: Some(Time(time_added)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tolerations_match_taints() {
+        let taint = Taint {
+            key: "dedicated".to_owned(),
+            value: Some("gpu".to_owned()),
+            effect: NO_SCHEDULE.to_owned(),
+            ..Default::default()
+        };
+        let toleration =
+            |value: serde_json::Value| -> Toleration { serde_json::from_value(value).unwrap() };
+        assert!(tolerates(
+            &toleration(serde_json::json!({ "operator": "Exists" })),
+            &taint
+        ));
+        assert!(tolerates(
+            &toleration(serde_json::json!({ "key": "dedicated", "value": "gpu" })),
+            &taint
+        ));
+        assert!(!tolerates(
+            &toleration(serde_json::json!({ "key": "dedicated", "value": "cpu" })),
+            &taint
+        ));
+        assert!(tolerates(
+            &toleration(serde_json::json!({ "key": "dedicated", "operator": "Exists" })),
+            &taint
+        ));
+        assert!(!tolerates(
+            &toleration(serde_json::json!({ "operator": "Exists", "effect": "NoExecute" })),
+            &taint
+        ));
+    }
+
+    #[test]
+    fn new_pods_are_refused_by_cordoned_and_tainted_nodes() {
+        let pod = pod_with_tolerations(serde_json::json!([]));
+        let daemon = pod_with_tolerations(serde_json::json!([
+            { "key": UNSCHEDULABLE_TAINT, "operator": "Exists", "effect": "NoSchedule" }
+        ]));
+        let cordoned = NodeTaints {
+            unschedulable: true,
+            taints: vec![],
+            ..Default::default()
+        };
+        assert_eq!(
+            cordoned.admit(&pod).unwrap_err().reason,
+            "NodeUnschedulable"
+        );
+        assert!(cordoned.admit(&daemon).is_ok());
+
+        let tainted = NodeTaints {
+            unschedulable: false,
+            taints: vec![no_execute("node.kubernetes.io/not-ready", Utc::now())],
+            ..Default::default()
+        };
+        assert_eq!(tainted.admit(&pod).unwrap_err().reason, "TaintToleration");
+        assert!(NodeTaints::default().admit(&pod).is_ok());
+    }
+
+    #[test]
+    fn new_pods_must_match_the_node_labels() {
+        let pod = PodBuilder::new()
+            .spec(serde_json::json!({
+                "containers": [{ "name": "app", "image": "hello:v1" }],
+                "nodeSelector": { "kubernetes.io/arch": "wasm32-wasi" }
+            }))
+            .build();
+        let labelled = |arch: &str| NodeTaints {
+            labels: Some(
+                vec![("kubernetes.io/arch".to_owned(), arch.to_owned())]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        assert!(labelled("wasm32-wasi").admit(&pod).is_ok());
+        assert_eq!(
+            labelled("amd64").admit(&pod).unwrap_err().reason,
+            "NodeAffinity"
+        );
+        // Until the node has been seen, its labels aren't known
+        assert!(NodeTaints::default().admit(&pod).is_ok());
+    }
+
+    #[test]
+    fn pods_are_evicted_after_their_toleration_period() {
+        let now = Utc::now();
+        let taints = NodeTaints {
+            unschedulable: false,
+            taints: vec![no_execute(
+                "node.kubernetes.io/unreachable",
+                now - chrono::Duration::seconds(100),
+            )],
+            ..Default::default()
+        };
+        let untolerating = pod_with_tolerations(serde_json::json!([]));
+        assert_eq!(
+            taints.eviction_delay(&untolerating, now),
+            Some(Duration::from_secs(0))
+        );
+        let tolerating = pod_with_tolerations(serde_json::json!([
+            {
+                "key": "node.kubernetes.io/unreachable",
+                "operator": "Exists",
+                "effect": "NoExecute",
+                "tolerationSeconds": 300
+            }
+        ]));
+        assert_eq!(
+            taints.eviction_delay(&tolerating, now),
+            Some(Duration::from_secs(200))
+        );
+        let forever = pod_with_tolerations(serde_json::json!([{ "operator": "Exists" }]));
+        assert_eq!(taints.eviction_delay(&forever, now), None);
+    }
+}
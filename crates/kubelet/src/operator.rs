@@ -1,22 +1,236 @@
+use crate::admission::{AdmitChain, PodAdmitAttributes, Rejection, SharedResourceAccounting};
+use crate::checkpoint::Checkpoints;
+use crate::device_plugin::DeviceManager;
+use crate::dns::Dns;
+use crate::network::Network;
 use crate::pod::initialize_pod_container_statuses;
-use crate::pod::Pod;
+use crate::pod::{make_registered_status, patch_status, Changes, Phase, Pod, PodKey};
 use crate::provider::Provider;
+use futures::StreamExt;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use krator::state::SharedState;
 use krator::ObjectState;
 use krator::{Manifest, Operator};
+use kube::api::ListParams;
+use kube::api::PatchParams;
 use kube::Api;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
 
 pub(crate) struct PodOperator<P: Provider> {
     provider: Arc<P>,
     client: kube::Client,
+    node_ip: IpAddr,
+    admission: AdmitChain,
+    accounting: SharedResourceAccounting,
+    devices: Option<Arc<DeviceManager>>,
+    network: Option<Arc<Network>>,
+    dns: Arc<Dns>,
+    checkpoints: Arc<Checkpoints>,
+    /// The pods that registered successfully and haven't been deregistered yet.
+    registered: Mutex<HashSet<PodKey>>,
 }
 
 impl<P: Provider> PodOperator<P> {
-    pub fn new(provider: Arc<P>, client: kube::Client) -> Self {
-        PodOperator { provider, client }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        provider: Arc<P>,
+        client: kube::Client,
+        node_ip: IpAddr,
+        admission: AdmitChain,
+        accounting: SharedResourceAccounting,
+        devices: Option<Arc<DeviceManager>>,
+        network: Option<Arc<Network>>,
+        dns: Arc<Dns>,
+        checkpoints: Arc<Checkpoints>,
+    ) -> Self {
+        PodOperator {
+            provider,
+            client,
+            node_ip,
+            admission,
+            accounting,
+            devices,
+            network,
+            dns,
+            checkpoints,
+            registered: Mutex::new(HashSet::new()),
+        }
     }
+
+    /// Free what the pod was admitted with, once it no longer needs it.
+    async fn release(&self, pod: &Pod) {
+        release(&self.accounting, self.devices.as_deref(), pod).await
+    }
+
+    /// The other pods bound to the pod's node that haven't finished, which it is admitted
+    /// alongside.
+    async fn other_pods(&self, pod: &Pod) -> Result<Vec<Pod>, Rejection> {
+        let api: Api<KubePod> = Api::all(self.client.clone());
+        let params = ListParams::default().fields(&format!(
+            "spec.nodeName={}",
+            pod.node_name().unwrap_or_default()
+        ));
+        let pods = match api.list(&params).await {
+            Ok(pods) => pods.items,
+            // Static pods run without the API server, so are admitted by what is known locally
+            Err(e) if pod.is_from_file() => {
+                warn!("Unable to list pods to admit static pod alongside: {:?}", e);
+                vec![]
+            }
+            Err(e) => return Err(Rejection::unexpected(e.into())),
+        };
+        Ok(pods
+            .into_iter()
+            .map(Pod::from)
+            .filter(|other| {
+                !other.has_finished()
+                    && !(other.namespace() == pod.namespace() && other.name() == pod.name())
+            })
+            .collect())
+    }
+
+    /// Admit the pod and set up what it needs to run.
+    async fn register(&self, manifest: Manifest<Pod>) -> anyhow::Result<()> {
+        let initial_manifest = manifest.latest();
+        let namespace = initial_manifest.namespace();
+        let name = initial_manifest.name().to_string();
+        let api: Api<KubePod> = Api::namespaced(self.client.clone(), namespace);
+
+        if initial_manifest.is_mirror() {
+            // Static pods are run from their manifest, so a mirror pod here has outlived its
+            // static pod and is about to be deleted
+            anyhow::bail!("Pod '{}' is a mirror pod, so isn't run", name);
+        }
+        crate::telemetry::start_pod_span(&initial_manifest);
+
+        if is_new(&initial_manifest) {
+            let admitted = match self.other_pods(&initial_manifest).await {
+                Ok(other_pods) => {
+                    let attributes = PodAdmitAttributes {
+                        pod: &initial_manifest,
+                        other_pods: &other_pods,
+                    };
+                    self.admission.admit(&attributes).await
+                }
+                Err(rejection) => Err(rejection),
+            };
+            if let Err(rejection) = admitted {
+                info!("Rejecting pod '{}': {}", name, rejection.message);
+                // An earlier handler may have admitted it
+                self.release(&initial_manifest).await;
+                let patch = serde_json::json!({
+                    "status": {
+                        "phase": Phase::Failed,
+                        "reason": rejection.reason,
+                        "message": rejection.message,
+                    }
+                });
+                api.patch_status(&name, &PatchParams::default(), serde_json::to_vec(&patch)?)
+                    .await?;
+                anyhow::bail!("Pod '{}' was rejected: {}", name, rejection.reason);
+            }
+        } else if let Err(e) = self.accounting.write().await.record(&initial_manifest) {
+            warn!(
+                "Unable to account for the resources of pod '{}': {:?}",
+                name, e
+            );
+        }
+
+        // Finished pods no longer use what they requested, though they may not be deleted for a
+        // while. Until then, the provider is told about the changes made to the pod.
+        let accounting = self.accounting.clone();
+        let devices = self.devices.clone();
+        let provider = Arc::clone(&self.provider);
+        let mut updates = manifest.clone();
+        let mut previous = initial_manifest.clone();
+        tokio::spawn(async move {
+            while let Some(pod) = updates.next().await {
+                if pod.has_finished() {
+                    release(&accounting, devices.as_deref(), &pod).await;
+                    break;
+                }
+                let changes = Changes::between(&previous, &pod);
+                if !changes.is_empty() {
+                    debug!("Pod '{}' was updated: {:?}", pod.name(), changes);
+                    if let Err(e) = provider.update_pod(&previous, &pod, &changes).await {
+                        warn!(
+                            "Unable to update pod '{}' to its new spec: {:?}",
+                            pod.name(),
+                            e
+                        );
+                    }
+                }
+                previous = pod;
+            }
+        });
+
+        // Pods can still run without cluster DNS, so this doesn't stop them
+        if let Err(e) = self.dns.setup(&initial_manifest).await {
+            warn!("Unable to set up DNS for pod '{}': {:?}", name, e);
+        }
+        if initial_manifest.is_from_file() {
+            // Static pods never hear back from the API server, so start out with their registered
+            // status. It is reported to their mirror pod, if there is one yet.
+            patch_status(&api, &name, make_registered_status(&initial_manifest)).await;
+            return Ok(());
+        }
+
+        let pod_ips = match &self.network {
+            Some(network) if !initial_manifest.host_network() => {
+                match network.setup(&initial_manifest).await {
+                    Ok(pod_ips) => pod_ips,
+                    Err(e) => {
+                        warn!("Unable to set up network for pod '{}': {:?}", name, e);
+                        self.release(&initial_manifest).await;
+                        let patch = serde_json::json!({
+                            "status": {
+                                "phase": Phase::Failed,
+                                "reason": "NetworkNotReady",
+                                "message": format!("Unable to set up pod network: {}", e),
+                            }
+                        });
+                        api.patch_status(
+                            &name,
+                            &PatchParams::default(),
+                            serde_json::to_vec(&patch)?,
+                        )
+                        .await?;
+                        anyhow::bail!("Unable to set up network for pod '{}': {}", name, e);
+                    }
+                }
+            }
+            _ => vec![self.node_ip],
+        };
+        if let Err(e) = self.checkpoints.save(&initial_manifest).await {
+            warn!("Unable to checkpoint pod '{}': {:?}", name, e);
+        }
+        initialize_pod_container_statuses(name, manifest, &api, self.node_ip, &pod_ips).await
+    }
+}
+
+async fn release(
+    accounting: &SharedResourceAccounting,
+    devices: Option<&DeviceManager>,
+    pod: &Pod,
+) {
+    accounting.write().await.release(pod);
+    if let Some(devices) = devices {
+        devices.release(pod).await;
+    }
+}
+
+/// Whether the pod has yet to start, so is still subject to admission. Pods that were already
+/// running, such as when the kubelet restarts, are kept running.
+fn is_new(pod: &Pod) -> bool {
+    let phase = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|status| status.phase.as_deref());
+    matches!(phase, None | Some("Pending"))
 }
 
 #[async_trait::async_trait]
@@ -28,7 +242,7 @@ impl<P: Provider> Operator for PodOperator<P> {
     type DeletedState = P::TerminatedState;
 
     async fn initialize_object_state(&self, manifest: &Pod) -> anyhow::Result<P::PodState> {
-        self.provider.initialize_pod_state(manifest).await
+        Ok(self.provider.initialize_pod_state(manifest).await?)
     }
 
     async fn shared_state(&self) -> SharedState<<P::PodState as ObjectState>::SharedState> {
@@ -36,11 +250,44 @@ impl<P: Provider> Operator for PodOperator<P> {
     }
 
     async fn registration_hook(&self, manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
-        let initial_manifest = manifest.latest();
-        let namespace = initial_manifest.namespace();
-        let name = initial_manifest.name().to_string();
-        let api: Api<KubePod> = Api::namespaced(self.client.clone(), namespace);
+        let key = PodKey::from(&manifest.latest());
+        self.register(manifest).await?;
+        // Pods that fail to register are still deregistered, but were never counted
+        if self.registered.lock().unwrap().insert(key) {
+            crate::metrics::PODS.inc();
+        }
+        Ok(())
+    }
 
-        initialize_pod_container_statuses(name, manifest, &api).await
+    async fn deregistration_hook(&self, manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
+        let pod = manifest.latest();
+        crate::telemetry::end_pod_span(&pod);
+        if self.registered.lock().unwrap().remove(&PodKey::from(&pod)) {
+            crate::metrics::PODS.dec();
+        }
+        self.release(&pod).await;
+        if let Err(e) = self.dns.teardown(&pod).await {
+            warn!("Unable to tear down DNS for pod '{}': {:?}", pod.name(), e);
+        }
+        if let Some(network) = &self.network {
+            if let Err(e) = network.teardown(&pod).await {
+                warn!(
+                    "Unable to tear down network for pod '{}': {:?}",
+                    pod.name(),
+                    e
+                );
+                // The checkpoint is kept, so that tearing the network down is tried again when
+                // the kubelet restarts
+                return Ok(());
+            }
+        }
+        if let Err(e) = self.checkpoints.remove(&pod).await {
+            warn!(
+                "Unable to remove checkpoint of pod '{}': {:?}",
+                pod.name(),
+                e
+            );
+        }
+        Ok(())
     }
 }
@@ -1,3 +1,11 @@
+//! Discovery and registration of node plugins, such as CSI drivers, through the kubelet plugin
+//! registration socket directory.
+//!
+//! Plugins running as DaemonSets create a socket in the directory that serves the plugin
+//! registration API. The [`PluginRegistry`] asks each plugin what it is, validates it and tells it
+//! whether it was registered. CSI drivers are kept in the registry for mounting volumes with, and
+//! device plugins are handed to the [`DeviceManager`] the registry was given, if there is one.
+use crate::device_plugin::DeviceManager;
 use crate::fs_watch::FileSystemWatcher;
 use crate::grpc_sock;
 use crate::plugin_registration_api::v1::{
@@ -6,16 +14,17 @@ use crate::plugin_registration_api::v1::{
 };
 
 use anyhow::Context;
-use log::{debug, error, trace, warn};
 use notify::Event;
 use tokio::fs::{create_dir_all, read_dir};
 use tokio::stream::StreamExt;
 use tokio::sync::{RwLock, RwLockWriteGuard};
 use tonic::Request;
+use tracing::{debug, error, trace, warn};
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[cfg(target_family = "unix")]
 const DEFAULT_PLUGIN_PATH: &str = "/var/lib/kubelet/plugins_registry/";
@@ -23,11 +32,10 @@ const DEFAULT_PLUGIN_PATH: &str = "/var/lib/kubelet/plugins_registry/";
 const DEFAULT_PLUGIN_PATH: &str = "c:\\ProgramData\\kubelet\\plugins_registry";
 
 const SOCKET_EXTENSION: &str = "sock";
-const ALLOWED_PLUGIN_TYPES: &[PluginType] = &[PluginType::CSIPlugin];
 
 /// An enum for capturing possible plugin types. This is purely for clarity and capturing this
 /// information is a compiled type as the information we get from gRPC is a string
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum PluginType {
     CSIPlugin,
     DevicePlugin,
@@ -61,6 +69,8 @@ struct PluginEntry {
 pub struct PluginRegistry {
     plugins: RwLock<HashMap<String, PluginEntry>>,
     plugin_dir: PathBuf,
+    /// The device manager that device plugins are handed to, without which they are refused
+    device_manager: Option<Arc<DeviceManager>>,
 }
 
 impl Default for PluginRegistry {
@@ -68,6 +78,7 @@ impl Default for PluginRegistry {
         PluginRegistry {
             plugin_dir: PathBuf::from(DEFAULT_PLUGIN_PATH),
             plugins: RwLock::new(HashMap::new()),
+            device_manager: None,
         }
     }
 }
@@ -81,9 +92,14 @@ impl PluginRegistry {
         }
     }
 
+    /// Hands device plugins discovered in the plugin directory to the given device manager, rather
+    /// than refusing them.
+    pub fn with_device_manager(mut self, device_manager: Arc<DeviceManager>) -> Self {
+        self.device_manager = Some(device_manager);
+        self
+    }
+
     /// Gets the endpoint for the given plugin name, returning `None` if it doesn't exist
-    // TODO: Remove clippy exception when CSI is completed.
-    #[allow(dead_code)]
     pub async fn get_endpoint(&self, plugin_name: &str) -> Option<PathBuf> {
         let plugins = self.plugins.read().await;
         plugins
@@ -166,8 +182,19 @@ impl PluginRegistry {
                 plugin_info
             );
 
-            // Step 3: Register plugin to local storage
+            // Step 3: Register plugin to local storage, and with the device manager if it is a
+            // device plugin
             self.register(&plugin_info, &discovered_path).await;
+            if let (Ok(PluginType::DevicePlugin), Some(device_manager)) = (
+                PluginType::try_from(plugin_info.r#type.as_str()),
+                &self.device_manager,
+            ) {
+                let endpoint = match plugin_info.endpoint.is_empty() {
+                    true => discovered_path.clone(),
+                    false => PathBuf::from(&plugin_info.endpoint),
+                };
+                device_manager.register_discovered(&plugin_info.name, &endpoint);
+            }
 
             // Step 4: Inform plugin
             inform_plugin(&discovered_path, None).await?;
@@ -201,9 +228,10 @@ impl PluginRegistry {
     /// Validates the given plugin info gathered from a discovered plugin, returning an error with
     /// additional information if it is not valid. This will validate 3 specific things (should
     /// answer YES to all of these):
-    /// 1. Is it a CSIPlugin? If it isn't we will deny it for now. This will be removed as we
-    ///    iterate and if it is needed
-    /// 2. Does the list of supported versions contain the version we expect?
+    /// 1. Is it a CSIPlugin, or a DevicePlugin when there is a device manager to hand it to?
+    /// 2. Does the list of supported versions contain the version we expect? For device plugins,
+    ///    this is the version of the device plugin API, and the device manager also checks that
+    ///    the plugin's name is an extended resource name
     /// 3. Is the plugin name available? 3a. If the name is already registered, is the endpoint the
     ///    exact same? If it is, we allow it to reregister
     async fn validate(&self, info: &PluginInfo, discovered_path: &PathBuf) -> anyhow::Result<()> {
@@ -213,11 +241,16 @@ impl PluginRegistry {
             discovered_path.display()
         );
 
-        self.validate_plugin_type(info.r#type.as_str())?;
+        let plugin_type = self.validate_plugin_type(info.r#type.as_str())?;
         trace!("Type validation complete for plugin {:?}", info);
 
         trace!("Checking supported versions for plugin {:?}", info);
-        self.validate_plugin_version(&info.supported_versions)?;
+        match (plugin_type, &self.device_manager) {
+            (PluginType::DevicePlugin, Some(device_manager)) => {
+                device_manager.validate_discovered(&info.name, &info.supported_versions)?
+            }
+            _ => self.validate_plugin_version(&info.supported_versions)?,
+        }
         trace!("Supported version check complete for plugin {:?}", info);
 
         trace!("Checking for naming collisions for plugin {:?}", info);
@@ -229,14 +262,16 @@ impl PluginRegistry {
 
     // Individual validation steps
 
-    /// Check for valid type and if it is a CSIPlugin
-    fn validate_plugin_type(&self, plugin_type: &str) -> anyhow::Result<()> {
+    /// Check for valid type, and that device plugins have a device manager to be handed to
+    fn validate_plugin_type(&self, plugin_type: &str) -> anyhow::Result<PluginType> {
         let plugin_type = PluginType::try_from(plugin_type)?;
-        if !is_allowed_plugin_type(plugin_type) {
-            warn!("DevicePlugins are not currently supported");
-            return Err(anyhow::anyhow!("DevicePlugins are not currently supported"));
+        if plugin_type == PluginType::DevicePlugin && self.device_manager.is_none() {
+            warn!("DevicePlugins are not supported without a device manager");
+            return Err(anyhow::anyhow!(
+                "DevicePlugins are not supported without a device manager"
+            ));
         }
-        Ok(())
+        Ok(plugin_type)
     }
 
     /// Check if we support one of the plugin's requested versions
@@ -300,11 +335,6 @@ fn remove_plugin(
     plugins.remove(&key);
 }
 
-// An allow list check for currently supported plugin types
-fn is_allowed_plugin_type(t: PluginType) -> bool {
-    ALLOWED_PLUGIN_TYPES.iter().any(|item| *item == t)
-}
-
 /// Attempts a `GetInfo` gRPC call to the endpoint to the path given
 async fn get_plugin_info(path: &PathBuf) -> anyhow::Result<PluginInfo> {
     trace!("Connecting to plugin at {:?} for GetInfo", path);
@@ -705,7 +735,7 @@ mod test {
                 .validate(&info, &PathBuf::from("/fake"))
                 .await
                 .is_err(),
-            "DevicePlugin type should error"
+            "DevicePlugin type without a device manager should error"
         );
 
         info.r#type = "NonExistent".to_string();
@@ -718,6 +748,48 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_device_plugin_with_device_manager() {
+        let client = kube::Client::new(kube::Config::new(
+            reqwest::Url::parse("http://127.0.0.1:8080").unwrap(),
+        ));
+        let device_manager = Arc::new(DeviceManager::new("/tmp/foo", client, "node"));
+        let registrar = PluginRegistry::new("/tmp/foo").with_device_manager(device_manager);
+        let mut info = PluginInfo {
+            r#type: "DevicePlugin".to_string(),
+            name: "example.com/gpu".to_string(),
+            endpoint: String::new(),
+            supported_versions: vec!["v1beta1".to_string()],
+        };
+
+        assert!(
+            registrar
+                .validate(&info, &PathBuf::from("/fake"))
+                .await
+                .is_ok(),
+            "DevicePlugin with a device manager shouldn't fail"
+        );
+
+        info.supported_versions = vec![API_VERSION.to_string()];
+        assert!(
+            registrar
+                .validate(&info, &PathBuf::from("/fake"))
+                .await
+                .is_err(),
+            "DevicePlugin not supporting the device plugin API version should error"
+        );
+
+        info.supported_versions = vec!["v1beta1".to_string()];
+        info.name = "gpu".to_string();
+        assert!(
+            registrar
+                .validate(&info, &PathBuf::from("/fake"))
+                .await
+                .is_err(),
+            "DevicePlugin without an extended resource name should error"
+        );
+    }
+
     #[tokio::test]
     async fn test_invalid_plugin_version() {
         // This path doesn't matter here
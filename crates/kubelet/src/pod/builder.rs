@@ -0,0 +1,55 @@
+//! Pods for tests, built from just the parts of them that a test cares about.
+use serde_json::{json, Value};
+
+use super::Pod;
+
+/// Builds a pod for a test. Pods are named `hello` in the `default` namespace, and have no
+/// containers, unless they are given other fields.
+pub(crate) struct PodBuilder {
+    pod: Value,
+}
+
+impl PodBuilder {
+    pub(crate) fn new() -> Self {
+        PodBuilder {
+            pod: json!({
+                "metadata": { "name": "hello", "namespace": "default" },
+                "spec": { "containers": [] }
+            }),
+        }
+    }
+
+    pub(crate) fn name(self, name: &str) -> Self {
+        self.metadata(json!({ "name": name }))
+    }
+
+    pub(crate) fn namespace(self, namespace: &str) -> Self {
+        self.metadata(json!({ "namespace": namespace }))
+    }
+
+    /// Sets the given fields of the pod's metadata, such as `uid` or `labels`.
+    pub(crate) fn metadata(self, fields: Value) -> Self {
+        self.set("metadata", fields)
+    }
+
+    /// Sets the given fields of the pod's spec, such as `containers`.
+    pub(crate) fn spec(self, fields: Value) -> Self {
+        self.set("spec", fields)
+    }
+
+    /// Sets the given fields of the pod's status.
+    pub(crate) fn status(self, fields: Value) -> Self {
+        self.set("status", fields)
+    }
+
+    pub(crate) fn build(self) -> Pod {
+        serde_json::from_value(self.pod).expect("test pod should be a valid pod")
+    }
+
+    fn set(mut self, part: &str, fields: Value) -> Self {
+        for (key, value) in fields.as_object().expect("fields should be an object") {
+            self.pod[part][key] = value.clone();
+        }
+        self
+    }
+}
@@ -0,0 +1,124 @@
+//! The changes made to a pod while it runs. Most of a pod's spec can't be changed once it has been
+//! created, but its containers' images, its active deadline and its tolerations can be, along
+//! with its labels and annotations.
+use k8s_openapi::api::core::v1::{Container as KubeContainer, PodSpec};
+
+use super::Pod;
+use crate::container::ContainerKey;
+
+/// What was changed about a pod when it was updated.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changes {
+    /// The containers whose image was changed
+    pub images: Vec<ContainerKey>,
+    /// Whether the pod's active deadline was changed
+    pub active_deadline: bool,
+    /// Whether the pod's tolerations were changed
+    pub tolerations: bool,
+    /// Whether the pod's labels were changed
+    pub labels: bool,
+    /// Whether the pod's annotations were changed
+    pub annotations: bool,
+}
+
+impl Changes {
+    /// What was changed about the pod between two of its versions. Changes to its status are
+    /// ignored.
+    pub fn between(old: &Pod, new: &Pod) -> Self {
+        let old_spec = old.as_kube_pod().spec.as_ref();
+        let new_spec = new.as_kube_pod().spec.as_ref();
+        let mut images = changed_images(
+            init_containers(old_spec),
+            init_containers(new_spec),
+            ContainerKey::Init,
+        );
+        images.extend(changed_images(
+            app_containers(old_spec),
+            app_containers(new_spec),
+            ContainerKey::App,
+        ));
+        Changes {
+            images,
+            active_deadline: old.active_deadline() != new.active_deadline(),
+            tolerations: old_spec.map(|spec| &spec.tolerations)
+                != new_spec.map(|spec| &spec.tolerations),
+            labels: old.labels() != new.labels(),
+            annotations: old.annotations() != new.annotations(),
+        }
+    }
+
+    /// Whether nothing was changed.
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+}
+
+fn init_containers(spec: Option<&PodSpec>) -> &[KubeContainer] {
+    spec.and_then(|spec| spec.init_containers.as_deref())
+        .unwrap_or_default()
+}
+
+fn app_containers(spec: Option<&PodSpec>) -> &[KubeContainer] {
+    spec.map(|spec| spec.containers.as_slice())
+        .unwrap_or_default()
+}
+
+/// The keys of the containers whose image is different in the new list of containers.
+fn changed_images(
+    old: &[KubeContainer],
+    new: &[KubeContainer],
+    key: fn(String) -> ContainerKey,
+) -> Vec<ContainerKey> {
+    new.iter()
+        .filter(|container| {
+            old.iter()
+                .find(|old| old.name == container.name)
+                .map_or(false, |old| old.image != container.image)
+        })
+        .map(|container| key(container.name.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+
+    fn pod(image: &str, deadline: Option<i64>, label: &str) -> Pod {
+        PodBuilder::new()
+            .metadata(serde_json::json!({ "labels": { "app": label } }))
+            .spec(serde_json::json!({
+                "activeDeadlineSeconds": deadline,
+                "initContainers": [{ "name": "setup", "image": "setup:v1" }],
+                "containers": [
+                    { "name": "app", "image": image },
+                    { "name": "proxy", "image": "proxy:v1" },
+                ],
+            }))
+            .status(serde_json::json!({ "phase": "Running" }))
+            .build()
+    }
+
+    #[test]
+    fn unchanged_pods_have_no_changes() {
+        let old = pod("hello:v1", None, "hello");
+        let mut new = old.clone();
+        new.kube_pod.status = None;
+        assert!(Changes::between(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn changed_fields_are_found() {
+        let old = pod("hello:v1", None, "hello");
+        let changes = Changes::between(&old, &pod("hello:v2", Some(60), "hello"));
+        assert_eq!(changes.images, vec![ContainerKey::App("app".to_owned())]);
+        assert!(changes.active_deadline);
+        assert!(!changes.labels);
+        assert!(!changes.annotations);
+
+        let changes = Changes::between(&old, &pod("hello:v1", None, "world"));
+        assert!(changes.images.is_empty());
+        assert!(!changes.active_deadline);
+        assert!(changes.labels);
+    }
+}
@@ -0,0 +1,416 @@
+//! Events recorded against pods, which show up in `kubectl describe pod`.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use kube::api::{Api, PatchParams, PostParams};
+use tracing::debug;
+
+use crate::pod::Pod;
+
+/// How long events are remembered for, so that repeats of them are counted rather than recorded
+/// again.
+const AGGREGATION_WINDOW: Duration = Duration::from_secs(600);
+/// How many events with the same reason but different messages may be recorded about a pod or
+/// container within the aggregation window before they are combined into one.
+const MAX_SIMILAR_EVENTS: usize = 10;
+/// The most events remembered at once. The ones seen least recently are forgotten first.
+const MAX_CACHED_EVENTS: usize = 4096;
+/// What the message of an event combined from similar events starts with.
+const COMBINED_MESSAGE_PREFIX: &str = "(combined from similar events): ";
+/// The component events are recorded as coming from.
+const COMPONENT: &str = "kubelet";
+
+/// The reasons the kubelet records events with, matching those of the Kubernetes kubelet.
+pub mod reason {
+    /// An image is being pulled.
+    pub const PULLING: &str = "Pulling";
+    /// An image has been pulled.
+    pub const PULLED: &str = "Pulled";
+    /// A container has been started.
+    pub const STARTED: &str = "Started";
+    /// Something failed, such as pulling an image or starting a container.
+    pub const FAILED: &str = "Failed";
+    /// A container failed and is waiting to be restarted.
+    pub const BACK_OFF: &str = "BackOff";
+    /// A container is being stopped.
+    pub const KILLING: &str = "Killing";
+    /// A pod's volumes couldn't be mounted.
+    pub const FAILED_MOUNT: &str = "FailedMount";
+}
+
+/// Whether an event is routine or a sign that something went wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventType {
+    /// Something routine happened.
+    Normal,
+    /// Something went wrong.
+    Warning,
+}
+
+/// Record an event about a pod. The reason is a short, CamelCase summary of what happened, such
+/// as `FailedPostStartHook`, and the message describes it for humans.
+///
+/// Every call creates a new event. Providers that record events often should use an
+/// [`EventRecorder`], which counts repeated events instead.
+pub async fn record_event(
+    client: &kube::Client,
+    pod: &Pod,
+    event_type: EventType,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let host = pod
+        .kube_pod
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.node_name.clone());
+    let event = new_event(pod, None, event_type, reason, message, host);
+    let events: Api<Event> = Api::namespaced(client.clone(), pod.namespace());
+    events.create(&PostParams::default(), &event).await?;
+    Ok(())
+}
+
+/// Records events about pods and their containers on behalf of a node, aggregating repeated
+/// events so that a pod failing over and over doesn't flood the API server.
+///
+/// An event that is the same as one recorded in the last ten minutes bumps the count of that
+/// event rather than being recorded again. Once more than ten events with the same reason but
+/// different messages have been recorded about a pod or container in that time, further ones are
+/// combined into a single event, whose message starts with `(combined from similar events)`.
+///
+/// Clones of a recorder share what they remember, so a provider should create one and clone it
+/// wherever events are recorded.
+#[derive(Clone)]
+pub struct EventRecorder {
+    client: kube::Client,
+    node_name: String,
+    cache: Arc<Mutex<EventCache>>,
+}
+
+impl EventRecorder {
+    /// Create a recorder for events on the given node.
+    pub fn new(client: kube::Client, node_name: &str) -> Self {
+        EventRecorder {
+            client,
+            node_name: node_name.to_owned(),
+            cache: Arc::new(Mutex::new(EventCache::default())),
+        }
+    }
+
+    /// Record an event about a pod, or about one of its containers if `container_name` is given.
+    /// The reason is a short, CamelCase summary of what happened, such as those in [`reason`], and
+    /// the message describes it for humans.
+    pub async fn record(
+        &self,
+        pod: &Pod,
+        container_name: Option<&str>,
+        event_type: EventType,
+        reason: &str,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let similar = SimilarKey {
+            namespace: pod.namespace().to_owned(),
+            name: pod.name().to_owned(),
+            uid: pod.kube_pod.metadata.uid.clone().unwrap_or_default(),
+            field_path: container_name.map(container_field_path),
+            event_type,
+            reason: reason.to_owned(),
+        };
+        // The cache isn't held on to while talking to the API server, so that recording events
+        // about one pod doesn't hold up recording them about others
+        let (key, message, repeat) =
+            self.cache
+                .lock()
+                .unwrap()
+                .observe(similar, message, Instant::now());
+        let events: Api<Event> = Api::namespaced(self.client.clone(), pod.namespace());
+
+        if let Some(repeat) = repeat {
+            let patch = serde_json::json!({
+                "count": repeat.count,
+                "lastTimestamp": Time(Utc::now()),
+                "message": message,
+            });
+            match events
+                .patch(
+                    &repeat.name,
+                    &PatchParams::default(),
+                    serde_json::to_vec(&patch)?,
+                )
+                .await
+            {
+                Ok(_) => return Ok(()),
+                // The event may have expired, in which case it is recorded again
+                Err(e) => debug!(
+                    "Unable to update event {}, recording it again: {}",
+                    repeat.name, e
+                ),
+            }
+        }
+
+        let event = new_event(
+            pod,
+            container_name,
+            event_type,
+            reason,
+            &message,
+            Some(self.node_name.clone()),
+        );
+        let created = events.create(&PostParams::default(), &event).await?;
+        if let Some(name) = created.metadata.name {
+            self.cache
+                .lock()
+                .unwrap()
+                .created(key, name, Instant::now());
+        }
+        Ok(())
+    }
+}
+
+fn new_event(
+    pod: &Pod,
+    container_name: Option<&str>,
+    event_type: EventType,
+    reason: &str,
+    message: &str,
+    host: Option<String>,
+) -> Event {
+    let now = Time(Utc::now());
+    Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}.", pod.name())),
+            namespace: Some(pod.namespace().to_owned()),
+            ..Default::default()
+        },
+        involved_object: ObjectReference {
+            api_version: Some("v1".to_owned()),
+            kind: Some("Pod".to_owned()),
+            name: Some(pod.name().to_owned()),
+            namespace: Some(pod.namespace().to_owned()),
+            uid: pod.kube_pod.metadata.uid.clone(),
+            field_path: container_name.map(container_field_path),
+            ..Default::default()
+        },
+        reason: Some(reason.to_owned()),
+        message: Some(message.to_owned()),
+        type_: Some(format!("{:?}", event_type)),
+        first_timestamp: Some(now.clone()),
+        last_timestamp: Some(now),
+        count: Some(1),
+        reporting_component: Some(COMPONENT.to_owned()),
+        reporting_instance: host.clone(),
+        source: Some(EventSource {
+            component: Some(COMPONENT.to_owned()),
+            host,
+        }),
+        ..Default::default()
+    }
+}
+
+/// The path of a container within its pod, as events about containers refer to it.
+fn container_field_path(container_name: &str) -> String {
+    format!("spec.containers{{{}}}", container_name)
+}
+
+/// What events with the same reason about the same pod or container have in common.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct SimilarKey {
+    namespace: String,
+    name: String,
+    uid: String,
+    field_path: Option<String>,
+    event_type: EventType,
+    reason: String,
+}
+
+/// What makes an event the same as another. Similar events that are combined all have no
+/// message, so that they update the same event.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct EventKey {
+    similar: SimilarKey,
+    message: Option<String>,
+}
+
+/// An event that was recorded, and is updated rather than recorded again when it repeats.
+struct CachedEvent {
+    name: String,
+    count: i32,
+    last_seen: Instant,
+}
+
+/// The messages of similar events recorded within the aggregation window.
+struct SimilarEvents {
+    since: Instant,
+    messages: HashSet<String>,
+}
+
+/// An event that repeats one already recorded.
+#[derive(Debug, PartialEq)]
+struct Repeat {
+    name: String,
+    count: i32,
+}
+
+#[derive(Default)]
+struct EventCache {
+    events: HashMap<EventKey, CachedEvent>,
+    similar: HashMap<SimilarKey, SimilarEvents>,
+}
+
+impl EventCache {
+    /// Note that an event is being recorded, returning what it is remembered as, the message it
+    /// is recorded with and, if it repeats an event already recorded, which event to update and
+    /// its new count.
+    fn observe(
+        &mut self,
+        similar: SimilarKey,
+        message: &str,
+        now: Instant,
+    ) -> (EventKey, String, Option<Repeat>) {
+        self.expire(now);
+
+        let seen = self
+            .similar
+            .entry(similar.clone())
+            .or_insert_with(|| SimilarEvents {
+                since: now,
+                messages: HashSet::new(),
+            });
+        let (key_message, message) =
+            if seen.messages.contains(message) || seen.messages.len() < MAX_SIMILAR_EVENTS {
+                seen.messages.insert(message.to_owned());
+                (Some(message.to_owned()), message.to_owned())
+            } else {
+                (None, format!("{}{}", COMBINED_MESSAGE_PREFIX, message))
+            };
+
+        let key = EventKey {
+            similar,
+            message: key_message,
+        };
+        let repeat = self.events.get_mut(&key).map(|cached| {
+            cached.count += 1;
+            cached.last_seen = now;
+            Repeat {
+                name: cached.name.clone(),
+                count: cached.count,
+            }
+        });
+        (key, message, repeat)
+    }
+
+    /// Remember an event that has been recorded, so that repeats of it update it.
+    fn created(&mut self, key: EventKey, name: String, now: Instant) {
+        if !self.events.contains_key(&key) && self.events.len() >= MAX_CACHED_EVENTS {
+            let oldest = self
+                .events
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_seen)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.events.remove(&oldest);
+            }
+        }
+        self.events.insert(
+            key,
+            CachedEvent {
+                name,
+                count: 1,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Forget events that haven't been seen within the aggregation window.
+    fn expire(&mut self, now: Instant) {
+        self.events
+            .retain(|_, cached| now.duration_since(cached.last_seen) < AGGREGATION_WINDOW);
+        self.similar
+            .retain(|_, seen| now.duration_since(seen.since) < AGGREGATION_WINDOW);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn similar_key(reason: &str) -> SimilarKey {
+        SimilarKey {
+            namespace: "default".to_owned(),
+            name: "hello".to_owned(),
+            uid: "1234".to_owned(),
+            field_path: Some(container_field_path("app")),
+            event_type: EventType::Warning,
+            reason: reason.to_owned(),
+        }
+    }
+
+    #[test]
+    fn repeated_events_are_counted() {
+        let mut cache = EventCache::default();
+        let now = Instant::now();
+        let (key, _, repeat) = cache.observe(similar_key(reason::BACK_OFF), "restarting", now);
+        assert_eq!(repeat, None);
+        cache.created(key, "hello.1".to_owned(), now);
+
+        let (_, _, repeat) = cache.observe(similar_key(reason::BACK_OFF), "restarting", now);
+        assert_eq!(
+            repeat,
+            Some(Repeat {
+                name: "hello.1".to_owned(),
+                count: 2
+            })
+        );
+        let (_, _, repeat) = cache.observe(similar_key(reason::FAILED), "restarting", now);
+        assert_eq!(repeat, None);
+    }
+
+    #[test]
+    fn events_are_forgotten_after_the_aggregation_window() {
+        let mut cache = EventCache::default();
+        let now = Instant::now();
+        let (key, _, _) = cache.observe(similar_key(reason::BACK_OFF), "restarting", now);
+        cache.created(key, "hello.1".to_owned(), now);
+
+        let later = now + AGGREGATION_WINDOW;
+        let (_, _, repeat) = cache.observe(similar_key(reason::BACK_OFF), "restarting", later);
+        assert_eq!(repeat, None);
+    }
+
+    #[test]
+    fn similar_events_are_combined() {
+        let mut cache = EventCache::default();
+        let now = Instant::now();
+        for i in 0..MAX_SIMILAR_EVENTS {
+            let (key, message, repeat) =
+                cache.observe(similar_key(reason::FAILED), &format!("failure {}", i), now);
+            assert_eq!(message, format!("failure {}", i));
+            assert_eq!(repeat, None);
+            cache.created(key, format!("hello.{}", i), now);
+        }
+
+        let (key, message, repeat) = cache.observe(similar_key(reason::FAILED), "failure 10", now);
+        assert_eq!(message, "(combined from similar events): failure 10");
+        assert_eq!(repeat, None);
+        cache.created(key, "hello.combined".to_owned(), now);
+
+        let (_, message, repeat) = cache.observe(similar_key(reason::FAILED), "failure 11", now);
+        assert_eq!(message, "(combined from similar events): failure 11");
+        assert_eq!(
+            repeat,
+            Some(Repeat {
+                name: "hello.combined".to_owned(),
+                count: 2
+            })
+        );
+
+        // Messages seen before the limit was reached are still counted against their own event
+        let (_, message, repeat) = cache.observe(similar_key(reason::FAILED), "failure 0", now);
+        assert_eq!(message, "failure 0");
+        assert_eq!(repeat.map(|r| r.count), Some(2));
+    }
+}
@@ -1,24 +1,31 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use log::{debug, error, info};
 use tokio::io::{AsyncRead, AsyncSeek};
 use tokio::sync::RwLock;
+use tokio::time::Instant;
+use tracing::{debug, error, info};
 
-use crate::container::{
-    ContainerKey, ContainerMapByName, Handle as ContainerHandle, HandleMap as ContainerHandleMap,
-};
-use crate::handle::StopHandler;
+use crate::container::{ContainerKey, Handle as ContainerHandle, HandleMap as ContainerHandleMap};
+use crate::exec::{CommandOptions, ExecResult, Sender as ExecSender, Stdin};
+use crate::handle::{CheckpointHandler, ExecHandler, StopHandler};
 use crate::log::{HandleFactory, Sender};
 use crate::pod::Pod;
-use crate::provider::ProviderError;
+use crate::provider::{PodRuntimeStatus, ProviderError};
 use crate::volume::Ref;
 
+/// How long the container handles are locked for at a time while waiting for containers to stop.
+const STOP_WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Handle is the top level handle into managing a pod. It manages updating
 /// statuses for the containers in the pod and can be used to stop the pod and
 /// access logs
 pub struct Handle<H, F> {
     container_handles: RwLock<ContainerHandleMap<H, F>>,
     pod: Pod,
+    stopped: AtomicBool,
     // Storage for the volume references so they don't get dropped until the runtime handle is
     // dropped
     // TODO: remove this; this is now part of the ModuleRunContext
@@ -47,54 +54,283 @@ impl<H: StopHandler, F> Handle<H, F> {
         Self {
             container_handles: RwLock::new(container_handles),
             pod,
+            stopped: AtomicBool::new(false),
             _volumes: volumes.unwrap_or_default(),
         }
     }
 
-    /// Insert container `Handle` by `ContainerKey`.
-    pub async fn insert_container_handle(&self, key: ContainerKey, value: ContainerHandle<H, F>) {
+    /// The pod managed by this handle.
+    pub fn pod(&self) -> &Pod {
+        &self.pod
+    }
+
+    /// Whether the pod has been stopped. Containers of a stopped pod should not be restarted.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// What the runtime says about the pod's containers, without waiting on them. The pod is
+    /// running while any of its containers is, and has exited once all of its app containers
+    /// have.
+    pub async fn runtime_status(&self) -> PodRuntimeStatus {
+        let handles = self.container_handles.read().await;
+        if handles
+            .values()
+            .any(|handle| handle.has_exited() == Some(false))
+        {
+            return PodRuntimeStatus::Running;
+        }
+        let containers = self.pod.containers();
+        let exited = !containers.is_empty()
+            && containers.iter().all(|container| {
+                handles
+                    .get(&ContainerKey::App(container.name().to_owned()))
+                    .map_or(false, |handle| handle.has_exited() == Some(true))
+            });
+        if exited {
+            PodRuntimeStatus::Exited
+        } else {
+            PodRuntimeStatus::Unknown
+        }
+    }
+
+    /// Insert container `Handle` by `ContainerKey`. If this replaces the handle of a container
+    /// that has been restarted, the logs of its previous run are kept.
+    pub async fn insert_container_handle(
+        &self,
+        key: ContainerKey,
+        mut value: ContainerHandle<H, F>,
+    ) {
         let mut map = self.container_handles.write().await;
+        if let Some(previous) = map.remove(&key) {
+            value.retain_previous(previous);
+        }
         map.insert(key, value);
     }
 
+    /// The key of the container with the given name, or failing that, of the run of a container
+    /// with the given ID. IDs can be given without their scheme, such as for requests that name the
+    /// container in their path.
+    fn container_key(
+        &self,
+        handles: &ContainerHandleMap<H, F>,
+        container: &str,
+    ) -> Result<ContainerKey, ProviderError> {
+        ContainerKey::all(container)
+            .iter()
+            .find(|key| handles.contains_key(key))
+            .cloned()
+            .or_else(|| {
+                handles
+                    .iter()
+                    .find(|(_, handle)| {
+                        handle.id().map_or(false, |id| {
+                            id == container || id.splitn(2, "://").nth(1) == Some(container)
+                        })
+                    })
+                    .map(|(key, _)| key.clone())
+            })
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: container.to_owned(),
+            })
+    }
+
     /// Streams output from the specified container into the given sender.
     /// Optionally tails the output and/or continues to watch the file and stream changes.
-    pub async fn output<R>(&self, container_name: &str, sender: Sender) -> anyhow::Result<()>
+    pub async fn output<R>(&self, container_name: &str, sender: Sender) -> Result<(), ProviderError>
     where
         R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
         F: HandleFactory<R>,
     {
         let mut handles = self.container_handles.write().await;
+        let key = self.container_key(&handles, container_name)?;
         let handle = handles
-            .get_mut_by_name(container_name.to_owned())
-            .ok_or_else(|| ProviderError::ContainerNotFound {
+            .get_mut(&key)
+            .expect("the container was just found in the map");
+        if sender.previous() && !handle.has_previous() {
+            return Err(ProviderError::PreviousContainerNotFound {
                 pod_name: self.pod.name().to_owned(),
                 container_name: container_name.to_owned(),
-            })?;
+            });
+        }
         handle.output(sender).await
     }
 
+    /// Reads the last lines of the specified container's output, keeping at most `max_bytes`
+    /// of them. See [`crate::log::tail`].
+    pub async fn log_tail<R>(
+        &self,
+        container_name: &str,
+        lines: usize,
+        max_bytes: usize,
+    ) -> Result<String, ProviderError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+        F: HandleFactory<R>,
+    {
+        let handles = self.container_handles.read().await;
+        let handle = &handles[&self.container_key(&handles, container_name)?];
+        handle.log_tail(lines, max_bytes).await
+    }
+
+    /// Runs a command in the specified container, feeding it data from `stdin` and streaming
+    /// its output into the given sender.
+    pub async fn exec(
+        &self,
+        container_name: &str,
+        options: CommandOptions,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<(), ProviderError>
+    where
+        H: ExecHandler,
+    {
+        let session = {
+            let handles = self.container_handles.read().await;
+            let handle = &handles[&self.container_key(&handles, container_name)?];
+            handle.exec(options, stdin, sender).await?
+        };
+        // The session can outlive the container's handle, which is released so that the
+        // container can still be stopped or restarted while the client is connected
+        session.await
+    }
+
+    /// Runs a command in the specified container to completion, returning its output and exit
+    /// code.
+    pub async fn exec_command(
+        &self,
+        container_name: &str,
+        options: CommandOptions,
+    ) -> Result<ExecResult, ProviderError>
+    where
+        H: ExecHandler,
+    {
+        let handles = self.container_handles.read().await;
+        let handle = &handles[&self.container_key(&handles, container_name)?];
+        handle.exec_command(options).await
+    }
+
+    /// Attaches to the specified container, feeding it data from `stdin` and streaming its output
+    /// into the given sender.
+    pub async fn attach<R>(
+        &self,
+        container_name: &str,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<(), ProviderError>
+    where
+        H: ExecHandler,
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+        F: HandleFactory<R>,
+    {
+        let session = {
+            let handles = self.container_handles.read().await;
+            let handle = &handles[&self.container_key(&handles, container_name)?];
+            handle.attach(stdin, sender).await?
+        };
+        session.await
+    }
+
+    /// Writes a snapshot of the specified container into `dir`.
+    pub async fn checkpoint(&self, container_name: &str, dir: &Path) -> Result<(), ProviderError>
+    where
+        H: CheckpointHandler,
+    {
+        let handles = self.container_handles.read().await;
+        let handle = &handles[&self.container_key(&handles, container_name)?];
+        handle.checkpoint(dir).await
+    }
+
     /// Signal the pod and all its running containers to stop and wait for them
-    /// to complete.
-    pub async fn stop(&self) -> anyhow::Result<()> {
-        {
+    /// to complete. Containers that haven't completed by the end of the pod's termination
+    /// grace period are killed. Init containers, which are only still running if they are
+    /// sidecars, are stopped once the other containers have stopped, as those may rely on them.
+    pub async fn stop(&self) -> Result<(), ProviderError>
+    where
+        H: ExecHandler,
+    {
+        self.stopped.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + self.pod.termination_grace_period();
+        for &init in &[false, true] {
+            let keys: Vec<ContainerKey> = {
+                let mut handles = self.container_handles.write().await;
+                let mut keys = Vec::new();
+                for (key, handle) in handles.iter_mut().filter(|(key, _)| key.is_init() == init) {
+                    info!("Stopping container: {}", key);
+                    match handle.stop().await {
+                        Ok(_) => debug!("Successfully signalled container {} to stop", key),
+                        // NOTE: I am not sure what recovery or retry steps should be
+                        // done here, but we should definitely continue and try to stop
+                        // the other containers
+                        Err(e) => error!("Error while trying to stop pod {}: {:?}", key, e),
+                    }
+                    keys.push(key.clone());
+                }
+                keys
+            };
+            self.wait_or_kill(keys, deadline).await;
+        }
+        Ok(())
+    }
+
+    /// Signal a single container in the pod to stop, such as when it fails its liveness probe.
+    pub async fn stop_container(&self, container_name: &str) -> Result<(), ProviderError>
+    where
+        H: ExecHandler,
+    {
+        let deadline = Instant::now() + self.pod.termination_grace_period();
+        let key = {
             let mut handles = self.container_handles.write().await;
-            for (key, handle) in handles.iter_mut() {
-                info!("Stopping container: {}", key);
-                match handle.stop().await {
+            let key = self.container_key(&handles, container_name)?;
+            handles
+                .get_mut(&key)
+                .expect("the container was just found in the map")
+                .stop()
+                .await?;
+            key
+        };
+        self.wait_or_kill(vec![key], deadline).await;
+        Ok(())
+    }
+
+    /// Wait for the given containers, which have been signalled to stop, to complete, killing
+    /// those that haven't by the deadline. The handles are only locked for a moment at a time
+    /// while waiting, so that the containers can still be read from, such as to stream their
+    /// logs, while they shut down.
+    async fn wait_or_kill(&self, mut keys: Vec<ContainerKey>, deadline: Instant) {
+        while !keys.is_empty() {
+            let mut running = Vec::new();
+            for key in keys {
+                let mut handles = self.container_handles.write().await;
+                let handle = match handles.get_mut(&key) {
+                    Some(handle) => handle,
+                    // The handle was replaced or removed, so there is no longer anything to wait on
+                    None => continue,
+                };
+                let until = std::cmp::min(Instant::now() + STOP_WAIT_INTERVAL, deadline);
+                let result = if until < deadline {
+                    match tokio::time::timeout_at(until, handle.wait()).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            running.push(key);
+                            continue;
+                        }
+                    }
+                } else {
+                    handle.wait_or_kill(deadline).await
+                };
+                match result {
                     Ok(_) => debug!("Successfully stopped container {}", key),
-                    // NOTE: I am not sure what recovery or retry steps should be
-                    // done here, but we should definitely continue and try to stop
-                    // the other containers
-                    Err(e) => error!("Error while trying to stop pod {}: {:?}", key, e),
+                    Err(e) => debug!("Container {} stopped with error: {:?}", key, e),
                 }
             }
+            keys = running;
         }
-        Ok(())
     }
 
     /// Wait for all containers in the pod to complete
-    pub async fn wait(&mut self) -> anyhow::Result<()> {
+    pub async fn wait(&mut self) -> Result<(), ProviderError> {
         let mut handles = self.container_handles.write().await;
         for (_, handle) in handles.iter_mut() {
             handle.wait().await?;
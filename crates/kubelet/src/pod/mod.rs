@@ -1,24 +1,54 @@
 //! `pod` is a collection of utilities surrounding the Kubernetes pod API.
+#[cfg(test)]
+pub(crate) mod builder;
+mod changes;
+mod event;
 mod handle;
 pub mod state;
 mod status;
+pub use changes::Changes;
 // Ignore deprecated here as this is just a reexport
+pub use event::{reason, record_event, EventRecorder, EventType};
 #[allow(deprecated)]
 pub use handle::{key_from_pod, pod_key, Handle};
-pub(crate) use status::initialize_pod_container_statuses;
 pub use status::{
-    make_registered_status, make_status, make_status_with_containers, patch_status, Phase, Status,
+    disruption_target_conditions, make_registered_status, make_status, make_status_with_containers,
+    patch_status, pod_conditions, qos_class, Phase, Status, DELETION_BY_TAINT_MANAGER,
+    DISRUPTION_TARGET, TERMINATION_BY_KUBELET,
+};
+pub(crate) use status::{
+    initialize_pod_container_statuses, mark_disruption_target, update_pod_conditions,
 };
 
 use crate::container::{Container, ContainerKey};
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::{
-    Container as KubeContainer, Pod as KubePod, Volume as KubeVolume,
+    Container as KubeContainer, ContainerStatus as KubeContainerStatus, EnvVar, Pod as KubePod,
+    Volume as KubeVolume,
 };
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use kube::api::Meta;
 use serde::Deserialize;
 
+/// The priority of the `system-cluster-critical` priority class. Pods with a priority at least
+/// this high are critical.
+const SYSTEM_CRITICAL_PRIORITY: i32 = 2_000_000_000;
+/// The priority of the `system-node-critical` priority class.
+const SYSTEM_NODE_CRITICAL_PRIORITY: i32 = 2_000_001_000;
+/// The annotation saying where a pod's manifest came from, which is `file` for static pods read
+/// from the pod manifest directory.
+pub(crate) const CONFIG_SOURCE_ANNOTATION: &str = "kubernetes.io/config.source";
+/// The annotation holding the hash of a static pod's manifest.
+pub(crate) const CONFIG_HASH_ANNOTATION: &str = "kubernetes.io/config.hash";
+/// The annotation marking a mirror pod, holding the hash of the static pod it stands in for.
+pub(crate) const CONFIG_MIRROR_ANNOTATION: &str = "kubernetes.io/config.mirror";
+/// The prefix of the annotation setting the restart policy of an init container, whose name
+/// follows the prefix. Init containers whose policy is `Always` are sidecars. The API version the
+/// kubelet is built against has no `restartPolicy` field on containers, so the annotation stands
+/// in for it.
+pub const RESTART_POLICY_ANNOTATION_PREFIX: &str = "restart-policy.krustlet.dev/";
+
 /// A Kubernetes Pod
 ///
 /// This is a new type around the k8s_openapi Pod definition
@@ -55,12 +85,68 @@ impl Pod {
         self.kube_pod.spec.as_ref()?.node_selector.as_ref()
     }
 
+    /// Get the name of the node the pod is scheduled to
+    pub fn node_name(&self) -> Option<&str> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.node_name.as_deref()
+    }
+
+    /// Get the name of the pod's runtime class, which picks the provider that runs it when a
+    /// kubelet hosts several
+    pub fn runtime_class_name(&self) -> Option<&str> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.runtime_class_name.as_deref()
+    }
+
+    /// Get the resources the pod's runtime takes to run the pod, on top of what its containers
+    /// request. The API server sets this from the `overhead` of the pod's RuntimeClass when the
+    /// pod is created.
+    pub fn overhead(&self) -> Option<&std::collections::BTreeMap<String, Quantity>> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.overhead.as_ref()
+    }
+
+    /// Whether the pod uses the node's network, rather than a network namespace of its own
+    pub fn host_network(&self) -> bool {
+        self.kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.host_network)
+            .unwrap_or(false)
+    }
+
     /// Get the pod's service account name
     pub fn service_account_name(&self) -> Option<&str> {
         let spec = self.kube_pod.spec.as_ref()?;
         spec.service_account_name.as_deref()
     }
 
+    /// Get the pod's restart policy, which is `Always` if not set
+    pub fn restart_policy(&self) -> &str {
+        self.kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.restart_policy.as_deref())
+            .unwrap_or("Always")
+    }
+
+    /// Get how long the pod may be active on the node before it is failed, if it has a deadline
+    pub fn active_deadline(&self) -> Option<std::time::Duration> {
+        let seconds = self.kube_pod.spec.as_ref()?.active_deadline_seconds?;
+        Some(std::time::Duration::from_secs(seconds.max(0) as u64))
+    }
+
+    /// Get how long the pod's containers are given to shut down, which is 30 seconds if not set
+    pub fn termination_grace_period(&self) -> std::time::Duration {
+        let seconds = self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.termination_grace_period_seconds)
+            .unwrap_or(30);
+        std::time::Duration::from_secs(seconds.max(0) as u64)
+    }
+
     /// Get the pod volumes
     pub fn volumes(&self) -> Option<&Vec<KubeVolume>> {
         let spec = self.kube_pod.spec.as_ref()?;
@@ -93,6 +179,12 @@ impl Pod {
             .unwrap_or(&EMPTY_MAP)
     }
 
+    /// Whether the init container with the given name is a sidecar, which is started before the
+    /// pod's app containers, keeps running alongside them and is stopped after them.
+    pub fn is_sidecar(&self, name: &str) -> bool {
+        is_sidecar(self.annotations(), name)
+    }
+
     /// Get the names of the pod's image pull secrets
     pub fn image_pull_secrets(&self) -> Vec<String> {
         match self.kube_pod.spec.as_ref() {
@@ -114,6 +206,27 @@ impl Pod {
         self.kube_pod.meta().owner_references.is_none()
     }
 
+    /// Indicate if the pod has finished, either succeeding or failing
+    pub fn has_finished(&self) -> bool {
+        let phase = self
+            .kube_pod
+            .status
+            .as_ref()
+            .and_then(|status| status.phase.as_deref());
+        matches!(phase, Some("Succeeded") | Some("Failed"))
+    }
+
+    /// Indicate if this pod was read from the pod manifest directory rather than the API server
+    pub fn is_from_file(&self) -> bool {
+        self.get_annotation(CONFIG_SOURCE_ANNOTATION) == Some("file")
+    }
+
+    /// Indicate if this pod is a mirror pod, which stands in the API server for a static pod read
+    /// from the pod manifest directory
+    pub fn is_mirror(&self) -> bool {
+        self.get_annotation(CONFIG_MIRROR_ANNOTATION).is_some()
+    }
+
     /// Indicate if this pod is part of a Daemonset
     pub fn is_daemonset(&self) -> bool {
         if let Some(owners) = &self.kube_pod.meta().owner_references {
@@ -126,6 +239,34 @@ impl Pod {
         false
     }
 
+    /// Get the priority of this pod. Lower priority pods are evicted, and stopped when the node
+    /// shuts down, before higher priority ones. Pods naming one of the system priority classes
+    /// without having been given a priority, as is the case for static pods, get the priority of
+    /// that class.
+    pub fn priority(&self) -> i32 {
+        let spec = match self.kube_pod.spec.as_ref() {
+            Some(spec) => spec,
+            None => return 0,
+        };
+        spec.priority
+            .unwrap_or_else(|| match spec.priority_class_name.as_deref() {
+                Some("system-node-critical") => SYSTEM_NODE_CRITICAL_PRIORITY,
+                Some("system-cluster-critical") => SYSTEM_CRITICAL_PRIORITY,
+                _ => 0,
+            })
+    }
+
+    /// Indicate if this pod is critical to the node or cluster, so should be the last to stop
+    /// when the node shuts down
+    pub fn is_critical(&self) -> bool {
+        self.priority() >= SYSTEM_CRITICAL_PRIORITY
+    }
+
+    /// Indicate if this pod is a critical static pod, which is never evicted
+    pub fn is_critical_static(&self) -> bool {
+        (self.is_from_file() || self.is_mirror()) && self.is_critical()
+    }
+
     ///  Get a specific annotation from the pod
     pub fn get_annotation(&self, key: &str) -> Option<&str> {
         Some(self.annotations().get(key)?.as_str())
@@ -142,10 +283,10 @@ impl Pod {
 
     /// Find container by `ContainerKey` and return it.
     pub fn find_container(&self, key: &ContainerKey) -> Option<Container> {
-        let containers: Vec<Container> = if key.is_init() {
-            self.init_containers()
-        } else {
-            self.containers()
+        let containers: Vec<Container> = match key {
+            ContainerKey::Init(_) => self.init_containers(),
+            ContainerKey::App(_) => self.containers(),
+            ContainerKey::Ephemeral(_) => self.ephemeral_containers(),
         };
         containers
             .into_iter()
@@ -154,22 +295,22 @@ impl Pod {
 
     /// Finds the index of the container in the Pod's container statuses.
     pub fn container_status_index(&self, key: &ContainerKey) -> Option<usize> {
-        match self.kube_pod.status.as_ref() {
-            Some(status) => {
-                match if key.is_init() {
-                    status.init_container_statuses.as_ref()
-                } else {
-                    status.container_statuses.as_ref()
-                } {
-                    Some(statuses) => statuses
-                        .iter()
-                        .enumerate()
-                        .find(|(_, status)| status.name == key.name())
-                        .map(|(idx, _)| idx),
-                    None => None,
-                }
-            }
-            None => None,
+        self.container_statuses(key)?
+            .iter()
+            .position(|status| status.name == key.name())
+    }
+
+    /// The statuses of the pod's containers of the same kind as the keyed container, if the pod
+    /// has any.
+    pub(crate) fn container_statuses(
+        &self,
+        key: &ContainerKey,
+    ) -> Option<&Vec<KubeContainerStatus>> {
+        let status = self.kube_pod.status.as_ref()?;
+        match key {
+            ContainerKey::Init(_) => status.init_container_statuses.as_ref(),
+            ContainerKey::App(_) => status.container_statuses.as_ref(),
+            ContainerKey::Ephemeral(_) => status.ephemeral_container_statuses.as_ref(),
         }
     }
 
@@ -197,6 +338,50 @@ impl Pod {
             .collect()
     }
 
+    /// Get a pod's ephemeral containers. An ephemeral container that targets another of the pod's
+    /// containers shares that container's environment, along with its volume mounts if it has
+    /// none of its own, as there is no process namespace for it to share.
+    pub fn ephemeral_containers(&self) -> Vec<Container> {
+        let spec = match self.kube_pod.spec.as_ref() {
+            Some(spec) => spec,
+            None => return vec![],
+        };
+        spec.ephemeral_containers
+            .iter()
+            .flatten()
+            .filter_map(|ephemeral| {
+                // Ephemeral containers have the fields of a container, along with the one they
+                // target
+                let mut container: KubeContainer = serde_json::to_value(ephemeral)
+                    .and_then(serde_json::from_value)
+                    .ok()?;
+                let target = ephemeral
+                    .target_container_name
+                    .as_ref()
+                    .and_then(|target| spec.containers.iter().find(|c| &c.name == target));
+                if let Some(target) = target {
+                    let own = container.env.take().unwrap_or_default();
+                    let mut env: Vec<EnvVar> = target
+                        .env
+                        .iter()
+                        .flatten()
+                        .filter(|var| !own.iter().any(|own| own.name == var.name))
+                        .cloned()
+                        .collect();
+                    env.extend(own);
+                    container.env = Some(env);
+                    if container.env_from.is_none() {
+                        container.env_from = target.env_from.clone();
+                    }
+                    if container.volume_mounts.as_ref().map_or(true, Vec::is_empty) {
+                        container.volume_mounts = target.volume_mounts.clone();
+                    }
+                }
+                Some(Container::new(&container))
+            })
+            .collect()
+    }
+
     /// Gets all of a pod's containers (init and application)
     pub fn all_containers(&self) -> Vec<Container> {
         let mut app_containers = self.containers();
@@ -252,6 +437,18 @@ impl std::convert::From<Pod> for KubePod {
     }
 }
 
+/// Whether the init container with the given name is a sidecar, according to its pod's
+/// annotations.
+pub(crate) fn is_sidecar(
+    annotations: &std::collections::BTreeMap<String, String>,
+    name: &str,
+) -> bool {
+    annotations
+        .get(&format!("{}{}", RESTART_POLICY_ANNOTATION_PREFIX, name))
+        .map(String::as_str)
+        == Some("Always")
+}
+
 /// PodKey is a unique human readable key for storing a handle to a pod in a hash.
 #[derive(Hash, Ord, Eq, PartialOrd, PartialEq, Debug, Clone, Default)]
 pub struct PodKey {
@@ -1,14 +1,20 @@
 //! Container statuses
 
 use super::Pod;
+use crate::backoff::RetryPolicy;
 use crate::container::make_initial_container_status;
+use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use k8s_openapi::api::core::v1::PodStatus as KubePodStatus;
+use k8s_openapi::api::core::v1::{PodCondition, PodIP};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use krator::{Manifest, ObjectStatus};
 use kube::api::PatchParams;
 use kube::Api;
-use log::{debug, warn};
+use std::net::IpAddr;
+use tracing::{debug, warn};
 
 /// Patch Pod status with Kubernetes API.
 pub async fn patch_status(api: &Api<KubePod>, name: &str, status: Status) {
@@ -20,7 +26,13 @@ pub async fn patch_status(api: &Api<KubePod>, name: &str, status: Status) {
                 &name,
                 std::str::from_utf8(&data).unwrap()
             );
-            match api.patch_status(&name, &PatchParams::default(), data).await {
+            let params = PatchParams::default();
+            let patched = RetryPolicy::api_request()
+                .retry("patch pod status", || {
+                    api.patch_status(&name, &params, data.clone())
+                })
+                .await;
+            match patched {
                 Ok(_) => (),
                 Err(e) => {
                     warn!("Pod {} error patching status: {:?}", name, e);
@@ -38,11 +50,15 @@ pub async fn patch_status(api: &Api<KubePod>, name: &str, status: Status) {
 
 const MAX_STATUS_INIT_RETRIES: usize = 5;
 
-/// Initializes Pod container status array and wait for Pod reflection to update.
+/// Initializes Pod container status array and wait for Pod reflection to update. The pod is
+/// reported as running on the node's IP, with the given IPs of its own, which are the node's IP
+/// for pods that run in the node's network namespace.
 pub async fn initialize_pod_container_statuses(
     name: String,
     pod: Manifest<Pod>,
     api: &Api<KubePod>,
+    node_ip: IpAddr,
+    pod_ips: &[IpAddr],
 ) -> anyhow::Result<()> {
     // NOTE: This loop patches the container statuses of the Pod with and then
     // waits for them to be picked up by the reflector. This is needed for a
@@ -67,7 +83,8 @@ pub async fn initialize_pod_container_statuses(
         }
         let (num_containers, num_init_containers) = {
             let pod = pod.latest();
-            patch_status(&api, &name, make_registered_status(&pod)).await;
+            let status = registered_status(&pod).host_ip(node_ip).pod_ips(pod_ips);
+            patch_status(&api, &name, status.build()).await;
             let num_containers = pod.containers().len();
             let num_init_containers = pod.init_containers().len();
             (num_containers, num_init_containers)
@@ -104,24 +121,311 @@ pub async fn initialize_pod_container_statuses(
 
 /// Initialize Pod status.
 /// This initializes Pod status to include containers in the correct order as expected by
-/// `patch_container_status`.
+/// `patch_container_status`, along with the pod's conditions, QoS class and start time.
 pub fn make_registered_status(pod: &Pod) -> Status {
-    let init_container_statuses: Vec<KubeContainerStatus> = pod
-        .init_containers()
+    registered_status(pod).build()
+}
+
+fn registered_status(pod: &Pod) -> StatusBuilder {
+    let initial_status = |container: &crate::container::Container| KubeContainerStatus {
+        container_id: Some(container_id(pod, container.name())),
+        ..make_initial_container_status(container)
+    };
+    let init_container_statuses: Vec<KubeContainerStatus> =
+        pod.init_containers().iter().map(initial_status).collect();
+    let container_statuses: Vec<KubeContainerStatus> =
+        pod.containers().iter().map(initial_status).collect();
+    let status = pod.as_kube_pod().status.as_ref();
+    let conditions = pod_conditions(
+        status
+            .and_then(|status| status.conditions.as_deref())
+            .unwrap_or_default(),
+        &init_container_statuses,
+        &container_statuses,
+        |name| pod.is_sidecar(name),
+    );
+    // A pod that was registered before, such as by a kubelet that has since restarted, keeps the
+    // time it started
+    let start_time = status
+        .and_then(|status| status.start_time.as_ref())
+        .map(|time| time.0)
+        .unwrap_or_else(Utc::now);
+    StatusBuilder::new()
+        .phase(Phase::Pending)
+        .reason("Registered")
+        .container_statuses(container_statuses)
+        .init_container_statuses(init_container_statuses)
+        .conditions(conditions)
+        .qos_class(qos_class(pod))
+        .start_time(start_time)
+}
+
+/// The ID a container is reported with until its provider reports the ID of a run of it. Containers
+/// of providers that don't give their runs IDs keep this one across restarts.
+fn container_id(pod: &Pod, container_name: &str) -> String {
+    let uid = pod
+        .as_kube_pod()
+        .metadata
+        .uid
+        .as_deref()
+        .unwrap_or_else(|| pod.name());
+    format!("krustlet://{}/{}", uid, container_name)
+}
+
+/// Work out the quality of service class of a pod from the resources its containers request.
+///
+/// Pods whose containers all have CPU and memory limits, and request no less than they are
+/// limited to, are `Guaranteed`. Pods whose containers have no requests or limits at all are
+/// `BestEffort`, and everything else is `Burstable`.
+pub fn qos_class(pod: &Pod) -> &'static str {
+    let mut any_set = false;
+    let mut guaranteed = true;
+    for container in pod.all_containers() {
+        let resources = container.resources();
+        let requests = resources.and_then(|resources| resources.requests.as_ref());
+        let limits = resources.and_then(|resources| resources.limits.as_ref());
+        for resource in &["cpu", "memory"] {
+            let request = requests
+                .and_then(|requests| requests.get(*resource))
+                .filter(|quantity| !is_zero(quantity));
+            let limit = limits
+                .and_then(|limits| limits.get(*resource))
+                .filter(|quantity| !is_zero(quantity));
+            any_set |= request.is_some() || limit.is_some();
+            guaranteed &= match (request, limit) {
+                // Requests default to the limit when they aren't given
+                (None, Some(_)) => true,
+                (Some(request), Some(limit)) => same_quantity(request, limit),
+                (_, None) => false,
+            };
+        }
+    }
+    if !any_set {
+        "BestEffort"
+    } else if guaranteed {
+        "Guaranteed"
+    } else {
+        "Burstable"
+    }
+}
+
+fn is_zero(quantity: &Quantity) -> bool {
+    crate::resources::parse(quantity).map_or(false, |value| value == 0.0)
+}
+
+fn same_quantity(a: &Quantity, b: &Quantity) -> bool {
+    match (crate::resources::parse(a), crate::resources::parse(b)) {
+        (Ok(a), Ok(b)) => (a - b).abs() <= f64::EPSILON * a.max(b),
+        _ => false,
+    }
+}
+
+/// Work out a pod's `Initialized`, `ContainersReady` and `Ready` conditions from the statuses of
+/// its containers, along with `PodScheduled`, which is always true for a pod on this node.
+/// Conditions of other types are kept as they are, and conditions that haven't changed keep the
+/// time they last changed. The init containers for which `is_sidecar` is true only have to have
+/// started for the pod to be initialized, and have to be ready for it to be ready.
+pub fn pod_conditions(
+    existing: &[PodCondition],
+    init_container_statuses: &[KubeContainerStatus],
+    container_statuses: &[KubeContainerStatus],
+    is_sidecar: impl Fn(&str) -> bool,
+) -> Vec<PodCondition> {
+    let exited = |status: &KubeContainerStatus| {
+        status
+            .state
+            .as_ref()
+            .and_then(|state| state.terminated.as_ref())
+            .map(|terminated| terminated.exit_code)
+    };
+
+    let initialized_container = |status: &KubeContainerStatus| {
+        if is_sidecar(&status.name) {
+            status.started == Some(true)
+                && status
+                    .state
+                    .as_ref()
+                    .map_or(false, |state| state.waiting.is_none())
+        } else {
+            exited(status) == Some(0)
+        }
+    };
+
+    let uninitialized: Vec<&str> = init_container_statuses
         .iter()
-        .map(make_initial_container_status)
+        .filter(|status| !initialized_container(status))
+        .map(|status| status.name.as_str())
         .collect();
-    let container_statuses: Vec<KubeContainerStatus> = pod
-        .containers()
+    let initialized = if uninitialized.is_empty() {
+        (true, None, None)
+    } else {
+        (
+            false,
+            Some("ContainersNotInitialized"),
+            Some(format!(
+                "containers with incomplete status: [{}]",
+                uninitialized.join(" ")
+            )),
+        )
+    };
+
+    let unready: Vec<&str> = init_container_statuses
         .iter()
-        .map(make_initial_container_status)
+        .filter(|status| is_sidecar(&status.name))
+        .chain(container_statuses)
+        .filter(|status| !status.ready)
+        .map(|status| status.name.as_str())
         .collect();
-    make_status_with_containers(
-        Phase::Pending,
-        "Registered",
-        container_statuses,
-        init_container_statuses,
+    let ready = if !uninitialized.is_empty() {
+        initialized.clone()
+    } else if unready.is_empty() {
+        (true, None, None)
+    } else if container_statuses
+        .iter()
+        .all(|status| exited(status).is_some())
+    {
+        (false, Some("PodCompleted"), None)
+    } else {
+        (
+            false,
+            Some("ContainersNotReady"),
+            Some(format!(
+                "containers with unready status: [{}]",
+                unready.join(" ")
+            )),
+        )
+    };
+
+    let now = Time(Utc::now());
+    let condition =
+        |type_: &str, (status, reason, message): (bool, Option<&str>, Option<String>)| {
+            let status = if status { "True" } else { "False" };
+            let last_transition_time = existing
+                .iter()
+                .find(|condition| condition.type_ == type_ && condition.status == status)
+                .and_then(|condition| condition.last_transition_time.clone())
+                .unwrap_or_else(|| now.clone());
+            PodCondition {
+                type_: type_.to_owned(),
+                status: status.to_owned(),
+                reason: reason.map(str::to_owned),
+                message,
+                last_transition_time: Some(last_transition_time),
+                last_probe_time: None,
+            }
+        };
+    let mut conditions = vec![
+        condition("Initialized", initialized),
+        condition("Ready", ready.clone()),
+        condition("ContainersReady", ready),
+        condition("PodScheduled", (true, None, None)),
+    ];
+    let others: Vec<PodCondition> = existing
+        .iter()
+        .filter(|condition| !conditions.iter().any(|c| c.type_ == condition.type_))
+        .cloned()
+        .collect();
+    conditions.extend(others);
+    conditions
+}
+
+/// Bring a pod's conditions up to date with the statuses of its containers, as returned from
+/// patching them. Nothing is patched if the conditions haven't changed.
+pub(crate) async fn update_pod_conditions(api: &Api<KubePod>, pod: &KubePod) -> anyhow::Result<()> {
+    let status = match pod.status.as_ref() {
+        Some(status) => status,
+        None => return Ok(()),
+    };
+    let existing = status.conditions.as_deref().unwrap_or_default();
+    let conditions = pod_conditions(
+        existing,
+        status
+            .init_container_statuses
+            .as_deref()
+            .unwrap_or_default(),
+        status.container_statuses.as_deref().unwrap_or_default(),
+        |name| {
+            pod.metadata
+                .annotations
+                .as_ref()
+                .map_or(false, |annotations| super::is_sidecar(annotations, name))
+        },
+    );
+    let unchanged = conditions.len() == existing.len()
+        && conditions.iter().all(|condition| {
+            existing.iter().any(|e| {
+                e.type_ == condition.type_
+                    && e.status == condition.status
+                    && e.reason == condition.reason
+                    && e.message == condition.message
+            })
+        });
+    if unchanged {
+        return Ok(());
+    }
+    let name = pod.metadata.name.as_deref().unwrap_or_default();
+    let patch = serde_json::json!({ "status": { "conditions": conditions } });
+    api.patch_status(name, &PatchParams::default(), serde_json::to_vec(&patch)?)
+        .await?;
+    Ok(())
+}
+
+/// The type of the condition that tells controllers a pod is being terminated on purpose, by the
+/// kubelet or an eviction, rather than because it failed.
+pub const DISRUPTION_TARGET: &str = "DisruptionTarget";
+/// The reason a pod is a disruption target when the kubelet terminates it.
+pub const TERMINATION_BY_KUBELET: &str = "TerminationByKubelet";
+/// The reason a pod is a disruption target when it is evicted for not tolerating its node's
+/// taints.
+pub const DELETION_BY_TAINT_MANAGER: &str = "DeletionByTaintManager";
+
+/// The pod's conditions with a true `DisruptionTarget` condition for the given reason. The
+/// condition keeps the time it last changed if the pod already had it.
+pub fn disruption_target_conditions(pod: &Pod, reason: &str, message: &str) -> Vec<PodCondition> {
+    let existing = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|status| status.conditions.clone())
+        .unwrap_or_default();
+    let last_transition_time = existing
+        .iter()
+        .find(|condition| condition.type_ == DISRUPTION_TARGET && condition.status == "True")
+        .and_then(|condition| condition.last_transition_time.clone())
+        .unwrap_or_else(|| Time(Utc::now()));
+    let mut conditions: Vec<PodCondition> = existing
+        .into_iter()
+        .filter(|condition| condition.type_ != DISRUPTION_TARGET)
+        .collect();
+    conditions.push(PodCondition {
+        type_: DISRUPTION_TARGET.to_owned(),
+        status: "True".to_owned(),
+        reason: Some(reason.to_owned()),
+        message: Some(message.to_owned()),
+        last_transition_time: Some(last_transition_time),
+        last_probe_time: None,
+    });
+    conditions
+}
+
+/// Mark a pod as a disruption target before it is terminated, so that its controller can tell
+/// why it went away.
+pub(crate) async fn mark_disruption_target(
+    api: &Api<KubePod>,
+    pod: &Pod,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let patch = serde_json::json!({
+        "status": { "conditions": disruption_target_conditions(pod, reason, message) }
+    });
+    api.patch_status(
+        pod.name(),
+        &PatchParams::default(),
+        serde_json::to_vec(&patch)?,
     )
+    .await?;
+    Ok(())
 }
 
 /// Create basic Pod status patch.
@@ -152,6 +456,13 @@ pub fn make_status_with_containers(
 /// Pod Status wrapper.
 pub struct Status(KubePodStatus);
 
+impl Status {
+    /// The Kubernetes pod status this patches a pod to.
+    pub(crate) fn into_kube_status(self) -> KubePodStatus {
+        self.0
+    }
+}
+
 #[derive(Default)]
 /// Builder for Pod Status wrapper.
 pub struct StatusBuilder(KubePodStatus);
@@ -198,6 +509,53 @@ impl StatusBuilder {
         self
     }
 
+    /// Set Pod conditions. These replace all of the pod's conditions.
+    pub fn conditions(mut self, conditions: Vec<PodCondition>) -> StatusBuilder {
+        self.0.conditions = Some(conditions);
+        self
+    }
+
+    /// Set Pod quality of service class.
+    pub fn qos_class(mut self, qos_class: &str) -> StatusBuilder {
+        self.0.qos_class = Some(qos_class.to_string());
+        self
+    }
+
+    /// Set when the Pod was started by the kubelet.
+    pub fn start_time(mut self, start_time: DateTime<Utc>) -> StatusBuilder {
+        self.0.start_time = Some(Time(start_time));
+        self
+    }
+
+    /// Set the IP of the node the Pod is running on.
+    pub fn host_ip(mut self, host_ip: IpAddr) -> StatusBuilder {
+        self.0.host_ip = Some(host_ip.to_string());
+        self
+    }
+
+    /// Set Pod IP.
+    pub fn pod_ip(mut self, pod_ip: IpAddr) -> StatusBuilder {
+        self.0.pod_ip = Some(pod_ip.to_string());
+        self.0.pod_ips = Some(vec![PodIP {
+            ip: Some(pod_ip.to_string()),
+        }]);
+        self
+    }
+
+    /// Set the Pod's IPs, the first of which is its primary IP.
+    pub fn pod_ips(mut self, pod_ips: &[IpAddr]) -> StatusBuilder {
+        self.0.pod_ip = pod_ips.first().map(ToString::to_string);
+        self.0.pod_ips = Some(
+            pod_ips
+                .iter()
+                .map(|ip| PodIP {
+                    ip: Some(ip.to_string()),
+                })
+                .collect(),
+        );
+        self
+    }
+
     /// Finalize Pod Status from builder.
     pub fn build(self) -> Status {
         Status(self.0)
@@ -256,6 +614,30 @@ impl ObjectStatus for Status {
             status.insert("initContainerStatuses".to_string(), serde_json::json!(s));
         };
 
+        if let Some(s) = self.0.conditions.clone() {
+            status.insert("conditions".to_string(), serde_json::json!(s));
+        };
+
+        if let Some(s) = self.0.qos_class.clone() {
+            status.insert("qosClass".to_string(), serde_json::Value::String(s));
+        };
+
+        if let Some(s) = self.0.start_time.clone() {
+            status.insert("startTime".to_string(), serde_json::json!(s));
+        };
+
+        if let Some(s) = self.0.host_ip.clone() {
+            status.insert("hostIP".to_string(), serde_json::Value::String(s));
+        };
+
+        if let Some(s) = self.0.pod_ip.clone() {
+            status.insert("podIP".to_string(), serde_json::Value::String(s));
+        };
+
+        if let Some(s) = self.0.pod_ips.clone() {
+            status.insert("podIPs".to_string(), serde_json::json!(s));
+        };
+
         serde_json::json!(
             {
                 "metadata": {
@@ -274,3 +656,196 @@ impl ObjectStatus for Status {
             .build()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+    use k8s_openapi::api::core::v1::{ContainerState, ContainerStateTerminated};
+
+    fn pod_with_resources(resources: Vec<serde_json::Value>) -> Pod {
+        let containers: Vec<serde_json::Value> = resources
+            .into_iter()
+            .enumerate()
+            .map(|(i, resources)| {
+                serde_json::json!({
+                    "name": format!("container{}", i),
+                    "image": "webassembly.azurecr.io/hello:v1",
+                    "resources": resources,
+                })
+            })
+            .collect();
+        PodBuilder::new()
+            .spec(serde_json::json!({ "containers": containers }))
+            .build()
+    }
+
+    fn container_status(name: &str, ready: bool, exit_code: Option<i32>) -> KubeContainerStatus {
+        KubeContainerStatus {
+            name: name.to_owned(),
+            ready,
+            state: Some(ContainerState {
+                terminated: exit_code.map(|exit_code| ContainerStateTerminated {
+                    exit_code,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn condition<'a>(conditions: &'a [PodCondition], type_: &str) -> &'a PodCondition {
+        conditions.iter().find(|c| c.type_ == type_).unwrap()
+    }
+
+    #[test]
+    fn qos_class_follows_requests_and_limits() {
+        let pod = pod_with_resources(vec![serde_json::json!({})]);
+        assert_eq!(qos_class(&pod), "BestEffort");
+
+        let pod = pod_with_resources(vec![serde_json::json!({
+            "limits": { "cpu": "500m", "memory": "64Mi" },
+        })]);
+        assert_eq!(qos_class(&pod), "Guaranteed");
+
+        let pod = pod_with_resources(vec![serde_json::json!({
+            "requests": { "cpu": "0.5", "memory": "64Mi" },
+            "limits": { "cpu": "500m", "memory": "67108864" },
+        })]);
+        assert_eq!(qos_class(&pod), "Guaranteed");
+
+        let pod = pod_with_resources(vec![
+            serde_json::json!({ "limits": { "cpu": "500m", "memory": "64Mi" } }),
+            serde_json::json!({ "requests": { "memory": "32Mi" } }),
+        ]);
+        assert_eq!(qos_class(&pod), "Burstable");
+    }
+
+    #[test]
+    fn pod_is_ready_once_initialized_and_containers_are_ready() {
+        let init = vec![container_status("init", false, Some(0))];
+        let containers = vec![
+            container_status("a", true, None),
+            container_status("b", false, None),
+        ];
+        let conditions = pod_conditions(&[], &init, &containers, |_| false);
+        assert_eq!(condition(&conditions, "PodScheduled").status, "True");
+        assert_eq!(condition(&conditions, "Initialized").status, "True");
+        let ready = condition(&conditions, "Ready");
+        assert_eq!(ready.status, "False");
+        assert_eq!(ready.reason.as_deref(), Some("ContainersNotReady"));
+        assert_eq!(
+            ready.message.as_deref(),
+            Some("containers with unready status: [b]")
+        );
+
+        let containers = vec![
+            container_status("a", true, None),
+            container_status("b", true, None),
+        ];
+        let updated = pod_conditions(&conditions, &init, &containers, |_| false);
+        assert_eq!(condition(&updated, "Ready").status, "True");
+        assert_eq!(condition(&updated, "ContainersReady").status, "True");
+        // Conditions that didn't change keep when they last changed
+        assert_eq!(
+            condition(&updated, "Initialized").last_transition_time,
+            condition(&conditions, "Initialized").last_transition_time
+        );
+    }
+
+    #[test]
+    fn pod_is_not_ready_until_initialized() {
+        let init = vec![container_status("init", false, None)];
+        let containers = vec![container_status("a", false, None)];
+        let conditions = pod_conditions(&[], &init, &containers, |_| false);
+        let initialized = condition(&conditions, "Initialized");
+        assert_eq!(initialized.status, "False");
+        assert_eq!(
+            initialized.reason.as_deref(),
+            Some("ContainersNotInitialized")
+        );
+        assert_eq!(
+            condition(&conditions, "Ready").reason.as_deref(),
+            Some("ContainersNotInitialized")
+        );
+    }
+
+    #[test]
+    fn sidecars_initialize_pod_once_running_and_must_be_ready() {
+        let running = |name: &str, ready: bool| KubeContainerStatus {
+            started: Some(true),
+            state: Some(ContainerState {
+                running: Some(Default::default()),
+                ..Default::default()
+            }),
+            ..container_status(name, ready, None)
+        };
+        let is_sidecar = |name: &str| name == "proxy";
+        let init = vec![
+            container_status("init", false, Some(0)),
+            container_status("proxy", false, None),
+        ];
+        let containers = vec![container_status("a", false, None)];
+        let conditions = pod_conditions(&[], &init, &containers, is_sidecar);
+        assert_eq!(condition(&conditions, "Initialized").status, "False");
+
+        let init = vec![
+            container_status("init", false, Some(0)),
+            running("proxy", false),
+        ];
+        let containers = vec![running("a", true)];
+        let conditions = pod_conditions(&[], &init, &containers, is_sidecar);
+        assert_eq!(condition(&conditions, "Initialized").status, "True");
+        assert_eq!(
+            condition(&conditions, "Ready").message.as_deref(),
+            Some("containers with unready status: [proxy]")
+        );
+
+        let init = vec![
+            container_status("init", false, Some(0)),
+            running("proxy", true),
+        ];
+        let conditions = pod_conditions(&[], &init, &containers, is_sidecar);
+        assert_eq!(condition(&conditions, "Ready").status, "True");
+    }
+
+    #[test]
+    fn completed_pods_are_not_ready() {
+        let containers = vec![container_status("a", false, Some(0))];
+        let conditions = pod_conditions(&[], &[], &containers, |_| false);
+        assert_eq!(
+            condition(&conditions, "Ready").reason.as_deref(),
+            Some("PodCompleted")
+        );
+    }
+
+    #[test]
+    fn other_conditions_are_kept() {
+        let existing = vec![PodCondition {
+            type_: "example.com/Gate".to_owned(),
+            status: "True".to_owned(),
+            ..Default::default()
+        }];
+        let conditions = pod_conditions(&existing, &[], &[], |_| false);
+        assert_eq!(conditions.len(), 5);
+        assert_eq!(condition(&conditions, "example.com/Gate").status, "True");
+    }
+
+    #[test]
+    fn disruption_target_replaces_earlier_one() {
+        let pod = PodBuilder::new()
+            .status(serde_json::json!({ "conditions": [
+                { "type": "Ready", "status": "True" },
+                { "type": "DisruptionTarget", "status": "False" }
+            ] }))
+            .build();
+        let conditions = disruption_target_conditions(&pod, "TerminationByKubelet", "evicted");
+        assert_eq!(conditions.len(), 2);
+        assert_eq!(condition(&conditions, "Ready").status, "True");
+        let target = condition(&conditions, DISRUPTION_TARGET);
+        assert_eq!(target.status, "True");
+        assert_eq!(target.reason.as_deref(), Some("TerminationByKubelet"));
+        assert_eq!(target.message.as_deref(), Some("evicted"));
+    }
+}
@@ -0,0 +1,11 @@
+//! `port_forward` contains types for forwarding client connections to ports in a workload.
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A bidirectional byte stream connected to a port in a workload.
+///
+/// This is implemented for anything that can be read from and written to, such as a
+/// `tokio::net::TcpStream`, so providers whose workloads are reachable from the host can connect
+/// to the port and hand back the connection.
+pub trait PortStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> PortStream for T {}
@@ -0,0 +1,364 @@
+//! Startup, liveness and readiness probes, which check on a running container at regular
+//! intervals.
+//!
+//! A failing readiness probe marks the container as not ready, and a failing liveness probe
+//! stops the container so that it can be restarted according to the pod's restart policy. If the
+//! container has a startup probe, it isn't started or ready and its other probes don't run until
+//! the startup probe passes, so slow starting modules aren't killed by their liveness probe. Exec
+//! probes are run through the provider's [`ExecHandler`].
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{join_all, AbortHandle, Abortable};
+use k8s_openapi::api::core::v1::{Pod as KubePod, Probe as KubeProbe};
+use tracing::{debug, info, warn};
+
+use crate::container::action::{self, Action};
+use crate::container::{patch_container_ready, patch_container_started, Container, ContainerKey};
+use crate::handle::{ExecHandler, StopHandler};
+use crate::pod::{Handle as PodHandle, Pod};
+
+const DEFAULT_PERIOD_SECONDS: i32 = 10;
+const DEFAULT_TIMEOUT_SECONDS: i32 = 1;
+const DEFAULT_SUCCESS_THRESHOLD: i32 = 1;
+const DEFAULT_FAILURE_THRESHOLD: i32 = 3;
+
+/// What a probe decides about its container.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Startup,
+    Liveness,
+    Readiness,
+}
+
+/// How often a probe runs and how many results in a row change its container's state.
+#[derive(Clone, Debug)]
+struct Settings {
+    initial_delay: Duration,
+    period: Duration,
+    timeout: Duration,
+    success_threshold: u32,
+    failure_threshold: u32,
+}
+
+impl From<&KubeProbe> for Settings {
+    fn from(probe: &KubeProbe) -> Self {
+        let seconds = |value: Option<i32>, default: i32| {
+            Duration::from_secs(value.unwrap_or(default).max(0) as u64)
+        };
+        let threshold = |value: Option<i32>, default: i32| value.unwrap_or(default).max(1) as u32;
+        Settings {
+            initial_delay: seconds(probe.initial_delay_seconds, 0),
+            period: seconds(probe.period_seconds, DEFAULT_PERIOD_SECONDS)
+                .max(Duration::from_secs(1)),
+            timeout: seconds(probe.timeout_seconds, DEFAULT_TIMEOUT_SECONDS)
+                .max(Duration::from_secs(1)),
+            success_threshold: threshold(probe.success_threshold, DEFAULT_SUCCESS_THRESHOLD),
+            failure_threshold: threshold(probe.failure_threshold, DEFAULT_FAILURE_THRESHOLD),
+        }
+    }
+}
+
+/// Counts the consecutive results of a probe to decide whether its container is healthy.
+#[derive(Debug)]
+struct Thresholds {
+    success_threshold: u32,
+    failure_threshold: u32,
+    successes: u32,
+    failures: u32,
+    healthy: Option<bool>,
+}
+
+impl Thresholds {
+    /// Create thresholds for a probe whose container starts out healthy, unhealthy, or neither
+    /// until enough results are in.
+    fn new(settings: &Settings, healthy: Option<bool>) -> Self {
+        Thresholds {
+            success_threshold: settings.success_threshold,
+            failure_threshold: settings.failure_threshold,
+            successes: 0,
+            failures: 0,
+            healthy,
+        }
+    }
+
+    /// Record the result of a probe, returning whether the container is now healthy if that
+    /// has changed.
+    fn record(&mut self, success: bool) -> Option<bool> {
+        if success {
+            self.successes += 1;
+            self.failures = 0;
+            if self.healthy != Some(true) && self.successes >= self.success_threshold {
+                self.healthy = Some(true);
+                return Some(true);
+            }
+        } else {
+            self.failures += 1;
+            self.successes = 0;
+            if self.healthy != Some(false) && self.failures >= self.failure_threshold {
+                self.healthy = Some(false);
+                return Some(false);
+            }
+        }
+        None
+    }
+}
+
+/// Everything a probe needs to check on its container and act on the results.
+struct Context<H, F> {
+    api: kube::Api<KubePod>,
+    pod: Pod,
+    container_name: String,
+    key: ContainerKey,
+    handle: Arc<PodHandle<H, F>>,
+    http: reqwest::Client,
+}
+
+impl<H: ExecHandler + StopHandler, F> Context<H, F> {
+    async fn check(&self, action: &Action) -> anyhow::Result<()> {
+        action
+            .run(&self.http, |options| {
+                self.handle.exec_command(&self.container_name, options)
+            })
+            .await
+    }
+
+    async fn set_ready(&self, ready: bool) {
+        if let Err(e) = patch_container_ready(&self.api, &self.pod, &self.key, ready).await {
+            warn!(
+                "Pod {} container {} readiness patch returned error: {:?}",
+                self.pod.name(),
+                self.container_name,
+                e
+            );
+        }
+    }
+
+    async fn set_started(&self, started: bool) {
+        if let Err(e) = patch_container_started(&self.api, &self.pod, &self.key, started).await {
+            warn!(
+                "Pod {} container {} started patch returned error: {:?}",
+                self.pod.name(),
+                self.container_name,
+                e
+            );
+        }
+    }
+
+    async fn stop(&self, kind: Kind) {
+        info!(
+            "Pod {} container {} failed its {:?} probe, stopping it",
+            self.pod.name(),
+            self.container_name,
+            kind
+        );
+        if let Err(e) = self.handle.stop_container(&self.container_name).await {
+            warn!(
+                "Unable to stop pod {} container {}: {:?}",
+                self.pod.name(),
+                self.container_name,
+                e
+            );
+        }
+    }
+
+    /// Run the startup probe, if there is one, then the other probes.
+    async fn run_all(&self, startup: Option<(Settings, Action)>, probes: Vec<Probe>) {
+        let has_readiness = probes.iter().any(|(kind, _, _)| *kind == Kind::Readiness);
+        if has_readiness || startup.is_some() {
+            self.set_ready(false).await;
+        }
+        if let Some((settings, action)) = startup {
+            self.set_started(false).await;
+            if !self.run(Kind::Startup, settings, action).await {
+                return;
+            }
+            self.set_started(true).await;
+            if !has_readiness {
+                self.set_ready(true).await;
+            }
+        }
+        join_all(
+            probes
+                .into_iter()
+                .map(|(kind, settings, action)| self.run(kind, settings, action)),
+        )
+        .await;
+    }
+
+    /// Run a probe until it passes, for startup probes, or until it stops the container. Returns
+    /// whether the container is still running.
+    async fn run(&self, kind: Kind, settings: Settings, action: Action) -> bool {
+        let healthy = match kind {
+            Kind::Startup => None,
+            Kind::Liveness => Some(true),
+            // Containers aren't ready until their readiness probe has passed
+            Kind::Readiness => Some(false),
+        };
+        let mut thresholds = Thresholds::new(&settings, healthy);
+        tokio::time::delay_for(settings.initial_delay).await;
+        loop {
+            let result = match tokio::time::timeout(settings.timeout, self.check(&action)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!(
+                    "probe timed out after {:?}",
+                    settings.timeout
+                )),
+            };
+            if let Err(e) = &result {
+                debug!(
+                    "Pod {} container {} {:?} probe failed: {:?}",
+                    self.pod.name(),
+                    self.container_name,
+                    kind,
+                    e
+                );
+            }
+            match (kind, thresholds.record(result.is_ok())) {
+                (Kind::Readiness, Some(ready)) => self.set_ready(ready).await,
+                (Kind::Startup, Some(true)) => return true,
+                (Kind::Startup, Some(false)) | (Kind::Liveness, Some(false)) => {
+                    self.stop(kind).await;
+                    return false;
+                }
+                _ => (),
+            }
+            tokio::time::delay_for(settings.period).await;
+        }
+    }
+}
+
+type Probe = (Kind, Settings, Action);
+
+/// The probes of a running container, which are stopped when this is dropped.
+pub struct Probes {
+    task: Option<AbortHandle>,
+}
+
+impl Drop for Probes {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+/// Start running the probes of a container that has just started. The probes run until the
+/// returned [`Probes`] is dropped, which should happen when the container stops running.
+pub fn start<H, F>(
+    client: kube::Client,
+    pod: &Pod,
+    container: &Container,
+    key: ContainerKey,
+    handle: Arc<PodHandle<H, F>>,
+) -> Probes
+where
+    H: ExecHandler + StopHandler + Send + Sync + 'static,
+    F: Send + Sync + 'static,
+{
+    let mut probes: Vec<Probe> = vec![
+        (Kind::Startup, container.startup_probe()),
+        (Kind::Liveness, container.liveness_probe()),
+        (Kind::Readiness, container.readiness_probe()),
+    ]
+    .into_iter()
+    .filter_map(|(kind, probe)| Some((kind, probe?)))
+    .filter_map(|(kind, probe)| {
+        match Action::new(
+            probe.exec.as_ref(),
+            probe.http_get.as_ref(),
+            probe.tcp_socket.as_ref(),
+            pod,
+            container,
+        ) {
+            Ok(action) => Some((kind, Settings::from(probe), action)),
+            Err(e) => {
+                warn!(
+                    "Pod {} container {} has an invalid {:?} probe: {:?}",
+                    pod.name(),
+                    container.name(),
+                    kind,
+                    e
+                );
+                None
+            }
+        }
+    })
+    .collect();
+    if probes.is_empty() {
+        return Probes { task: None };
+    }
+    let startup = match probes
+        .iter()
+        .position(|(kind, _, _)| *kind == Kind::Startup)
+    {
+        Some(index) => {
+            let (_, settings, action) = probes.remove(index);
+            Some((settings, action))
+        }
+        None => None,
+    };
+
+    let http = action::http_client();
+    let context = Context {
+        api: kube::Api::namespaced(client, pod.namespace()),
+        pod: pod.clone(),
+        container_name: container.name().to_owned(),
+        key,
+        handle,
+        http,
+    };
+    let (task, registration) = AbortHandle::new_pair();
+    tokio::spawn(Abortable::new(
+        async move { context.run_all(startup, probes).await },
+        registration,
+    ));
+    Probes { task: Some(task) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings(success_threshold: u32, failure_threshold: u32) -> Settings {
+        Settings {
+            initial_delay: Duration::from_secs(0),
+            period: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+            success_threshold,
+            failure_threshold,
+        }
+    }
+
+    #[test]
+    fn container_becomes_unhealthy_after_failure_threshold() {
+        let mut thresholds = Thresholds::new(&settings(1, 3), Some(true));
+        assert_eq!(thresholds.record(false), None);
+        assert_eq!(thresholds.record(false), None);
+        assert_eq!(thresholds.record(true), None);
+        assert_eq!(thresholds.record(false), None);
+        assert_eq!(thresholds.record(false), None);
+        assert_eq!(thresholds.record(false), Some(false));
+        assert_eq!(thresholds.record(false), None);
+    }
+
+    #[test]
+    fn container_becomes_healthy_after_success_threshold() {
+        let mut thresholds = Thresholds::new(&settings(2, 3), Some(false));
+        assert_eq!(thresholds.record(true), None);
+        assert_eq!(thresholds.record(true), Some(true));
+        assert_eq!(thresholds.record(true), None);
+    }
+
+    #[test]
+    fn startup_probe_passes_or_fails_once() {
+        let mut thresholds = Thresholds::new(&settings(1, 3), None);
+        assert_eq!(thresholds.record(true), Some(true));
+        assert_eq!(thresholds.record(true), None);
+
+        let mut thresholds = Thresholds::new(&settings(1, 3), None);
+        assert_eq!(thresholds.record(false), None);
+        assert_eq!(thresholds.record(false), None);
+        assert_eq!(thresholds.record(false), Some(false));
+    }
+}
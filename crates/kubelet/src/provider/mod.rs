@@ -1,17 +1,31 @@
 //! Traits and types needed to create backend providers for a Kubelet
-use std::collections::HashMap;
+pub mod registry;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use async_trait::async_trait;
-use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, Secret};
+use k8s_openapi::api::core::v1::{
+    ConfigMap, EnvVarSource, Node, PodList, ResourceFieldSelector, Secret,
+};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use kube::api::Api;
-use log::{error, info};
+use kube::error::ErrorResponse;
 use thiserror::Error;
+use tracing::{error, info, warn};
 
-use crate::container::Container;
+use crate::admission::AdmitHandler;
+use crate::container::{expand, Container};
+use crate::device_plugin::DeviceManager;
+use crate::exec::{CommandOptions, Sender as ExecSender, Stdin};
 use crate::log::Sender;
-use crate::node::Builder;
-use crate::pod::Pod;
+use crate::node::{Builder, NodeProps};
+use crate::plugin_watcher::PluginRegistry;
 use crate::pod::Status as PodStatus;
+use crate::pod::{Changes as PodChanges, Pod};
+use crate::port_forward::PortStream;
+use crate::stats::PodStats;
 use krator::{ObjectState, State};
 
 /// A back-end for a Kubelet.
@@ -31,7 +45,7 @@ use krator::{ObjectState, State};
 /// ```rust
 /// use async_trait::async_trait;
 /// use kubelet::pod::{Pod, Status};
-/// use kubelet::provider::Provider;
+/// use kubelet::provider::{Provider, ProviderError};
 /// use kubelet::pod::state::Stub;
 /// use kubelet::pod::state::prelude::*;
 /// use std::sync::Arc;
@@ -63,11 +77,11 @@ use krator::{ObjectState, State};
 ///         Arc::new(RwLock::new(ProviderState {}))
 ///     }
 ///
-///     async fn initialize_pod_state(&self, _pod: &Pod) -> anyhow::Result<Self::PodState> {
+///     async fn initialize_pod_state(&self, _pod: &Pod) -> Result<Self::PodState, ProviderError> {
 ///         Ok(PodState)
 ///     }
 ///
-///     async fn logs(&self, namespace: String, pod: String, container: String, sender: kubelet::log::Sender) -> anyhow::Result<()> { todo!() }
+///     async fn logs(&self, namespace: String, pod: String, container: String, sender: kubelet::log::Sender) -> Result<(), ProviderError> { todo!() }
 /// }
 /// ```
 #[async_trait]
@@ -94,14 +108,51 @@ pub trait Provider: Sized + Send + Sync + 'static {
     /// Gets the provider state.
     fn provider_state(&self) -> krator::SharedState<Self::ProviderState>;
 
+    /// The architecture and operating system the node is labelled with, the taints it is created
+    /// with, its extended resources and the most pods it may run.
+    ///
+    /// The default implementation of this labels the node with [`Provider::ARCH`] as both its
+    /// architecture and operating system, without taints or extended resources.
+    fn node_props(&self) -> NodeProps {
+        NodeProps::new(Self::ARCH)
+    }
+
     /// Allows provider to populate node information.
-    async fn node(&self, _builder: &mut Builder) -> anyhow::Result<()> {
+    async fn node(&self, _builder: &mut Builder) -> Result<(), ProviderError> {
         Ok(())
     }
 
+    /// The registry of node plugins, such as CSI drivers, that the provider mounts volumes with.
+    /// The kubelet runs plugin discovery and registration for it.
+    ///
+    /// The default implementation of this returns `None`, in which case the kubelet runs a
+    /// registry of its own that providers can't use.
+    fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
+        None
+    }
+
+    /// The device manager that allocates the devices of device plugins to the provider's pods.
+    /// The kubelet serves device plugin registration for it, and allocates devices to new pods
+    /// as they are admitted.
+    ///
+    /// The default implementation of this returns `None`, in which case device plugins can't
+    /// register with the node.
+    fn device_manager(&self) -> Option<Arc<DeviceManager>> {
+        None
+    }
+
+    /// Admit handlers that new pods must also pass before they are started, such as ones that
+    /// refuse pods the provider can't run. They are run after the kubelet's own handlers, and a
+    /// pod they refuse is failed with their reason and message.
+    ///
+    /// The default implementation of this returns no handlers.
+    fn admit_handlers(&self) -> Vec<Arc<dyn AdmitHandler>> {
+        vec![]
+    }
+
     /// Hook to allow provider to introduced shared state into Pod state.
     // TODO: Is there a way to provide a default implementation of this if Self::PodState: Default?
-    async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState>;
+    async fn initialize_pod_state(&self, pod: &Pod) -> Result<Self::PodState, ProviderError>;
 
     /// Given a Pod, get back the logs for the associated workload.
     async fn logs(
@@ -110,14 +161,134 @@ pub trait Provider: Sized + Send + Sync + 'static {
         pod: String,
         container: String,
         sender: Sender,
-    ) -> anyhow::Result<()>;
+    ) -> Result<(), ProviderError>;
+
+    /// Execute a given command on a workload, feeding it data from `stdin` as the client sends
+    /// it and streaming its output back through `sender`.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn exec(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _options: CommandOptions,
+        _stdin: Stdin,
+        _sender: ExecSender,
+    ) -> Result<(), ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Attach to the running process of a workload, feeding it data from `stdin` as the client
+    /// sends it and streaming its output back through `sender`.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn attach(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _stdin: Stdin,
+        _sender: ExecSender,
+    ) -> Result<(), ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Checkpoint a running container, writing a snapshot of it to disk that it can later be
+    /// restored from, and return where the snapshot was written.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn checkpoint(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+    ) -> Result<PathBuf, ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Open a connection to the given port of a pod, which is used to forward a client's
+    /// connection to the pod.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn port_forward(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _port: u16,
+    ) -> Result<Box<dyn PortStream>, ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
 
-    /// Execute a given command on a workload and then return the result.
+    /// Get resource usage for each of the pods the provider is running. This is combined with
+    /// stats for the node to answer requests to the kubelet Summary API.
     ///
     /// The default implementation of this returns a message that this feature is
     /// not available. Override this only when there is an implementation.
-    async fn exec(&self, _pod: Pod, _command: String) -> anyhow::Result<Vec<String>> {
-        Err(NotImplementedError.into())
+    async fn stats(&self) -> Result<Vec<PodStats>, ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Get the pods the provider is currently running. This answers requests to the kubelet's
+    /// `/runningpods/` debugging endpoint, and may differ from what the API server believes is
+    /// scheduled on the node.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn running_pods(&self) -> Result<PodList, ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Ask the runtime whether a pod is still running, rather than relying on the status its
+    /// state machine last reported. The kubelet uses this to skip stopping pods that have
+    /// already exited when the node shuts down.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn pod_status(
+        &self,
+        _namespace: String,
+        _pod: String,
+    ) -> Result<PodRuntimeStatus, ProviderError> {
+        Err(ProviderError::Unsupported)
+    }
+
+    /// Stop a pod's containers because the node is shutting down. The kubelet stops waiting once
+    /// the pod's termination grace period, or what is left of the node's shutdown grace period,
+    /// has passed, and then reports the pod as terminated.
+    ///
+    /// The default implementation of this does nothing, leaving the pod's containers to stop
+    /// when the kubelet exits.
+    async fn shutdown_pod(&self, _pod: &Pod) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    /// React to a running pod being updated, such as to its labels or annotations, or to the
+    /// image of one of its containers. This is called with the pod before and after the update,
+    /// along with what changed between them, for updates that change more than its status.
+    /// Providers whose pod states watch the pod's manifest can also react to updates there.
+    ///
+    /// The default implementation of this does nothing.
+    async fn update_pod(
+        &self,
+        _old: &Pod,
+        _new: &Pod,
+        _changes: &PodChanges,
+    ) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    /// Remove what the provider kept for a pod that was deleted while the kubelet wasn't
+    /// running, such as its logs, which would otherwise never be removed. This is called when
+    /// the kubelet starts, for pods it ran before it restarted.
+    ///
+    /// The default implementation of this does nothing.
+    async fn remove_orphaned_pod(&self, _pod: &Pod) -> Result<(), ProviderError> {
+        Ok(())
     }
 
     /// Resolve the environment variables for a container.
@@ -131,30 +302,8 @@ pub trait Provider: Sized + Send + Sync + 'static {
         container: &Container,
         pod: &Pod,
         client: &kube::Client,
-    ) -> HashMap<String, String> {
-        let mut env = HashMap::new();
-        let vars = match container.env().as_ref() {
-            Some(e) => e,
-            None => return env,
-        };
-
-        for env_var in vars.clone().into_iter() {
-            let key = env_var.name;
-            let value = match env_var.value {
-                Some(v) => v,
-                None => {
-                    on_missing_env_value(
-                        env_var.value_from,
-                        client,
-                        pod.namespace(),
-                        &field_map(pod),
-                    )
-                    .await
-                }
-            };
-            env.insert(key, value);
-        }
-        env
+    ) -> anyhow::Result<HashMap<String, String>> {
+        env_vars(container, pod, client).await
     }
 }
 
@@ -164,30 +313,114 @@ pub trait Provider: Sized + Send + Sync + 'static {
 /// environment variable resolution in a special way, such as allowing
 /// custom Downward API fields.
 ///
+/// Variables from the container's `envFrom` sources are added first, so that its `env` entries
+/// override them. This fails if a ConfigMap or Secret that isn't optional can't be fetched.
+/// `$(VAR_NAME)` references in `env` values are expanded using the variables defined before
+/// them.
+///
 /// It is safe to call from within your own providers.
 pub async fn env_vars(
     container: &Container,
     pod: &Pod,
     client: &kube::Client,
-) -> HashMap<String, String> {
-    let mut env = HashMap::new();
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut env = env_from_vars(container, pod.namespace(), client).await?;
     let vars = match container.env().as_ref() {
         Some(e) => e,
-        None => return env,
+        None => return Ok(env),
     };
 
     for env_var in vars.clone().into_iter() {
         let key = env_var.name;
         let value = match env_var.value {
-            Some(v) => v,
+            Some(v) => expand(&v, &env),
             None => {
-                on_missing_env_value(env_var.value_from, client, pod.namespace(), &field_map(pod))
+                on_missing_env_value(env_var.value_from, container, pod, client, &field_map(pod))
                     .await
             }
         };
         env.insert(key, value);
     }
-    env
+    Ok(env)
+}
+
+/// Resolve the variables a container takes from whole ConfigMaps and Secrets through `envFrom`.
+/// Each key of the source becomes a variable, with the source's prefix in front of it. Keys that
+/// aren't valid variable names are skipped, as the Kubernetes kubelet does.
+async fn env_from_vars(
+    container: &Container,
+    ns: &str,
+    client: &kube::Client,
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+    let sources = match container.env_from().as_ref() {
+        Some(sources) => sources,
+        None => return Ok(env),
+    };
+
+    for source in sources {
+        let data: BTreeMap<String, String> = if let Some(cfref) = source.config_map_ref.as_ref() {
+            let name = cfref.name.as_deref().unwrap_or_default();
+            match Api::<ConfigMap>::namespaced(client.clone(), ns)
+                .get(name)
+                .await
+            {
+                Ok(cfgmap) => cfgmap.data.unwrap_or_default(),
+                Err(kube::Error::Api(ErrorResponse { code: 404, .. }))
+                    if cfref.optional.unwrap_or(false) =>
+                {
+                    continue
+                }
+                Err(e) => anyhow::bail!("unable to get config map {}: {}", name, e),
+            }
+        } else if let Some(secref) = source.secret_ref.as_ref() {
+            let name = secref.name.as_deref().unwrap_or_default();
+            match Api::<Secret>::namespaced(client.clone(), ns)
+                .get(name)
+                .await
+            {
+                Ok(secret) => secret
+                    .data
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(k, v)| (k, String::from_utf8_lossy(&v.0).into_owned()))
+                    .collect(),
+                Err(kube::Error::Api(ErrorResponse { code: 404, .. }))
+                    if secref.optional.unwrap_or(false) =>
+                {
+                    continue
+                }
+                Err(e) => anyhow::bail!("unable to get secret {}: {}", name, e),
+            }
+        } else {
+            continue;
+        };
+
+        let prefix = source.prefix.as_deref().unwrap_or_default();
+        for (key, value) in data {
+            let key = format!("{}{}", prefix, key);
+            if is_env_var_name(&key) {
+                env.insert(key, value);
+            } else {
+                warn!(
+                    "Skipping {} from envFrom of container {}, as it is not a valid environment variable name",
+                    key,
+                    container.name()
+                );
+            }
+        }
+    }
+    Ok(env)
+}
+
+/// Check whether a name is a valid environment variable name, which Kubernetes defines as
+/// letters, digits, `_`, `-` and `.`, not starting with a digit.
+fn is_env_var_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
 }
 
 /// Called when an env var does not have a value associated with.
@@ -196,10 +429,12 @@ pub async fn env_vars(
 #[doc(hidden)]
 async fn on_missing_env_value(
     env_var_source: Option<EnvVarSource>,
+    container: &Container,
+    pod: &Pod,
     client: &kube::Client,
-    ns: &str,
     fields: &HashMap<String, String>,
 ) -> String {
+    let ns = pod.namespace();
     let env_src = match env_var_source {
         Some(env_src) => env_src,
         None => return String::new(),
@@ -257,11 +492,77 @@ async fn on_missing_env_value(
     if let Some(cfkey) = env_src.field_ref.as_ref() {
         return fields.get(&cfkey.field_path).cloned().unwrap_or_default();
     }
-    // Reource Fields (Not implementable just yet... need more of a model.)
+    // Downward API (Resource Field Refs)
+    if let Some(selector) = env_src.resource_field_ref.as_ref() {
+        return match resource_field_value(selector, container, pod, client).await {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Error resolving resource {}: {:?}", selector.resource, e);
+                String::new()
+            }
+        };
+    }
 
     String::new()
 }
 
+/// Get the value of one of the container's resource limits or requests, such as `limits.memory`,
+/// in units of the selector's divisor.
+///
+/// As in Kubernetes, a limit that isn't set is the amount of that resource allocatable on the
+/// node, and a request that isn't set is 0.
+async fn resource_field_value(
+    selector: &ResourceFieldSelector,
+    container: &Container,
+    pod: &Pod,
+    client: &kube::Client,
+) -> anyhow::Result<String> {
+    let resources = container.resources().cloned().unwrap_or_default();
+    let (quantity, resource) = if let Some(resource) = selector.resource.strip_prefix("limits.") {
+        let limit = resources
+            .limits
+            .and_then(|mut limits| limits.remove(resource));
+        match limit {
+            Some(limit) => (limit, resource),
+            None => (node_allocatable(resource, pod, client).await?, resource),
+        }
+    } else if let Some(resource) = selector.resource.strip_prefix("requests.") {
+        let request = resources
+            .requests
+            .and_then(|mut requests| requests.remove(resource));
+        (
+            request.unwrap_or_else(|| Quantity("0".to_owned())),
+            resource,
+        )
+    } else {
+        anyhow::bail!("unsupported resource {}", selector.resource)
+    };
+    if !["cpu", "memory", "ephemeral-storage"].contains(&resource) {
+        anyhow::bail!("unsupported resource {}", selector.resource);
+    }
+    let divisor = selector
+        .divisor
+        .clone()
+        .unwrap_or_else(|| Quantity("1".to_owned()));
+    Ok(crate::resources::in_units_of(&quantity, &divisor)?.to_string())
+}
+
+/// Get the amount of a resource that is allocatable on the pod's node.
+async fn node_allocatable(
+    resource: &str,
+    pod: &Pod,
+    client: &kube::Client,
+) -> anyhow::Result<Quantity> {
+    let node_name = pod
+        .node_name()
+        .ok_or_else(|| anyhow::anyhow!("pod {} is not scheduled to a node", pod.name()))?;
+    let node = Api::<Node>::all(client.clone()).get(node_name).await?;
+    node.status
+        .and_then(|status| status.allocatable)
+        .and_then(|mut allocatable| allocatable.remove(resource))
+        .ok_or_else(|| anyhow::anyhow!("node {} has no allocatable {}", node_name, resource))
+}
+
 /// Build the map of allowable field_ref values.
 ///
 /// The Downward API only supports a small selection of fields. This
@@ -270,6 +571,14 @@ fn field_map(pod: &Pod) -> HashMap<String, String> {
     let mut map: HashMap<String, String> = HashMap::new();
     map.insert("metadata.name".into(), pod.name().to_owned());
     map.insert("metadata.namespace".into(), pod.namespace().to_owned());
+    map.insert(
+        "metadata.uid".into(),
+        pod.as_kube_pod().metadata.uid.clone().unwrap_or_default(),
+    );
+    map.insert(
+        "spec.nodeName".into(),
+        pod.node_name().unwrap_or_default().to_owned(),
+    );
     map.insert(
         "spec.serviceAccountName".into(),
         pod.service_account_name().unwrap_or_default().to_owned(),
@@ -282,17 +591,48 @@ fn field_map(pod: &Pod) -> HashMap<String, String> {
         "status.podIP".into(),
         pod.pod_ip().unwrap_or_default().to_owned(),
     );
+    let pod_ips = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|status| status.pod_ips.as_ref())
+        .map(|ips| {
+            ips.iter()
+                .filter_map(|ip| ip.ip.clone())
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+    map.insert(
+        "status.podIPs".into(),
+        pod_ips.unwrap_or_else(|| pod.pod_ip().unwrap_or_default().to_owned()),
+    );
+    // Labels and annotations can be selected as `metadata.labels['key']`, as in Kubernetes, or
+    // as `metadata.labels.key`
     pod.labels().iter().for_each(|(k, v)| {
         info!("adding {} to labels", k);
         map.insert(format!("metadata.labels.{}", k), v.clone());
+        map.insert(format!("metadata.labels['{}']", k), v.clone());
     });
     pod.annotations().iter().for_each(|(k, v)| {
         map.insert(format!("metadata.annotations.{}", k), v.clone());
+        map.insert(format!("metadata.annotations['{}']", k), v.clone());
     });
     map
 }
 
-/// A Provider error
+/// What the runtime running a pod says about it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PodRuntimeStatus {
+    /// Some of the pod's containers are still running
+    Running,
+    /// All of the pod's containers have exited
+    Exited,
+    /// The runtime can't tell, such as for a pod it has no record of
+    Unknown,
+}
+
+/// An error of a provider operation. Its kind tells the kubelet how to report it, such as which
+/// HTTP status to answer a request with, or which reason to give for a pod that failed with it.
 #[derive(Debug, Error)]
 pub enum ProviderError {
     /// Pod was not found
@@ -309,9 +649,116 @@ pub enum ProviderError {
         /// The container's name
         container_name: String,
     },
+    /// Container has not been restarted, so there are no logs from a previous run of it
+    #[error(
+        "previous terminated container {} in pod {} not found",
+        container_name,
+        pod_name
+    )]
+    PreviousContainerNotFound {
+        /// The container's pod's name
+        pod_name: String,
+        /// The container's name
+        container_name: String,
+    },
+    /// The images of a pod could not be pulled
+    #[error("unable to pull images: {0:#}")]
+    ImagePull(anyhow::Error),
+    /// The volumes of a pod could not be set up
+    #[error("unable to mount volumes: {0:#}")]
+    VolumeSetup(anyhow::Error),
+    /// A container could not be started by the runtime
+    #[error("unable to start container: {0:#}")]
+    RuntimeStart(anyhow::Error),
+    /// The request can't be carried out as it was made, such as a command for a container that
+    /// isn't running
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    /// The provider doesn't support the operation
+    #[error("operation not supported")]
+    Unsupported,
+    /// Any other error
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl ProviderError {
+    /// Whether the error is that the pod or container asked for doesn't exist.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            ProviderError::PodNotFound { .. }
+                | ProviderError::ContainerNotFound { .. }
+                | ProviderError::PreviousContainerNotFound { .. }
+        )
+    }
+
+    /// The reason to give in the status of a pod that failed with this error, which matches the
+    /// reason the Kubernetes kubelet gives for it where there is one.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ProviderError::ImagePull(_) => "ErrImagePull",
+            ProviderError::VolumeSetup(_) => "FailedMount",
+            ProviderError::RuntimeStart(_) => "RunContainerError",
+            _ => "Error",
+        }
+    }
+}
+
+/// Errors that were wrapped into an [`anyhow::Error`] on their way up keep their kind, so that
+/// implementations can use `?` on results of either type.
+impl From<anyhow::Error> for ProviderError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<ProviderError>() {
+            Ok(error) => error,
+            Err(error) => ProviderError::Other(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for ProviderError {
+    fn from(error: std::io::Error) -> Self {
+        ProviderError::Other(error.into())
+    }
+}
+
+impl From<kube::Error> for ProviderError {
+    fn from(error: kube::Error) -> Self {
+        ProviderError::Other(error.into())
+    }
 }
 
-/// A specific operation is not implemented
-#[derive(Error, Debug)]
-#[error("Operation not supported")]
-pub struct NotImplementedError;
+impl From<tokio::task::JoinError> for ProviderError {
+    fn from(error: tokio::task::JoinError) -> Self {
+        ProviderError::Other(error.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn provider_errors_keep_their_kind_through_anyhow() {
+        let error = anyhow::Error::from(ProviderError::PodNotFound {
+            pod_name: "hello".to_owned(),
+        });
+        assert!(ProviderError::from(error).is_not_found());
+
+        let error = ProviderError::from(anyhow::anyhow!("disk full"));
+        assert!(matches!(error, ProviderError::Other(_)));
+        assert_eq!(error.to_string(), "disk full");
+        assert_eq!(error.reason(), "Error");
+    }
+
+    #[test]
+    fn env_var_names_are_validated() {
+        assert!(is_env_var_name("DATABASE_URL"));
+        assert!(is_env_var_name("app.config-file"));
+        assert!(is_env_var_name("_private"));
+        assert!(!is_env_var_name(""));
+        assert!(!is_env_var_name("1PASSWORD"));
+        assert!(!is_env_var_name("has space"));
+        assert!(!is_env_var_name("key=value"));
+    }
+}
@@ -0,0 +1,910 @@
+//! Hosting several providers in one kubelet, and running each pod with the one for its runtime
+//! class.
+//!
+//! A [`ProviderRegistry`] is itself a [`Provider`], so it is given to the [`Kubelet`] in place of
+//! a single provider. Each provider is registered under a runtime class name, and runs the pods
+//! whose `spec.runtimeClassName` names it. Pods that don't name a runtime class run with the
+//! default provider, if there is one. Pods naming a runtime class that no provider is registered
+//! under are refused when they are admitted.
+//!
+//! Every provider adds its own labels, taints and extended resources to the node, so pods have to
+//! tolerate the taints of all of them, such as through the `scheduling` section of their
+//! RuntimeClass.
+//!
+//! [`Kubelet`]: crate::Kubelet
+use std::any::Any;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::PodList;
+use krator::{Manifest, ObjectState, SharedState, State, Transition};
+use tokio::sync::RwLock;
+
+use super::{PodRuntimeStatus, Provider, ProviderError};
+use crate::admission::{AdmitHandler, PodAdmitAttributes, Rejection};
+use crate::device_plugin::DeviceManager;
+use crate::exec::{CommandOptions, Sender as ExecSender, Stdin};
+use crate::log::Sender;
+use crate::node::{Builder, NodeProps};
+use crate::plugin_watcher::PluginRegistry;
+use crate::pod::{Pod, PodKey, Status as PodStatus};
+use crate::port_forward::PortStream;
+use crate::stats::PodStats;
+
+/// The reason given to pods naming a runtime class that no provider is registered under.
+const UNSUPPORTED_RUNTIME_CLASS: &str = "UnsupportedRuntimeClass";
+
+/// A pod's state, belonging to one of the registry's providers.
+type ErasedPodState = Box<dyn Any + Send + Sync>;
+
+/// Several providers, each running the pods of a runtime class.
+pub struct ProviderRegistry {
+    providers: HashMap<String, Arc<dyn RegisteredProvider>>,
+    default: Option<String>,
+    shared: SharedState<RegistryState>,
+}
+
+impl Default for ProviderRegistry {
+    fn default() -> Self {
+        ProviderRegistry {
+            providers: HashMap::new(),
+            default: None,
+            shared: Arc::new(RwLock::new(RegistryState::default())),
+        }
+    }
+}
+
+impl ProviderRegistry {
+    /// Returns a registry without any providers.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers a provider to run the pods of the given runtime class, replacing any provider
+    /// already registered under it.
+    pub fn with_provider<P: Provider>(mut self, runtime_class: &str, provider: P) -> Self {
+        let shared = provider.provider_state();
+        self.providers.insert(
+            runtime_class.to_owned(),
+            Arc::new(Registered {
+                provider: Arc::new(provider),
+                shared,
+            }),
+        );
+        self
+    }
+
+    /// Runs pods that don't name a runtime class with the provider registered under the given
+    /// one.
+    pub fn with_default(mut self, runtime_class: &str) -> Self {
+        self.default = Some(runtime_class.to_owned());
+        self
+    }
+
+    /// The runtime class whose provider runs the pod, if a provider is registered under it.
+    fn runtime_class(&self, pod: &Pod) -> Option<&str> {
+        route(pod, self.default.as_deref()).filter(|name| self.providers.contains_key(*name))
+    }
+
+    /// The provider running the given pod.
+    async fn provider_of(
+        &self,
+        namespace: &str,
+        pod: &str,
+    ) -> Result<&Arc<dyn RegisteredProvider>, ProviderError> {
+        let key = PodKey::new(namespace, pod);
+        let shared = self.shared.read().await;
+        shared
+            .pods
+            .get(&key)
+            .and_then(|runtime_class| self.providers.get(runtime_class))
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod.to_owned(),
+            })
+    }
+
+    /// The first of the providers, in order of runtime class, to have the given thing.
+    fn first<T>(&self, get: impl Fn(&dyn RegisteredProvider) -> Option<T>) -> Option<T> {
+        let mut runtime_classes: Vec<&String> = self.providers.keys().collect();
+        runtime_classes.sort();
+        runtime_classes
+            .into_iter()
+            .find_map(|runtime_class| get(self.providers[runtime_class].as_ref()))
+    }
+}
+
+/// The runtime class that the pod names, or the default one.
+fn route<'a>(pod: &'a Pod, default: Option<&'a str>) -> Option<&'a str> {
+    pod.runtime_class_name().or(default)
+}
+
+/// State shared between all of the registry's pods.
+#[derive(Default)]
+pub struct RegistryState {
+    /// The runtime class whose provider runs each pod.
+    pods: HashMap<PodKey, String>,
+}
+
+/// The state of a pod run by one of the registry's providers.
+pub struct RoutedPodState {
+    key: PodKey,
+    provider: Arc<dyn RegisteredProvider>,
+    inner: ErasedPodState,
+}
+
+#[async_trait]
+impl ObjectState for RoutedPodState {
+    type Manifest = Pod;
+    type Status = PodStatus;
+    type SharedState = RegistryState;
+
+    async fn async_drop(self, shared: &mut RegistryState) {
+        shared.pods.remove(&self.key);
+        self.provider.drop_pod_state(self.inner).await;
+    }
+}
+
+/// Starts a pod in the initial state of the provider for its runtime class.
+#[derive(Debug, Default)]
+pub struct InitialRoute;
+
+#[async_trait]
+impl State<RoutedPodState> for InitialRoute {
+    async fn next(
+        self: Box<Self>,
+        _shared: SharedState<RegistryState>,
+        state: &mut RoutedPodState,
+        _manifest: Manifest<Pod>,
+    ) -> Transition<RoutedPodState> {
+        let next = RoutedState(state.provider.initial_state());
+        Transition::next_unchecked(self, next)
+    }
+
+    async fn status(&self, state: &mut RoutedPodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        let provider = state.provider.clone();
+        provider.initial_state().status(state, pod).await
+    }
+}
+
+/// Stops a pod that was deleted early in the terminated state of the provider for its runtime
+/// class.
+#[derive(Debug, Default)]
+pub struct TerminatedRoute;
+
+#[async_trait]
+impl State<RoutedPodState> for TerminatedRoute {
+    async fn next(
+        self: Box<Self>,
+        _shared: SharedState<RegistryState>,
+        state: &mut RoutedPodState,
+        _manifest: Manifest<Pod>,
+    ) -> Transition<RoutedPodState> {
+        let next = RoutedState(state.provider.terminated_state());
+        Transition::next_unchecked(self, next)
+    }
+
+    async fn status(&self, state: &mut RoutedPodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        let provider = state.provider.clone();
+        provider.terminated_state().status(state, pod).await
+    }
+}
+
+/// A state of the provider running a pod.
+#[derive(Debug)]
+pub struct RoutedState(Box<dyn ErasedState>);
+
+#[async_trait]
+impl State<RoutedPodState> for RoutedState {
+    async fn next(
+        self: Box<Self>,
+        _shared: SharedState<RegistryState>,
+        state: &mut RoutedPodState,
+        manifest: Manifest<Pod>,
+    ) -> Transition<RoutedPodState> {
+        let mut current = self;
+        match current.0.next(state, manifest).await {
+            Ok(Some(next)) => Transition::next_unchecked(current, RoutedState(next)),
+            Ok(None) => Transition::Complete(Ok(())),
+            Err(e) => Transition::Complete(Err(e)),
+        }
+    }
+
+    async fn status(&self, state: &mut RoutedPodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        self.0.status(state, pod).await
+    }
+}
+
+/// A state of one of the registry's providers, with the provider's types hidden.
+#[async_trait]
+trait ErasedState: Send + Sync + std::fmt::Debug {
+    /// Runs the state, returning the state to go to next, or `None` if the pod's state machine
+    /// has completed.
+    async fn next(
+        &mut self,
+        state: &mut RoutedPodState,
+        manifest: Manifest<Pod>,
+    ) -> anyhow::Result<Option<Box<dyn ErasedState>>>;
+
+    async fn status(&self, state: &mut RoutedPodState, pod: &Pod) -> anyhow::Result<PodStatus>;
+}
+
+/// A state of a provider, along with the provider's state.
+struct Erased<P: Provider> {
+    /// The state, which is taken when it is run.
+    state: Option<Box<dyn State<P::PodState>>>,
+    shared: SharedState<P::ProviderState>,
+}
+
+impl<P: Provider> std::fmt::Debug for Erased<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.state {
+            Some(state) => state.fmt(formatter),
+            None => formatter.write_str("Completed"),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: Provider> ErasedState for Erased<P> {
+    async fn next(
+        &mut self,
+        state: &mut RoutedPodState,
+        manifest: Manifest<Pod>,
+    ) -> anyhow::Result<Option<Box<dyn ErasedState>>> {
+        let pod_state = pod_state::<P>(state)?;
+        let current = self
+            .state
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("State has already been run"))?;
+        match current.next(self.shared.clone(), pod_state, manifest).await {
+            Transition::Next(next) => Ok(Some(Box::new(Erased::<P> {
+                state: Some(next.into()),
+                shared: self.shared.clone(),
+            }))),
+            Transition::Complete(result) => result.map(|()| None),
+        }
+    }
+
+    async fn status(&self, state: &mut RoutedPodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        let pod_state = pod_state::<P>(state)?;
+        match &self.state {
+            Some(current) => current.status(pod_state, pod).await,
+            None => Err(anyhow::anyhow!("State has already been run")),
+        }
+    }
+}
+
+/// The provider's own state for a pod.
+fn pod_state<P: Provider>(state: &mut RoutedPodState) -> anyhow::Result<&mut P::PodState> {
+    state
+        .inner
+        .downcast_mut::<P::PodState>()
+        .ok_or_else(|| anyhow::anyhow!("Pod state belongs to another provider"))
+}
+
+/// A provider in the registry, with its types hidden so that providers of different types can be
+/// kept together.
+#[async_trait]
+trait RegisteredProvider: Send + Sync {
+    fn node_props(&self) -> NodeProps;
+    async fn node(&self, builder: &mut Builder) -> Result<(), ProviderError>;
+    fn plugin_registry(&self) -> Option<Arc<PluginRegistry>>;
+    fn device_manager(&self) -> Option<Arc<DeviceManager>>;
+    fn admit_handlers(&self) -> Vec<Arc<dyn AdmitHandler>>;
+    async fn initialize_pod_state(&self, pod: &Pod) -> Result<ErasedPodState, ProviderError>;
+    fn initial_state(&self) -> Box<dyn ErasedState>;
+    fn terminated_state(&self) -> Box<dyn ErasedState>;
+    async fn drop_pod_state(&self, state: ErasedPodState);
+    async fn logs(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        sender: Sender,
+    ) -> Result<(), ProviderError>;
+    async fn exec(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        options: CommandOptions,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<(), ProviderError>;
+    async fn attach(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<(), ProviderError>;
+    async fn checkpoint(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+    ) -> Result<PathBuf, ProviderError>;
+    async fn port_forward(
+        &self,
+        namespace: String,
+        pod: String,
+        port: u16,
+    ) -> Result<Box<dyn PortStream>, ProviderError>;
+    async fn stats(&self) -> Result<Vec<PodStats>, ProviderError>;
+    async fn running_pods(&self) -> Result<PodList, ProviderError>;
+    async fn pod_status(
+        &self,
+        namespace: String,
+        pod: String,
+    ) -> Result<PodRuntimeStatus, ProviderError>;
+    async fn shutdown_pod(&self, pod: &Pod) -> Result<(), ProviderError>;
+    async fn remove_orphaned_pod(&self, pod: &Pod) -> Result<(), ProviderError>;
+}
+
+struct Registered<P: Provider> {
+    provider: Arc<P>,
+    /// The provider's state, which is shared by all the pods it runs.
+    shared: SharedState<P::ProviderState>,
+}
+
+#[async_trait]
+impl<P: Provider> RegisteredProvider for Registered<P> {
+    fn node_props(&self) -> NodeProps {
+        self.provider.node_props()
+    }
+
+    async fn node(&self, builder: &mut Builder) -> Result<(), ProviderError> {
+        self.provider.node(builder).await
+    }
+
+    fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
+        self.provider.plugin_registry()
+    }
+
+    fn device_manager(&self) -> Option<Arc<DeviceManager>> {
+        self.provider.device_manager()
+    }
+
+    fn admit_handlers(&self) -> Vec<Arc<dyn AdmitHandler>> {
+        self.provider.admit_handlers()
+    }
+
+    async fn initialize_pod_state(&self, pod: &Pod) -> Result<ErasedPodState, ProviderError> {
+        let state = self.provider.initialize_pod_state(pod).await?;
+        Ok(Box::new(state))
+    }
+
+    fn initial_state(&self) -> Box<dyn ErasedState> {
+        Box::new(Erased::<P> {
+            state: Some(Box::new(P::InitialState::default())),
+            shared: self.shared.clone(),
+        })
+    }
+
+    fn terminated_state(&self) -> Box<dyn ErasedState> {
+        Box::new(Erased::<P> {
+            state: Some(Box::new(P::TerminatedState::default())),
+            shared: self.shared.clone(),
+        })
+    }
+
+    async fn drop_pod_state(&self, state: ErasedPodState) {
+        if let Ok(state) = state.downcast::<P::PodState>() {
+            let mut shared = self.shared.write().await;
+            state.async_drop(&mut shared).await;
+        }
+    }
+
+    async fn logs(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        sender: Sender,
+    ) -> Result<(), ProviderError> {
+        self.provider.logs(namespace, pod, container, sender).await
+    }
+
+    async fn exec(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        options: CommandOptions,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<(), ProviderError> {
+        self.provider
+            .exec(namespace, pod, container, options, stdin, sender)
+            .await
+    }
+
+    async fn attach(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<(), ProviderError> {
+        self.provider
+            .attach(namespace, pod, container, stdin, sender)
+            .await
+    }
+
+    async fn checkpoint(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+    ) -> Result<PathBuf, ProviderError> {
+        self.provider.checkpoint(namespace, pod, container).await
+    }
+
+    async fn port_forward(
+        &self,
+        namespace: String,
+        pod: String,
+        port: u16,
+    ) -> Result<Box<dyn PortStream>, ProviderError> {
+        self.provider.port_forward(namespace, pod, port).await
+    }
+
+    async fn stats(&self) -> Result<Vec<PodStats>, ProviderError> {
+        self.provider.stats().await
+    }
+
+    async fn running_pods(&self) -> Result<PodList, ProviderError> {
+        self.provider.running_pods().await
+    }
+
+    async fn pod_status(
+        &self,
+        namespace: String,
+        pod: String,
+    ) -> Result<PodRuntimeStatus, ProviderError> {
+        self.provider.pod_status(namespace, pod).await
+    }
+
+    async fn shutdown_pod(&self, pod: &Pod) -> Result<(), ProviderError> {
+        self.provider.shutdown_pod(pod).await
+    }
+
+    async fn remove_orphaned_pod(&self, pod: &Pod) -> Result<(), ProviderError> {
+        self.provider.remove_orphaned_pod(pod).await
+    }
+}
+
+/// Refuses pods naming a runtime class that no provider is registered under.
+struct RuntimeClassAdmitHandler {
+    runtime_classes: Vec<String>,
+    default: Option<String>,
+}
+
+#[async_trait]
+impl AdmitHandler for RuntimeClassAdmitHandler {
+    async fn admit(&self, attributes: &PodAdmitAttributes<'_>) -> Result<(), Rejection> {
+        match route(attributes.pod, self.default.as_deref()) {
+            Some(name) if self.runtime_classes.iter().any(|known| known == name) => Ok(()),
+            Some(name) => Err(Rejection::new(
+                UNSUPPORTED_RUNTIME_CLASS,
+                format!("Node doesn't run pods of runtime class {}", name),
+            )),
+            None => Err(Rejection::new(
+                UNSUPPORTED_RUNTIME_CLASS,
+                "Node only runs pods that name a runtime class",
+            )),
+        }
+    }
+}
+
+/// Runs a provider's admit handler for the pods of its runtime class only.
+struct RoutedAdmitHandler {
+    runtime_class: String,
+    default: Option<String>,
+    handler: Arc<dyn AdmitHandler>,
+}
+
+#[async_trait]
+impl AdmitHandler for RoutedAdmitHandler {
+    async fn admit(&self, attributes: &PodAdmitAttributes<'_>) -> Result<(), Rejection> {
+        if route(attributes.pod, self.default.as_deref()) == Some(self.runtime_class.as_str()) {
+            self.handler.admit(attributes).await
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for ProviderRegistry {
+    type ProviderState = RegistryState;
+    type PodState = RoutedPodState;
+    type InitialState = InitialRoute;
+    type TerminatedState = TerminatedRoute;
+
+    const ARCH: &'static str = "wasm32";
+
+    fn provider_state(&self) -> SharedState<RegistryState> {
+        self.shared.clone()
+    }
+
+    /// The architecture and operating system of the default provider, if there is one, with the
+    /// taints and extended resources of every provider, in order of runtime class. The node runs
+    /// no more pods than the provider that can run the fewest.
+    fn node_props(&self) -> NodeProps {
+        let mut props = match self.default.as_ref().and_then(|d| self.providers.get(d)) {
+            Some(default) => default.node_props(),
+            None => NodeProps::new(Self::ARCH),
+        };
+        props.taints.clear();
+        props.extended_resources.clear();
+        let mut runtime_classes: Vec<&String> = self.providers.keys().collect();
+        runtime_classes.sort();
+        for runtime_class in runtime_classes {
+            let provider_props = self.providers[runtime_class].node_props();
+            for taint in provider_props.taints {
+                if !props.taints.contains(&taint) {
+                    props.taints.push(taint);
+                }
+            }
+            for (name, quantity) in provider_props.extended_resources {
+                props.extended_resources.entry(name).or_insert(quantity);
+            }
+            props.max_pods = match (props.max_pods, provider_props.max_pods) {
+                (Some(max_pods), Some(other)) => Some(std::cmp::min(max_pods, other)),
+                (max_pods, other) => max_pods.or(other),
+            };
+        }
+        props
+    }
+
+    /// Adds the labels of every provider to the node, in order of runtime class.
+    async fn node(&self, builder: &mut Builder) -> Result<(), ProviderError> {
+        let mut runtime_classes: Vec<&String> = self.providers.keys().collect();
+        runtime_classes.sort();
+        for runtime_class in runtime_classes {
+            self.providers[runtime_class].node(builder).await?;
+        }
+        Ok(())
+    }
+
+    /// The plugin registry of the first provider, in order of runtime class, to have one. The
+    /// kubelet runs a single registry, so providers that need one have to share it.
+    fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
+        self.first(|provider| provider.plugin_registry())
+    }
+
+    /// The device manager of the first provider, in order of runtime class, to have one. The
+    /// kubelet serves device plugin registration for a single device manager, so providers that
+    /// allocate devices have to share it.
+    fn device_manager(&self) -> Option<Arc<DeviceManager>> {
+        self.first(|provider| provider.device_manager())
+    }
+
+    fn admit_handlers(&self) -> Vec<Arc<dyn AdmitHandler>> {
+        let mut handlers: Vec<Arc<dyn AdmitHandler>> = vec![Arc::new(RuntimeClassAdmitHandler {
+            runtime_classes: self.providers.keys().cloned().collect(),
+            default: self.default.clone(),
+        })];
+        for (runtime_class, provider) in &self.providers {
+            for handler in provider.admit_handlers() {
+                handlers.push(Arc::new(RoutedAdmitHandler {
+                    runtime_class: runtime_class.clone(),
+                    default: self.default.clone(),
+                    handler,
+                }));
+            }
+        }
+        handlers
+    }
+
+    async fn initialize_pod_state(&self, pod: &Pod) -> Result<RoutedPodState, ProviderError> {
+        let runtime_class = self.runtime_class(pod).ok_or_else(|| {
+            anyhow::anyhow!(
+                "No provider runs pods of runtime class {}",
+                route(pod, self.default.as_deref()).unwrap_or("(none)")
+            )
+        })?;
+        let provider = self.providers[runtime_class].clone();
+        let inner = provider.initialize_pod_state(pod).await?;
+        let key = PodKey::from(pod);
+        self.shared
+            .write()
+            .await
+            .pods
+            .insert(key.clone(), runtime_class.to_owned());
+        Ok(RoutedPodState {
+            key,
+            provider,
+            inner,
+        })
+    }
+
+    async fn logs(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        sender: Sender,
+    ) -> Result<(), ProviderError> {
+        self.provider_of(&namespace, &pod)
+            .await?
+            .logs(namespace, pod, container, sender)
+            .await
+    }
+
+    async fn exec(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        options: CommandOptions,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<(), ProviderError> {
+        self.provider_of(&namespace, &pod)
+            .await?
+            .exec(namespace, pod, container, options, stdin, sender)
+            .await
+    }
+
+    async fn attach(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        stdin: Stdin,
+        sender: ExecSender,
+    ) -> Result<(), ProviderError> {
+        self.provider_of(&namespace, &pod)
+            .await?
+            .attach(namespace, pod, container, stdin, sender)
+            .await
+    }
+
+    async fn checkpoint(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+    ) -> Result<PathBuf, ProviderError> {
+        self.provider_of(&namespace, &pod)
+            .await?
+            .checkpoint(namespace, pod, container)
+            .await
+    }
+
+    async fn port_forward(
+        &self,
+        namespace: String,
+        pod: String,
+        port: u16,
+    ) -> Result<Box<dyn PortStream>, ProviderError> {
+        self.provider_of(&namespace, &pod)
+            .await?
+            .port_forward(namespace, pod, port)
+            .await
+    }
+
+    /// The stats of the pods of every provider that has them.
+    async fn stats(&self) -> Result<Vec<PodStats>, ProviderError> {
+        let mut stats = None;
+        for provider in self.providers.values() {
+            if let Ok(pods) = provider.stats().await {
+                stats.get_or_insert_with(Vec::new).extend(pods);
+            }
+        }
+        stats.ok_or(ProviderError::Unsupported)
+    }
+
+    /// The running pods of every provider that lists them.
+    async fn running_pods(&self) -> Result<PodList, ProviderError> {
+        let mut running: Option<PodList> = None;
+        for provider in self.providers.values() {
+            if let Ok(pods) = provider.running_pods().await {
+                match &mut running {
+                    Some(running) => running.items.extend(pods.items),
+                    None => running = Some(pods),
+                }
+            }
+        }
+        running.ok_or(ProviderError::Unsupported)
+    }
+
+    /// The status of the pod from the provider running it. Pods that no provider runs, such as
+    /// after the kubelet restarted, have an unknown status.
+    async fn pod_status(
+        &self,
+        namespace: String,
+        pod: String,
+    ) -> Result<PodRuntimeStatus, ProviderError> {
+        match self.provider_of(&namespace, &pod).await {
+            Ok(provider) => provider.pod_status(namespace, pod).await,
+            Err(_) => Ok(PodRuntimeStatus::Unknown),
+        }
+    }
+
+    async fn shutdown_pod(&self, pod: &Pod) -> Result<(), ProviderError> {
+        match self.runtime_class(pod) {
+            Some(runtime_class) => self.providers[runtime_class].shutdown_pod(pod).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn remove_orphaned_pod(&self, pod: &Pod) -> Result<(), ProviderError> {
+        match self.runtime_class(pod) {
+            Some(runtime_class) => self.providers[runtime_class].remove_orphaned_pod(pod).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+
+    struct MockProvider(&'static str);
+
+    struct MockPodState(&'static str);
+
+    #[async_trait]
+    impl ObjectState for MockPodState {
+        type Manifest = Pod;
+        type Status = PodStatus;
+        type SharedState = ();
+        async fn async_drop(self, _provider_state: &mut ()) {}
+    }
+
+    #[async_trait]
+    impl Provider for MockProvider {
+        type ProviderState = ();
+        type InitialState = crate::pod::state::Stub;
+        type TerminatedState = crate::pod::state::Stub;
+        type PodState = MockPodState;
+
+        const ARCH: &'static str = "mock";
+
+        fn provider_state(&self) -> SharedState<()> {
+            Arc::new(RwLock::new(()))
+        }
+
+        fn node_props(&self) -> NodeProps {
+            let mut props = NodeProps::new(Self::ARCH);
+            props.add_taint("NoSchedule", "krustlet/runtime", self.0);
+            props.add_extended_resource(&format!("example.com/{}", self.0), "1");
+            if self.0 == "wasi" {
+                props.max_pods = Some(50);
+            }
+            props
+        }
+
+        async fn initialize_pod_state(&self, _pod: &Pod) -> Result<MockPodState, ProviderError> {
+            Ok(MockPodState(self.0))
+        }
+
+        async fn logs(
+            &self,
+            _namespace: String,
+            _pod: String,
+            _container: String,
+            _sender: Sender,
+        ) -> Result<(), ProviderError> {
+            Ok(())
+        }
+    }
+
+    fn pod(name: &str, runtime_class: Option<&str>) -> Pod {
+        PodBuilder::new()
+            .name(name)
+            .spec(serde_json::json!({
+                "runtimeClassName": runtime_class,
+                "containers": [{ "name": "app", "image": "hello:v1" }]
+            }))
+            .build()
+    }
+
+    fn registry() -> ProviderRegistry {
+        ProviderRegistry::new()
+            .with_provider("wasi", MockProvider("wasi"))
+            .with_provider("wasm-component", MockProvider("wasm-component"))
+            .with_default("wasi")
+    }
+
+    async fn admit(registry: &ProviderRegistry, pod: &Pod) -> Result<(), Rejection> {
+        let attributes = PodAdmitAttributes {
+            pod,
+            other_pods: &[],
+        };
+        for handler in registry.admit_handlers() {
+            handler.admit(&attributes).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pods_run_with_the_provider_for_their_runtime_class() {
+        let registry = registry();
+        for (pod, provider) in &[
+            (pod("component", Some("wasm-component")), "wasm-component"),
+            (pod("wasi", Some("wasi")), "wasi"),
+            (pod("default", None), "wasi"),
+        ] {
+            let mut state = registry.initialize_pod_state(pod).await.unwrap();
+            assert_eq!(pod_state::<MockProvider>(&mut state).unwrap().0, *provider);
+            assert!(registry
+                .provider_of(pod.namespace(), pod.name())
+                .await
+                .is_ok());
+
+            state.async_drop(&mut *registry.shared.write().await).await;
+            assert!(registry
+                .provider_of(pod.namespace(), pod.name())
+                .await
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn pods_of_unknown_runtime_classes_are_refused() {
+        let registry = registry();
+        assert!(admit(&registry, &pod("wasi", Some("wasi"))).await.is_ok());
+        assert!(admit(&registry, &pod("default", None)).await.is_ok());
+        let rejection = admit(&registry, &pod("gvisor", Some("gvisor")))
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.reason, UNSUPPORTED_RUNTIME_CLASS);
+        assert!(registry
+            .initialize_pod_state(&pod("gvisor", Some("gvisor")))
+            .await
+            .is_err());
+
+        let without_default = ProviderRegistry::new().with_provider("wasi", MockProvider("wasi"));
+        let rejection = admit(&without_default, &pod("default", None))
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.reason, UNSUPPORTED_RUNTIME_CLASS);
+    }
+
+    #[test]
+    fn node_props_of_every_provider_are_combined() {
+        let props = registry().node_props();
+        assert_eq!(props.arch, "mock");
+        let taints: Vec<_> = props
+            .taints
+            .iter()
+            .map(|taint| taint.value.as_deref().unwrap_or_default())
+            .collect();
+        assert_eq!(taints, vec!["wasi", "wasm-component"]);
+        assert!(props.extended_resources.contains_key("example.com/wasi"));
+        assert!(props
+            .extended_resources
+            .contains_key("example.com/wasm-component"));
+        assert_eq!(props.max_pods, Some(50));
+    }
+
+    #[tokio::test]
+    async fn status_of_pods_comes_from_their_provider() {
+        let registry = registry();
+        let pod = pod("wasi", Some("wasi"));
+        assert_eq!(
+            registry
+                .pod_status("default".to_owned(), "wasi".to_owned())
+                .await
+                .unwrap(),
+            PodRuntimeStatus::Unknown
+        );
+
+        let _state = registry.initialize_pod_state(&pod).await.unwrap();
+        assert!(matches!(
+            registry
+                .pod_status("default".to_owned(), "wasi".to_owned())
+                .await,
+            Err(ProviderError::Unsupported)
+        ));
+    }
+}
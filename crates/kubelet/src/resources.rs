@@ -0,0 +1,131 @@
+//! Helpers for reading the resource quantities used in pod specs, such as `128Mi` of memory or
+//! `500m` of CPU.
+
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+/// Binary suffixes, which are powers of two.
+const BINARY_SUFFIXES: &[(&str, f64)] = &[
+    ("Ki", 1024.0),
+    ("Mi", 1_048_576.0),
+    ("Gi", 1_073_741_824.0),
+    ("Ti", 1_099_511_627_776.0),
+    ("Pi", 1_125_899_906_842_624.0),
+    ("Ei", 1_152_921_504_606_846_976.0),
+];
+
+/// Decimal suffixes, which are powers of ten.
+const DECIMAL_SUFFIXES: &[(&str, f64)] = &[
+    ("n", 1e-9),
+    ("u", 1e-6),
+    ("m", 1e-3),
+    ("k", 1e3),
+    ("M", 1e6),
+    ("G", 1e9),
+    ("T", 1e12),
+    ("P", 1e15),
+    ("E", 1e18),
+];
+
+/// Parse a quantity into its value, so `1Ki` is 1024 and `500m` is 0.5.
+pub fn parse(quantity: &Quantity) -> anyhow::Result<f64> {
+    let s = quantity.0.trim();
+    let invalid = || anyhow::anyhow!("invalid quantity {:?}", quantity.0);
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '+' || c == '-'))
+        .unwrap_or_else(|| s.len());
+    let (number, suffix) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let multiplier = if suffix.is_empty() {
+        1.0
+    } else if let Some((_, m)) = BINARY_SUFFIXES
+        .iter()
+        .chain(DECIMAL_SUFFIXES)
+        .find(|(name, _)| *name == suffix)
+    {
+        *m
+    } else if suffix.starts_with('e') || suffix.starts_with('E') {
+        let exponent: i32 = suffix[1..].parse().map_err(|_| invalid())?;
+        10f64.powi(exponent)
+    } else {
+        return Err(invalid());
+    };
+    let value = number * multiplier;
+    if value < 0.0 || !value.is_finite() {
+        return Err(invalid());
+    }
+    Ok(value)
+}
+
+/// Parse a memory quantity into a number of bytes, rounding up any fraction of a byte.
+pub fn memory_bytes(quantity: &Quantity) -> anyhow::Result<u64> {
+    Ok(parse(quantity)?.ceil() as u64)
+}
+
+/// Parse a CPU quantity into a number of millicores, rounding up any fraction of a millicore.
+pub fn cpu_millis(quantity: &Quantity) -> anyhow::Result<u64> {
+    Ok((parse(quantity)? * 1000.0).ceil() as u64)
+}
+
+/// Divide a quantity by a divisor, such as `1Mi` or `1m`, rounding up to a whole number of
+/// divisors. This is how resource values are exposed through the downward API.
+pub fn in_units_of(quantity: &Quantity, divisor: &Quantity) -> anyhow::Result<u64> {
+    let divisor_value = parse(divisor)?;
+    if divisor_value == 0.0 {
+        anyhow::bail!("invalid divisor {:?}", divisor.0);
+    }
+    let units = parse(quantity)? / divisor_value;
+    // Don't let floating point error in an exact division round up to the next unit
+    let nearest = units.round();
+    if (units - nearest).abs() <= nearest * 1e-9 {
+        Ok(nearest as u64)
+    } else {
+        Ok(units.ceil() as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn quantity(s: &str) -> Quantity {
+        Quantity(s.to_owned())
+    }
+
+    #[test]
+    fn memory_quantities_are_parsed() {
+        assert_eq!(memory_bytes(&quantity("128974848")).unwrap(), 128_974_848);
+        assert_eq!(memory_bytes(&quantity("129e6")).unwrap(), 129_000_000);
+        assert_eq!(memory_bytes(&quantity("129M")).unwrap(), 129_000_000);
+        assert_eq!(memory_bytes(&quantity("123Mi")).unwrap(), 128_974_848);
+        assert_eq!(memory_bytes(&quantity("1.5Gi")).unwrap(), 1_610_612_736);
+    }
+
+    #[test]
+    fn cpu_quantities_are_parsed() {
+        assert_eq!(cpu_millis(&quantity("500m")).unwrap(), 500);
+        assert_eq!(cpu_millis(&quantity("0.1")).unwrap(), 100);
+        assert_eq!(cpu_millis(&quantity("2")).unwrap(), 2000);
+    }
+
+    #[test]
+    fn quantities_are_divided_rounding_up() {
+        assert_eq!(in_units_of(&quantity("500m"), &quantity("1")).unwrap(), 1);
+        assert_eq!(
+            in_units_of(&quantity("500m"), &quantity("1m")).unwrap(),
+            500
+        );
+        assert_eq!(
+            in_units_of(&quantity("128Mi"), &quantity("1Mi")).unwrap(),
+            128
+        );
+        assert!(in_units_of(&quantity("1"), &quantity("0")).is_err());
+    }
+
+    #[test]
+    fn invalid_quantities_are_rejected() {
+        assert!(parse(&quantity("")).is_err());
+        assert!(parse(&quantity("12Qi")).is_err());
+        assert!(parse(&quantity("-1")).is_err());
+        assert!(parse(&quantity("Mi")).is_err());
+    }
+}
@@ -3,33 +3,44 @@
 use k8s_openapi::api::core::v1::Secret;
 use kube::api::Api;
 use oci_distribution::secrets::RegistryAuth;
+use tracing::warn;
+
+use crate::pod::{record_event, EventType, Pod};
+
+/// The registry hosts that all mean Docker Hub.
+const DOCKER_HUB_HOSTS: &[&str] = &["docker.io", "index.docker.io", "registry-1.docker.io"];
 
 /// Resolves registry authentication from image pull secrets
 pub struct RegistryAuthResolver {
     kube_client: kube::Client,
-    pod_namespace: String,
+    pod: Pod,
     image_pull_secret_names: Vec<String>,
 }
 
 impl RegistryAuthResolver {
     /// Creates a resolver for the given pod
-    pub fn new(client: kube::Client, pod: &crate::pod::Pod) -> Self {
+    pub fn new(client: kube::Client, pod: &Pod) -> Self {
         // TODO: is it safe to capture this stuff or might we need to re-resolve e.g.
         // the list of secret names after a pod modify?
         RegistryAuthResolver {
             kube_client: client,
-            pod_namespace: pod.namespace().to_owned(),
+            pod: pod.clone(),
             image_pull_secret_names: pod.image_pull_secrets(),
         }
     }
 
     /// Get the registry authentication method appropriate to the given image reference
+    ///
+    /// The pod's image pull secrets are searched in order, and the first one with credentials for
+    /// the image's registry is used. Secrets that can't be fetched are skipped with a warning
+    /// event, as the image may not need them, and if no secret has credentials for the registry
+    /// the image is pulled anonymously.
     pub async fn resolve_registry_auth(
         &self,
         reference: &oci_distribution::Reference,
     ) -> anyhow::Result<RegistryAuth> {
         let secrets_api: Api<Secret> =
-            Api::namespaced(self.kube_client.clone(), &self.pod_namespace);
+            Api::namespaced(self.kube_client.clone(), self.pod.namespace());
 
         let secret_futures: Vec<_> = self
             .image_pull_secret_names
@@ -38,9 +49,9 @@ impl RegistryAuthResolver {
             .collect();
         let secret_results = futures::future::join_all(secret_futures).await;
 
-        for secret_result in secret_results {
+        for (name, secret_result) in self.image_pull_secret_names.iter().zip(secret_results) {
             match secret_result {
-                Err(e) => return Err(e.into()),
+                Err(e) => self.record_missing_secret(name, &e).await,
                 Ok(secret) => {
                     if let Some(auth) = parse_auth(&secret, reference.registry()) {
                         return Ok(auth);
@@ -51,6 +62,29 @@ impl RegistryAuthResolver {
 
         Ok(RegistryAuth::Anonymous)
     }
+
+    async fn record_missing_secret(&self, name: &str, error: &kube::Error) {
+        let message = format!(
+            "Unable to retrieve image pull secret {}, attempting to pull the image may not succeed: {}",
+            name, error
+        );
+        warn!("Pod {}: {}", self.pod.name(), message);
+        if let Err(e) = record_event(
+            &self.kube_client,
+            &self.pod,
+            EventType::Warning,
+            "FailedToRetrieveImagePullSecret",
+            &message,
+        )
+        .await
+        {
+            warn!(
+                "Unable to record FailedToRetrieveImagePullSecret event for pod {}: {:?}",
+                self.pod.name(),
+                e
+            );
+        }
+    }
 }
 
 fn parse_auth(secret: &Secret, registry_name: &str) -> Option<RegistryAuth> {
@@ -81,6 +115,7 @@ fn parse_auth_from_secret_value(
     //     "reg2": { ... }
     //   }
     // }
+    // or, for the older `.dockercfg` secrets, the map of registries on its own.
     parse_byte_string_json(secret_value)
         .and_then(|value| parse_auth_from_json_value(&value, registry_name))
 }
@@ -93,20 +128,91 @@ fn parse_auth_from_json_value(
     json_value: &serde_json::Value,
     registry_name: &str,
 ) -> Option<RegistryAuth> {
-    json_value
-        .get("auths")
-        .and_then(|auths| auths.get(registry_name))
-        .and_then(|creds| parse_auth_from_json_creds(creds))
+    let auths = json_value.get("auths").unwrap_or(json_value).as_object()?;
+    let registry_host = normalize_registry(registry_name);
+    auths
+        .iter()
+        .filter(|(key, _)| normalize_registry(key) == registry_host)
+        .find_map(|(_, creds)| parse_auth_from_json_creds(creds))
 }
 
 fn parse_auth_from_json_creds(json_creds: &serde_json::Value) -> Option<RegistryAuth> {
     let username = json_creds.get("username");
     let password = json_creds.get("password");
-    // TODO: my test creds also included an entry "auth" - should we return this? (e.g. bearer auth?)
     match (username, password) {
         (Some(serde_json::Value::String(u)), Some(serde_json::Value::String(p))) => {
             Some(RegistryAuth::Basic(u.to_owned(), p.to_owned()))
         }
-        _ => None,
+        // Otherwise the credentials may be in the `auth` entry, as base64 encoded `user:password`
+        _ => {
+            let auth = json_creds.get("auth")?.as_str()?;
+            let decoded = String::from_utf8(base64::decode(auth).ok()?).ok()?;
+            let mut parts = decoded.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(u), Some(p)) => Some(RegistryAuth::Basic(u.to_owned(), p.to_owned())),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Reduce a registry as it appears in a docker config, such as `https://index.docker.io/v1/`, to
+/// its host, so it can be compared with the registry of an image reference.
+fn normalize_registry(registry: &str) -> &str {
+    let registry = registry
+        .strip_prefix("https://")
+        .or_else(|| registry.strip_prefix("http://"))
+        .unwrap_or(registry);
+    let host = registry.split('/').next().unwrap_or(registry);
+    if DOCKER_HUB_HOSTS.contains(&host) {
+        DOCKER_HUB_HOSTS[0]
+    } else {
+        host
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn auth(value: serde_json::Value, registry_name: &str) -> Option<RegistryAuth> {
+        parse_auth_from_json_value(&value, registry_name)
+    }
+
+    fn basic(auth: Option<RegistryAuth>) -> Option<(String, String)> {
+        match auth {
+            Some(RegistryAuth::Basic(u, p)) => Some((u, p)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn credentials_are_selected_by_registry() {
+        let config = serde_json::json!({
+            "auths": {
+                "https://index.docker.io/v1/": { "username": "hub", "password": "hub-pass" },
+                "myregistry.azurecr.io": { "username": "acr", "password": "acr-pass" }
+            }
+        });
+        assert_eq!(
+            basic(auth(config.clone(), "docker.io")),
+            Some(("hub".to_owned(), "hub-pass".to_owned()))
+        );
+        assert_eq!(
+            basic(auth(config.clone(), "myregistry.azurecr.io")),
+            Some(("acr".to_owned(), "acr-pass".to_owned()))
+        );
+        assert!(auth(config, "ghcr.io").is_none());
+    }
+
+    #[test]
+    fn encoded_and_legacy_credentials_are_read() {
+        let config = serde_json::json!({
+            "localhost:5000": { "auth": base64::encode("user:pa:ss") }
+        });
+        assert_eq!(
+            basic(auth(config, "localhost:5000")),
+            Some(("user".to_owned(), "pa:ss".to_owned()))
+        );
     }
 }
@@ -0,0 +1,252 @@
+//! Graceful shutdown of the node when the kubelet is asked to stop.
+//!
+//! The node is cordoned so that nothing new is scheduled to it, and then the pods running on it
+//! are stopped by the provider in two rounds: other pods first, then critical pods. Each round
+//! gets its share of the shutdown grace period, within which pods are stopped in order of
+//! priority, lowest first, and each pod gets at most its own termination grace period. Once a pod has stopped, or run out of time, it is reported as failed
+//! with a `Shutdown` reason and a `DisruptionTarget` condition, so that its controller replaces it
+//! on another node.
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::future::join_all;
+use k8s_openapi::api::core::v1::{ContainerStatus as KubeContainerStatus, Pod as KubePod};
+use kube::api::{Api, ListParams, PatchParams};
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::container::Status as ContainerStatus;
+use crate::node;
+use crate::pod::{disruption_target_conditions, Phase, Pod, TERMINATION_BY_KUBELET};
+use crate::provider::{PodRuntimeStatus, Provider};
+
+/// The reason given to pods stopped by the node shutting down.
+const SHUTDOWN_REASON: &str = "Shutdown";
+/// The message given to pods stopped by the node shutting down.
+const SHUTDOWN_MESSAGE: &str = "Pod was terminated in response to node shutdown.";
+
+/// Shut the node down: cordon it, then stop the pods running on it and report them as shut down.
+pub(crate) async fn shutdown<P: Provider>(
+    client: &kube::Client,
+    config: &Config,
+    provider: Arc<P>,
+) -> anyhow::Result<()> {
+    info!("Shutting down node '{}'", config.node_name);
+    if let Err(e) = node::cordon(client, &config.node_name).await {
+        // The pods running on the node should still be stopped
+        error!("Unable to cordon node '{}': {:?}", config.node_name, e);
+    }
+
+    let pod_client: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", config.node_name));
+    let pods = pod_client
+        .list(&params)
+        .await?
+        .items
+        .into_iter()
+        .map(Pod::from)
+        .filter(|pod| !pod.has_finished());
+    let (critical, other): (Vec<Pod>, Vec<Pod>) = pods.partition(Pod::is_critical);
+
+    let (other_period, critical_period) = grace_periods(
+        config.shutdown_grace_period,
+        config.shutdown_grace_period_critical_pods,
+    );
+    info!(
+        "Stopping {} pods within {:?}, then {} critical pods within {:?}",
+        other.len(),
+        other_period,
+        critical.len(),
+        critical_period
+    );
+    stop_pods(client, provider.as_ref(), other, other_period).await;
+    stop_pods(client, provider.as_ref(), critical, critical_period).await;
+    info!("Node '{}' shut down", config.node_name);
+    Ok(())
+}
+
+/// How long other pods and critical pods get to stop. Critical pods get their share first, and
+/// other pods get what is left.
+fn grace_periods(total: Duration, critical_pods: Duration) -> (Duration, Duration) {
+    let critical_pods = std::cmp::min(critical_pods, total);
+    (total - critical_pods, critical_pods)
+}
+
+/// Stop pods within the given period. Pods of the same priority are stopped all at once, lowest
+/// priority first, and each priority gets whatever is left of the period.
+async fn stop_pods<P: Provider>(
+    client: &kube::Client,
+    provider: &P,
+    pods: Vec<Pod>,
+    period: Duration,
+) {
+    let deadline = Instant::now() + period;
+    for tier in priority_tiers(pods) {
+        let period = deadline.saturating_duration_since(Instant::now());
+        join_all(
+            tier.iter()
+                .map(|pod| stop_pod(client, provider, pod, period)),
+        )
+        .await;
+    }
+}
+
+/// Group pods by priority, lowest priority first.
+fn priority_tiers(mut pods: Vec<Pod>) -> Vec<Vec<Pod>> {
+    pods.sort_by_key(Pod::priority);
+    let mut tiers: Vec<Vec<Pod>> = vec![];
+    for pod in pods {
+        match tiers.last_mut() {
+            Some(tier) if tier[0].priority() == pod.priority() => tier.push(pod),
+            _ => tiers.push(vec![pod]),
+        }
+    }
+    tiers
+}
+
+async fn stop_pod<P: Provider>(client: &kube::Client, provider: &P, pod: &Pod, period: Duration) {
+    let grace_period = std::cmp::min(pod.termination_grace_period(), period);
+    let status = provider
+        .pod_status(pod.namespace().to_owned(), pod.name().to_owned())
+        .await;
+    if let Ok(PodRuntimeStatus::Exited) = status {
+        info!("Pod '{}' has already exited", pod.name());
+    } else {
+        info!(
+            "Stopping pod '{}' with a grace period of {:?}",
+            pod.name(),
+            grace_period
+        );
+        match tokio::time::timeout(grace_period, provider.shutdown_pod(pod)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Error stopping pod '{}': {:?}", pod.name(), e),
+            Err(_) => warn!(
+                "Pod '{}' did not stop within {:?}",
+                pod.name(),
+                grace_period
+            ),
+        }
+    }
+
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let result = match serde_json::to_vec(&shutdown_status(pod)) {
+        Ok(data) => api
+            .patch_status(pod.name(), &PatchParams::default(), data)
+            .await
+            .map(|_| ())
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        error!(
+            "Unable to report pod '{}' as shut down: {:?}",
+            pod.name(),
+            e
+        );
+    }
+}
+
+/// The status of a pod that was stopped by the node shutting down.
+fn shutdown_status(pod: &Pod) -> serde_json::Value {
+    let timestamp = Utc::now();
+    serde_json::json!({
+        "status": {
+            "phase": Phase::Failed,
+            "reason": SHUTDOWN_REASON,
+            "message": SHUTDOWN_MESSAGE,
+            "conditions": disruption_target_conditions(
+                pod,
+                TERMINATION_BY_KUBELET,
+                SHUTDOWN_MESSAGE,
+            ),
+            "containerStatuses": pod.containers().iter().map(|container| {
+                ContainerStatus::Terminated {
+                    timestamp,
+                    message: SHUTDOWN_MESSAGE.to_owned(),
+                    failed: true,
+                }
+                .to_kubernetes(container.name())
+            }).collect::<Vec<KubeContainerStatus>>(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::builder::PodBuilder;
+
+    #[test]
+    fn critical_pods_get_their_share_of_the_grace_period() {
+        assert_eq!(
+            grace_periods(Duration::from_secs(30), Duration::from_secs(10)),
+            (Duration::from_secs(20), Duration::from_secs(10))
+        );
+        assert_eq!(
+            grace_periods(Duration::from_secs(5), Duration::from_secs(10)),
+            (Duration::from_secs(0), Duration::from_secs(5))
+        );
+    }
+
+    fn pod_with_priority(name: &str, spec: serde_json::Value) -> Pod {
+        PodBuilder::new()
+            .name(name)
+            .spec(serde_json::json!({ "containers": [{ "name": "app", "image": "hello:v1" }] }))
+            .spec(spec)
+            .build()
+    }
+
+    #[test]
+    fn lower_priority_pods_are_stopped_first() {
+        let pods = vec![
+            pod_with_priority("high", serde_json::json!({ "priority": 1000 })),
+            pod_with_priority("default", serde_json::json!({})),
+            pod_with_priority("low", serde_json::json!({ "priority": -10 })),
+            pod_with_priority("also-high", serde_json::json!({ "priority": 1000 })),
+        ];
+        let tiers: Vec<Vec<&str>> = priority_tiers(pods)
+            .iter()
+            .map(|tier| tier.iter().map(Pod::name).collect())
+            .collect();
+        assert_eq!(
+            tiers,
+            vec![vec!["low"], vec!["default"], vec!["high", "also-high"]]
+        );
+    }
+
+    #[test]
+    fn system_priority_classes_have_a_priority_without_one_being_set() {
+        let node = pod_with_priority(
+            "node",
+            serde_json::json!({ "priorityClassName": "system-node-critical" }),
+        );
+        let cluster = pod_with_priority(
+            "cluster",
+            serde_json::json!({ "priorityClassName": "system-cluster-critical" }),
+        );
+        assert!(node.priority() > cluster.priority());
+        assert!(node.is_critical());
+        assert!(cluster.is_critical());
+        assert!(!pod_with_priority("other", serde_json::json!({ "priority": 1000 })).is_critical());
+    }
+
+    #[test]
+    fn shut_down_pods_are_failed_with_a_reason() {
+        let pod = PodBuilder::new()
+            .spec(serde_json::json!({ "containers": [{ "name": "app", "image": "hello:v1" }] }))
+            .status(serde_json::json!({ "phase": "Running" }))
+            .build();
+        assert!(!pod.has_finished());
+        let status = shutdown_status(&pod);
+        assert_eq!(status["status"]["phase"], "Failed");
+        assert_eq!(status["status"]["reason"], "Shutdown");
+        assert_eq!(
+            status["status"]["conditions"][0]["type"],
+            "DisruptionTarget"
+        );
+        let container = &status["status"]["containerStatuses"][0];
+        assert_eq!(container["name"], "app");
+        assert_eq!(container["state"]["terminated"]["exitCode"], 1);
+    }
+}
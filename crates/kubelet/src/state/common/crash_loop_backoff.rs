@@ -1,8 +1,11 @@
 //! The pod is backing off after repeated failures and retries.
 
 use super::registered::Registered;
-use super::{BackoffSequence, GenericPodState, GenericProvider};
+use super::{
+    enter_or_warn, record, BackoffSequence, GenericPodState, GenericProvider, GenericState,
+};
 use crate::pod::state::prelude::*;
+use crate::pod::{reason, EventType};
 
 /// The pod is backing off after repeated failures and retries.
 pub struct CrashLoopBackoff<P: GenericProvider> {
@@ -27,10 +30,20 @@ impl<P: GenericProvider> Default for CrashLoopBackoff<P> {
 impl<P: GenericProvider> State<P::PodState> for CrashLoopBackoff<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
-        _pod: Manifest<Pod>,
+        pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
+        let pod = pod.latest();
+        enter_or_warn(&provider_state, &pod, GenericState::CrashLoopBackoff).await;
+        record(
+            &provider_state,
+            &pod,
+            EventType::Warning,
+            reason::BACK_OFF,
+            "Back-off restarting failed pod",
+        )
+        .await;
         pod_state.backoff(BackoffSequence::CrashLoop).await;
         let next = Registered::<P>::default();
         Transition::next(self, next)
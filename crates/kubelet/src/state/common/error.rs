@@ -2,12 +2,14 @@
 
 use super::crash_loop_backoff::CrashLoopBackoff;
 use super::registered::Registered;
-use super::{GenericPodState, GenericProvider, ThresholdTrigger};
+use super::{enter_or_warn, GenericPodState, GenericProvider, GenericState, ThresholdTrigger};
 use crate::pod::state::prelude::*;
+use crate::provider::ProviderError;
 
 /// The Pod failed to run.
 pub struct Error<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    reason: String,
     message: String,
 }
 
@@ -23,19 +25,31 @@ impl<P: GenericProvider> Error<P> {
     pub fn new(message: String) -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            reason: message.clone(),
             message,
         }
     }
+
+    /// Creates an instance of the Error state for a provider error, whose kind gives the reason
+    /// reported in the pod's status.
+    pub fn from_provider_error(error: ProviderError) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            reason: error.reason().to_owned(),
+            message: error.to_string(),
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl<P: GenericProvider> State<P::PodState> for Error<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
-        _pod: Manifest<Pod>,
+        pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
+        enter_or_warn(&provider_state, &pod.latest(), GenericState::Error).await;
         match pod_state.record_error().await {
             ThresholdTrigger::Triggered => {
                 let next = CrashLoopBackoff::<P>::default();
@@ -50,7 +64,11 @@ impl<P: GenericProvider> State<P::PodState> for Error<P> {
     }
 
     async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, &self.message))
+        Ok(StatusBuilder::new()
+            .phase(Phase::Pending)
+            .reason(&self.reason)
+            .message(&self.message)
+            .build())
     }
 }
 
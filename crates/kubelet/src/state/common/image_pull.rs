@@ -1,11 +1,20 @@
 //! Kubelet is pulling container images.
 
+use super::error::Error;
 use super::image_pull_backoff::ImagePullBackoff;
 use super::volume_mount::VolumeMount;
-use super::{BackoffSequence, GenericPodState, GenericProvider, GenericProviderState};
+use super::{
+    enter, record, BackoffSequence, GenericPodState, GenericProvider, GenericProviderState,
+    GenericState,
+};
+use crate::backoff::RetryPolicy;
+use crate::node::problem::{self, Problem};
 use crate::pod::state::prelude::*;
+use crate::pod::{reason, EventType};
+use crate::store::signature::VerificationError;
 
-use log::error;
+use tracing::{error, info_span};
+use tracing_futures::Instrument;
 
 /// Kubelet is pulling container images.
 pub struct ImagePull<P: GenericProvider> {
@@ -36,19 +45,61 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
 
+        if let Err(e) = enter(&provider_state, &pod, GenericState::ImagePull).await {
+            error!("{:?}", e);
+            return Transition::next(self, Error::<P>::new(e.to_string()));
+        }
         let (client, store) = {
             // Minimise the amount of time we hold any locks
             let state_reader = provider_state.read().await;
             (state_reader.client(), state_reader.store())
         };
-        let auth_resolver = crate::secret::RegistryAuthResolver::new(client, &pod);
-        let modules = match store.fetch_pod_modules(&pod, &auth_resolver).await {
+        record(
+            &provider_state,
+            &pod,
+            EventType::Normal,
+            reason::PULLING,
+            "Pulling images of the pod's containers",
+        )
+        .await;
+        let auth_resolver = crate::secret::RegistryAuthResolver::new(client.clone(), &pod);
+        let timer = crate::metrics::IMAGE_PULL_DURATION.start_timer();
+        let modules = match RetryPolicy::image_pull()
+            .retry("pull images of the pod", || {
+                store.fetch_pod_modules(&pod, &auth_resolver, &client)
+            })
+            .instrument(info_span!(
+                parent: &crate::telemetry::pod_span(pod.namespace(), pod.name()),
+                "image_pull"
+            ))
+            .await
+        {
             Ok(m) => m,
             Err(e) => {
                 error!("{:?}", e);
+                timer.stop_and_discard();
+                let reason = if e.downcast_ref::<VerificationError>().is_some() {
+                    "ImageVerificationFailed"
+                } else {
+                    reason::FAILED
+                };
+                let message = format!("{:#}", e);
+                if problem::is_unreachable(&e) {
+                    problem::report(Problem::RegistryUnreachable, &message);
+                }
+                record(&provider_state, &pod, EventType::Warning, reason, &message).await;
                 return Transition::next(self, ImagePullBackoff::<P>::default());
             }
         };
+        let seconds = timer.stop_and_record();
+        record(
+            &provider_state,
+            &pod,
+            EventType::Normal,
+            reason::PULLED,
+            &format!("Successfully pulled images in {:.3}s", seconds),
+        )
+        .await;
         pod_state.set_modules(modules).await;
         pod_state.reset_backoff(BackoffSequence::ImagePull).await;
         Transition::next(self, VolumeMount::<P>::default())
@@ -59,5 +110,6 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
     }
 }
 
+impl<P: GenericProvider> TransitionTo<Error<P>> for ImagePull<P> {}
 impl<P: GenericProvider> TransitionTo<ImagePullBackoff<P>> for ImagePull<P> {}
 impl<P: GenericProvider> TransitionTo<VolumeMount<P>> for ImagePull<P> {}
@@ -1,8 +1,11 @@
 //! Kubelet encountered an error when pulling container image.
 
 use super::image_pull::ImagePull;
-use super::{BackoffSequence, GenericPodState, GenericProvider};
+use super::{
+    enter_or_warn, record, BackoffSequence, GenericPodState, GenericProvider, GenericState,
+};
 use crate::pod::state::prelude::*;
+use crate::pod::{reason, EventType};
 
 /// Kubelet encountered an error when pulling container image.
 pub struct ImagePullBackoff<P: GenericProvider> {
@@ -27,10 +30,20 @@ impl<P: GenericProvider> Default for ImagePullBackoff<P> {
 impl<P: GenericProvider> State<P::PodState> for ImagePullBackoff<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
-        _pod: Manifest<Pod>,
+        pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
+        let pod = pod.latest();
+        enter_or_warn(&provider_state, &pod, GenericState::ImagePullBackoff).await;
+        record(
+            &provider_state,
+            &pod,
+            EventType::Normal,
+            reason::BACK_OFF,
+            "Back-off pulling images",
+        )
+        .await;
         pod_state.backoff(BackoffSequence::ImagePull).await;
         Transition::next(self, ImagePull::<P>::default())
     }
@@ -2,11 +2,18 @@
 //! logic, and the machinery to use them. This removes the need to write these
 //! states in many providers; instead, the provider need only implement the
 //! GenericProviderState and GenericPodState traits for its state types.
+//!
+//! Pods go through the generic states as Registered → ImagePull → VolumeMount → the provider's
+//! run state, backing off through ImagePullBackoff or Error and CrashLoopBackoff when something
+//! fails, and each state reports the pod's status as it is entered. States record events about
+//! what they do, and providers can act on pods entering each of them with
+//! [`GenericProviderState::on_state`], without having to write states of their own.
 
 use crate::pod::state::prelude::PodStatus;
-use crate::pod::Pod;
-use krator::{ObjectState, State};
+use crate::pod::{record_event, EventType, Pod};
+use krator::{ObjectState, SharedState, State};
 use std::collections::HashMap;
+use tracing::warn;
 
 pub mod crash_loop_backoff;
 pub mod error;
@@ -24,6 +31,25 @@ pub enum BackoffSequence {
     CrashLoop,
 }
 
+/// The generic states, as given to [`GenericProviderState::on_state`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenericState {
+    /// The pod is being validated.
+    Registered,
+    /// The pod's modules are being pulled.
+    ImagePull,
+    /// Pulling the pod's modules failed, and is being retried after a backoff.
+    ImagePullBackoff,
+    /// The pod's volumes are being mounted.
+    VolumeMount,
+    /// Preparing the pod failed.
+    Error,
+    /// The pod failed repeatedly, and is being retried after a backoff.
+    CrashLoopBackoff,
+    /// The pod was deleted and is being stopped.
+    Terminated,
+}
+
 /// Indicates whether a threshold has been triggered.
 pub enum ThresholdTrigger {
     /// The threshold has been triggered.
@@ -43,9 +69,86 @@ pub trait GenericProviderState: 'static + Send + Sync {
     fn store(&self) -> std::sync::Arc<dyn crate::store::Store + Sync + Send>;
     /// Gets the path at which to construct temporary directories for volumes.
     fn volume_path(&self) -> std::path::PathBuf;
+    /// Gets the host paths that pods may mount as hostPath volumes, along with anything under
+    /// them. By default, none are allowed.
+    fn allowed_host_paths(&self) -> Vec<std::path::PathBuf> {
+        vec![]
+    }
+    /// Gets the registry of node plugins used to mount CSI volumes, if the provider has one.
+    fn plugin_registry(&self) -> Option<std::sync::Arc<crate::plugin_watcher::PluginRegistry>> {
+        None
+    }
+    /// Gets the recorder that events about pods are recorded with, if the provider has one.
+    /// Without one, events are recorded without being aggregated.
+    fn event_recorder(&self) -> Option<crate::pod::EventRecorder> {
+        None
+    }
+    /// Gets the device manager that allocates devices to pods, if the provider has one.
+    fn device_manager(&self) -> Option<std::sync::Arc<crate::device_plugin::DeviceManager>> {
+        None
+    }
     /// Stops the specified pod. This typically involves tearing down a
     /// runtime or other execution environment.
     async fn stop(&self, pod: &crate::pod::Pod) -> anyhow::Result<()>;
+    /// Called as the pod enters one of the generic states, before the state does anything. An
+    /// error fails the pod if the state is one that prepares it to run, and is logged otherwise.
+    /// By default, this does nothing.
+    async fn on_state(&self, _pod: &crate::pod::Pod, _state: GenericState) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run the provider's hook for the state the pod is entering.
+async fn enter<S: GenericProviderState>(
+    provider_state: &SharedState<S>,
+    pod: &Pod,
+    state: GenericState,
+) -> anyhow::Result<()> {
+    let state_reader = provider_state.read().await;
+    state_reader.on_state(pod, state).await
+}
+
+/// Run the provider's hook for a state that the pod can't be failed from, logging any error.
+async fn enter_or_warn<S: GenericProviderState>(
+    provider_state: &SharedState<S>,
+    pod: &Pod,
+    state: GenericState,
+) {
+    if let Err(e) = enter(provider_state, pod, state).await {
+        warn!(
+            "Hook for pod {} entering {:?} failed: {:?}",
+            pod.name(),
+            state,
+            e
+        );
+    }
+}
+
+/// Record an event about the pod, with the provider's event recorder if it has one. Failing to
+/// record it is only logged.
+async fn record<S: GenericProviderState>(
+    provider_state: &SharedState<S>,
+    pod: &Pod,
+    event_type: EventType,
+    reason: &str,
+    message: &str,
+) {
+    let (client, events) = {
+        let state_reader = provider_state.read().await;
+        (state_reader.client(), state_reader.event_recorder())
+    };
+    let recorded = match &events {
+        Some(events) => events.record(pod, None, event_type, reason, message).await,
+        None => record_event(&client, pod, event_type, reason, message).await,
+    };
+    if let Err(e) = recorded {
+        warn!(
+            "Unable to record {} event for pod {}: {:?}",
+            reason,
+            pod.name(),
+            e
+        );
+    }
 }
 
 /// Exposes pod state in a way that can be consumed by
@@ -59,6 +162,13 @@ pub trait GenericPodState: ObjectState<Manifest = Pod, Status = PodStatus> {
     /// the provider's execution environment. Typically your
     /// implementation can just move the volumes map into a member field.
     async fn set_volumes(&mut self, volumes: HashMap<String, crate::volume::Ref>);
+    /// Stores what each of the pod's containers needs in order to use the devices allocated to
+    /// it by device plugins, by container name. By default, this does nothing.
+    async fn set_devices(
+        &mut self,
+        _devices: HashMap<String, crate::device_plugin::ContainerDevices>,
+    ) {
+    }
     /// Backs off (waits) after an error of the specified kind.
     async fn backoff(&mut self, sequence: BackoffSequence);
     /// Resets the backoff time for the specified kind of error.
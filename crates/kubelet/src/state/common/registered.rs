@@ -1,11 +1,12 @@
 //! The Kubelet is aware of the Pod.
 
 use crate::pod::state::prelude::*;
-use log::{debug, error, info};
+use tracing::{debug, error, info};
 
 use super::error::Error;
 use super::image_pull::ImagePull;
-use super::GenericProvider;
+use super::{enter, record, GenericProvider, GenericState};
+use crate::pod::{reason, EventType};
 
 /// The Kubelet is aware of the Pod.
 pub struct Registered<P: GenericProvider> {
@@ -30,20 +31,29 @@ impl<P: GenericProvider> Default for Registered<P> {
 impl<P: GenericProvider> State<P::PodState> for Registered<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         _pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
 
         debug!("Preparing to register pod: {}", pod.name());
-        match P::validate_pod_and_containers_runnable(&pod) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("{:?}", e);
-                let next = Error::<P>::new(e.to_string());
-                return Transition::next(self, next);
-            }
+        let registered = match P::validate_pod_and_containers_runnable(&pod) {
+            Ok(_) => enter(&provider_state, &pod, GenericState::Registered).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = registered {
+            error!("{:?}", e);
+            let message = e.to_string();
+            record(
+                &provider_state,
+                &pod,
+                EventType::Warning,
+                reason::FAILED,
+                &message,
+            )
+            .await;
+            return Transition::next(self, Error::<P>::new(message));
         }
         info!("Pod registered: {}", pod.name());
         let next = ImagePull::<P>::default();
@@ -1,6 +1,6 @@
 //! Pod was deleted.
 
-use super::{GenericProvider, GenericProviderState};
+use super::{enter_or_warn, GenericProvider, GenericProviderState, GenericState};
 use crate::pod::state::prelude::*;
 
 /// Pod was deleted.
@@ -32,6 +32,7 @@ impl<P: GenericProvider> State<P::PodState> for Terminated<P> {
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
 
+        enter_or_warn(&provider_state, &pod, GenericState::Terminated).await;
         let state_reader = provider_state.read().await;
         // TODO: In original code, pod key was stored in state rather than
         // re-derived.  Is this important e.g. could pod mutate in ways
@@ -1,13 +1,16 @@
-//! Kubelet is pulling container images.
+//! Kubelet is mounting the pod's volumes.
 
-use log::error;
+use tracing::error;
 
-use super::{GenericPodState, GenericProvider, GenericProviderState};
+use super::{enter, record, GenericPodState, GenericProvider, GenericProviderState, GenericState};
+use crate::backoff::RetryPolicy;
 use crate::pod::state::prelude::*;
+use crate::pod::{reason, EventType};
+use crate::provider::ProviderError;
 use crate::state::common::error::Error;
 use crate::volume::Ref;
 
-/// Kubelet is pulling container images.
+/// Kubelet is mounting the pod's volumes.
 pub struct VolumeMount<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
 }
@@ -36,19 +39,53 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
 
-        let (client, volume_path) = {
+        if let Err(e) = enter(&provider_state, &pod, GenericState::VolumeMount).await {
+            error!("{:?}", e);
+            return Transition::next(self, Error::<P>::new(e.to_string()));
+        }
+        let (client, volume_path, plugin_registry, allowed_host_paths, device_manager) = {
             let state_reader = provider_state.read().await;
-            (state_reader.client(), state_reader.volume_path())
+            (
+                state_reader.client(),
+                state_reader.volume_path(),
+                state_reader.plugin_registry(),
+                state_reader.allowed_host_paths(),
+                state_reader.device_manager(),
+            )
         };
-        let volumes = match Ref::volumes_from_pod(&volume_path, &pod, &client).await {
+        let volumes = match RetryPolicy::api_request()
+            .retry("mount the pod's volumes", || {
+                Ref::volumes_from_pod(
+                    &volume_path,
+                    &pod,
+                    &client,
+                    plugin_registry.clone(),
+                    &allowed_host_paths,
+                )
+            })
+            .await
+        {
             Ok(v) => v,
             Err(e) => {
                 error!("{:?}", e);
-                let next = Error::<P>::new(e.to_string());
-                return Transition::next(self, next);
+                let error = ProviderError::VolumeSetup(e);
+                record(
+                    &provider_state,
+                    &pod,
+                    EventType::Warning,
+                    reason::FAILED_MOUNT,
+                    &error.to_string(),
+                )
+                .await;
+                return Transition::next(self, Error::<P>::from_provider_error(error));
             }
         };
         pod_state.set_volumes(volumes).await;
+        if let Some(device_manager) = device_manager {
+            pod_state
+                .set_devices(device_manager.pod_devices(&pod).await)
+                .await;
+        }
         Transition::next_unchecked(self, P::RunState::default())
     }
 
@@ -0,0 +1,406 @@
+//! Static pods, which are run from manifests in a local directory rather than being scheduled to
+//! the node through the API server.
+//!
+//! The directory is read when the kubelet starts, whenever its contents change, and periodically
+//! in case a change was missed. The pods read are handed to the pod operator directly, so they run
+//! whether or not the API server can be reached. When it can, each static pod gets a mirror pod
+//! there, so that it shows up with the rest of the node's pods. Mirror pods are recreated if they
+//! are deleted, and only go away for good when their static pod does.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::future::join_all;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::api::{Api, DeleteParams, ListParams, PatchParams, PostParams};
+use kube_runtime::watcher::Event;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, info, warn};
+
+use crate::fs_watch::FileSystemWatcher;
+use crate::pod::{
+    make_registered_status, Pod, CONFIG_HASH_ANNOTATION, CONFIG_MIRROR_ANNOTATION,
+    CONFIG_SOURCE_ANNOTATION,
+};
+
+/// How often the manifest directory is read, in case a change to it was missed.
+const SYNC_INTERVAL: Duration = Duration::from_secs(20);
+/// How long a static pod is given to stop beyond its termination grace period before it is
+/// deregistered.
+const STOP_MARGIN: Duration = Duration::from_secs(5);
+
+/// The static pods read from a manifest directory, and their mirror pods.
+pub(crate) struct StaticPods {
+    dir: PathBuf,
+    node_name: String,
+    client: kube::Client,
+    events: UnboundedSender<Event<Pod>>,
+    /// The pods running, by the path of the manifest they were read from
+    pods: HashMap<PathBuf, Pod>,
+}
+
+impl StaticPods {
+    /// Create a manager for the static pods in `dir`, which sends the pods to run to `events`.
+    pub(crate) fn new(
+        dir: PathBuf,
+        node_name: String,
+        client: kube::Client,
+        events: UnboundedSender<Event<Pod>>,
+    ) -> Self {
+        StaticPods {
+            dir,
+            node_name,
+            client,
+            events,
+            pods: HashMap::new(),
+        }
+    }
+
+    /// Start the static pods in the manifest directory. This should be done before the pod
+    /// operator starts, so that it knows which pods are static before it hears of their mirror
+    /// pods.
+    pub(crate) async fn start(&mut self) {
+        self.sync_pods().await;
+    }
+
+    /// Keep the static pods in line with the manifest directory, and their mirror pods in line
+    /// with the static pods.
+    pub(crate) async fn run(mut self) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let mut watcher = FileSystemWatcher::new(&self.dir)?;
+        loop {
+            if let Err(e) = self.sync_mirror_pods().await {
+                // The static pods keep running, and the mirror pods are retried on the next sync
+                warn!("Unable to sync mirror pods: {:?}", e);
+            }
+            tokio::select! {
+                event = watcher.next() => match event {
+                    Some(Ok(event)) => debug!("Pod manifest directory changed: {:?}", event),
+                    Some(Err(e)) => warn!("Error watching pod manifest directory: {:?}", e),
+                    None => anyhow::bail!("Pod manifest directory watcher stopped"),
+                },
+                _ = tokio::time::delay_for(SYNC_INTERVAL) => (),
+            }
+            self.sync_pods().await;
+        }
+    }
+
+    /// Start the pods of new manifests, and stop the pods of removed ones. Pods whose manifest
+    /// changed are stopped, then started again from the new manifest.
+    async fn sync_pods(&mut self) {
+        let manifests = match read_manifests(&self.dir, &self.node_name) {
+            Ok(manifests) => manifests,
+            Err(e) => {
+                warn!(
+                    "Unable to read pod manifest directory {}: {:?}",
+                    self.dir.display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        let mut stopping = vec![];
+        let removed: Vec<PathBuf> = self
+            .pods
+            .keys()
+            .filter(|path| !manifests.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in removed {
+            if let Some(pod) = self.pods.remove(&path) {
+                info!(
+                    "Manifest {} was removed, stopping static pod '{}'",
+                    path.display(),
+                    pod.name()
+                );
+                stopping.push((pod, None));
+            }
+        }
+
+        for (path, manifest) in manifests {
+            let pod = match manifest {
+                Ok(pod) => pod,
+                Err(e) => {
+                    // It may be part way through being written, so whatever was read from it
+                    // before keeps running
+                    warn!("Unable to read pod manifest {}: {:?}", path.display(), e);
+                    continue;
+                }
+            };
+            match self.pods.get(&path) {
+                Some(existing) if config_hash(existing) == config_hash(&pod) => (),
+                Some(_) => {
+                    info!(
+                        "Manifest {} changed, restarting static pod '{}'",
+                        path.display(),
+                        pod.name()
+                    );
+                    if let Some(old) = self.pods.insert(path, pod.clone()) {
+                        stopping.push((old, Some(pod)));
+                    }
+                }
+                None if self.pods.values().any(|other| same_pod(other, &pod)) => warn!(
+                    "Ignoring manifest {}, as static pod '{}' is already defined in another one",
+                    path.display(),
+                    pod.name()
+                ),
+                None => {
+                    info!(
+                        "Starting static pod '{}' from manifest {}",
+                        pod.name(),
+                        path.display()
+                    );
+                    send(&self.events, Event::Applied(pod.clone()));
+                    self.pods.insert(path, pod);
+                }
+            }
+        }
+
+        join_all(
+            stopping
+                .into_iter()
+                .map(|(pod, replacement)| stop(&self.events, pod, replacement)),
+        )
+        .await;
+    }
+
+    /// Create mirror pods for static pods that don't have an up to date one, and delete mirror
+    /// pods that don't match a static pod.
+    async fn sync_mirror_pods(&self) -> anyhow::Result<()> {
+        let api: Api<KubePod> = Api::all(self.client.clone());
+        let params = ListParams::default().fields(&format!("spec.nodeName={}", self.node_name));
+        let mirrors: Vec<Pod> = api
+            .list(&params)
+            .await?
+            .items
+            .into_iter()
+            .map(Pod::from)
+            .filter(Pod::is_mirror)
+            .collect();
+
+        let delete_params = DeleteParams {
+            grace_period_seconds: Some(0),
+            ..Default::default()
+        };
+        for mirror in &mirrors {
+            let up_to_date = self.pods.values().any(|pod| {
+                same_pod(pod, mirror)
+                    && mirror.get_annotation(CONFIG_MIRROR_ANNOTATION) == config_hash(pod)
+            });
+            // Mirror pods being deleted are waiting on the kubelet to finish deleting them, as
+            // with any other pod, but static pods only stop when their manifest is removed
+            if !up_to_date || mirror.deletion_timestamp().is_some() {
+                info!("Deleting mirror pod '{}'", mirror.name());
+                let api: Api<KubePod> = Api::namespaced(self.client.clone(), mirror.namespace());
+                api.delete(mirror.name(), &delete_params).await?;
+            }
+        }
+
+        for pod in self.pods.values() {
+            if mirrors.iter().any(|mirror| same_pod(pod, mirror)) {
+                // Any out of date mirror pod was just deleted, and is replaced on the next sync
+                continue;
+            }
+            info!("Creating mirror pod for static pod '{}'", pod.name());
+            let api: Api<KubePod> = Api::namespaced(self.client.clone(), pod.namespace());
+            api.create(&PostParams::default(), &mirror_pod(pod)).await?;
+            // The status isn't set on creation, and the pod's status so far is needed for later
+            // patches to it to apply
+            let status = serde_json::json!({ "status": pod.as_kube_pod().status });
+            api.patch_status(
+                pod.name(),
+                &PatchParams::default(),
+                serde_json::to_vec(&status)?,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Stop a static pod, giving it its termination grace period, then start its replacement if it
+/// has one.
+async fn stop(events: &UnboundedSender<Event<Pod>>, pod: Pod, replacement: Option<Pod>) {
+    let grace_period = pod.termination_grace_period();
+    let mut deleting = pod.into_kube_pod();
+    deleting.metadata.deletion_timestamp = Some(Time(Utc::now()));
+    deleting.metadata.deletion_grace_period_seconds = Some(grace_period.as_secs() as i64);
+    send(events, Event::Applied(Pod::from(deleting.clone())));
+    // The pod is only deregistered once it is gone, as is done for pods deleted from the API
+    // server
+    tokio::time::delay_for(grace_period + STOP_MARGIN).await;
+    send(events, Event::Deleted(Pod::from(deleting)));
+    if let Some(pod) = replacement {
+        send(events, Event::Applied(pod));
+    }
+}
+
+fn send(events: &UnboundedSender<Event<Pod>>, event: Event<Pod>) {
+    if events.send(event).is_err() {
+        warn!("Pod operator has stopped, unable to update static pods");
+    }
+}
+
+/// Read the manifests in a directory, by path. Hidden files and files that aren't YAML or JSON
+/// are skipped.
+fn read_manifests(
+    dir: &Path,
+    node_name: &str,
+) -> std::io::Result<HashMap<PathBuf, anyhow::Result<Pod>>> {
+    let mut manifests = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !is_manifest(&path) {
+            continue;
+        }
+        let manifest = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| static_pod(&contents, node_name));
+        manifests.insert(path, manifest);
+    }
+    Ok(manifests)
+}
+
+fn is_manifest(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map_or(true, |name| name.starts_with('.'));
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    !hidden && path.is_file() && matches!(extension, Some("yaml") | Some("yml") | Some("json"))
+}
+
+/// Read a static pod from a YAML or JSON manifest, filling in what the API server would have for
+/// a pod scheduled to the node.
+fn static_pod(manifest: &[u8], node_name: &str) -> anyhow::Result<Pod> {
+    let mut pod: KubePod = serde_yaml::from_slice(manifest)?;
+    // Hash what was read rather than the file itself, so that formatting changes don't restart
+    // the pod
+    let hash = format!("{:x}", Sha256::digest(&serde_json::to_vec(&pod)?));
+
+    let name = pod
+        .metadata
+        .name
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("pod manifest has no name"))?;
+    pod.metadata.name = Some(format!("{}-{}", name, node_name));
+    if pod.metadata.namespace.is_none() {
+        pod.metadata.namespace = Some("default".to_owned());
+    }
+    pod.metadata.uid = Some(hash.clone());
+    pod.metadata.creation_timestamp = Some(Time(Utc::now()));
+    let annotations = pod
+        .metadata
+        .annotations
+        .get_or_insert_with(Default::default);
+    annotations.insert(CONFIG_SOURCE_ANNOTATION.to_owned(), "file".to_owned());
+    annotations.insert(CONFIG_HASH_ANNOTATION.to_owned(), hash);
+    pod.spec
+        .as_mut()
+        .ok_or_else(|| anyhow::anyhow!("pod manifest has no spec"))?
+        .node_name = Some(node_name.to_owned());
+    // Static pods never hear back from the API server, so start out with the status they are
+    // registered with
+    pod.status = Some(make_registered_status(&Pod::from(pod.clone())).into_kube_status());
+    Ok(Pod::from(pod))
+}
+
+/// The mirror pod that stands in the API server for a static pod.
+fn mirror_pod(pod: &Pod) -> KubePod {
+    let mut mirror = pod.as_kube_pod().clone();
+    mirror.metadata.uid = None;
+    mirror.metadata.creation_timestamp = None;
+    mirror.metadata.resource_version = None;
+    if let Some(hash) = config_hash(pod) {
+        mirror
+            .metadata
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(CONFIG_MIRROR_ANNOTATION.to_owned(), hash.to_owned());
+    }
+    mirror.status = None;
+    mirror
+}
+
+fn config_hash(pod: &Pod) -> Option<&str> {
+    pod.get_annotation(CONFIG_HASH_ANNOTATION)
+}
+
+fn same_pod(a: &Pod, b: &Pod) -> bool {
+    a.namespace() == b.namespace() && a.name() == b.name()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+apiVersion: v1
+kind: Pod
+metadata:
+  name: hello
+spec:
+  containers:
+    - name: app
+      image: hello:v1
+"#;
+
+    #[test]
+    fn static_pods_are_filled_in_for_the_node() {
+        let pod = static_pod(MANIFEST.as_bytes(), "krustlet").unwrap();
+        assert_eq!(pod.name(), "hello-krustlet");
+        assert_eq!(pod.namespace(), "default");
+        assert_eq!(pod.node_name(), Some("krustlet"));
+        assert!(pod.is_from_file());
+        assert!(!pod.is_mirror());
+        assert_eq!(pod.as_kube_pod().metadata.uid.as_deref(), config_hash(&pod));
+    }
+
+    #[test]
+    fn only_changes_to_the_pod_change_its_hash() {
+        let pod = static_pod(MANIFEST.as_bytes(), "krustlet").unwrap();
+        let reformatted = r#"{
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "hello" },
+            "spec": { "containers": [{ "name": "app", "image": "hello:v1" }] }
+        }"#;
+        let same = static_pod(reformatted.as_bytes(), "krustlet").unwrap();
+        assert_eq!(config_hash(&pod), config_hash(&same));
+        let changed = static_pod(
+            MANIFEST.replace("hello:v1", "hello:v2").as_bytes(),
+            "krustlet",
+        )
+        .unwrap();
+        assert_ne!(config_hash(&pod), config_hash(&changed));
+    }
+
+    #[test]
+    fn manifests_need_a_name_and_spec() {
+        assert!(static_pod(b"apiVersion: v1\nkind: Pod\nspec: {}", "krustlet").is_err());
+        assert!(static_pod(
+            b"apiVersion: v1\nkind: Pod\nmetadata:\n  name: a",
+            "krustlet"
+        )
+        .is_err());
+        assert!(static_pod(b"apiVersion: apps/v1\nkind: Deployment", "krustlet").is_err());
+    }
+
+    #[test]
+    fn mirror_pods_carry_the_static_pod_hash() {
+        let pod = static_pod(MANIFEST.as_bytes(), "krustlet").unwrap();
+        let mirror = Pod::from(mirror_pod(&pod));
+        assert!(mirror.is_mirror());
+        assert!(same_pod(&pod, &mirror));
+        assert_eq!(
+            mirror.get_annotation(CONFIG_MIRROR_ANNOTATION),
+            config_hash(&pod)
+        );
+        assert_eq!(mirror.as_kube_pod().metadata.uid, None);
+    }
+}
@@ -0,0 +1,235 @@
+//! `stats` contains the types of the kubelet Summary API, which is served from `/stats/summary`
+//! and scraped by the likes of metrics-server and `kubectl top`.
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// A summary of resource usage on the node and of the pods running on it.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    /// Stats for the node as a whole.
+    pub node: NodeStats,
+    /// Stats for each of the pods running on the node.
+    pub pods: Vec<PodStats>,
+}
+
+/// Resource usage of the node.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeStats {
+    /// The name of the node.
+    pub node_name: String,
+    /// When the kubelet started collecting stats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<DateTime<Utc>>,
+    /// CPU usage of the node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuStats>,
+    /// Memory usage of the node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryStats>,
+}
+
+/// Resource usage of a pod.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodStats {
+    /// The pod these stats are for.
+    pub pod_ref: PodReference,
+    /// When the pod started running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<DateTime<Utc>>,
+    /// Stats for each of the pod's containers.
+    pub containers: Vec<ContainerStats>,
+    /// CPU usage of the pod as a whole.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuStats>,
+    /// Memory usage of the pod as a whole.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryStats>,
+    /// Memory the runtime uses to run the pod, on top of what its containers use. This is what
+    /// the `overhead` of the pod's RuntimeClass should allow for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_overhead: Option<MemoryStats>,
+}
+
+/// Identifies the pod that stats belong to.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodReference {
+    /// The name of the pod.
+    pub name: String,
+    /// The namespace of the pod.
+    pub namespace: String,
+    /// The UID of the pod.
+    pub uid: String,
+}
+
+/// Resource usage of a container.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerStats {
+    /// The name of the container.
+    pub name: String,
+    /// When the container started running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<DateTime<Utc>>,
+    /// CPU usage of the container.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuStats>,
+    /// Memory usage of the container.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryStats>,
+}
+
+/// CPU usage at a point in time.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuStats {
+    /// When the sample was taken.
+    pub time: DateTime<Utc>,
+    /// Average CPU usage across all cores since the last sample, in nanocores.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_nano_cores: Option<u64>,
+    /// Cumulative CPU time consumed across all cores, in nanoseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_core_nano_seconds: Option<u64>,
+}
+
+/// Memory usage at a point in time.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryStats {
+    /// When the sample was taken.
+    pub time: DateTime<Utc>,
+    /// Memory that is available for use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_bytes: Option<u64>,
+    /// Total memory in use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_bytes: Option<u64>,
+    /// Memory in use that cannot be reclaimed under pressure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_set_bytes: Option<u64>,
+}
+
+/// The number of clock ticks per second used in `/proc/stat`.
+#[cfg(target_os = "linux")]
+const USER_HZ: u64 = 100;
+
+/// Collects CPU and memory usage of the node. Usage is only available on Linux, elsewhere only
+/// the node name and start time are filled in.
+pub(crate) async fn node_stats(node_name: &str, start_time: DateTime<Utc>) -> NodeStats {
+    NodeStats {
+        node_name: node_name.to_owned(),
+        start_time: Some(start_time),
+        cpu: node_cpu().await,
+        memory: node_memory().await,
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn node_cpu() -> Option<CpuStats> {
+    let stat = tokio::fs::read_to_string("/proc/stat").await.ok()?;
+    let ticks = parse_cpu_ticks(&stat)?;
+    Some(CpuStats {
+        time: Utc::now(),
+        usage_nano_cores: None,
+        usage_core_nano_seconds: Some(ticks * (1_000_000_000 / USER_HZ)),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn node_cpu() -> Option<CpuStats> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+async fn node_memory() -> Option<MemoryStats> {
+    let meminfo = tokio::fs::read_to_string("/proc/meminfo").await.ok()?;
+    let (total, available) = parse_meminfo(&meminfo)?;
+    let used = total.saturating_sub(available);
+    Some(MemoryStats {
+        time: Utc::now(),
+        available_bytes: Some(available),
+        usage_bytes: Some(used),
+        working_set_bytes: Some(used),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn node_memory() -> Option<MemoryStats> {
+    None
+}
+
+/// Returns the number of ticks spent doing work (rather than idling or waiting on IO) from the
+/// aggregate `cpu` line of `/proc/stat`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    let line = stat.lines().find(|l| l.starts_with("cpu "))?;
+    let fields = line
+        .split_whitespace()
+        .skip(1)
+        .map(|f| f.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    // Fields are user, nice, system, idle, iowait, irq, softirq, steal, ...
+    let busy = fields
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 3 && *i != 4)
+        .take(6)
+        .map(|(_, v)| v)
+        .sum();
+    Some(busy)
+}
+
+/// Returns the total and available memory in bytes from `/proc/meminfo`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_meminfo(meminfo: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| -> Option<u64> {
+        let line = meminfo.lines().find(|l| l.starts_with(name))?;
+        let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kb * 1024)
+    };
+    Some((field("MemTotal:")?, field("MemAvailable:")?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn can_parse_cpu_ticks() {
+        let stat = "cpu  100 10 50 1000 20 5 5 0 0 0\ncpu0 50 5 25 500 10 2 3 0 0 0\n";
+        assert_eq!(parse_cpu_ticks(stat), Some(170));
+    }
+
+    #[test]
+    fn can_parse_meminfo() {
+        let meminfo =
+            "MemTotal:        2048 kB\nMemFree:          512 kB\nMemAvailable:     1024 kB\n";
+        assert_eq!(parse_meminfo(meminfo), Some((2048 * 1024, 1024 * 1024)));
+    }
+
+    #[test]
+    fn summary_serializes_to_camel_case() {
+        let summary = Summary {
+            node: NodeStats {
+                node_name: "krustlet".to_owned(),
+                ..Default::default()
+            },
+            pods: vec![PodStats {
+                pod_ref: PodReference {
+                    name: "foo".to_owned(),
+                    namespace: "default".to_owned(),
+                    uid: "1234".to_owned(),
+                },
+                ..Default::default()
+            }],
+        };
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["node"]["nodeName"], "krustlet");
+        assert_eq!(json["pods"][0]["podRef"]["uid"], "1234");
+        assert!(json["node"].get("cpu").is_none());
+    }
+}
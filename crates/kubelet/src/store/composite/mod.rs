@@ -73,6 +73,14 @@ impl Store for CompositeStore {
             self.base.get(image_ref, pull_policy, auth).await
         }
     }
+
+    async fn digest(&self, image_ref: &Reference) -> Option<String> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.digest(image_ref).await
+        } else {
+            self.base.digest(image_ref).await
+        }
+    }
 }
 
 #[cfg(test)]
@@ -2,21 +2,26 @@
 pub mod composite;
 pub mod fs;
 pub mod oci;
+pub mod signature;
 
 use oci_distribution::client::ImageData;
 use oci_distribution::secrets::RegistryAuth;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 
+use anyhow::Context;
 use async_trait::async_trait;
-use log::debug;
 use oci_distribution::Reference;
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
 
 use crate::container::PullPolicy;
-use crate::pod::Pod;
+use crate::pod::{record_event, EventType, Pod};
 use crate::store::oci::Client;
+use crate::store::signature::{VerificationError, Verifier};
 
 /// A store of container modules.
 ///
@@ -59,10 +64,20 @@ pub trait Store: Sync {
         auth: &RegistryAuth,
     ) -> anyhow::Result<Vec<u8>>;
 
+    /// The digest of the image that the module for `image_ref` was last fetched from, if it is
+    /// known.
+    ///
+    /// The default implementation doesn't know the digests of images.
+    async fn digest(&self, _image_ref: &Reference) -> Option<String> {
+        None
+    }
+
     /// Fetch all container modules for a given `Pod` storing the name of the
     /// container and the module's data as key/value pairs in a hashmap.
     ///
-    /// This will fetch all of the container modules in parallel.
+    /// This will fetch all of the container modules in parallel, recording
+    /// `Pulling` and `Pulled` events on the pod for each image, as the
+    /// Kubernetes kubelet does.
     ///
     /// # Panics
     ///
@@ -71,6 +86,7 @@ pub trait Store: Sync {
         &self,
         pod: &Pod,
         auth: &crate::secret::RegistryAuthResolver,
+        client: &kube::Client,
     ) -> anyhow::Result<HashMap<String, Vec<u8>>> {
         debug!(
             "Fetching all the container modules for pod '{}'",
@@ -88,11 +104,34 @@ pub trait Store: Sync {
                 .expect("Could not identify pull policy.");
             async move {
                 let registry_authentication = auth.resolve_registry_auth(&reference).await?;
-                Ok((
-                    container.name().to_string(),
-                    self.get(&reference, pull_policy, &registry_authentication)
-                        .await?,
-                ))
+                let message = format!("Pulling image \"{}\"", reference);
+                record_pull_event(client, pod, "Pulling", &message).await;
+                let start = Instant::now();
+                let credentials = match registry_authentication {
+                    RegistryAuth::Anonymous => {
+                        "anonymously, as none of the pod's image pull secrets has credentials for it"
+                    }
+                    _ => "using credentials from the pod's image pull secrets",
+                };
+                let module = self
+                    .get(&reference, pull_policy, &registry_authentication)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "failed to pull image {} from registry {} {}",
+                            reference,
+                            reference.registry(),
+                            credentials
+                        )
+                    })?;
+                let message = format!(
+                    "Successfully pulled image \"{}\" in {:.3}s ({} bytes)",
+                    reference,
+                    start.elapsed().as_secs_f64(),
+                    module.len()
+                );
+                record_pull_event(client, pod, "Pulled", &message).await;
+                Ok((container.name().to_string(), module))
             }
         });
 
@@ -104,14 +143,57 @@ pub trait Store: Sync {
     }
 }
 
+/// The image ID a container running the module fetched for `image_ref` is reported with. This is
+/// the image's repository and `digest` if the digest of the image is known, as the Kubernetes
+/// kubelet reports it, and the digest of the module itself otherwise.
+pub fn image_id(image_ref: &Reference, digest: Option<&str>, module: &[u8]) -> String {
+    match digest {
+        Some(digest) if image_ref.registry().is_empty() => {
+            format!("{}@{}", image_ref.repository(), digest)
+        }
+        Some(digest) => format!(
+            "{}/{}@{}",
+            image_ref.registry(),
+            image_ref.repository(),
+            digest
+        ),
+        None => format!("sha256:{:x}", Sha256::digest(module)),
+    }
+}
+
+/// Record a normal event about pulling a pod's images, logging if it can't be recorded.
+async fn record_pull_event(client: &kube::Client, pod: &Pod, reason: &str, message: &str) {
+    debug!("Pod {}: {}", pod.name(), message);
+    if let Err(e) = record_event(client, pod, EventType::Normal, reason, message).await {
+        warn!(
+            "Unable to record {} event for pod {}: {:?}",
+            reason,
+            pod.name(),
+            e
+        );
+    }
+}
+
 /// A `Store` implementation which obtains module data from remote registries
 /// but caches it in local storage.
+///
+/// If the store has a [`Verifier`], every image is checked by it before its module is returned,
+/// whether the module was pulled or cached.
 pub struct LocalStore<S: Storer, C: Client> {
     storer: Arc<RwLock<S>>,
     client: Arc<Mutex<C>>,
+    verifier: Option<Arc<dyn Verifier>>,
 }
 
 impl<S: Storer, C: Client> LocalStore<S, C> {
+    /// Check every image with `verifier` before returning its module
+    pub fn with_verifier(self, verifier: Arc<dyn Verifier>) -> Self {
+        Self {
+            verifier: Some(verifier),
+            ..self
+        }
+    }
+
     async fn pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
         debug!("Pulling image ref '{:?}' from registry", image_ref);
         let image_data = self.client.lock().await.pull(image_ref, auth).await?;
@@ -122,22 +204,17 @@ impl<S: Storer, C: Client> LocalStore<S, C> {
             .await?;
         Ok(())
     }
-}
 
-#[async_trait]
-impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C> {
-    async fn get(
+    /// Get a module, pulling it first if the pull policy calls for it, along with the digest of
+    /// the image it was pulled from.
+    async fn get_module(
         &self,
         image_ref: &Reference,
         pull_policy: PullPolicy,
         auth: &RegistryAuth,
-    ) -> anyhow::Result<Vec<u8>> {
-        match pull_policy {
-            PullPolicy::IfNotPresent => {
-                if !self.storer.read().await.is_present(image_ref).await {
-                    self.pull(image_ref, auth).await?
-                }
-            }
+    ) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+        let cached = match pull_policy {
+            PullPolicy::IfNotPresent => self.storer.read().await.is_present(image_ref).await,
             PullPolicy::Always => {
                 let digest = self
                     .client
@@ -145,20 +222,71 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
                     .await
                     .fetch_digest(image_ref, auth)
                     .await?;
-                let already_got_with_digest = self
-                    .storer
+                self.storer
                     .read()
                     .await
                     .is_present_with_digest(image_ref, digest)
-                    .await;
-                if !already_got_with_digest {
-                    self.pull(image_ref, auth).await?
-                }
+                    .await
             }
-            PullPolicy::Never => (),
+            PullPolicy::Never => true,
         };
 
-        self.storer.read().await.get_local(image_ref).await
+        if cached {
+            match self
+                .storer
+                .read()
+                .await
+                .get_local_with_digest(image_ref)
+                .await
+            {
+                Ok(module) => return Ok(module),
+                Err(e) if pull_policy != PullPolicy::Never => {
+                    warn!(
+                        "Unable to use cached module for {}, pulling it again: {:?}",
+                        image_ref, e
+                    )
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.pull(image_ref, auth).await?;
+        self.storer
+            .read()
+            .await
+            .get_local_with_digest(image_ref)
+            .await
+    }
+}
+
+#[async_trait]
+impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C> {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<Vec<u8>> {
+        // The module and its digest are read together, so that a pull of the same image in between
+        // can't swap the module for one the digest doesn't describe
+        let (module, digest) = self.get_module(image_ref, pull_policy, auth).await?;
+        if let Some(verifier) = &self.verifier {
+            let verification = match digest {
+                Some(digest) => verifier.verify(image_ref, &digest, auth).await,
+                None => Err(anyhow::anyhow!("the image's digest is not known")),
+            };
+            if let Err(e) = verification {
+                return Err(VerificationError {
+                    image: image_ref.to_string(),
+                    reason: format!("{:#}", e),
+                }
+                .into());
+            }
+        }
+        Ok(module)
+    }
+
+    async fn digest(&self, image_ref: &Reference) -> Option<String> {
+        self.storer.read().await.digest(image_ref).await
     }
 }
 
@@ -172,13 +300,54 @@ pub trait Storer {
     /// Get a module's data from the backing store given its image `Reference`.
     ///
     /// The implementation must fail if the image is not present
-    /// locally. `Storer` handles only reading and writing its own backing store;
-    /// remote fetch is handled at the `Store` level.
+    /// locally, or if its local data is no longer valid. `Storer` handles
+    /// only reading and writing its own backing store; remote fetch is
+    /// handled at the `Store` level.
     async fn get_local(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>>;
 
     /// Whether the specified module is already present in the backing store.
     async fn is_present(&self, image_ref: &Reference) -> bool;
 
+    /// Get a module's data from the backing store along with the digest of the image it was
+    /// pulled from, as [`Storer::get_local`] and [`Storer::digest`] do.
+    ///
+    /// Callers hold the lock on the `Storer` across both reads, so the module can't be replaced
+    /// in between and the digest always describes the module returned.
+    async fn get_local_with_digest(
+        &self,
+        image_ref: &Reference,
+    ) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+        let module = self.get_local(image_ref).await?;
+        Ok((module, self.digest(image_ref).await))
+    }
+
     /// Whether the specified module is already present in the backing store with the specified digest.
     async fn is_present_with_digest(&self, image_ref: &Reference, digest: String) -> bool;
+
+    /// The digest of the image that the stored module for the specified image `Reference` was
+    /// pulled from, if it is known.
+    ///
+    /// The default implementation doesn't record digests, so images can't be verified.
+    async fn digest(&self, _image_ref: &Reference) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn image_id_names_digest_of_image_or_module() {
+        let image_ref = Reference::try_from("webassembly.azurecr.io/hello:v1").unwrap();
+        assert_eq!(
+            image_id(&image_ref, Some("sha256:abc"), b""),
+            "webassembly.azurecr.io/hello@sha256:abc"
+        );
+        assert_eq!(
+            image_id(&image_ref, None, b""),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
 }
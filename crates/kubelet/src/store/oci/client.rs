@@ -59,8 +59,15 @@ pub trait Client {
 #[async_trait]
 impl Client for oci_distribution::Client {
     async fn pull(&mut self, image: &Reference, auth: &RegistryAuth) -> anyhow::Result<ImageData> {
-        self.pull(image, auth, vec![manifest::WASM_LAYER_MEDIA_TYPE])
-            .await
+        self.pull(
+            image,
+            auth,
+            vec![
+                manifest::WASM_LAYER_MEDIA_TYPE,
+                manifest::WASM_MODULE_MEDIA_TYPE,
+            ],
+        )
+        .await
     }
 
     async fn fetch_digest(
@@ -1,19 +1,36 @@
 use crate::store::Storer;
 use oci_distribution::client::ImageData;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use log::debug;
+use chrono::{DateTime, Utc};
 use oci_distribution::Reference;
+use sha2::{Digest, Sha256};
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
 
 use super::client::Client;
 use crate::store::LocalStore;
 
+/// The directory under the store's root that holds module data, named by its digest.
+const BLOBS_DIR: &str = "blobs";
+/// The file recording which module an image reference resolved to.
+const MODULE_DIGEST_FILE: &str = "module.digest";
+/// The file recording the digest of the image an image reference was pulled as.
+const IMAGE_DIGEST_FILE: &str = "digest.txt";
+/// The file recording when an image reference's module was last read.
+const LAST_USED_FILE: &str = "last_used";
+
 /// A module store that keeps modules cached on the file system
 ///
+/// Each module is stored once, named by the SHA-256 digest of its data, and each image reference
+/// records the digest of the module it resolved to. Module data is checked against its digest
+/// whenever it is read. If the store has a size limit, the modules that were used least recently
+/// are removed whenever storing a new module takes it over the limit.
+///
 /// This type is generic over the type of client used
 /// to fetch modules from a remote store. This client is expected
 /// to be a [`Client`]
@@ -22,17 +39,29 @@ pub type FileStore<C> = LocalStore<FileStorer, C>;
 impl<C: Client + Send> FileStore<C> {
     /// Create a new `FileStore`
     pub fn new<T: AsRef<Path>>(client: C, root_dir: T) -> Self {
+        Self::from_storer(client, FileStorer::new(root_dir))
+    }
+
+    /// Create a new `FileStore` that keeps the modules it caches within `size_limit` bytes
+    pub fn with_size_limit<T: AsRef<Path>>(client: C, root_dir: T, size_limit: u64) -> Self {
+        Self::from_storer(
+            client,
+            FileStorer::new(root_dir).with_size_limit(size_limit),
+        )
+    }
+
+    fn from_storer(client: C, storer: FileStorer) -> Self {
         Self {
-            storer: Arc::new(RwLock::new(FileStorer {
-                root_dir: root_dir.as_ref().into(),
-            })),
+            storer: Arc::new(RwLock::new(storer)),
             client: Arc::new(Mutex::new(client)),
+            verifier: None,
         }
     }
 }
 
 pub struct FileStorer {
     root_dir: PathBuf,
+    size_limit: Option<u64>,
 }
 
 impl FileStorer {
@@ -40,70 +69,168 @@ impl FileStorer {
     pub fn new<T: AsRef<Path>>(root_dir: T) -> Self {
         Self {
             root_dir: root_dir.as_ref().into(),
+            size_limit: None,
+        }
+    }
+
+    /// Limit the total size of the modules the `FileStorer` keeps to `size_limit` bytes
+    pub fn with_size_limit(self, size_limit: u64) -> Self {
+        Self {
+            size_limit: Some(size_limit),
+            ..self
         }
     }
 
     fn pull_path(&self, r: &Reference) -> PathBuf {
         let mut path = self.root_dir.join(r.registry());
         path.push(r.repository());
-        path.push(r.tag().unwrap_or("latest"));
+        match (r.tag(), r.digest()) {
+            (Some(tag), _) => path.push(tag),
+            // Colons aren't allowed in file names on Windows
+            (None, Some(digest)) => path.push(digest.replace(':', "_")),
+            (None, None) => path.push("latest"),
+        }
         path
     }
 
-    fn pull_file_path(&self, r: &Reference) -> PathBuf {
-        self.pull_path(r).join("module.wasm")
+    fn module_digest_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join(MODULE_DIGEST_FILE)
     }
 
     fn digest_file_path(&self, r: &Reference) -> PathBuf {
-        self.pull_path(r).join("digest.txt")
+        self.pull_path(r).join(IMAGE_DIGEST_FILE)
+    }
+
+    fn last_used_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join(LAST_USED_FILE)
+    }
+
+    fn blob_path(&self, digest: &str) -> anyhow::Result<PathBuf> {
+        blob_path(&self.root_dir, digest)
+    }
+
+    /// The path of the module an image reference resolved to, if it has been stored.
+    async fn module_path(&self, image_ref: &Reference) -> Option<(String, PathBuf)> {
+        let digest = tokio::fs::read_to_string(self.module_digest_file_path(image_ref))
+            .await
+            .ok()?;
+        let path = self.blob_path(digest.trim()).ok()?;
+        Some((digest.trim().to_owned(), path))
+    }
+
+    /// Forget which module an image reference resolved to, so that it is pulled again.
+    async fn forget(&self, image_ref: &Reference) {
+        for path in &[
+            self.module_digest_file_path(image_ref),
+            self.digest_file_path(image_ref),
+        ] {
+            if let Err(e) = tokio::fs::remove_file(path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Unable to remove {:?}: {:?}", path, e);
+                }
+            }
+        }
     }
 }
 
 #[async_trait]
 impl Storer for FileStorer {
     async fn get_local(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
-        let path = self.pull_file_path(image_ref);
+        let not_available = || anyhow::anyhow!("Image ref {} not available locally", image_ref);
+        let (digest, path) = self
+            .module_path(image_ref)
+            .await
+            .ok_or_else(not_available)?;
         if !path.exists() {
-            return Err(anyhow::anyhow!(
-                "Image ref {} not available locally",
-                image_ref
-            ));
+            return Err(not_available());
         }
 
         debug!("Fetching image ref '{:?}' from disk", image_ref);
-        Ok(tokio::fs::read(path).await?)
+        let data = tokio::fs::read(&path).await?;
+        if sha256_digest(&data) != digest {
+            // Make sure the module is pulled again rather than read from disk
+            self.forget(image_ref).await;
+            tokio::fs::remove_file(&path).await.ok();
+            return Err(anyhow::anyhow!(
+                "Module for image ref {} does not match its digest {}",
+                image_ref,
+                digest
+            ));
+        }
+        if let Err(e) =
+            tokio::fs::write(self.last_used_file_path(image_ref), Utc::now().to_rfc3339()).await
+        {
+            warn!("Unable to record use of image ref {}: {:?}", image_ref, e);
+        }
+        Ok(data)
     }
+
     async fn store(&mut self, image_ref: &Reference, image_data: ImageData) -> anyhow::Result<()> {
+        // FIXME: we need to determine the proper file path for each layer rather than assuming it's a single-layer image.
+        let module = match image_data.layers.first() {
+            Some(layer) => &layer.data,
+            None => return Err(anyhow::anyhow!("No module layer present in image data")),
+        };
+        let module_digest = sha256_digest(module);
+        let blob_path = self.blob_path(&module_digest)?;
+        if let Some(parent) = blob_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if !file_content_is_digest(&blob_path, &module_digest).await {
+            // Write the module somewhere else first, so that a module is never read half written
+            let temp_path = blob_path.with_extension("tmp");
+            tokio::fs::write(&temp_path, module).await?;
+            tokio::fs::rename(&temp_path, &blob_path).await?;
+        }
+
         tokio::fs::create_dir_all(self.pull_path(image_ref)).await?;
         let digest_path = self.digest_file_path(image_ref);
-        // We delete the digest file before writing the image file, rather
-        // than simply overwriting the digest file after writing the image file.
-        // This addresses failure modes where, for example, the image file
+        // We delete the digest file before writing the module digest file, rather
+        // than simply overwriting the digest file after writing the module digest file.
+        // This addresses failure modes where, for example, the module digest file
         // gets updated but the digest file write fails and the store ends
-        // up associating the wrong digest with the file on disk.
+        // up associating the wrong digest with the module on disk.
         if digest_path.exists() {
             tokio::fs::remove_file(&digest_path).await?;
         }
-        // FIXME: we need to determine the proper file path for each layer rather than assuming it's a single-layer image.
-        let module_path = self.pull_file_path(image_ref);
-        if image_data.layers.is_empty() {
-            return Err(anyhow::anyhow!("No module layer present in image data"));
-        }
-        tokio::fs::write(&module_path, &image_data.layers[0].data).await?;
+        tokio::fs::write(self.module_digest_file_path(image_ref), &module_digest).await?;
+        tokio::fs::write(self.last_used_file_path(image_ref), Utc::now().to_rfc3339()).await?;
         if let Some(d) = image_data.digest {
             tokio::fs::write(&digest_path, d).await?;
         }
+
+        if let Some(size_limit) = self.size_limit {
+            let root_dir = self.root_dir.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                collect_garbage(&root_dir, size_limit, &module_digest)
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => warn!("Unable to remove unused modules: {:?}", e),
+                Err(e) => warn!("Unable to remove unused modules: {:?}", e),
+            }
+        }
         Ok(())
     }
 
     async fn is_present(&self, image_ref: &Reference) -> bool {
-        let path = self.pull_file_path(image_ref);
-        path.exists()
+        match self.module_path(image_ref).await {
+            Some((_, path)) => path.exists(),
+            None => false,
+        }
     }
 
     async fn is_present_with_digest(&self, image_ref: &Reference, digest: String) -> bool {
         let path = self.digest_file_path(image_ref);
-        path.exists() && file_content_is(path, digest).await
+        path.exists() && file_content_is(path, digest).await && self.is_present(image_ref).await
+    }
+
+    async fn digest(&self, image_ref: &Reference) -> Option<String> {
+        let digest = tokio::fs::read_to_string(self.digest_file_path(image_ref))
+            .await
+            .ok()?;
+        Some(digest.trim().to_owned())
     }
 }
 
@@ -112,6 +239,7 @@ impl<C: Client + Send> Clone for FileStore<C> {
         Self {
             storer: self.storer.clone(),
             client: self.client.clone(),
+            verifier: self.verifier.clone(),
         }
     }
 }
@@ -126,6 +254,118 @@ async fn file_content_is(path: PathBuf, text: String) -> bool {
     }
 }
 
+async fn file_content_is_digest(path: &Path, digest: &str) -> bool {
+    match tokio::fs::read(path).await {
+        Err(_) => false,
+        Ok(content) => sha256_digest(&content) == digest,
+    }
+}
+
+fn sha256_digest(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(bytes))
+}
+
+fn blob_path(root_dir: &Path, digest: &str) -> anyhow::Result<PathBuf> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(root_dir.join(BLOBS_DIR).join("sha256").join(hex))
+        }
+        _ => Err(anyhow::anyhow!("invalid module digest {:?}", digest)),
+    }
+}
+
+/// An image reference's record of the module it resolved to.
+struct ModuleRef {
+    dir: PathBuf,
+    digest: String,
+    last_used: DateTime<Utc>,
+}
+
+/// Remove the modules that were used least recently until the store is within its size limit.
+/// Modules that no image reference resolves to any more go first. The module that was just
+/// stored is kept, even if it is over the limit by itself.
+fn collect_garbage(root_dir: &Path, size_limit: u64, keep: &str) -> anyhow::Result<()> {
+    let mut refs = vec![];
+    find_module_refs(root_dir, &root_dir.join(BLOBS_DIR), &mut refs)?;
+    let mut last_used: HashMap<&str, DateTime<Utc>> = HashMap::new();
+    for module_ref in &refs {
+        let entry = last_used
+            .entry(&module_ref.digest)
+            .or_insert(module_ref.last_used);
+        *entry = (*entry).max(module_ref.last_used);
+    }
+
+    let blobs_dir = root_dir.join(BLOBS_DIR).join("sha256");
+    let mut blobs = vec![];
+    if blobs_dir.exists() {
+        for entry in std::fs::read_dir(&blobs_dir)? {
+            let entry = entry?;
+            let digest = format!("sha256:{}", entry.file_name().to_string_lossy());
+            blobs.push((digest, entry.metadata()?.len()));
+        }
+    }
+    let mut total: u64 = blobs.iter().map(|(_, size)| size).sum();
+    if total <= size_limit {
+        return Ok(());
+    }
+    blobs.sort_by_key(|(digest, _)| {
+        last_used
+            .get(digest.as_str())
+            .copied()
+            .unwrap_or(chrono::MIN_DATETIME)
+    });
+
+    for (digest, size) in blobs {
+        if total <= size_limit {
+            break;
+        }
+        if digest == keep {
+            continue;
+        }
+        for module_ref in refs.iter().filter(|r| r.digest == digest) {
+            for file in &[MODULE_DIGEST_FILE, IMAGE_DIGEST_FILE, LAST_USED_FILE] {
+                match std::fs::remove_file(module_ref.dir.join(file)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
+        }
+        std::fs::remove_file(blobs_dir.join(digest.trim_start_matches("sha256:")))?;
+        info!(
+            "Removed module {} to keep the module store within {} bytes",
+            digest, size_limit
+        );
+        total -= size;
+    }
+    Ok(())
+}
+
+/// Find the image references under a directory that have been resolved to modules.
+fn find_module_refs(dir: &Path, blobs_dir: &Path, refs: &mut Vec<ModuleRef>) -> anyhow::Result<()> {
+    let module_digest_path = dir.join(MODULE_DIGEST_FILE);
+    if module_digest_path.exists() {
+        let last_used = std::fs::read_to_string(dir.join(LAST_USED_FILE))
+            .ok()
+            .and_then(|last_used| DateTime::parse_from_rfc3339(last_used.trim()).ok())
+            .map(|last_used| last_used.with_timezone(&Utc))
+            .unwrap_or(chrono::MIN_DATETIME);
+        refs.push(ModuleRef {
+            dir: dir.to_owned(),
+            digest: std::fs::read_to_string(module_digest_path)?
+                .trim()
+                .to_owned(),
+            last_used,
+        });
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && path != blobs_dir {
+            find_module_refs(&path, blobs_dir, refs)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -434,4 +674,128 @@ mod test {
         assert_eq!(6, module_bytes_after[1]);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn file_module_store_pulls_again_if_cached_module_is_corrupt() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        store
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await?;
+        let blobs_dir = scratch_dir.path.join(BLOBS_DIR).join("sha256");
+        for entry in std::fs::read_dir(&blobs_dir)? {
+            std::fs::write(entry?.path(), vec![9, 9, 9])?;
+        }
+        let module_bytes = store
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_stores_identical_modules_once() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![
+            ("foo/bar:1.0", vec![1, 2, 3], "sha256:123"),
+            ("foo/bar:latest", vec![1, 2, 3], "sha256:123"),
+        ]);
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        for image in &["foo/bar:1.0", "foo/bar:latest"] {
+            store
+                .get(
+                    &Reference::try_from(*image)?,
+                    PullPolicy::IfNotPresent,
+                    &RegistryAuth::Anonymous,
+                )
+                .await?;
+        }
+        let blobs_dir = scratch_dir.path.join(BLOBS_DIR).join("sha256");
+        assert_eq!(1, std::fs::read_dir(&blobs_dir)?.count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_removes_least_recently_used_modules() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![
+            ("foo/bar:1.0", vec![1, 2, 3], "sha256:123"),
+            ("foo/baz:1.0", vec![4, 5, 6], "sha256:456"),
+            ("foo/qux:1.0", vec![7, 8, 9], "sha256:789"),
+        ]);
+        let bar = Reference::try_from("foo/bar:1.0")?;
+        let baz = Reference::try_from("foo/baz:1.0")?;
+        let qux = Reference::try_from("foo/qux:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::with_size_limit(fake_client, &scratch_dir.path, 6);
+        for image_ref in &[&bar, &baz, &bar, &qux] {
+            store
+                .get(
+                    image_ref,
+                    PullPolicy::IfNotPresent,
+                    &RegistryAuth::Anonymous,
+                )
+                .await?;
+        }
+        let storer = store.storer.read().await;
+        assert!(storer.is_present(&bar).await);
+        assert!(!storer.is_present(&baz).await);
+        assert!(storer.is_present(&qux).await);
+        Ok(())
+    }
+
+    /// Trusts only images with one digest.
+    struct DigestVerifier(&'static str);
+
+    #[async_trait]
+    impl crate::store::signature::Verifier for DigestVerifier {
+        async fn verify(
+            &self,
+            _image_ref: &Reference,
+            digest: &str,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<()> {
+            anyhow::ensure!(digest == self.0, "untrusted digest {}", digest);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn file_module_store_verifies_the_digest_of_the_module_it_returns() -> anyhow::Result<()>
+    {
+        let mut fake_client =
+            FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path)
+            .with_verifier(Arc::new(DigestVerifier("sha256:4567")));
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("module of an untrusted image should not be returned");
+
+        fake_client.update("foo/bar:1.0", vec![4, 5, 6, 7], "sha256:4567");
+        let module_bytes = store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        assert_eq!(vec![4, 5, 6, 7], module_bytes);
+        let (module, digest) = store
+            .storer
+            .read()
+            .await
+            .get_local_with_digest(&fake_ref)
+            .await?;
+        assert_eq!(vec![4, 5, 6, 7], module);
+        assert_eq!(Some("sha256:4567".to_owned()), digest);
+        Ok(())
+    }
 }
@@ -0,0 +1,276 @@
+//! Verification of image signatures before their modules are allowed to run.
+//!
+//! Signatures are checked the way [cosign](https://github.com/sigstore/cosign) stores them: as an
+//! image in the same repository, tagged with the digest of the signed image, whose layers are
+//! signed payloads naming that digest. Only ECDSA P-256 keys, which cosign generates, are
+//! supported.
+use std::path::Path;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// The media type of the layers cosign stores signed payloads in.
+const SIMPLE_SIGNING_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+/// The layer annotation cosign stores the base64 encoded signature of a payload in.
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+/// The DER encoded start of a P-256 public key, which is followed by the key as an uncompressed
+/// curve point.
+const P256_KEY_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// Checks that an image satisfies a policy before its module is allowed to run.
+#[async_trait]
+pub trait Verifier: Send + Sync {
+    /// Check the image with the given digest, failing if it doesn't satisfy the policy.
+    async fn verify(
+        &self,
+        image_ref: &Reference,
+        digest: &str,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<()>;
+}
+
+/// An image did not satisfy the verification policy, so its module must not be run.
+#[derive(Debug, Error)]
+#[error("image {} failed verification: {}", image, reason)]
+pub struct VerificationError {
+    /// The image that failed verification
+    pub image: String,
+    /// Why the image failed verification
+    pub reason: String,
+}
+
+/// A public key that images may be signed with.
+#[derive(Clone, Debug)]
+pub struct PublicKey {
+    /// The key as an uncompressed curve point
+    point: Vec<u8>,
+}
+
+impl PublicKey {
+    /// Parse a PEM encoded ECDSA P-256 public key, as generated by `cosign generate-key-pair`.
+    pub fn from_pem(pem: &str) -> anyhow::Result<Self> {
+        let encoded: String = pem
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("-----"))
+            .collect();
+        let der = base64::decode(&encoded).context("public key is not valid PEM")?;
+        if !der.starts_with(P256_KEY_PREFIX) || der.len() != P256_KEY_PREFIX.len() + 65 {
+            anyhow::bail!("public key is not an ECDSA P-256 key");
+        }
+        Ok(PublicKey {
+            point: der[P256_KEY_PREFIX.len()..].to_vec(),
+        })
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &self.point)
+            .verify(message, signature)
+            .is_ok()
+    }
+}
+
+/// A [`Verifier`] that only allows images that have a cosign signature by one of its keys.
+pub struct CosignVerifier {
+    client: Mutex<oci_distribution::Client>,
+    keys: Vec<PublicKey>,
+}
+
+impl CosignVerifier {
+    /// Create a verifier that trusts signatures by any of the given keys
+    pub fn new(client: oci_distribution::Client, keys: Vec<PublicKey>) -> Self {
+        CosignVerifier {
+            client: Mutex::new(client),
+            keys,
+        }
+    }
+
+    /// Create a verifier that trusts signatures by any of the keys in the given PEM files
+    pub fn from_key_files<P: AsRef<Path>>(
+        client: oci_distribution::Client,
+        paths: &[P],
+    ) -> anyhow::Result<Self> {
+        let keys = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                std::fs::read_to_string(path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|pem| PublicKey::from_pem(&pem))
+                    .with_context(|| format!("unable to load image verification key {:?}", path))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self::new(client, keys))
+    }
+
+    /// Whether a payload is signed by one of the trusted keys and names the given digest.
+    fn is_trusted(&self, payload: &[u8], signature: &str, digest: &str) -> bool {
+        let signature = match base64::decode(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        self.keys.iter().any(|key| key.verify(payload, &signature))
+            && signed_digest(payload).as_deref() == Some(digest)
+    }
+}
+
+#[async_trait]
+impl Verifier for CosignVerifier {
+    async fn verify(
+        &self,
+        image_ref: &Reference,
+        digest: &str,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<()> {
+        let signature_ref = signature_reference(image_ref, digest)?;
+        let mut client = self.client.lock().await;
+        let (manifest, _) = client
+            .pull_image_manifest(&signature_ref, auth)
+            .await
+            .with_context(|| format!("unable to fetch signatures from {}", signature_ref))?;
+        for layer in manifest
+            .layers
+            .iter()
+            .filter(|layer| layer.media_type == SIMPLE_SIGNING_MEDIA_TYPE)
+        {
+            let signature = match layer
+                .annotations
+                .as_ref()
+                .and_then(|annotations| annotations.get(SIGNATURE_ANNOTATION))
+            {
+                Some(signature) => signature,
+                None => continue,
+            };
+            let mut payload = Vec::new();
+            client
+                .pull_blob(&signature_ref, auth, &layer.digest, &mut payload)
+                .await?;
+            if self.is_trusted(&payload, signature, digest) {
+                debug!("Image {} has a trusted signature", image_ref);
+                return Ok(());
+            }
+        }
+        anyhow::bail!("image has no signature of {} by a trusted key", digest)
+    }
+}
+
+/// The reference cosign stores the signatures of the image with the given digest under.
+fn signature_reference(image_ref: &Reference, digest: &str) -> anyhow::Result<Reference> {
+    let reference = format!(
+        "{}/{}:{}.sig",
+        image_ref.registry(),
+        image_ref.repository(),
+        digest.replace(':', "-")
+    );
+    Ok(reference.parse()?)
+}
+
+/// The image digest a signed payload names.
+fn signed_digest(payload: &[u8]) -> Option<String> {
+    let payload: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    payload
+        .pointer("/critical/image/docker-manifest-digest")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    const DIGEST: &str = "sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7";
+
+    fn key_pair() -> (EcdsaKeyPair, PublicKey) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+        let mut der = P256_KEY_PREFIX.to_vec();
+        der.extend_from_slice(key_pair.public_key().as_ref());
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            base64::encode(&der)
+        );
+        (key_pair, PublicKey::from_pem(&pem).unwrap())
+    }
+
+    fn payload(digest: &str) -> Vec<u8> {
+        serde_json::json!({
+            "critical": {
+                "identity": { "docker-reference": "webassembly.azurecr.io/hello-wasm" },
+                "image": { "docker-manifest-digest": digest },
+                "type": "cosign container image signature"
+            },
+            "optional": null
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn sign(key_pair: &EcdsaKeyPair, payload: &[u8]) -> String {
+        let signature = key_pair.sign(&SystemRandom::new(), payload).unwrap();
+        base64::encode(signature.as_ref())
+    }
+
+    fn verifier(keys: Vec<PublicKey>) -> CosignVerifier {
+        CosignVerifier::new(oci_distribution::Client::default(), keys)
+    }
+
+    #[test]
+    fn signatures_by_trusted_keys_are_accepted() {
+        let (key_pair, key) = key_pair();
+        let payload = payload(DIGEST);
+        let signature = sign(&key_pair, &payload);
+        assert!(verifier(vec![key]).is_trusted(&payload, &signature, DIGEST));
+    }
+
+    #[test]
+    fn untrusted_or_mismatched_signatures_are_rejected() {
+        let (key_pair, key) = key_pair();
+        let (_, other_key) = key_pair();
+        let payload = payload(DIGEST);
+        let signature = sign(&key_pair, &payload);
+
+        assert!(!verifier(vec![other_key]).is_trusted(&payload, &signature, DIGEST));
+        assert!(!verifier(vec![]).is_trusted(&payload, &signature, DIGEST));
+        let verifier = verifier(vec![key]);
+        let other_digest = DIGEST.replace('5', "6");
+        assert!(!verifier.is_trusted(&payload, &signature, &other_digest));
+        let mut tampered = payload.clone();
+        tampered.push(b' ');
+        assert!(!verifier.is_trusted(&tampered, &signature, DIGEST));
+        assert!(!verifier.is_trusted(&payload, "not base64!", DIGEST));
+    }
+
+    #[test]
+    fn signature_reference_is_tagged_with_the_digest() {
+        let image: Reference = "webassembly.azurecr.io/hello-wasm:v1".parse().unwrap();
+        let reference = signature_reference(&image, DIGEST).unwrap();
+        assert_eq!(reference.registry(), "webassembly.azurecr.io");
+        assert_eq!(reference.repository(), "hello-wasm");
+        assert_eq!(
+            reference.tag(),
+            Some("sha256-51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7.sig")
+        );
+    }
+
+    #[test]
+    fn only_p256_keys_are_accepted() {
+        assert!(
+            PublicKey::from_pem("-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----")
+                .is_err()
+        );
+        assert!(PublicKey::from_pem("not a key").is_err());
+    }
+}
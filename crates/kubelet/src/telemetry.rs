@@ -0,0 +1,175 @@
+//! Structured logging and tracing of what the kubelet does.
+//!
+//! The kubelet logs through [`tracing`], and each pod it runs gets a span that lasts from when it
+//! is registered until it is deregistered. What is done for the pod, such as pulling its modules,
+//! running its containers, patching their statuses and serving exec, attach, log and port forward
+//! requests for it, happens in spans of its own under the pod's span, which carry the pod's name
+//! and namespace and, where there is one, the container's name. Use [`pod_span`] to get the span
+//! of a pod.
+//!
+//! [`init`] sets up the subscriber that records all this. Events are logged to stderr at the
+//! configured log level, or at the levels `RUST_LOG` gives when there is none. When the kubelet is
+//! built with the `otlp` feature and given an OTLP endpoint, spans are also exported to it, so
+//! that the lifecycle of a pod can be followed in a tracing backend such as Jaeger or Tempo.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tracing::level_filters::LevelFilter;
+use tracing::Span;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::config::Config;
+use crate::pod::{Pod, PodKey};
+
+lazy_static! {
+    /// Changes the level the subscriber set up by [`init`] records events at.
+    static ref LEVEL: Mutex<Option<reload::Handle<LevelFilter, Registry>>> = Mutex::new(None);
+    /// The spans of the pods that are registered.
+    static ref POD_SPANS: Mutex<HashMap<PodKey, Span>> = Mutex::new(HashMap::new());
+}
+
+/// Keeps spans being exported until it is dropped, which exports any that are still pending.
+/// Keep it for as long as the kubelet runs.
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    _exporter: Option<opentelemetry_otlp::Uninstall>,
+}
+
+/// Set up the global subscriber that logs events and exports spans, as configured. Events logged
+/// with the `log` crate are recorded too. This fails if a global subscriber has already been set,
+/// or if spans are to be exported and the exporter can't be set up.
+pub fn init(config: &Config) -> anyhow::Result<Telemetry> {
+    let env_filter = std::env::var_os("RUST_LOG").map(|_| EnvFilter::from_default_env());
+    // RUST_LOG can only narrow the configured level down, so it is the only limit when the level
+    // isn't configured
+    let level = match (config.log_level, &env_filter) {
+        (Some(level), _) => as_trace(level),
+        (None, Some(_)) => LevelFilter::TRACE,
+        (None, None) => LevelFilter::ERROR,
+    };
+    let (level_layer, level_handle) = reload::Layer::new(level);
+    let subscriber = tracing_subscriber::registry()
+        .with(level_layer)
+        .with(env_filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr));
+
+    #[cfg(feature = "otlp")]
+    let telemetry = {
+        let (exporter_layer, exporter) = match &config.otlp_endpoint {
+            Some(endpoint) => {
+                let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+                    .with_endpoint(endpoint)
+                    .with_trace_config(opentelemetry::sdk::trace::config().with_resource(
+                        opentelemetry::sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                            "service.name",
+                            "krustlet",
+                        )]),
+                    ))
+                    .install()?;
+                (
+                    Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+                    Some(uninstall),
+                )
+            }
+            None => (None, None),
+        };
+        subscriber.with(exporter_layer).try_init()?;
+        Telemetry {
+            _exporter: exporter,
+        }
+    };
+    #[cfg(not(feature = "otlp"))]
+    let telemetry = {
+        if config.otlp_endpoint.is_some() {
+            anyhow::bail!("Unable to export spans, krustlet was built without the otlp feature");
+        }
+        subscriber.try_init()?;
+        Telemetry {}
+    };
+
+    log::set_max_level(level_to_log(level));
+    *LEVEL.lock().unwrap() = Some(level_handle);
+    Ok(telemetry)
+}
+
+/// Change the most verbose level that events are recorded at. This does nothing unless the
+/// subscriber was set up with [`init`], other than for events logged with the `log` crate.
+pub fn set_level(level: log::LevelFilter) {
+    log::set_max_level(level);
+    if let Some(handle) = LEVEL.lock().unwrap().as_ref() {
+        if let Err(e) = handle.reload(as_trace(level)) {
+            tracing::warn!("Unable to change the log level to {}: {}", level, e);
+        }
+    }
+}
+
+/// The span of a registered pod, which what is done for the pod should be traced under. Pods that
+/// aren't registered have no span, in which case spans made under it have no parent.
+pub fn pod_span<N: AsRef<str>, T: AsRef<str>>(namespace: N, pod_name: T) -> Span {
+    POD_SPANS
+        .lock()
+        .unwrap()
+        .get(&PodKey::new(namespace, pod_name))
+        .cloned()
+        .unwrap_or_else(Span::none)
+}
+
+/// Start the span of a pod that is being registered.
+pub(crate) fn start_pod_span(pod: &Pod) {
+    let span = tracing::info_span!(
+        parent: None,
+        "pod",
+        pod = pod.name(),
+        namespace = pod.namespace()
+    );
+    POD_SPANS.lock().unwrap().insert(PodKey::from(pod), span);
+}
+
+/// End the span of a pod that has been deregistered. It is closed once the spans under it are.
+pub(crate) fn end_pod_span(pod: &Pod) {
+    POD_SPANS.lock().unwrap().remove(&PodKey::from(pod));
+}
+
+fn as_trace(level: log::LevelFilter) -> LevelFilter {
+    match level {
+        log::LevelFilter::Off => LevelFilter::OFF,
+        log::LevelFilter::Error => LevelFilter::ERROR,
+        log::LevelFilter::Warn => LevelFilter::WARN,
+        log::LevelFilter::Info => LevelFilter::INFO,
+        log::LevelFilter::Debug => LevelFilter::DEBUG,
+        log::LevelFilter::Trace => LevelFilter::TRACE,
+    }
+}
+
+fn level_to_log(level: LevelFilter) -> log::LevelFilter {
+    match level.into_level() {
+        None => log::LevelFilter::Off,
+        Some(tracing::Level::ERROR) => log::LevelFilter::Error,
+        Some(tracing::Level::WARN) => log::LevelFilter::Warn,
+        Some(tracing::Level::INFO) => log::LevelFilter::Info,
+        Some(tracing::Level::DEBUG) => log::LevelFilter::Debug,
+        Some(tracing::Level::TRACE) => log::LevelFilter::Trace,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels_convert_both_ways() {
+        for level in &[
+            log::LevelFilter::Off,
+            log::LevelFilter::Error,
+            log::LevelFilter::Warn,
+            log::LevelFilter::Info,
+            log::LevelFilter::Debug,
+            log::LevelFilter::Trace,
+        ] {
+            assert_eq!(level_to_log(as_trace(*level)), *level);
+        }
+    }
+}
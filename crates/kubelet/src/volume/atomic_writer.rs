@@ -0,0 +1,220 @@
+//! Writes the files of a projected volume so that a module reading the volume always sees a
+//! complete set of files, even while they are being updated.
+//!
+//! This follows the layout the Kubernetes kubelet uses. The files are written to a new,
+//! timestamped directory inside the volume, and a `..data` symlink is swapped to point at it in a
+//! single rename. Each file or directory at the top level of the volume is a symlink through
+//! `..data`, so all of them change at once when `..data` does.
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// The symlink that points at the directory holding the current files.
+const DATA_DIR_NAME: &str = "..data";
+/// The symlink that is renamed over `..data` to switch to a new set of files.
+const NEW_DATA_DIR_NAME: &str = "..data_tmp";
+
+/// The contents of a volume, as file paths relative to the volume and the data of each file.
+pub(super) type Payload = HashMap<PathBuf, Vec<u8>>;
+
+/// Write the payload to the volume directory, replacing any files that were written before.
+pub(super) fn write(dir: &Path, payload: &Payload) -> io::Result<()> {
+    for path in payload.keys() {
+        validate(path)?;
+    }
+    std::fs::create_dir_all(dir)?;
+    let old_data_dir = std::fs::read_link(dir.join(DATA_DIR_NAME)).ok();
+
+    let data_dir_name = format!("..{}", chrono::Utc::now().format("%Y_%m_%d_%H_%M_%S.%f"));
+    let data_dir = dir.join(&data_dir_name);
+    std::fs::create_dir(&data_dir)?;
+    for (path, data) in payload {
+        let file_path = data_dir.join(path);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(file_path, data)?;
+    }
+
+    let new_data_link = dir.join(NEW_DATA_DIR_NAME);
+    remove_symlink(&new_data_link).or_else(ignore_not_found)?;
+    symlink_dir(Path::new(&data_dir_name), &new_data_link)?;
+    swap_symlink(&new_data_link, &dir.join(DATA_DIR_NAME))?;
+
+    let top_level: HashSet<OsString> = payload
+        .keys()
+        .filter_map(|path| path.components().next())
+        .map(|component| component.as_os_str().to_owned())
+        .collect();
+    for name in &top_level {
+        let link = dir.join(name);
+        if std::fs::symlink_metadata(&link).is_err() {
+            symlink(
+                &Path::new(DATA_DIR_NAME).join(name),
+                &link,
+                &data_dir.join(name),
+            )?;
+        }
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !name.to_string_lossy().starts_with("..") && !top_level.contains(&name) {
+            remove_symlink(&entry.path())?;
+        }
+    }
+
+    if let Some(old_data_dir) = old_data_dir {
+        if old_data_dir != Path::new(&data_dir_name) {
+            std::fs::remove_dir_all(dir.join(old_data_dir)).or_else(ignore_not_found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check that a path stays inside the volume and can't be mistaken for one of the writer's own
+/// entries.
+fn validate(path: &Path) -> io::Result<()> {
+    let invalid = |reason: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid volume path {:?}: {}", path, reason),
+        ))
+    };
+    if path.as_os_str().is_empty() {
+        return invalid("path is empty");
+    }
+    if path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return invalid("path must be relative and must not contain '..'");
+    }
+    if path.to_string_lossy().starts_with("..") {
+        return invalid("path must not start with '..'");
+    }
+    Ok(())
+}
+
+fn ignore_not_found(e: io::Error) -> io::Result<()> {
+    if e.kind() == io::ErrorKind::NotFound {
+        Ok(())
+    } else {
+        Err(e)
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn symlink(src: &Path, dst: &Path, _target: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
+
+#[cfg(target_family = "windows")]
+fn symlink(src: &Path, dst: &Path, target: &Path) -> io::Result<()> {
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(src, dst)
+    } else {
+        std::os::windows::fs::symlink_file(src, dst)
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn symlink_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
+
+#[cfg(target_family = "windows")]
+fn symlink_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_dir(src, dst)
+}
+
+#[cfg(target_family = "unix")]
+fn remove_symlink(path: &Path) -> io::Result<()> {
+    std::fs::remove_file(path)
+}
+
+#[cfg(target_family = "windows")]
+fn remove_symlink(path: &Path) -> io::Result<()> {
+    // Directory symlinks are removed as directories on Windows
+    std::fs::remove_file(path).or_else(|_| std::fs::remove_dir(path))
+}
+
+/// Move the `from` symlink over the `to` symlink. This is atomic on Unix, where rename replaces
+/// the existing link. Windows can't rename over a directory symlink, so there is a moment there
+/// where the volume has no files.
+#[cfg(target_family = "unix")]
+fn swap_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::rename(from, to)
+}
+
+#[cfg(target_family = "windows")]
+fn swap_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    remove_symlink(to).or_else(ignore_not_found)?;
+    std::fs::rename(from, to)
+}
+
+// Creating symlinks needs extra privileges on Windows
+#[cfg(all(test, target_family = "unix"))]
+mod test {
+    use super::*;
+
+    fn payload(files: &[(&str, &str)]) -> Payload {
+        files
+            .iter()
+            .map(|(path, data)| (PathBuf::from(path), data.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn files_are_written_through_the_data_link() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            &payload(&[("config.toml", "a = 1"), ("nested/key", "value")]),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("config.toml")).unwrap(),
+            "a = 1"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("nested/key")).unwrap(),
+            "value"
+        );
+        assert!(std::fs::symlink_metadata(dir.path().join("config.toml"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+
+    #[test]
+    fn updates_replace_the_previous_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), &payload(&[("old", "1"), ("kept", "1")])).unwrap();
+        let old_data_dir = std::fs::read_link(dir.path().join(DATA_DIR_NAME)).unwrap();
+        // Make sure the timestamped directories have different names
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write(dir.path(), &payload(&[("kept", "2"), ("new", "2")])).unwrap();
+
+        assert!(!dir.path().join("old").exists());
+        assert!(!dir.path().join(old_data_dir).exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("kept")).unwrap(),
+            "2"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("new")).unwrap(),
+            "2"
+        );
+        assert!(!dir.path().join(NEW_DATA_DIR_NAME).exists());
+    }
+
+    #[test]
+    fn paths_outside_the_volume_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(write(dir.path(), &payload(&[("../escape", "")])).is_err());
+        assert!(write(dir.path(), &payload(&[("/etc/passwd", "")])).is_err());
+        assert!(write(dir.path(), &payload(&[("..data", "")])).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+//! Persistent volume claims backed by CSI drivers. The drivers are found through the
+//! [`PluginRegistry`], and volumes are mounted by calling the driver's node service, staging them
+//! first if the driver supports it.
+//!
+//! Only drivers that don't need their volumes attached to the node by a controller are supported,
+//! as nothing waits for the volume to be attached or passes on its publish context.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use k8s_openapi::api::core::v1::{
+    PersistentVolume, PersistentVolumeClaim, PersistentVolumeClaimVolumeSource, Secret,
+    SecretReference,
+};
+use k8s_openapi::ByteString;
+use kube::api::Api;
+use lazy_static::lazy_static;
+use tracing::{debug, error};
+
+use crate::csi_api::v1::node_client::NodeClient;
+use crate::csi_api::v1::node_service_capability::{self, rpc};
+use crate::csi_api::v1::volume_capability::{access_mode, AccessMode, AccessType, MountVolume};
+use crate::csi_api::v1::{
+    NodeGetCapabilitiesRequest, NodePublishVolumeRequest, NodeStageVolumeRequest,
+    NodeUnpublishVolumeRequest, NodeUnstageVolumeRequest, VolumeCapability,
+};
+use crate::grpc_sock;
+use crate::plugin_watcher::PluginRegistry;
+use crate::pod::Pod;
+
+lazy_static! {
+    /// The number of pods using each staged volume, by staging path, so volumes are only unstaged
+    /// once no pod on the node uses them.
+    static ref STAGED_VOLUMES: Mutex<HashMap<PathBuf, usize>> = Mutex::new(HashMap::new());
+}
+
+/// A CSI volume that has been published for a pod.
+#[derive(Clone, Debug)]
+pub(super) struct Published {
+    endpoint: PathBuf,
+    volume_id: String,
+    target_path: PathBuf,
+    staging_path: Option<PathBuf>,
+}
+
+/// Publish the persistent volume bound to a claim at the target path.
+pub(super) async fn publish(
+    claim: &PersistentVolumeClaimVolumeSource,
+    pod: &Pod,
+    client: &kube::Client,
+    plugin_registry: Option<&PluginRegistry>,
+    volume_dir: &Path,
+    target_path: &Path,
+) -> anyhow::Result<Published> {
+    let plugin_registry = plugin_registry
+        .ok_or_else(|| anyhow::anyhow!("this provider does not support CSI volumes"))?;
+    let claims: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), pod.namespace());
+    let volume_name = claims
+        .get(&claim.claim_name)
+        .await?
+        .spec
+        .and_then(|spec| spec.volume_name)
+        .ok_or_else(|| {
+            anyhow::anyhow!("persistent volume claim {} is not bound", claim.claim_name)
+        })?;
+    let volumes: Api<PersistentVolume> = Api::all(client.clone());
+    let spec = volumes.get(&volume_name).await?.spec.unwrap_or_default();
+    let csi = spec
+        .csi
+        .ok_or_else(|| anyhow::anyhow!("persistent volume {} is not a CSI volume", volume_name))?;
+    let endpoint = plugin_registry
+        .get_endpoint(&csi.driver)
+        .await
+        .ok_or_else(|| {
+            anyhow::anyhow!("CSI driver {} is not registered on this node", csi.driver)
+        })?;
+
+    let capability = VolumeCapability {
+        access_type: Some(AccessType::Mount(MountVolume {
+            fs_type: csi.fs_type.unwrap_or_default(),
+            mount_flags: spec.mount_options.unwrap_or_default(),
+        })),
+        access_mode: Some(AccessMode {
+            mode: csi_access_mode(&spec.access_modes.unwrap_or_default()) as i32,
+        }),
+    };
+    let volume_context: HashMap<String, String> = csi
+        .volume_attributes
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let mut node = NodeClient::new(grpc_sock::client::socket_channel(&endpoint).await?);
+
+    let staging_path = if supports_staging(&mut node).await? {
+        let staging_path = volume_dir
+            .join("csi")
+            .join(&volume_name)
+            .join("globalmount");
+        tokio::fs::create_dir_all(&staging_path).await?;
+        node.node_stage_volume(NodeStageVolumeRequest {
+            volume_id: csi.volume_handle.clone(),
+            publish_context: HashMap::new(),
+            staging_target_path: staging_path.to_string_lossy().into_owned(),
+            volume_capability: Some(capability.clone()),
+            secrets: secret_data(client, csi.node_stage_secret_ref).await?,
+            volume_context: volume_context.clone(),
+        })
+        .await?;
+        *STAGED_VOLUMES
+            .lock()
+            .unwrap()
+            .entry(staging_path.clone())
+            .or_insert(0) += 1;
+        debug!("staged CSI volume {} at {:?}", volume_name, staging_path);
+        Some(staging_path)
+    } else {
+        None
+    };
+
+    let published = Published {
+        endpoint,
+        volume_id: csi.volume_handle,
+        target_path: target_path.to_owned(),
+        staging_path,
+    };
+    let request = NodePublishVolumeRequest {
+        volume_id: published.volume_id.clone(),
+        publish_context: HashMap::new(),
+        staging_target_path: published
+            .staging_path
+            .as_ref()
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        target_path: target_path.to_string_lossy().into_owned(),
+        volume_capability: Some(capability),
+        readonly: claim.read_only.unwrap_or(false) || csi.read_only.unwrap_or(false),
+        secrets: secret_data(client, csi.node_publish_secret_ref).await?,
+        volume_context,
+    };
+    if let Err(e) = node.node_publish_volume(request).await {
+        // Don't leave the volume staged for a pod that won't use it
+        if let Err(e) = published.unstage(&mut node).await {
+            error!("unable to unstage CSI volume {}: {:?}", volume_name, e);
+        }
+        return Err(e.into());
+    }
+    debug!("published CSI volume {} at {:?}", volume_name, target_path);
+    Ok(published)
+}
+
+impl Published {
+    /// Unpublish the volume, and unstage it if no other pod uses it, in the background. This is
+    /// for when volumes are dropped, which can't wait for the driver.
+    pub(super) fn unpublish_in_background(&self) {
+        let published = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = published.unpublish().await {
+                error!(
+                    "unable to unpublish CSI volume {} from {:?}: {:?}",
+                    published.volume_id, published.target_path, e
+                );
+            }
+        });
+    }
+
+    async fn unpublish(&self) -> anyhow::Result<()> {
+        let mut node = NodeClient::new(grpc_sock::client::socket_channel(&self.endpoint).await?);
+        node.node_unpublish_volume(NodeUnpublishVolumeRequest {
+            volume_id: self.volume_id.clone(),
+            target_path: self.target_path.to_string_lossy().into_owned(),
+        })
+        .await?;
+        // The driver should have removed the target, but make sure, without touching its contents
+        match tokio::fs::remove_dir(&self.target_path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                error!("unable to remove {:?}: {:?}", self.target_path, e)
+            }
+            _ => (),
+        }
+        self.unstage(&mut node).await
+    }
+
+    /// Unstage the volume if this was the last pod using it.
+    async fn unstage(
+        &self,
+        node: &mut NodeClient<tonic::transport::Channel>,
+    ) -> anyhow::Result<()> {
+        let staging_path = match &self.staging_path {
+            Some(staging_path) => staging_path,
+            None => return Ok(()),
+        };
+        {
+            let mut staged = STAGED_VOLUMES.lock().unwrap();
+            match staged.get_mut(staging_path) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    return Ok(());
+                }
+                _ => {
+                    staged.remove(staging_path);
+                }
+            }
+        }
+        node.node_unstage_volume(NodeUnstageVolumeRequest {
+            volume_id: self.volume_id.clone(),
+            staging_target_path: staging_path.to_string_lossy().into_owned(),
+        })
+        .await?;
+        debug!(
+            "unstaged CSI volume {} from {:?}",
+            self.volume_id, staging_path
+        );
+        Ok(())
+    }
+}
+
+/// Check whether the driver stages volumes before publishing them.
+async fn supports_staging(
+    node: &mut NodeClient<tonic::transport::Channel>,
+) -> anyhow::Result<bool> {
+    let capabilities = node
+        .node_get_capabilities(NodeGetCapabilitiesRequest {})
+        .await?
+        .into_inner()
+        .capabilities;
+    Ok(capabilities.iter().any(|capability| {
+        matches!(
+            &capability.r#type,
+            Some(node_service_capability::Type::Rpc(call))
+                if call.r#type == rpc::Type::StageUnstageVolume as i32
+        )
+    }))
+}
+
+/// Pick the CSI access mode for a persistent volume's access modes.
+fn csi_access_mode(access_modes: &[String]) -> access_mode::Mode {
+    if access_modes.iter().any(|mode| mode == "ReadWriteMany") {
+        access_mode::Mode::MultiNodeMultiWriter
+    } else if access_modes.iter().any(|mode| mode == "ReadOnlyMany") {
+        access_mode::Mode::MultiNodeReaderOnly
+    } else {
+        access_mode::Mode::SingleNodeWriter
+    }
+}
+
+/// Fetch the secret a CSI call needs, if there is one.
+async fn secret_data(
+    client: &kube::Client,
+    secret_ref: Option<SecretReference>,
+) -> anyhow::Result<HashMap<String, String>> {
+    let (name, namespace) = match secret_ref {
+        Some(SecretReference {
+            name: Some(name),
+            namespace,
+        }) => (name, namespace.unwrap_or_else(|| "default".to_owned())),
+        _ => return Ok(HashMap::new()),
+    };
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), &namespace);
+    Ok(secrets
+        .get(&name)
+        .await?
+        .data
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key, ByteString(data))| (key, String::from_utf8_lossy(&data).into_owned()))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn access_modes_are_mapped_to_csi() {
+        let modes =
+            |modes: &[&str]| -> Vec<String> { modes.iter().map(|mode| mode.to_string()).collect() };
+        assert_eq!(
+            csi_access_mode(&modes(&["ReadWriteOnce"])),
+            access_mode::Mode::SingleNodeWriter
+        );
+        assert_eq!(
+            csi_access_mode(&modes(&["ReadOnlyMany"])),
+            access_mode::Mode::MultiNodeReaderOnly
+        );
+        assert_eq!(
+            csi_access_mode(&modes(&["ReadWriteOnce", "ReadWriteMany"])),
+            access_mode::Mode::MultiNodeMultiWriter
+        );
+    }
+}
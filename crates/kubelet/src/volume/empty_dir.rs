@@ -0,0 +1,179 @@
+//! Empty directory volumes, which give a pod scratch space that lasts as long as the pod does.
+//!
+//! Volumes with the `Memory` medium are backed by a tmpfs on Linux, whose size the kernel limits
+//! to the volume's size limit. Other volumes are directories under the kubelet's volume
+//! directory, and are checked regularly to see whether they have grown past their size limit. If
+//! one has, the pod is evicted, as the Kubernetes kubelet does.
+use std::path::Path;
+use std::time::Duration;
+
+use futures::future::{AbortHandle, Abortable};
+use k8s_openapi::api::core::v1::{EmptyDirVolumeSource, Pod as KubePod};
+use kube::api::{Api, DeleteParams};
+use tracing::{error, info, warn};
+
+use crate::pod::{mark_disruption_target, record_event, EventType, Pod, TERMINATION_BY_KUBELET};
+
+/// How often the size of a disk backed volume is checked.
+const SIZE_CHECK_PERIOD: Duration = Duration::from_secs(30);
+
+/// Create the directory for an empty directory volume, mounting a tmpfs on it if the volume is
+/// memory backed. Returns whether a tmpfs was mounted, which it isn't on platforms without one.
+pub(super) async fn create(source: &EmptyDirVolumeSource, path: &Path) -> anyhow::Result<bool> {
+    tokio::fs::create_dir_all(path).await?;
+    if source.medium.as_deref() != Some("Memory") {
+        return Ok(false);
+    }
+    let size_limit = source
+        .size_limit
+        .as_ref()
+        .map(crate::resources::memory_bytes)
+        .transpose()?;
+    mount_tmpfs(path, size_limit).await
+}
+
+#[cfg(target_os = "linux")]
+async fn mount_tmpfs(path: &Path, size_limit: Option<u64>) -> anyhow::Result<bool> {
+    let mut options = "mode=0777".to_owned();
+    if let Some(size_limit) = size_limit {
+        options.push_str(&format!(",size={}", size_limit));
+    }
+    let path = path.to_owned();
+    let output = tokio::task::spawn_blocking(move || {
+        std::process::Command::new("mount")
+            .args(&["-t", "tmpfs", "-o", &options, "tmpfs"])
+            .arg(&path)
+            .output()
+    })
+    .await??;
+    if !output.status.success() {
+        anyhow::bail!(
+            "unable to mount tmpfs: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn mount_tmpfs(path: &Path, _size_limit: Option<u64>) -> anyhow::Result<bool> {
+    warn!(
+        "memory backed volumes are only supported on Linux, using a directory on disk for {:?}",
+        path
+    );
+    Ok(false)
+}
+
+/// Unmount the tmpfs of a memory backed volume. This blocks, as it is used when volumes are
+/// dropped.
+#[cfg(target_os = "linux")]
+pub(super) fn unmount_tmpfs(path: &Path) {
+    match std::process::Command::new("umount").arg(path).output() {
+        Ok(output) if output.status.success() => (),
+        Ok(output) => error!(
+            "unable to unmount tmpfs at {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => error!("unable to unmount tmpfs at {:?}: {:?}", path, e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn unmount_tmpfs(_path: &Path) {}
+
+/// Start checking that a disk backed volume stays within its size limit, evicting the pod if it
+/// doesn't. The returned handle stops the checks.
+pub(super) fn watch_size(
+    source: &EmptyDirVolumeSource,
+    path: &Path,
+    pod: &Pod,
+    client: &kube::Client,
+) -> anyhow::Result<Option<AbortHandle>> {
+    let size_limit = match &source.size_limit {
+        Some(size_limit) => crate::resources::memory_bytes(size_limit)?,
+        None => return Ok(None),
+    };
+    let path = path.to_owned();
+    let pod = pod.clone();
+    let client = client.clone();
+    let (handle, registration) = AbortHandle::new_pair();
+    tokio::spawn(Abortable::new(
+        async move {
+            loop {
+                tokio::time::delay_for(SIZE_CHECK_PERIOD).await;
+                let dir = path.clone();
+                let size = tokio::task::spawn_blocking(move || dir_size(&dir))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|size| size.map_err(anyhow::Error::from));
+                let size = match size {
+                    Ok(size) => size,
+                    Err(e) => {
+                        warn!("unable to get size of volume {:?}: {:?}", path, e);
+                        continue;
+                    }
+                };
+                if size > size_limit {
+                    let message = format!(
+                        "Usage of EmptyDir volume {:?} exceeds the limit of {} bytes",
+                        path.file_name().unwrap_or_default(),
+                        size_limit
+                    );
+                    evict(&client, &pod, &message).await;
+                    return;
+                }
+            }
+        },
+        registration,
+    ));
+    Ok(Some(handle))
+}
+
+/// Evict a pod by deleting it, so that its controller can replace it.
+async fn evict(client: &kube::Client, pod: &Pod, message: &str) {
+    info!("Evicting pod {}: {}", pod.name(), message);
+    if let Err(e) = record_event(client, pod, EventType::Warning, "Evicted", message).await {
+        warn!("Unable to record eviction of pod {}: {:?}", pod.name(), e);
+    }
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    if let Err(e) = mark_disruption_target(&api, pod, TERMINATION_BY_KUBELET, message).await {
+        warn!(
+            "Unable to mark pod {} as a disruption target: {:?}",
+            pod.name(),
+            e
+        );
+    }
+    if let Err(e) = api.delete(pod.name(), &DeleteParams::default()).await {
+        error!("Unable to evict pod {}: {:?}", pod.name(), e);
+    }
+}
+
+/// Add up the sizes of the files under a directory.
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dir_size_includes_nested_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), vec![0; 100]).unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        std::fs::write(dir.path().join("nested").join("b"), vec![0; 50]).unwrap();
+        assert_eq!(dir_size(dir.path()).unwrap(), 150);
+    }
+}
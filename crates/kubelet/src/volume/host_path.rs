@@ -0,0 +1,233 @@
+//! Host path volumes, which expose files on the node to pods. As that can give a pod access to
+//! anything on the node, only paths under the kubelet's allowed host paths can be mounted, and
+//! the path must be of the type the volume asks for.
+use std::path::{Component, Path, PathBuf};
+
+use k8s_openapi::api::core::v1::HostPathVolumeSource;
+
+/// Check that a host path volume is allowed and that its path is of the right type, creating it
+/// if the volume asks for that.
+pub(super) async fn check(
+    source: &HostPathVolumeSource,
+    allowed_host_paths: &[PathBuf],
+) -> anyhow::Result<()> {
+    let path = Path::new(&source.path);
+    if !is_allowed(path, allowed_host_paths) {
+        anyhow::bail!(
+            "host path {} is not under any of the kubelet's allowed host paths",
+            source.path
+        );
+    }
+    let mut real_allowed_paths = Vec::with_capacity(allowed_host_paths.len());
+    for allowed in allowed_host_paths {
+        if let Ok(allowed) = tokio::fs::canonicalize(allowed).await {
+            real_allowed_paths.push(allowed);
+        }
+    }
+    // The path may go through a symlink, so check where it really is before anything is created
+    let real_path = resolve(path).await?;
+    check_real_path(&source.path, &real_path, &real_allowed_paths)?;
+
+    let path_type = source.type_.as_deref().unwrap_or_default();
+    match path_type {
+        "DirectoryOrCreate" => tokio::fs::create_dir_all(path).await?,
+        "FileOrCreate" => {
+            if tokio::fs::metadata(path).await.is_err() {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                // A new file is never created through a symlink
+                let created = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .await;
+                match created {
+                    Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+                    _ => (),
+                }
+            }
+        }
+        _ => (),
+    }
+    // The path may have been swapped for a symlink while it was being created
+    let real_path = tokio::fs::canonicalize(path).await?;
+    check_real_path(&source.path, &real_path, &real_allowed_paths)?;
+
+    let file_type = tokio::fs::metadata(path).await?.file_type();
+    let matches = match path_type {
+        "" => true,
+        "Directory" | "DirectoryOrCreate" => file_type.is_dir(),
+        "File" | "FileOrCreate" => file_type.is_file(),
+        "Socket" | "CharDevice" | "BlockDevice" => is_special(&file_type, path_type),
+        other => anyhow::bail!("unknown host path type {}", other),
+    };
+    if !matches {
+        anyhow::bail!("host path {} is not of type {}", source.path, path_type);
+    }
+    Ok(())
+}
+
+/// Where a path really is, following the symlinks in the part of it that exists. The rest of the
+/// path, which is yet to be created, is added on as it is. A path that goes through a symlink to
+/// somewhere that doesn't exist is refused, as creating it would create the symlink's target.
+async fn resolve(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match tokio::fs::canonicalize(existing).await {
+            Ok(real_path) => {
+                return Ok(missing
+                    .iter()
+                    .rev()
+                    .fold(real_path, |real_path, name| real_path.join(name)))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if tokio::fs::symlink_metadata(existing).await.is_ok() {
+                    anyhow::bail!(
+                        "host path {} goes through {}, which is a broken symlink",
+                        path.display(),
+                        existing.display()
+                    );
+                }
+                match (existing.file_name(), existing.parent()) {
+                    (Some(name), Some(parent)) => {
+                        missing.push(name);
+                        existing = parent;
+                    }
+                    _ => return Err(e.into()),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Check that where a host path really is is under one of the allowed paths, which must also be
+/// where they really are.
+fn check_real_path(
+    host_path: &str,
+    real_path: &Path,
+    real_allowed_paths: &[PathBuf],
+) -> anyhow::Result<()> {
+    if !is_allowed(real_path, real_allowed_paths) {
+        anyhow::bail!(
+            "host path {} resolves to {}, which is not under any of the kubelet's allowed host paths",
+            host_path,
+            real_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Check whether a path is one of the allowed paths or under one of them. Paths with `..` in them
+/// are never allowed, as they could climb out of an allowed path.
+fn is_allowed(path: &Path, allowed_host_paths: &[PathBuf]) -> bool {
+    path.is_absolute()
+        && !path
+            .components()
+            .any(|component| component == Component::ParentDir)
+        && allowed_host_paths
+            .iter()
+            .any(|allowed| allowed.is_absolute() && path.starts_with(allowed))
+}
+
+#[cfg(target_family = "unix")]
+fn is_special(file_type: &std::fs::FileType, path_type: &str) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    match path_type {
+        "Socket" => file_type.is_socket(),
+        "CharDevice" => file_type.is_char_device(),
+        "BlockDevice" => file_type.is_block_device(),
+        _ => false,
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn is_special(_file_type: &std::fs::FileType, _path_type: &str) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn only_paths_under_allowed_paths_are_allowed() {
+        let allowed = vec![PathBuf::from("/var/data"), PathBuf::from("/etc/app.conf")];
+        assert!(is_allowed(Path::new("/var/data"), &allowed));
+        assert!(is_allowed(Path::new("/var/data/cache"), &allowed));
+        assert!(is_allowed(Path::new("/etc/app.conf"), &allowed));
+        assert!(!is_allowed(Path::new("/var/database"), &allowed));
+        assert!(!is_allowed(
+            Path::new("/var/data/../../etc/shadow"),
+            &allowed
+        ));
+        assert!(!is_allowed(Path::new("var/data"), &allowed));
+        assert!(!is_allowed(Path::new("/etc"), &allowed));
+        assert!(!is_allowed(Path::new("/var/data"), &[]));
+    }
+
+    #[tokio::test]
+    async fn directories_are_created_and_types_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let allowed = vec![dir.path().canonicalize().unwrap()];
+        let source = |path: &Path, path_type: &str| HostPathVolumeSource {
+            path: allowed[0].join(path).to_string_lossy().into_owned(),
+            type_: Some(path_type.to_owned()),
+        };
+
+        check(&source(Path::new("new"), "DirectoryOrCreate"), &allowed)
+            .await
+            .unwrap();
+        assert!(dir.path().join("new").is_dir());
+        check(&source(Path::new("new"), "Directory"), &allowed)
+            .await
+            .unwrap();
+        assert!(check(&source(Path::new("new"), "File"), &allowed)
+            .await
+            .is_err());
+        assert!(check(&source(Path::new("missing"), "Directory"), &allowed)
+            .await
+            .is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn nothing_is_created_through_symlinks_out_of_allowed_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let allowed = root.join("allowed");
+        let outside = root.join("outside");
+        std::fs::create_dir(&allowed).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, allowed.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("target"), allowed.join("broken")).unwrap();
+        let allowed_paths = vec![allowed.clone()];
+        let source = |path: PathBuf, path_type: &str| HostPathVolumeSource {
+            path: path.to_string_lossy().into_owned(),
+            type_: Some(path_type.to_owned()),
+        };
+
+        let escaping = vec![
+            source(allowed.join("link/dir/nested"), "DirectoryOrCreate"),
+            source(allowed.join("link/file"), "FileOrCreate"),
+            source(allowed.join("link/dir/file"), "FileOrCreate"),
+            source(allowed.join("broken"), "FileOrCreate"),
+            source(allowed.join("broken"), "DirectoryOrCreate"),
+        ];
+        for volume in &escaping {
+            assert!(check(volume, &allowed_paths).await.is_err());
+        }
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+
+        check(
+            &source(allowed.join("file"), "FileOrCreate"),
+            &allowed_paths,
+        )
+        .await
+        .unwrap();
+        assert!(allowed.join("file").is_file());
+    }
+}
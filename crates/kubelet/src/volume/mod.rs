@@ -1,41 +1,77 @@
 //! A module for use in managing volumes in providers. Use of this module is not mandatory to create
 //! a Provider, but it does provide common implementation logic for supported volume providers.
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
+use futures::future::{AbortHandle, Abortable};
+use futures::stream::{select_all, BoxStream};
+use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::core::v1::Volume as KubeVolume;
-use k8s_openapi::api::core::v1::{ConfigMap, KeyToPath, Secret};
+use k8s_openapi::api::core::v1::{
+    ConfigMap, DownwardAPIVolumeFile, KeyToPath, Pod as KubePod, Secret, VolumeProjection,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use k8s_openapi::ByteString;
-use kube::api::Api;
-use log::{debug, error};
+use kube::api::{Api, ListParams};
+use kube::error::ErrorResponse;
+use kube::Error;
+use kube_runtime::watcher::watcher;
+use tracing::{debug, error, warn};
 
+use crate::features::{self, Feature};
+use crate::plugin_watcher::PluginRegistry;
 use crate::pod::Pod;
 
+mod atomic_writer;
+mod csi;
+mod empty_dir;
+mod host_path;
+mod service_account_token;
+
+use atomic_writer::Payload;
+use service_account_token::Token;
+
+/// How long to wait before watching the sources of a volume again after the watch failed.
+const WATCH_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(Debug)]
 enum Type {
     ConfigMap,
     Secret,
+    Projected,
+    DownwardApi,
+    EmptyDir { tmpfs: bool },
+    Csi(csi::Published),
     HostPath,
 }
 
-/// A smart wrapper around the location of a volume on the host system. If this is a ConfigMap or
-/// Secret volume, dropping this reference will clean up the temporary volume. [AsRef] and
-/// [std::ops::Deref] are implemented for this type so you can still use it like a normal PathBuf
+/// A smart wrapper around the location of a volume on the host system. If this is a ConfigMap,
+/// Secret, projected or downward API volume, its files are updated while this reference is held.
+/// Dropping it will clean up any volume other than a HostPath one. [AsRef] and [std::ops::Deref]
+/// are implemented for this type so you can still use it like a normal PathBuf
 #[derive(Debug)]
 pub struct Ref {
     host_path: PathBuf,
     volume_type: Type,
+    watch: Option<AbortHandle>,
 }
 
 impl Ref {
     /// Resolves the volumes for a pod, including preparing temporary directories containing the
-    /// contents of secrets and configmaps. Returns a HashMap of volume names to a PathBuf for the
-    /// directory where the volume is mounted
+    /// contents of secrets, configmaps and pod fields. The contents are kept up to date as the
+    /// secrets, configmaps and pod change, using the same `..data` symlink layout as the
+    /// Kubernetes kubelet, so modules should read the files each time they need them. Persistent
+    /// volume claims are mounted through the CSI drivers in the plugin registry, if there is one,
+    /// and host paths must be under one of the allowed host paths. Returns a HashMap of volume
+    /// names to a PathBuf for the directory where the volume is mounted
     pub async fn volumes_from_pod(
         volume_dir: &PathBuf,
         pod: &Pod,
         client: &kube::Client,
+        plugin_registry: Option<Arc<PluginRegistry>>,
+        allowed_host_paths: &[PathBuf],
     ) -> anyhow::Result<HashMap<String, Self>> {
         let base_path = volume_dir.join(pod_dir_name(pod));
         tokio::fs::create_dir_all(&base_path).await?;
@@ -43,8 +79,18 @@ impl Ref {
             let volumes = vols.iter().map(|v| {
                 let mut host_path = base_path.clone();
                 host_path.push(&v.name);
+                let plugin_registry = plugin_registry.as_deref();
                 async move {
-                    let volume_type = configure(v, pod.namespace(), client, &host_path).await?;
+                    let (volume_type, watch) = configure(
+                        v,
+                        pod,
+                        client,
+                        plugin_registry,
+                        allowed_host_paths,
+                        volume_dir,
+                        &host_path,
+                    )
+                    .await?;
                     Ok((
                         v.name.to_owned(),
                         // Every other volume type should mount to the given host_path except for a
@@ -53,10 +99,12 @@ impl Ref {
                             Some(hostpath) => Ref {
                                 host_path: PathBuf::from(&hostpath.path),
                                 volume_type,
+                                watch,
                             },
                             None => Ref {
                                 host_path,
                                 volume_type,
+                                watch,
                             },
                         },
                     ))
@@ -88,7 +136,18 @@ impl Deref for Ref {
 
 impl Drop for Ref {
     fn drop(&mut self) {
-        if matches!(self.volume_type, Type::ConfigMap | Type::Secret) {
+        if let Some(watch) = &self.watch {
+            watch.abort();
+        }
+        if let Type::EmptyDir { tmpfs: true } = self.volume_type {
+            empty_dir::unmount_tmpfs(&self.host_path);
+        }
+        if let Type::Csi(published) = &self.volume_type {
+            // The volume's contents belong to its driver, which removes the directory
+            published.unpublish_in_background();
+            return;
+        }
+        if !matches!(self.volume_type, Type::HostPath) {
             // TODO: Currently there is no way to do this async (though there is an async destructors proposal)
             debug!(
                 "deleting {:?} directory {:?}",
@@ -108,110 +167,371 @@ impl Drop for Ref {
 /// Because it isn't a HashMap, we need to check all fields individually
 async fn configure(
     vol: &KubeVolume,
-    namespace: &str,
+    pod: &Pod,
     client: &kube::Client,
+    plugin_registry: Option<&PluginRegistry>,
+    allowed_host_paths: &[PathBuf],
+    volume_dir: &PathBuf,
     path: &PathBuf,
-) -> anyhow::Result<Type> {
-    if let Some(cm) = &vol.config_map {
-        populate_from_config_map(
-            &cm.name
-                .as_ref()
-                .ok_or_else(|| anyhow::anyhow!("no configmap name was given"))?,
-            namespace,
-            client,
-            path,
-            &cm.items,
-        )
-        .await
+) -> anyhow::Result<(Type, Option<AbortHandle>)> {
+    let (volume_type, sources) = if let Some(cm) = &vol.config_map {
+        let source = Source::config_map(cm.name.as_ref(), &cm.items, cm.optional)?;
+        (Type::ConfigMap, vec![source])
     } else if let Some(s) = &vol.secret {
-        populate_from_secret(
-            &s.secret_name
-                .as_ref()
-                .ok_or_else(|| anyhow::anyhow!("no secret name was given"))?,
-            namespace,
-            client,
-            path,
-            &s.items,
-        )
-        .await
+        let source = Source::secret(s.secret_name.as_ref(), &s.items, s.optional)?;
+        (Type::Secret, vec![source])
+    } else if let Some(projected) = &vol.projected {
+        let sources = projected
+            .sources
+            .iter()
+            .map(|projection| Source::from_projection(projection, pod))
+            .collect::<anyhow::Result<_>>()?;
+        (Type::Projected, sources)
+    } else if let Some(downward_api) = &vol.downward_api {
+        let source = Source::downward_api(&downward_api.items, pod);
+        (Type::DownwardApi, vec![source])
+    } else if let Some(empty_dir) = &vol.empty_dir {
+        let tmpfs = empty_dir::create(empty_dir, path).await?;
+        let watch = if tmpfs {
+            None
+        } else {
+            empty_dir::watch_size(empty_dir, path, pod, client)?
+        };
+        return Ok((Type::EmptyDir { tmpfs }, watch));
+    } else if let Some(claim) = &vol.persistent_volume_claim {
+        if !features::enabled(Feature::CsiVolumes) {
+            anyhow::bail!(
+                "PersistentVolumeClaim volumes need the {} feature gate to be enabled",
+                Feature::CsiVolumes
+            );
+        }
+        let published = csi::publish(claim, pod, client, plugin_registry, volume_dir, path).await?;
+        return Ok((Type::Csi(published), None));
     } else if let Some(hostpath) = &vol.host_path {
-        // Check the the directory exists on the host
-        tokio::fs::metadata(&hostpath.path).await?;
-        Ok(Type::HostPath)
+        host_path::check(hostpath, allowed_host_paths).await?;
+        return Ok((Type::HostPath, None));
     } else {
-        Err(anyhow::anyhow!(
-            "Unsupported volume type. Currently supported types: ConfigMap, Secret, and HostPath"
-        ))
-    }
+        return Err(anyhow::anyhow!(
+            "Unsupported volume type. Currently supported types: ConfigMap, Secret, Projected, DownwardAPI, EmptyDir, PersistentVolumeClaim (with CSI drivers), and HostPath"
+        ));
+    };
+    let watch = Projection::new(sources, pod.namespace(), client, path)
+        .populate()
+        .await?;
+    Ok((volume_type, Some(watch)))
 }
 
-async fn populate_from_secret(
-    name: &str,
-    namespace: &str,
-    client: &kube::Client,
-    path: &PathBuf,
-    items: &Option<Vec<KeyToPath>>,
-) -> anyhow::Result<Type> {
-    tokio::fs::create_dir_all(path).await?;
-    let secret_client: Api<Secret> = Api::namespaced(client.clone(), namespace);
-    let secret = secret_client.get(name).await?;
-    let data = secret.data.unwrap_or_default();
-    let data = data.iter().map(|(key, ByteString(data))| async move {
-        match mount_setting_for(key, items) {
-            ItemMount::MountAt(mount_path) => {
-                let file_path = path.join(mount_path);
-                tokio::fs::write(file_path, &data).await
+/// A Kubernetes object whose data is projected into a volume.
+#[derive(Clone, Debug)]
+enum Source {
+    ConfigMap {
+        name: String,
+        items: Option<Vec<KeyToPath>>,
+        optional: bool,
+    },
+    Secret {
+        name: String,
+        items: Option<Vec<KeyToPath>>,
+        optional: bool,
+    },
+    /// Fields of the pod itself.
+    DownwardApi {
+        pod_name: String,
+        items: Vec<DownwardAPIVolumeFile>,
+    },
+    /// A token for the pod's service account.
+    ServiceAccountToken(Token),
+}
+
+impl Source {
+    fn config_map(
+        name: Option<&String>,
+        items: &Option<Vec<KeyToPath>>,
+        optional: Option<bool>,
+    ) -> anyhow::Result<Self> {
+        Ok(Source::ConfigMap {
+            name: name
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no configmap name was given"))?,
+            items: items.clone(),
+            optional: optional.unwrap_or(false),
+        })
+    }
+
+    fn secret(
+        name: Option<&String>,
+        items: &Option<Vec<KeyToPath>>,
+        optional: Option<bool>,
+    ) -> anyhow::Result<Self> {
+        Ok(Source::Secret {
+            name: name
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no secret name was given"))?,
+            items: items.clone(),
+            optional: optional.unwrap_or(false),
+        })
+    }
+
+    fn downward_api(items: &Option<Vec<DownwardAPIVolumeFile>>, pod: &Pod) -> Self {
+        Source::DownwardApi {
+            pod_name: pod.name().to_owned(),
+            items: items.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Get the source of one of the parts of a projected volume.
+    fn from_projection(projection: &VolumeProjection, pod: &Pod) -> anyhow::Result<Self> {
+        if let Some(cm) = &projection.config_map {
+            Source::config_map(cm.name.as_ref(), &cm.items, cm.optional)
+        } else if let Some(s) = &projection.secret {
+            Source::secret(s.name.as_ref(), &s.items, s.optional)
+        } else if let Some(downward_api) = &projection.downward_api {
+            Ok(Source::downward_api(&downward_api.items, pod))
+        } else if let Some(token) = &projection.service_account_token {
+            Ok(Source::ServiceAccountToken(Token::new(token, pod)))
+        } else {
+            Err(anyhow::anyhow!("projected volume source has no source set"))
+        }
+    }
+
+    /// Fetch the object and build the files it projects into the volume. An optional object that
+    /// doesn't exist projects no files.
+    async fn payload(&self, namespace: &str, client: &kube::Client) -> anyhow::Result<Payload> {
+        match self {
+            Source::ConfigMap {
+                name,
+                items,
+                optional,
+            } => {
+                let api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+                let config_map = match api.get(name).await {
+                    Ok(config_map) => config_map,
+                    Err(Error::Api(ErrorResponse { code: 404, .. })) if *optional => {
+                        return Ok(Payload::new())
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let binary_data = config_map
+                    .binary_data
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(key, ByteString(data))| (key, data));
+                let data = config_map
+                    .data
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(key, data)| (key, data.into_bytes()));
+                Ok(project(binary_data.chain(data), items))
+            }
+            Source::Secret {
+                name,
+                items,
+                optional,
+            } => {
+                let api: Api<Secret> = Api::namespaced(client.clone(), namespace);
+                let secret = match api.get(name).await {
+                    Ok(secret) => secret,
+                    Err(Error::Api(ErrorResponse { code: 404, .. })) if *optional => {
+                        return Ok(Payload::new())
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let data = secret
+                    .data
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(key, ByteString(data))| (key, data));
+                Ok(project(data, items))
+            }
+            Source::DownwardApi { pod_name, items } => {
+                let api: Api<KubePod> = Api::namespaced(client.clone(), namespace);
+                let pod = api.get(pod_name).await?;
+                items
+                    .iter()
+                    .map(|item| {
+                        let value = downward_api_value(&pod.metadata, item)?;
+                        Ok((PathBuf::from(&item.path), value.into_bytes()))
+                    })
+                    .collect()
+            }
+            Source::ServiceAccountToken(token) => {
+                let value = token.get(namespace, client).await?;
+                let mut payload = Payload::new();
+                payload.insert(PathBuf::from(&token.path), value.into_bytes());
+                Ok(payload)
+            }
+        }
+    }
+
+    /// Watch the object, yielding whenever it changes, or for tokens, whenever they are due to be
+    /// replaced.
+    fn watch(&self, namespace: &str, client: &kube::Client) -> BoxStream<'static, WatchResult> {
+        let by_name = |name: &str| ListParams::default().fields(&format!("metadata.name={}", name));
+        match self {
+            Source::ConfigMap { name, .. } => {
+                let api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+                watcher(api, by_name(name)).map_ok(|_| ()).boxed()
             }
-            ItemMount::DoNotMount => Ok(()),
+            Source::Secret { name, .. } => {
+                let api: Api<Secret> = Api::namespaced(client.clone(), namespace);
+                watcher(api, by_name(name)).map_ok(|_| ()).boxed()
+            }
+            Source::DownwardApi { pod_name, .. } => {
+                let api: Api<KubePod> = Api::namespaced(client.clone(), namespace);
+                watcher(api, by_name(pod_name)).map_ok(|_| ()).boxed()
+            }
+            Source::ServiceAccountToken(token) => token.watch(),
         }
-    });
-    futures::future::join_all(data)
-        .await
-        .into_iter()
-        .collect::<tokio::io::Result<_>>()?;
+    }
+}
 
-    Ok(Type::Secret)
+/// Get the value of a pod field for a downward API volume. Only the pod's metadata can be
+/// projected into a volume: its name, namespace, UID, and its labels and annotations either as a
+/// whole or one at a time, such as `metadata.labels['app']`.
+fn downward_api_value(
+    metadata: &ObjectMeta,
+    item: &DownwardAPIVolumeFile,
+) -> anyhow::Result<String> {
+    let field = match (&item.field_ref, &item.resource_field_ref) {
+        (Some(field_ref), _) => field_ref.field_path.as_str(),
+        (None, Some(_)) => anyhow::bail!(
+            "downward API volume item {} uses resourceFieldRef, which is not supported",
+            item.path
+        ),
+        (None, None) => anyhow::bail!("downward API volume item {} has no field set", item.path),
+    };
+    let value = match field {
+        "metadata.name" => metadata.name.clone().unwrap_or_default(),
+        "metadata.namespace" => metadata.namespace.clone().unwrap_or_default(),
+        "metadata.uid" => metadata.uid.clone().unwrap_or_default(),
+        "metadata.labels" => format_map(metadata.labels.as_ref()),
+        "metadata.annotations" => format_map(metadata.annotations.as_ref()),
+        _ => {
+            let (map, key) = if let Some(key) = subscript(field, "metadata.labels") {
+                (metadata.labels.as_ref(), key)
+            } else if let Some(key) = subscript(field, "metadata.annotations") {
+                (metadata.annotations.as_ref(), key)
+            } else {
+                anyhow::bail!("unsupported downward API field {}", field)
+            };
+            map.and_then(|map| map.get(key))
+                .cloned()
+                .unwrap_or_default()
+        }
+    };
+    Ok(value)
 }
 
-async fn populate_from_config_map(
-    name: &str,
-    namespace: &str,
-    client: &kube::Client,
-    path: &PathBuf,
+/// Format labels or annotations the way the Kubernetes kubelet does, as sorted `key="value"`
+/// lines.
+fn format_map(map: Option<&BTreeMap<String, String>>) -> String {
+    map.iter()
+        .flat_map(|map| map.iter())
+        .map(|(key, value)| format!("{}={:?}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Get the key of a field such as `metadata.labels['app']`.
+fn subscript<'a>(field: &'a str, prefix: &str) -> Option<&'a str> {
+    let open = format!("{}['", prefix);
+    if field.len() >= open.len() + 2 && field.starts_with(&open) && field.ends_with("']") {
+        Some(&field[open.len()..field.len() - 2])
+    } else {
+        None
+    }
+}
+
+type WatchResult = Result<(), kube_runtime::watcher::Error>;
+
+/// Build the files for the given keys and data, keeping only the listed items if there are any.
+fn project(
+    data: impl Iterator<Item = (String, Vec<u8>)>,
     items: &Option<Vec<KeyToPath>>,
-) -> anyhow::Result<Type> {
-    tokio::fs::create_dir_all(path).await?;
-    let cm_client: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
-    let config_map = cm_client.get(name).await?;
-    let binary_data = config_map.binary_data.unwrap_or_default();
-    let binary_data = binary_data.iter().map(|(key, data)| async move {
-        match mount_setting_for(key, items) {
-            ItemMount::MountAt(mount_path) => {
-                let file_path = path.join(mount_path);
-                tokio::fs::write(file_path, &data.0).await
+) -> Payload {
+    data.filter_map(|(key, data)| match mount_setting_for(&key, items) {
+        ItemMount::MountAt(mount_path) => Some((PathBuf::from(mount_path), data)),
+        ItemMount::DoNotMount => None,
+    })
+    .collect()
+}
+
+/// A volume whose files are projected from Kubernetes objects, and kept up to date as those
+/// objects change.
+struct Projection {
+    sources: Vec<Source>,
+    namespace: String,
+    client: kube::Client,
+    path: PathBuf,
+}
+
+impl Projection {
+    fn new(sources: Vec<Source>, namespace: &str, client: &kube::Client, path: &PathBuf) -> Self {
+        Projection {
+            sources,
+            namespace: namespace.to_owned(),
+            client: client.clone(),
+            path: path.clone(),
+        }
+    }
+
+    /// Write the volume's files, then start keeping them up to date. The returned handle stops
+    /// the updates.
+    async fn populate(self) -> anyhow::Result<AbortHandle> {
+        let payload = self.payload().await?;
+        self.write(&payload).await?;
+        let (handle, registration) = AbortHandle::new_pair();
+        tokio::spawn(Abortable::new(self.watch(payload), registration));
+        Ok(handle)
+    }
+
+    async fn payload(&self) -> anyhow::Result<Payload> {
+        let mut payload = Payload::new();
+        for source in &self.sources {
+            payload.extend(source.payload(&self.namespace, &self.client).await?);
+        }
+        Ok(payload)
+    }
+
+    async fn write(&self, payload: &Payload) -> anyhow::Result<()> {
+        let path = self.path.clone();
+        let payload = payload.clone();
+        tokio::task::spawn_blocking(move || atomic_writer::write(&path, &payload)).await??;
+        Ok(())
+    }
+
+    /// Rewrite the volume's files whenever one of its sources changes.
+    async fn watch(self, mut current: Payload) {
+        let mut events = select_all(
+            self.sources
+                .iter()
+                .map(|source| source.watch(&self.namespace, &self.client)),
+        );
+        while let Some(event) = events.next().await {
+            if let Err(e) = event {
+                warn!("error watching sources of volume {:?}: {:?}", self.path, e);
+                tokio::time::delay_for(WATCH_RETRY_DELAY).await;
+                continue;
             }
-            ItemMount::DoNotMount => Ok(()),
-        }
-    });
-    let binary_data = futures::future::join_all(binary_data);
-    let data = config_map.data.unwrap_or_default();
-    let data = data.iter().map(|(key, data)| async move {
-        match mount_setting_for(key, items) {
-            ItemMount::MountAt(mount_path) => {
-                let file_path = path.join(mount_path);
-                tokio::fs::write(file_path, data).await
+            let payload = match self.payload().await {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("unable to fetch sources of volume {:?}: {:?}", self.path, e);
+                    continue;
+                }
+            };
+            if payload == current {
+                continue;
+            }
+            match self.write(&payload).await {
+                Ok(()) => {
+                    debug!("updated files of volume {:?}", self.path);
+                    current = payload;
+                }
+                Err(e) => error!("unable to update files of volume {:?}: {:?}", self.path, e),
             }
-            ItemMount::DoNotMount => Ok(()),
         }
-    });
-    let data = futures::future::join_all(data);
-    let (binary_data, data) = futures::future::join(binary_data, data).await;
-    binary_data
-        .into_iter()
-        .chain(data)
-        .collect::<tokio::io::Result<_>>()?;
-
-    Ok(Type::ConfigMap)
+    }
 }
 
 fn pod_dir_name(pod: &Pod) -> String {
@@ -230,6 +550,43 @@ fn mount_setting_for(key: &str, items_to_mount: &Option<Vec<KeyToPath>>) -> Item
     }
 }
 
+/// Resolve the `subPath` of a volume mount to the directory it names in the volume, creating it if
+/// it doesn't exist yet, as the Kubernetes kubelet does. Symlinks in the volume are only followed
+/// as far as they stay within it, so that a container can't be given a directory outside of its
+/// volume by whatever wrote the symlink.
+pub async fn sub_path(volume: &Path, sub_path: &str) -> anyhow::Result<PathBuf> {
+    let relative = Path::new(sub_path);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        anyhow::bail!(
+            "subPath {} must be a relative path that stays within its volume",
+            sub_path
+        );
+    }
+    let root = tokio::fs::canonicalize(volume).await?;
+    let mut path = root.clone();
+    for component in relative.components().filter(|c| *c != Component::CurDir) {
+        path.push(component);
+        match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = tokio::fs::canonicalize(&path).await?;
+                if !target.starts_with(&root) {
+                    anyhow::bail!("subPath {} leads outside of its volume", sub_path);
+                }
+                path = target;
+            }
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tokio::fs::create_dir(&path).await?
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(path)
+}
+
 enum ItemMount {
     MountAt(String),
     DoNotMount,
@@ -243,3 +600,64 @@ impl From<Option<String>> for ItemMount {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn item(field_path: &str) -> DownwardAPIVolumeFile {
+        serde_json::from_value(serde_json::json!({
+            "path": "value",
+            "fieldRef": { "fieldPath": field_path }
+        }))
+        .unwrap()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sub_paths_are_created_and_kept_within_their_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        let volume = dir.path().join("volume");
+        std::fs::create_dir_all(volume.join("data")).unwrap();
+        let volume = volume.canonicalize().unwrap();
+
+        assert_eq!(
+            sub_path(&volume, "data").await.unwrap(),
+            volume.join("data")
+        );
+        assert_eq!(
+            sub_path(&volume, "./logs/app").await.unwrap(),
+            volume.join("logs/app")
+        );
+        assert!(volume.join("logs/app").is_dir());
+        assert!(sub_path(&volume, "../outside").await.is_err());
+        assert!(sub_path(&volume, "/etc").await.is_err());
+
+        std::os::unix::fs::symlink(volume.join("data"), volume.join("inside")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), volume.join("outside")).unwrap();
+        assert_eq!(
+            sub_path(&volume, "inside").await.unwrap(),
+            volume.join("data")
+        );
+        assert!(sub_path(&volume, "outside/volume").await.is_err());
+    }
+
+    #[test]
+    fn downward_api_values_come_from_pod_metadata() {
+        let metadata: ObjectMeta = serde_json::from_value(serde_json::json!({
+            "name": "web",
+            "namespace": "default",
+            "labels": { "tier": "frontend", "app": "web" },
+            "annotations": { "note": "say \"hi\"" }
+        }))
+        .unwrap();
+        let value = |field_path| downward_api_value(&metadata, &item(field_path)).unwrap();
+        assert_eq!(value("metadata.name"), "web");
+        assert_eq!(value("metadata.namespace"), "default");
+        assert_eq!(value("metadata.labels"), "app=\"web\"\ntier=\"frontend\"");
+        assert_eq!(value("metadata.annotations"), "note=\"say \\\"hi\\\"\"");
+        assert_eq!(value("metadata.labels['tier']"), "frontend");
+        assert_eq!(value("metadata.labels['missing']"), "");
+        assert!(downward_api_value(&metadata, &item("spec.nodeName")).is_err());
+    }
+}
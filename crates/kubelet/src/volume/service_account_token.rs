@@ -0,0 +1,126 @@
+//! Service account tokens for projected volumes, which are requested from the TokenRequest API.
+//! The tokens are bound to the pod, so they stop being valid once it is deleted, and they expire,
+//! so they are requested again before they do.
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use k8s_openapi::api::authentication::v1::{BoundObjectReference, TokenRequest, TokenRequestSpec};
+use k8s_openapi::api::core::v1::ServiceAccountTokenProjection;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use super::{WatchResult, WATCH_RETRY_DELAY};
+use crate::pod::Pod;
+
+/// How long tokens are valid for if the volume doesn't say.
+const DEFAULT_EXPIRATION_SECONDS: i64 = 60 * 60;
+/// The shortest lifetime the API server allows tokens to have.
+const MIN_EXPIRATION_SECONDS: i64 = 10 * 60;
+
+/// A token that was requested for a volume, and when to replace it.
+#[derive(Debug)]
+struct Cached {
+    token: String,
+    refresh_at: Instant,
+}
+
+/// A service account token projected into a volume.
+#[derive(Clone, Debug)]
+pub(super) struct Token {
+    pub(super) path: String,
+    service_account: String,
+    audience: Option<String>,
+    expiration_seconds: i64,
+    pod_name: String,
+    pod_uid: Option<String>,
+    cached: Arc<Mutex<Option<Cached>>>,
+}
+
+impl Token {
+    pub(super) fn new(projection: &ServiceAccountTokenProjection, pod: &Pod) -> Self {
+        Token {
+            path: projection.path.clone(),
+            service_account: pod.service_account_name().unwrap_or("default").to_owned(),
+            audience: projection.audience.clone().filter(|a| !a.is_empty()),
+            expiration_seconds: projection
+                .expiration_seconds
+                .unwrap_or(DEFAULT_EXPIRATION_SECONDS)
+                .max(MIN_EXPIRATION_SECONDS),
+            pod_name: pod.name().to_owned(),
+            pod_uid: pod.as_kube_pod().metadata.uid.clone(),
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Get the current token, requesting a new one if there is none yet or if the current one is
+    /// 80% of the way through its lifetime.
+    pub(super) async fn get(
+        &self,
+        namespace: &str,
+        client: &kube::Client,
+    ) -> anyhow::Result<String> {
+        let mut cached = self.cached.lock().await;
+        match &*cached {
+            Some(token) if Instant::now() < token.refresh_at => Ok(token.token.clone()),
+            _ => {
+                let token = self.request(namespace, client).await?;
+                let value = token.token.clone();
+                *cached = Some(token);
+                Ok(value)
+            }
+        }
+    }
+
+    async fn request(&self, namespace: &str, client: &kube::Client) -> anyhow::Result<Cached> {
+        let token_request = TokenRequest {
+            spec: TokenRequestSpec {
+                // No audiences means the API server's own audiences
+                audiences: self.audience.iter().cloned().collect(),
+                bound_object_ref: Some(BoundObjectReference {
+                    api_version: Some("v1".to_owned()),
+                    kind: Some("Pod".to_owned()),
+                    name: Some(self.pod_name.clone()),
+                    uid: self.pod_uid.clone(),
+                }),
+                expiration_seconds: Some(self.expiration_seconds),
+            },
+            ..Default::default()
+        };
+        let request = http::Request::post(format!(
+            "/api/v1/namespaces/{}/serviceaccounts/{}/token",
+            namespace, self.service_account
+        ))
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&token_request)?)?;
+        let response: TokenRequest = client.request(request).await?;
+        let status = response.status.ok_or_else(|| {
+            anyhow::anyhow!(
+                "no token was returned for service account {}",
+                self.service_account
+            )
+        })?;
+        let lifetime = (status.expiration_timestamp.0 - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        Ok(Cached {
+            token: status.token,
+            refresh_at: Instant::now() + lifetime.mul_f64(0.8),
+        })
+    }
+
+    /// Yield whenever the token is due to be replaced.
+    pub(super) fn watch(&self) -> BoxStream<'static, WatchResult> {
+        futures::stream::unfold(self.cached.clone(), |cached| async move {
+            let retry_at = Instant::now() + WATCH_RETRY_DELAY;
+            let refresh_at = match &*cached.lock().await {
+                Some(token) => token.refresh_at.max(retry_at),
+                None => retry_at,
+            };
+            tokio::time::delay_until(refresh_at).await;
+            Some((Ok(()), cached))
+        })
+        .boxed()
+    }
+}
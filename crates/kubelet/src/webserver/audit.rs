@@ -0,0 +1,329 @@
+//! Auditing of the requests that give remote access to pods: reading container logs, exec,
+//! attach and port forwarding.
+//!
+//! Two events are recorded for each of these requests: one when it is received, saying who made it
+//! and which pod and container it is for, and one once it is done with, which adds the command
+//! that was run or ports that were forwarded and how it turned out. Requests that are denied are
+//! recorded too. Events are appended to a file as
+//! a JSON object per line, and posted to a webhook in batches of JSON objects, as configured.
+//! Auditing never holds up a request: events that can't be recorded are dropped with a warning.
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, warn};
+use url::Url;
+
+use super::auth::User;
+use crate::config::ServerConfig;
+
+/// The most events posted to the webhook at once.
+const MAX_WEBHOOK_BATCH: usize = 100;
+
+/// Where events are recorded.
+#[derive(Clone, Default)]
+pub(crate) struct AuditLog {
+    file: Option<Arc<Mutex<File>>>,
+    webhook: Option<UnboundedSender<Event>>,
+}
+
+impl AuditLog {
+    /// Open the audit log the server is configured with. Posting to a webhook starts a task, so
+    /// this must be called from within a Tokio runtime.
+    pub(crate) fn new(config: &ServerConfig) -> anyhow::Result<Self> {
+        let file = match &config.audit_log_path {
+            Some(path) => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| {
+                        anyhow::anyhow!("unable to open audit log {}: {}", path.display(), e)
+                    })?;
+                Some(Arc::new(Mutex::new(file)))
+            }
+            None => None,
+        };
+        let webhook = match &config.audit_webhook_url {
+            Some(url) => {
+                let url = Url::parse(url)
+                    .map_err(|e| anyhow::anyhow!("invalid audit webhook URL {}: {}", url, e))?;
+                if url.scheme() != "http" && url.scheme() != "https" {
+                    anyhow::bail!(
+                        "unsupported audit webhook scheme {}, expected http or https",
+                        url.scheme()
+                    );
+                }
+                let (sender, receiver) = unbounded_channel();
+                tokio::spawn(post_events(reqwest::Client::new(), url, receiver));
+                Some(sender)
+            }
+            None => None,
+        };
+        Ok(AuditLog { file, webhook })
+    }
+
+    /// Start auditing a request for a pod, or one of its containers, recording that it was
+    /// received. It is recorded again once it is finished.
+    pub(crate) fn start<N: AsRef<str>, P: AsRef<str>>(
+        &self,
+        verb: Verb,
+        user: Option<User>,
+        namespace: N,
+        pod: P,
+        container: Option<&str>,
+    ) -> Entry {
+        let event = Event {
+            audit_id: uuid::Uuid::new_v4().to_string(),
+            stage: Stage::RequestReceived,
+            request_received_timestamp: Utc::now(),
+            completed_timestamp: None,
+            verb,
+            user,
+            namespace: namespace.as_ref().to_owned(),
+            pod: pod.as_ref().to_owned(),
+            container: container.map(str::to_owned),
+            command: None,
+            ports: None,
+            outcome: None,
+        };
+        debug!("Audit event: {:?}", event);
+        self.record(&event);
+        Entry {
+            log: self.clone(),
+            event,
+        }
+    }
+
+    /// Start auditing a request by its path, if it is one of the requests that are audited. This
+    /// is used for requests that are turned away before they are routed.
+    pub(crate) fn start_for_path(&self, user: Option<User>, path: &str) -> Option<Entry> {
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let (verb, namespace, pod, container) = match segments.as_slice() {
+            ["containerLogs", namespace, pod, container] => {
+                (Verb::Log, namespace, pod, Some(container))
+            }
+            ["exec", namespace, pod, container] => (Verb::Exec, namespace, pod, Some(container)),
+            ["attach", namespace, pod, container] => {
+                (Verb::Attach, namespace, pod, Some(container))
+            }
+            ["portForward", namespace, pod] => (Verb::PortForward, namespace, pod, None),
+            _ => return None,
+        };
+        Some(self.start(verb, user, namespace, pod, container.copied()))
+    }
+
+    fn record(&self, event: &Event) {
+        if let Some(file) = &self.file {
+            match serde_json::to_vec(event) {
+                Ok(mut line) => {
+                    line.push(b'\n');
+                    // The line is written at once, so that lines of concurrent requests don't mix
+                    if let Err(e) = file.lock().unwrap().write_all(&line) {
+                        warn!("Unable to write audit event {}: {}", event.audit_id, e);
+                    }
+                }
+                Err(e) => warn!("Unable to serialize audit event {}: {}", event.audit_id, e),
+            }
+        }
+        if let Some(webhook) = &self.webhook {
+            // The task only stops when the kubelet does, so there is nowhere else to send it
+            let _ = webhook.send(event.clone());
+        }
+    }
+}
+
+/// A request being audited.
+pub(crate) struct Entry {
+    log: AuditLog,
+    event: Event,
+}
+
+impl Entry {
+    /// Record the command run by the request.
+    pub(crate) fn command(mut self, command: &[String]) -> Self {
+        self.event.command = Some(command.to_vec());
+        self
+    }
+
+    /// Record the ports forwarded by the request.
+    pub(crate) fn ports(mut self, ports: &[u16]) -> Self {
+        self.event.ports = Some(ports.to_vec());
+        self
+    }
+
+    /// Record how the request turned out.
+    pub(crate) fn finish(mut self, outcome: Outcome) {
+        self.event.stage = Stage::ResponseComplete;
+        self.event.completed_timestamp = Some(Utc::now());
+        self.event.outcome = Some(outcome);
+        debug!("Audit event: {:?}", self.event);
+        self.log.record(&self.event);
+    }
+}
+
+/// The kinds of request that are audited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Verb {
+    /// Reading a container's logs.
+    Log,
+    /// Running a command in a container.
+    Exec,
+    /// Attaching to a container's process.
+    Attach,
+    /// Forwarding ports of a pod.
+    PortForward,
+}
+
+/// How far along a request was when an event was recorded for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub(crate) enum Stage {
+    /// The request has just been received, so how it turns out is yet to be known.
+    RequestReceived,
+    /// The request is done with.
+    ResponseComplete,
+}
+
+/// How an audited request turned out.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "result")]
+pub(crate) enum Outcome {
+    /// The request was served in full.
+    Succeeded,
+    /// The command that was run, or the process that was attached to, exited with this code.
+    Exited {
+        #[serde(rename = "exitCode")]
+        exit_code: i32,
+    },
+    /// The request failed.
+    Failed { message: String },
+    /// The client went away before the request was done with.
+    Disconnected,
+    /// The user isn't allowed to make the request.
+    Forbidden { reason: String },
+}
+
+/// An audit event, as it is recorded.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Event {
+    audit_id: String,
+    stage: Stage,
+    request_received_timestamp: DateTime<Utc>,
+    completed_timestamp: Option<DateTime<Utc>>,
+    verb: Verb,
+    /// Who made the request, if it was authenticated
+    user: Option<User>,
+    namespace: String,
+    pod: String,
+    container: Option<String>,
+    command: Option<Vec<String>>,
+    ports: Option<Vec<u16>>,
+    /// How the request turned out, once it is done with
+    outcome: Option<Outcome>,
+}
+
+/// Posts events to the webhook as they are recorded, batching up any that are recorded while a
+/// batch is being posted.
+async fn post_events(client: reqwest::Client, url: Url, mut receiver: UnboundedReceiver<Event>) {
+    while let Some(event) = receiver.recv().await {
+        let mut batch = vec![event];
+        while batch.len() < MAX_WEBHOOK_BATCH {
+            match receiver.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        let result = client
+            .post(url.clone())
+            .json(&batch)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            warn!(
+                "Unable to post {} audit events to {}: {}",
+                batch.len(),
+                url,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn audited_paths_are_recognized() {
+        let log = AuditLog::default();
+        let entry = log
+            .start_for_path(None, "/exec/default/hello/greeter")
+            .unwrap();
+        assert_eq!(entry.event.verb, Verb::Exec);
+        assert_eq!(entry.event.namespace, "default");
+        assert_eq!(entry.event.pod, "hello");
+        assert_eq!(entry.event.container.as_deref(), Some("greeter"));
+
+        let entry = log
+            .start_for_path(None, "/portForward/default/hello")
+            .unwrap();
+        assert_eq!(entry.event.verb, Verb::PortForward);
+        assert_eq!(entry.event.container, None);
+
+        assert!(log.start_for_path(None, "/stats/summary").is_none());
+        assert!(log.start_for_path(None, "/exec/default/hello").is_none());
+    }
+
+    #[test]
+    fn events_are_recorded_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog {
+            file: Some(Arc::new(Mutex::new(File::create(&path).unwrap()))),
+            webhook: None,
+        };
+        let user = User {
+            name: "jane".to_owned(),
+            groups: vec!["admins".to_owned()],
+            ..Default::default()
+        };
+        log.start(Verb::Exec, Some(user), "default", "hello", Some("greeter"))
+            .command(&["ls".to_owned(), "/".to_owned()])
+            .finish(Outcome::Exited { exit_code: 2 });
+        log.start(Verb::PortForward, None, "default", "hello", None)
+            .ports(&[8080])
+            .finish(Outcome::Disconnected);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0]["stage"], "RequestReceived");
+        assert_eq!(events[0]["verb"], "exec");
+        assert_eq!(events[0]["user"]["username"], "jane");
+        assert_eq!(events[0]["container"], "greeter");
+        assert_eq!(events[0]["outcome"], serde_json::Value::Null);
+        assert_eq!(events[1]["stage"], "ResponseComplete");
+        assert_eq!(events[1]["auditId"], events[0]["auditId"]);
+        assert_eq!(events[1]["command"], serde_json::json!(["ls", "/"]));
+        assert_eq!(
+            events[1]["outcome"],
+            serde_json::json!({ "result": "Exited", "exitCode": 2 })
+        );
+        assert_eq!(events[2]["stage"], "RequestReceived");
+        assert_eq!(events[2]["verb"], "portforward");
+        assert_eq!(events[3]["ports"], serde_json::json!([8080]));
+        assert_eq!(events[3]["outcome"]["result"], "Disconnected");
+    }
+}
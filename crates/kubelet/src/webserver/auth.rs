@@ -0,0 +1,367 @@
+//! Authentication and authorization of requests to the Kubelet server.
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use http::{Method, Request, StatusCode};
+use hyper::Body;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec,
+};
+use kube::api::{Api, PostParams};
+use serde::Serialize;
+use tracing::{debug, error};
+use warp::path::FullPath;
+use warp::{Filter, Rejection, Reply};
+
+use super::audit::{AuditLog, Outcome};
+use crate::config::{AuthorizationMode, ServerConfig};
+
+/// The OID of the common name attribute of an X.509 name.
+const COMMON_NAME_OID: &str = "2.5.4.3";
+/// The OID of the organization attribute of an X.509 name.
+const ORGANIZATION_OID: &str = "2.5.4.10";
+
+/// The user a request was made by.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct User {
+    #[serde(rename = "username")]
+    pub(crate) name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) uid: Option<String>,
+    pub(crate) groups: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) extra: BTreeMap<String, Vec<String>>,
+}
+
+impl User {
+    /// The user identified by a client certificate. Like the Kubernetes API server, the subject's
+    /// common name is the user's name and its organizations are the user's groups.
+    fn from_certificate(der: &[u8]) -> anyhow::Result<Self> {
+        let (_, cert) = x509_parser::parse_x509_der(der)
+            .map_err(|e| anyhow::anyhow!("invalid client certificate: {:?}", e))?;
+        let mut user = User::default();
+        let attributes = cert
+            .tbs_certificate
+            .subject
+            .rdn_seq
+            .iter()
+            .flat_map(|rdn| rdn.set.iter());
+        for attribute in attributes {
+            let value = match attribute.attr_value.as_str() {
+                Ok(value) => value.to_owned(),
+                Err(_) => continue,
+            };
+            match attribute.attr_type.to_id_string().as_str() {
+                COMMON_NAME_OID => user.name = value,
+                ORGANIZATION_OID => user.groups.push(value),
+                _ => (),
+            }
+        }
+        if user.name.is_empty() {
+            anyhow::bail!("client certificate has no common name");
+        }
+        Ok(user)
+    }
+
+    /// The user of requests that don't identify themselves.
+    fn anonymous() -> Self {
+        User {
+            name: "system:anonymous".to_owned(),
+            groups: vec!["system:unauthenticated".to_owned()],
+            ..Default::default()
+        }
+    }
+}
+
+/// The user a request was authenticated as. One is put in the extensions of each request before it
+/// is routed, and filled in by [`filter`] once the request has been authorized, so that routes can
+/// tell who they are serving with [`user`].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Identity(Arc<Mutex<Option<User>>>);
+
+/// Put an empty [`Identity`] in the extensions of a request, along with the user its connection
+/// was identified as, if there is one.
+pub(crate) fn identify(req: &mut Request<Body>, certificate_user: Option<User>) {
+    if let Some(user) = certificate_user {
+        req.extensions_mut().insert(user);
+    }
+    req.extensions_mut().insert(Identity::default());
+}
+
+/// Reasons a request is turned away before it reaches a route.
+#[derive(Debug)]
+enum AuthError {
+    /// The request's credentials were rejected.
+    Unauthorized,
+    /// The user isn't allowed to make the request.
+    Forbidden(String),
+    /// The API server could not be asked about the request.
+    Failed(anyhow::Error),
+}
+
+impl warp::reject::Reject for AuthError {}
+
+/// Checks requests against the Kubernetes API, as configured by the [`ServerConfig`].
+pub(crate) struct Auth {
+    client: kube::Client,
+    node_name: String,
+    token_webhook: bool,
+    mode: AuthorizationMode,
+    audit: AuditLog,
+}
+
+impl Auth {
+    /// Check requests as configured, recording those that are denied access to pods in `audit`.
+    pub(crate) fn new(
+        client: kube::Client,
+        node_name: String,
+        config: &ServerConfig,
+        audit: AuditLog,
+    ) -> Self {
+        Auth {
+            client,
+            node_name,
+            token_webhook: config.authentication_token_webhook,
+            mode: config.authorization_mode,
+            audit,
+        }
+    }
+
+    async fn check(
+        &self,
+        method: &Method,
+        path: &str,
+        certificate_user: Option<User>,
+        authorization: Option<String>,
+    ) -> Result<User, AuthError> {
+        let user = match certificate_user {
+            Some(user) => user,
+            None => self.authenticate(authorization).await?,
+        };
+        if let Err(e) = self.authorize(&user, method, path).await {
+            if let AuthError::Forbidden(reason) = &e {
+                if let Some(entry) = self.audit.start_for_path(Some(user.clone()), path) {
+                    entry.finish(Outcome::Forbidden {
+                        reason: reason.clone(),
+                    });
+                }
+            }
+            return Err(e);
+        }
+        debug!("Authorized {} {} for user {}", method, path, user.name);
+        Ok(user)
+    }
+
+    /// Identify the user a request was made by from its bearer token. Requests without one, or
+    /// a client certificate, are anonymous.
+    async fn authenticate(&self, authorization: Option<String>) -> Result<User, AuthError> {
+        let token = match authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(token) if self.token_webhook => token.trim().to_owned(),
+            _ => return Ok(User::anonymous()),
+        };
+        let review = TokenReview {
+            spec: TokenReviewSpec {
+                token: Some(token),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let review = Api::<TokenReview>::all(self.client.clone())
+            .create(&PostParams::default(), &review)
+            .await
+            .map_err(|e| AuthError::Failed(e.into()))?;
+        let status = review.status.unwrap_or_default();
+        if status.authenticated != Some(true) {
+            debug!(
+                "Token was not authenticated: {}",
+                status.error.unwrap_or_default()
+            );
+            return Err(AuthError::Unauthorized);
+        }
+        let user = status.user.unwrap_or_default();
+        Ok(User {
+            name: user.username.unwrap_or_default(),
+            uid: user.uid,
+            groups: user.groups.unwrap_or_default(),
+            extra: user.extra.unwrap_or_default(),
+        })
+    }
+
+    /// Check that the user may make the request, which is treated as a request for a subresource
+    /// of this node.
+    async fn authorize(&self, user: &User, method: &Method, path: &str) -> Result<(), AuthError> {
+        if self.mode == AuthorizationMode::AlwaysAllow {
+            return Ok(());
+        }
+        let review = SubjectAccessReview {
+            spec: SubjectAccessReviewSpec {
+                user: Some(user.name.clone()),
+                uid: user.uid.clone(),
+                groups: Some(user.groups.clone()),
+                extra: Some(user.extra.clone()),
+                resource_attributes: Some(ResourceAttributes {
+                    verb: Some(verb(method).to_owned()),
+                    version: Some("v1".to_owned()),
+                    resource: Some("nodes".to_owned()),
+                    subresource: Some(subresource(path).to_owned()),
+                    name: Some(self.node_name.clone()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let review = Api::<SubjectAccessReview>::all(self.client.clone())
+            .create(&PostParams::default(), &review)
+            .await
+            .map_err(|e| AuthError::Failed(e.into()))?;
+        match review.status {
+            Some(status) if status.allowed => Ok(()),
+            status => Err(AuthError::Forbidden(format!(
+                "user={}, verb={}, resource=nodes, subresource={}: {}",
+                user.name,
+                verb(method),
+                subresource(path),
+                status.and_then(|s| s.reason).unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+/// A filter that rejects requests which fail authentication or authorization. Use
+/// [`handle_rejection`] to turn those rejections into responses.
+pub(crate) fn filter(auth: Arc<Auth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::ext::optional::<User>())
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |method: Method,
+                  path: FullPath,
+                  certificate_user: Option<User>,
+                  identity: Option<Identity>,
+                  authorization: Option<String>| {
+                let auth = auth.clone();
+                async move {
+                    let user = auth
+                        .check(&method, path.as_str(), certificate_user, authorization)
+                        .await
+                        .map_err(warp::reject::custom)?;
+                    if let Some(identity) = identity {
+                        *identity.0.lock().unwrap() = Some(user);
+                    }
+                    Ok::<_, Rejection>(())
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// A filter that extracts the user a request was authorized for by [`filter`], if it was.
+pub(crate) fn user() -> impl Filter<Extract = (Option<User>,), Error = Infallible> + Clone {
+    warp::ext::optional::<Identity>()
+        .map(|identity: Option<Identity>| identity.and_then(|i| i.0.lock().unwrap().clone()))
+}
+
+/// Identify the user of a connection from the certificate chain the client presented, which
+/// has already been verified during the TLS handshake.
+pub(crate) fn client_certificate_user(certs: &[rustls::Certificate]) -> Option<User> {
+    let cert = certs.first()?;
+    match User::from_certificate(&cert.0) {
+        Ok(user) => Some(user),
+        Err(e) => {
+            debug!("Unable to identify client from its certificate: {}", e);
+            None
+        }
+    }
+}
+
+/// Respond to requests rejected by [`filter`]. Any other rejection is passed on.
+pub(crate) async fn handle_rejection(rejection: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    let (code, body) = match rejection.find::<AuthError>() {
+        Some(AuthError::Unauthorized) => (StatusCode::UNAUTHORIZED, "Unauthorized".to_owned()),
+        Some(AuthError::Forbidden(reason)) => {
+            (StatusCode::FORBIDDEN, format!("Forbidden ({})", reason))
+        }
+        Some(AuthError::Failed(e)) => {
+            error!("Error checking request with the API server: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            )
+        }
+        None => return Err(rejection),
+    };
+    Ok(Box::new(warp::reply::with_status(body, code)))
+}
+
+/// The verb a request is authorized as.
+fn verb(method: &Method) -> &'static str {
+    match *method {
+        Method::GET | Method::HEAD => "get",
+        Method::POST => "create",
+        Method::PUT => "update",
+        Method::PATCH => "patch",
+        Method::DELETE => "delete",
+        _ => "*",
+    }
+}
+
+/// The node subresource a request is authorized as, following the mapping used by the Kubernetes
+/// kubelet.
+fn subresource(path: &str) -> &'static str {
+    match path.trim_start_matches('/').split('/').next() {
+        Some("stats") => "stats",
+        Some("metrics") => "metrics",
+        Some("logs") => "log",
+        Some("spec") => "spec",
+        Some("checkpoint") => "checkpoint",
+        _ => "proxy",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths_map_to_node_subresources() {
+        assert_eq!(subresource("/stats/summary"), "stats");
+        assert_eq!(subresource("/metrics"), "metrics");
+        assert_eq!(subresource("/containerLogs/default/pod/container"), "proxy");
+        assert_eq!(subresource("/exec/default/pod/container"), "proxy");
+        assert_eq!(
+            subresource("/checkpoint/default/pod/container"),
+            "checkpoint"
+        );
+        assert_eq!(subresource("/"), "proxy");
+    }
+
+    #[test]
+    fn client_certificate_identifies_user() {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "system:node:krusty");
+        params
+            .distinguished_name
+            .push(rcgen::DnType::OrganizationName, "system:nodes");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let user = User::from_certificate(&cert.serialize_der().unwrap()).unwrap();
+        assert_eq!(user.name, "system:node:krusty");
+        assert_eq!(user.groups, vec!["system:nodes"]);
+    }
+
+    #[test]
+    fn methods_map_to_verbs() {
+        assert_eq!(verb(&Method::GET), "get");
+        assert_eq!(verb(&Method::POST), "create");
+        assert_eq!(verb(&Method::DELETE), "delete");
+    }
+}
@@ -0,0 +1,207 @@
+//! Limits on how long requests to the Kubelet server may take and how many streams it serves at
+//! once, so that a flood of clients can't exhaust the node's memory or file descriptors.
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http::status::StatusCode;
+use http::{Request, Response};
+use hyper::Body;
+use tower::Service;
+
+use crate::config::ServerConfig;
+
+/// How long each kind of request may take to start answering.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RouteTimeouts {
+    log: Duration,
+    exec: Duration,
+    attach: Duration,
+    /// The timeout of every other request, which are all quick to answer
+    other: Duration,
+}
+
+impl RouteTimeouts {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        RouteTimeouts {
+            log: config.log_request_timeout,
+            exec: config.exec_request_timeout,
+            attach: config.attach_request_timeout,
+            other: config.request_timeout,
+        }
+    }
+
+    /// The timeout of requests for the given path.
+    fn for_path(&self, path: &str) -> Duration {
+        match path.trim_start_matches('/').split('/').next() {
+            Some("containerLogs") => self.log,
+            Some("exec") => self.exec,
+            Some("attach") => self.attach,
+            _ => self.other,
+        }
+    }
+}
+
+/// A service that answers with `504 Gateway Timeout` if the inner service doesn't start its
+/// response within the timeout of the request's route. Only the response head is timed, so
+/// streaming responses can run for as long as they need once started.
+#[derive(Clone)]
+pub(crate) struct Timeout<S> {
+    inner: S,
+    timeouts: RouteTimeouts,
+}
+
+impl<S> Timeout<S> {
+    pub(crate) fn new(inner: S, timeouts: RouteTimeouts) -> Self {
+        Timeout { inner, timeouts }
+    }
+}
+
+impl<S> Service<Request<Body>> for Timeout<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let timeout = self.timeouts.for_path(req.uri().path());
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, response).await {
+                Ok(response) => response,
+                Err(_) => {
+                    let mut response = Response::new(Body::from("Request timed out"));
+                    *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+                    Ok(response)
+                }
+            }
+        })
+    }
+}
+
+/// Counts the streams being served, refusing new ones once the maximum is reached.
+#[derive(Clone)]
+pub(crate) struct StreamLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl StreamLimit {
+    pub(crate) fn new(max: usize) -> Self {
+        StreamLimit {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Reserve a stream, returning `None` if the maximum is already being served. The stream is
+    /// released when the returned permit is dropped.
+    pub(crate) fn try_acquire(&self) -> Option<StreamPermit> {
+        let mut active = self.active.load(Ordering::SeqCst);
+        loop {
+            if active >= self.max {
+                return None;
+            }
+            match self.active.compare_exchange(
+                active,
+                active + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    return Some(StreamPermit {
+                        active: self.active.clone(),
+                    })
+                }
+                Err(current) => active = current,
+            }
+        }
+    }
+}
+
+/// A stream reserved from a [`StreamLimit`].
+pub(crate) struct StreamPermit {
+    active: Arc<AtomicUsize>,
+}
+
+impl StreamPermit {
+    /// Keep the stream reserved until `stream` completes.
+    pub(crate) async fn hold<F: Future>(self, stream: F) -> F::Output {
+        let output = stream.await;
+        drop(self);
+        output
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The response to a stream request refused by a [`StreamLimit`].
+pub(crate) fn too_many_streams() -> Response<Body> {
+    let mut response = Response::new(Body::from("Too many streams, try again later"));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stream_limit_releases_dropped_permits() {
+        let limit = StreamLimit::new(2);
+        let first = limit.try_acquire().expect("first stream should be allowed");
+        let _second = limit
+            .try_acquire()
+            .expect("second stream should be allowed");
+        assert!(limit.try_acquire().is_none());
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    fn timeouts(other: Duration) -> RouteTimeouts {
+        RouteTimeouts {
+            log: Duration::from_secs(1),
+            exec: Duration::from_secs(2),
+            attach: Duration::from_secs(3),
+            other,
+        }
+    }
+
+    #[test]
+    fn timeouts_are_chosen_by_route() {
+        let timeouts = timeouts(Duration::from_secs(4));
+        let of = |path| timeouts.for_path(path).as_secs();
+        assert_eq!(of("/containerLogs/default/hello/greeter"), 1);
+        assert_eq!(of("/exec/default/hello/greeter"), 2);
+        assert_eq!(of("/attach/default/hello/greeter"), 3);
+        assert_eq!(of("/healthz"), 4);
+        assert_eq!(of("/stats/summary"), 4);
+        assert_eq!(of("/executable"), 4);
+    }
+
+    #[tokio::test]
+    async fn timeout_answers_slow_requests() {
+        let slow = tower::service_fn(|_: Request<Body>| async {
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        });
+        let mut service = Timeout::new(slow, timeouts(Duration::from_millis(10)));
+        let response = service.call(Request::new(Body::empty())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+}
@@ -1,55 +1,366 @@
-use crate::config::ServerConfig;
+use crate::config::Config;
+use crate::exec::CommandOptions;
 use crate::log::{Options, Sender};
-use crate::provider::{NotImplementedError, Provider};
+use crate::provider::{Provider, ProviderError};
+use crate::stats::{self, Summary};
+use futures::StreamExt;
 use http::status::StatusCode;
 use http::Response;
 use hyper::Body;
+use krator::SyncLoopStatus;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::Service;
 /// Server is an HTTP(S) server for answering Kubelet callbacks.
 ///
 /// Logs and exec calls are the main things that a server should handle.
-use log::{debug, error};
-use std::convert::Infallible;
-use std::sync::Arc;
+use tracing::{debug, error, info_span, warn};
+use tracing_futures::Instrument;
+use warp::ws::Ws;
 use warp::Filter;
 
+mod audit;
+mod auth;
+mod limit;
+mod port_forward;
+mod profiling;
+mod stream;
+mod tls;
+
+use audit::{AuditLog, Outcome, Verb};
+use stream::STREAM_PROTOCOL;
+
 const PING: &str = "this is the Krustlet HTTP server";
 
+/// How long watching for pods may keep failing before the sync loop is reported as unhealthy.
+const SYNC_LOOP_FAILURE_THRESHOLD: Duration = Duration::from_secs(120);
+
 /// Start the Krustlet HTTP(S) server
 ///
 /// This is a primitive implementation of an HTTP provider for the internal API.
+///
+/// Requests are authenticated and authorized with the Kubernetes API through `client`, as set in
+/// the server configuration. Responses must start within the configured request timeout, and
+/// only a limited number of log, exec, attach and port forward streams are served at once.
+///
+/// The health of the pod sync loop is reported from `sync_loop`.
 pub(crate) async fn start<T: Provider>(
     provider: Arc<T>,
-    config: &ServerConfig,
+    config: &Config,
+    client: kube::Client,
+    sync_loop: SyncLoopStatus,
 ) -> anyhow::Result<()> {
+    let start_time = chrono::Utc::now();
     let health = warp::get().and(warp::path("healthz")).map(|| PING);
     let ping = warp::get().and(warp::path::end()).map(|| PING);
+    let sync_loop_health = warp::get()
+        .and(warp::path!("healthz" / "syncloop"))
+        .and_then(move || get_sync_loop_health(sync_loop.clone()));
+
+    let configz_config = Arc::new(config.clone());
+    let configz = warp::get()
+        .and(warp::path("configz"))
+        .and(warp::path::end())
+        .and_then(move || get_configz(configz_config.clone()));
+
+    let profiling_enabled = config.server_config.enable_profiling;
+    if profiling_enabled && cfg!(not(feature = "profiling")) {
+        warn!("Profiling is enabled, but the kubelet was built without the profiling feature");
+    }
+    let pprof_profile = warp::get()
+        .and(warp::path!("debug" / "pprof" / "profile"))
+        .and(warp::query::<profiling::ProfileOptions>())
+        .and_then(move |opts| profiling::profile(profiling_enabled, opts));
+    let pprof_heap = warp::get()
+        .and(warp::path!("debug" / "pprof" / "heap"))
+        .and_then(move || profiling::heap(profiling_enabled));
+
+    let streams = limit::StreamLimit::new(config.server_config.max_streams);
+    let audit = AuditLog::new(&config.server_config)?;
 
     let logs_provider = provider.clone();
+    let logs_streams = streams.clone();
+    let logs_audit = audit.clone();
     let logs = warp::get()
         .and(warp::path!("containerLogs" / String / String / String))
         .and(warp::query::<Options>())
-        .and_then(move |namespace, pod, container, opts| {
+        .and(auth::user())
+        .and_then(move |namespace, pod, container, opts, user| {
             let provider = logs_provider.clone();
-            get_container_logs(provider, namespace, pod, container, opts)
+            let permit = logs_streams.try_acquire();
+            let entry = logs_audit.start(Verb::Log, user, &namespace, &pod, Some(&container));
+            get_container_logs(provider, namespace, pod, container, opts, permit, entry)
         });
 
     let exec_provider = provider.clone();
-    let exec = warp::post()
+    let exec_streams = streams.clone();
+    let exec_audit = audit.clone();
+    let exec = warp::get()
+        .and(warp::path!("exec" / String / String / String))
+        .and(warp::query::raw())
+        .and(warp::ws())
+        .and(auth::user())
+        .map(
+            move |namespace, pod, container, query: String, ws: Ws, user| {
+                let provider = exec_provider.clone();
+                let entry =
+                    exec_audit.start(Verb::Exec, user, &namespace, &pod, Some(&container));
+                let options = match parse_exec_query(&query) {
+                    Ok(options) => options,
+                    Err(e) => return bad_request(e, entry),
+                };
+                let entry = entry.command(&options.command);
+                let permit = match exec_streams.try_acquire() {
+                    Some(permit) => permit,
+                    None => return too_many_streams(entry),
+                };
+                crate::metrics::SERVER_REQUESTS
+                    .with_label_values(&["exec"])
+                    .inc();
+                let reply = ws.on_upgrade(move |socket| {
+                    debug!(
+                        "Got exec request for container {} in pod {} in namespace {}. Options: {:?}.",
+                        container, pod, namespace, options
+                    );
+                    let span = info_span!(
+                        parent: &crate::telemetry::pod_span(&namespace, &pod),
+                        "exec",
+                        pod = %pod,
+                        namespace = %namespace,
+                        container = %container,
+                        command = ?options.command
+                    );
+                    permit.hold(async move {
+                        let outcome = stream::serve(
+                            socket,
+                            options.clone(),
+                            "Exec",
+                            move |stdin, sender| async move {
+                                provider
+                                    .exec(namespace, pod, container, options, stdin, sender)
+                                    .await
+                            },
+                        )
+                        .instrument(span)
+                        .await;
+                        entry.finish(outcome);
+                    })
+                });
+                Box::new(warp::reply::with_header(
+                    reply,
+                    "Sec-WebSocket-Protocol",
+                    STREAM_PROTOCOL,
+                )) as Box<dyn warp::Reply>
+            },
+        );
+
+    let attach_provider = provider.clone();
+    let attach_streams = streams.clone();
+    let attach_audit = audit.clone();
+    let attach = warp::get()
+        .and(warp::path!("attach" / String / String / String))
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::ws())
+        .and(auth::user())
+        .map(
+            move |namespace, pod, container, query: String, ws: Ws, user| {
+                let provider = attach_provider.clone();
+                let entry =
+                    attach_audit.start(Verb::Attach, user, &namespace, &pod, Some(&container));
+                let options = match parse_exec_query(&query) {
+                    Ok(options) => options,
+                    Err(e) => return bad_request(e, entry),
+                };
+                let permit = match attach_streams.try_acquire() {
+                    Some(permit) => permit,
+                    None => return too_many_streams(entry),
+                };
+                crate::metrics::SERVER_REQUESTS
+                    .with_label_values(&["attach"])
+                    .inc();
+                let reply = ws.on_upgrade(move |socket| {
+                    debug!(
+                        "Got attach request for container {} in pod {} in namespace {}. Options: {:?}.",
+                        container, pod, namespace, options
+                    );
+                    let span = info_span!(
+                        parent: &crate::telemetry::pod_span(&namespace, &pod),
+                        "attach",
+                        pod = %pod,
+                        namespace = %namespace,
+                        container = %container
+                    );
+                    permit.hold(async move {
+                        let outcome = stream::serve(
+                            socket,
+                            options,
+                            "Attach",
+                            move |stdin, sender| async move {
+                                provider
+                                    .attach(namespace, pod, container, stdin, sender)
+                                    .await
+                            },
+                        )
+                        .instrument(span)
+                        .await;
+                        entry.finish(outcome);
+                    })
+                });
+                Box::new(warp::reply::with_header(
+                    reply,
+                    "Sec-WebSocket-Protocol",
+                    STREAM_PROTOCOL,
+                )) as Box<dyn warp::Reply>
+            },
+        );
+
+    let port_forward_provider = provider.clone();
+    let port_forward_streams = streams;
+    let port_forward_audit = audit.clone();
+    // Only websocket upgrades are accepted: SPDY port forwarding is not served.
+    let port_forward = warp::get()
+        .and(warp::path!("portForward" / String / String))
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::ws())
+        .and(auth::user())
+        .map(
+            move |namespace: String, pod: String, query: String, ws: Ws, user| {
+                let provider = port_forward_provider.clone();
+                let entry =
+                    port_forward_audit.start(Verb::PortForward, user, &namespace, &pod, None);
+                let ports = match port_forward::parse_ports(&query) {
+                    Ok(ports) => ports,
+                    Err(e) => return bad_request(e, entry),
+                };
+                let entry = entry.ports(&ports);
+                let permit = match port_forward_streams.try_acquire() {
+                    Some(permit) => permit,
+                    None => return too_many_streams(entry),
+                };
+                debug!(
+                    "Got port forward request for ports {:?} of pod {} in namespace {}.",
+                    ports, pod, namespace
+                );
+                let span = info_span!(
+                    parent: &crate::telemetry::pod_span(&namespace, &pod),
+                    "port_forward",
+                    pod = %pod,
+                    namespace = %namespace,
+                    ports = ?ports
+                );
+                let reply = ws.on_upgrade(move |socket| {
+                    permit.hold(async move {
+                        let outcome = port_forward::serve(provider, namespace, pod, ports, socket)
+                            .instrument(span)
+                            .await;
+                        entry.finish(outcome);
+                    })
+                });
+                Box::new(warp::reply::with_header(
+                    reply,
+                    "Sec-WebSocket-Protocol",
+                    port_forward::PORT_FORWARD_PROTOCOL,
+                )) as Box<dyn warp::Reply>
+            },
+        );
+
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and_then(get_metrics);
+
+    let stats_provider = provider.clone();
+    let node_name = config.node_name.clone();
+    let stats_summary = warp::get()
+        .and(warp::path!("stats" / "summary"))
+        .and_then(move || {
+            let provider = stats_provider.clone();
+            get_stats_summary(provider, node_name.clone(), start_time)
+        });
+
+    let running_pods_provider = provider.clone();
+    let running_pods = warp::get()
+        .and(warp::path("runningpods"))
+        .and(warp::path::end())
+        .and_then(move || get_running_pods(running_pods_provider.clone()));
+
+    let exec_provider = provider.clone();
+    let post_exec = warp::post()
         .and(warp::path!("exec" / String / String / String))
         .and_then(move |namespace, pod, container| {
             let provider = exec_provider.clone();
             post_exec(provider, namespace, pod, container)
         });
 
-    let routes = ping.or(health).or(logs).or(exec);
+    let checkpoint_provider = provider.clone();
+    let checkpoint = warp::post()
+        .and(warp::path!("checkpoint" / String / String / String))
+        .and_then(move |namespace, pod, container| {
+            let provider = checkpoint_provider.clone();
+            post_checkpoint(provider, namespace, pod, container)
+        });
+
+    let auth = Arc::new(auth::Auth::new(
+        client.clone(),
+        config.node_name.clone(),
+        &config.server_config,
+        audit,
+    ));
+    let routes = auth::filter(auth)
+        .and(
+            ping.or(sync_loop_health)
+                .or(health)
+                .or(configz)
+                .or(logs)
+                .or(exec)
+                .or(post_exec)
+                .or(attach)
+                .or(port_forward)
+                .or(checkpoint)
+                .or(stats_summary)
+                .or(running_pods)
+                .or(pprof_profile)
+                .or(pprof_heap)
+                .or(metrics),
+        )
+        .recover(auth::handle_rejection);
+    let service = limit::Timeout::new(
+        warp::service(routes),
+        limit::RouteTimeouts::new(&config.server_config),
+    );
 
-    warp::serve(routes)
-        .tls()
-        .cert_path(&config.cert_file)
-        .key_path(&config.private_key_file)
-        .run((config.addr, config.port))
-        .await;
-    Ok(())
+    let addr = std::net::SocketAddr::new(config.server_config.addr, config.server_config.port);
+    if config.server_config.insecure_plaintext {
+        warn!(
+            "Serving plain HTTP on {}. This is insecure and only meant for development",
+            addr
+        );
+        hyper::Server::bind(&addr)
+            .serve(hyper::service::make_service_fn(move |_| {
+                let service = service.clone();
+                async move {
+                    Ok::<_, Infallible>(hyper::service::service_fn(move |mut req| {
+                        auth::identify(&mut req, None);
+                        service.clone().call(req)
+                    }))
+                }
+            }))
+            .await?;
+        return Ok(());
+    }
+    let reloads = if config.server_config.rotate_certificates {
+        let (reload_sender, reloads) = tokio::sync::watch::channel(());
+        tokio::spawn(crate::bootstrapping::rotate_tls_cert(
+            config.clone(),
+            client,
+            reload_sender,
+        ));
+        Some(reloads)
+    } else {
+        None
+    };
+    tls::serve(service, addr, config.server_config.clone(), reloads).await
 }
 
 /// Get the logs from the running container.
@@ -61,35 +372,199 @@ async fn get_container_logs<T: Provider>(
     pod: String,
     container: String,
     opts: Options,
+    permit: Option<limit::StreamPermit>,
+    entry: audit::Entry,
 ) -> Result<Response<Body>, Infallible> {
+    let permit = match permit {
+        Some(permit) => permit,
+        None => {
+            entry.finish(Outcome::Failed {
+                message: TOO_MANY_STREAMS.to_owned(),
+            });
+            return Ok(limit::too_many_streams());
+        }
+    };
     debug!(
         "Got container log request for container {} in pod {} in namespace {}. Options: {:?}.",
         container, pod, namespace, opts
     );
+    crate::metrics::SERVER_REQUESTS
+        .with_label_values(&["containerLogs"])
+        .inc();
     let (sender, log_body) = Body::channel();
     let log_sender = Sender::new(sender, opts);
 
-    match provider.logs(namespace, pod, container, log_sender).await {
-        Ok(()) => Ok(Response::new(log_body)),
+    let span = info_span!(
+        parent: &crate::telemetry::pod_span(&namespace, &pod),
+        "logs",
+        pod = %pod,
+        namespace = %namespace,
+        container = %container
+    );
+    match provider
+        .logs(namespace, pod, container, log_sender)
+        .instrument(span)
+        .await
+    {
+        // The stream stays reserved until the response body is done with.
+        Ok(()) => {
+            entry.finish(Outcome::Succeeded);
+            Ok(Response::new(Body::wrap_stream(log_body.map(
+                move |chunk| {
+                    let _permit = &permit;
+                    chunk
+                },
+            ))))
+        }
         Err(e) => {
-            error!("Error fetching logs: {}", e);
-            if e.is::<NotImplementedError>() {
-                return_with_code(
-                    StatusCode::NOT_IMPLEMENTED,
-                    "Logs not implemented in provider.".to_owned(),
-                )
-            } else {
-                return_with_code(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Server error: {}", e),
-                )
+            entry.finish(Outcome::Failed {
+                message: e.to_string(),
+            });
+            provider_error_response("Logs", e)
+        }
+    }
+}
+
+/// Get the kubelet's metrics in the Prometheus text format.
+///
+/// Implements the kubelet path /metrics
+async fn get_metrics() -> Result<Response<Body>, Infallible> {
+    match crate::metrics::gather() {
+        Ok((content_type, body)) => {
+            let mut response = Response::new(body.into());
+            if let Ok(value) = http::HeaderValue::from_str(&content_type) {
+                response
+                    .headers_mut()
+                    .insert(http::header::CONTENT_TYPE, value);
             }
+            Ok(response)
+        }
+        Err(e) => {
+            error!("Error gathering metrics: {}", e);
+            return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            )
+        }
+    }
+}
+
+/// Get a summary of resource usage on the node and of the pods running on it.
+///
+/// Implements the kubelet path /stats/summary
+async fn get_stats_summary<T: Provider>(
+    provider: Arc<T>,
+    node_name: String,
+    start_time: chrono::DateTime<chrono::Utc>,
+) -> Result<Response<Body>, Infallible> {
+    let pods = match provider.stats().await {
+        Ok(pods) => pods,
+        // Node stats are still useful when the provider can't report on its pods
+        Err(ProviderError::Unsupported) => Vec::new(),
+        Err(e) => {
+            error!("Error fetching pod stats: {}", e);
+            return return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            );
         }
+    };
+    let summary = Summary {
+        node: stats::node_stats(&node_name, start_time).await,
+        pods,
+    };
+    json_response(&summary)
+}
+
+/// Report whether the pod sync loop is making progress. It is unhealthy until it has handled the
+/// initial list of pods, or if watching for pods has been failing for a while. A loop that is
+/// simply idle because no pods have changed is healthy.
+///
+/// Implements the kubelet path /healthz/syncloop
+async fn get_sync_loop_health(status: SyncLoopStatus) -> Result<Response<Body>, Infallible> {
+    if status.last_event().is_none() {
+        return return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "sync loop has not handled any pod events yet".to_owned(),
+        );
+    }
+    match status.failing_since() {
+        Some(since) if since.elapsed() > SYNC_LOOP_FAILURE_THRESHOLD => return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!(
+                "sync loop has been failing to watch pods for {} seconds",
+                since.elapsed().as_secs()
+            ),
+        ),
+        _ => Ok(Response::new("ok".into())),
+    }
+}
+
+/// Get the configuration the kubelet is running with.
+///
+/// Implements the kubelet path /configz
+async fn get_configz(config: Arc<Config>) -> Result<Response<Body>, Infallible> {
+    #[derive(serde::Serialize)]
+    struct Configz<'a> {
+        kubeletconfig: &'a Config,
+    }
+    json_response(&Configz {
+        kubeletconfig: &config,
+    })
+}
+
+/// Get the pods the provider is running, which may differ from those the API server has
+/// scheduled on the node.
+///
+/// Implements the kubelet path /runningpods/
+async fn get_running_pods<T: Provider>(provider: Arc<T>) -> Result<Response<Body>, Infallible> {
+    crate::metrics::SERVER_REQUESTS
+        .with_label_values(&["runningpods"])
+        .inc();
+    let pods = match provider.running_pods().await {
+        Ok(pods) => pods,
+        Err(e) => return provider_error_response("Running pods", e),
+    };
+    json_response(&pods)
+}
+
+/// Parse the query string of an exec or attach request into [`CommandOptions`]. The `command`
+/// parameter may be repeated, once for each argument. At least one of the stdin, stdout and stderr
+/// streams must be requested.
+fn parse_exec_query(query: &str) -> anyhow::Result<CommandOptions> {
+    let mut options = CommandOptions::default();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "command" => options.command.push(value.into_owned()),
+            "stdin" => options.stdin = parse_bool(&key, &value)?,
+            "stdout" => options.stdout = parse_bool(&key, &value)?,
+            "stderr" => options.stderr = parse_bool(&key, &value)?,
+            "tty" => options.tty = parse_bool(&key, &value)?,
+            _ => (),
+        }
+    }
+    if !(options.stdin || options.stdout || options.stderr) {
+        anyhow::bail!("you must specify at least one of stdin, stdout, stderr");
+    }
+    // A TTY has a single output stream
+    if options.tty {
+        options.stderr = false;
+    }
+    Ok(options)
+}
+
+fn parse_bool(key: &str, value: &str) -> anyhow::Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "1" => Ok(true),
+        "false" | "0" | "" => Ok(false),
+        _ => anyhow::bail!("invalid value {:?} for {}", value, key),
     }
 }
 
 /// Run a pod exec command and get the output
 ///
+/// Exec over SPDY is not supported, clients must use websockets.
+///
 /// Implements the kubelet path /exec/{namespace}/{pod}/{container}
 async fn post_exec<T: Provider>(
     _provider: Arc<T>,
@@ -103,8 +578,135 @@ async fn post_exec<T: Provider>(
     )
 }
 
+/// The snapshots a checkpoint request wrote.
+#[derive(serde::Serialize)]
+struct CheckpointResult {
+    items: Vec<String>,
+}
+
+/// Checkpoint a running container, writing a snapshot of it on the node.
+///
+/// This is experimental, like the kubelet API it mirrors, and is only implemented by providers
+/// that can snapshot the containers they run.
+///
+/// Implements the kubelet path /checkpoint/{namespace}/{pod}/{container}
+async fn post_checkpoint<T: Provider>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+) -> Result<Response<Body>, Infallible> {
+    crate::metrics::SERVER_REQUESTS
+        .with_label_values(&["checkpoint"])
+        .inc();
+    debug!(
+        "Got checkpoint request for container {} in pod {} in namespace {}.",
+        container, pod, namespace
+    );
+    match provider.checkpoint(namespace, pod, container).await {
+        Ok(path) => json_response(&CheckpointResult {
+            items: vec![path.display().to_string()],
+        }),
+        Err(e) => provider_error_response("Checkpoint", e),
+    }
+}
+
+/// The audit message of streaming requests refused because too many streams are being served.
+const TOO_MANY_STREAMS: &str = "too many streams";
+
+fn bad_request(e: anyhow::Error, entry: audit::Entry) -> Box<dyn warp::Reply> {
+    entry.finish(Outcome::Failed {
+        message: format!("bad request: {}", e),
+    });
+    Box::new(warp::reply::with_status(
+        format!("Bad request: {}", e),
+        StatusCode::BAD_REQUEST,
+    ))
+}
+
+fn too_many_streams(entry: audit::Entry) -> Box<dyn warp::Reply> {
+    entry.finish(Outcome::Failed {
+        message: TOO_MANY_STREAMS.to_owned(),
+    });
+    Box::new(limit::too_many_streams())
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> Result<Response<Body>, Infallible> {
+    match serde_json::to_string(value) {
+        Ok(body) => {
+            let mut response = Response::new(body.into());
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/json"),
+            );
+            Ok(response)
+        }
+        Err(e) => return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Server error: {}", e),
+        ),
+    }
+}
+
+/// Answer a request the provider failed to carry out with the status its error calls for: a 404
+/// for pods and containers that don't exist, a 501 for operations the provider doesn't support,
+/// and a 500 for anything that went wrong on the way.
+fn provider_error_response(what: &str, e: ProviderError) -> Result<Response<Body>, Infallible> {
+    match e {
+        ProviderError::Unsupported => return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            format!("{} not implemented in provider.", what),
+        ),
+        e if e.is_not_found() => {
+            return_with_code(StatusCode::NOT_FOUND, format!("Not found: {}", e))
+        }
+        ProviderError::InvalidRequest(_) => {
+            return_with_code(StatusCode::BAD_REQUEST, format!("Bad request: {}", e))
+        }
+        e => {
+            error!("{} failed: {}", what, e);
+            return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            )
+        }
+    }
+}
+
 fn return_with_code(code: StatusCode, body: String) -> Result<Response<Body>, Infallible> {
     let mut response = Response::new(body.into());
     *response.status_mut() = code;
     Ok(response)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn can_parse_exec_query() {
+        let options = parse_exec_query(
+            "command=echo&command=hello%20world&stdin=true&stdout=1&stderr=false&tty=0",
+        )
+        .unwrap();
+        assert_eq!(options.command, vec!["echo", "hello world"]);
+        assert!(options.stdin);
+        assert!(options.stdout);
+        assert!(!options.stderr);
+        assert!(!options.tty);
+    }
+
+    #[test]
+    fn tty_disables_stderr() {
+        let options = parse_exec_query("command=sh&stdout=true&stderr=true&tty=true").unwrap();
+        assert!(options.tty);
+        assert!(options.stdout);
+        assert!(!options.stderr);
+    }
+
+    #[test]
+    fn exec_query_requires_a_stream() {
+        assert!(parse_exec_query("command=ls").is_err());
+        assert!(parse_exec_query("command=ls&stdout=maybe").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Websocket sessions for `kubectl port-forward`.
+//!
+//! Only the websocket transport is served. Clients that forward ports over SPDY, which is what
+//! `kubectl port-forward` uses by default, get an error back when the upgrade is refused.
+//!
+//! Each requested port is assigned a pair of channels: data for the port is sent on channel
+//! `2 * n` and errors on channel `2 * n + 1`, where `n` is the index of the port in the request.
+//! The first frame sent on each channel holds the port number as a little endian `u16`.
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+use warp::ws::{Message, WebSocket};
+
+use super::audit::Outcome;
+use crate::port_forward::PortStream;
+use crate::provider::{Provider, ProviderError};
+
+/// The websocket subprotocol spoken on port forwarding connections.
+pub(crate) const PORT_FORWARD_PROTOCOL: &str = "portforward.k8s.io";
+
+/// The number of frames buffered before the session applies backpressure.
+const BUFFER_SIZE: usize = 16;
+
+/// The most ports a single request can forward, as each port's pair of channels must be numbered
+/// within a byte.
+const MAX_PORTS: usize = 128;
+
+/// Parse the ports to forward from the query string of a port forwarding request. The `ports`
+/// parameter may be repeated or hold a comma separated list.
+pub(crate) fn parse_ports(query: &str) -> anyhow::Result<Vec<u16>> {
+    let mut ports = Vec::new();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        if key == "ports" {
+            for port in value.split(',') {
+                let port = port.trim();
+                ports.push(
+                    port.parse()
+                        .map_err(|e| anyhow::anyhow!("invalid port {}: {}", port, e))?,
+                );
+            }
+        }
+    }
+    if ports.is_empty() {
+        anyhow::bail!("at least one port must be given");
+    }
+    if ports.len() > MAX_PORTS {
+        anyhow::bail!("at most {} ports can be forwarded at once", MAX_PORTS);
+    }
+    Ok(ports)
+}
+
+/// Forward the given ports of a pod over the websocket until the client goes away or all of the
+/// connections to the pod are closed. How the session turned out is returned, for it to be
+/// audited: it failed if any of the ports couldn't be forwarded.
+///
+/// Implements the kubelet path /portForward/{namespace}/{pod} over websockets with the
+/// `portforward.k8s.io` subprotocol. SPDY is not served.
+pub(crate) async fn serve<T: Provider>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    ports: Vec<u16>,
+    socket: WebSocket,
+) -> Outcome {
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let (frame_tx, mut frame_rx) = mpsc::channel::<Vec<u8>>(BUFFER_SIZE);
+
+    let mut writers: Vec<Option<WriteHalf<Box<dyn PortStream>>>> = Vec::new();
+    let mut readers = Vec::new();
+    let mut failures = Vec::new();
+    for (index, port) in ports.into_iter().enumerate() {
+        let data_channel = (index * 2) as u8;
+        let error_channel = data_channel + 1;
+        for channel in &[data_channel, error_channel] {
+            let [low, high] = port.to_le_bytes();
+            if ws_tx
+                .send(Message::binary(vec![*channel, low, high]))
+                .await
+                .is_err()
+            {
+                debug!("Client went away before port forwarding started");
+                return Outcome::Disconnected;
+            }
+        }
+
+        match provider
+            .port_forward(namespace.clone(), pod.clone(), port)
+            .await
+        {
+            Ok(stream) => {
+                let (reader, writer) = tokio::io::split(stream);
+                writers.push(Some(writer));
+                readers.push(forward_from_port(reader, data_channel, frame_tx.clone()));
+            }
+            Err(e) => {
+                error!("Error forwarding port {} of pod {}: {}", port, pod, e);
+                let message = if let ProviderError::Unsupported = e {
+                    "Port forwarding not implemented in provider.".to_owned()
+                } else {
+                    format!("Unable to forward port {}: {}", port, e)
+                };
+                let mut frame = vec![error_channel];
+                frame.extend(message.clone().into_bytes());
+                let _ = ws_tx.send(Message::binary(frame)).await;
+                writers.push(None);
+                failures.push(message);
+            }
+        }
+    }
+    // Only the readers should keep the client side open
+    drop(frame_tx);
+
+    let forward_to_pod = async move {
+        while let Some(Ok(message)) = ws_rx.next().await {
+            if message.is_close() {
+                break;
+            }
+            let (channel, data) = match message.as_bytes().split_first() {
+                Some((channel, data)) if channel % 2 == 0 => (*channel as usize / 2, data),
+                _ => continue,
+            };
+            if let Some(writer) = writers.get_mut(channel) {
+                if let Some(w) = writer {
+                    if let Err(e) = w.write_all(data).await {
+                        debug!("Connection to forwarded port closed: {}", e);
+                        *writer = None;
+                    }
+                }
+            }
+        }
+    };
+
+    let forward_to_client = async move {
+        while let Some(frame) = frame_rx.recv().await {
+            if let Err(e) = ws_tx.send(Message::binary(frame)).await {
+                debug!("Client went away: {}", e);
+                return false;
+            }
+        }
+        if let Err(e) = ws_tx.close().await {
+            debug!("Unable to close websocket: {}", e);
+        }
+        true
+    };
+
+    let completed = tokio::select! {
+        _ = forward_to_pod => {
+            debug!("Client closed port forwarding session");
+            true
+        }
+        (_, completed) = async {
+            tokio::join!(futures::future::join_all(readers), forward_to_client)
+        } => completed,
+    };
+    match (completed, failures.is_empty()) {
+        (_, false) => Outcome::Failed {
+            message: failures.join("; "),
+        },
+        (true, true) => Outcome::Succeeded,
+        (false, true) => Outcome::Disconnected,
+    }
+}
+
+/// Sends everything read from the port to the client on the given channel.
+async fn forward_from_port(
+    mut reader: ReadHalf<Box<dyn PortStream>>,
+    channel: u8,
+    mut frames: mpsc::Sender<Vec<u8>>,
+) {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) => {
+                debug!("Error reading from forwarded port: {}", e);
+                return;
+            }
+        };
+        let mut frame = Vec::with_capacity(n + 1);
+        frame.push(channel);
+        frame.extend_from_slice(&buf[..n]);
+        if frames.send(frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn can_parse_ports() {
+        assert_eq!(parse_ports("ports=8080").unwrap(), vec![8080]);
+        assert_eq!(
+            parse_ports("ports=8080&ports=9090").unwrap(),
+            vec![8080, 9090]
+        );
+        assert_eq!(parse_ports("ports=80%2C443").unwrap(), vec![80, 443]);
+        assert!(parse_ports("ports=http").is_err());
+        assert!(parse_ports("").is_err());
+        let too_many: Vec<_> = (1..=129).map(|p| format!("ports={}", p)).collect();
+        assert!(parse_ports(&too_many.join("&")).is_err());
+        assert_eq!(parse_ports(&too_many[..128].join("&")).unwrap().len(), 128);
+    }
+}
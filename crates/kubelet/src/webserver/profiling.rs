@@ -0,0 +1,140 @@
+//! Profiling endpoints for diagnosing the kubelet's own CPU and memory use. They are only
+//! available when the kubelet is built with the `profiling` feature and enabled in the server
+//! configuration.
+use std::convert::Infallible;
+use std::time::Duration;
+
+use http::status::StatusCode;
+use http::Response;
+use hyper::Body;
+use serde::Deserialize;
+
+use super::return_with_code;
+
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+#[cfg(feature = "profiling")]
+const PROFILE_FREQUENCY: i32 = 100;
+
+/// The query parameters of a CPU profile request.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ProfileOptions {
+    /// How long to profile for. Defaults to 30 seconds.
+    seconds: Option<u64>,
+}
+
+/// Get a CPU profile of the kubelet, sampled over the requested number of seconds, in the pprof
+/// protobuf format.
+///
+/// Implements the kubelet path /debug/pprof/profile
+pub(crate) async fn profile(
+    enabled: bool,
+    opts: ProfileOptions,
+) -> Result<Response<Body>, Infallible> {
+    if !enabled {
+        return not_enabled();
+    }
+    profile_impl(Duration::from_secs(
+        opts.seconds.unwrap_or(DEFAULT_PROFILE_SECONDS),
+    ))
+    .await
+}
+
+/// Get the kubelet's heap statistics as reported by jemalloc.
+///
+/// Implements the kubelet path /debug/pprof/heap
+pub(crate) async fn heap(enabled: bool) -> Result<Response<Body>, Infallible> {
+    if !enabled {
+        return not_enabled();
+    }
+    heap_impl()
+}
+
+fn not_enabled() -> Result<Response<Body>, Infallible> {
+    return_with_code(
+        StatusCode::NOT_FOUND,
+        "Profiling is not enabled.".to_owned(),
+    )
+}
+
+#[cfg(feature = "profiling")]
+async fn profile_impl(duration: Duration) -> Result<Response<Body>, Infallible> {
+    // The profiler isn't Send, so it runs on its own thread while this task waits for the result
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(sample_cpu(duration));
+    });
+    match rx.await {
+        Ok(Ok(body)) => {
+            let mut response = Response::new(body.into());
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/octet-stream"),
+            );
+            Ok(response)
+        }
+        Ok(Err(e)) => return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unable to profile: {}", e),
+        ),
+        Err(_) => return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Profiler stopped unexpectedly".to_owned(),
+        ),
+    }
+}
+
+#[cfg(feature = "profiling")]
+fn sample_cpu(duration: Duration) -> anyhow::Result<Vec<u8>> {
+    use prost::Message;
+
+    let guard = pprof::ProfilerGuard::new(PROFILE_FREQUENCY)?;
+    std::thread::sleep(duration);
+    let profile = guard.report().build()?.pprof()?;
+    let mut body = Vec::new();
+    profile.encode(&mut body)?;
+    Ok(body)
+}
+
+#[cfg(feature = "profiling")]
+fn heap_impl() -> Result<Response<Body>, Infallible> {
+    use jemalloc_ctl::{epoch, stats};
+
+    let read = || -> Result<String, jemalloc_ctl::Error> {
+        // Statistics are cached by jemalloc until the epoch is advanced
+        epoch::advance()?;
+        Ok(format!(
+            "allocated: {}\nactive: {}\nmetadata: {}\nresident: {}\nmapped: {}\nretained: {}\n",
+            stats::allocated::read()?,
+            stats::active::read()?,
+            stats::metadata::read()?,
+            stats::resident::read()?,
+            stats::mapped::read()?,
+            stats::retained::read()?,
+        ))
+    };
+    match read() {
+        Ok(body) => Ok(Response::new(body.into())),
+        Err(e) => return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unable to read heap statistics: {}", e),
+        ),
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+async fn profile_impl(_duration: Duration) -> Result<Response<Body>, Infallible> {
+    not_built()
+}
+
+#[cfg(not(feature = "profiling"))]
+fn heap_impl() -> Result<Response<Body>, Infallible> {
+    not_built()
+}
+
+#[cfg(not(feature = "profiling"))]
+fn not_built() -> Result<Response<Body>, Infallible> {
+    return_with_code(
+        StatusCode::NOT_IMPLEMENTED,
+        "The kubelet was built without the profiling feature.".to_owned(),
+    )
+}
@@ -0,0 +1,396 @@
+//! Websocket sessions that multiplex a process's stdin, stdout and stderr over a single
+//! connection, as used by exec and attach.
+use std::future::Future;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, error, warn};
+use warp::ws::{Message, WebSocket};
+
+use super::audit::Outcome;
+use crate::exec::{CommandOptions, ExecOutput, Sender, Stdin};
+use crate::provider::ProviderError;
+
+/// The websocket subprotocol spoken on streaming connections. Every frame is prefixed with a
+/// byte identifying the stream it belongs to.
+pub(crate) const STREAM_PROTOCOL: &str = "v4.channel.k8s.io";
+const STDIN_CHANNEL: u8 = 0;
+const STDOUT_CHANNEL: u8 = 1;
+const STDERR_CHANNEL: u8 = 2;
+/// The channel on which a `v1.Status` describing how the session ended is sent.
+const STATUS_CHANNEL: u8 = 3;
+
+/// The number of stdin or output frames buffered before a session applies backpressure.
+const BUFFER_SIZE: usize = 16;
+/// How often the client is pinged, so that it keeps sending something even while idle.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long the client can go without sending anything, not even a reply to a ping, before the
+/// connection is treated as dead.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Runs `session` against the given websocket, feeding it stdin frames from the client and
+/// sending what it outputs back as stdout and stderr frames. Only the streams requested in
+/// `options` are forwarded.
+///
+/// Once the session completes, a `v1.Status` is sent on the status channel describing whether it
+/// succeeded and, if the process exited, with what exit code. The session is dropped if the
+/// client goes away before it completes, which includes the client not answering pings for
+/// [`IDLE_TIMEOUT`], so that a connection that died without being closed doesn't keep the
+/// session's resources in the provider forever. `action` is used to describe the session in
+/// error messages. How the session turned out is returned, for it to be audited.
+pub(crate) async fn serve<F, Fut>(
+    socket: WebSocket,
+    options: CommandOptions,
+    action: &str,
+    session: F,
+) -> Outcome
+where
+    F: FnOnce(Stdin, Sender) -> Fut,
+    Fut: Future<Output = Result<(), ProviderError>>,
+{
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let (stdin_tx, stdin_rx) = mpsc::channel(BUFFER_SIZE);
+    let (output_tx, mut output_rx) = mpsc::channel(BUFFER_SIZE);
+
+    // Forward stdin frames until the client closes the connection. Dropping `stdin_tx` signals
+    // EOF to the process, so it is dropped straight away if the client did not ask for stdin.
+    let mut stdin_tx = if options.stdin { Some(stdin_tx) } else { None };
+    let forward_stdin = async move {
+        loop {
+            // Replies to pings count as hearing from the client, as do any other frames
+            let message = match tokio::time::timeout(IDLE_TIMEOUT, ws_rx.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(_) => break,
+                Err(_) => {
+                    warn!(
+                        "Nothing heard from {} client in {:?}, dropping its session",
+                        action, IDLE_TIMEOUT
+                    );
+                    break;
+                }
+            };
+            if message.is_close() {
+                break;
+            }
+            if let (Some(tx), Some((&STDIN_CHANNEL, data))) =
+                (stdin_tx.as_mut(), message.as_bytes().split_first())
+            {
+                if tx.send(data.to_vec()).await.is_err() {
+                    stdin_tx = None;
+                }
+            }
+        }
+    };
+
+    let (result_tx, result_rx) = oneshot::channel();
+    let forward_output = async move {
+        let mut exit_code = None;
+        let mut pings = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        loop {
+            let output = tokio::select! {
+                output = output_rx.recv() => match output {
+                    Some(output) => output,
+                    None => break,
+                },
+                _ = pings.tick() => {
+                    if let Err(e) = ws_tx.send(Message::ping(Vec::new())).await {
+                        debug!("Client went away: {}", e);
+                        return Outcome::Disconnected;
+                    }
+                    continue;
+                }
+            };
+            let (channel, data) = match output {
+                ExecOutput::Stdout(data) if options.stdout => (STDOUT_CHANNEL, data),
+                ExecOutput::Stderr(data) if options.stderr => (STDERR_CHANNEL, data),
+                // A TTY merges stderr into stdout
+                ExecOutput::Stderr(data) if options.tty && options.stdout => (STDOUT_CHANNEL, data),
+                ExecOutput::Exit(code) => {
+                    debug!("Process exited with code {}", code);
+                    exit_code = Some(code);
+                    continue;
+                }
+                _ => continue,
+            };
+            let mut frame = Vec::with_capacity(data.len() + 1);
+            frame.push(channel);
+            frame.extend(data);
+            if let Err(e) = ws_tx.send(Message::binary(frame)).await {
+                debug!("Client went away: {}", e);
+                return Outcome::Disconnected;
+            }
+        }
+        // The session has finished once all of its senders are gone
+        let (status, outcome) = match (exit_code, result_rx.await) {
+            (_, Ok(Err(message))) => (failure_status(message.clone()), Outcome::Failed { message }),
+            (Some(code), _) => (exit_status(code), Outcome::Exited { exit_code: code }),
+            _ => (success_status(), Outcome::Succeeded),
+        };
+        let mut frame = vec![STATUS_CHANNEL];
+        frame.extend(status.to_string().into_bytes());
+        if let Err(e) = ws_tx.send(Message::binary(frame)).await {
+            debug!("Unable to send status to client: {}", e);
+        }
+        if let Err(e) = ws_tx.close().await {
+            debug!("Unable to close websocket: {}", e);
+        }
+        outcome
+    };
+
+    let session = session(Stdin::new(stdin_rx), Sender::new(output_tx));
+    let run = async move {
+        let result = session.await.map_err(|e| {
+            error!("Error during {}: {}", action, e);
+            match e {
+                ProviderError::Unsupported => format!("{} not implemented in provider.", action),
+                e if e.is_not_found() => format!("Not found: {}", e),
+                e => format!("Server error: {}", e),
+            }
+        });
+        // The client may already be gone, in which case there is nobody left to tell
+        let _ = result_tx.send(result);
+    };
+
+    tokio::select! {
+        _ = forward_stdin => {
+            debug!("Client closed {} session", action);
+            Outcome::Disconnected
+        }
+        (_, outcome) = async { tokio::join!(run, forward_output) } => outcome,
+    }
+}
+
+fn success_status() -> serde_json::Value {
+    json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "metadata": {},
+        "status": "Success",
+    })
+}
+
+fn failure_status(message: String) -> serde_json::Value {
+    json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "metadata": {},
+        "status": "Failure",
+        "message": message,
+        "reason": "InternalError",
+        "code": 500,
+    })
+}
+
+/// Builds the status for a process that exited with the given code, in the form clients use to
+/// recover the exit code.
+fn exit_status(code: i32) -> serde_json::Value {
+    if code == 0 {
+        return success_status();
+    }
+    json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "metadata": {},
+        "status": "Failure",
+        "message": format!("command terminated with non-zero exit code: exit code {}", code),
+        "reason": "NonZeroExitCode",
+        "details": {
+            "causes": [{
+                "reason": "ExitCode",
+                "message": code.to_string(),
+            }],
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use warp::test::WsClient;
+    use warp::Filter;
+
+    /// Serves `session` to a test client, returning the client and a channel that receives how
+    /// the session turned out.
+    async fn connect<F, Fut>(
+        options: CommandOptions,
+        session: F,
+    ) -> (WsClient, mpsc::UnboundedReceiver<Outcome>)
+    where
+        F: FnOnce(Stdin, Sender) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ProviderError>> + Send + 'static,
+    {
+        let (outcome_tx, outcome_rx) = mpsc::unbounded_channel();
+        let route = warp::ws().map(move |ws: warp::ws::Ws| {
+            let (options, session, outcome_tx) =
+                (options.clone(), session.clone(), outcome_tx.clone());
+            ws.on_upgrade(move |socket| async move {
+                let _ = outcome_tx.send(serve(socket, options, "Exec", session).await);
+            })
+        });
+        let client = warp::test::ws()
+            .handshake(route)
+            .await
+            .expect("handshake should succeed");
+        (client, outcome_rx)
+    }
+
+    fn options(stdin: bool, stderr: bool, tty: bool) -> CommandOptions {
+        CommandOptions {
+            command: vec!["sh".to_owned()],
+            stdin,
+            stdout: true,
+            stderr,
+            tty,
+        }
+    }
+
+    async fn send_stdin(client: &mut WsClient, data: &[u8]) {
+        let mut frame = vec![STDIN_CHANNEL];
+        frame.extend_from_slice(data);
+        client.send(Message::binary(frame)).await;
+    }
+
+    /// Receives the next frame, split into its channel and data.
+    async fn recv_frame(client: &mut WsClient) -> (u8, Vec<u8>) {
+        let message = client.recv().await.expect("a frame should be sent");
+        let (channel, data) = message
+            .as_bytes()
+            .split_first()
+            .expect("frames should start with their channel");
+        (*channel, data.to_vec())
+    }
+
+    async fn recv_status(client: &mut WsClient) -> serde_json::Value {
+        let (channel, data) = recv_frame(client).await;
+        assert_eq!(channel, STATUS_CHANNEL);
+        serde_json::from_slice(&data).expect("status should be JSON")
+    }
+
+    #[tokio::test]
+    async fn exec_forwards_stdin_and_output_as_it_comes() {
+        let (mut client, mut outcome) = connect(
+            options(true, true, false),
+            |mut stdin: Stdin, mut sender: Sender| async move {
+                while let Some(data) = stdin.recv().await {
+                    if data == b"exit" {
+                        sender.stderr("bye\n").await?;
+                        sender.exit(3).await?;
+                        break;
+                    }
+                    sender.stdout(data).await?;
+                }
+                Ok::<_, ProviderError>(())
+            },
+        )
+        .await;
+
+        // Each chunk is answered before the next one is sent, so output isn't held back until
+        // the process exits
+        send_stdin(&mut client, b"hello").await;
+        assert_eq!(
+            recv_frame(&mut client).await,
+            (STDOUT_CHANNEL, b"hello".to_vec())
+        );
+        send_stdin(&mut client, b"world").await;
+        assert_eq!(
+            recv_frame(&mut client).await,
+            (STDOUT_CHANNEL, b"world".to_vec())
+        );
+        send_stdin(&mut client, b"exit").await;
+        assert_eq!(
+            recv_frame(&mut client).await,
+            (STDERR_CHANNEL, b"bye\n".to_vec())
+        );
+
+        let status = recv_status(&mut client).await;
+        assert_eq!(status["status"], "Failure");
+        assert_eq!(status["reason"], "NonZeroExitCode");
+        assert_eq!(status["details"]["causes"][0]["message"], "3");
+        assert_eq!(outcome.recv().await, Some(Outcome::Exited { exit_code: 3 }));
+    }
+
+    #[tokio::test]
+    async fn only_requested_streams_are_sent() {
+        let session = |_: Stdin, mut sender: Sender| async move {
+            sender.stderr("err").await?;
+            sender.stdout("out").await?;
+            sender.exit(0).await?;
+            Ok::<_, ProviderError>(())
+        };
+
+        let (mut client, _) = connect(options(false, false, false), session.clone()).await;
+        assert_eq!(
+            recv_frame(&mut client).await,
+            (STDOUT_CHANNEL, b"out".to_vec())
+        );
+        assert_eq!(recv_status(&mut client).await["status"], "Success");
+
+        // A TTY merges stderr into stdout
+        let (mut client, _) = connect(options(false, false, true), session).await;
+        assert_eq!(
+            recv_frame(&mut client).await,
+            (STDOUT_CHANNEL, b"err".to_vec())
+        );
+        assert_eq!(
+            recv_frame(&mut client).await,
+            (STDOUT_CHANNEL, b"out".to_vec())
+        );
+        assert_eq!(recv_status(&mut client).await["status"], "Success");
+    }
+
+    #[tokio::test]
+    async fn failed_sessions_are_reported_in_the_status() {
+        let (mut client, mut outcome) = connect(options(false, true, false), |_, _| async {
+            Err::<(), _>(ProviderError::Unsupported)
+        })
+        .await;
+
+        let status = recv_status(&mut client).await;
+        assert_eq!(status["status"], "Failure");
+        assert_eq!(status["message"], "Exec not implemented in provider.");
+        assert_eq!(
+            outcome.recv().await,
+            Some(Outcome::Failed {
+                message: "Exec not implemented in provider.".to_owned()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn attach_runs_until_the_client_goes_away() {
+        // Like an attached process, the session keeps going until the client leaves, echoing
+        // what it is sent
+        let (mut client, mut outcome) = connect(
+            options(true, true, false),
+            |mut stdin: Stdin, mut sender: Sender| async move {
+                while let Some(data) = stdin.recv().await {
+                    sender.stdout(data).await?;
+                }
+                futures::future::pending::<()>().await;
+                Ok::<_, ProviderError>(())
+            },
+        )
+        .await;
+
+        send_stdin(&mut client, b"ping").await;
+        assert_eq!(
+            recv_frame(&mut client).await,
+            (STDOUT_CHANNEL, b"ping".to_vec())
+        );
+        client.send(Message::close()).await;
+        assert_eq!(outcome.recv().await, Some(Outcome::Disconnected));
+    }
+
+    #[test]
+    fn exit_status_carries_exit_code() {
+        assert_eq!(exit_status(0)["status"], "Success");
+        let status = exit_status(3);
+        assert_eq!(status["status"], "Failure");
+        assert_eq!(status["reason"], "NonZeroExitCode");
+        assert_eq!(status["details"]["causes"][0]["reason"], "ExitCode");
+        assert_eq!(status["details"]["causes"][0]["message"], "3");
+    }
+}
@@ -0,0 +1,152 @@
+//! Serving the Kubelet server over TLS. Warp's own TLS support can't verify client certificates,
+//! so connections are accepted here and handed to the warp service once the handshake is done.
+use std::convert::Infallible;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use http::{Request, Response};
+use hyper::server::conn::Http;
+use hyper::Body;
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, ProtocolVersion,
+    RootCertStore, Session,
+};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+use tracing::{debug, error, info};
+
+use super::auth;
+use crate::config::{ServerConfig, TlsVersion};
+
+/// Build the TLS configuration for the server from its certificate and key, allowing only the
+/// configured TLS versions. If a client CA is configured, certificates clients present must be
+/// signed by it. Clients without one are still accepted, and authenticated some other way, such
+/// as with a bearer token, or treated as anonymous.
+pub(crate) fn load_config(config: &ServerConfig) -> anyhow::Result<rustls::ServerConfig> {
+    let verifier = match &config.client_ca_file {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let mut reader = BufReader::new(std::fs::File::open(path)?);
+            let (valid, _) = roots
+                .add_pem_file(&mut reader)
+                .map_err(|_| anyhow::anyhow!("unable to read client CA file {}", path.display()))?;
+            if valid == 0 {
+                anyhow::bail!("no valid certificates in client CA file {}", path.display());
+            }
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut tls = rustls::ServerConfig::new(verifier);
+    tls.versions = match config.tls_min_version {
+        TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+    };
+    tls.set_single_cert(
+        load_certs(&config.cert_file)?,
+        load_private_key(&config.private_key_file)?,
+    )?;
+    Ok(tls)
+}
+
+fn load_certs(path: &std::path::Path) -> anyhow::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let certs = pemfile::certs(&mut reader)
+        .map_err(|_| anyhow::anyhow!("unable to read certificate file {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates in certificate file {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &std::path::Path) -> anyhow::Result<PrivateKey> {
+    let read_keys = |parse: fn(&mut dyn std::io::BufRead) -> Result<Vec<PrivateKey>, ()>| {
+        let mut reader = BufReader::new(std::fs::File::open(path)?);
+        parse(&mut reader)
+            .map_err(|_| anyhow::anyhow!("unable to read private key file {}", path.display()))
+    };
+    let mut keys = read_keys(pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_keys(pemfile::rsa_private_keys)?;
+    }
+    keys.pop()
+        .ok_or_else(|| anyhow::anyhow!("no private key in private key file {}", path.display()))
+}
+
+/// Serve requests over TLS with the given service. The user identified by a connection's client
+/// certificate, if any, is added to the extensions of each request made over it.
+///
+/// The TLS configuration is loaded again whenever `reloads` yields, so that renewed certificates
+/// are used for new connections.
+pub(crate) async fn serve<S>(
+    service: S,
+    addr: SocketAddr,
+    config: ServerConfig,
+    reloads: Option<watch::Receiver<()>>,
+) -> anyhow::Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let acceptor = Arc::new(RwLock::new(TlsAcceptor::from(Arc::new(load_config(
+        &config,
+    )?))));
+    if let Some(mut reloads) = reloads {
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            while reloads.recv().await.is_some() {
+                match load_config(&config) {
+                    Ok(tls) => {
+                        *acceptor.write().unwrap() = TlsAcceptor::from(Arc::new(tls));
+                        info!("Reloaded TLS configuration");
+                    }
+                    Err(e) => error!("Unable to reload TLS configuration: {:?}", e),
+                }
+            }
+        });
+    }
+    let mut listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Unable to accept connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.read().unwrap().clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let user = stream
+                .get_ref()
+                .1
+                .get_peer_certificates()
+                .and_then(|certs| auth::client_certificate_user(&certs));
+            let service = hyper::service::service_fn(move |mut req: Request<Body>| {
+                auth::identify(&mut req, user.clone());
+                service.clone().call(req)
+            });
+            if let Err(e) = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Error serving connection from {}: {}", peer, e);
+            }
+        });
+    }
+}
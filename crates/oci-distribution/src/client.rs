@@ -5,8 +5,9 @@
 
 use crate::errors::*;
 use crate::manifest::{
-    OciDescriptor, OciManifest, Versioned, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
-    IMAGE_MANIFEST_MEDIA_TYPE,
+    OciDescriptor, OciImageIndex, OciManifest, Versioned, IMAGE_LAYER_GZIP_MEDIA_TYPE,
+    IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
 };
 use crate::secrets::RegistryAuth;
 use crate::secrets::*;
@@ -20,9 +21,62 @@ use log::debug;
 use reqwest::header::HeaderMap;
 use sha2::Digest;
 use std::collections::HashMap;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
 use www_authenticate::{Challenge, ChallengeFields, RawChallenge, WwwAuthenticate};
 
+/// How many times to try pulling a layer before giving up.
+const LAYER_PULL_ATTEMPTS: u32 = 5;
+/// How long to wait before the first retry of a layer pull. This doubles with each retry.
+const LAYER_PULL_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// The manifest media types the client can pull.
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    IMAGE_MANIFEST_MEDIA_TYPE,
+    OCI_IMAGE_MEDIA_TYPE,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE,
+];
+
+/// Check whether a manifest is an image index rather than an image manifest. OCI image indexes
+/// don't have to give their media type, so an index without one is recognized by its list of
+/// manifests.
+fn is_image_index(versioned: &Versioned, text: &str) -> bool {
+    match versioned.media_type.as_deref() {
+        Some(media_type) => {
+            media_type == OCI_IMAGE_INDEX_MEDIA_TYPE || media_type == IMAGE_MANIFEST_LIST_MEDIA_TYPE
+        }
+        None => serde_json::from_str::<serde_json::Value>(text)
+            .map(|value| value.get("manifests").is_some())
+            .unwrap_or(false),
+    }
+}
+
+/// Parse the text of an image manifest.
+fn parse_manifest(text: &str, image: &Reference) -> anyhow::Result<OciManifest> {
+    debug!("Parsing response as OciManifest: {}", text);
+    serde_json::from_str(text).with_context(|| {
+        format!(
+            "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
+            image
+        )
+    })
+}
+
+/// A failed attempt at pulling a layer.
+struct LayerPullError {
+    /// Whether the failure may go away if the pull is tried again
+    transient: bool,
+    error: anyhow::Error,
+}
+
+impl LayerPullError {
+    fn from_reqwest(error: reqwest::Error) -> Self {
+        LayerPullError {
+            transient: !error.is_builder() && !error.is_redirect(),
+            error: error.into(),
+        }
+    }
+}
+
 /// The data for an image or module.
 #[derive(Clone)]
 pub struct ImageData {
@@ -171,6 +225,40 @@ impl Client {
         })
     }
 
+    /// Pull an image's manifest and return it along with the image's digest
+    ///
+    /// This is for images whose layers aren't modules, such as signatures, which
+    /// need the layer annotations from the manifest. The layers themselves can be
+    /// pulled with [`Client::pull_blob`].
+    pub async fn pull_image_manifest(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<(OciManifest, String)> {
+        if !self.tokens.contains_key(image.registry()) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        self.pull_manifest(image).await
+    }
+
+    /// Pull a blob, such as a layer, from an image's repository into `out`
+    ///
+    /// The blob is checked against its digest once it has been pulled.
+    pub async fn pull_blob(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        digest: &str,
+        out: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        if !self.tokens.contains_key(image.registry()) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        self.pull_layer(image, digest, out).await
+    }
+
     /// Push an image and return the uploaded URL of the image
     ///
     /// The client will check if it's already been authenticated and if
@@ -367,7 +455,56 @@ impl Client {
     ///
     /// If the connection has already gone through authentication, this will
     /// use the bearer token. Otherwise, this will attempt an anonymous pull.
+    ///
+    /// If the image is a multi-platform image, the manifest for the `wasm32`/`wasi` platform is
+    /// pulled from its index. The digest returned is always the digest of the image itself, so
+    /// that it matches what the registry reports for the reference.
     async fn pull_manifest(&self, image: &Reference) -> anyhow::Result<(OciManifest, String)> {
+        let (text, digest) = self.pull_manifest_text(image).await?;
+        let versioned = self.validate_image_manifest(&text).await?;
+        if !is_image_index(&versioned, &text) {
+            return Ok((parse_manifest(&text, image)?, digest));
+        }
+
+        debug!("Parsing response as OciImageIndex: {}", text);
+        let index: OciImageIndex = serde_json::from_str(&text).with_context(|| {
+            format!(
+                "Failed to parse response from pulling manifest for '{:?}' as an OciImageIndex",
+                image
+            )
+        })?;
+        let entry = index.wasm_manifest().ok_or_else(|| {
+            anyhow::anyhow!(
+                "image {} has no manifest for the {}/{} platform",
+                image,
+                crate::manifest::WASM_ARCHITECTURE,
+                crate::manifest::WASM_OS
+            )
+        })?;
+        let platform_image: Reference = format!(
+            "{}/{}@{}",
+            image.registry(),
+            image.repository(),
+            entry.digest
+        )
+        .parse()?;
+        let (text, _) = self.pull_manifest_text(&platform_image).await?;
+        let versioned = self.validate_image_manifest(&text).await?;
+        if is_image_index(&versioned, &text) {
+            return Err(anyhow::anyhow!(
+                "image {} has nested image indexes, which are not supported",
+                image
+            ));
+        }
+        Ok((parse_manifest(&text, &platform_image)?, digest))
+    }
+
+    /// Pull the raw text of a manifest, along with its digest.
+    ///
+    /// The manifest is hashed and rejected if it doesn't match the digest the registry reports
+    /// for it, or the digest it was pulled by, so that the digest returned always describes the
+    /// manifest returned.
+    async fn pull_manifest_text(&self, image: &Reference) -> anyhow::Result<(String, String)> {
         let url = self.to_v2_manifest_url(image);
         debug!("Pulling image manifest from {}", url);
         let request = self.client.get(&url);
@@ -381,17 +518,26 @@ impl Client {
             reqwest::StatusCode::OK => {
                 let digest = digest_header_value(&res)?;
                 let text = res.text().await?;
-
-                self.validate_image_manifest(&text).await?;
-
-                debug!("Parsing response as OciManifest: {}", text);
-                let manifest: OciManifest = serde_json::from_str(&text).with_context(|| {
-                    format!(
-                        "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
-                        image
-                    )
-                })?;
-                Ok((manifest, digest))
+                let actual = sha256_digest(text.as_bytes());
+                if digest != actual {
+                    return Err(anyhow::anyhow!(
+                        "manifest for {} has digest {}, but the registry reported {}",
+                        image,
+                        actual,
+                        digest
+                    ));
+                }
+                if let Some(requested) = image.digest() {
+                    if requested != actual {
+                        return Err(anyhow::anyhow!(
+                            "manifest for {} has digest {}, which is not the digest it was \
+                             pulled by",
+                            image,
+                            actual
+                        ));
+                    }
+                }
+                Ok((text, digest))
             }
             s if s.is_client_error() => {
                 // According to the OCI spec, we should see an error in the message body.
@@ -408,7 +554,7 @@ impl Client {
         }
     }
 
-    async fn validate_image_manifest(&self, text: &str) -> anyhow::Result<()> {
+    async fn validate_image_manifest(&self, text: &str) -> anyhow::Result<Versioned> {
         debug!("validating manifest: {}", text);
         let versioned: Versioned = serde_json::from_str(&text)
             .with_context(|| "Failed to parse manifest as a Versioned object")?;
@@ -418,14 +564,13 @@ impl Client {
                 versioned.schema_version
             ));
         }
-        if let Some(media_type) = versioned.media_type {
-            // TODO: support manifest lists?
-            if media_type != IMAGE_MANIFEST_MEDIA_TYPE {
+        if let Some(media_type) = &versioned.media_type {
+            if !MANIFEST_MEDIA_TYPES.contains(&media_type.as_str()) {
                 return Err(anyhow::anyhow!("unsupported media type: {}", media_type));
             }
         }
 
-        Ok(())
+        Ok(versioned)
     }
 
     /// Pull a single layer from an OCI registy.
@@ -435,25 +580,76 @@ impl Client {
     /// repository and the registry, but it is not used to verify that
     /// the digest is a layer inside of the image. (The manifest is
     /// used for that.)
-    async fn pull_layer<T: AsyncWrite + Unpin>(
+    ///
+    /// Transient failures, such as dropped connections or 5XX responses, are retried with a
+    /// backoff. If the registry supports range requests, a retry resumes from where the
+    /// previous attempt stopped rather than downloading the whole layer again. The layer is
+    /// checked against its digest once it has been downloaded.
+    async fn pull_layer(
         &self,
         image: &Reference,
         digest: &str,
-        mut out: T,
+        out: &mut Vec<u8>,
     ) -> anyhow::Result<()> {
         let url = self.to_v2_blob_url(image.registry(), image.repository(), digest);
-        let mut stream = self
-            .client
-            .get(&url)
-            .headers(self.auth_headers(image))
-            .send()
-            .await?
-            .bytes_stream();
+        let mut delay = LAYER_PULL_RETRY_DELAY;
+        for attempt in 1..=LAYER_PULL_ATTEMPTS {
+            match self.pull_layer_from(image, &url, out).await {
+                Ok(()) => break,
+                Err(e) if e.transient && attempt < LAYER_PULL_ATTEMPTS => {
+                    debug!(
+                        "Pulling layer {} failed with {:?}, resuming after {} bytes in {:?}",
+                        digest,
+                        e.error,
+                        out.len(),
+                        delay
+                    );
+                    tokio::time::delay_for(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => return Err(e.error),
+            }
+        }
 
-        while let Some(bytes) = stream.next().await {
-            out.write_all(&bytes?).await?;
+        let actual_digest = sha256_digest(out);
+        if digest.starts_with("sha256:") && actual_digest != digest {
+            return Err(anyhow::anyhow!(
+                "layer {} does not match its digest, got {}",
+                digest,
+                actual_digest
+            ));
         }
+        Ok(())
+    }
 
+    /// Make one attempt at pulling a layer, resuming from the end of what has been pulled so far
+    async fn pull_layer_from(
+        &self,
+        image: &Reference,
+        url: &str,
+        out: &mut Vec<u8>,
+    ) -> Result<(), LayerPullError> {
+        let mut request = self.client.get(url).headers(self.auth_headers(image));
+        if !out.is_empty() {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", out.len()));
+        }
+        let res = request.send().await.map_err(LayerPullError::from_reqwest)?;
+        match res.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => (),
+            // The registry sent the whole layer, whether or not part of it was asked for
+            s if s.is_success() => out.clear(),
+            s => {
+                return Err(LayerPullError {
+                    transient: s.is_server_error() || s == reqwest::StatusCode::TOO_MANY_REQUESTS,
+                    error: anyhow::anyhow!("{} when pulling layer from {}", s, url),
+                })
+            }
+        }
+
+        let mut stream = res.bytes_stream();
+        while let Some(bytes) = stream.next().await {
+            out.extend_from_slice(&bytes.map_err(LayerPullError::from_reqwest)?);
+        }
         Ok(())
     }
 
@@ -701,7 +897,7 @@ impl Client {
     /// be set on all OCI Registry request.
     fn auth_headers(&self, image: &Reference) -> HeaderMap {
         let mut headers = HeaderMap::new();
-        headers.insert("Accept", "application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.oci.image.manifest.v1+json".parse().unwrap());
+        headers.insert("Accept", MANIFEST_MEDIA_TYPES.join(",").parse().unwrap());
 
         if let Some(token) = self.tokens.get(image.registry()) {
             headers.insert("Authorization", token.bearer_token().parse().unwrap());
@@ -1102,6 +1298,190 @@ mod test {
         }
     }
 
+    /// Serve a layer over two connections: the first is dropped halfway through the layer, and
+    /// the second answers the resumed pull with the rest of the layer if `partial` is set, or
+    /// with the whole layer otherwise. Returns the registry address and a handle that yields the
+    /// `Range` header of the resumed pull.
+    fn serve_dropped_layer(
+        layer: Vec<u8>,
+        partial: bool,
+    ) -> (String, std::thread::JoinHandle<Option<String>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        fn read_range(stream: &std::net::TcpStream) -> Option<String> {
+            let mut range = None;
+            for line in BufReader::new(stream).lines() {
+                let line = line.expect("failed to read request");
+                if line.is_empty() {
+                    break;
+                }
+                let mut parts = line.splitn(2, ':');
+                let name = parts.next().unwrap_or_default();
+                if name.eq_ignore_ascii_case("range") {
+                    range = parts.next().map(|value| value.trim().to_owned());
+                }
+            }
+            range
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let registry = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let half = layer.len() / 2;
+
+            let (mut stream, _) = listener.accept().expect("failed to accept first pull");
+            read_range(&stream);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                layer.len()
+            )
+            .unwrap();
+            stream.write_all(&layer[..half]).unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().expect("failed to accept resumed pull");
+            let range = read_range(&stream);
+            let body = if partial {
+                write!(
+                    stream,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                    half,
+                    layer.len() - 1,
+                    layer.len()
+                )
+                .unwrap();
+                &layer[half..]
+            } else {
+                write!(stream, "HTTP/1.1 200 OK\r\n").unwrap();
+                &layer[..]
+            };
+            write!(
+                stream,
+                "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+            range
+        });
+        (registry, server)
+    }
+
+    async fn pull_dropped_layer(partial: bool) {
+        let layer: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+        let digest = sha256_digest(&layer);
+        let (registry, server) = serve_dropped_layer(layer.clone(), partial);
+        let reference = Reference::try_from(format!("{}/stub/layer:v1", registry))
+            .expect("failed to parse reference");
+        let c = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+        });
+
+        let mut file: Vec<u8> = Vec::new();
+        c.pull_layer(&reference, &digest, &mut file)
+            .await
+            .expect("failed to pull layer");
+
+        let range = server.join().expect("stub registry panicked");
+        assert_eq!(range.as_deref(), Some("bytes=2048-"));
+        assert_eq!(file, layer);
+    }
+
+    #[tokio::test]
+    async fn test_pull_layer_resumes_after_dropped_connection() {
+        pull_dropped_layer(true).await;
+    }
+
+    #[tokio::test]
+    async fn test_pull_layer_restarts_when_range_is_ignored() {
+        pull_dropped_layer(false).await;
+    }
+
+    /// Serve a manifest once, with `digest` as the digest the registry reports for it. Returns the
+    /// registry address.
+    fn serve_manifest(manifest: &'static str, digest: String) -> String {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let registry = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("failed to accept manifest pull");
+            for line in BufReader::new(&stream).lines() {
+                if line.expect("failed to read request").is_empty() {
+                    break;
+                }
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nDocker-Content-Digest: {}\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                manifest::OCI_IMAGE_MEDIA_TYPE,
+                digest,
+                manifest.len(),
+                manifest
+            )
+            .unwrap();
+        });
+        registry
+    }
+
+    const SIGNED_MANIFEST: &str = r#"{"schemaVersion":2,"layers":[{"digest":"sha256:aa"}]}"#;
+    const TAMPERED_MANIFEST: &str = r#"{"schemaVersion":2,"layers":[{"digest":"sha256:bb"}]}"#;
+
+    fn stub_client() -> Client {
+        Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_pull_manifest_text_returns_verified_digest() {
+        let digest = sha256_digest(SIGNED_MANIFEST.as_bytes());
+        let registry = serve_manifest(SIGNED_MANIFEST, digest.clone());
+        let reference = Reference::try_from(format!("{}/stub/manifest:v1", registry))
+            .expect("failed to parse reference");
+
+        let (text, pulled) = stub_client()
+            .pull_manifest_text(&reference)
+            .await
+            .expect("failed to pull manifest");
+        assert_eq!(text, SIGNED_MANIFEST);
+        assert_eq!(pulled, digest);
+    }
+
+    #[tokio::test]
+    async fn test_pull_manifest_text_rejects_manifest_not_matching_reported_digest() {
+        // A registry that reports a signed image's digest for a different manifest
+        let registry = serve_manifest(TAMPERED_MANIFEST, sha256_digest(SIGNED_MANIFEST.as_bytes()));
+        let reference = Reference::try_from(format!("{}/stub/manifest:v1", registry))
+            .expect("failed to parse reference");
+
+        stub_client()
+            .pull_manifest_text(&reference)
+            .await
+            .expect_err("tampered manifest should be rejected");
+    }
+
+    #[tokio::test]
+    async fn test_pull_manifest_text_rejects_manifest_not_matching_requested_digest() {
+        let registry = serve_manifest(
+            TAMPERED_MANIFEST,
+            sha256_digest(TAMPERED_MANIFEST.as_bytes()),
+        );
+        let reference = Reference::try_from(format!(
+            "{}/stub/manifest@{}",
+            registry,
+            sha256_digest(SIGNED_MANIFEST.as_bytes())
+        ))
+        .expect("failed to parse reference");
+
+        stub_client()
+            .pull_manifest_text(&reference)
+            .await
+            .expect_err("manifest not matching the requested digest should be rejected");
+    }
+
     #[tokio::test]
     async fn test_pull() {
         for &image in TEST_IMAGES {
@@ -3,10 +3,24 @@ use std::collections::HashMap;
 
 /// The mediatype for WASM layers.
 pub const WASM_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wasm";
+/// The mediatype some toolchains use for WASM modules pushed as OCI artifacts.
+pub const WASM_MODULE_MEDIA_TYPE: &str = "application/vnd.wasm.module.v1+wasm";
 /// The mediatype for a WASM image config.
 pub const WASM_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v1+json";
 /// The mediatype for an OCI manifest.
 pub const IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+/// The mediatype for an OCI image manifest.
+pub const OCI_IMAGE_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// The mediatype for an OCI image index, which lists the manifests of an image for several
+/// platforms.
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+/// The mediatype Docker uses for manifest lists, its version of an image index.
+pub const IMAGE_MANIFEST_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+/// The architecture of the platform WASM modules are published for in image indexes.
+pub const WASM_ARCHITECTURE: &str = "wasm32";
+/// The operating system of the platform WASM modules are published for in image indexes.
+pub const WASM_OS: &str = "wasi";
 /// The mediatype for an image config (manifest).
 pub const IMAGE_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
 /// The mediatype that Docker uses for image configs.
@@ -75,6 +89,70 @@ impl Default for OciManifest {
     }
 }
 
+/// The OCI image index lists the manifests of an image, usually one for each platform it is
+/// built for.
+///
+/// It is part of the OCI specification, and is defined here:
+/// https://github.com/opencontainers/image-spec/blob/master/image-index.md
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciImageIndex {
+    /// This is a schema version.
+    pub schema_version: u8,
+
+    /// This is an optional media type describing this index.
+    pub media_type: Option<String>,
+
+    /// The manifests in the index.
+    pub manifests: Vec<ImageIndexEntry>,
+
+    /// The annotations for this index
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl OciImageIndex {
+    /// Find the manifest for WASM modules in the index, which is the one for the `wasm32`
+    /// architecture and `wasi` operating system.
+    pub fn wasm_manifest(&self) -> Option<&ImageIndexEntry> {
+        self.manifests.iter().find(|entry| {
+            entry.platform.as_ref().map_or(false, |platform| {
+                platform.architecture == WASM_ARCHITECTURE && platform.os == WASM_OS
+            })
+        })
+    }
+}
+
+/// An entry in an image index, which describes a manifest and the platform it is for.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndexEntry {
+    /// The media type of the manifest.
+    pub media_type: String,
+    /// The digest of the manifest.
+    pub digest: String,
+    /// The size, in bytes, of the manifest.
+    pub size: i64,
+    /// The platform the manifest is for. This is optional, as not every manifest is for a
+    /// particular platform.
+    pub platform: Option<Platform>,
+    /// This OPTIONAL property contains arbitrary metadata for this entry.
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// The platform an image runs on.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Platform {
+    /// The CPU architecture, such as `amd64` or `wasm32`.
+    pub architecture: String,
+    /// The operating system, such as `linux` or `wasi`.
+    pub os: String,
+    /// The version of the operating system.
+    #[serde(rename = "os.version")]
+    pub os_version: Option<String>,
+    /// The variant of the CPU, such as `v7` for ARMv7.
+    pub variant: Option<String>,
+}
+
 /// Versioned provides a struct with the manifest's schemaVersion and mediaType.
 /// Incoming content with unknown schema versions can be decoded against this
 /// struct to check the version.
@@ -165,6 +243,44 @@ mod test {
     }
     "#;
 
+    const TEST_INDEX: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 528,
+                "digest": "sha256:1f9c6b4ea02b9be5fa1a2e8a2c9e0f7bd2ec0bbdb9ad0e2ef5c0a2f5e1b1a3c4",
+                "platform": { "architecture": "amd64", "os": "linux" }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 402,
+                "digest": "sha256:7b1e5a2f3c4d6e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f",
+                "platform": { "architecture": "wasm32", "os": "wasi" }
+            }
+        ]
+    }
+    "#;
+
+    #[test]
+    fn test_index_selects_wasm_manifest() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        assert_eq!(2, index.manifests.len());
+        let entry = index.wasm_manifest().expect("wasm manifest");
+        assert_eq!(
+            "sha256:7b1e5a2f3c4d6e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f",
+            entry.digest
+        );
+        assert_eq!(OCI_IMAGE_MEDIA_TYPE, entry.media_type);
+
+        let linux_only = OciImageIndex {
+            manifests: index.manifests[..1].to_vec(),
+            ..index
+        };
+        assert!(linux_only.wasm_manifest().is_none());
+    }
+
     #[test]
     fn test_manifest() {
         let manifest: OciManifest = serde_json::from_str(TEST_MANIFEST).expect("parsed manifest");
@@ -0,0 +1,283 @@
+//! Limits on the memory and CPU of processes, using version 2 cgroups on Linux.
+//!
+//! Each container's processes run in a cgroup of their own, under a cgroup of their pod, under a
+//! root cgroup created by the provider. A pod's cgroup is limited to what its containers are
+//! limited to, plus the overhead of its runtime class, for each resource that all of its containers
+//! are limited in. Where cgroups can't be used, such as on other operating systems, or where the
+//! kubelet may not write to the cgroup filesystem, processes run without limits.
+use std::path::{Path, PathBuf};
+
+use kubelet::container::Container;
+use kubelet::pod::Pod;
+use kubelet::resources;
+
+/// The period, in microseconds, over which the CPU time of a cgroup is limited.
+const CPU_PERIOD_MICROS: u64 = 100_000;
+
+/// The limits that a container sets on its resources.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Limits {
+    memory_bytes: Option<u64>,
+    cpu_millis: Option<u64>,
+}
+
+impl Limits {
+    /// The memory and CPU limits of the container.
+    pub(crate) fn of(container: &Container) -> anyhow::Result<Self> {
+        let limits = match container.resources().and_then(|r| r.limits.as_ref()) {
+            Some(limits) => limits,
+            None => return Ok(Limits::default()),
+        };
+        Ok(Limits {
+            memory_bytes: limits
+                .get("memory")
+                .map(resources::memory_bytes)
+                .transpose()?,
+            cpu_millis: limits.get("cpu").map(resources::cpu_millis).transpose()?,
+        })
+    }
+
+    /// The memory and CPU limits of a pod. Init containers run one at a time before the others,
+    /// so a pod is limited to the most of what any one of them is limited to and what its other
+    /// containers are limited to together. A resource is only limited if all of the pod's
+    /// containers are limited in it.
+    pub(crate) fn of_pod(pod: &Pod) -> anyhow::Result<Self> {
+        let containers = pod
+            .containers()
+            .iter()
+            .map(Limits::of)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let init_containers = pod
+            .init_containers()
+            .iter()
+            .map(Limits::of)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let overhead = pod.overhead();
+        let memory_overhead = overhead
+            .and_then(|overhead| overhead.get("memory"))
+            .map(resources::memory_bytes)
+            .transpose()?;
+        let cpu_overhead = overhead
+            .and_then(|overhead| overhead.get("cpu"))
+            .map(resources::cpu_millis)
+            .transpose()?;
+        Ok(Limits {
+            memory_bytes: pod_limit(
+                &containers,
+                &init_containers,
+                |limits| limits.memory_bytes,
+                memory_overhead,
+            ),
+            cpu_millis: pod_limit(
+                &containers,
+                &init_containers,
+                |limits| limits.cpu_millis,
+                cpu_overhead,
+            ),
+        })
+    }
+
+    /// The value of the cgroup's `memory.max` file.
+    fn memory_max(&self) -> String {
+        match self.memory_bytes {
+            Some(bytes) => bytes.to_string(),
+            None => "max".to_owned(),
+        }
+    }
+
+    /// The value of the cgroup's `cpu.max` file: the CPU time it may use in each period.
+    fn cpu_max(&self) -> String {
+        match self.cpu_millis {
+            // The kernel refuses quotas of less than a millisecond
+            Some(millis) => format!(
+                "{} {}",
+                std::cmp::max(millis * CPU_PERIOD_MICROS / 1000, 1000),
+                CPU_PERIOD_MICROS
+            ),
+            None => format!("max {}", CPU_PERIOD_MICROS),
+        }
+    }
+}
+
+/// The limit of a pod in one resource, if all of its containers are limited in it.
+fn pod_limit(
+    containers: &[Limits],
+    init_containers: &[Limits],
+    limit: impl Fn(&Limits) -> Option<u64>,
+    overhead: Option<u64>,
+) -> Option<u64> {
+    if containers.is_empty() {
+        return None;
+    }
+    let together: u64 = containers.iter().map(&limit).sum::<Option<u64>>()?;
+    let init = init_containers
+        .iter()
+        .map(&limit)
+        .try_fold(0, |most, limit| {
+            limit.map(|limit| std::cmp::max(most, limit))
+        })?;
+    Some(std::cmp::max(together, init) + overhead.unwrap_or(0))
+}
+
+/// The path of the cgroup of a pod's containers under the root cgroup.
+pub(crate) fn pod_path(root: &Path, pod: &Pod) -> PathBuf {
+    root.join(format!("{}_{}", pod.namespace(), pod.name()))
+}
+
+/// The cgroup of a pod, or of a container's processes.
+#[derive(Clone, Debug)]
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+/// Create the root cgroup that the cgroups of containers are created in, returning its path, or
+/// `None` if cgroups can't be used.
+#[cfg(target_os = "linux")]
+pub(crate) fn create_root(path: &Path) -> Option<PathBuf> {
+    let result = (|| -> std::io::Result<()> {
+        std::fs::create_dir_all(path)?;
+        if let Some(parent) = path.parent() {
+            enable_controllers(parent)?;
+        }
+        enable_controllers(path)
+    })();
+    match result {
+        Ok(()) => Some(path.to_owned()),
+        Err(e) => {
+            log::warn!(
+                "Unable to create cgroup {}, so processes will run without resource limits: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Create the root cgroup that the cgroups of containers are created in, returning its path, or
+/// `None` if cgroups can't be used.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn create_root(_path: &Path) -> Option<PathBuf> {
+    log::warn!(
+        "Cgroups are only available on Linux, so processes will run without resource limits"
+    );
+    None
+}
+
+/// Let the cgroups under the given one limit memory and CPU.
+fn enable_controllers(path: &Path) -> std::io::Result<()> {
+    std::fs::write(path.join("cgroup.subtree_control"), "+cpu +memory")
+}
+
+impl Cgroup {
+    /// Create the cgroup of a pod with the given limits under the root cgroup, or update its
+    /// limits if it already exists.
+    pub(crate) fn create_pod(root: &Path, pod: &Pod, limits: &Limits) -> std::io::Result<Self> {
+        let cgroup = Cgroup::create(pod_path(root, pod), limits)?;
+        enable_controllers(&cgroup.path)?;
+        Ok(cgroup)
+    }
+
+    /// Create the cgroup of a container with the given limits under this pod's cgroup.
+    pub(crate) fn create_container(&self, name: &str, limits: &Limits) -> std::io::Result<Self> {
+        Cgroup::create(self.path.join(name), limits)
+    }
+
+    fn create(path: PathBuf, limits: &Limits) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        let cgroup = Cgroup { path };
+        cgroup.write("memory.max", &limits.memory_max())?;
+        cgroup.write("cpu.max", &limits.cpu_max())?;
+        Ok(cgroup)
+    }
+
+    /// Move a process into the cgroup.
+    pub(crate) fn add(&self, pid: u32) -> std::io::Result<()> {
+        self.write("cgroup.procs", &pid.to_string())
+    }
+
+    /// Remove the cgroup once its processes, and the cgroups under it, are gone.
+    pub(crate) fn remove(&self) -> std::io::Result<()> {
+        std::fs::remove_dir(&self.path)
+    }
+
+    fn write(&self, file: &str, value: &str) -> std::io::Result<()> {
+        std::fs::write(self.path.join(file), value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn container(limits: serde_json::Value) -> Container {
+        Container::new(
+            &serde_json::from_value(serde_json::json!({
+                "name": "app",
+                "image": "hello:v1",
+                "resources": { "limits": limits }
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn limits_are_written_in_cgroup_units() {
+        let limits = Limits::of(&container(
+            serde_json::json!({ "memory": "64Mi", "cpu": "250m" }),
+        ))
+        .unwrap();
+        assert_eq!(limits.memory_max(), "67108864");
+        assert_eq!(limits.cpu_max(), "25000 100000");
+
+        let limits = Limits::of(&container(serde_json::json!({ "cpu": "2" }))).unwrap();
+        assert_eq!(limits.memory_max(), "max");
+        assert_eq!(limits.cpu_max(), "200000 100000");
+    }
+
+    #[test]
+    fn containers_without_limits_are_unlimited() {
+        let limits = Limits::of(&container(serde_json::json!({}))).unwrap();
+        assert_eq!(limits, Limits::default());
+        assert_eq!(limits.cpu_max(), "max 100000");
+    }
+
+    #[test]
+    fn pods_are_limited_to_their_containers_and_overhead() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "hello", "namespace": "default" },
+            "spec": {
+                "overhead": { "memory": "16Mi", "cpu": "100m" },
+                "initContainers": [{
+                    "name": "init",
+                    "image": "init:v1",
+                    "resources": { "limits": { "memory": "256Mi" } }
+                }],
+                "containers": [
+                    {
+                        "name": "a",
+                        "image": "a:v1",
+                        "resources": { "limits": { "memory": "64Mi", "cpu": "500m" } }
+                    },
+                    {
+                        "name": "b",
+                        "image": "b:v1",
+                        "resources": { "limits": { "memory": "64Mi" } }
+                    }
+                ]
+            }
+        }))
+        .unwrap();
+        let limits = Limits::of_pod(&pod).unwrap();
+        // The init container is limited to more than the others together
+        assert_eq!(limits.memory_bytes, Some((256 + 16) * 1024 * 1024));
+        // Container b isn't limited in CPU, so neither is the pod
+        assert_eq!(limits.cpu_millis, None);
+    }
+
+    #[test]
+    fn tiny_cpu_limits_are_rounded_up_to_the_smallest_quota() {
+        let limits = Limits::of(&container(serde_json::json!({ "cpu": "1m" }))).unwrap();
+        assert_eq!(limits.cpu_max(), "1000 100000");
+    }
+}
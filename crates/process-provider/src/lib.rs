@@ -0,0 +1,325 @@
+//! A custom kubelet backend that runs native binaries as processes on the host
+//!
+//! The crate provides the [`ProcessProvider`] type which can be used as a provider with
+//! [`kubelet`]. It is a reference implementation of a provider for workloads that aren't
+//! WebAssembly, so that a cluster can run both through the same kubelet crate.
+//!
+//! A container's image is pulled like a WebAssembly module is, and holds the binary that is run
+//! for the container, rather than a filesystem. The binary always runs with the container's args,
+//! in a working directory of the container's own, with the container's volumes linked into it at
+//! their mount paths. On Linux, each container's processes are limited to the memory and CPU the
+//! container is limited to through a cgroup of their own, nested in a cgroup of the pod that also
+//! accounts for the pod's overhead.
+//!
+//! # Example
+//! ```rust,no_run
+//! use kubelet::{Kubelet, config::Config};
+//! use kubelet::store::oci::FileStore;
+//! use std::sync::Arc;
+//! use process_provider::ProcessProvider;
+//!
+//! async {
+//!     // Get a configuration for the Kubelet
+//!     let kubelet_config = Config::default();
+//!     let client = oci_distribution::Client::default();
+//!     let store = Arc::new(FileStore::new(client, &std::path::PathBuf::from("")));
+//!
+//!     // Load a kubernetes configuration
+//!     let kubeconfig = kube::Config::infer().await.unwrap();
+//!
+//!     // Instantiate the provider type
+//!     let provider = ProcessProvider::new(store, &kubelet_config, kubeconfig.clone()).await.unwrap();
+//!
+//!     // Instantiate the Kubelet
+//!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config).await.unwrap();
+//!     // Start the Kubelet and block on it
+//!     kubelet.start().await.unwrap();
+//! };
+//! ```
+
+#![deny(missing_docs)]
+
+mod cgroup;
+mod process;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::PodList;
+use kubelet::exec::{CommandOptions, Stdin};
+use kubelet::log::storage::{LogHandleFactory, LogStorage};
+use kubelet::node::{Builder, NodeProps};
+use kubelet::pod::state::prelude::SharedState;
+use kubelet::pod::{EventRecorder, Handle, Pod, PodKey};
+use kubelet::provider::{PodRuntimeStatus, Provider, ProviderError};
+use kubelet::state::common::registered::Registered;
+use kubelet::state::common::terminated::Terminated;
+use kubelet::state::common::{GenericProvider, GenericProviderState};
+use kubelet::store::Store;
+use kubelet::volume::Ref;
+use process::ProcessHandle;
+use tokio::sync::RwLock;
+
+mod states;
+use states::pod::PodState;
+
+/// The architecture of the host, as Kubernetes names it.
+#[cfg(target_arch = "x86_64")]
+const TARGET_ARCH: &str = "amd64";
+#[cfg(target_arch = "aarch64")]
+const TARGET_ARCH: &str = "arm64";
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const TARGET_ARCH: &str = std::env::consts::ARCH;
+
+/// The key of the taint that keeps pods not meant for this provider off the node.
+const RUNTIME_TAINT_KEY: &str = "krustlet/runtime";
+/// The value of the taint that keeps pods not meant for this provider off the node.
+const RUNTIME_TAINT_VALUE: &str = "process";
+
+const VOLUME_DIR: &str = "volumes";
+/// The directory that the binaries and working directories of pods are kept in.
+const PROCESS_DIR: &str = "processes";
+/// The cgroup that the cgroups of containers are created in.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup/krustlet";
+
+/// ProcessProvider provides a Kubelet runtime implementation that runs native binaries as
+/// processes on the host.
+#[derive(Clone)]
+pub struct ProcessProvider {
+    shared: ProviderState,
+}
+
+type PodHandleMap = Arc<RwLock<HashMap<PodKey, Arc<Handle<ProcessHandle, LogHandleFactory>>>>>;
+
+/// Provider-level state shared between all pods
+#[derive(Clone)]
+pub struct ProviderState {
+    handles: PodHandleMap,
+    store: Arc<dyn Store + Sync + Send>,
+    log_storage: Arc<dyn LogStorage>,
+    events: EventRecorder,
+    kubeconfig: kube::Config,
+    volume_path: PathBuf,
+    allowed_host_paths: Vec<PathBuf>,
+    /// Where the binaries and working directories of pods are kept
+    process_path: PathBuf,
+    /// The cgroup that the cgroups of containers are created in, if cgroups can be used
+    cgroup_root: Option<PathBuf>,
+    /// The kubelet's data directory, which pods' network namespaces are kept in
+    data_dir: PathBuf,
+}
+
+impl ProviderState {
+    /// The directory that a pod's binaries and working directories are kept in.
+    fn pod_dir(&self, pod: &Pod) -> PathBuf {
+        self.process_path
+            .join(format!("{}_{}", pod.namespace(), pod.name()))
+    }
+}
+
+#[async_trait]
+impl GenericProviderState for ProviderState {
+    fn client(&self) -> kube::client::Client {
+        kube::Client::new(self.kubeconfig.clone())
+    }
+    fn store(&self) -> std::sync::Arc<(dyn Store + Send + Sync + 'static)> {
+        self.store.clone()
+    }
+    fn volume_path(&self) -> PathBuf {
+        self.volume_path.clone()
+    }
+    fn allowed_host_paths(&self) -> Vec<PathBuf> {
+        self.allowed_host_paths.clone()
+    }
+    fn event_recorder(&self) -> Option<EventRecorder> {
+        Some(self.events.clone())
+    }
+    async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
+        // Stopping can take as long as the pod's grace period, so don't hold on to the map
+        let key = PodKey::from(pod);
+        let handle = self.handles.read().await.get(&key).cloned();
+        match handle {
+            Some(handle) => Ok(handle.stop().await?),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ProcessProvider {
+    /// Create a new process provider from a binary store and a kubelet config
+    pub async fn new(
+        store: Arc<dyn Store + Sync + Send>,
+        config: &kubelet::config::Config,
+        kubeconfig: kube::Config,
+    ) -> anyhow::Result<Self> {
+        let volume_path = config.data_dir.join(VOLUME_DIR);
+        let process_path = config.data_dir.join(PROCESS_DIR);
+        tokio::fs::create_dir_all(&volume_path).await?;
+        tokio::fs::create_dir_all(&process_path).await?;
+        let cgroup_root =
+            tokio::task::spawn_blocking(|| cgroup::create_root(std::path::Path::new(CGROUP_ROOT)))
+                .await?;
+        Ok(Self {
+            shared: ProviderState {
+                handles: Default::default(),
+                store,
+                log_storage: kubelet::log::storage::from_config(config)?,
+                events: EventRecorder::new(
+                    kube::Client::new(kubeconfig.clone()),
+                    &config.node_name,
+                ),
+                kubeconfig,
+                volume_path,
+                allowed_host_paths: config.allowed_host_paths.clone(),
+                process_path,
+                cgroup_root,
+                data_dir: config.data_dir.clone(),
+            },
+        })
+    }
+}
+
+struct ModuleRunContext {
+    /// The binary pulled for each container, by container name
+    modules: HashMap<String, Vec<u8>>,
+    volumes: HashMap<String, Ref>,
+}
+
+#[async_trait::async_trait]
+impl Provider for ProcessProvider {
+    type ProviderState = ProviderState;
+    type InitialState = Registered<Self>;
+    type TerminatedState = Terminated<Self>;
+    type PodState = PodState;
+
+    const ARCH: &'static str = TARGET_ARCH;
+
+    fn provider_state(&self) -> SharedState<ProviderState> {
+        Arc::new(RwLock::new(self.shared.clone()))
+    }
+
+    fn node_props(&self) -> NodeProps {
+        let mut props = NodeProps::new(TARGET_ARCH);
+        props.os = std::env::consts::OS.to_owned();
+        // Pods have to opt in to running as processes, as their images aren't run as containers
+        props.add_taint("NoSchedule", RUNTIME_TAINT_KEY, RUNTIME_TAINT_VALUE);
+        props.add_taint("NoExecute", RUNTIME_TAINT_KEY, RUNTIME_TAINT_VALUE);
+        props
+    }
+
+    async fn node(&self, builder: &mut Builder) -> Result<(), ProviderError> {
+        builder.set_architecture(TARGET_ARCH);
+        Ok(())
+    }
+
+    async fn initialize_pod_state(&self, pod: &Pod) -> Result<Self::PodState, ProviderError> {
+        Ok(PodState::new(pod))
+    }
+
+    async fn logs(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        sender: kubelet::log::Sender,
+    ) -> Result<(), ProviderError> {
+        let handles = self.shared.handles.read().await;
+        let handle = handles
+            .get(&PodKey::new(&namespace, &pod_name))
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.output(&container_name, sender).await
+    }
+
+    async fn exec(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        options: CommandOptions,
+        stdin: Stdin,
+        sender: kubelet::exec::Sender,
+    ) -> Result<(), ProviderError> {
+        // Hold on to the pod handle rather than the whole map, as exec sessions can be long lived
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned()
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.exec(&container_name, options, stdin, sender).await
+    }
+
+    async fn pod_status(
+        &self,
+        namespace: String,
+        pod_name: String,
+    ) -> Result<PodRuntimeStatus, ProviderError> {
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned();
+        match handle {
+            Some(handle) => Ok(handle.runtime_status().await),
+            None => Ok(PodRuntimeStatus::Unknown),
+        }
+    }
+
+    async fn running_pods(&self) -> Result<PodList, ProviderError> {
+        let handles = self.shared.handles.read().await;
+        Ok(PodList {
+            items: handles
+                .values()
+                .map(|handle| handle.pod().as_kube_pod().clone())
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    async fn shutdown_pod(&self, pod: &Pod) -> Result<(), ProviderError> {
+        Ok(self.shared.stop(pod).await?)
+    }
+}
+
+impl GenericProvider for ProcessProvider {
+    type ProviderState = ProviderState;
+    type PodState = PodState;
+    type RunState = crate::states::pod::starting::Starting;
+
+    fn validate_pod_runnable(pod: &Pod) -> anyhow::Result<()> {
+        if !pod.init_containers().is_empty() {
+            return Err(anyhow::anyhow!(
+                "Cannot run {}: spec specifies init containers which are not supported as processes",
+                pod.name()
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_container_runnable(
+        container: &kubelet::container::Container,
+    ) -> anyhow::Result<()> {
+        if container.command().is_some() {
+            return Err(anyhow::anyhow!(
+                "Cannot run {}: spec specifies a command, but the binary in the image is always run",
+                container.name()
+            ));
+        }
+        if let Some(image) = container.image()? {
+            if image.whole().starts_with("k8s.gcr.io/kube-proxy") {
+                return Err(anyhow::anyhow!("Cannot run kube-proxy"));
+            }
+        }
+        Ok(())
+    }
+}
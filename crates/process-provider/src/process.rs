@@ -0,0 +1,336 @@
+//! Running a container's binary as a process on the host.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use log::{debug, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use kubelet::container::Handle as ContainerHandle;
+use kubelet::container::Status;
+use kubelet::exec::{CommandOptions, ExecResult};
+use kubelet::handle::{ExecHandler, StopHandler};
+use kubelet::log::storage::{ContainerLog, LogHandleFactory, LogSink};
+use kubelet::log::{format_record, LogStream};
+use kubelet::provider::ProviderError;
+
+use crate::cgroup::Cgroup;
+
+/// How a running process is asked to stop.
+#[derive(Clone, Copy, Debug)]
+enum Signal {
+    /// Ask the process to exit, which it may take its time over.
+    Stop,
+    /// Make the process exit right away.
+    Kill,
+}
+
+/// A binary to run as a process, and how to run it.
+pub(crate) struct Process {
+    /// The binary that was pulled for the container
+    pub(crate) binary: PathBuf,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: HashMap<String, String>,
+    pub(crate) working_dir: PathBuf,
+    /// The cgroup that the process and any commands run alongside it are limited by
+    pub(crate) cgroup: Option<Cgroup>,
+    /// The pod's network namespace, which the process and commands run alongside it join, if
+    /// the pod has one
+    pub(crate) netns: Option<PathBuf>,
+}
+
+impl Process {
+    /// Start the process, writing its output to the given log, and sending its status on the
+    /// given channel once it has exited.
+    pub(crate) async fn start(
+        self,
+        log: ContainerLog,
+        mut status_sender: Sender<Status>,
+    ) -> anyhow::Result<ContainerHandle<ProcessHandle, LogHandleFactory>> {
+        let mut child = self
+            .command(&self.binary, &self.args)?
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        debug!(
+            "Started process {} from {}",
+            child.id(),
+            self.binary.display()
+        );
+        self.limit(child.id());
+
+        // Each line the process writes is recorded with when it was written and which stream it
+        // was written to
+        let sink = Arc::new(Mutex::new(log.sink));
+        let mut forwarders = vec![];
+        if let Some(stdout) = child.stdout.take() {
+            forwarders.push(tokio::spawn(forward(
+                stdout,
+                LogStream::Stdout,
+                sink.clone(),
+            )));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forwarders.push(tokio::spawn(forward(stderr, LogStream::Stderr, sink)));
+        }
+
+        let (signal_sender, signals) = mpsc::unbounded_channel();
+        let cgroup = self.cgroup.clone();
+        let exited = Arc::new(AtomicBool::new(false));
+        let task_exited = exited.clone();
+        let task = tokio::spawn(async move {
+            let status = wait(child, signals).await;
+            task_exited.store(true, Ordering::SeqCst);
+            // The process has exited, but anything it wrote may not have been logged yet
+            for forwarder in forwarders {
+                forwarder.await.ok();
+            }
+            if let Some(cgroup) = cgroup {
+                if let Err(e) = cgroup.remove() {
+                    warn!("Unable to remove cgroup of exited process: {}", e);
+                }
+            }
+            let (message, failed) = exit_message(status);
+            status_sender
+                .send(Status::terminated(&message, failed))
+                .await
+                .map_err(|_| anyhow::anyhow!("container state machine has gone away"))
+        });
+
+        Ok(ContainerHandle::new(
+            ProcessHandle {
+                task: Some(task),
+                exited,
+                signals: signal_sender,
+                process: self,
+            },
+            log.handles,
+        ))
+    }
+
+    /// A command that runs the given program in the container's environment.
+    fn command(&self, program: &std::path::Path, args: &[String]) -> std::io::Result<Command> {
+        let mut command = std::process::Command::new(program);
+        command
+            .args(args)
+            .env_clear()
+            .envs(&self.env)
+            .current_dir(&self.working_dir);
+        if let Some(netns) = &self.netns {
+            join_namespace(&mut command, netns)?;
+        }
+        let mut command = Command::from(command);
+        command.kill_on_drop(true);
+        Ok(command)
+    }
+
+    /// Move a process into the container's cgroup, if it has one.
+    fn limit(&self, pid: u32) {
+        if let Some(cgroup) = &self.cgroup {
+            if let Err(e) = cgroup.add(pid) {
+                warn!("Unable to limit the resources of process {}: {}", pid, e);
+            }
+        }
+    }
+}
+
+/// Have the command's process join the network namespace bound to the given file.
+#[cfg(target_os = "linux")]
+fn join_namespace(
+    command: &mut std::process::Command,
+    netns: &std::path::Path,
+) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let namespace = std::fs::File::open(netns)?;
+    // SAFETY: only setns is called between forking and exec, which is async-signal-safe. The
+    // file is moved into the closure, so it is open for as long as the command is.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Network namespaces are only given to pods on Linux.
+#[cfg(not(target_os = "linux"))]
+fn join_namespace(
+    _command: &mut std::process::Command,
+    _netns: &std::path::Path,
+) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Wait for a process to exit, passing on any signals sent to it in the meantime.
+async fn wait(
+    mut child: Child,
+    mut signals: UnboundedReceiver<Signal>,
+) -> std::io::Result<ExitStatus> {
+    loop {
+        tokio::select! {
+            status = &mut child => return status,
+            Some(signal) = signals.recv() => {
+                if let Err(e) = send_signal(&mut child, signal) {
+                    warn!("Unable to signal process {} to {:?}: {}", child.id(), signal, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send_signal(child: &mut Child, signal: Signal) -> std::io::Result<()> {
+    match signal {
+        Signal::Stop => {
+            // SAFETY: the process is the child's own, and is only reaped once this loop has
+            // seen it exit, so its pid can't have been reused
+            if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        }
+        Signal::Kill => child.kill(),
+    }
+}
+
+/// There are no signals to ask the process to stop with, so it is always killed.
+#[cfg(not(unix))]
+fn send_signal(child: &mut Child, _signal: Signal) -> std::io::Result<()> {
+    child.kill()
+}
+
+/// Write each line of a process's output to its log.
+async fn forward<R: AsyncRead + Unpin>(
+    output: R,
+    stream: LogStream,
+    sink: Arc<Mutex<Box<dyn LogSink>>>,
+) {
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match output.read_until(b'\n', &mut line).await {
+            Ok(0) => return,
+            Ok(_) => {
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                let record = format_record(stream, Utc::now(), &line);
+                let sink = sink.clone();
+                // Writing to the log may block
+                let result =
+                    tokio::task::spawn_blocking(move || sink.lock().unwrap().write_record(&record))
+                        .await;
+                match result {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => warn!("Unable to write process output to log: {}", e),
+                    Err(e) => warn!("Unable to write process output to log: {}", e),
+                }
+            }
+            Err(e) => {
+                warn!("Unable to read process output: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// The message that a process's container is terminated with, and whether it failed.
+fn exit_message(status: std::io::Result<ExitStatus>) -> (String, bool) {
+    match status {
+        Ok(status) if status.success() => ("Process exited successfully".to_owned(), false),
+        Ok(status) => match status.code() {
+            Some(code) => (format!("Process exited with code {}", code), true),
+            None => ("Process was terminated by a signal".to_owned(), true),
+        },
+        Err(e) => (format!("Unable to wait for process to exit: {}", e), true),
+    }
+}
+
+/// A handle to a container's running process.
+pub struct ProcessHandle {
+    /// The task waiting for the process to exit, until it has been waited on
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+    /// Whether the process has exited
+    exited: Arc<AtomicBool>,
+    /// Passes signals on to the task waiting for the process, which owns it
+    signals: UnboundedSender<Signal>,
+    /// How the process was run, which commands are run alongside it with
+    process: Process,
+}
+
+impl ProcessHandle {
+    fn signal(&self, signal: Signal) -> anyhow::Result<()> {
+        // The task only goes away once the process has exited, in which case there is nothing to
+        // stop
+        self.signals.send(signal).ok();
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl StopHandler for ProcessHandle {
+    async fn stop(&mut self) -> Result<(), ProviderError> {
+        Ok(self.signal(Signal::Stop)?)
+    }
+
+    async fn kill(&mut self) -> Result<(), ProviderError> {
+        Ok(self.signal(Signal::Kill)?)
+    }
+
+    fn has_exited(&self) -> Option<bool> {
+        Some(self.exited.load(Ordering::SeqCst))
+    }
+
+    async fn wait(&mut self) -> Result<(), ProviderError> {
+        // The task is only taken once it has completed, so that waiting can be cancelled and
+        // resumed, such as when waiting for the process to stop times out
+        if let Some(task) = &mut self.task {
+            let result = task.await;
+            self.task = None;
+            result??;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl ExecHandler for ProcessHandle {
+    /// Runs the command as a process of its own, with the same environment, working directory
+    /// and limits as the container's process.
+    async fn exec(&self, options: CommandOptions) -> Result<ExecResult, ProviderError> {
+        let (program, args) = options
+            .command
+            .split_first()
+            .ok_or_else(|| ProviderError::InvalidRequest("no command was given".to_owned()))?;
+        let child = self
+            .process
+            .command(std::path::Path::new(program), args)?
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        self.process.limit(child.id());
+        let output = child.wait_with_output().await?;
+
+        let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        Ok(ExecResult {
+            output: text,
+            exit_code: output.status.code().unwrap_or(1),
+        })
+    }
+}
@@ -0,0 +1,13 @@
+pub(crate) mod container;
+pub(crate) mod pod;
+
+/// When called in a state's `next` function, exits the state machine
+/// returns a fatal error to the kubelet.
+#[macro_export]
+macro_rules! fail_fatal {
+    ($err:ident) => {{
+        let aerr = anyhow::Error::from($err);
+        log::error!("{:?}", aerr);
+        return Transition::Complete(Err(aerr));
+    }};
+}
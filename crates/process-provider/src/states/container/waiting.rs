@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{info, warn};
+use tokio::sync::mpsc;
+
+use kubelet::container::expand;
+use kubelet::container::state::prelude::*;
+use kubelet::log::storage::ContainerLog;
+use kubelet::pod::{Handle as PodHandle, PodKey};
+use kubelet::state::common::GenericProviderState;
+
+use crate::cgroup::{Cgroup, Limits};
+use crate::process::Process;
+use crate::ProviderState;
+
+use super::running::Running;
+use super::terminated::Terminated;
+use super::ContainerState;
+
+/// The container is waiting for its process to start.
+#[derive(Default, Debug, TransitionTo)]
+#[transition_to(Running, Terminated)]
+pub struct Waiting;
+
+#[async_trait::async_trait]
+impl State<ContainerState> for Waiting {
+    async fn next(
+        self: Box<Self>,
+        shared: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
+    ) -> Transition<ContainerState> {
+        let container = container.latest();
+
+        info!(
+            "Starting container {} for pod {}",
+            container.name(),
+            state.pod.name(),
+        );
+
+        let (tx, rx) = mpsc::channel(1);
+        let started = match prepare(&shared, state, &container).await {
+            Ok((process, log)) => process.start(log, tx).await,
+            Err(e) => Err(e),
+        };
+        let container_handle = match started {
+            Ok(handle) => handle,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to start process: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        {
+            let provider_state = shared.read().await;
+            let mut handles_writer = provider_state.handles.write().await;
+            let pod_handle = handles_writer
+                .entry(PodKey::from(&state.pod))
+                .or_insert_with(|| {
+                    Arc::new(PodHandle::new(HashMap::new(), state.pod.clone(), None))
+                });
+            pod_handle
+                .insert_container_handle(state.container_key.clone(), container_handle)
+                .await;
+        }
+
+        Transition::next(self, Running::new(rx))
+    }
+
+    async fn status(
+        &self,
+        _state: &mut ContainerState,
+        _container: &Container,
+    ) -> anyhow::Result<Status> {
+        Ok(Status::waiting("Process is starting."))
+    }
+}
+
+/// Lay out the container's binary and working directory, and work out how to run its process.
+async fn prepare(
+    shared: &SharedState<ProviderState>,
+    state: &ContainerState,
+    container: &Container,
+) -> anyhow::Result<(Process, ContainerLog)> {
+    let (client, log_storage, pod_dir, cgroup_root, data_dir) = {
+        let provider_state = shared.read().await;
+        (
+            provider_state.client(),
+            provider_state.log_storage.clone(),
+            provider_state.pod_dir(&state.pod),
+            provider_state.cgroup_root.clone(),
+            provider_state.data_dir.clone(),
+        )
+    };
+
+    let (binary, volumes) = {
+        let run_context = state.run_context.read().await;
+        // The binary is kept in the run context in case the container is restarted
+        let binary = run_context
+            .modules
+            .get(container.name())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("no binary was pulled for the container"))?;
+        let volumes = container
+            .volume_mounts()
+            .iter()
+            .flatten()
+            .map(|vm| {
+                let volume = run_context.volumes.get(&vm.name).ok_or_else(|| {
+                    anyhow::anyhow!("no volume with the name of {} found", vm.name)
+                })?;
+                Ok((PathBuf::from(&vm.mount_path), volume.deref().clone()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        (binary, volumes)
+    };
+
+    let mut env = kubelet::provider::env_vars(container, &state.pod, &client).await?;
+    // Processes see the node's filesystem, so are told where the pod's resolv.conf is instead
+    if let Some(resolv_conf) = kubelet::dns::resolv_conf_path(&data_dir, &state.pod) {
+        env.entry(kubelet::dns::RESOLV_CONF_ENV.to_owned())
+            .or_insert_with(|| resolv_conf.to_string_lossy().into_owned());
+    }
+    let args = container
+        .args()
+        .iter()
+        .flatten()
+        .map(|arg| expand(arg, &env))
+        .collect();
+
+    // Binaries are kept apart from working directories, so that a container can't overwrite
+    // another's binary
+    let binary_path = pod_dir.join("bin").join(container.name());
+    write_binary(&binary_path, &binary).await?;
+    let container_dir = pod_dir.join("containers").join(container.name());
+    tokio::fs::create_dir_all(&container_dir).await?;
+    for (mount_path, host_path) in volumes {
+        link_volume(&within(&container_dir, &mount_path), &host_path).await?;
+    }
+    let working_dir = match container.working_dir() {
+        Some(dir) => within(&container_dir, Path::new(dir)),
+        None => container_dir,
+    };
+    tokio::fs::create_dir_all(&working_dir).await?;
+
+    let cgroup = match cgroup_root {
+        Some(root) => {
+            let pod_limits = Limits::of_pod(&state.pod)?;
+            let limits = Limits::of(container)?;
+            let pod = state.pod.clone();
+            let name = container.name().to_owned();
+            // Each container's cgroup is nested in its pod's, which also holds the pod's overhead
+            let created = tokio::task::spawn_blocking(move || {
+                Cgroup::create_pod(&root, &pod, &pod_limits)?.create_container(&name, &limits)
+            })
+            .await?;
+            match created {
+                Ok(cgroup) => Some(cgroup),
+                Err(e) => {
+                    warn!(
+                        "Unable to create cgroup for pod {} container {}, so it will run without resource limits: {}",
+                        state.pod.name(),
+                        container.name(),
+                        e
+                    );
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let pod = state.pod.clone();
+    let name = container.name().to_owned();
+    // Starting the log may block, so run it in a blocking task
+    let log = tokio::task::spawn_blocking(move || log_storage.create(&pod, &name)).await??;
+
+    Ok((
+        Process {
+            binary: binary_path,
+            args,
+            env,
+            working_dir,
+            cgroup,
+            netns: kubelet::network::namespace_path(&data_dir, &state.pod),
+        },
+        log,
+    ))
+}
+
+/// A path inside the container's directory, with absolute paths taken to be relative to it.
+fn within(container_dir: &Path, path: &Path) -> PathBuf {
+    container_dir.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Write a binary out so that it can be run.
+async fn write_binary(path: &Path, binary: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, binary).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).await?;
+    }
+    Ok(())
+}
+
+/// Make a volume available at its mount path, by linking to it from there.
+#[cfg(unix)]
+async fn link_volume(link: &Path, volume: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = link.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // A restarted container's links are already there
+    if tokio::fs::symlink_metadata(link).await.is_ok() {
+        tokio::fs::remove_file(link).await?;
+    }
+    tokio::fs::os::unix::symlink(volume, link).await?;
+    Ok(())
+}
+
+/// Make a volume available at its mount path, by linking to it from there.
+#[cfg(not(unix))]
+async fn link_volume(_link: &Path, _volume: &Path) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "volumes can only be given to processes on Unix"
+    ))
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use krator::{ObjectState, SharedState};
+use kubelet::backoff::BackoffStrategy;
+use kubelet::backoff::ExponentialBackoffStrategy;
+use kubelet::pod::{Pod, PodKey, Status};
+use kubelet::state::common::{BackoffSequence, GenericPodState, ThresholdTrigger};
+use log::warn;
+use tokio::sync::RwLock;
+
+use crate::ModuleRunContext;
+use crate::ProviderState;
+
+pub(crate) mod completed;
+pub(crate) mod running;
+pub(crate) mod starting;
+
+/// State that is shared between pod state handlers.
+pub struct PodState {
+    key: PodKey,
+    run_context: SharedState<ModuleRunContext>,
+    errors: usize,
+    image_pull_backoff_strategy: ExponentialBackoffStrategy,
+    crash_loop_backoff_strategy: ExponentialBackoffStrategy,
+    /// The pod, whose containers' logs and working directories are removed along with it
+    pod: Pod,
+}
+
+#[async_trait]
+impl ObjectState for PodState {
+    type Manifest = Pod;
+    type Status = Status;
+    type SharedState = ProviderState;
+    async fn async_drop(self, provider_state: &mut Self::SharedState) {
+        {
+            let mut handles = provider_state.handles.write().await;
+            handles.remove(&self.key);
+        }
+        if let Err(e) = tokio::fs::remove_dir_all(provider_state.pod_dir(&self.pod)).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(
+                    "Unable to remove working directories of pod {}: {}",
+                    self.key.name(),
+                    e
+                );
+            }
+        }
+        if let Some(root) = &provider_state.cgroup_root {
+            // The cgroups of the pod's containers are removed as they exit
+            let path = crate::cgroup::pod_path(root, &self.pod);
+            if let Err(e) = tokio::fs::remove_dir(&path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Unable to remove cgroup of pod {}: {}", self.key.name(), e);
+                }
+            }
+        }
+        // The logs of a pod are only kept for as long as the pod exists
+        let log_storage = provider_state.log_storage.clone();
+        let pod = self.pod;
+        let result = tokio::task::spawn_blocking(move || log_storage.remove_pod(&pod)).await;
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Unable to remove logs of pod {}: {}", self.key.name(), e),
+            Err(e) => warn!("Unable to remove logs of pod {}: {}", self.key.name(), e),
+        }
+    }
+}
+
+impl PodState {
+    pub fn new(pod: &Pod) -> Self {
+        let run_context = ModuleRunContext {
+            modules: Default::default(),
+            volumes: Default::default(),
+        };
+        PodState {
+            key: PodKey::from(pod),
+            run_context: Arc::new(RwLock::new(run_context)),
+            errors: 0,
+            image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
+            crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
+            pod: pod.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl GenericPodState for PodState {
+    async fn set_modules(&mut self, modules: HashMap<String, Vec<u8>>) {
+        let mut run_context = self.run_context.write().await;
+        run_context.modules = modules;
+    }
+    async fn set_volumes(&mut self, volumes: HashMap<String, kubelet::volume::Ref>) {
+        let mut run_context = self.run_context.write().await;
+        run_context.volumes = volumes;
+    }
+    async fn backoff(&mut self, sequence: BackoffSequence) {
+        let backoff_strategy = match sequence {
+            BackoffSequence::ImagePull => &mut self.image_pull_backoff_strategy,
+            BackoffSequence::CrashLoop => &mut self.crash_loop_backoff_strategy,
+        };
+        backoff_strategy.wait().await;
+    }
+    async fn reset_backoff(&mut self, sequence: BackoffSequence) {
+        let backoff_strategy = match sequence {
+            BackoffSequence::ImagePull => &mut self.image_pull_backoff_strategy,
+            BackoffSequence::CrashLoop => &mut self.crash_loop_backoff_strategy,
+        };
+        backoff_strategy.reset();
+    }
+    async fn record_error(&mut self) -> ThresholdTrigger {
+        self.errors += 1;
+        if self.errors > 3 {
+            self.errors = 0;
+            ThresholdTrigger::Triggered
+        } else {
+            ThresholdTrigger::Untriggered
+        }
+    }
+}
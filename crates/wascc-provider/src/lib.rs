@@ -32,9 +32,11 @@
 #![deny(missing_docs)]
 
 use async_trait::async_trait;
+use k8s_openapi::api::core::v1::PodList;
 use kubelet::container::Handle as ContainerHandle;
-use kubelet::handle::StopHandler;
-use kubelet::node::Builder;
+use kubelet::exec::{CommandOptions, ExecResult};
+use kubelet::handle::{ExecHandler, StopHandler};
+use kubelet::node::{Builder, NodeProps};
 use kubelet::pod::state::prelude::SharedState;
 use kubelet::pod::{Handle, Pod, PodKey};
 use kubelet::provider::Provider;
@@ -59,8 +61,10 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use tokio::sync::Mutex as TokioMutex;
 
+mod messaging;
 mod states;
 
+use messaging::{MessagingProvider, MESSAGING_CAPABILITY};
 use states::pod::PodState;
 
 /// The architecture that the pod targets.
@@ -98,13 +102,13 @@ pub struct ActorHandle {
 
 #[async_trait::async_trait]
 impl StopHandler for ActorHandle {
-    async fn stop(&mut self) -> anyhow::Result<()> {
+    async fn stop(&mut self) -> Result<(), ProviderError> {
         debug!("stopping wascc instance {}", self.key);
         let host = self.host.clone();
         let key = self.key.clone();
         let volumes: Vec<VolumeBinding> = self.volumes.drain(0..).collect();
         let capabilities = self.capabilities.clone();
-        tokio::task::spawn_blocking(move || {
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
             let lock = host.lock().unwrap();
             lock.remove_actor(&key)
                 .map_err(|e| anyhow::anyhow!("unable to remove actor: {:?}", e))?;
@@ -123,15 +127,24 @@ impl StopHandler for ActorHandle {
             }
             Ok(())
         })
-        .await?
+        .await??;
+        Ok(())
     }
 
-    async fn wait(&mut self) -> anyhow::Result<()> {
+    async fn wait(&mut self) -> Result<(), ProviderError> {
         // TODO: Figure out if there is a way to wait for an actor to be removed
         Ok(())
     }
 }
 
+#[async_trait::async_trait]
+impl ExecHandler for ActorHandle {
+    async fn exec(&self, _options: CommandOptions) -> Result<ExecResult, ProviderError> {
+        // Actors only respond to capability messages, so there is nothing to run commands in
+        Err(ProviderError::Unsupported)
+    }
+}
+
 /// WasccProvider provides a Kubelet runtime implementation that executes WASM binaries.
 ///
 /// Currently, this runtime uses WASCC as a host, loading the primary container as an actor.
@@ -149,6 +162,7 @@ pub struct ProviderState {
     handles: Arc<RwLock<BTreeMap<PodKey, Handle<ActorHandle, LogHandleFactory>>>>,
     store: Arc<dyn Store + Sync + Send>,
     volume_path: PathBuf,
+    allowed_host_paths: Vec<PathBuf>,
     log_path: PathBuf,
     host: Arc<Mutex<Host>>,
     port_map: Arc<TokioMutex<BTreeMap<u16, PodKey>>>,
@@ -165,11 +179,14 @@ impl GenericProviderState for ProviderState {
     fn volume_path(&self) -> PathBuf {
         self.volume_path.clone()
     }
+    fn allowed_host_paths(&self) -> Vec<PathBuf> {
+        self.allowed_host_paths.clone()
+    }
     async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
         let key = PodKey::from(pod);
         let mut handle_writer = self.handles.write().await;
         if let Some(handle) = handle_writer.get_mut(&key) {
-            handle.stop().await
+            Ok(handle.stop().await?)
         } else {
             Ok(())
         }
@@ -226,7 +243,18 @@ impl WasccProvider {
                 .lock()
                 .unwrap()
                 .add_native_capability(logging_capability)
-                .map_err(|e| anyhow::anyhow!("Failed to add log capability: {}", e))
+                .map_err(|e| anyhow::anyhow!("Failed to add log capability: {}", e))?;
+
+            info!("Loading messaging capability");
+            let messaging_capability =
+                NativeCapability::from_instance(MessagingProvider::new(), None).map_err(|e| {
+                    anyhow::anyhow!("Failed to instantiate messaging capability: {}", e)
+                })?;
+            cloned_host
+                .lock()
+                .unwrap()
+                .add_native_capability(messaging_capability)
+                .map_err(|e| anyhow::anyhow!("Failed to add messaging capability: {}", e))
         })
         .await??;
         Ok(Self {
@@ -235,6 +263,7 @@ impl WasccProvider {
                 handles: Default::default(),
                 store,
                 volume_path,
+                allowed_host_paths: config.allowed_host_paths.clone(),
                 log_path,
                 host,
                 port_map,
@@ -261,14 +290,19 @@ impl Provider for WasccProvider {
         Arc::new(RwLock::new(self.shared.clone()))
     }
 
-    async fn node(&self, builder: &mut Builder) -> anyhow::Result<()> {
+    fn node_props(&self) -> NodeProps {
+        let mut props = NodeProps::new(Self::ARCH);
+        props.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
+        props.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
+        props
+    }
+
+    async fn node(&self, builder: &mut Builder) -> Result<(), ProviderError> {
         builder.set_architecture("wasm-wasi");
-        builder.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
-        builder.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
         Ok(())
     }
 
-    async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState> {
+    async fn initialize_pod_state(&self, pod: &Pod) -> Result<Self::PodState, ProviderError> {
         Ok(PodState::new(pod))
     }
 
@@ -278,7 +312,7 @@ impl Provider for WasccProvider {
         pod_name: String,
         container_name: String,
         sender: kubelet::log::Sender,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), ProviderError> {
         let mut handles = self.shared.handles.write().await;
         let handle = handles
             .get_mut(&PodKey::new(&namespace, &pod_name))
@@ -287,6 +321,21 @@ impl Provider for WasccProvider {
             })?;
         handle.output(&container_name, sender).await
     }
+
+    async fn running_pods(&self) -> Result<PodList, ProviderError> {
+        let handles = self.shared.handles.read().await;
+        Ok(PodList {
+            items: handles
+                .values()
+                .map(|handle| handle.pod().as_kube_pod().clone())
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    async fn shutdown_pod(&self, pod: &Pod) -> Result<(), ProviderError> {
+        Ok(self.shared.stop(pod).await?)
+    }
 }
 
 impl GenericProvider for WasccProvider {
@@ -403,6 +452,14 @@ fn wascc_run(
         });
     }
 
+    if actor_caps.contains(&MESSAGING_CAPABILITY.to_owned()) {
+        capabilities.push(Capability {
+            name: MESSAGING_CAPABILITY,
+            binding: None,
+            env: env.clone(),
+        });
+    }
+
     if actor_caps.contains(&FS_CAPABILITY.to_owned()) {
         for vol in &volumes {
             info!(
@@ -0,0 +1,185 @@
+//! A node-local implementation of the `wascc:messaging` capability.
+//!
+//! Actors bound to the capability subscribe to the subjects listed in their `SUBSCRIPTION`
+//! environment variable, separated by commas. A message published by any actor on the node is
+//! delivered to every actor subscribed to its subject, which lets actors be driven by messages
+//! rather than only by HTTP requests. Messages don't leave the node.
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::RwLock;
+
+use log::{debug, warn};
+use wascc_codec::capabilities::{
+    CapabilityDescriptor, CapabilityProvider, Dispatcher, NullDispatcher, OperationDirection,
+    OP_GET_CAPABILITY_DESCRIPTOR,
+};
+use wascc_codec::core::{CapabilityConfiguration, OP_BIND_ACTOR, OP_REMOVE_ACTOR};
+use wascc_codec::messaging::{
+    BrokerMessage, RequestMessage, OP_DELIVER_MESSAGE, OP_PERFORM_REQUEST, OP_PUBLISH_MESSAGE,
+};
+use wascc_codec::{deserialize, serialize};
+
+/// The name of the Messaging capability.
+pub(crate) const MESSAGING_CAPABILITY: &str = "wascc:messaging";
+
+/// The key used to list the subjects an actor subscribes to.
+const SUBSCRIPTION_KEY: &str = "SUBSCRIPTION";
+
+/// Origin of messages coming from the waSCC host.
+const SYSTEM_ACTOR: &str = "system";
+
+/// Delivers the messages published by actors to the actors subscribed to their subject.
+pub(crate) struct MessagingProvider {
+    dispatcher: RwLock<Box<dyn Dispatcher>>,
+    /// The subjects each actor subscribes to.
+    subscriptions: RwLock<HashMap<String, Vec<String>>>,
+}
+
+impl MessagingProvider {
+    pub(crate) fn new() -> Self {
+        MessagingProvider {
+            dispatcher: RwLock::new(Box::new(NullDispatcher::new())),
+            subscriptions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn configure(
+        &self,
+        config: CapabilityConfiguration,
+    ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        let subjects = config
+            .values
+            .get(SUBSCRIPTION_KEY)
+            .map(|subjects| {
+                subjects
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|subject| !subject.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default();
+        debug!(
+            "Subscribing actor {} to subjects {:?}",
+            config.module, subjects
+        );
+        self.subscriptions
+            .write()
+            .unwrap()
+            .insert(config.module, subjects);
+        Ok(vec![])
+    }
+
+    /// The actors subscribed to the subject.
+    fn subscribers(&self, subject: &str) -> Vec<String> {
+        let subscriptions = self.subscriptions.read().unwrap();
+        let mut actors: Vec<String> = subscriptions
+            .iter()
+            .filter(|(_, subjects)| subjects.iter().any(|s| s == subject))
+            .map(|(actor, _)| actor.clone())
+            .collect();
+        actors.sort();
+        actors
+    }
+
+    /// Delivers a published message to every subscriber, other than the actor publishing it.
+    fn publish(
+        &self,
+        publisher: &str,
+        message: BrokerMessage,
+    ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        let body = serialize(&message)?;
+        let dispatcher = self.dispatcher.read().unwrap();
+        for actor in self.subscribers(&message.subject) {
+            if actor == publisher {
+                continue;
+            }
+            if let Err(e) = dispatcher.dispatch(&actor, OP_DELIVER_MESSAGE, &body) {
+                warn!(
+                    "Unable to deliver message on subject {} to actor {}: {}",
+                    message.subject, actor, e
+                );
+            }
+        }
+        Ok(vec![])
+    }
+
+    /// Delivers a request to the first subscriber, returning its reply.
+    fn request(
+        &self,
+        requester: &str,
+        request: RequestMessage,
+    ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        let actor = self
+            .subscribers(&request.subject)
+            .into_iter()
+            .find(|actor| actor != requester)
+            .ok_or_else(|| format!("No actor is subscribed to subject {}", request.subject))?;
+        let message = BrokerMessage {
+            subject: request.subject,
+            reply_to: requester.to_owned(),
+            body: request.body,
+        };
+        self.dispatcher
+            .read()
+            .unwrap()
+            .dispatch(&actor, OP_DELIVER_MESSAGE, &serialize(&message)?)
+    }
+
+    fn get_descriptor(&self) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        Ok(serialize(
+            CapabilityDescriptor::builder()
+                .id(MESSAGING_CAPABILITY)
+                .name("krustlet Messaging Provider")
+                .long_description("A node-local waSCC messaging capability provider")
+                .version(env!("CARGO_PKG_VERSION"))
+                .revision(1)
+                .with_operation(
+                    OP_PUBLISH_MESSAGE,
+                    OperationDirection::ToProvider,
+                    "Publish a message on a subject",
+                )
+                .with_operation(
+                    OP_PERFORM_REQUEST,
+                    OperationDirection::ToProvider,
+                    "Send a request on a subject and wait for the reply",
+                )
+                .with_operation(
+                    OP_DELIVER_MESSAGE,
+                    OperationDirection::ToActor,
+                    "Deliver a message on a subscribed subject",
+                )
+                .build(),
+        )?)
+    }
+}
+
+impl CapabilityProvider for MessagingProvider {
+    fn configure_dispatch(
+        &self,
+        dispatcher: Box<dyn Dispatcher>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        *self.dispatcher.write().unwrap() = dispatcher;
+        Ok(())
+    }
+
+    fn handle_call(
+        &self,
+        actor: &str,
+        op: &str,
+        msg: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Sync + Send>> {
+        match (op, actor) {
+            (OP_BIND_ACTOR, SYSTEM_ACTOR) => self.configure(deserialize(msg)?),
+            (OP_REMOVE_ACTOR, SYSTEM_ACTOR) => {
+                let config = deserialize::<CapabilityConfiguration>(msg)?;
+                self.subscriptions.write().unwrap().remove(&config.module);
+                Ok(vec![])
+            }
+            (OP_GET_CAPABILITY_DESCRIPTOR, SYSTEM_ACTOR) => self.get_descriptor(),
+            (OP_PUBLISH_MESSAGE, _) => self.publish(actor, deserialize(msg)?),
+            (OP_PERFORM_REQUEST, _) => self.request(actor, deserialize(msg)?),
+            _ => Err(format!("Unknown operation: {}", op).into()),
+        }
+    }
+}
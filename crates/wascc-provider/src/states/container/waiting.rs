@@ -7,7 +7,7 @@ use log::{debug, error, info};
 use tokio::sync::Mutex;
 
 use kubelet::container::state::prelude::*;
-use kubelet::pod::{Handle as PodHandle, Pod, PodKey};
+use kubelet::pod::{record_event, EventType, Handle as PodHandle, Pod, PodKey};
 use kubelet::provider::Provider;
 
 use crate::rand::Rng;
@@ -140,7 +140,28 @@ impl State<ContainerState> for Waiting {
             )
         };
 
-        let env = <WasccProvider as Provider>::env_vars(&container, &state.pod, &client).await;
+        let env = match <WasccProvider as Provider>::env_vars(&container, &state.pod, &client).await
+        {
+            Ok(env) => env,
+            Err(e) => {
+                let message = format!(
+                    "Pod {} container {} failed to resolve its environment: {:?}",
+                    state.pod.name(),
+                    container.name(),
+                    e
+                );
+                if let Err(e) =
+                    record_event(&client, &state.pod, EventType::Warning, "Failed", &message).await
+                {
+                    error!(
+                        "Unable to record event for pod {}: {:?}",
+                        state.pod.name(),
+                        e
+                    );
+                }
+                return Transition::next(self, Terminated::new(message, true));
+            }
+        };
         let volume_bindings: Vec<VolumeBinding> =
             if let Some(volume_mounts) = container.volume_mounts().as_ref() {
                 let run_context = state.run_context.read().await;
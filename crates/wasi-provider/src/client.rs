@@ -0,0 +1,457 @@
+//! Host functions that let WASI modules make HTTP requests and resolve names, without the full
+//! sockets shim.
+//!
+//! Modules of pods annotated with [`ALLOWED_HOSTS_ANNOTATION`] can import these functions from
+//! the `krustlet` module. The annotation lists the hosts the module may call, separated by
+//! commas. Each is a host name or IP, `*.` followed by a domain to allow any name under the
+//! domain, or `*` to allow any host.
+//!
+//! * `krustlet_http_request(method: i32, method_len: i32, url: i32, url_len: i32, headers: i32,
+//!   headers_len: i32, body: i32, body_len: i32, response: i32, response_len: i32) -> errno`
+//!   makes an HTTP or HTTPS request and waits for the whole response, writing a handle for it to
+//!   `response` and the length of its message to `response_len`. Headers are given as
+//!   `Name: value` lines separated by CRLF. Redirects are only followed to allowed hosts
+//! * `krustlet_http_read(response: i32, buf: i32, buf_len: i32) -> errno` copies the response's
+//!   message, in HTTP/1.1 form with its whole body, to `buf` and frees it, failing with `ENOBUFS`
+//!   if it doesn't fit
+//! * `krustlet_dns_resolve(name: i32, name_len: i32, buf: i32, buf_len: i32, len: i32) -> errno`
+//!   resolves the name, writing its IPs to `buf` one per line and their length to `len`. If they
+//!   don't fit it fails with `ENOBUFS`, having still written their length
+//!
+//! Each function returns a WASI errno, which is 0 on success. Hosts that aren't allowed fail with
+//! `EACCES`.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::ToSocketAddrs;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use reqwest::{redirect, Method, Url};
+use tokio::runtime::Handle;
+use tracing::debug;
+use wasmtime::{Caller, Func, Memory, Store};
+
+use crate::shim::{
+    bytes, call, write_u32, EACCES, EBADF, ECONNREFUSED, EHOSTUNREACH, EINVAL, EIO, ENOBUFS,
+    ETIMEDOUT,
+};
+
+/// The name of the import module that the functions are in.
+pub(crate) const CLIENT_MODULE: &str = "krustlet";
+
+/// The pod annotation listing the hosts that the pod's modules may call.
+pub const ALLOWED_HOSTS_ANNOTATION: &str = "wasi.krustlet.dev/allowed-hosts";
+
+/// How long a request may take, including reading the whole response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The largest response body a module is given, as the whole response is kept in memory.
+const MAX_RESPONSE_BODY: usize = 16 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 10;
+
+/// The hosts a pod's modules may call, and the client they call them with.
+#[derive(Clone)]
+pub(crate) struct AllowedHosts {
+    patterns: Arc<Vec<String>>,
+    http: reqwest::Client,
+    /// The runtime that requests are made on, as modules run outside of it
+    runtime: Handle,
+}
+
+impl AllowedHosts {
+    /// The hosts allowed by the value of a pod's annotation. This must be called from within the
+    /// runtime, which requests are then made on.
+    pub(crate) fn new(annotation: &str) -> anyhow::Result<Self> {
+        let patterns: Arc<Vec<String>> = Arc::new(
+            annotation
+                .split(',')
+                .map(|pattern| pattern.trim().to_lowercase())
+                .filter(|pattern| !pattern.is_empty())
+                .collect(),
+        );
+        let redirect_patterns = patterns.clone();
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if url_allowed(&redirect_patterns, attempt.url()) {
+                    attempt.follow()
+                } else {
+                    // The module is given the redirect, which it can't follow
+                    attempt.stop()
+                }
+            }))
+            .build()?;
+        Ok(AllowedHosts {
+            patterns,
+            http,
+            runtime: Handle::current(),
+        })
+    }
+
+    fn allows(&self, host: &str) -> bool {
+        host_allowed(&self.patterns, host)
+    }
+}
+
+fn host_allowed(patterns: &[String], host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    patterns.iter().any(|pattern| {
+        if pattern == "*" {
+            true
+        } else if let Some(domain) = pattern.strip_prefix("*.") {
+            host.len() > domain.len()
+                && host.ends_with(domain)
+                && host[..host.len() - domain.len()].ends_with('.')
+        } else {
+            *pattern == host
+        }
+    })
+}
+
+fn url_allowed(patterns: &[String], url: &Url) -> bool {
+    // IPv6 hosts are given in brackets in URLs
+    url.host_str().map_or(false, |host| {
+        host_allowed(patterns, host.trim_start_matches('[').trim_end_matches(']'))
+    })
+}
+
+/// The responses an instance of a module has been given but not yet read, by handle.
+#[derive(Default)]
+struct Responses {
+    messages: HashMap<i32, Vec<u8>>,
+    next_handle: i32,
+}
+
+/// The functions for an instance of a module.
+#[derive(Clone)]
+pub(crate) struct Client {
+    allowed: AllowedHosts,
+    responses: Rc<RefCell<Responses>>,
+}
+
+impl Client {
+    /// The functions for an instance that may call the given hosts. Responses that haven't been
+    /// read are freed once the instance is dropped.
+    pub(crate) fn new(allowed: &AllowedHosts) -> Self {
+        Client {
+            allowed: allowed.clone(),
+            responses: Rc::new(RefCell::new(Responses::default())),
+        }
+    }
+
+    /// The functions, by name, for an instance in `store`.
+    pub(crate) fn exports(&self, store: &Store) -> HashMap<&'static str, Func> {
+        let mut exports = HashMap::new();
+        let client = self.clone();
+        exports.insert(
+            "krustlet_http_request",
+            Func::wrap(
+                store,
+                move |caller: Caller<'_>,
+                      method: i32,
+                      method_len: i32,
+                      url: i32,
+                      url_len: i32,
+                      headers: i32,
+                      headers_len: i32,
+                      body: i32,
+                      body_len: i32,
+                      response: i32,
+                      response_len: i32| {
+                    call(&caller, |memory| {
+                        client.request(
+                            memory,
+                            [
+                                (method, method_len),
+                                (url, url_len),
+                                (headers, headers_len),
+                                (body, body_len),
+                            ],
+                            response,
+                            response_len,
+                        )
+                    })
+                },
+            ),
+        );
+        let client = self.clone();
+        exports.insert(
+            "krustlet_http_read",
+            Func::wrap(
+                store,
+                move |caller: Caller<'_>, response: i32, buf: i32, buf_len: i32| {
+                    call(&caller, |memory| {
+                        client.read(memory, response, buf, buf_len)
+                    })
+                },
+            ),
+        );
+        let client = self.clone();
+        exports.insert(
+            "krustlet_dns_resolve",
+            Func::wrap(
+                store,
+                move |caller: Caller<'_>,
+                      name: i32,
+                      name_len: i32,
+                      buf: i32,
+                      buf_len: i32,
+                      len: i32| {
+                    call(&caller, |memory| {
+                        client.resolve(memory, (name, name_len), buf, buf_len, len)
+                    })
+                },
+            ),
+        );
+        exports
+    }
+
+    /// Make a request given as its method, URL, headers and body in the module's memory.
+    fn request(
+        &self,
+        memory: &Memory,
+        [method, url, headers, body]: [(i32, i32); 4],
+        response: i32,
+        response_len: i32,
+    ) -> Result<(), i32> {
+        // SAFETY: the bytes are copied out before the memory is touched again
+        let method = unsafe { bytes(memory, method.0, method.1)? }.to_vec();
+        // SAFETY: as above
+        let url = unsafe { bytes(memory, url.0, url.1)? }.to_vec();
+        // SAFETY: as above
+        let headers = unsafe { bytes(memory, headers.0, headers.1)? }.to_vec();
+        // SAFETY: as above
+        let body = unsafe { bytes(memory, body.0, body.1)? }.to_vec();
+
+        let method = Method::from_bytes(&method).map_err(|_| EINVAL)?;
+        let url = std::str::from_utf8(&url)
+            .ok()
+            .and_then(|url| Url::parse(url).ok())
+            .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+            .ok_or(EINVAL)?;
+        if !url_allowed(&self.allowed.patterns, &url) {
+            debug!("Module is not allowed to make requests to {}", url);
+            return Err(EACCES);
+        }
+        let mut request = self.allowed.http.request(method, url.clone()).body(body);
+        let headers = std::str::from_utf8(&headers).map_err(|_| EINVAL)?;
+        for line in headers.split("\r\n").filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, ':');
+            let name = HeaderName::from_bytes(parts.next().unwrap_or_default().trim().as_bytes())
+                .map_err(|_| EINVAL)?;
+            let value =
+                HeaderValue::from_str(parts.next().ok_or(EINVAL)?.trim()).map_err(|_| EINVAL)?;
+            request = request.header(name, value);
+        }
+
+        // The module's thread waits for the request, which is made on the runtime
+        let made = self.allowed.runtime.spawn(async move {
+            let mut response = request.send().await?;
+            let mut message =
+                format!("{:?} {}\r\n", response.version(), response.status()).into_bytes();
+            for (name, value) in response.headers() {
+                if name == TRANSFER_ENCODING || name == CONTENT_LENGTH {
+                    continue;
+                }
+                message.extend_from_slice(name.as_str().as_bytes());
+                message.extend_from_slice(b": ");
+                message.extend_from_slice(value.as_bytes());
+                message.extend_from_slice(b"\r\n");
+            }
+            let mut body = vec![];
+            while let Some(chunk) = response.chunk().await? {
+                if body.len() + chunk.len() > MAX_RESPONSE_BODY {
+                    return Ok(None);
+                }
+                body.extend_from_slice(&chunk);
+            }
+            message.extend_from_slice(format!("content-length: {}\r\n\r\n", body.len()).as_bytes());
+            message.extend_from_slice(&body);
+            Ok::<_, reqwest::Error>(Some(message))
+        });
+        let message = match futures::executor::block_on(made) {
+            Ok(Ok(Some(message))) => message,
+            Ok(Ok(None)) => {
+                debug!("Response from {} was too large to give to module", url);
+                return Err(EIO);
+            }
+            Ok(Err(e)) => {
+                debug!("Module's request to {} failed: {}", url, e);
+                return Err(if e.is_timeout() {
+                    ETIMEDOUT
+                } else if e.is_connect() {
+                    ECONNREFUSED
+                } else {
+                    EIO
+                });
+            }
+            Err(_) => return Err(EIO),
+        };
+
+        let mut responses = self.responses.borrow_mut();
+        responses.next_handle += 1;
+        let handle = responses.next_handle;
+        write_u32(memory, response, handle as u32)?;
+        write_u32(memory, response_len, message.len() as u32)?;
+        responses.messages.insert(handle, message);
+        Ok(())
+    }
+
+    fn read(&self, memory: &Memory, response: i32, buf: i32, buf_len: i32) -> Result<(), i32> {
+        let mut responses = self.responses.borrow_mut();
+        let message = responses.messages.get(&response).ok_or(EBADF)?;
+        if (buf_len as u32 as usize) < message.len() {
+            return Err(ENOBUFS);
+        }
+        // SAFETY: the bytes are written to right away
+        unsafe { bytes(memory, buf, message.len() as i32)? }.copy_from_slice(message);
+        responses.messages.remove(&response);
+        Ok(())
+    }
+
+    fn resolve(
+        &self,
+        memory: &Memory,
+        (name, name_len): (i32, i32),
+        buf: i32,
+        buf_len: i32,
+        len: i32,
+    ) -> Result<(), i32> {
+        // SAFETY: the bytes are copied out before the memory is touched again
+        let name = std::str::from_utf8(unsafe { bytes(memory, name, name_len)? })
+            .map_err(|_| EINVAL)?
+            .to_owned();
+        if !self.allowed.allows(&name) {
+            debug!("Module is not allowed to resolve {}", name);
+            return Err(EACCES);
+        }
+        let addrs = (name.as_str(), 0).to_socket_addrs().map_err(|e| {
+            debug!("Unable to resolve {} for module: {}", name, e);
+            EHOSTUNREACH
+        })?;
+        let mut ips = vec![];
+        for addr in addrs {
+            if !ips.contains(&addr.ip()) {
+                ips.push(addr.ip());
+            }
+        }
+        let text = ips
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        write_u32(memory, len, u32::try_from(text.len()).map_err(|_| EIO)?)?;
+        if (buf_len as u32 as usize) < text.len() {
+            return Err(ENOBUFS);
+        }
+        // SAFETY: the bytes are written to right away
+        unsafe { bytes(memory, buf, text.len() as i32)? }.copy_from_slice(text.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn patterns(annotation: &[&str]) -> Vec<String> {
+        annotation
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn wildcards_only_allow_names_under_their_domain() {
+        let patterns = patterns(&["*.example.com"]);
+        assert!(host_allowed(&patterns, "api.example.com"));
+        assert!(host_allowed(&patterns, "a.b.example.com"));
+        assert!(!host_allowed(&patterns, "example.com"));
+        assert!(!host_allowed(&patterns, "evilexample.com"));
+        assert!(!host_allowed(&patterns, "example.com.evil.net"));
+    }
+
+    #[test]
+    fn hosts_are_matched_ignoring_case_and_trailing_dots() {
+        let patterns = patterns(&["example.com", "*.example.org"]);
+        assert!(host_allowed(&patterns, "example.com"));
+        assert!(host_allowed(&patterns, "example.com."));
+        assert!(host_allowed(&patterns, "EXAMPLE.com"));
+        assert!(host_allowed(&patterns, "api.example.org."));
+        assert!(!host_allowed(&patterns, "api.example.com"));
+        assert!(!host_allowed(&[], "example.com"));
+        assert!(host_allowed(&self::patterns(&["*"]), "anything.test"));
+    }
+
+    #[test]
+    fn urls_are_matched_by_host_with_ipv6_brackets_stripped() {
+        let patterns = patterns(&["::1", "example.com"]);
+        let allowed = |url: &str| url_allowed(&patterns, &Url::parse(url).unwrap());
+        assert!(allowed("http://[::1]:8080/path"));
+        assert!(allowed("https://example.com./"));
+        assert!(allowed("https://user@example.com/"));
+        assert!(!allowed("http://[::2]/"));
+        assert!(!allowed("https://example.com.evil.net/"));
+        assert!(!allowed("file:///etc/passwd"));
+    }
+
+    /// Serve requests on a new port, redirecting `/` to `location` and answering anything else
+    /// with a 200. Returns the port.
+    fn serve_redirect(location: &'static str) -> u16 {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("failed to accept request");
+                let mut lines = BufReader::new(&stream).lines();
+                let request_line = lines.next().unwrap().expect("failed to read request");
+                for line in lines {
+                    if line.expect("failed to read request").is_empty() {
+                        break;
+                    }
+                }
+                let location = location.replace("{port}", &port.to_string());
+                if request_line.starts_with("GET / ") {
+                    write!(
+                        stream,
+                        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                        location
+                    )
+                } else {
+                    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                }
+                .unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn redirects_are_only_followed_to_allowed_hosts() {
+        let hosts = AllowedHosts::new("127.0.0.1").unwrap();
+
+        let port = serve_redirect("http://127.0.0.1:{port}/followed");
+        let response = hosts
+            .http
+            .get(&format!("http://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let port = serve_redirect("http://localhost:{port}/followed");
+        let response = hosts
+            .http
+            .get(&format!("http://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::FOUND);
+    }
+}
@@ -0,0 +1,471 @@
+//! Settings for the WASI provider. They are read from command line flags and environment
+//! variables, falling back to the kubelet's configuration file, alongside the kubelet's own
+//! settings.
+//!
+//! Binaries running the provider flatten [`WasiOpts`] into their flags along with
+//! [`kubelet::config::Opts`], and build a [`WasiConfig`] from them once the kubelet's
+//! configuration has been read.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+/// Settings for the wasmtime engine that the WASI provider runs modules with, and for what the
+/// modules may do.
+#[derive(Clone, Debug)]
+pub struct WasiConfig {
+    /// How much the compiler optimizes modules.
+    pub opt_level: OptLevel,
+    /// The most 64KiB pages of memory a module may use. Containers with a lower memory limit
+    /// are held to that instead.
+    pub max_memory_pages: Option<u32>,
+    /// The most stack space, in bytes, that a module's WebAssembly code may use.
+    pub max_wasm_stack: Option<usize>,
+    /// Whether modules may use the SIMD proposal.
+    pub simd: bool,
+    /// Whether modules may use the threads proposal.
+    pub threads: bool,
+    /// Whether modules may use the bulk memory proposal.
+    pub bulk_memory: bool,
+    /// Whether the WebAssembly instructions modules run are counted, so that modules can be kept to
+    /// their CPU limits.
+    pub fuel: bool,
+    /// The destinations that modules of pods which opt in to outbound networking may connect to.
+    /// If this is empty, modules may not connect anywhere.
+    pub allowed_outbound: Vec<OutboundRule>,
+    /// Whether every module is run on threads restricted with landlock and seccomp, rather than
+    /// only those of pods whose seccomp profile annotation asks for the runtime's default.
+    pub sandbox: bool,
+}
+
+impl Default for WasiConfig {
+    fn default() -> Self {
+        WasiConfig {
+            opt_level: OptLevel::Speed,
+            max_memory_pages: None,
+            max_wasm_stack: None,
+            simd: false,
+            threads: false,
+            bulk_memory: false,
+            fuel: false,
+            allowed_outbound: vec![],
+            sandbox: false,
+        }
+    }
+}
+
+impl WasiConfig {
+    /// Builds the settings from the flags, falling back to those in the configuration file, if
+    /// there is one, and then to the defaults.
+    pub fn new(opts: WasiOpts, config_file: Option<&Path>) -> anyhow::Result<Self> {
+        let file: WasiConfigFile = match config_file {
+            Some(path) => kubelet::config::read_config_file(path)?,
+            None => WasiConfigFile::default(),
+        };
+        let opt_level = match opts.opt_level.or(file.opt_level) {
+            Some(level) => level.parse().context("invalid WASI optimization level")?,
+            None => OptLevel::Speed,
+        };
+        let allowed_outbound = opts
+            .allowed_outbound
+            .map(|rules| {
+                rules
+                    .split(',')
+                    .map(|rule| rule.trim().to_owned())
+                    .collect()
+            })
+            .or(file.allowed_outbound)
+            .unwrap_or_default()
+            .iter()
+            .map(|rule| rule.parse())
+            .collect::<anyhow::Result<_>>()
+            .context("invalid WASI outbound rule")?;
+        Ok(WasiConfig {
+            opt_level,
+            max_memory_pages: opts.max_memory_pages.or(file.max_memory_pages),
+            max_wasm_stack: opts.max_wasm_stack.or(file.max_wasm_stack),
+            simd: opts.simd.or(file.simd).unwrap_or(false),
+            threads: opts.threads.or(file.threads).unwrap_or(false),
+            bulk_memory: opts.bulk_memory.or(file.bulk_memory).unwrap_or(false),
+            fuel: opts.fuel.or(file.fuel).unwrap_or(false),
+            allowed_outbound,
+            sandbox: opts.sandbox.or(file.sandbox).unwrap_or(false),
+        })
+    }
+}
+
+/// The WASI provider's settings in the kubelet's configuration file.
+#[derive(Default, Deserialize)]
+struct WasiConfigFile {
+    #[serde(default, rename = "wasiOptLevel")]
+    opt_level: Option<String>,
+    #[serde(default, rename = "wasiMaxMemoryPages")]
+    max_memory_pages: Option<u32>,
+    #[serde(default, rename = "wasiMaxWasmStack")]
+    max_wasm_stack: Option<usize>,
+    #[serde(default, rename = "wasiSimd")]
+    simd: Option<bool>,
+    #[serde(default, rename = "wasiThreads")]
+    threads: Option<bool>,
+    #[serde(default, rename = "wasiBulkMemory")]
+    bulk_memory: Option<bool>,
+    #[serde(default, rename = "wasiFuel")]
+    fuel: Option<bool>,
+    #[serde(default, rename = "wasiAllowedOutbound")]
+    allowed_outbound: Option<Vec<String>>,
+    #[serde(default, rename = "wasiSandbox")]
+    sandbox: Option<bool>,
+}
+
+/// Command line flags for the WASI provider's settings
+#[derive(StructOpt, Clone, Debug, Default)]
+pub struct WasiOpts {
+    #[structopt(
+        long = "wasi-opt-level",
+        env = "KRUSTLET_WASI_OPT_LEVEL",
+        help = "How much WASI modules are optimized when they are compiled, either None, Speed or SpeedAndSize. Defaults to Speed"
+    )]
+    opt_level: Option<String>,
+
+    #[structopt(
+        long = "wasi-max-memory-pages",
+        env = "KRUSTLET_WASI_MAX_MEMORY_PAGES",
+        help = "The most 64KiB pages of memory a WASI module may use. Defaults to no limit other than the container's memory limit"
+    )]
+    max_memory_pages: Option<u32>,
+
+    #[structopt(
+        long = "wasi-max-wasm-stack",
+        env = "KRUSTLET_WASI_MAX_WASM_STACK",
+        help = "The most stack space, in bytes, a WASI module's WebAssembly code may use. Defaults to wasmtime's default"
+    )]
+    max_wasm_stack: Option<usize>,
+
+    #[structopt(
+        long = "wasi-simd",
+        env = "KRUSTLET_WASI_SIMD",
+        help = "Whether WASI modules may use the WebAssembly SIMD proposal. Defaults to false"
+    )]
+    simd: Option<bool>,
+
+    #[structopt(
+        long = "wasi-threads",
+        env = "KRUSTLET_WASI_THREADS",
+        help = "Whether WASI modules may use the WebAssembly threads proposal. Defaults to false"
+    )]
+    threads: Option<bool>,
+
+    #[structopt(
+        long = "wasi-bulk-memory",
+        env = "KRUSTLET_WASI_BULK_MEMORY",
+        help = "Whether WASI modules may use the WebAssembly bulk memory proposal. Defaults to false"
+    )]
+    bulk_memory: Option<bool>,
+
+    #[structopt(
+        long = "wasi-fuel",
+        env = "KRUSTLET_WASI_FUEL",
+        help = "Whether the instructions WASI modules run are counted, so that containers' CPU limits can be enforced. Defaults to false"
+    )]
+    fuel: Option<bool>,
+
+    #[structopt(
+        long = "wasi-allowed-outbound",
+        env = "KRUSTLET_WASI_ALLOWED_OUTBOUND",
+        help = "The networks, and optionally ports, that WASI modules of pods which opt in to outbound networking may connect to, such as 10.0.0.0/8:443 (comma separated). Defaults to none"
+    )]
+    allowed_outbound: Option<String>,
+
+    #[structopt(
+        long = "wasi-sandbox",
+        env = "KRUSTLET_WASI_SANDBOX",
+        help = "Whether every WASI module is run on threads restricted with landlock and seccomp, rather than only those of pods that ask for the runtime's default seccomp profile. Defaults to false"
+    )]
+    sandbox: Option<bool>,
+}
+
+/// A destination that WASI modules may connect to: a network in CIDR notation, along with the
+/// port or range of ports on it that may be connected to, if not all of them.
+///
+/// Rules are written as `10.0.0.0/8`, `10.0.0.0/8:443` or `10.0.0.0/8:8000-8080`, with IPv6
+/// networks bracketed when they have ports, as in `[fd00::/8]:443`. A single address may be
+/// given without a prefix length.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboundRule {
+    network: IpAddr,
+    prefix_len: u8,
+    /// The first and last port that may be connected to
+    ports: Option<(u16, u16)>,
+}
+
+impl OutboundRule {
+    /// Whether the rule allows connections to the address.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        let in_network = match (self.network, addr.ip()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        };
+        in_network
+            && self
+                .ports
+                .map_or(true, |(first, last)| (first..=last).contains(&addr.port()))
+    }
+}
+
+/// Whether the first `prefix_len` bits of the two addresses are the same.
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let whole_bytes = usize::from(prefix_len / 8);
+    let rest = prefix_len % 8;
+    if network[..whole_bytes] != ip[..whole_bytes] {
+        return false;
+    }
+    let mask = !(0xffu8 >> rest);
+    rest == 0 || network[whole_bytes] & mask == ip[whole_bytes] & mask
+}
+
+impl std::str::FromStr for OutboundRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // IPv6 addresses are full of colons, so their ports can only be told apart in brackets
+        let (network, ports) = if s.starts_with('[') {
+            let end = s
+                .find(']')
+                .ok_or_else(|| anyhow::anyhow!("missing ] in outbound rule {:?}", s))?;
+            let ports = match &s[end + 1..] {
+                "" => None,
+                rest if rest.starts_with(':') => Some(&rest[1..]),
+                _ => anyhow::bail!("expected a port after ] in outbound rule {:?}", s),
+            };
+            (&s[1..end], ports)
+        } else if s.matches(':').count() == 1 {
+            let mut parts = s.splitn(2, ':');
+            (parts.next().unwrap_or_default(), parts.next())
+        } else {
+            (s, None)
+        };
+
+        let mut network_parts = network.splitn(2, '/');
+        let network: IpAddr = network_parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid network in outbound rule {:?}: {}", s, e))?;
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match network_parts.next() {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_prefix_len)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "invalid prefix length in outbound rule {:?}, expected 0 to {}",
+                        s,
+                        max_prefix_len
+                    )
+                })?,
+            None => max_prefix_len,
+        };
+
+        let ports = match ports {
+            Some(ports) => {
+                let parse_port = |port: &str| {
+                    port.parse::<u16>().map_err(|e| {
+                        anyhow::anyhow!("invalid port in outbound rule {:?}: {}", s, e)
+                    })
+                };
+                let mut range = ports.splitn(2, '-');
+                let first = parse_port(range.next().unwrap_or_default())?;
+                let last = match range.next() {
+                    Some(last) => parse_port(last)?,
+                    None => first,
+                };
+                if first > last {
+                    anyhow::bail!("invalid port range in outbound rule {:?}", s);
+                }
+                Some((first, last))
+            }
+            None => None,
+        };
+
+        Ok(OutboundRule {
+            network,
+            prefix_len,
+            ports,
+        })
+    }
+}
+
+impl std::fmt::Display for OutboundRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let network = format!("{}/{}", self.network, self.prefix_len);
+        let ports = match self.ports {
+            None => return f.write_str(&network),
+            Some((first, last)) if first == last => first.to_string(),
+            Some((first, last)) => format!("{}-{}", first, last),
+        };
+        if self.network.is_ipv6() {
+            write!(f, "[{}]:{}", network, ports)
+        } else {
+            write!(f, "{}:{}", network, ports)
+        }
+    }
+}
+
+/// How much the WebAssembly compiler optimizes modules.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptLevel {
+    /// No optimizations, so modules compile as quickly as possible.
+    None,
+    /// Optimize for speed.
+    Speed,
+    /// Optimize for both speed and size.
+    SpeedAndSize,
+}
+
+impl std::str::FromStr for OptLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "None" => Ok(OptLevel::None),
+            "Speed" => Ok(OptLevel::Speed),
+            "SpeedAndSize" => Ok(OptLevel::SpeedAndSize),
+            _ => Err(anyhow::anyhow!(
+                "unknown optimization level {:?}, expected None, Speed or SpeedAndSize",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        std::io::Write::write_all(&mut file, contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn config_file_settings_are_read() {
+        let file = config_file(
+            r#"{
+            "nodeName": "krusty-node",
+            "wasiOptLevel": "SpeedAndSize",
+            "wasiMaxMemoryPages": 1024,
+            "wasiMaxWasmStack": 524288,
+            "wasiSimd": true,
+            "wasiThreads": false,
+            "wasiBulkMemory": true,
+            "wasiFuel": true,
+            "wasiAllowedOutbound": ["10.0.0.0/8:443", "[fd00::/8]:8000-8080"],
+            "wasiSandbox": true
+        }"#,
+        );
+        let config = WasiConfig::new(WasiOpts::default(), Some(file.path())).unwrap();
+        assert_eq!(config.opt_level, OptLevel::SpeedAndSize);
+        assert_eq!(config.max_memory_pages, Some(1024));
+        assert_eq!(config.max_wasm_stack, Some(524288));
+        assert!(config.simd);
+        assert!(!config.threads);
+        assert!(config.bulk_memory);
+        assert!(config.fuel);
+        assert!(config.sandbox);
+        assert_eq!(
+            config
+                .allowed_outbound
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["10.0.0.0/8:443", "[fd00::/8]:8000-8080"]
+        );
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_config_file() {
+        let file = config_file(r#"{ "wasiOptLevel": "SpeedAndSize", "wasiFuel": true }"#);
+        let opts = WasiOpts {
+            opt_level: Some("None".to_owned()),
+            fuel: Some(false),
+            allowed_outbound: Some("10.0.0.0/8, 192.168.0.1:53".to_owned()),
+            ..Default::default()
+        };
+        let config = WasiConfig::new(opts, Some(file.path())).unwrap();
+        assert_eq!(config.opt_level, OptLevel::None);
+        assert!(!config.fuel);
+        assert_eq!(config.allowed_outbound.len(), 2);
+    }
+
+    #[test]
+    fn defaults_are_respected() {
+        let config = WasiConfig::new(WasiOpts::default(), None).unwrap();
+        assert_eq!(config.opt_level, OptLevel::Speed);
+        assert_eq!(config.max_memory_pages, None);
+        assert!(!config.simd);
+        assert!(!config.fuel);
+        assert!(config.allowed_outbound.is_empty());
+        assert!(!config.sandbox);
+    }
+
+    #[test]
+    fn outbound_rules_allow_addresses_in_their_network_and_ports() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        let rule: OutboundRule = "10.1.0.0/16".parse().unwrap();
+        assert!(rule.allows(&addr("10.1.2.3:80")));
+        assert!(rule.allows(&addr("10.1.255.255:65535")));
+        assert!(!rule.allows(&addr("10.2.0.1:80")));
+        assert!(!rule.allows(&addr("[::ffff:10.1.2.3]:80")));
+
+        let rule: OutboundRule = "192.168.1.128/25:8000-8080".parse().unwrap();
+        assert!(rule.allows(&addr("192.168.1.200:8000")));
+        assert!(rule.allows(&addr("192.168.1.128:8080")));
+        assert!(!rule.allows(&addr("192.168.1.127:8000")));
+        assert!(!rule.allows(&addr("192.168.1.200:8081")));
+
+        let rule: OutboundRule = "[fd00::/8]:443".parse().unwrap();
+        assert!(rule.allows(&addr("[fd12::1]:443")));
+        assert!(!rule.allows(&addr("[fe80::1]:443")));
+        assert!(!rule.allows(&addr("[fd12::1]:80")));
+
+        let rule: OutboundRule = "203.0.113.7".parse().unwrap();
+        assert!(rule.allows(&addr("203.0.113.7:53")));
+        assert!(!rule.allows(&addr("203.0.113.8:53")));
+        assert_eq!(rule.to_string(), "203.0.113.7/32");
+
+        let rule: OutboundRule = "0.0.0.0/0:53".parse().unwrap();
+        assert!(rule.allows(&addr("8.8.8.8:53")));
+    }
+
+    #[test]
+    fn invalid_outbound_rules_are_reported() {
+        for rule in &[
+            "10.0.0.0/33",
+            "10.0.0/8",
+            "10.0.0.0/8:http",
+            "10.0.0.0/8:80-79",
+            "[fd00::/8",
+            "fd00::/8]:443",
+        ] {
+            assert!(rule.parse::<OutboundRule>().is_err(), "{} was parsed", rule);
+        }
+        let file = config_file(r#"{ "wasiAllowedOutbound": ["10.0.0.0/8:0-"] }"#);
+        let error = WasiConfig::new(WasiOpts::default(), Some(file.path()))
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("WASI outbound rule"),
+            error.to_string()
+        );
+    }
+}
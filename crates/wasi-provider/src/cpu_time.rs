@@ -0,0 +1,115 @@
+//! Measures the CPU time used by the threads that run a module's instances.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The CPU time used by the threads running a module's instances, which is shared between them
+/// and read to report the container's stats. Threads are only measured on Linux.
+#[derive(Clone, Default)]
+pub(crate) struct CpuTime {
+    inner: Arc<Mutex<CpuTimeState>>,
+}
+
+#[derive(Default)]
+struct CpuTimeState {
+    /// The CPU time used by threads that are no longer counted
+    finished: Duration,
+    /// The threads being counted, by ID, along with the CPU time each had already used when it
+    /// started being counted
+    threads: HashMap<i32, Duration>,
+}
+
+impl CpuTime {
+    /// Counts the CPU time the current thread uses until the returned guard is dropped, which must
+    /// happen on the same thread.
+    pub(crate) fn count_thread(&self) -> CountedThread {
+        let thread = thread::current().map(|(id, used)| {
+            self.inner.lock().unwrap().threads.insert(id, used);
+            id
+        });
+        CountedThread {
+            cpu_time: self.clone(),
+            thread,
+        }
+    }
+
+    /// The CPU time used so far, if it can be measured.
+    pub(crate) fn total(&self) -> Option<Duration> {
+        if cfg!(not(target_os = "linux")) {
+            return None;
+        }
+        let state = self.inner.lock().unwrap();
+        Some(
+            state
+                .threads
+                .iter()
+                // A thread that has just finished may no longer be found, in which case what it
+                // used is counted once its guard is dropped
+                .filter_map(|(id, start)| Some(thread::used(*id)?.saturating_sub(*start)))
+                .fold(state.finished, |total, used| total + used),
+        )
+    }
+}
+
+/// Counts the CPU time used by the thread that holds it until it is dropped.
+pub(crate) struct CountedThread {
+    cpu_time: CpuTime,
+    thread: Option<i32>,
+}
+
+impl Drop for CountedThread {
+    fn drop(&mut self) {
+        let id = match self.thread {
+            Some(id) => id,
+            None => return,
+        };
+        let mut state = self.cpu_time.inner.lock().unwrap();
+        if let (Some(start), Some((_, used))) = (state.threads.remove(&id), thread::current()) {
+            state.finished += used.saturating_sub(start);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod thread {
+    use std::time::Duration;
+
+    /// The ID of the current thread, along with the CPU time it has used. The time is read from
+    /// the thread's own clock, which can still be read once the thread has been sandboxed.
+    pub(super) fn current() -> Option<(i32, Duration)> {
+        // Safety: gettid takes no arguments and can't fail
+        let id = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safety: time is a valid timespec for the call to write to
+        match unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) } {
+            0 => Some((id, Duration::new(time.tv_sec as u64, time.tv_nsec as u32))),
+            _ => None,
+        }
+    }
+
+    /// The CPU time used by the thread of this process with the given ID, if it is still running.
+    /// The first field of the thread's schedstat is the time it has spent running in nanoseconds,
+    /// which is what its own clock measures too.
+    pub(super) fn used(id: i32) -> Option<Duration> {
+        let schedstat =
+            std::fs::read_to_string(format!("/proc/self/task/{}/schedstat", id)).ok()?;
+        let nanos = schedstat.split_whitespace().next()?.parse().ok()?;
+        Some(Duration::from_nanos(nanos))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod thread {
+    use std::time::Duration;
+
+    pub(super) fn current() -> Option<(i32, Duration)> {
+        None
+    }
+
+    pub(super) fn used(_id: i32) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,312 @@
+//! A proxy that serves HTTP requests made on a container's ports by handing them to its module.
+//!
+//! Each TCP port that a container declares is listened on on the pod's IPs, which are the node's
+//! unless the pod has a network namespace of its own, so that Services route traffic to the pod
+//! as they would to any other. Modules take the
+//! requests through functions they import from the `krustlet_http` module:
+//!
+//! * `accept(request: i32, len: i32) -> errno` waits for a request, writing a handle for it to
+//!   `request` and the length of its message to `len`. It gives up after a second with `EAGAIN`,
+//!   as the module can only be interrupted when the container stops once control is back in it
+//! * `read(request: i32, buf: i32, buf_len: i32) -> errno` copies the request's message, in
+//!   HTTP/1.1 form with its whole body, to `buf`, failing with `ENOBUFS` if it doesn't fit
+//! * `respond(request: i32, status: i32, headers: i32, headers_len: i32, body: i32,
+//!   body_len: i32) -> errno` answers the request, with its headers given as `Name: value` lines
+//!   separated by CRLF
+//!
+//! Each function returns a WASI errno, which is 0 on success. Only the instance of the module
+//! that is run takes requests; `accept` fails with `ENOTCONN` in instances created for exec, and
+//! in modules of containers without ports. Requests made once the module has finished are
+//! answered with `503 Service Unavailable`.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::http::request::Parts;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, StatusCode};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+use wasmtime::{Caller, Func, Memory, Store};
+
+use crate::shim::{bytes, call, write_u32, EAGAIN, EBADF, EINVAL, ENOBUFS, ENOTCONN};
+
+/// The name of the import module that the bridge's functions are in.
+pub(crate) const HTTP_MODULE: &str = "krustlet_http";
+
+/// How long accepting waits for a request before giving control back to the module.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A request waiting for the module to answer it.
+struct Exchange {
+    /// The request in HTTP/1.1 form
+    message: Vec<u8>,
+    respond: oneshot::Sender<Response<Body>>,
+}
+
+/// The requests made on a container's ports, for its module to take.
+pub(crate) struct Requests {
+    receiver: mpsc::Receiver<Exchange>,
+    /// Shuts the container's servers down once the requests are dropped
+    _shutdown: Vec<oneshot::Sender<()>>,
+}
+
+/// Listen for HTTP requests on the ports, which must not be in use, in the given network
+/// namespace or else the node's.
+pub(crate) fn serve(ports: &[u16], netns: Option<&Path>) -> anyhow::Result<Requests> {
+    let (sender, receiver) = mpsc::channel();
+    let sender = Arc::new(Mutex::new(sender));
+    let mut shutdown = vec![];
+    for port in ports.iter().copied() {
+        let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+        let builder = listen(addr, netns)
+            .and_then(|listener| Ok(hyper::Server::from_tcp(listener)?))
+            .map_err(|e| anyhow::anyhow!("unable to listen on port {}: {}", port, e))?;
+        let sender = sender.clone();
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| forward(sender.clone(), request)))
+            }
+        });
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = builder.serve(make_service).with_graceful_shutdown(async {
+            stopped.await.ok();
+        });
+        debug!("Serving HTTP requests on port {}", port);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("Unable to serve HTTP requests on port {}: {}", port, e);
+            }
+        });
+        shutdown.push(stop);
+    }
+    Ok(Requests {
+        receiver,
+        _shutdown: shutdown,
+    })
+}
+
+fn listen(addr: SocketAddr, netns: Option<&Path>) -> anyhow::Result<TcpListener> {
+    let bind = move || -> std::io::Result<TcpListener> {
+        let listener = TcpListener::bind(addr)?;
+        // The listener is handed to the runtime, which expects it not to block
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    };
+    // A socket stays in the namespace it was created in, whichever thread uses it
+    Ok(match netns {
+        Some(netns) => kubelet::network::in_namespace(netns, bind)?,
+        None => bind()?,
+    })
+}
+
+/// Hand a request to the module, and wait for its answer.
+async fn forward(
+    sender: Arc<Mutex<mpsc::Sender<Exchange>>>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                format!("unable to read request body: {}", e),
+            ))
+        }
+    };
+    let (respond, response) = oneshot::channel();
+    let exchange = Exchange {
+        message: message(&parts, &body),
+        respond,
+    };
+    if sender.lock().unwrap().send(exchange).is_err() {
+        return Ok(error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "module is not running".to_owned(),
+        ));
+    }
+    Ok(response.await.unwrap_or_else(|_| {
+        error_response(
+            StatusCode::BAD_GATEWAY,
+            "module finished without responding".to_owned(),
+        )
+    }))
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response
+}
+
+/// The request in HTTP/1.1 form. The whole body has already been read, so it is given with its
+/// length rather than in chunks.
+fn message(parts: &Parts, body: &[u8]) -> Vec<u8> {
+    let target = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |target| target.as_str());
+    let mut message = format!("{} {} HTTP/1.1\r\n", parts.method, target).into_bytes();
+    for (name, value) in parts.headers.iter() {
+        if name == header::TRANSFER_ENCODING || name == header::CONTENT_LENGTH {
+            continue;
+        }
+        message.extend_from_slice(name.as_str().as_bytes());
+        message.extend_from_slice(b": ");
+        message.extend_from_slice(value.as_bytes());
+        message.extend_from_slice(b"\r\n");
+    }
+    message.extend_from_slice(format!("content-length: {}\r\n\r\n", body.len()).as_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+/// The response a module answers with, or `EINVAL` if it isn't valid.
+fn response(status: i32, headers: &[u8], body: Vec<u8>) -> Result<Response<Body>, i32> {
+    let status = u16::try_from(status)
+        .ok()
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or(EINVAL)?;
+    let headers = std::str::from_utf8(headers).map_err(|_| EINVAL)?;
+    let mut builder = Response::builder().status(status);
+    for line in headers.split("\r\n").filter(|line| !line.is_empty()) {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().trim();
+        let value = parts.next().ok_or(EINVAL)?.trim();
+        builder = builder.header(name, value);
+    }
+    builder.body(Body::from(body)).map_err(|_| EINVAL)
+}
+
+struct Accepted {
+    /// The container's requests, unless this instance doesn't take them
+    requests: Option<Requests>,
+    /// The requests the module has accepted but not yet answered, by handle
+    exchanges: HashMap<i32, Exchange>,
+    next_handle: i32,
+}
+
+/// The bridge's functions for an instance of a module.
+#[derive(Clone)]
+pub(crate) struct Bridge {
+    accepted: Rc<RefCell<Accepted>>,
+}
+
+impl Bridge {
+    /// The bridge for an instance that takes the given requests, if any. Requests it has
+    /// accepted but not answered fail once the instance is dropped.
+    pub(crate) fn new(requests: Option<Requests>) -> Self {
+        Bridge {
+            accepted: Rc::new(RefCell::new(Accepted {
+                requests,
+                exchanges: HashMap::new(),
+                next_handle: 0,
+            })),
+        }
+    }
+
+    /// The bridge's functions, by name, for an instance in `store`.
+    pub(crate) fn exports(&self, store: &Store) -> HashMap<&'static str, Func> {
+        let mut exports = HashMap::new();
+        let bridge = self.clone();
+        exports.insert(
+            "accept",
+            Func::wrap(store, move |caller: Caller<'_>, request: i32, len: i32| {
+                call(&caller, |memory| bridge.accept(memory, request, len))
+            }),
+        );
+        let bridge = self.clone();
+        exports.insert(
+            "read",
+            Func::wrap(
+                store,
+                move |caller: Caller<'_>, request: i32, buf: i32, buf_len: i32| {
+                    call(&caller, |memory| bridge.read(memory, request, buf, buf_len))
+                },
+            ),
+        );
+        let bridge = self.clone();
+        exports.insert(
+            "respond",
+            Func::wrap(
+                store,
+                move |caller: Caller<'_>,
+                      request: i32,
+                      status: i32,
+                      headers: i32,
+                      headers_len: i32,
+                      body: i32,
+                      body_len: i32| {
+                    call(&caller, |memory| {
+                        bridge.respond(
+                            memory,
+                            request,
+                            status,
+                            (headers, headers_len),
+                            (body, body_len),
+                        )
+                    })
+                },
+            ),
+        );
+        exports
+    }
+
+    fn accept(&self, memory: &Memory, request: i32, len: i32) -> Result<(), i32> {
+        let mut accepted = self.accepted.borrow_mut();
+        let requests = accepted.requests.as_ref().ok_or(ENOTCONN)?;
+        let exchange = match requests.receiver.recv_timeout(ACCEPT_TIMEOUT) {
+            Ok(exchange) => exchange,
+            Err(RecvTimeoutError::Timeout) => return Err(EAGAIN),
+            Err(RecvTimeoutError::Disconnected) => return Err(ENOTCONN),
+        };
+        accepted.next_handle += 1;
+        let handle = accepted.next_handle;
+        write_u32(memory, request, handle as u32)?;
+        write_u32(memory, len, exchange.message.len() as u32)?;
+        accepted.exchanges.insert(handle, exchange);
+        Ok(())
+    }
+
+    fn read(&self, memory: &Memory, request: i32, buf: i32, buf_len: i32) -> Result<(), i32> {
+        let accepted = self.accepted.borrow();
+        let message = &accepted.exchanges.get(&request).ok_or(EBADF)?.message;
+        if (buf_len as u32 as usize) < message.len() {
+            return Err(ENOBUFS);
+        }
+        // SAFETY: the bytes are written to right away
+        unsafe { bytes(memory, buf, message.len() as i32)? }.copy_from_slice(message);
+        Ok(())
+    }
+
+    fn respond(
+        &self,
+        memory: &Memory,
+        request: i32,
+        status: i32,
+        (headers, headers_len): (i32, i32),
+        (body, body_len): (i32, i32),
+    ) -> Result<(), i32> {
+        if !self.accepted.borrow().exchanges.contains_key(&request) {
+            return Err(EBADF);
+        }
+        // SAFETY: the bytes are copied out before the memory is touched again
+        let headers = unsafe { bytes(memory, headers, headers_len)? }.to_vec();
+        // SAFETY: as above
+        let body = unsafe { bytes(memory, body, body_len)? }.to_vec();
+        let response = response(status, &headers, body)?;
+        if let Some(exchange) = self.accepted.borrow_mut().exchanges.remove(&request) {
+            // The client may have given up waiting, which the module needn't hear about
+            exchange.respond.send(response).ok();
+        }
+        Ok(())
+    }
+}
@@ -8,7 +8,7 @@
 //! use kubelet::{Kubelet, config::Config};
 //! use kubelet::store::oci::FileStore;
 //! use std::sync::Arc;
-//! use wasi_provider::WasiProvider;
+//! use wasi_provider::{WasiConfig, WasiProvider};
 //!
 //! async {
 //!     // Get a configuration for the Kubelet
@@ -20,7 +20,10 @@
 //!     let kubeconfig = kube::Config::infer().await.unwrap();
 //!
 //!     // Instantiate the provider type
-//!     let provider = WasiProvider::new(store, &kubelet_config, kubeconfig.clone()).await.unwrap();
+//!     let wasi_config = WasiConfig::default();
+//!     let provider = WasiProvider::new(store, &kubelet_config, &wasi_config, kubeconfig.clone())
+//!         .await
+//!         .unwrap();
 //!
 //!     // Instantiate the Kubelet
 //!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config).await.unwrap();
@@ -31,30 +34,53 @@
 
 #![deny(missing_docs)]
 
+mod client;
+mod config;
+mod cpu_time;
+mod http;
+mod module_cache;
+mod sandbox;
+mod shim;
+mod snapshot;
+mod sockets;
 mod wasi_runtime;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use kubelet::node::Builder;
+use k8s_openapi::api::core::v1::PodList;
+use kubelet::device_plugin::{ContainerDevices, DeviceManager};
+use kubelet::exec::{CommandOptions, Stdin};
+use kubelet::log::storage::{LogHandleFactory, LogStorage};
+use kubelet::node::{Builder, NodeProps};
+use kubelet::plugin_watcher::PluginRegistry;
 use kubelet::pod::state::prelude::SharedState;
-use kubelet::pod::{Handle, Pod, PodKey};
-use kubelet::provider::{Provider, ProviderError};
+use kubelet::pod::{reason, EventRecorder, EventType, Handle, Pod, PodKey};
+use kubelet::provider::{PodRuntimeStatus, Provider, ProviderError};
 use kubelet::state::common::registered::Registered;
 use kubelet::state::common::terminated::Terminated;
 use kubelet::state::common::{GenericProvider, GenericProviderState};
+use kubelet::stats::{ContainerStats, CpuStats, MemoryStats, PodReference, PodStats};
 use kubelet::store::Store;
 use kubelet::volume::Ref;
+use module_cache::ModuleCache;
 use tokio::sync::RwLock;
-use wasi_runtime::Runtime;
+use tracing::{info, warn};
+use wasi_runtime::{Engine, Runtime, Usage};
+
+pub use client::ALLOWED_HOSTS_ANNOTATION;
+pub use config::{OptLevel, OutboundRule, WasiConfig, WasiOpts};
+pub use sockets::OUTBOUND_NETWORK_ANNOTATION;
 
 mod states;
 use states::pod::PodState;
 
 const TARGET_WASM32_WASI: &str = "wasm32-wasi";
-const LOG_DIR_NAME: &str = "wasi-logs";
+const MODULE_CACHE_DIR_NAME: &str = "wasi-compiled-modules";
 const VOLUME_DIR: &str = "volumes";
 
 /// WasiProvider provides a Kubelet runtime implementation that executes WASM
@@ -64,16 +90,33 @@ pub struct WasiProvider {
     shared: ProviderState,
 }
 
-type PodHandleMap = Arc<RwLock<HashMap<PodKey, Arc<Handle<Runtime, wasi_runtime::HandleFactory>>>>>;
+type PodHandleMap = Arc<RwLock<HashMap<PodKey, Arc<Handle<Runtime, LogHandleFactory>>>>>;
+
+/// What the module each of a pod's containers runs is using, by container name.
+type UsageMap = Arc<RwLock<HashMap<PodKey, HashMap<String, Usage>>>>;
 
 /// Provider-level state shared between all pods
 #[derive(Clone)]
 pub struct ProviderState {
     handles: PodHandleMap,
+    usages: UsageMap,
     store: Arc<dyn Store + Sync + Send>,
-    log_path: PathBuf,
+    log_storage: Arc<dyn LogStorage>,
+    events: EventRecorder,
+    module_cache: ModuleCache,
+    engine: Engine,
+    max_memory_pages: Option<u32>,
+    /// The destinations that modules of pods which allow outbound networking may connect to
+    allowed_outbound: Vec<OutboundRule>,
+    /// Whether every module is run in the sandbox, rather than only those of pods that ask for it
+    sandbox: bool,
+    /// The kubelet's data directory, which pods' network namespaces are kept in
+    data_dir: PathBuf,
     kubeconfig: kube::Config,
     volume_path: PathBuf,
+    allowed_host_paths: Vec<PathBuf>,
+    plugin_registry: Arc<PluginRegistry>,
+    device_manager: Arc<DeviceManager>,
 }
 
 #[async_trait]
@@ -87,35 +130,91 @@ impl GenericProviderState for ProviderState {
     fn volume_path(&self) -> PathBuf {
         self.volume_path.clone()
     }
+    fn allowed_host_paths(&self) -> Vec<PathBuf> {
+        self.allowed_host_paths.clone()
+    }
+    fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
+        Some(self.plugin_registry.clone())
+    }
+    fn event_recorder(&self) -> Option<EventRecorder> {
+        Some(self.events.clone())
+    }
+    fn device_manager(&self) -> Option<Arc<DeviceManager>> {
+        Some(self.device_manager.clone())
+    }
     async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
+        // Stopping can take as long as the pod's grace period, so don't hold on to the map
         let key = PodKey::from(pod);
-        let mut handle_writer = self.handles.write().await;
-        if let Some(handle) = handle_writer.get_mut(&key) {
-            handle.stop().await
-        } else {
-            Ok(())
+        let handle = self.handles.read().await.get(&key).cloned();
+        match handle {
+            Some(handle) => {
+                for container in pod.containers() {
+                    let message = format!("Stopping container {}", container.name());
+                    if let Err(e) = self
+                        .events
+                        .record(
+                            pod,
+                            Some(container.name()),
+                            EventType::Normal,
+                            reason::KILLING,
+                            &message,
+                        )
+                        .await
+                    {
+                        warn!(
+                            "Unable to record Killing event for pod {}: {:?}",
+                            pod.name(),
+                            e
+                        );
+                    }
+                }
+                Ok(handle.stop().await?)
+            }
+            None => Ok(()),
         }
     }
 }
 
 impl WasiProvider {
-    /// Create a new wasi provider from a module store and a kubelet config
+    /// Create a new wasi provider from a module store, a kubelet config and the provider's own
+    /// config
     pub async fn new(
         store: Arc<dyn Store + Sync + Send>,
         config: &kubelet::config::Config,
+        wasi_config: &WasiConfig,
         kubeconfig: kube::Config,
     ) -> anyhow::Result<Self> {
-        let log_path = config.data_dir.join(LOG_DIR_NAME);
         let volume_path = config.data_dir.join(VOLUME_DIR);
-        tokio::fs::create_dir_all(&log_path).await?;
         tokio::fs::create_dir_all(&volume_path).await?;
+        let device_manager = Arc::new(DeviceManager::new(
+            &config.device_plugins_dir,
+            kube::Client::new(kubeconfig.clone()),
+            &config.node_name,
+        ));
+        // Device plugins are handed to the device manager whichever directory they register in
+        let plugin_registry =
+            PluginRegistry::new(&config.plugins_dir).with_device_manager(device_manager.clone());
         Ok(Self {
             shared: ProviderState {
                 handles: Default::default(),
+                usages: Default::default(),
                 store,
-                log_path,
+                log_storage: kubelet::log::storage::from_config(config)?,
+                events: EventRecorder::new(
+                    kube::Client::new(kubeconfig.clone()),
+                    &config.node_name,
+                ),
+                module_cache: ModuleCache::new(config.data_dir.join(MODULE_CACHE_DIR_NAME)),
+                engine: Engine::new(wasi_config, config.max_pods)?,
+                max_memory_pages: wasi_config.max_memory_pages,
+                allowed_outbound: wasi_config.allowed_outbound.clone(),
+                sandbox: wasi_config.sandbox,
+                data_dir: config.data_dir.clone(),
                 volume_path,
+                allowed_host_paths: config.allowed_host_paths.clone(),
                 kubeconfig,
+                plugin_registry: Arc::new(plugin_registry),
+                device_manager,
             },
         })
     }
@@ -124,6 +223,11 @@ impl WasiProvider {
 struct ModuleRunContext {
     modules: HashMap<String, Vec<u8>>,
     volumes: HashMap<String, Ref>,
+    /// What each container needs in order to use the devices allocated to it, by container name
+    devices: HashMap<String, ContainerDevices>,
+    /// The containers whose image was changed while they ran, which are restarted with their new
+    /// module whatever the pod's restart policy
+    updated: HashSet<String>,
 }
 
 #[async_trait::async_trait]
@@ -139,14 +243,27 @@ impl Provider for WasiProvider {
         Arc::new(RwLock::new(self.shared.clone()))
     }
 
-    async fn node(&self, builder: &mut Builder) -> anyhow::Result<()> {
+    fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
+        Some(self.shared.plugin_registry.clone())
+    }
+
+    fn device_manager(&self) -> Option<Arc<DeviceManager>> {
+        Some(self.shared.device_manager.clone())
+    }
+
+    fn node_props(&self) -> NodeProps {
+        let mut props = NodeProps::new(Self::ARCH);
+        props.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
+        props.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
+        props
+    }
+
+    async fn node(&self, builder: &mut Builder) -> Result<(), ProviderError> {
         builder.set_architecture("wasm-wasi");
-        builder.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
-        builder.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
         Ok(())
     }
 
-    async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState> {
+    async fn initialize_pod_state(&self, pod: &Pod) -> Result<Self::PodState, ProviderError> {
         Ok(PodState::new(pod))
     }
 
@@ -156,7 +273,7 @@ impl Provider for WasiProvider {
         pod_name: String,
         container_name: String,
         sender: kubelet::log::Sender,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), ProviderError> {
         let mut handles = self.shared.handles.write().await;
         let handle = handles
             .get_mut(&PodKey::new(&namespace, &pod_name))
@@ -165,6 +282,195 @@ impl Provider for WasiProvider {
             })?;
         handle.output(&container_name, sender).await
     }
+
+    async fn exec(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        options: CommandOptions,
+        stdin: Stdin,
+        sender: kubelet::exec::Sender,
+    ) -> Result<(), ProviderError> {
+        // Hold on to the pod handle rather than the whole map, as exec sessions can be long lived
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned()
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.exec(&container_name, options, stdin, sender).await
+    }
+
+    async fn attach(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        stdin: Stdin,
+        sender: kubelet::exec::Sender,
+    ) -> Result<(), ProviderError> {
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned()
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.attach(&container_name, stdin, sender).await
+    }
+
+    async fn checkpoint(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+    ) -> Result<PathBuf, ProviderError> {
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned()
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        let dir = snapshot::new_dir(
+            &self.shared.data_dir,
+            &namespace,
+            &pod_name,
+            &container_name,
+        );
+        handle.checkpoint(&container_name, &dir).await?;
+        info!(
+            "Checkpointed container {} of pod {} to {}",
+            container_name,
+            pod_name,
+            dir.display()
+        );
+        Ok(dir)
+    }
+
+    async fn pod_status(
+        &self,
+        namespace: String,
+        pod_name: String,
+    ) -> Result<PodRuntimeStatus, ProviderError> {
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned();
+        match handle {
+            Some(handle) => Ok(handle.runtime_status().await),
+            None => Ok(PodRuntimeStatus::Unknown),
+        }
+    }
+
+    async fn running_pods(&self) -> Result<PodList, ProviderError> {
+        let handles = self.shared.handles.read().await;
+        Ok(PodList {
+            items: handles
+                .values()
+                .map(|handle| handle.pod().as_kube_pod().clone())
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    async fn stats(&self) -> Result<Vec<PodStats>, ProviderError> {
+        let now = chrono::Utc::now();
+        let usages = self.shared.usages.read().await;
+        let stats = self
+            .shared
+            .handles
+            .read()
+            .await
+            .iter()
+            .map(|(key, handle)| {
+                let pod = handle.pod();
+                let usages = usages.get(key);
+                let containers: Vec<ContainerStats> = usages
+                    .into_iter()
+                    .flatten()
+                    .map(|(name, usage)| ContainerStats {
+                        name: name.clone(),
+                        cpu: cpu_stats(now, usage.cpu_time()),
+                        memory: Some(memory_stats(now, usage.memory(), usage.memory_limit())),
+                        ..Default::default()
+                    })
+                    .collect();
+                // The pod's usage is only known if it is known for all of its containers
+                let cpu_time = usages
+                    .into_iter()
+                    .flat_map(HashMap::values)
+                    .map(Usage::cpu_time)
+                    .sum::<Option<Duration>>();
+                let memory = usages
+                    .into_iter()
+                    .flat_map(HashMap::values)
+                    .map(Usage::memory)
+                    .sum();
+                PodStats {
+                    pod_ref: PodReference {
+                        name: pod.name().to_owned(),
+                        namespace: pod.namespace().to_owned(),
+                        uid: pod.as_kube_pod().metadata.uid.clone().unwrap_or_default(),
+                    },
+                    containers,
+                    cpu: cpu_stats(now, cpu_time),
+                    memory: Some(memory_stats(now, memory, None)),
+                    // What the runtime itself uses to run the pod's modules isn't measured, so no
+                    // runtime overhead is reported
+                    ..Default::default()
+                }
+            })
+            .collect();
+        Ok(stats)
+    }
+
+    async fn shutdown_pod(&self, pod: &Pod) -> Result<(), ProviderError> {
+        Ok(self.shared.stop(pod).await?)
+    }
+
+    async fn remove_orphaned_pod(&self, pod: &Pod) -> Result<(), ProviderError> {
+        let log_storage = self.shared.log_storage.clone();
+        let orphan = pod.clone();
+        tokio::task::spawn_blocking(move || log_storage.remove_pod(&orphan)).await??;
+        kubelet::container::termination::remove_pod(&self.shared.data_dir, pod).await?;
+        Ok(())
+    }
+}
+
+/// The CPU stats of modules that have used `cpu_time`, if it could be measured.
+fn cpu_stats(time: chrono::DateTime<chrono::Utc>, cpu_time: Option<Duration>) -> Option<CpuStats> {
+    Some(CpuStats {
+        time,
+        usage_nano_cores: None,
+        usage_core_nano_seconds: Some(u64::try_from(cpu_time?.as_nanos()).unwrap_or(u64::MAX)),
+    })
+}
+
+/// The memory stats of modules using `used` bytes of memory, out of the `limit` they may use if
+/// they have one. None of the memory modules use can be reclaimed, so all of it is in their
+/// working set.
+fn memory_stats(time: chrono::DateTime<chrono::Utc>, used: u64, limit: Option<u64>) -> MemoryStats {
+    MemoryStats {
+        time,
+        available_bytes: limit.map(|limit| limit.saturating_sub(used)),
+        usage_bytes: Some(used),
+        working_set_bytes: Some(used),
+    }
 }
 
 impl GenericProvider for WasiProvider {
@@ -0,0 +1,83 @@
+//! A cache of compiled modules, so that a module only has to be compiled the first time it is run
+//! on a node rather than every time a pod using it starts.
+//!
+//! Compiled modules are kept on disk, named by the digest of the module they were compiled from,
+//! the way the engine was configured and the version of wasmtime that compiled them. A compiled
+//! module that can't be loaded, such as one written by another version of wasmtime, is compiled
+//! again and replaced.
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+/// The version of wasmtime that modules are compiled with. Compiled modules can only be loaded
+/// by the version that compiled them, so this should be kept in step with the dependency.
+const WASMTIME_VERSION: &str = "0.27";
+
+/// The directory compiled modules are kept in.
+#[derive(Clone, Debug)]
+pub struct ModuleCache {
+    dir: PathBuf,
+}
+
+impl ModuleCache {
+    /// Create a cache that keeps compiled modules in the given directory
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        ModuleCache {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    /// Load the compiled form of a module, compiling it and caching the result if it hasn't been
+    /// compiled before. `engine_kind` names how the engine was configured, as modules compiled
+    /// for one configuration can't be used with another.
+    ///
+    /// This blocks, so it should be called from a blocking task. Failing to use the cache isn't an
+    /// error, as the module can always be compiled.
+    pub fn load_or_compile(
+        &self,
+        engine: &wasmtime::Engine,
+        module_data: &[u8],
+        engine_kind: &str,
+    ) -> anyhow::Result<wasmtime::Module> {
+        let path = self.dir.join(format!(
+            "{:x}-{}-{}.cwasm",
+            Sha256::digest(module_data),
+            engine_kind,
+            WASMTIME_VERSION
+        ));
+        match std::fs::read(&path) {
+            Ok(compiled) => match wasmtime::Module::deserialize(engine, &compiled) {
+                Ok(module) => {
+                    debug!("Loaded compiled module from {:?}", path);
+                    return Ok(module);
+                }
+                Err(e) => warn!(
+                    "Unable to load compiled module {:?}, compiling it again: {:?}",
+                    path, e
+                ),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => warn!("Unable to read compiled module {:?}: {:?}", path, e),
+        }
+
+        let module = wasmtime::Module::new(engine, module_data)?;
+        if let Err(e) = self.store(&path, &module) {
+            warn!("Unable to cache compiled module at {:?}: {:?}", path, e);
+        }
+        Ok(module)
+    }
+
+    fn store(&self, path: &Path, module: &wasmtime::Module) -> anyhow::Result<()> {
+        let compiled = module.serialize()?;
+        std::fs::create_dir_all(&self.dir)?;
+        // Write the module somewhere else first, so that a module is never read half written,
+        // even if several pods using it start at once
+        let mut temp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        temp.write_all(&compiled)?;
+        temp.persist(path)?;
+        debug!("Cached compiled module at {:?}", path);
+        Ok(())
+    }
+}
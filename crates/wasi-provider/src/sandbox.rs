@@ -0,0 +1,392 @@
+//! Host hardening for the threads that run modules. WASI already keeps a module to the directories
+//! it is given, so this only limits what a module that escapes wasmtime through a bug in it can
+//! do: landlock keeps a sandboxed thread to the module's own directories on the host, and seccomp
+//! refuses it the system calls a module never needs, such as `execve`, `mount` and `ptrace`.
+//!
+//! Both apply to the thread that sets them up and can't be undone, so sandboxed modules are run on
+//! threads of their own. Landlock needs Linux 5.13 or later, and on older kernels modules are
+//! sandboxed with seccomp alone. Every module is sandboxed if the node's `wasiSandbox` setting is
+//! on, and a pod's containers are sandboxed if their seccomp profile annotation asks for the
+//! runtime's default.
+use std::path::PathBuf;
+
+use kubelet::pod::Pod;
+
+/// The annotation setting the seccomp profile of all of a pod's containers.
+const POD_SECCOMP_ANNOTATION: &str = "seccomp.security.alpha.kubernetes.io/pod";
+/// The prefix of the annotation setting the seccomp profile of one of a pod's containers. The
+/// container's name follows the prefix.
+const CONTAINER_SECCOMP_ANNOTATION_PREFIX: &str = "container.seccomp.security.alpha.kubernetes.io/";
+/// The profiles that ask for the runtime's default, which is the sandbox.
+const DEFAULT_PROFILES: &[&str] = &["runtime/default", "docker/default"];
+
+/// The files and directories read to resolve names, which modules that may use the network need.
+const RESOLVER_PATHS: &[&str] = &[
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/etc/nsswitch.conf",
+    "/etc/host.conf",
+    "/etc/gai.conf",
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+];
+
+/// Whether the pod asks for its container to be sandboxed. The container's own annotation takes
+/// precedence over the pod's.
+pub(crate) fn requested(pod: &Pod, container: &str) -> bool {
+    let annotation = format!("{}{}", CONTAINER_SECCOMP_ANNOTATION_PREFIX, container);
+    pod.get_annotation(&annotation)
+        .or_else(|| pod.get_annotation(POD_SECCOMP_ANNOTATION))
+        .map_or(false, |profile| DEFAULT_PROFILES.contains(&profile))
+}
+
+/// How a sandboxed thread may use a path on the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// The paths on the host that a sandboxed module's threads may reach.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Sandbox {
+    paths: Vec<(PathBuf, Access)>,
+}
+
+impl Sandbox {
+    pub(crate) fn new() -> Self {
+        Sandbox::default()
+    }
+
+    /// Let the sandboxed threads use the given path, and what is beneath it.
+    pub(crate) fn allow(mut self, path: impl Into<PathBuf>, access: Access) -> Self {
+        self.paths.push((path.into(), access));
+        self
+    }
+
+    /// Let the sandboxed threads read what the host's resolver needs to look names up.
+    pub(crate) fn allow_name_resolution(self) -> Self {
+        RESOLVER_PATHS
+            .iter()
+            .fold(self, |sandbox, path| sandbox.allow(path, Access::ReadOnly))
+    }
+
+    /// Restrict the calling thread to the sandbox. This can't be undone, so it must only be called
+    /// from a thread that does nothing but run the module.
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub(crate) fn apply(&self) -> anyhow::Result<()> {
+        // Both landlock and seccomp need the thread to give up gaining privileges first
+        let no_new_privs = unsafe {
+            libc::prctl(
+                libc::PR_SET_NO_NEW_PRIVS,
+                1 as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+            )
+        };
+        if no_new_privs != 0 {
+            anyhow::bail!(
+                "unable to stop the thread gaining privileges: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        if !landlock::restrict(&self.paths)? {
+            static WARN: std::sync::Once = std::sync::Once::new();
+            WARN.call_once(|| {
+                tracing::warn!(
+                    "Landlock is not supported by this kernel, so modules are sandboxed with \
+                     seccomp alone"
+                )
+            });
+        }
+        seccomp::restrict()
+    }
+
+    #[cfg(not(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    )))]
+    pub(crate) fn apply(&self) -> anyhow::Result<()> {
+        anyhow::bail!("modules can only be sandboxed on x86_64 and aarch64 Linux")
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod landlock {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::RawFd;
+    use std::path::{Path, PathBuf};
+
+    use tracing::debug;
+
+    use super::Access;
+
+    // Landlock's system calls have the same numbers on every architecture
+    const SYS_CREATE_RULESET: libc::c_long = 444;
+    const SYS_ADD_RULE: libc::c_long = 445;
+    const SYS_RESTRICT_SELF: libc::c_long = 446;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    const REMOVE_DIR: u64 = 1 << 4;
+    const REMOVE_FILE: u64 = 1 << 5;
+    const MAKE_DIR: u64 = 1 << 7;
+    const MAKE_REG: u64 = 1 << 8;
+    const MAKE_SYM: u64 = 1 << 12;
+    /// Every access right of the first version of landlock, all of which are restricted
+    const ALL: u64 = (1 << 13) - 1;
+    /// The access rights that apply to files, rather than directories
+    const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE;
+    const READ_ONLY: u64 = READ_FILE | READ_DIR;
+    /// Read-write paths still can't have devices, sockets or pipes made in them
+    const READ_WRITE: u64 =
+        READ_ONLY | WRITE_FILE | REMOVE_DIR | REMOVE_FILE | MAKE_DIR | MAKE_REG | MAKE_SYM;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Closes a file descriptor when it is dropped.
+    struct Fd(RawFd);
+
+    impl Drop for Fd {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.0) };
+        }
+    }
+
+    /// Restrict the calling thread's access to the file system to the given paths, returning
+    /// false if the kernel doesn't support landlock.
+    pub(super) fn restrict(paths: &[(PathBuf, Access)]) -> anyhow::Result<bool> {
+        let attr = RulesetAttr {
+            handled_access_fs: ALL,
+        };
+        let ruleset = unsafe {
+            libc::syscall(
+                SYS_CREATE_RULESET,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            let e = std::io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Ok(false),
+                _ => Err(anyhow::anyhow!("unable to create landlock ruleset: {}", e)),
+            };
+        }
+        let ruleset = Fd(ruleset as RawFd);
+        for (path, access) in paths {
+            add_rule(&ruleset, path, *access)?;
+        }
+        if unsafe { libc::syscall(SYS_RESTRICT_SELF, ruleset.0, 0) } != 0 {
+            anyhow::bail!(
+                "unable to apply landlock ruleset: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(true)
+    }
+
+    fn add_rule(ruleset: &Fd, path: &Path, access: Access) -> anyhow::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            let e = std::io::Error::last_os_error();
+            // Paths that this host doesn't have, such as /lib64 on some distributions, have
+            // nothing beneath them to allow
+            if e.kind() == std::io::ErrorKind::NotFound {
+                debug!(
+                    "Not allowing {} in sandbox, as it doesn't exist",
+                    path.display()
+                );
+                return Ok(());
+            }
+            anyhow::bail!("unable to open {} for sandbox: {}", path.display(), e);
+        }
+        let fd = Fd(fd);
+        let mut allowed_access = match access {
+            Access::ReadOnly => READ_ONLY,
+            Access::ReadWrite => READ_WRITE,
+        };
+        if !path.is_dir() {
+            allowed_access &= FILE;
+        }
+        let attr = PathBeneathAttr {
+            allowed_access,
+            parent_fd: fd.0,
+        };
+        let added = unsafe {
+            libc::syscall(
+                SYS_ADD_RULE,
+                ruleset.0,
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if added != 0 {
+            anyhow::bail!(
+                "unable to allow {} in sandbox: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    /// The system calls that sandboxed threads are refused. Modules only ever need the system
+    /// calls wasmtime and WASI make on their behalf, none of which are these.
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_kcmp,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_fanotify_init,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+        libc::SYS_syslog,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_personality,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_sethostname,
+        libc::SYS_setdomainname,
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    /// The bit set in the numbers of x32 system calls, which share x86_64's architecture
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    /// Where the system call's number and architecture are in the data the filter is run on
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    const BPF_JMP_JGE_K: u16 = 0x35;
+    const BPF_RET_K: u16 = 0x06;
+
+    #[repr(C)]
+    struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: libc::c_ushort,
+        filter: *const SockFilter,
+    }
+
+    /// Refuse the calling thread the denied system calls, which fail with `EPERM`.
+    pub(super) fn restrict() -> anyhow::Result<()> {
+        let filter = program();
+        let prog = SockFprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_ptr(),
+        };
+        let restricted = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                SECCOMP_MODE_FILTER,
+                &prog as *const SockFprog,
+            )
+        };
+        if restricted != 0 {
+            anyhow::bail!(
+                "unable to apply seccomp filter: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    fn program() -> Vec<SockFilter> {
+        let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let mut filter = vec![
+            // System calls made as another architecture are refused, as their numbers mean
+            // something else
+            statement(BPF_LD_W_ABS, ARCH_OFFSET),
+            jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+            statement(BPF_RET_K, deny),
+            statement(BPF_LD_W_ABS, NR_OFFSET),
+            jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+            statement(BPF_RET_K, deny),
+        ];
+        for nr in DENIED {
+            filter.push(jump(BPF_JMP_JEQ_K, *nr as u32, 0, 1));
+            filter.push(statement(BPF_RET_K, deny));
+        }
+        filter.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+        filter
+    }
+
+    fn statement(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+}
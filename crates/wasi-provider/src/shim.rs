@@ -0,0 +1,55 @@
+//! Helpers for the host-function shims that modules import, which pass data through the
+//! module's memory and report failures as WASI errnos.
+use wasmtime::{Caller, Extern, Memory, Trap};
+
+// The WASI errnos that the shims' functions return
+pub(crate) const ESUCCESS: i32 = 0;
+pub(crate) const EACCES: i32 = 2;
+pub(crate) const EAGAIN: i32 = 6;
+pub(crate) const EBADF: i32 = 8;
+pub(crate) const ECONNREFUSED: i32 = 14;
+pub(crate) const ECONNRESET: i32 = 15;
+pub(crate) const EFAULT: i32 = 21;
+pub(crate) const EHOSTUNREACH: i32 = 23;
+pub(crate) const EINVAL: i32 = 28;
+pub(crate) const EIO: i32 = 29;
+pub(crate) const ENOBUFS: i32 = 42;
+pub(crate) const ENOTCONN: i32 = 53;
+pub(crate) const ETIMEDOUT: i32 = 73;
+
+/// Runs one of a shim's functions against the calling module's memory, returning its errno.
+pub(crate) fn call(
+    caller: &Caller<'_>,
+    f: impl FnOnce(&Memory) -> Result<(), i32>,
+) -> Result<i32, Trap> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("module has no memory export for the host to use"))?;
+    Ok(match f(&memory) {
+        Ok(()) => ESUCCESS,
+        Err(errno) => errno,
+    })
+}
+
+/// The `len` bytes of the module's memory at `ptr`, or `EFAULT` if they are out of bounds.
+///
+/// # Safety
+///
+/// The module's memory must not be used in any other way, such as by growing it, while the
+/// bytes are borrowed.
+pub(crate) unsafe fn bytes(memory: &Memory, ptr: i32, len: i32) -> Result<&mut [u8], i32> {
+    let start = ptr as u32 as usize;
+    let end = start.checked_add(len as u32 as usize).ok_or(EFAULT)?;
+    memory
+        .data_unchecked_mut()
+        .get_mut(start..end)
+        .ok_or(EFAULT)
+}
+
+/// Writes a little-endian `u32`, as WebAssembly stores them, to the module's memory at `ptr`.
+pub(crate) fn write_u32(memory: &Memory, ptr: i32, value: u32) -> Result<(), i32> {
+    // SAFETY: the bytes are written to right away
+    unsafe { bytes(memory, ptr, 4)? }.copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
@@ -0,0 +1,297 @@
+//! Experimental snapshots of running modules, which the kubelet's `/checkpoint` API takes and
+//! which containers can be restored from, on the node that took them or on another one.
+//!
+//! A snapshot holds the contents of the memories a module exports and the values of its exported
+//! mutable globals, along with the digest of the module it was taken of, as it can only be
+//! restored into the same module. WebAssembly can't be paused part way through a function, so a
+//! module is interrupted to be snapshotted, which terminates its container. Only modules with a
+//! `_start` export can be snapshotted, as reactors are only ever running during exec.
+//!
+//! Snapshots are kept under the kubelet's data directory until they are removed by hand, and a
+//! container is restored from one by naming it, relative to that directory, in the container's
+//! [`RESTORE_ANNOTATION_PREFIX`] annotation. A restored module carries on from its
+//! `krustlet_resume` export if it has one, and runs `_start` again otherwise.
+use std::convert::TryFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+use tracing::debug;
+use wasmtime::{Extern, Mutability, Val};
+
+use kubelet::pod::Pod;
+
+/// The directory under the kubelet's data directory that snapshots are kept in.
+const SNAPSHOT_DIR: &str = "snapshots";
+/// The file in a snapshot that describes it, which is written last.
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// The prefix of the annotation naming the snapshot a container is restored from. The container's
+/// name follows the prefix.
+pub(crate) const RESTORE_ANNOTATION_PREFIX: &str = "snapshot.wasi.krustlet.dev/";
+/// The export a restored module carries on from, if it has one.
+pub(crate) const RESUME_EXPORT: &str = "krustlet_resume";
+
+/// What is kept of a module in a snapshot.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    /// The digest of the module the snapshot was taken of
+    module_digest: String,
+    /// When the snapshot was taken
+    created_at: DateTime<Utc>,
+    memories: Vec<MemorySnapshot>,
+    globals: Vec<GlobalSnapshot>,
+}
+
+/// An exported memory, whose contents are kept in a file of their own.
+#[derive(Deserialize, Serialize)]
+struct MemorySnapshot {
+    name: String,
+    /// The size of the memory, in pages
+    pages: u32,
+    file: String,
+}
+
+/// An exported mutable global.
+#[derive(Deserialize, Serialize)]
+struct GlobalSnapshot {
+    name: String,
+    value: Value,
+}
+
+/// The value of a global. Floats are kept as their bits, so that they are restored exactly.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+enum Value {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+impl TryFrom<Val> for Value {
+    type Error = anyhow::Error;
+
+    fn try_from(val: Val) -> anyhow::Result<Self> {
+        match val {
+            Val::I32(v) => Ok(Value::I32(v)),
+            Val::I64(v) => Ok(Value::I64(v)),
+            Val::F32(bits) => Ok(Value::F32(bits)),
+            Val::F64(bits) => Ok(Value::F64(bits)),
+            other => anyhow::bail!("globals of type {:?} can't be snapshotted", other.ty()),
+        }
+    }
+}
+
+impl From<&Value> for Val {
+    fn from(value: &Value) -> Self {
+        match *value {
+            Value::I32(v) => Val::I32(v),
+            Value::I64(v) => Val::I64(v),
+            Value::F32(bits) => Val::F32(bits),
+            Value::F64(bits) => Val::F64(bits),
+        }
+    }
+}
+
+/// The directory that snapshots are kept in, for a kubelet with the given data directory.
+pub(crate) fn root(data_dir: &Path) -> PathBuf {
+    data_dir.join(SNAPSHOT_DIR)
+}
+
+/// A new directory for a snapshot of the pod's container, for a kubelet with the given data
+/// directory.
+pub(crate) fn new_dir(data_dir: &Path, namespace: &str, pod: &str, container: &str) -> PathBuf {
+    root(data_dir)
+        .join(format!("{}_{}", namespace, pod))
+        .join(format!(
+            "{}-{}",
+            container,
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        ))
+}
+
+/// The snapshot the pod's container is to be restored from, if its annotation names one. Only
+/// snapshots kept in the kubelet's data directory can be named.
+pub(crate) fn restore_dir(
+    data_dir: &Path,
+    pod: &Pod,
+    container: &str,
+) -> anyhow::Result<Option<PathBuf>> {
+    let annotation = format!("{}{}", RESTORE_ANNOTATION_PREFIX, container);
+    let name = match pod.get_annotation(&annotation) {
+        Some(name) => Path::new(name),
+        None => return Ok(None),
+    };
+    if name.as_os_str().is_empty() || !name.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        anyhow::bail!(
+            "annotation {} must name a snapshot relative to the snapshot directory, not {:?}",
+            annotation,
+            name
+        );
+    }
+    Ok(Some(root(data_dir).join(name)))
+}
+
+/// Write a snapshot of the instance into `dir`. The instance must not be running.
+///
+/// This blocks, so it should be called from the thread running the module.
+pub(crate) fn take(
+    instance: &wasmtime::Instance,
+    module_data: &[u8],
+    dir: &Path,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let mut manifest = Manifest {
+        module_digest: digest(module_data),
+        created_at: Utc::now(),
+        memories: vec![],
+        globals: vec![],
+    };
+    for export in instance.exports() {
+        let name = export.name().to_owned();
+        match export.into_extern() {
+            Extern::Memory(memory) => {
+                let file = format!("memory-{}.bin", manifest.memories.len());
+                // Safety: the module isn't running while the slice is in use
+                std::fs::write(dir.join(&file), unsafe { memory.data_unchecked() })?;
+                manifest.memories.push(MemorySnapshot {
+                    name,
+                    pages: memory.size(),
+                    file,
+                });
+            }
+            Extern::Global(global) if global.ty().mutability() == Mutability::Var => {
+                manifest.globals.push(GlobalSnapshot {
+                    name,
+                    value: Value::try_from(global.get())?,
+                });
+            }
+            _ => (),
+        }
+    }
+    // The manifest is written last, so that a snapshot cut short by a failure can't be restored
+    let partial = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    std::fs::write(&partial, serde_json::to_vec(&manifest)?)?;
+    std::fs::rename(&partial, dir.join(MANIFEST_FILE))?;
+    debug!(
+        "Snapshotted {} memories and {} globals to {}",
+        manifest.memories.len(),
+        manifest.globals.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Restore the instance from the snapshot in `dir`, which must have been taken of the same module.
+/// The instance must not have started running.
+///
+/// This blocks, so it should be called from the thread running the module.
+pub(crate) fn restore(
+    instance: &wasmtime::Instance,
+    module_data: &[u8],
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let manifest: Manifest = serde_json::from_slice(&std::fs::read(dir.join(MANIFEST_FILE))?)?;
+    if manifest.module_digest != digest(module_data) {
+        anyhow::bail!("snapshot {} was taken of a different module", dir.display());
+    }
+    for snapshot in &manifest.memories {
+        let memory = instance
+            .get_memory(&snapshot.name)
+            .ok_or_else(|| anyhow::anyhow!("module does not export memory {}", snapshot.name))?;
+        if memory.size() < snapshot.pages {
+            memory.grow(snapshot.pages - memory.size())?;
+        }
+        let data = std::fs::read(dir.join(&snapshot.file))?;
+        // Safety: the module isn't running while the slice is in use
+        let target = unsafe { memory.data_unchecked_mut() };
+        if data.len() > target.len() {
+            anyhow::bail!("snapshot of memory {} is larger than it", snapshot.name);
+        }
+        target[..data.len()].copy_from_slice(&data);
+    }
+    for snapshot in &manifest.globals {
+        instance
+            .get_global(&snapshot.name)
+            .ok_or_else(|| anyhow::anyhow!("module does not export global {}", snapshot.name))?
+            .set(Val::from(&snapshot.value))?;
+    }
+    debug!(
+        "Restored module from snapshot {} taken at {}",
+        dir.display(),
+        manifest.created_at
+    );
+    Ok(())
+}
+
+fn digest(module_data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(module_data))
+}
+
+/// A request to snapshot a module into a directory, along with a channel for sending back
+/// whether it was snapshotted.
+pub(crate) struct Request {
+    pub(crate) dir: PathBuf,
+    pub(crate) response: oneshot::Sender<anyhow::Result<()>>,
+}
+
+enum State {
+    /// The module is running, and hasn't been asked for a snapshot
+    Idle,
+    Requested(Request),
+    /// The module can no longer be snapshotted, for the given reason
+    Closed(&'static str),
+}
+
+/// The snapshot requests of a running module, which are shared between its runtime and the thread
+/// running it.
+#[derive(Clone)]
+pub(crate) struct Requests(Arc<Mutex<State>>);
+
+impl Requests {
+    pub(crate) fn new() -> Self {
+        Requests(Arc::new(Mutex::new(State::Idle)))
+    }
+
+    /// Ask for a snapshot of the module into `dir`, returning a channel for whether it was
+    /// snapshotted. The module has to be stopped for the request to be taken.
+    pub(crate) fn request(
+        &self,
+        dir: PathBuf,
+    ) -> anyhow::Result<oneshot::Receiver<anyhow::Result<()>>> {
+        let mut state = self.0.lock().unwrap();
+        match &*state {
+            State::Idle => {
+                let (tx, rx) = oneshot::channel();
+                *state = State::Requested(Request { dir, response: tx });
+                Ok(rx)
+            }
+            State::Requested(_) => anyhow::bail!("module is already being checkpointed"),
+            State::Closed(reason) => anyhow::bail!("{}", reason),
+        }
+    }
+
+    /// Refuse any later requests for the given reason, returning the pending request, if any.
+    pub(crate) fn close(&self, reason: &'static str) -> Option<Request> {
+        match std::mem::replace(&mut *self.0.lock().unwrap(), State::Closed(reason)) {
+            State::Requested(request) => Some(request),
+            _ => None,
+        }
+    }
+}
+
+/// Closes the requests of a module once the thread running it is done with it, however it gets
+/// there, so that no request is left waiting on a module that has gone.
+pub(crate) struct CloseOnDrop(pub(crate) Requests);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close("module is no longer running");
+    }
+}
@@ -0,0 +1,260 @@
+//! A host-function shim that lets WASI modules make outbound TCP and UDP connections.
+//!
+//! WASI has no settled way for modules to open sockets yet, so modules of pods annotated with
+//! [`OUTBOUND_NETWORK_ANNOTATION`] set to `true` can import these functions from the
+//! `krustlet_sockets` module instead:
+//!
+//! * `connect(kind: i32, addr: i32, addr_len: i32, socket: i32) -> errno` connects to the
+//!   `host:port` address held as UTF-8 in the module's memory, over TCP if `kind` is 0 or UDP if
+//!   it is 1, and writes a handle for the socket to `socket`
+//! * `send(socket: i32, buf: i32, buf_len: i32, sent: i32) -> errno` sends bytes on the socket,
+//!   writing how many were sent to `sent`
+//! * `recv(socket: i32, buf: i32, buf_len: i32, received: i32) -> errno` receives bytes from the
+//!   socket, writing how many were received to `received`. If nothing arrives within a second it
+//!   fails with `EAGAIN`, so that the module can still be stopped while it waits
+//! * `close(socket: i32) -> errno` closes the socket
+//!
+//! Each function returns a WASI errno, which is 0 on success. Connections are only made to
+//! addresses allowed by the node's outbound rules; others fail with `EACCES`.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::rc::Rc;
+use std::time::Duration;
+
+use tracing::debug;
+use wasmtime::{Caller, Func, Memory, Store};
+
+use crate::config::OutboundRule;
+
+use crate::shim::{
+    bytes, call, write_u32, EACCES, EAGAIN, EBADF, ECONNREFUSED, ECONNRESET, EHOSTUNREACH, EINVAL,
+    EIO, ENOTCONN, ESUCCESS, ETIMEDOUT,
+};
+
+/// The name of the import module that the shim's functions are in.
+pub(crate) const SOCKETS_MODULE: &str = "krustlet_sockets";
+
+/// The pod annotation that lets the pod's modules make outbound connections, when set to `true`.
+pub const OUTBOUND_NETWORK_ANNOTATION: &str = "wasi.krustlet.dev/outbound-network";
+
+/// How long connecting may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long receiving waits for data before giving control back to the module.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+const TCP: i32 = 0;
+const UDP: i32 = 1;
+
+/// A socket opened by a module.
+enum Socket {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl Socket {
+    fn connect(kind: i32, addr: SocketAddr) -> std::io::Result<Self> {
+        if kind == TCP {
+            let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+            stream.set_read_timeout(Some(RECV_TIMEOUT))?;
+            Ok(Socket::Tcp(stream))
+        } else {
+            let local: SocketAddr = if addr.is_ipv4() {
+                (Ipv4Addr::UNSPECIFIED, 0).into()
+            } else {
+                (Ipv6Addr::UNSPECIFIED, 0).into()
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(addr)?;
+            socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+            Ok(Socket::Udp(socket))
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            Socket::Udp(socket) => socket.send(buf),
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            Socket::Udp(socket) => socket.recv(buf),
+        }
+    }
+}
+
+/// The sockets opened by an instance of a module, by handle.
+#[derive(Default)]
+struct Open {
+    sockets: HashMap<i32, Socket>,
+    next_handle: i32,
+}
+
+/// The shim's functions for an instance of a module.
+#[derive(Clone)]
+pub(crate) struct Sockets {
+    allowed: Rc<Vec<OutboundRule>>,
+    open: Rc<RefCell<Open>>,
+}
+
+impl Sockets {
+    /// The shim for an instance that may connect to the destinations the rules allow. Sockets
+    /// are closed once the instance is dropped.
+    pub(crate) fn new(allowed: &[OutboundRule]) -> Self {
+        Sockets {
+            allowed: Rc::new(allowed.to_vec()),
+            open: Rc::new(RefCell::new(Open::default())),
+        }
+    }
+
+    /// The shim's functions, by name, for an instance in `store`.
+    pub(crate) fn exports(&self, store: &Store) -> HashMap<&'static str, Func> {
+        let mut exports = HashMap::new();
+        let sockets = self.clone();
+        exports.insert(
+            "connect",
+            Func::wrap(
+                store,
+                move |caller: Caller<'_>, kind: i32, addr: i32, addr_len: i32, socket: i32| {
+                    call(&caller, |memory| {
+                        sockets.connect(memory, kind, addr, addr_len, socket)
+                    })
+                },
+            ),
+        );
+        let sockets = self.clone();
+        exports.insert(
+            "send",
+            Func::wrap(
+                store,
+                move |caller: Caller<'_>, socket: i32, buf: i32, buf_len: i32, sent: i32| {
+                    call(&caller, |memory| {
+                        sockets.send(memory, socket, buf, buf_len, sent)
+                    })
+                },
+            ),
+        );
+        let sockets = self.clone();
+        exports.insert(
+            "recv",
+            Func::wrap(
+                store,
+                move |caller: Caller<'_>, socket: i32, buf: i32, buf_len: i32, received: i32| {
+                    call(&caller, |memory| {
+                        sockets.recv(memory, socket, buf, buf_len, received)
+                    })
+                },
+            ),
+        );
+        let sockets = self.clone();
+        exports.insert(
+            "close",
+            Func::wrap(store, move |socket: i32| {
+                match sockets.open.borrow_mut().sockets.remove(&socket) {
+                    Some(_) => ESUCCESS,
+                    None => EBADF,
+                }
+            }),
+        );
+        exports
+    }
+
+    fn connect(
+        &self,
+        memory: &Memory,
+        kind: i32,
+        addr: i32,
+        addr_len: i32,
+        socket: i32,
+    ) -> Result<(), i32> {
+        if kind != TCP && kind != UDP {
+            return Err(EINVAL);
+        }
+        // SAFETY: the bytes are copied out before the memory is touched again
+        let addr = std::str::from_utf8(unsafe { bytes(memory, addr, addr_len)? })
+            .map_err(|_| EINVAL)?
+            .to_owned();
+        let candidates = addr.to_socket_addrs().map_err(|e| {
+            debug!("Unable to resolve {} for module: {}", addr, e);
+            EHOSTUNREACH
+        })?;
+        let allowed = candidates
+            .find(|candidate| self.allowed.iter().any(|rule| rule.allows(candidate)))
+            .ok_or_else(|| {
+                debug!("Module is not allowed to connect to {}", addr);
+                EACCES
+            })?;
+        let connected = Socket::connect(kind, allowed).map_err(|e| {
+            debug!("Module was unable to connect to {}: {}", allowed, e);
+            errno(&e)
+        })?;
+
+        let mut open = self.open.borrow_mut();
+        open.next_handle += 1;
+        let handle = open.next_handle;
+        write_u32(memory, socket, handle as u32)?;
+        open.sockets.insert(handle, connected);
+        Ok(())
+    }
+
+    fn send(
+        &self,
+        memory: &Memory,
+        socket: i32,
+        buf: i32,
+        buf_len: i32,
+        sent: i32,
+    ) -> Result<(), i32> {
+        let count = {
+            let mut open = self.open.borrow_mut();
+            let socket = open.sockets.get_mut(&socket).ok_or(EBADF)?;
+            // SAFETY: the bytes are only used while they are sent
+            socket
+                .send(unsafe { bytes(memory, buf, buf_len)? })
+                .map_err(|e| errno(&e))?
+        };
+        write_u32(memory, sent, count as u32)
+    }
+
+    fn recv(
+        &self,
+        memory: &Memory,
+        socket: i32,
+        buf: i32,
+        buf_len: i32,
+        received: i32,
+    ) -> Result<(), i32> {
+        let count = {
+            let mut open = self.open.borrow_mut();
+            let socket = open.sockets.get_mut(&socket).ok_or(EBADF)?;
+            // SAFETY: the bytes are only used while they are received into
+            match socket.recv(unsafe { bytes(memory, buf, buf_len)? }) {
+                Ok(count) => count,
+                // Timing out is how the read timeout shows up on some platforms
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Err(EAGAIN)
+                }
+                Err(e) => return Err(errno(&e)),
+            }
+        };
+        write_u32(memory, received, count as u32)
+    }
+}
+
+/// The WASI errno for a socket error.
+fn errno(e: &std::io::Error) -> i32 {
+    match e.kind() {
+        ErrorKind::ConnectionRefused => ECONNREFUSED,
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => ECONNRESET,
+        ErrorKind::NotConnected => ENOTCONN,
+        ErrorKind::TimedOut => ETIMEDOUT,
+        ErrorKind::WouldBlock => EAGAIN,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
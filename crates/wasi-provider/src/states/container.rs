@@ -1,9 +1,13 @@
 use crate::ModuleRunContext;
 use crate::ProviderState;
 use krator::{ObjectState, SharedState};
+use kubelet::backoff::ExponentialBackoffStrategy;
 use kubelet::container::{Container, ContainerKey, Status};
 use kubelet::pod::Pod;
+use std::time::Instant;
+use tokio::sync::oneshot;
 
+pub(crate) mod crash_loop_backoff;
 pub(crate) mod running;
 pub(crate) mod terminated;
 pub(crate) mod waiting;
@@ -12,6 +16,12 @@ pub(crate) struct ContainerState {
     pod: Pod,
     container_key: ContainerKey,
     run_context: SharedState<ModuleRunContext>,
+    restart_count: i32,
+    crash_loop_backoff_strategy: ExponentialBackoffStrategy,
+    /// When the current run of the container started.
+    started_at: Option<Instant>,
+    /// Told when the container first starts running, if anything is waiting for it to.
+    started: Option<oneshot::Sender<()>>,
 }
 
 impl ContainerState {
@@ -24,8 +34,19 @@ impl ContainerState {
             pod,
             container_key,
             run_context,
+            restart_count: 0,
+            crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
+            started_at: None,
+            started: None,
         }
     }
+
+    /// Send on the channel once the container first starts running. The channel is closed
+    /// without a message if the container finishes without ever running.
+    pub fn notify_started(mut self, started: oneshot::Sender<()>) -> Self {
+        self.started = Some(started);
+        self
+    }
 }
 
 #[async_trait::async_trait]
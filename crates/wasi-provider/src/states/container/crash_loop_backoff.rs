@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kubelet::container::patch_container_restart_count;
+use kubelet::container::state::prelude::*;
+use kubelet::pod::{reason, EventType};
+use kubelet::state::common::GenericProviderState;
+use tracing::warn;
+
+use crate::ProviderState;
+
+use super::waiting::Waiting;
+use super::ContainerState;
+
+/// The container exited and is waiting to be restarted.
+#[derive(Debug, TransitionTo)]
+#[transition_to(Waiting)]
+pub struct CrashLoopBackOff {
+    delay: Duration,
+}
+
+impl CrashLoopBackOff {
+    pub fn new(delay: Duration) -> Self {
+        CrashLoopBackOff { delay }
+    }
+}
+
+#[async_trait::async_trait]
+impl State<ContainerState> for CrashLoopBackOff {
+    async fn next(
+        self: Box<Self>,
+        shared: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
+    ) -> Transition<ContainerState> {
+        let container = container.latest();
+        let (client, events) = {
+            let provider_state = shared.read().await;
+            (provider_state.client(), provider_state.events.clone())
+        };
+        if let Err(e) = events
+            .record(
+                &state.pod,
+                Some(container.name()),
+                EventType::Warning,
+                reason::BACK_OFF,
+                "Back-off restarting failed container",
+            )
+            .await
+        {
+            warn!(
+                "Unable to record event for pod {}: {:?}",
+                state.pod.name(),
+                e
+            );
+        }
+        tokio::time::delay_for(self.delay).await;
+
+        state.restart_count += 1;
+        let api: kube::Api<KubePod> = kube::Api::namespaced(client, state.pod.namespace());
+        if let Err(e) = patch_container_restart_count(
+            &api,
+            &state.pod,
+            &state.container_key,
+            state.restart_count,
+        )
+        .await
+        {
+            warn!(
+                "Pod {} container {} restart count patch returned error: {:?}",
+                state.pod.name(),
+                state.container_key,
+                e
+            );
+        }
+
+        Transition::next(self, Waiting)
+    }
+
+    async fn status(
+        &self,
+        _state: &mut ContainerState,
+        _container: &Container,
+    ) -> anyhow::Result<Status> {
+        Ok(Status::waiting(&format!(
+            "CrashLoopBackOff: back-off {}s restarting container",
+            self.delay.as_secs()
+        )))
+    }
+}
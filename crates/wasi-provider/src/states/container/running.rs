@@ -2,18 +2,64 @@ use super::terminated::Terminated;
 use super::ContainerState;
 use crate::ProviderState;
 use kubelet::container::state::prelude::*;
+use kubelet::container::termination::{
+    TerminationMessage, FALLBACK_LOG_LINES, MAX_FALLBACK_LOG_BYTES,
+};
+use kubelet::pod::PodKey;
+use kubelet::state::common::GenericProviderState;
+use std::time::Instant;
 use tokio::sync::mpsc::Receiver;
+use tracing::warn;
 
 /// The container is starting.
 #[derive(Debug, TransitionTo)]
 #[transition_to(Terminated)]
 pub struct Running {
     rx: Receiver<Status>,
+    termination: TerminationMessage,
 }
 
 impl Running {
-    pub fn new(rx: Receiver<Status>) -> Self {
-        Running { rx }
+    pub fn new(rx: Receiver<Status>, termination: TerminationMessage) -> Self {
+        Running { rx, termination }
+    }
+}
+
+/// The message the module left when it terminated: what it wrote to its termination message
+/// path or, if its policy falls back to its logs and it failed, the last lines of its output.
+async fn termination_message(
+    shared: &SharedState<ProviderState>,
+    state: &ContainerState,
+    container_name: &str,
+    termination: &TerminationMessage,
+    failed: bool,
+) -> Option<String> {
+    if let Some(message) = termination.read().await {
+        return Some(message);
+    }
+    if !termination.falls_back_to_logs(failed) {
+        return None;
+    }
+    let pod_handle = {
+        let provider_state = shared.read().await;
+        let handles = provider_state.handles.read().await;
+        handles.get(&PodKey::from(&state.pod)).cloned()
+    }?;
+    match pod_handle
+        .log_tail(container_name, FALLBACK_LOG_LINES, MAX_FALLBACK_LOG_BYTES)
+        .await
+    {
+        Ok(logs) if !logs.trim().is_empty() => Some(logs),
+        Ok(_) => None,
+        Err(e) => {
+            warn!(
+                "Unable to read logs of pod {} container {}: {:?}",
+                state.pod.name(),
+                container_name,
+                e
+            );
+            None
+        }
     }
 }
 
@@ -21,15 +67,49 @@ impl Running {
 impl State<ContainerState> for Running {
     async fn next(
         mut self: Box<Self>,
-        _shared_state: SharedState<ProviderState>,
-        _state: &mut ContainerState,
-        _container: Manifest<Container>,
+        shared_state: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
     ) -> Transition<ContainerState> {
+        state.started_at = Some(Instant::now());
+        if let Some(started) = state.started.take() {
+            started.send(()).ok();
+        }
+        // The probes run until this state is left
+        let _probes = {
+            let container = container.latest();
+            let provider_state = shared_state.read().await;
+            let pod_handle = provider_state
+                .handles
+                .read()
+                .await
+                .get(&PodKey::from(&state.pod))
+                .cloned();
+            pod_handle.map(|pod_handle| {
+                kubelet::probe::start(
+                    provider_state.client(),
+                    &state.pod,
+                    &container,
+                    state.container_key.clone(),
+                    pod_handle,
+                )
+            })
+        };
         while let Some(status) = self.rx.recv().await {
             if let Status::Terminated {
                 failed, message, ..
             } = status
             {
+                let container_name = container.latest().name().to_owned();
+                let message = termination_message(
+                    &shared_state,
+                    state,
+                    &container_name,
+                    &self.termination,
+                    failed,
+                )
+                .await
+                .unwrap_or(message);
                 return Transition::next(self, Terminated::new(message, failed));
             }
         }
@@ -1,13 +1,21 @@
+use std::time::Duration;
+
+use kubelet::backoff::BackoffStrategy;
 use kubelet::container::state::prelude::*;
-use log::error;
+use kubelet::pod::PodKey;
+use tracing::{error, info};
 
 use crate::ProviderState;
 
+use super::crash_loop_backoff::CrashLoopBackOff;
 use super::ContainerState;
 
+/// How long a container has to run for its crash loop backoff to be reset.
+const BACKOFF_RESET_AFTER: Duration = Duration::from_secs(600);
+
 /// The container is starting.
 #[derive(Debug, TransitionTo)]
-#[transition_to()]
+#[transition_to(CrashLoopBackOff)]
 pub struct Terminated {
     message: String,
     failed: bool,
@@ -19,11 +27,47 @@ impl Terminated {
     }
 }
 
+/// Whether a container that exited should be started again, according to its pod's restart
+/// policy. Init containers are only restarted if they failed, unless they are sidecars, which are
+/// always restarted, as are containers whose image was changed. Ephemeral containers and
+/// containers of a pod that is being stopped are never restarted.
+async fn should_restart(
+    shared: &SharedState<ProviderState>,
+    state: &ContainerState,
+    failed: bool,
+) -> bool {
+    if state.container_key.is_ephemeral() {
+        return false;
+    }
+    let sidecar =
+        state.container_key.is_init() && state.pod.is_sidecar(&state.container_key.name());
+    let updated = state
+        .run_context
+        .write()
+        .await
+        .updated
+        .remove(&state.container_key.name());
+    let restart = match state.pod.restart_policy() {
+        _ if sidecar || updated => true,
+        "Always" if !state.container_key.is_init() => true,
+        "Always" | "OnFailure" => failed,
+        _ => false,
+    };
+    if !restart {
+        return false;
+    }
+    let provider_state = shared.read().await;
+    let handles = provider_state.handles.read().await;
+    handles
+        .get(&PodKey::from(&state.pod))
+        .map_or(false, |handle| !handle.is_stopped())
+}
+
 #[async_trait::async_trait]
 impl State<ContainerState> for Terminated {
     async fn next(
         self: Box<Self>,
-        _shared_state: SharedState<ProviderState>,
+        shared: SharedState<ProviderState>,
         state: &mut ContainerState,
         container: Manifest<Container>,
     ) -> Transition<ContainerState> {
@@ -36,6 +80,25 @@ impl State<ContainerState> for Terminated {
                 container.name(),
                 &self.message
             );
+        }
+
+        if should_restart(&shared, state, self.failed).await {
+            if state.started_at.map_or(false, |started_at| {
+                started_at.elapsed() >= BACKOFF_RESET_AFTER
+            }) {
+                state.crash_loop_backoff_strategy.reset();
+            }
+            let delay = state.crash_loop_backoff_strategy.next_duration();
+            info!(
+                "Restarting pod {} container {} in {:?}",
+                state.pod.name(),
+                container.name(),
+                delay
+            );
+            return Transition::next(self, CrashLoopBackOff::new(delay));
+        }
+
+        if self.failed {
             Transition::Complete(Err(anyhow::anyhow!(self.message.clone())))
         } else {
             Transition::Complete(Ok(()))
@@ -1,51 +1,90 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use log::{debug, info};
 use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
 
 use kubelet::container::state::prelude::*;
-use kubelet::pod::{Handle as PodHandle, PodKey};
+use kubelet::container::termination::TerminationMessage;
+use kubelet::container::{expand, patch_container_ids, Lifecycle};
+use kubelet::features::{self, Feature};
+use kubelet::pod::{reason, EventType, Handle as PodHandle, PodKey};
 use kubelet::state::common::GenericProviderState;
 use kubelet::volume::Ref;
 
-use crate::wasi_runtime::WasiRuntime;
+use crate::client::{AllowedHosts, ALLOWED_HOSTS_ANNOTATION};
+use crate::sandbox::{self, Access, Sandbox};
+use crate::snapshot;
+use crate::sockets::OUTBOUND_NETWORK_ANNOTATION;
+use crate::wasi_runtime::{WasiRuntime, WASM_PAGE_SIZE};
 use crate::ProviderState;
 
 use super::running::Running;
 use super::terminated::Terminated;
 use super::ContainerState;
 
-fn volume_path_map(
+/// The directories on the host that the container's volume mounts give its module, mapped to where
+/// the module sees them, along with those of them that are mounted read-only. Mounts with a
+/// `subPath` or `subPathExpr` give the module that directory of the volume rather than all of it,
+/// with `subPathExpr` expanded using the container's environment.
+async fn volume_path_map(
     container: &Container,
     volumes: &HashMap<String, Ref>,
-) -> anyhow::Result<HashMap<PathBuf, Option<PathBuf>>> {
-    if let Some(volume_mounts) = container.volume_mounts().as_ref() {
-        volume_mounts
-            .iter()
-            .map(|vm| -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
-                // Check the volume exists first
-                let vol = volumes.get(&vm.name).ok_or_else(|| {
-                    anyhow::anyhow!(
-                        "no volume with the name of {} found for container {}",
-                        vm.name,
-                        container.name()
-                    )
-                })?;
-                let mut guest_path = PathBuf::from(&vm.mount_path);
-                if let Some(sub_path) = &vm.sub_path {
-                    guest_path.push(sub_path);
+    env: &HashMap<String, String>,
+) -> anyhow::Result<(HashMap<PathBuf, Option<PathBuf>>, HashSet<PathBuf>)> {
+    let mut dirs = HashMap::new();
+    let mut read_only = HashSet::new();
+    for vm in container.volume_mounts().iter().flatten() {
+        // Check the volume exists first
+        let vol = volumes.get(&vm.name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "no volume with the name of {} found for container {}",
+                vm.name,
+                container.name()
+            )
+        })?;
+        let sub_path = match (&vm.sub_path, &vm.sub_path_expr) {
+            (Some(sub_path), _) if !sub_path.is_empty() => Some(sub_path.clone()),
+            (_, Some(sub_path_expr)) if !sub_path_expr.is_empty() => {
+                Some(expand(sub_path_expr, env))
+            }
+            _ => None,
+        };
+        let host_path = match sub_path {
+            Some(sub_path) => {
+                let host_path = kubelet::volume::sub_path(vol.deref(), &sub_path).await?;
+                // Modules can only be given directories, not single files
+                if !host_path.is_dir() {
+                    anyhow::bail!(
+                        "subPath {} of volume {} is not a directory",
+                        sub_path,
+                        vm.name
+                    );
                 }
-                // We can safely assume that this should be valid UTF-8 because it would have
-                // been validated by the k8s API
-                Ok((vol.deref().clone(), Some(guest_path)))
-            })
-            .collect::<anyhow::Result<HashMap<PathBuf, Option<PathBuf>>>>()
-    } else {
-        Ok(HashMap::default())
+                host_path
+            }
+            None => vol.deref().clone(),
+        };
+        if vm.read_only.unwrap_or(false) {
+            read_only.insert(host_path.clone());
+        }
+        dirs.insert(host_path, Some(PathBuf::from(&vm.mount_path)));
     }
+    Ok((dirs, read_only))
+}
+
+/// The TCP ports the container declares, which HTTP requests are passed to its module from.
+fn http_ports(container: &Container) -> Vec<u16> {
+    container
+        .ports()
+        .iter()
+        .flatten()
+        .filter(|port| port.protocol.as_deref().unwrap_or("TCP") == "TCP")
+        .filter_map(|port| u16::try_from(port.container_port).ok())
+        .collect()
 }
 
 /// The container is starting.
@@ -69,14 +108,69 @@ impl State<ContainerState> for Waiting {
             state.pod.name(),
         );
 
-        let (client, log_path) = {
+        let (
+            client,
+            events,
+            log_storage,
+            module_cache,
+            engine,
+            max_memory_pages,
+            allowed_outbound,
+            sandbox_all,
+            data_dir,
+            store,
+            usages,
+        ) = {
             let provider_state = shared.read().await;
-            (provider_state.client(), provider_state.log_path.clone())
+            (
+                provider_state.client(),
+                provider_state.events.clone(),
+                provider_state.log_storage.clone(),
+                provider_state.module_cache.clone(),
+                provider_state.engine.clone(),
+                provider_state.max_memory_pages,
+                provider_state.allowed_outbound.clone(),
+                provider_state.sandbox,
+                provider_state.data_dir.clone(),
+                provider_state.store(),
+                provider_state.usages.clone(),
+            )
         };
 
-        let (module_data, container_volumes) = {
+        // The environment is resolved first, as volume mounts' subPathExpr is expanded with it
+        let mut env = match kubelet::provider::env_vars(&container, &state.pod, &client).await {
+            Ok(env) => env,
+            Err(e) => {
+                let message = format!(
+                    "Pod {} container {} failed to resolve its environment: {:?}",
+                    state.pod.name(),
+                    container.name(),
+                    e
+                );
+                if let Err(e) = events
+                    .record(
+                        &state.pod,
+                        Some(container.name()),
+                        EventType::Warning,
+                        reason::FAILED,
+                        &message,
+                    )
+                    .await
+                {
+                    warn!(
+                        "Unable to record event for pod {}: {:?}",
+                        state.pod.name(),
+                        e
+                    );
+                }
+                return Transition::next(self, Terminated::new(message, true));
+            }
+        };
+
+        let (module_data, mut container_volumes, read_only_mounts, devices) = {
             let mut run_context = state.run_context.write().await;
-            let module_data = match run_context.modules.remove(container.name()) {
+            // The module data is kept in the run context in case the container is restarted
+            let module_data = match run_context.modules.get(container.name()).cloned() {
                 Some(data) => data,
                 None => {
                     return Transition::next(
@@ -92,7 +186,8 @@ impl State<ContainerState> for Waiting {
                     );
                 }
             };
-            let container_volumes = match volume_path_map(&container, &run_context.volumes) {
+            let volumes = volume_path_map(&container, &run_context.volumes, &env).await;
+            let (container_volumes, read_only_mounts) = match volumes {
                 Ok(volumes) => volumes,
                 Err(e) => {
                     return Transition::next(
@@ -109,27 +204,302 @@ impl State<ContainerState> for Waiting {
                     )
                 }
             };
-            (module_data, container_volumes)
+            let devices = run_context
+                .devices
+                .get(container.name())
+                .cloned()
+                .unwrap_or_default();
+            (module_data, container_volumes, read_only_mounts, devices)
         };
 
-        let env = kubelet::provider::env_vars(&container, &state.pod, &client).await;
-        let args = container.args().clone().unwrap_or_default();
+        // Device plugins direct how their devices are used through environment variables and
+        // mounts. WASI modules can only be given directories, so device nodes are left out.
+        for mount in devices.mounts {
+            container_volumes.insert(mount.host_path, Some(mount.container_path));
+        }
+        if !devices.devices.is_empty() {
+            warn!(
+                "Pod {} container {} was allocated device nodes, which can't be given to WASI modules",
+                state.pod.name(),
+                container.name()
+            );
+        }
+
+        env.extend(devices.env);
+
+        // The pod's resolv.conf is given to the module as its /etc, unless a volume is mounted there
+        if let Some(resolv_conf) = kubelet::dns::resolv_conf_path(&data_dir, &state.pod) {
+            let etc = PathBuf::from("/etc");
+            let etc_is_mounted = container_volumes
+                .values()
+                .any(|mount_path| mount_path.as_ref() == Some(&etc));
+            if let (Some(dir), false) = (resolv_conf.parent(), etc_is_mounted) {
+                container_volumes.insert(dir.to_owned(), Some(etc));
+                env.entry(kubelet::dns::RESOLV_CONF_ENV.to_owned())
+                    .or_insert_with(|| "/etc/resolv.conf".to_owned());
+            }
+        }
+        // The directory of the termination message path is backed by a directory on the host that
+        // the message is read from. Messages written into a volume mounted there aren't reported.
+        let termination = TerminationMessage::new(&data_dir, &state.pod, &container);
+        if let Err(e) = termination.prepare().await {
+            warn!(
+                "Unable to create termination message file for pod {} container {}: {}",
+                state.pod.name(),
+                container.name(),
+                e
+            );
+        } else if let (Some(host_dir), Some(container_dir)) =
+            (termination.host_dir(), termination.container_dir())
+        {
+            let is_mounted = container_volumes
+                .values()
+                .any(|mount_path| mount_path.as_deref() == Some(container_dir));
+            if !is_mounted {
+                container_volumes.insert(host_dir.to_owned(), Some(container_dir.to_owned()));
+            }
+        }
+        // Modules have no root file system of their own, so a read-only one makes all of a module's
+        // directories read-only, apart from the one its termination message is written to
+        let read_only_root = container
+            .security_context()
+            .and_then(|context| context.read_only_root_filesystem)
+            .unwrap_or(false);
+        let read_only_dirs: HashSet<PathBuf> = if read_only_root {
+            container_volumes
+                .keys()
+                .filter(|dir| Some(dir.as_path()) != termination.host_dir())
+                .cloned()
+                .collect()
+        } else {
+            read_only_mounts
+        };
+        let args = container
+            .args()
+            .iter()
+            .flatten()
+            .map(|arg| expand(arg, &env))
+            .collect();
 
         // TODO: ~magic~ number
         let (tx, rx) = mpsc::channel(8);
 
+        let limits = container.resources().and_then(|r| r.limits.as_ref());
+        let memory_limit = match limits
+            .and_then(|limits| limits.get("memory"))
+            .map(kubelet::resources::memory_bytes)
+            .transpose()
+        {
+            Ok(limit) => limit,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid memory limit: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+        // The node's cap on module memory applies to containers without a lower limit of their own
+        let max_memory = max_memory_pages.map(|pages| u64::from(pages) * WASM_PAGE_SIZE);
+        let memory_limit = match (memory_limit, max_memory) {
+            (Some(limit), Some(max)) => Some(limit.min(max)),
+            (limit, max) => limit.or(max),
+        };
+        let cpu_limit = match limits
+            .and_then(|limits| limits.get("cpu"))
+            .map(kubelet::resources::cpu_millis)
+            .transpose()
+        {
+            Ok(limit) => limit,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid CPU limit: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+        // CPU limits are enforced by counting the fuel modules use, which the engine only does if
+        // told to
+        let cpu_limit = match cpu_limit {
+            Some(millis) if engine.fuel() => Some(millis),
+            Some(_) => {
+                warn!(
+                    "Pod {} container {} has a CPU limit, which is not enforced for WASI modules \
+                     unless fuel is turned on",
+                    state.pod.name(),
+                    container.name()
+                );
+                None
+            }
+            None => None,
+        };
+
+        let allowed_hosts = match state
+            .pod
+            .get_annotation(ALLOWED_HOSTS_ANNOTATION)
+            .map(AllowedHosts::new)
+            .transpose()
+        {
+            Ok(allowed_hosts) => allowed_hosts,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to set up its HTTP client: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let snapshot = match snapshot::restore_dir(&data_dir, &state.pod, container.name()) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} names an invalid snapshot: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let outbound_network =
+            state.pod.get_annotation(OUTBOUND_NETWORK_ANNOTATION) == Some("true");
+        if outbound_network && !features::enabled(Feature::WasiSockets) {
+            return Transition::next(
+                self,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} asks for outbound networking, which needs the {} feature gate to be enabled",
+                        state.pod.name(),
+                        container.name(),
+                        Feature::WasiSockets
+                    ),
+                    true,
+                ),
+            );
+        }
+        let sandbox = if sandbox_all || sandbox::requested(&state.pod, container.name()) {
+            // A sandboxed module can only write snapshots if the directory they go in exists
+            let snapshot_root = snapshot::root(&data_dir);
+            if let Err(e) = tokio::fs::create_dir_all(&snapshot_root).await {
+                warn!(
+                    "Unable to create snapshot directory for pod {} container {}: {}",
+                    state.pod.name(),
+                    container.name(),
+                    e
+                );
+            }
+            let sandbox = container_volumes
+                .keys()
+                .fold(Sandbox::new(), |sandbox, dir| {
+                    if read_only_dirs.contains(dir) {
+                        sandbox.allow(dir, Access::ReadOnly)
+                    } else {
+                        sandbox.allow(dir, Access::ReadWrite)
+                    }
+                })
+                .allow(snapshot_root, Access::ReadWrite);
+            if outbound_network || allowed_hosts.is_some() {
+                Some(sandbox.allow_name_resolution())
+            } else {
+                Some(sandbox)
+            }
+        } else {
+            None
+        };
+
+        // Containers are reported with the digest their image resolved to, which has to be worked
+        // out before the module is handed to the runtime
+        let image_ref = container.image().ok().flatten();
+        let image_digest = match &image_ref {
+            Some(image_ref) => store.digest(image_ref).await,
+            None => None,
+        };
+        let image_id = image_ref.map(|image_ref| {
+            kubelet::store::image_id(&image_ref, image_digest.as_deref(), &module_data)
+        });
+
         let runtime = match WasiRuntime::new(
             container.name().to_owned(),
             module_data,
             env,
             args,
             container_volumes,
-            log_path,
+            log_storage,
+            state.pod.clone(),
             tx,
+            engine,
         )
         .await
         {
-            Ok(runtime) => runtime,
+            Ok(runtime) => {
+                let runtime = runtime
+                    .with_module_cache(module_cache)
+                    .with_read_only_dirs(read_only_dirs);
+                let runtime = match memory_limit {
+                    Some(limit) => runtime.with_memory_limit(limit),
+                    None => runtime,
+                };
+                let runtime = match cpu_limit {
+                    Some(millis) => runtime.with_cpu_limit(millis),
+                    None => runtime,
+                };
+                let runtime = if outbound_network {
+                    runtime.with_outbound_network(allowed_outbound)
+                } else {
+                    runtime
+                };
+                let runtime = match allowed_hosts {
+                    Some(allowed_hosts) => runtime.with_allowed_hosts(allowed_hosts),
+                    None => runtime,
+                };
+                let runtime = match snapshot {
+                    Some(dir) => runtime.with_snapshot(dir),
+                    None => runtime,
+                };
+                let runtime = match sandbox {
+                    Some(sandbox) => runtime.with_sandbox(sandbox),
+                    None => runtime,
+                };
+                let runtime = runtime.with_http_ports(http_ports(&container));
+                let runtime = match kubelet::network::namespace_path(&data_dir, &state.pod) {
+                    Some(netns) => runtime.with_network_namespace(netns),
+                    None => runtime,
+                };
+                if container.stdin().unwrap_or(false) {
+                    runtime.with_stdin(container.stdin_once().unwrap_or(false))
+                } else {
+                    runtime
+                }
+            }
             Err(e) => {
                 return Transition::next(
                     self,
@@ -145,24 +515,65 @@ impl State<ContainerState> for Waiting {
                 )
             }
         };
+        usages
+            .write()
+            .await
+            .entry(PodKey::from(&state.pod))
+            .or_default()
+            .insert(container.name().to_owned(), runtime.usage());
+
         debug!("Starting container {} on thread", container.name());
         let container_handle = match runtime.start().await {
-            Ok(handle) => handle,
+            Ok(handle) => match Lifecycle::new(client.clone(), &state.pod, &container) {
+                Some(lifecycle) => handle.with_lifecycle(lifecycle),
+                None => handle,
+            },
             Err(e) => {
-                return Transition::next(
-                    self,
-                    Terminated::new(
-                        format!(
-                            "Pod {} container {} failed to start: {:?}",
-                            state.pod.name(),
-                            container.name(),
-                            e
-                        ),
-                        true,
-                    ),
-                )
+                let message = format!(
+                    "Pod {} container {} failed to start: {:?}",
+                    state.pod.name(),
+                    container.name(),
+                    e
+                );
+                if let Err(e) = events
+                    .record(
+                        &state.pod,
+                        Some(container.name()),
+                        EventType::Warning,
+                        reason::FAILED,
+                        &message,
+                    )
+                    .await
+                {
+                    warn!(
+                        "Unable to record event for pod {}: {:?}",
+                        state.pod.name(),
+                        e
+                    );
+                }
+                return Transition::next(self, Terminated::new(message, true));
             }
         };
+        if let Err(e) = container_handle.post_start().await {
+            let mut container_handle = container_handle;
+            container_handle
+                .terminate(state.pod.termination_grace_period())
+                .await
+                .ok();
+            return Transition::next(
+                self,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} postStart hook failed: {:?}",
+                        state.pod.name(),
+                        container.name(),
+                        e
+                    ),
+                    true,
+                ),
+            );
+        }
+        let container_id = container_handle.id().map(str::to_owned);
         let pod_key = PodKey::from(&state.pod);
         {
             let provider_state = shared.write().await;
@@ -174,7 +585,48 @@ impl State<ContainerState> for Waiting {
                 .insert_container_handle(state.container_key.clone(), container_handle)
                 .await;
         }
-        Transition::next(self, Running::new(rx))
+        if let (Some(container_id), Some(image_id)) = (container_id, image_id) {
+            debug!(
+                "Container {} started as {} from image {}",
+                container.name(),
+                container_id,
+                image_id
+            );
+            let api = kube::Api::namespaced(client, state.pod.namespace());
+            if let Err(e) = patch_container_ids(
+                &api,
+                &state.pod,
+                &state.container_key,
+                &container_id,
+                &image_id,
+            )
+            .await
+            {
+                warn!(
+                    "Unable to report IDs of container {} of pod {}: {:?}",
+                    container.name(),
+                    state.pod.name(),
+                    e
+                );
+            }
+        }
+        if let Err(e) = events
+            .record(
+                &state.pod,
+                Some(container.name()),
+                EventType::Normal,
+                reason::STARTED,
+                &format!("Started container {}", container.name()),
+            )
+            .await
+        {
+            warn!(
+                "Unable to record event for pod {}: {:?}",
+                state.pod.name(),
+                e
+            );
+        }
+        Transition::next(self, Running::new(rx, termination))
     }
 
     async fn status(
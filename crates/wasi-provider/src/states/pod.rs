@@ -4,15 +4,21 @@ use async_trait::async_trait;
 use krator::{ObjectState, SharedState};
 use kubelet::backoff::BackoffStrategy;
 use kubelet::backoff::ExponentialBackoffStrategy;
+use kubelet::container::termination;
 use kubelet::pod::Pod;
 use kubelet::pod::PodKey;
 use kubelet::pod::Status;
 use kubelet::state::common::{BackoffSequence, GenericPodState, ThresholdTrigger};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
+use tracing::warn;
 
 pub(crate) mod completed;
+pub(crate) mod deadline_exceeded;
+pub(crate) mod failed;
+pub(crate) mod init_failed;
 pub(crate) mod initializing;
 pub(crate) mod running;
 pub(crate) mod starting;
@@ -24,6 +30,10 @@ pub struct PodState {
     errors: usize,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
     pub(crate) crash_loop_backoff_strategy: ExponentialBackoffStrategy,
+    /// When the pod's containers were first started, which its active deadline counts from.
+    pub(crate) started_at: Option<Instant>,
+    /// The pod, whose containers' logs are removed along with it
+    pod: Pod,
 }
 
 #[async_trait]
@@ -36,6 +46,23 @@ impl ObjectState for PodState {
             let mut handles = provider_state.handles.write().await;
             handles.remove(&self.key);
         }
+        provider_state.usages.write().await.remove(&self.key);
+        // The logs of a pod are only kept for as long as the pod exists
+        let log_storage = provider_state.log_storage.clone();
+        let pod = self.pod.clone();
+        let result = tokio::task::spawn_blocking(move || log_storage.remove_pod(&pod)).await;
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Unable to remove logs of pod {}: {}", self.key.name(), e),
+            Err(e) => warn!("Unable to remove logs of pod {}: {}", self.key.name(), e),
+        }
+        if let Err(e) = termination::remove_pod(&provider_state.data_dir, &self.pod).await {
+            warn!(
+                "Unable to remove termination messages of pod {}: {}",
+                self.key.name(),
+                e
+            );
+        }
     }
 }
 
@@ -44,6 +71,8 @@ impl PodState {
         let run_context = ModuleRunContext {
             modules: Default::default(),
             volumes: Default::default(),
+            devices: Default::default(),
+            updated: Default::default(),
         };
         let key = PodKey::from(pod);
         PodState {
@@ -52,6 +81,8 @@ impl PodState {
             errors: 0,
             image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
             crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
+            started_at: None,
+            pod: pod.clone(),
         }
     }
 }
@@ -66,6 +97,13 @@ impl GenericPodState for PodState {
         let mut run_context = self.run_context.write().await;
         run_context.volumes = volumes;
     }
+    async fn set_devices(
+        &mut self,
+        devices: HashMap<String, kubelet::device_plugin::ContainerDevices>,
+    ) {
+        let mut run_context = self.run_context.write().await;
+        run_context.devices = devices;
+    }
     async fn backoff(&mut self, sequence: BackoffSequence) {
         let backoff_strategy = match sequence {
             BackoffSequence::ImagePull => &mut self.image_pull_backoff_strategy,
@@ -0,0 +1,26 @@
+use crate::{PodState, ProviderState};
+use kubelet::pod::state::prelude::*;
+
+/// The Pod was active for longer than its `activeDeadlineSeconds`, so its containers were stopped.
+#[derive(Default, Debug)]
+pub struct DeadlineExceeded;
+
+#[async_trait::async_trait]
+impl State<PodState> for DeadlineExceeded {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<ProviderState>,
+        _pod_state: &mut PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<PodState> {
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason("DeadlineExceeded")
+            .message("Pod was active on the node longer than the specified deadline")
+            .build())
+    }
+}
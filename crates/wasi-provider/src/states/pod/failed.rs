@@ -0,0 +1,34 @@
+use crate::{PodState, ProviderState};
+use kubelet::pod::state::prelude::*;
+
+/// Every container of the Pod has finished, and at least one of them failed.
+#[derive(Debug)]
+pub struct Failed {
+    message: String,
+}
+
+impl Failed {
+    pub fn new(message: String) -> Self {
+        Failed { message }
+    }
+}
+
+#[async_trait::async_trait]
+impl State<PodState> for Failed {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<ProviderState>,
+        _pod_state: &mut PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<PodState> {
+        Transition::Complete(Err(anyhow::anyhow!(self.message)))
+    }
+
+    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason("Error")
+            .message(&self.message)
+            .build())
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{PodState, ProviderState};
+use kubelet::pod::state::prelude::*;
+
+/// An init container failed and will not be restarted, so the Pod can't start.
+#[derive(Debug)]
+pub struct InitFailed {
+    message: String,
+}
+
+impl InitFailed {
+    pub fn new(message: String) -> Self {
+        InitFailed { message }
+    }
+}
+
+#[async_trait::async_trait]
+impl State<PodState> for InitFailed {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<ProviderState>,
+        _pod_state: &mut PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<PodState> {
+        Transition::Complete(Err(anyhow::anyhow!(self.message)))
+    }
+
+    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason("Init:Error")
+            .message(&self.message)
+            .build())
+    }
+}
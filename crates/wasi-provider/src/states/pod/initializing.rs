@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
-use log::{error, info};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
 
 use kubelet::backoff::BackoffStrategy;
 use kubelet::container::state::run_to_completion;
@@ -13,11 +14,17 @@ use crate::states::container::waiting::Waiting;
 use crate::states::container::ContainerState;
 use crate::{PodState, ProviderState};
 
+use super::init_failed::InitFailed;
 use super::starting::Starting;
 
+/// The Kubelet is running the Pod's init containers, one at a time and in order. Sidecars are left
+/// running once they have started, and the next init container is run alongside them.
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Starting, Error<crate::WasiProvider>)]
-pub struct Initializing;
+#[transition_to(Initializing, InitFailed, Starting, Error<crate::WasiProvider>)]
+pub struct Initializing {
+    /// The index of the init container to run next.
+    next: usize,
+}
 
 #[async_trait::async_trait]
 impl State<PodState> for Initializing {
@@ -30,57 +37,110 @@ impl State<PodState> for Initializing {
         let pod_rx = pod.clone();
         let pod = pod.latest();
 
+        let init_container = match pod.init_containers().into_iter().nth(self.next) {
+            Some(init_container) => init_container,
+            None => {
+                info!("Finished init containers for pod {:?}", pod.name());
+                pod_state.crash_loop_backoff_strategy.reset();
+                return Transition::next(self, Starting);
+            }
+        };
+
         let client = {
             let provider_state = provider_state.read().await;
             provider_state.client()
         };
 
-        for init_container in pod.init_containers() {
-            info!(
-                "Starting init container {:?} for pod {:?}",
-                init_container.name(),
-                pod.name()
-            );
+        info!(
+            "Starting init container {:?} for pod {:?}",
+            init_container.name(),
+            pod.name()
+        );
 
-            // Each new init container resets the CrashLoopBackoff timer.
-            pod_state.crash_loop_backoff_strategy.reset();
+        // Each new init container resets the CrashLoopBackoff timer.
+        pod_state.crash_loop_backoff_strategy.reset();
 
-            let initial_state = Waiting;
+        let initial_state = Waiting;
 
-            let container_key = ContainerKey::Init(init_container.name().to_string());
-            let container_state = ContainerState::new(
-                pod.clone(),
-                container_key.clone(),
-                Arc::clone(&pod_state.run_context),
-            );
+        let container_key = ContainerKey::Init(init_container.name().to_string());
+        let container_state = ContainerState::new(
+            pod.clone(),
+            container_key.clone(),
+            Arc::clone(&pod_state.run_context),
+        );
 
-            match run_to_completion(
-                &client,
-                initial_state,
-                // TODO: I think everything should be a SharedState to the same pod in the reflector.
-                Arc::clone(&provider_state),
-                container_state,
-                pod_rx.clone(),
-                container_key,
-            )
-            .await
-            {
-                Ok(_) => (),
-                Err(e) => {
-                    error!("Init container {} failed: {:?}", init_container.name(), e);
-                    return Transition::Complete(Err(anyhow::anyhow!(format!(
-                        "Init container {} failed",
-                        init_container.name()
-                    ))));
+        if pod.is_sidecar(init_container.name()) {
+            let (started_tx, started_rx) = oneshot::channel();
+            let container_state = container_state.notify_started(started_tx);
+            let task_provider = Arc::clone(&provider_state);
+            let task_pod = pod.clone();
+            tokio::task::spawn(async move {
+                let name = container_key.name();
+                // Sidecars are restarted whenever they exit, so they only finish once the pod is
+                // stopped
+                if let Err(e) = run_to_completion(
+                    &client,
+                    initial_state,
+                    task_provider,
+                    container_state,
+                    pod_rx,
+                    container_key,
+                )
+                .await
+                {
+                    warn!(
+                        "Sidecar container {} of pod {} failed: {:?}",
+                        name,
+                        task_pod.name(),
+                        e
+                    );
                 }
+            });
+            if started_rx.await.is_err() {
+                let message = format!(
+                    "Sidecar container {} failed to start",
+                    init_container.name()
+                );
+                error!("{}", message);
+                return Transition::next(self, InitFailed::new(message));
+            }
+            let next = Initializing {
+                next: self.next + 1,
+            };
+            return Transition::next(self, next);
+        }
+
+        match run_to_completion(
+            &client,
+            initial_state,
+            // TODO: I think everything should be a SharedState to the same pod in the reflector.
+            Arc::clone(&provider_state),
+            container_state,
+            pod_rx,
+            container_key,
+        )
+        .await
+        {
+            Ok(_) => {
+                let next = Initializing {
+                    next: self.next + 1,
+                };
+                Transition::next(self, next)
+            }
+            Err(e) => {
+                error!("Init container {} failed: {:?}", init_container.name(), e);
+                let next = InitFailed::new(format!(
+                    "Init container {} failed: {}",
+                    init_container.name(),
+                    e
+                ));
+                Transition::next(self, next)
             }
         }
-        info!("Finished init containers for pod {:?}", pod.name());
-        pod_state.crash_loop_backoff_strategy.reset();
-        Transition::next(self, Starting)
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pmeod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Running, "Initializing"))
+    async fn status(&self, _pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        let reason = format!("Init:{}/{}", self.next, pod.init_containers().len());
+        Ok(make_status(Phase::Pending, &reason))
     }
 }
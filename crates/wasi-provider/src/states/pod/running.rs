@@ -1,22 +1,39 @@
+use std::collections::HashSet;
+use std::time::Instant as StdInstant;
+
+use futures::StreamExt;
 use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
+use tracing::{info, warn};
 
+use kubelet::container::state::run_to_completion;
+use kubelet::container::{patch_container_status, Container, ContainerKey, Status};
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::{reason, Changes, EventRecorder, EventType, PodKey};
+use kubelet::secret::RegistryAuthResolver;
 use kubelet::state::common::error::Error;
 use kubelet::state::common::GenericProviderState;
+use kubelet::store::Store;
 
 use super::completed::Completed;
-use crate::fail_fatal;
-use crate::{PodState, ProviderState};
+use super::deadline_exceeded::DeadlineExceeded;
+use super::failed::Failed;
+use crate::states::container::waiting::Waiting;
+use crate::states::container::ContainerState;
+use crate::{ModuleRunContext, PodState, ProviderState};
+
+/// The result of one of the pod's containers running to completion, along with its name.
+pub(crate) type ContainerResult = (String, anyhow::Result<()>);
 
 /// The Kubelet is running the Pod.
 #[derive(Debug, TransitionTo)]
-#[transition_to(Completed, Error<crate::WasiProvider>)]
+#[transition_to(Completed, Failed, DeadlineExceeded, Error<crate::WasiProvider>)]
 pub struct Running {
-    rx: Receiver<anyhow::Result<()>>,
+    rx: Receiver<ContainerResult>,
 }
 
 impl Running {
-    pub fn new(rx: Receiver<anyhow::Result<()>>) -> Self {
+    pub(crate) fn new(rx: Receiver<ContainerResult>) -> Self {
         Running { rx }
     }
 }
@@ -26,30 +43,81 @@ impl State<PodState> for Running {
     async fn next(
         mut self: Box<Self>,
         provider_state: SharedState<ProviderState>,
-        _pod_state: &mut PodState,
+        pod_state: &mut PodState,
         pod: Manifest<Pod>,
     ) -> Transition<PodState> {
-        let pod = pod.latest();
+        let mut updates = pod.clone();
+        let manifest = pod;
+        let pod = manifest.latest();
+        kubelet::metrics::observe_pod_start(&pod);
 
         let mut completed = 0;
+        let mut failed = vec![];
         let total_containers = pod.containers().len();
+        let mut deadline = active_deadline(&pod, pod_state);
+        let mut previous = pod.clone();
+
+        // Ephemeral containers are added to the pod while it runs, such as by `kubectl debug`
+        let mut ephemeral = HashSet::new();
+        start_ephemeral_containers(&provider_state, pod_state, &manifest, &mut ephemeral);
 
-        while let Some(result) = self.rx.recv().await {
-            match result {
-                Ok(()) => {
-                    completed += 1;
-                    if completed == total_containers {
-                        return Transition::next(self, Completed);
+        loop {
+            let result = tokio::select! {
+                result = next_result(&mut self.rx, deadline) => result,
+                Some(_) = updates.next() => {
+                    let latest = manifest.latest();
+                    let changes = Changes::between(&previous, &latest);
+                    if changes.active_deadline {
+                        info!(
+                            "Pod {} active deadline changed to {:?}",
+                            latest.name(),
+                            latest.active_deadline()
+                        );
+                        deadline = active_deadline(&latest, pod_state);
                     }
+                    restart_updated_containers(&provider_state, pod_state, &latest, &changes);
+                    previous = latest;
+                    start_ephemeral_containers(
+                        &provider_state,
+                        pod_state,
+                        &manifest,
+                        &mut ephemeral,
+                    );
+                    continue;
                 }
-                Err(e) => {
-                    // Stop remaining containers;
+            };
+            let result = match result {
+                Some(result) => result,
+                None => {
+                    info!(
+                        "Pod {} exceeded its active deadline, stopping it",
+                        pod.name()
+                    );
                     {
-                        let provider = provider_state.write().await;
+                        let provider = provider_state.read().await;
                         provider.stop(&pod).await.ok();
                     }
-                    fail_fatal!(e);
+                    return Transition::next(self, DeadlineExceeded);
                 }
+            };
+            let result = match result {
+                Some(result) => result,
+                None => break,
+            };
+            // A container that fails doesn't stop the others, which keep running until they
+            // finish too, as they would on any other node
+            if let (name, Err(e)) = result {
+                warn!("Pod {} container {} failed: {:?}", pod.name(), name, e);
+                failed.push(format!("{} ({})", name, e));
+            }
+            completed += 1;
+            if completed == total_containers {
+                stop_sidecars(&provider_state, &pod).await;
+                if failed.is_empty() {
+                    return Transition::next(self, Completed);
+                }
+                let message = format!("Containers failed: {}", failed.join(", "));
+                return Transition::next(self, Failed::new(message));
             }
         }
         Transition::next(
@@ -65,3 +133,332 @@ impl State<PodState> for Running {
         Ok(make_status(Phase::Running, "Running"))
     }
 }
+
+/// When the pod's active deadline passes, if it has one. The deadline counts from when the pod
+/// started.
+fn active_deadline(pod: &Pod, pod_state: &PodState) -> Option<Instant> {
+    pod.active_deadline().map(|deadline| {
+        let started_at = pod_state.started_at.unwrap_or_else(std::time::Instant::now);
+        Instant::from_std(started_at + deadline)
+    })
+}
+
+/// Waits for the next of the pod's containers to complete, returning `None` if the pod's deadline
+/// passes first.
+async fn next_result(
+    rx: &mut Receiver<ContainerResult>,
+    deadline: Option<Instant>,
+) -> Option<Option<ContainerResult>> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, rx.recv()).await.ok(),
+        None => Some(rx.recv().await),
+    }
+}
+
+/// Starts the ephemeral containers that have been added to the pod since it was last checked.
+/// Ephemeral containers only ever run once, so those that already have a status, such as from
+/// before the kubelet restarted, aren't started again.
+fn start_ephemeral_containers(
+    provider_state: &SharedState<ProviderState>,
+    pod_state: &PodState,
+    manifest: &Manifest<Pod>,
+    started: &mut HashSet<String>,
+) {
+    let pod = manifest.latest();
+    for container in pod.ephemeral_containers() {
+        let key = ContainerKey::Ephemeral(container.name().to_owned());
+        if started.contains(container.name()) || pod.container_status_index(&key).is_some() {
+            continue;
+        }
+        started.insert(container.name().to_owned());
+        info!(
+            "Starting ephemeral container {} for pod {}",
+            container.name(),
+            pod.name()
+        );
+        tokio::spawn(run_ephemeral_container(
+            provider_state.clone(),
+            pod_state.run_context.clone(),
+            manifest.clone(),
+            container,
+            key,
+        ));
+    }
+}
+
+/// Pulls the module of an ephemeral container and runs it to completion alongside the pod's
+/// other containers, with the pod's volumes. Ephemeral containers don't count towards whether the
+/// pod has completed.
+async fn run_ephemeral_container(
+    provider_state: SharedState<ProviderState>,
+    run_context: SharedState<ModuleRunContext>,
+    manifest: Manifest<Pod>,
+    container: Container,
+    key: ContainerKey,
+) {
+    let pod = manifest.latest();
+    let (client, store, events) = {
+        let provider_state = provider_state.read().await;
+        (
+            provider_state.client(),
+            provider_state.store(),
+            provider_state.events.clone(),
+        )
+    };
+    let module = match fetch_module(&client, &*store, &events, &pod, &container).await {
+        Ok(module) => module,
+        Err(e) => {
+            let message = format!(
+                "Pod {} ephemeral container {} failed to pull its image: {:?}",
+                pod.name(),
+                container.name(),
+                e
+            );
+            warn!("{}", message);
+            record(
+                &events,
+                &pod,
+                &container,
+                EventType::Warning,
+                reason::FAILED,
+                &message,
+            )
+            .await;
+            let api = kube::Api::namespaced(client, pod.namespace());
+            if let Err(e) =
+                patch_container_status(&api, &pod, &key, &Status::terminated(&message, true)).await
+            {
+                warn!(
+                    "Unable to report status of ephemeral container {} of pod {}: {:?}",
+                    container.name(),
+                    pod.name(),
+                    e
+                );
+            }
+            return;
+        }
+    };
+    run_context
+        .write()
+        .await
+        .modules
+        .insert(container.name().to_owned(), module);
+
+    let container_state = ContainerState::new(pod.clone(), key.clone(), run_context);
+    let result = run_to_completion(
+        &client,
+        Waiting,
+        provider_state,
+        container_state,
+        manifest,
+        key,
+    )
+    .await;
+    if let Err(e) = result {
+        warn!(
+            "Pod {} ephemeral container {} failed: {:?}",
+            pod.name(),
+            container.name(),
+            e
+        );
+    }
+}
+
+/// Restarts the containers whose image was changed, with the module of their new image. Init
+/// containers have already completed unless they are sidecars, so only sidecars are restarted.
+fn restart_updated_containers(
+    provider_state: &SharedState<ProviderState>,
+    pod_state: &PodState,
+    pod: &Pod,
+    changes: &Changes,
+) {
+    for key in &changes.images {
+        if key.is_init() && !pod.is_sidecar(&key.name()) {
+            continue;
+        }
+        if let Some(container) = pod.find_container(key) {
+            tokio::spawn(restart_with_image(
+                provider_state.clone(),
+                pod_state.run_context.clone(),
+                pod.clone(),
+                container,
+            ));
+        }
+    }
+}
+
+/// Pulls the module of a container's new image and restarts the container with it. The container
+/// is left running its old module if the new one can't be pulled.
+async fn restart_with_image(
+    provider_state: SharedState<ProviderState>,
+    run_context: SharedState<ModuleRunContext>,
+    pod: Pod,
+    container: Container,
+) {
+    let (client, store, events, handle) = {
+        let provider_state = provider_state.read().await;
+        let handles = provider_state.handles.read().await;
+        let handle = handles.get(&PodKey::from(&pod)).cloned();
+        (
+            provider_state.client(),
+            provider_state.store(),
+            provider_state.events.clone(),
+            handle,
+        )
+    };
+    let module = match fetch_module(&client, &*store, &events, &pod, &container).await {
+        Ok(module) => module,
+        Err(e) => {
+            let message = format!(
+                "Pod {} container {} failed to pull its new image: {:?}",
+                pod.name(),
+                container.name(),
+                e
+            );
+            warn!("{}", message);
+            record(
+                &events,
+                &pod,
+                &container,
+                EventType::Warning,
+                reason::FAILED,
+                &message,
+            )
+            .await;
+            return;
+        }
+    };
+    {
+        let mut run_context = run_context.write().await;
+        run_context
+            .modules
+            .insert(container.name().to_owned(), module);
+        run_context.updated.insert(container.name().to_owned());
+    }
+    let handle = match handle {
+        Some(handle) => handle,
+        None => return,
+    };
+    let message = format!(
+        "Container {} image changed, it will be killed and re-created.",
+        container.name()
+    );
+    record(
+        &events,
+        &pod,
+        &container,
+        EventType::Normal,
+        reason::KILLING,
+        &message,
+    )
+    .await;
+    if let Err(e) = handle.stop_container(container.name()).await {
+        warn!(
+            "Unable to stop pod {} container {} to restart it: {:?}",
+            pod.name(),
+            container.name(),
+            e
+        );
+    }
+}
+
+/// Pulls the module of a container that wasn't pulled along with the rest of the pod's.
+async fn fetch_module(
+    client: &kube::Client,
+    store: &(dyn Store + Send + Sync),
+    events: &EventRecorder,
+    pod: &Pod,
+    container: &Container,
+) -> anyhow::Result<Vec<u8>> {
+    let image_ref = container
+        .image()?
+        .ok_or_else(|| anyhow::anyhow!("container has no image"))?;
+    let pull_policy = container.effective_pull_policy()?;
+    let auth = RegistryAuthResolver::new(client.clone(), pod)
+        .resolve_registry_auth(&image_ref)
+        .await?;
+    let message = format!("Pulling image \"{}\"", image_ref);
+    record(
+        events,
+        pod,
+        container,
+        EventType::Normal,
+        reason::PULLING,
+        &message,
+    )
+    .await;
+    let start = StdInstant::now();
+    let module = store.get(&image_ref, pull_policy, &auth).await?;
+    let message = format!(
+        "Successfully pulled image \"{}\" in {:.3}s ({} bytes)",
+        image_ref,
+        start.elapsed().as_secs_f64(),
+        module.len()
+    );
+    record(
+        events,
+        pod,
+        container,
+        EventType::Normal,
+        reason::PULLED,
+        &message,
+    )
+    .await;
+    Ok(module)
+}
+
+/// Stops the pod's sidecars once all of its app containers have finished, as they would otherwise
+/// keep it running for good.
+async fn stop_sidecars(provider_state: &SharedState<ProviderState>, pod: &Pod) {
+    let sidecars: Vec<Container> = pod
+        .init_containers()
+        .into_iter()
+        .filter(|container| pod.is_sidecar(container.name()))
+        .collect();
+    if sidecars.is_empty() {
+        return;
+    }
+    let (handle, events) = {
+        let provider_state = provider_state.read().await;
+        let handles = provider_state.handles.read().await;
+        let handle = handles.get(&PodKey::from(pod)).cloned();
+        (handle, provider_state.events.clone())
+    };
+    let handle = match handle {
+        Some(handle) => handle,
+        None => return,
+    };
+    for sidecar in &sidecars {
+        let message = format!("Stopping container {}", sidecar.name());
+        record(
+            &events,
+            pod,
+            sidecar,
+            EventType::Normal,
+            reason::KILLING,
+            &message,
+        )
+        .await;
+    }
+    // The app containers have all finished, so only the sidecars are left to stop. The pod is
+    // marked as stopped, so they aren't restarted once they exit
+    if let Err(e) = handle.stop().await {
+        warn!("Unable to stop sidecars of pod {}: {:?}", pod.name(), e);
+    }
+}
+
+async fn record(
+    events: &EventRecorder,
+    pod: &Pod,
+    container: &Container,
+    event_type: EventType,
+    reason: &str,
+    message: &str,
+) {
+    if let Err(e) = events
+        .record(pod, Some(container.name()), event_type, reason, message)
+        .await
+    {
+        warn!("Unable to record event for pod {}: {:?}", pod.name(), e);
+    }
+}
@@ -1,10 +1,13 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
-use log::info;
+use tracing::info;
 
 use kubelet::container::state::run_to_completion;
 use kubelet::container::ContainerKey;
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::{Handle as PodHandle, PodKey};
 use kubelet::state::common::GenericProviderState;
 
 use crate::states::container::waiting::Waiting;
@@ -30,6 +33,21 @@ impl State<PodState> for Starting {
         let pod = pod.latest();
 
         info!("Starting containers for pod {:?}.", pod.name());
+        pod_state.started_at.get_or_insert_with(Instant::now);
+        {
+            // Create the pod handle up front, so that containers can tell whether the pod has
+            // been stopped before deciding to restart. A handle left by a previous, stopped run of
+            // the pod is replaced.
+            let provider_state = provider_state.read().await;
+            let mut handles = provider_state.handles.write().await;
+            let key = PodKey::from(&pod);
+            if handles.get(&key).map_or(true, |handle| handle.is_stopped()) {
+                handles.insert(
+                    key,
+                    Arc::new(PodHandle::new(HashMap::new(), pod.clone(), None)),
+                );
+            }
+        }
         let containers = pod.containers();
         let (tx, rx) = tokio::sync::mpsc::channel(containers.len());
         for container in containers {
@@ -43,6 +61,7 @@ impl State<PodState> for Starting {
             let task_provider = Arc::clone(&provider_state);
             let mut task_tx = tx.clone();
             let task_pod = pod_rx.clone();
+            let name = container.name().to_owned();
             tokio::task::spawn(async move {
                 let client = {
                     let provider_state = task_provider.read().await;
@@ -58,7 +77,7 @@ impl State<PodState> for Starting {
                     container_key,
                 )
                 .await;
-                task_tx.send(result).await
+                task_tx.send((name, result)).await
             });
         }
         info!("All containers started for pod {:?}.", pod.name());
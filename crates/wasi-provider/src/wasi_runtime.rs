@@ -1,43 +1,367 @@
 use anyhow::bail;
-use futures::task;
-use log::{debug, error, info, trace};
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::future::Future;
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
 
-use tempfile::NamedTempFile;
-use tokio::sync::mpsc::Sender;
+use cap_std::fs::Dir;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
 use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
-use wasi_common::preopen_dir;
-use wasmtime::InterruptHandle;
-use wasmtime_wasi::old::snapshot_0::Wasi as WasiUnstable;
-use wasmtime_wasi::{Wasi, WasiCtxBuilder};
+use wasi_cap_std_sync::WasiCtxBuilder;
+use wasi_common::dir::DirCaps;
+use wasi_common::file::{FileCaps, WasiFile};
+use wasmtime::{
+    InstanceAllocationStrategy, InstanceLimits, InterruptHandle, ModuleLimits,
+    PoolingAllocationStrategy, ResourceLimiter, Val, ValType,
+};
+use wasmtime_wasi::snapshots::preview_0::Wasi as WasiUnstable;
+use wasmtime_wasi::Wasi;
 
 use kubelet::container::Handle as ContainerHandle;
 use kubelet::container::Status;
-use kubelet::handle::StopHandler;
+use kubelet::exec::copy::{self, Mount};
+use kubelet::exec::{
+    CommandOptions, ExecOutput, ExecResult, ExecStream, Sender as ExecSender, Stdin,
+};
+use kubelet::handle::{CheckpointHandler, ExecHandler, StopHandler};
+use kubelet::log::storage::{LogHandleFactory, LogSink, LogStorage};
+use kubelet::log::{format_record, take_lines, HandleFactory, LogStream};
+use kubelet::node::problem::{self, Problem};
+use kubelet::pod::Pod;
+use kubelet::provider::ProviderError;
+
+use crate::client::{AllowedHosts, Client, CLIENT_MODULE};
+use crate::config::{OptLevel, OutboundRule, WasiConfig};
+use crate::cpu_time::CpuTime;
+use crate::http::{self, Bridge, Requests, HTTP_MODULE};
+use crate::module_cache::ModuleCache;
+use crate::sandbox::Sandbox;
+use crate::snapshot::{self, CloseOnDrop, Requests as SnapshotRequests};
+use crate::sockets::{Sockets, SOCKETS_MODULE};
+
+/// A request to call one of a module's exported functions, along with a channel for sending back
+/// the result.
+struct ExecRequest {
+    command: Vec<String>,
+    response: oneshot::Sender<anyhow::Result<ExecResult>>,
+}
 
 pub struct Runtime {
-    handle: JoinHandle<anyhow::Result<()>>,
+    /// The task running the module, until it has been waited on
+    handle: Option<JoinHandle<anyhow::Result<()>>>,
+    /// Whether the module has stopped running, whichever way it stopped
+    exited: Arc<AtomicBool>,
     interrupt_handle: InterruptHandle,
+    /// How far the module has been asked to stop, shared with the thread running it
+    stop: Arc<StopState>,
+    /// Channel to the thread running the module for exec requests. This is taken when the
+    /// runtime is stopped so that the thread stops waiting for requests
+    exec_sender: Mutex<Option<UnboundedSender<ExecRequest>>>,
+    /// Requests to snapshot the module, which the thread running it takes once it is interrupted
+    snapshots: SnapshotRequests,
+    /// The write end of the stdin that exec sessions feed
+    stdin: StdinPipe,
+    /// The write end of the module's own stdin, which clients attached to the container feed, if
+    /// the container asked for one
+    attach_stdin: Option<StdinPipe>,
+    /// Whether the module's own stdin is closed once the first client attached to it goes away
+    stdin_once: bool,
+    /// Opens readers of the log that output from the module is written to
+    log: LogHandleFactory,
+    /// The directories the module can see, which `kubectl cp` copies to and from
+    mounts: Vec<Mount>,
+}
+
+/// How often output written by the module is checked for while an exec request runs
+const EXEC_OUTPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Runtime {
+    /// Sends a request to call an export to the thread running the module, returning a channel
+    /// for the result.
+    fn send_exec(
+        &self,
+        command: Vec<String>,
+    ) -> anyhow::Result<oneshot::Receiver<anyhow::Result<ExecResult>>> {
+        let (tx, rx) = oneshot::channel();
+        let sender = self.exec_sender.lock().unwrap();
+        let sender = sender
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("module has been stopped"))?;
+        sender
+            .send(ExecRequest {
+                command,
+                response: tx,
+            })
+            .map_err(|_| anyhow::anyhow!("module is not accepting exec requests"))?;
+        Ok(rx)
+    }
 }
 
 #[async_trait::async_trait]
 impl StopHandler for Runtime {
-    async fn stop(&mut self) -> anyhow::Result<()> {
+    async fn stop(&mut self) -> Result<(), ProviderError> {
+        // WASI has no signals, so a running module is asked to exit in the only way it can tell.
+        // Reactor modules stop once they are no longer sent exec requests, after finishing the
+        // ones they have been sent. Modules running `_start` have the stdin attached clients
+        // write to closed, if they have one, and are otherwise left to finish. Whatever is still
+        // running once the pod's grace period is over is killed by the kubelet
+        self.exec_sender.lock().unwrap().take();
+        self.stop.stopping.store(true, Ordering::SeqCst);
+        if let Some(pipe) = &self.attach_stdin {
+            pipe.lock().unwrap().take();
+        }
+        Ok(())
+    }
+
+    async fn kill(&mut self) -> Result<(), ProviderError> {
+        // Interrupts take effect as soon as the module is running WebAssembly code, so a module
+        // blocked in a host call stops once that call returns
+        self.stop.killed.store(true, Ordering::SeqCst);
         self.interrupt_handle.interrupt();
+        self.exec_sender.lock().unwrap().take();
         Ok(())
     }
 
-    async fn wait(&mut self) -> anyhow::Result<()> {
-        (&mut self.handle).await??;
+    fn has_exited(&self) -> Option<bool> {
+        Some(self.exited.load(Ordering::SeqCst))
+    }
+
+    async fn wait(&mut self) -> Result<(), ProviderError> {
+        // The task is only taken once it has completed, so that waiting can be cancelled and
+        // resumed, such as when waiting for the module to stop times out
+        if let Some(handle) = &mut self.handle {
+            let result = handle.await;
+            self.handle = None;
+            result??;
+        }
         Ok(())
     }
 }
 
+#[async_trait::async_trait]
+impl CheckpointHandler for Runtime {
+    async fn checkpoint(&self, dir: &Path) -> Result<(), ProviderError> {
+        let response = self.snapshots.request(dir.to_owned())?;
+        // The module is snapshotted by the thread running it once the interrupt stops it, or
+        // right away if it has already stopped running WebAssembly code
+        self.interrupt_handle.interrupt();
+        self.exec_sender.lock().unwrap().take();
+        Ok(response
+            .await
+            .map_err(|_| anyhow::anyhow!("module stopped before it could be checkpointed"))??)
+    }
+}
+
+#[async_trait::async_trait]
+impl ExecHandler for Runtime {
+    async fn exec(&self, options: CommandOptions) -> Result<ExecResult, ProviderError> {
+        Ok(self
+            .send_exec(options.command)?
+            .await
+            .map_err(|_| anyhow::anyhow!("module exited before the command completed"))??)
+    }
+
+    async fn exec_streaming(
+        &self,
+        options: CommandOptions,
+        stdin: Stdin,
+    ) -> Result<ExecStream, ProviderError> {
+        // Anything the module writes while the command runs is streamed back, so start reading
+        // from the current end of its output
+        let log = self.log.clone();
+        let mut output = tokio::task::spawn_blocking(move || log.new_handle()).await?;
+        output.seek(std::io::SeekFrom::End(0)).await?;
+
+        // Pipe anything the client sends into the module's stdin while the command runs
+        tokio::spawn(pump_stdin(self.stdin.clone(), stdin));
+
+        let mut response = self.send_exec(options.command)?;
+        let (mut tx, rx) = tokio::sync::mpsc::channel(EXEC_OUTPUT_BUFFER_SIZE);
+        tokio::spawn(async move {
+            let mut pending = Vec::new();
+            let result = loop {
+                forward_output(&mut output, &mut pending, &mut tx).await;
+                tokio::select! {
+                    result = &mut response => break result,
+                    _ = tokio::time::delay_for(EXEC_OUTPUT_POLL_INTERVAL) => (),
+                }
+            };
+            forward_output(&mut output, &mut pending, &mut tx).await;
+            let result = result.unwrap_or_else(|_| {
+                Err(anyhow::anyhow!(
+                    "module exited before the command completed"
+                ))
+            });
+            let (output, code) = match result {
+                Ok(result) if result.output.is_empty() => (None, result.exit_code),
+                Ok(result) => (
+                    Some(ExecOutput::Stdout(
+                        format!("{}\n", result.output).into_bytes(),
+                    )),
+                    result.exit_code,
+                ),
+                Err(e) => (Some(ExecOutput::Stderr(format!("{}\n", e).into_bytes())), 1),
+            };
+            // The client may have gone away, in which case there is nobody left to tell
+            if let Some(output) = output {
+                if tx.send(output).await.is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send(ExecOutput::Exit(code)).await;
+        });
+        Ok(Box::pin(rx))
+    }
+
+    async fn attach_stdin(&self, stdin: Stdin) -> Result<(), ProviderError> {
+        let pipe = self.attach_stdin.clone().ok_or_else(|| {
+            ProviderError::InvalidRequest("container does not have stdin enabled".to_owned())
+        })?;
+        let stdin_once = self.stdin_once;
+        tokio::spawn(async move {
+            if let Err(e) = pump_stdin(pipe.clone(), stdin).await {
+                debug!("Unable to write to module stdin: {}", e);
+            }
+            if stdin_once {
+                // Closing the pipe lets the module see the end of its input
+                pipe.lock().unwrap().take();
+            }
+        });
+        Ok(())
+    }
+
+    /// Modules have no `tar` to run, so files are copied straight from the directories they
+    /// see.
+    async fn copy_from(&self, path: &Path, sender: ExecSender) -> Result<(), ProviderError> {
+        let (host_path, _) = copy::resolve(&self.mounts, path)?;
+        Ok(copy::send_archive(host_path, path, sender).await?)
+    }
+
+    async fn copy_to(&self, dir: &Path, stdin: &mut Stdin) -> Result<(), ProviderError> {
+        let (host_dir, mount) = copy::resolve(&self.mounts, dir)?;
+        if mount.read_only {
+            return Err(ProviderError::InvalidRequest(format!(
+                "{} is read-only",
+                dir.display()
+            )));
+        }
+        Ok(copy::receive_archive(stdin, host_dir).await?)
+    }
+}
+
+/// The number of output chunks buffered for an exec request before reading output pauses
+const EXEC_OUTPUT_BUFFER_SIZE: usize = 16;
+
+/// Sends any new lines in the module's output to the exec stream. Incomplete lines are kept in
+/// `pending` until the rest of them has been written.
+async fn forward_output<R: AsyncRead + Unpin>(
+    output: &mut R,
+    pending: &mut Vec<u8>,
+    tx: &mut tokio::sync::mpsc::Sender<ExecOutput>,
+) {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        match output.read(&mut buf).await {
+            Ok(0) => return,
+            Ok(n) => {
+                pending.extend_from_slice(&buf[..n]);
+                for chunk in take_lines(pending) {
+                    if tx.send(chunk).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("Unable to read module output: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Copies output written by the module to one of its streams into its log, recording each line
+/// with the time it was written and the stream it was written to. This runs until every copy of
+/// the pipe's write end has been closed.
+fn record_output(
+    output: os_pipe::PipeReader,
+    stream: LogStream,
+    log: Arc<Mutex<Box<dyn LogSink>>>,
+) -> std::io::Result<()> {
+    let mut output = std::io::BufReader::new(output);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if output.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        let record = format_record(stream, chrono::Utc::now(), &line);
+        // Both streams share the log, so each record is written whole while holding it
+        log.lock().unwrap().write_record(&record)?;
+    }
+}
+
+/// Starts copying output written to a new pipe into the log, returning the write end of the pipe.
+fn spawn_output_recorder(
+    stream: LogStream,
+    log: Arc<Mutex<Box<dyn LogSink>>>,
+) -> std::io::Result<std::fs::File> {
+    let (read, write) = os_pipe::pipe()?;
+    std::thread::spawn(move || {
+        if let Err(e) = record_output(read, stream, log) {
+            error!("Unable to write module {} to log: {}", stream.as_str(), e);
+        }
+    });
+    Ok(into_file(write))
+}
+
+/// The write ends of the pipes that a module's stdout and stderr are recorded from.
+struct Output {
+    stdout: std::fs::File,
+    stderr: std::fs::File,
+}
+
+impl Output {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Output {
+            stdout: self.stdout.try_clone()?,
+            stderr: self.stderr.try_clone()?,
+        })
+    }
+}
+
+/// The write end of a stdin pipe, which is `None` once it has been closed.
+type StdinPipe = Arc<Mutex<Option<os_pipe::PipeWriter>>>;
+
+/// Writes everything received on `stdin` into a stdin pipe of the module. Writing to the pipe can
+/// block if the module isn't reading, so it happens on the blocking pool.
+async fn pump_stdin(pipe: StdinPipe, mut stdin: Stdin) -> anyhow::Result<()> {
+    while let Some(data) = stdin.recv().await {
+        let pipe = pipe.clone();
+        tokio::task::spawn_blocking(move || match pipe.lock().unwrap().as_mut() {
+            Some(pipe) => pipe.write_all(&data),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "stdin has been closed",
+            )),
+        })
+        .await??;
+    }
+    Ok(())
+}
+
 /// WasiRuntime provides a WASI compatible runtime. A runtime should be used for
 /// each "instance" of a process and can be passed to a thread pool for running
 pub struct WasiRuntime {
@@ -45,12 +369,37 @@ pub struct WasiRuntime {
     name: String,
     /// Data needed for the runtime
     data: Arc<Data>,
-    /// The tempfile that output from the wasmtime process writes to
-    output: Arc<NamedTempFile>,
+    /// Where a new log is started each time the runtime is started
+    log_storage: Arc<dyn LogStorage>,
+    /// The pod the runtime's container belongs to
+    pod: Pod,
     /// A channel to send status updates on the runtime
     status_sender: Sender<Status>,
+    /// The memory the module uses, and the most it may use if it has a limit
+    memory: MemoryLimit,
+    /// The CPU time used by the threads running the module
+    cpu_time: CpuTime,
+    /// The most CPU, in millicores, that the module may use, if the engine counts fuel and the
+    /// module has a CPU limit
+    cpu_limit: Option<u64>,
+    /// Where the compiled module is cached, if anywhere
+    module_cache: Option<ModuleCache>,
+    /// The engine the module is compiled and run with, which is shared by all modules
+    engine: Engine,
+    /// Whether the module gets a stdin that clients can attach to, and whether it is closed once
+    /// the first client goes away
+    stdin: Option<bool>,
+    /// The ports that HTTP requests are passed to the module from
+    http_ports: Vec<u16>,
+    /// The pod's network namespace, which the ports are listened on in, if the pod has one
+    netns: Option<PathBuf>,
+    /// The snapshot the module is restored from when it starts, if any
+    restore: Option<PathBuf>,
+    /// What of the host the threads running the module are kept to, if they are sandboxed
+    sandbox: Option<Arc<Sandbox>>,
 }
 
+#[derive(Clone)]
 struct Data {
     /// binary module data to be run as a wasm module
     module_data: Vec<u8>,
@@ -62,18 +411,12 @@ struct Data {
     /// (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     /// the same path will be allowed in the runtime
     dirs: HashMap<PathBuf, Option<PathBuf>>,
-}
-
-/// Holds our tempfile handle.
-pub struct HandleFactory {
-    temp: Arc<NamedTempFile>,
-}
-
-impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
-    /// Creates `tokio::fs::File` on demand for log reading.
-    fn new_handle(&self) -> tokio::fs::File {
-        tokio::fs::File::from_std(self.temp.reopen().unwrap())
-    }
+    /// the local file system paths in `dirs` that the module may only read from
+    read_only_dirs: HashSet<PathBuf>,
+    /// the destinations the module may connect to through the sockets shim, if it may use it
+    allowed_outbound: Option<Vec<OutboundRule>>,
+    /// the hosts the module may call through the client functions, if it may use them
+    allowed_hosts: Option<AllowedHosts>,
 }
 
 impl WasiRuntime {
@@ -87,27 +430,21 @@ impl WasiRuntime {
     /// * `dirs` - a map of local file system paths to optional path names in the runtime
     ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     ///     the same path will be allowed in the runtime
-    /// * `log_dir` - location for storing logs
-    pub async fn new<L: AsRef<Path> + Send + Sync + 'static>(
+    /// * `log_storage` - where the container's logs are kept
+    /// * `pod` - the pod the container belongs to
+    /// * `engine` - the engine shared by all modules
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
         name: String,
         module_data: Vec<u8>,
         env: HashMap<String, String>,
         args: Vec<String>,
         dirs: HashMap<PathBuf, Option<PathBuf>>,
-        log_dir: L,
+        log_storage: Arc<dyn LogStorage>,
+        pod: Pod,
         status_sender: Sender<Status>,
+        engine: Engine,
     ) -> anyhow::Result<Self> {
-        let temp = tokio::task::spawn_blocking(move || -> anyhow::Result<NamedTempFile> {
-            Ok(NamedTempFile::new_in(log_dir)?)
-        })
-        .await??;
-
-        // We need to use named temp file because we need multiple file handles
-        // and if we are running in the temp dir, we run the possibility of the
-        // temp file getting cleaned out from underneath us while running. If we
-        // think it necessary, we can make these permanent files with a cleanup
-        // loop that runs elsewhere. These will get deleted when the reference
-        // is dropped
         Ok(WasiRuntime {
             name,
             data: Arc::new(Data {
@@ -115,97 +452,281 @@ impl WasiRuntime {
                 env,
                 args,
                 dirs,
+                read_only_dirs: HashSet::new(),
+                allowed_outbound: None,
+                allowed_hosts: None,
             }),
-            output: Arc::new(temp),
+            log_storage,
+            pod,
             status_sender,
+            memory: MemoryLimit::new(None),
+            cpu_time: CpuTime::default(),
+            cpu_limit: None,
+            module_cache: None,
+            engine,
+            stdin: None,
+            http_ports: vec![],
+            netns: None,
+            restore: None,
+            sandbox: None,
         })
     }
 
-    pub async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
-        let temp = self.output.clone();
-        // Because a reopen is blocking, run in a blocking task to get new
-        // handles to the tempfile
-        let output_write = tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
-            Ok(temp.reopen()?)
-        })
-        .await??;
+    /// Limits the memory the module may use to the given number of bytes. A module that fails
+    /// because it couldn't grow its memory past the limit is reported as OOMKilled.
+    pub fn with_memory_limit(mut self, bytes: u64) -> Self {
+        self.memory = MemoryLimit::new(Some(bytes));
+        self
+    }
+
+    /// Limits the CPU the module may use to the given number of millicores, which the engine must
+    /// count fuel for. Like containers the kernel runs, a module that reaches its limit is held
+    /// back until it is within it again rather than stopped.
+    pub fn with_cpu_limit(mut self, millis: u64) -> Self {
+        self.cpu_limit = Some(millis);
+        self
+    }
+
+    /// Caches the compiled module in `module_cache`, so that it doesn't have to be compiled each
+    /// time the module is started.
+    pub fn with_module_cache(mut self, module_cache: ModuleCache) -> Self {
+        self.module_cache = Some(module_cache);
+        self
+    }
+
+    /// Gives the module the given directories, out of those it was created with, without the
+    /// rights to change what is in them.
+    pub fn with_read_only_dirs(mut self, dirs: HashSet<PathBuf>) -> Self {
+        self.data_mut().read_only_dirs = dirs;
+        self
+    }
+
+    /// Lets the module import the sockets shim, through which it may connect to the
+    /// destinations that `allowed` allows.
+    pub fn with_outbound_network(mut self, allowed: Vec<OutboundRule>) -> Self {
+        self.data_mut().allowed_outbound = Some(allowed);
+        self
+    }
+
+    /// Lets the module make HTTP requests to, and resolve the names of, the given hosts through
+    /// the functions of the `krustlet` import module.
+    pub(crate) fn with_allowed_hosts(mut self, allowed: AllowedHosts) -> Self {
+        self.data_mut().allowed_hosts = Some(allowed);
+        self
+    }
 
-        let (interrupt_handle, handle) = self.spawn_wasmtime(output_write).await?;
+    /// The data to change for the runs of the module started from now on. The data is only shared
+    /// with the module's instances once the runtime is started, so it is only copied if the
+    /// runtime is changed after that, leaving the running instances with the data they started
+    /// with.
+    fn data_mut(&mut self) -> &mut Data {
+        Arc::make_mut(&mut self.data)
+    }
+
+    /// Gives the module a stdin that clients attached to the container can write to. If `once` is
+    /// true, stdin is closed when the first client to attach goes away.
+    ///
+    /// Only modules with a `_start` export read this stdin, as reactors only run during exec.
+    pub fn with_stdin(mut self, once: bool) -> Self {
+        self.stdin = Some(once);
+        self
+    }
+
+    /// Passes the HTTP requests made on the node's `ports` to the module, which takes them through
+    /// the `krustlet_http` import module. The ports are listened on for as long as the module runs.
+    pub fn with_http_ports(mut self, ports: Vec<u16>) -> Self {
+        self.http_ports = ports;
+        self
+    }
+
+    /// Listens on the module's ports in the given network namespace, so that requests reach the
+    /// module on the pod's IPs rather than the node's.
+    pub fn with_network_namespace(mut self, netns: PathBuf) -> Self {
+        self.netns = Some(netns);
+        self
+    }
 
-        let log_handle_factory = HandleFactory {
-            temp: self.output.clone(),
+    /// Restores the module from the snapshot in `dir` each time it starts, before it runs. The
+    /// snapshot must have been taken of the same module.
+    pub(crate) fn with_snapshot(mut self, dir: PathBuf) -> Self {
+        self.restore = Some(dir);
+        self
+    }
+
+    /// Runs the module on threads of its own that are kept to the given sandbox once the module
+    /// has been instantiated.
+    pub(crate) fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(Arc::new(sandbox));
+        self
+    }
+
+    /// What the module is using, which is measured across all of its runs.
+    pub(crate) fn usage(&self) -> Usage {
+        Usage {
+            memory: self.memory.clone(),
+            cpu_time: self.cpu_time.clone(),
+        }
+    }
+
+    /// Starts a new run of the module. Each run starts a new log, so the logs of the previous
+    /// run are left intact, and is given an ID of its own, of the form `wasi://<uuid>`.
+    #[tracing::instrument(skip(self))]
+    pub async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, LogHandleFactory>> {
+        let log_storage = self.log_storage.clone();
+        let pod = self.pod.clone();
+        let name = self.name.clone();
+        // Starting the log may block, so run it in a blocking task
+        let log = tokio::task::spawn_blocking(move || log_storage.create(&pod, &name)).await??;
+
+        // The module writes to pipes rather than straight into the log, so that each line can be
+        // recorded with when it was written and which stream it was written to
+        let log_handle_factory = log.handles;
+        let log = Arc::new(Mutex::new(log.sink));
+        let output_pipes = Output {
+            stdout: spawn_output_recorder(LogStream::Stdout, log.clone())?,
+            stderr: spawn_output_recorder(LogStream::Stderr, log)?,
+        };
+
+        let (stdin_read, stdin_write) = os_pipe::pipe()?;
+        let (attach_read, attach_stdin) = match self.stdin {
+            Some(_) => {
+                let (read, write) = os_pipe::pipe()?;
+                (
+                    Some(into_file(read)),
+                    Some(Arc::new(Mutex::new(Some(write)))),
+                )
+            }
+            None => (None, None),
+        };
+        let (exec_sender, exec_receiver) = mpsc::unbounded_channel();
+        let mounts = self
+            .data
+            .dirs
+            .iter()
+            .map(|(host_path, container_path)| Mount {
+                host_path: host_path.clone(),
+                container_path: container_path.clone().unwrap_or_else(|| host_path.clone()),
+                read_only: self.data.read_only_dirs.contains(host_path),
+            })
+            .collect();
+        let snapshots = SnapshotRequests::new();
+        let http_requests = if self.http_ports.is_empty() {
+            None
+        } else {
+            Some(http::serve(&self.http_ports, self.netns.as_deref())?)
+        };
+
+        let (interrupt_handle, stop, handle) = self
+            .spawn_wasmtime(
+                output_pipes,
+                into_file(stdin_read),
+                attach_read,
+                exec_receiver,
+                http_requests,
+                snapshots.clone(),
+            )
+            .await?;
+        let exited = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let exited = exited.clone();
+            tokio::spawn(async move {
+                let result = handle.await;
+                exited.store(true, Ordering::SeqCst);
+                result?
+            })
         };
 
         Ok(ContainerHandle::new(
             Runtime {
-                handle,
+                handle: Some(handle),
+                exited,
                 interrupt_handle,
+                stop,
+                exec_sender: Mutex::new(Some(exec_sender)),
+                snapshots,
+                stdin: Arc::new(Mutex::new(Some(stdin_write))),
+                attach_stdin,
+                stdin_once: self.stdin.unwrap_or(false),
+                log: log_handle_factory.clone(),
+                mounts,
             },
             log_handle_factory,
-        ))
+        )
+        .with_id(format!("wasi://{}", uuid::Uuid::new_v4())))
     }
 
     // Spawns a running wasmtime instance with the given context and status
     // channel. Due to the Instance type not being Send safe, all of the logic
-    // needs to be done within the spawned task
+    // needs to be done within the spawned task. Modules without a `_start`
+    // export are kept alive to serve exec requests until they are stopped,
+    // while modules with one serve them from a second instance. HTTP
+    // requests are only taken by the first instance, and stop being served
+    // once it is done. A module interrupted to be snapshotted is snapshotted
+    // once `_start` returns. Sandboxed modules are run on a thread of their
+    // own, as the sandbox would otherwise outlive them on the blocking thread
+    // the runtime reuses
     async fn spawn_wasmtime(
         &self,
-        output_write: std::fs::File,
-    ) -> anyhow::Result<(InterruptHandle, JoinHandle<anyhow::Result<()>>)> {
+        output_write: Output,
+        stdin_read: std::fs::File,
+        attach_read: Option<std::fs::File>,
+        exec_receiver: UnboundedReceiver<ExecRequest>,
+        http_requests: Option<Requests>,
+        snapshots: SnapshotRequests,
+    ) -> anyhow::Result<(
+        InterruptHandle,
+        Arc<StopState>,
+        JoinHandle<anyhow::Result<()>>,
+    )> {
         // Clone the module data Arc so it can be moved
         let data = self.data.clone();
         let name = self.name.clone();
         let status_sender = self.status_sender.clone();
+        let memory_limit = self.memory.clone();
+        memory_limit.start_run();
+        let cpu_time = self.cpu_time.clone();
+        let cpu_limit = self.cpu_limit.map(CpuLimit::new);
+        let module_cache = self.module_cache.clone();
+        let engine = self.engine.clone();
+        let restore = self.restore.clone();
+        let sandbox = self.sandbox.clone();
+        let dedicated_thread = sandbox.is_some();
+        let stop = Arc::new(StopState::default());
+        let run_stop = stop.clone();
         let (tx, rx) = oneshot::channel();
 
-        let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-            let waker = task::noop_waker();
-            let mut cx = Context::from_waker(&waker);
-            // Build the WASI instance and then generate a list of WASI modules
-            let mut ctx_builder_snapshot = WasiCtxBuilder::new();
-            let mut ctx_builder_snapshot = ctx_builder_snapshot
-                .args(&data.args)
-                .envs(&data.env)
-                .stdout(wasi_common::OsFile::try_from(output_write.try_clone()?)?)
-                .stderr(wasi_common::OsFile::try_from(output_write.try_clone()?)?);
-            let mut ctx_builder_unstable = wasi_common::old::snapshot_0::WasiCtxBuilder::new();
-            let mut ctx_builder_unstable = ctx_builder_unstable
-                .args(&data.args)
-                .envs(&data.env)
-                .stdout(output_write.try_clone()?)
-                .stderr(output_write);
-
-            for (key, value) in data.dirs.iter() {
-                let guest_dir = value.as_ref().unwrap_or(key);
-                debug!(
-                    "mounting hostpath {} as guestpath {}",
-                    key.display(),
-                    guest_dir.display()
-                );
-                ctx_builder_snapshot =
-                    ctx_builder_snapshot.preopened_dir(preopen_dir(key)?, guest_dir);
-                ctx_builder_unstable =
-                    ctx_builder_unstable.preopened_dir(preopen_dir(key)?, guest_dir);
-            }
-            let wasi_ctx_snapshot = ctx_builder_snapshot.build()?;
-            let wasi_ctx_unstable = ctx_builder_unstable.build()?;
-            let mut config = wasmtime::Config::new();
-            config.interruptable(true);
-            let engine = wasmtime::Engine::new(&config);
-            let store = wasmtime::Store::new(&engine);
+        // The module runs on a thread of its own, where it is still traced in the container's span
+        let span = tracing::Span::current();
+        let run = move || -> anyhow::Result<_> {
+            let _span = span.enter();
+            let _snapshots = CloseOnDrop(snapshots.clone());
+            let _cpu_time = cpu_time.count_thread();
+            let store = new_store(&engine, &memory_limit, &cpu_limit)?;
             let interrupt = store.interrupt_handle()?;
             tx.send(interrupt)
                 .map_err(|_| anyhow::anyhow!("Unable to send interrupt back to main thread"))?;
 
-            let wasi_snapshot = Wasi::new(&store, wasi_ctx_snapshot);
-            let wasi_unstable = WasiUnstable::new(&store, wasi_ctx_unstable);
-            let module = match wasmtime::Module::new(&engine, &data.module_data) {
+            let module = match &module_cache {
+                Some(module_cache) => module_cache.load_or_compile(
+                    engine.engine(),
+                    &data.module_data,
+                    engine.settings(),
+                ),
+                None => wasmtime::Module::new(engine.engine(), &data.module_data),
+            };
+            let module = match module {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
                 Ok(m) => m,
                 Err(e) => {
                     let message = "unable to create module";
                     error!("{}: {:?}", message, e);
+                    if problem::is_out_of_memory(&e) {
+                        problem::report(
+                            Problem::CompileOutOfMemory,
+                            &format!("{} {}: {}", message, name, e),
+                        );
+                    }
                     send(
                         status_sender.clone(),
                         name.clone(),
@@ -214,31 +735,45 @@ impl WasiRuntime {
                             message: message.into(),
                             timestamp: chrono::Utc::now(),
                         },
-                        &mut cx,
                     );
                     return Err(anyhow::anyhow!("{}: {}", message, e));
                 }
             };
-            // Iterate through the module includes and resolve imports
-            let imports = module
-                .imports()
-                .map(|i| {
-                    // This is super funky logic, but it matches what is in 0.12.0
-                    let export = match i.module() {
-                        "wasi_snapshot_preview1" => wasi_snapshot.get_export(i.name()),
-                        "wasi_unstable" => wasi_unstable.get_export(i.name()),
-                        other => bail!("import module `{}` was not found", other),
-                    };
-                    match export {
-                        Some(export) => Ok(export.clone().into()),
-                        None => bail!(
-                            "import `{}` was not found in module `{}`",
-                            i.name(),
-                            i.module()
-                        ),
-                    }
-                })
-                .collect::<Result<Vec<_>, _>>();
+            // Modules without a `_start` export are reactors, which stay
+            // instantiated so their exports can be called through exec. Modules
+            // that run `_start` serve exec requests from a second instance on
+            // another thread, as this one is busy running the module. The stdin
+            // exec sessions write to goes to whichever instance serves them, and
+            // a module that runs `_start` reads the stdin attached clients write
+            // to, if it has one.
+            let is_reactor = !module.exports().any(|e| e.name() == "_start");
+            let finished = Arc::new(AtomicBool::new(false));
+            let (main_stdin, exec_requests) = if is_reactor {
+                (Some(stdin_read), Some(exec_receiver))
+            } else {
+                spawn_exec_instance(
+                    engine.clone(),
+                    module.clone(),
+                    memory_limit.clone(),
+                    cpu_limit.clone(),
+                    cpu_time.clone(),
+                    data.clone(),
+                    output_write.try_clone()?,
+                    stdin_read,
+                    exec_receiver,
+                    finished.clone(),
+                    sandbox.clone(),
+                );
+                (attach_read, None)
+            };
+            let imports = resolve_imports(
+                &store,
+                &module,
+                &data,
+                output_write,
+                main_stdin,
+                http_requests,
+            );
             let imports = match imports {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
@@ -254,34 +789,75 @@ impl WasiRuntime {
                             message: message.into(),
                             timestamp: chrono::Utc::now(),
                         },
-                        &mut cx,
                     );
                     return Err(e);
                 }
             };
 
-            let instance = match wasmtime::Instance::new(&store, &module, &imports) {
+            let instantiated = run(
+                &store,
+                &cpu_limit,
+                wasmtime::Instance::new_async(&store, &module, &imports),
+            );
+            let instance = match instantiated {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
                 Ok(m) => m,
                 Err(e) => {
-                    let message = "unable to instantiate module";
+                    let killed = run_stop.killed.load(Ordering::SeqCst);
+                    let message = exceeded_limit(&e, &memory_limit, killed)
+                        .unwrap_or_else(|| "unable to instantiate module".to_owned());
                     error!("{}: {:?}", message, e);
                     send(
                         status_sender.clone(),
                         name,
                         Status::Terminated {
                             failed: true,
-                            message: message.into(),
+                            message: message.clone(),
                             timestamp: chrono::Utc::now(),
                         },
-                        &mut cx,
                     );
                     // Converting from anyhow
                     return Err(anyhow::anyhow!("{}: {}", message, e));
                 }
             };
 
+            let restored = match &restore {
+                Some(dir) => match snapshot::restore(&instance, &data.module_data, dir) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        let message = "unable to restore module from snapshot";
+                        error!("{}: {:?}", message, e);
+                        send(
+                            status_sender.clone(),
+                            name,
+                            Status::Terminated {
+                                failed: true,
+                                message: message.into(),
+                                timestamp: chrono::Utc::now(),
+                            },
+                        );
+                        return Err(anyhow::anyhow!("{}: {}", message, e));
+                    }
+                },
+                None => false,
+            };
+
+            if let Err(e) = sandbox.as_ref().map_or(Ok(()), |sandbox| sandbox.apply()) {
+                let message = "unable to sandbox module";
+                error!("{}: {:?}", message, e);
+                send(
+                    status_sender.clone(),
+                    name,
+                    Status::Terminated {
+                        failed: true,
+                        message: message.into(),
+                        timestamp: chrono::Utc::now(),
+                    },
+                );
+                return Err(anyhow::anyhow!("{}: {}", message, e));
+            }
+
             // NOTE(taylor): In the future, if we want to pass args directly, we'll
             // need to do a bit more to pass them in here.
             info!("starting run of module");
@@ -291,11 +867,36 @@ impl WasiRuntime {
                 Status::Running {
                     timestamp: chrono::Utc::now(),
                 },
-                &mut cx,
             );
+            if let Some(mut exec_requests) = exec_requests {
+                info!("module has no _start export, serving exec requests");
+                snapshots.close("modules without a _start export can't be checkpointed");
+                while let Some(request) = futures::executor::block_on(exec_requests.recv()) {
+                    let result = call_export(&instance, &cpu_limit, &request.command);
+                    // The requester may have gone away, which is fine
+                    let _ = request.response.send(result);
+                }
+                info!("module stopped");
+                send(
+                    status_sender,
+                    name,
+                    Status::Terminated {
+                        failed: false,
+                        message: "Module stopped".into(),
+                        timestamp: chrono::Utc::now(),
+                    },
+                );
+                return Ok(());
+            }
+            // A restored module carries on from where it was snapshotted if it knows how to
+            let entry = if restored && instance.get_func(snapshot::RESUME_EXPORT).is_some() {
+                snapshot::RESUME_EXPORT
+            } else {
+                "_start"
+            };
             let export = instance
-                .get_export("_start")
-                .ok_or_else(|| anyhow::anyhow!("_start import doesn't exist in wasm module"))?;
+                .get_export(entry)
+                .ok_or_else(|| anyhow::anyhow!("{} import doesn't exist in wasm module", entry))?;
             let func = match export {
                 wasmtime::Extern::Func(f) => f,
                 _ => {
@@ -309,28 +910,66 @@ impl WasiRuntime {
                             message: message.into(),
                             timestamp: chrono::Utc::now(),
                         },
-                        &mut cx,
                     );
 
                     return Err(anyhow::anyhow!(message));
                 }
             };
-            match func.call(&[]) {
+            // A module asked to stop before it got going isn't started, as it may not be able to
+            // tell that it should stop. Once it has started, it runs until it finishes or is
+            // killed
+            let result = if run_stop.stopping.load(Ordering::SeqCst) {
+                info!("module stopped before it started");
+                Ok(Box::new([]) as Box<[Val]>)
+            } else {
+                run(&store, &cpu_limit, func.call_async(&[]))
+            };
+            finished.store(true, Ordering::SeqCst);
+            if let Some(request) = snapshots.close("module is no longer running") {
+                info!("module stopped to be checkpointed");
+                let snapshotted = snapshot::take(&instance, &data.module_data, &request.dir);
+                let status = match &snapshotted {
+                    Ok(()) => Status::Terminated {
+                        failed: false,
+                        message: "Module checkpointed".into(),
+                        timestamp: chrono::Utc::now(),
+                    },
+                    Err(e) => {
+                        error!("unable to checkpoint module: {:?}", e);
+                        Status::Terminated {
+                            failed: true,
+                            message: "unable to checkpoint module".into(),
+                            timestamp: chrono::Utc::now(),
+                        }
+                    }
+                };
+                // The requester may have gone away, which is fine
+                let _ = request.response.send(snapshotted);
+                send(status_sender, name, status);
+                return Ok(());
+            }
+            match result {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
                 Ok(_) => {}
                 Err(e) => {
-                    let message = "unable to run module";
+                    let killed = run_stop.killed.load(Ordering::SeqCst);
+                    let limit = exceeded_limit(&e, &memory_limit, killed);
+                    // Modules exiting, exceeding their limits or being killed aren't failures of
+                    // the runtime
+                    if exit_status(&e).is_none() && limit.is_none() && !killed {
+                        problem::report(Problem::Trap, &format!("module {} trapped: {}", name, e));
+                    }
+                    let message = limit.unwrap_or_else(|| "unable to run module".to_owned());
                     error!("{}: {:?}", message, e);
                     send(
                         status_sender.clone(),
                         name.clone(),
                         Status::Terminated {
                             failed: true,
-                            message: message.into(),
+                            message: message.clone(),
                             timestamp: chrono::Utc::now(),
                         },
-                        &mut cx,
                     );
                     return Err(anyhow::anyhow!("{}: {}", message, e));
                 }
@@ -345,27 +984,919 @@ impl WasiRuntime {
                     message: "Module run completed".into(),
                     timestamp: chrono::Utc::now(),
                 },
-                &mut cx,
             );
             Ok(())
-        });
+        };
+        let handle = if dedicated_thread {
+            spawn_dedicated(run)?
+        } else {
+            tokio::task::spawn_blocking(run)
+        };
         // Wait for the interrupt to be sent back to us
         let interrupt = rx.await?;
-        Ok((interrupt, handle))
+        Ok((interrupt, stop, handle))
     }
 }
 
-fn send(mut sender: Sender<Status>, name: String, status: Status, cx: &mut Context<'_>) {
+/// How far a module has been asked to stop, shared between its [`Runtime`] and the thread running
+/// it.
+#[derive(Default)]
+struct StopState {
+    /// Whether the module has been asked to stop
+    stopping: AtomicBool,
+    /// Whether the module has been killed, which interrupts it with a trap that isn't a failure of
+    /// the runtime
+    killed: AtomicBool,
+}
+
+/// Runs `f` on a new thread, returning a handle to a task that finishes when it does.
+fn spawn_dedicated<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<JoinHandle<anyhow::Result<T>>> {
+    let (tx, rx) = oneshot::channel();
+    std::thread::Builder::new()
+        .name("wasi-sandbox".to_owned())
+        .spawn(move || {
+            // The task may have been dropped, which is fine
+            let _ = tx.send(f());
+        })?;
+    Ok(tokio::spawn(async move {
+        rx.await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("thread running module panicked")))
+    }))
+}
+
+/// The size of a page of WebAssembly memory, in bytes.
+pub(crate) const WASM_PAGE_SIZE: u64 = 65536;
+
+/// The most pages a WebAssembly memory can have.
+const WASM_MAX_PAGES: u32 = 65536;
+
+/// The message for the limit that stopped a module which failed with `error`, if it was stopped by
+/// one. A module that failed after its last attempt to grow its memory was refused ran out of
+/// memory, and is reported as OOMKilled like containers killed by the kernel. Modules that exit or
+/// are killed weren't stopped by their limits, even if they were refused memory along the way.
+/// CPU limits never stop a module, as modules are only held back by them.
+fn exceeded_limit(
+    error: &anyhow::Error,
+    memory_limit: &MemoryLimit,
+    killed: bool,
+) -> Option<String> {
+    if killed || exit_status(error).is_some() {
+        return None;
+    }
+    match memory_limit.limit() {
+        Some(limit) if memory_limit.exceeded() => Some(format!(
+            "OOMKilled: module exceeded its memory limit of {} bytes",
+            limit
+        )),
+        _ => None,
+    }
+}
+
+/// The engine modules are compiled and run with. It is shared by all modules, so that the node
+/// only keeps one configured compiler, code registry and pool of instance memory rather than one
+/// per module.
+///
+/// Instances are allocated from a pool that is reserved up front, which makes starting a module
+/// cheap and keeps memories in place with guard pages around them as they grow. Memory and CPU
+/// limits are enforced by each module's store rather than by the engine, so limited and unlimited
+/// modules share the same compiled code. Modules are called asynchronously, on stacks of their
+/// own, so that those with a CPU limit can be paused when they reach it.
+#[derive(Clone)]
+pub struct Engine {
+    engine: wasmtime::Engine,
+    /// The settings that affect how modules are compiled, so that modules compiled with other
+    /// settings aren't loaded from the module cache
+    settings: String,
+    /// Whether the engine counts the fuel modules use, in which case their stores must be given
+    /// fuel to run on
+    fuel: bool,
+}
+
+/// How many instances the pool has room for per pod the node may run. Each container runs its
+/// module in one instance, and modules running `_start` serve exec requests from a second one,
+/// so this leaves room for four such containers per pod.
+const POOLED_INSTANCES_PER_POD: u32 = 8;
+
+/// The most function types, functions and globals a module may define. These size the space
+/// reserved for each instance in the pool, and are well above what compilers emit for all but
+/// the largest modules.
+const MAX_MODULE_TYPES: u32 = 1_000;
+const MAX_MODULE_FUNCTIONS: u32 = 100_000;
+const MAX_MODULE_GLOBALS: u32 = 1_000;
+
+/// The most elements a module's table may have.
+const MAX_TABLE_ELEMENTS: u32 = 100_000;
+
+/// The stack space, in bytes, left for the host functions a module calls on top of the most its
+/// WebAssembly code may use.
+const HOST_STACK_SIZE: usize = 1 << 20;
+
+impl Engine {
+    /// Creates the engine with the given settings, with room in its pool for the instances of
+    /// `max_pods` pods.
+    pub fn new(wasi_config: &WasiConfig, max_pods: u16) -> anyhow::Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config
+            .interruptable(true)
+            .cranelift_opt_level(match wasi_config.opt_level {
+                OptLevel::None => wasmtime::OptLevel::None,
+                OptLevel::Speed => wasmtime::OptLevel::Speed,
+                OptLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+            })
+            .wasm_simd(wasi_config.simd)
+            .wasm_threads(wasi_config.threads)
+            .wasm_bulk_memory(wasi_config.bulk_memory)
+            .consume_fuel(wasi_config.fuel)
+            .async_support(true);
+        if let Some(max_wasm_stack) = wasi_config.max_wasm_stack {
+            // Modules are called on stacks of their own, which must have room for all the stack
+            // their WebAssembly code may use
+            config
+                .async_stack_size(max_wasm_stack.saturating_add(HOST_STACK_SIZE))?
+                .max_wasm_stack(max_wasm_stack)?;
+        }
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling {
+            strategy: PoolingAllocationStrategy::NextAvailable,
+            module_limits: ModuleLimits {
+                types: MAX_MODULE_TYPES,
+                functions: MAX_MODULE_FUNCTIONS,
+                globals: MAX_MODULE_GLOBALS,
+                table_elements: MAX_TABLE_ELEMENTS,
+                memory_pages: wasi_config.max_memory_pages.unwrap_or(WASM_MAX_PAGES),
+                ..Default::default()
+            },
+            instance_limits: InstanceLimits {
+                count: u32::from(max_pods.max(1)) * POOLED_INSTANCES_PER_POD,
+                ..Default::default()
+            },
+        })?;
+        let settings = format!(
+            "{:?}-simd{}-threads{}-bulk{}-fuel{}",
+            wasi_config.opt_level,
+            wasi_config.simd,
+            wasi_config.threads,
+            wasi_config.bulk_memory,
+            wasi_config.fuel
+        );
+        Ok(Engine {
+            engine: wasmtime::Engine::new(&config)?,
+            settings,
+            fuel: wasi_config.fuel,
+        })
+    }
+
+    fn engine(&self) -> &wasmtime::Engine {
+        &self.engine
+    }
+
+    /// Whether the engine counts the fuel modules use, so that they can be given fuel to run on.
+    pub(crate) fn fuel(&self) -> bool {
+        self.fuel
+    }
+
+    /// A name for the way the engine is configured, which modules compiled by it are cached by.
+    fn settings(&self) -> &str {
+        &self.settings
+    }
+}
+
+/// How much fuel a store is given when the engine counts fuel but the module has no CPU limit.
+/// This is as much as a store can hold, which no module gets through.
+const UNLIMITED_FUEL: u64 = i64::MAX as u64;
+
+/// How much fuel each millicore of a container's CPU limit lets its module use a second. Fuel is
+/// used at about one unit per WebAssembly instruction, so a limit of one CPU allows about 10^9
+/// instructions a second.
+const FUEL_PER_MILLICORE: u64 = 1_000_000;
+
+/// The period CPU limits are enforced over. As with the kernel's CFS bandwidth control, a module
+/// may use its limit's share of each period, and is paused until the next one once it has.
+const CPU_PERIOD: Duration = Duration::from_millis(100);
+
+/// How many times a period a module with a CPU limit is paused to count the fuel it has used.
+const CPU_CHECKS_PER_PERIOD: u64 = 10;
+
+/// Creates a store for an instance of a module, which counts the instance's memory against
+/// `memory_limit`, and its CPU against `cpu_limit` if the module has one. Stores are given fuel to
+/// run on if the engine counts fuel, as much as they may use without a CPU limit.
+fn new_store(
+    engine: &Engine,
+    memory_limit: &MemoryLimit,
+    cpu_limit: &Option<CpuLimit>,
+) -> anyhow::Result<wasmtime::Store> {
+    let store =
+        wasmtime::Store::new_with_limits(engine.engine(), StoreMemoryLimit::new(memory_limit));
+    if engine.fuel {
+        match cpu_limit {
+            Some(limit) => limit.start(&store)?,
+            None => store.add_fuel(UNLIMITED_FUEL)?,
+        }
+    }
+    Ok(store)
+}
+
+/// Runs a call into a module, such as `Func::call_async`, on the current thread. Nothing a module
+/// calls is asynchronous, so the call only yields when the module has used the fuel it was given
+/// under a CPU limit, at which point it is paused while it is over the limit and carries on.
+fn run<T>(
+    store: &wasmtime::Store,
+    cpu_limit: &Option<CpuLimit>,
+    call: impl Future<Output = T>,
+) -> T {
+    futures::pin_mut!(call);
+    let mut context = Context::from_waker(futures::task::noop_waker_ref());
     loop {
-        if let Poll::Ready(r) = sender.poll_ready(cx) {
-            if r.is_ok() {
-                sender.try_send(status).expect("Possible deadlock, exiting");
+        if let Poll::Ready(result) = call.as_mut().poll(&mut context) {
+            return result;
+        }
+        if let Some(limit) = cpu_limit {
+            limit.wait(store);
+        }
+    }
+}
+
+/// The CPU a module may use, which is shared by the stores of all its instances.
+#[derive(Clone)]
+struct CpuLimit {
+    inner: Arc<CpuLimitState>,
+}
+
+struct CpuLimitState {
+    /// How much fuel the module may use each period
+    fuel_per_period: u64,
+    /// How much fuel an instance is given at a time, after which it yields
+    fuel_per_check: u64,
+    /// When the current period ends, and how much fuel the module has used in it
+    period: Mutex<(Instant, u64)>,
+}
+
+impl CpuLimit {
+    fn new(millis: u64) -> Self {
+        let fuel_per_period =
+            u128::from(millis) * u128::from(FUEL_PER_MILLICORE) * CPU_PERIOD.as_millis() / 1000;
+        let fuel_per_period = u64::try_from(fuel_per_period)
+            .unwrap_or(UNLIMITED_FUEL)
+            .min(UNLIMITED_FUEL)
+            .max(1);
+        CpuLimit {
+            inner: Arc::new(CpuLimitState {
+                fuel_per_period,
+                fuel_per_check: (fuel_per_period / CPU_CHECKS_PER_PERIOD).max(1),
+                period: Mutex::new((Instant::now(), 0)),
+            }),
+        }
+    }
+
+    /// Gives an instance's store its first fuel, which it yields once it has used.
+    fn start(&self, store: &wasmtime::Store) -> anyhow::Result<()> {
+        store.add_fuel(self.inner.fuel_per_check)?;
+        store.out_of_fuel_async_yield(u32::MAX, self.inner.fuel_per_check);
+        Ok(())
+    }
+
+    /// Counts the fuel an instance used before yielding against the current period, pausing the
+    /// thread running it until the next period if the module has used all it may in this one.
+    /// The instance then yields again once it has used as much more.
+    fn wait(&self, store: &wasmtime::Store) {
+        let paused_until = {
+            let mut period = self.inner.period.lock().unwrap();
+            let now = Instant::now();
+            if now >= period.0 {
+                *period = (now + CPU_PERIOD, 0);
+            }
+            period.1 = period.1.saturating_add(self.inner.fuel_per_check);
+            if period.1 >= self.inner.fuel_per_period {
+                Some(period.0)
+            } else {
+                None
+            }
+        };
+        if let Some(until) = paused_until {
+            std::thread::sleep(until.saturating_duration_since(Instant::now()));
+        }
+        // The number of times the instance yields is reset each time, so that it never runs out
+        store.out_of_fuel_async_yield(u32::MAX, self.inner.fuel_per_check);
+    }
+}
+
+/// What a module is using of the node, which is read to report its container's stats.
+#[derive(Clone)]
+pub(crate) struct Usage {
+    memory: MemoryLimit,
+    cpu_time: CpuTime,
+}
+
+impl Usage {
+    /// The memory, in bytes, that the module's instances are using.
+    pub(crate) fn memory(&self) -> u64 {
+        self.memory.used()
+    }
+
+    /// The most memory, in bytes, that the module may use, if it has a limit.
+    pub(crate) fn memory_limit(&self) -> Option<u64> {
+        self.memory.limit()
+    }
+
+    /// The CPU time that the module's instances have used, if it can be measured.
+    pub(crate) fn cpu_time(&self) -> Option<Duration> {
+        self.cpu_time.total()
+    }
+}
+
+/// The memory a module's instances use, and the most they may use in all if the module has a
+/// limit, which is shared by the stores of all its instances.
+#[derive(Clone)]
+struct MemoryLimit {
+    inner: Arc<MemoryLimitState>,
+}
+
+struct MemoryLimitState {
+    limit: Option<u64>,
+    used: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl MemoryLimit {
+    fn new(limit: Option<u64>) -> Self {
+        MemoryLimit {
+            inner: Arc::new(MemoryLimitState {
+                limit,
+                used: AtomicU64::new(0),
+                exceeded: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    fn limit(&self) -> Option<u64> {
+        self.inner.limit
+    }
+
+    /// The memory, in bytes, that the module's instances are using.
+    fn used(&self) -> u64 {
+        self.inner.used.load(Ordering::SeqCst)
+    }
+
+    /// Forgets whether the module's memory was refused by the limit, as a new run of the module is
+    /// starting.
+    fn start_run(&self) {
+        self.inner.exceeded.store(false, Ordering::SeqCst);
+    }
+
+    /// Whether the module's last attempt to grow its memory was refused by the limit.
+    fn exceeded(&self) -> bool {
+        self.inner.exceeded.load(Ordering::SeqCst)
+    }
+
+    /// Accounts for `bytes` more memory, unless that would take the module over its limit.
+    fn reserve(&self, bytes: u64) -> bool {
+        let mut used = self.inner.used.load(Ordering::SeqCst);
+        loop {
+            let total = match used.checked_add(bytes) {
+                Some(total) if self.inner.limit.map_or(true, |limit| total <= limit) => total,
+                _ => {
+                    self.inner.exceeded.store(true, Ordering::SeqCst);
+                    return false;
+                }
+            };
+            match self
+                .inner
+                .used
+                .compare_exchange(used, total, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => {
+                    self.inner.exceeded.store(false, Ordering::SeqCst);
+                    return true;
+                }
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        self.inner.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+/// Counts the memories of one of a module's stores against the module's [`MemoryLimit`]. Memories
+/// are counted from when they are created, and what a store counted is given back to the limit
+/// when the store is dropped along with its instances.
+struct StoreMemoryLimit {
+    limit: MemoryLimit,
+    used: Cell<u64>,
+}
+
+impl StoreMemoryLimit {
+    fn new(limit: &MemoryLimit) -> Self {
+        StoreMemoryLimit {
+            limit: limit.clone(),
+            used: Cell::new(0),
+        }
+    }
+}
+
+impl ResourceLimiter for StoreMemoryLimit {
+    fn memory_growing(&self, current: u32, desired: u32, maximum: Option<u32>) -> bool {
+        if maximum.map_or(false, |maximum| desired > maximum) {
+            return false;
+        }
+        let bytes = u64::from(desired.saturating_sub(current)) * WASM_PAGE_SIZE;
+        if !self.limit.reserve(bytes) {
+            return false;
+        }
+        self.used.set(self.used.get() + bytes);
+        true
+    }
+
+    fn table_growing(&self, _current: u32, desired: u32, maximum: Option<u32>) -> bool {
+        maximum.map_or(true, |maximum| desired <= maximum)
+    }
+}
+
+impl Drop for StoreMemoryLimit {
+    fn drop(&mut self) {
+        self.limit.release(self.used.get());
+    }
+}
+
+/// Builds the WASI context for an instance of the module, with output written to `output` and
+/// input read from `stdin` if it is given, and resolves the module's imports against it. The
+/// instance takes `http_requests`, if it is given them.
+fn resolve_imports(
+    store: &wasmtime::Store,
+    module: &wasmtime::Module,
+    data: &Data,
+    output: Output,
+    stdin: Option<std::fs::File>,
+    http_requests: Option<Requests>,
+) -> anyhow::Result<Vec<wasmtime::Extern>> {
+    let env: Vec<(String, String)> = data
+        .env
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let mut builder = WasiCtxBuilder::new()
+        .args(&data.args)?
+        .envs(&env)?
+        .stdout(wasi_file(output.stdout))
+        .stderr(wasi_file(output.stderr));
+    if let Some(stdin) = stdin {
+        builder = builder.stdin(wasi_file(stdin));
+    }
+    let mut ctx = builder.build()?;
+
+    for (fd, (key, value)) in (FIRST_PREOPEN_FD..).zip(data.dirs.iter()) {
+        let guest_dir = value.as_ref().unwrap_or(key);
+        debug!(
+            "mounting hostpath {} as guestpath {}",
+            key.display(),
+            guest_dir.display()
+        );
+        let (caps, file_caps) = if data.read_only_dirs.contains(key) {
+            read_only_caps()
+        } else {
+            (DirCaps::all(), FileCaps::all())
+        };
+        ctx.insert_dir(fd, open_dir(key)?, caps, file_caps, guest_dir.clone());
+    }
+    // Both snapshots of WASI share the context, so a module sees the same files through either
+    let ctx = Rc::new(RefCell::new(ctx));
+    let wasi_snapshot = Wasi::new(store, ctx.clone());
+    let wasi_unstable = WasiUnstable::new(store, ctx);
+    let sockets = data
+        .allowed_outbound
+        .as_ref()
+        .map(|allowed| Sockets::new(allowed).exports(store));
+    let http = Bridge::new(http_requests).exports(store);
+    let client = data
+        .allowed_hosts
+        .as_ref()
+        .map(|allowed| Client::new(allowed).exports(store));
+
+    // Iterate through the module includes and resolve imports
+    module
+        .imports()
+        .map(|i| {
+            // This is super funky logic, but it matches what is in 0.12.0
+            let export = match i.module() {
+                "wasi_snapshot_preview1" => wasi_snapshot.get_export(i.name()).cloned(),
+                "wasi_unstable" => wasi_unstable.get_export(i.name()).cloned(),
+                SOCKETS_MODULE => match &sockets {
+                    Some(sockets) => sockets.get(i.name()).cloned(),
+                    None => bail!(
+                        "import module `{}` is only available to pods that allow outbound networking",
+                        SOCKETS_MODULE
+                    ),
+                },
+                HTTP_MODULE => http.get(i.name()).cloned(),
+                CLIENT_MODULE => match &client {
+                    Some(client) => client.get(i.name()).cloned(),
+                    None => bail!(
+                        "import module `{}` is only available to pods that allow hosts to be called",
+                        CLIENT_MODULE
+                    ),
+                },
+                other => bail!("import module `{}` was not found", other),
+            };
+            match export {
+                Some(export) => Ok(export.into()),
+                None => bail!(
+                    "import `{}` was not found in module `{}`",
+                    i.name(),
+                    i.module()
+                ),
+            }
+        })
+        .collect()
+}
+
+/// The descriptor of the first directory preopened for a module. The descriptors before it are
+/// the module's stdin, stdout and stderr.
+const FIRST_PREOPEN_FD: u32 = 3;
+
+/// Wraps one of the pipes a module's standard streams are connected to for WASI.
+fn wasi_file(file: std::fs::File) -> Box<dyn WasiFile> {
+    // Safety: the pipe was created for the module, so it doesn't give it access to anything else
+    let file = unsafe { cap_std::fs::File::from_std(file) };
+    Box::new(wasi_cap_std_sync::file::File::from_cap_std(file))
+}
+
+/// Opens a directory on the host to preopen for a module.
+fn open_dir(path: &Path) -> anyhow::Result<Box<wasi_cap_std_sync::dir::Dir>> {
+    // Safety: the module can only reach what is inside the directory, which it has been given
+    let dir = unsafe { Dir::open_ambient_dir(path)? };
+    Ok(Box::new(wasi_cap_std_sync::dir::Dir::from_cap_std(dir)))
+}
+
+/// The capabilities a module has to a read-only directory and the files in it: it may open, list
+/// and stat what is in it, and read and seek the files it opens.
+fn read_only_caps() -> (DirCaps, FileCaps) {
+    let dir = DirCaps::OPEN
+        | DirCaps::READDIR
+        | DirCaps::READLINK
+        | DirCaps::FILESTAT_GET
+        | DirCaps::PATH_FILESTAT_GET;
+    let file = FileCaps::READ
+        | FileCaps::SEEK
+        | FileCaps::TELL
+        | FileCaps::ADVISE
+        | FileCaps::FILESTAT_GET
+        | FileCaps::FDSTAT_SET_FLAGS
+        | FileCaps::POLL_READWRITE;
+    (dir, file)
+}
+
+/// Serves exec requests for a module that is running its `_start` function, using a second
+/// instance of the module on its own thread. The second instance shares the module's output,
+/// environment and directories but not its memory, so exported functions called through exec
+/// don't see the state of the running instance, though it does count against the same memory
+/// and CPU limits. Requests are refused once `finished` is set.
+#[allow(clippy::too_many_arguments)]
+fn spawn_exec_instance(
+    engine: Engine,
+    module: wasmtime::Module,
+    memory_limit: MemoryLimit,
+    cpu_limit: Option<CpuLimit>,
+    cpu_time: CpuTime,
+    data: Arc<Data>,
+    output: Output,
+    stdin: std::fs::File,
+    mut requests: UnboundedReceiver<ExecRequest>,
+    finished: Arc<AtomicBool>,
+    sandbox: Option<Arc<Sandbox>>,
+) {
+    std::thread::spawn(move || {
+        let _cpu_time = cpu_time.count_thread();
+        // The instance is only created once it is needed, as most modules are never exec'd into.
+        // The thread is sandboxed before then, as instantiating only opens the module's own
+        // directories
+        let mut instance = match sandbox.as_ref().map_or(Ok(()), |sandbox| sandbox.apply()) {
+            Ok(()) => None,
+            Err(e) => Some(Err(anyhow::anyhow!(
+                "unable to sandbox module for exec: {}",
+                e
+            ))),
+        };
+        let mut inputs = Some((output, stdin));
+        while let Some(request) = futures::executor::block_on(requests.recv()) {
+            if finished.load(Ordering::SeqCst) {
+                let _ = request
+                    .response
+                    .send(Err(anyhow::anyhow!("module has finished running")));
                 return;
             }
-            trace!("Receiver for status showing as closed: {:?}", r);
+            if instance.is_none() {
+                if let Some((output, stdin)) = inputs.take() {
+                    instance = Some(
+                        new_store(&engine, &memory_limit, &cpu_limit)
+                            .and_then(|store| {
+                                let imports = resolve_imports(
+                                    &store,
+                                    &module,
+                                    &data,
+                                    output,
+                                    Some(stdin),
+                                    None,
+                                )?;
+                                let instance =
+                                    wasmtime::Instance::new_async(&store, &module, &imports);
+                                run(&store, &cpu_limit, instance)
+                            })
+                            .map_err(|e| {
+                                anyhow::anyhow!("unable to instantiate module for exec: {}", e)
+                            }),
+                    );
+                }
+            }
+            let result = match &instance {
+                Some(Ok(instance)) => call_export(instance, &cpu_limit, &request.command),
+                Some(Err(e)) => Err(anyhow::anyhow!("{}", e)),
+                None => Err(anyhow::anyhow!("module is not accepting exec requests")),
+            };
+            // The requester may have gone away, which is fine
+            let _ = request.response.send(result);
         }
+    });
+}
+
+/// Exports that allocate memory in a module, taking a size in bytes and returning a pointer to the
+/// allocation. These are looked for in order when a string is passed to a module. They are the
+/// names used by common toolchains, the last being wasm-bindgen's.
+const ALLOCATOR_EXPORTS: &[&str] = &["alloc", "malloc", "__wbindgen_malloc"];
+
+/// An argument to an exported function.
+enum Arg {
+    /// A value passed as a single parameter.
+    Val(Val),
+    /// A string, passed as a pointer to a copy of it in the module's memory followed by its length.
+    Str(String),
+}
+
+/// Calls the exported function named by the first element of `command`, passing the remaining
+/// elements as arguments, and returns the results separated by spaces. If the function exits
+/// through `proc_exit`, its exit code is returned instead.
+///
+/// Functions returning a string in the canonical ABI have it decoded rather than printed as
+/// integers. The string is either returned as a pointer and a length, or, for modules built
+/// without multi-value support, written as a pointer and a length to a return area whose address
+/// is passed as the function's first parameter.
+fn call_export(
+    instance: &wasmtime::Instance,
+    cpu_limit: &Option<CpuLimit>,
+    command: &[String],
+) -> anyhow::Result<ExecResult> {
+    let (name, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("no command was given"))?;
+    let func = instance
+        .get_func(name)
+        .ok_or_else(|| anyhow::anyhow!("module does not export a function named {}", name))?;
+    let ty = func.ty();
+    let (uses_return_area, args) = match parse_args(ty.params(), args) {
+        Ok(args) => (false, args),
+        Err(e) => match ty.params() {
+            [ValType::I32, rest @ ..] if ty.results().is_empty() => match parse_args(rest, args) {
+                Ok(args) => (true, args),
+                Err(_) => return Err(e),
+            },
+            _ => return Err(e),
+        },
+    };
+    let mut params = Vec::with_capacity(args.len() + 1);
+    let return_area = if uses_return_area {
+        let return_area = allocate(instance, cpu_limit, RETURN_AREA_SIZE)?;
+        params.push(Val::I32(return_area));
+        Some(return_area)
+    } else {
+        None
+    };
+    params.extend(marshal_args(instance, cpu_limit, args)?);
+    let results = match run(instance.store(), cpu_limit, func.call_async(&params)) {
+        Ok(results) => results,
+        Err(e) => match exit_status(&e) {
+            Some(exit_code) => {
+                return Ok(ExecResult {
+                    output: String::new(),
+                    exit_code,
+                })
+            }
+            None => return Err(e),
+        },
+    };
+    let results = match return_area {
+        Some(return_area) => read_return_area(instance, return_area)?.to_vec(),
+        None => results.into_vec(),
+    };
+    let output = match results.as_slice() {
+        [Val::I32(ptr), Val::I32(len)] => read_string(instance, *ptr, *len),
+        _ => None,
+    };
+    Ok(ExecResult {
+        output: output.unwrap_or_else(|| {
+            results
+                .iter()
+                .map(stringify_val)
+                .collect::<Vec<_>>()
+                .join(" ")
+        }),
+        exit_code: 0,
+    })
+}
+
+/// The exit code a module gave `proc_exit`, if that is how a call into it ended.
+fn exit_status(error: &anyhow::Error) -> Option<i32> {
+    error
+        .downcast_ref::<wasmtime::Trap>()
+        .and_then(wasmtime::Trap::i32_exit_status)
+}
+
+/// Parses command line arguments into arguments for a function with the given parameter types.
+/// Arguments that don't parse as the type of the next parameter are passed as strings, which
+/// take up two `i32` parameters. Arguments wrapped in double quotes are always passed as strings,
+/// without the quotes.
+fn parse_args(types: &[ValType], args: &[String]) -> anyhow::Result<Vec<Arg>> {
+    let mut parsed = Vec::with_capacity(args.len());
+    let mut remaining = types;
+    for arg in args {
+        let quoted = arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"');
+        let val = match remaining.first() {
+            Some(ty) if !quoted => parse_val(ty, arg),
+            _ => None,
+        };
+        if let Some(val) = val {
+            parsed.push(Arg::Val(val));
+            remaining = &remaining[1..];
+            continue;
+        }
+        match remaining {
+            [ValType::I32, ValType::I32, rest @ ..] => {
+                let string = if quoted { &arg[1..arg.len() - 1] } else { arg };
+                parsed.push(Arg::Str(string.to_owned()));
+                remaining = rest;
+            }
+            [] => bail!(
+                "function takes {} parameters but more were given",
+                types.len()
+            ),
+            [ty, ..] => bail!(
+                "invalid argument {:?} for a parameter of type {:?}. Strings can only be passed \
+                 where the function takes a pointer and a length as two i32 parameters",
+                arg,
+                ty
+            ),
+        }
+    }
+    if !remaining.is_empty() {
+        bail!(
+            "function takes {} parameters but only {} were given",
+            types.len(),
+            types.len() - remaining.len()
+        );
+    }
+    Ok(parsed)
+}
+
+/// Parses an argument as a value of the given type, if it is a supported numeric type.
+fn parse_val(ty: &ValType, arg: &str) -> Option<Val> {
+    match ty {
+        ValType::I32 => arg.parse().ok().map(Val::I32),
+        ValType::I64 => arg.parse().ok().map(Val::I64),
+        ValType::F32 => arg.parse::<f32>().ok().map(|v| Val::F32(v.to_bits())),
+        ValType::F64 => arg.parse::<f64>().ok().map(|v| Val::F64(v.to_bits())),
+        _ => None,
+    }
+}
+
+/// Turns arguments into the values passed to a function, copying strings into the module's
+/// memory.
+fn marshal_args(
+    instance: &wasmtime::Instance,
+    cpu_limit: &Option<CpuLimit>,
+    args: Vec<Arg>,
+) -> anyhow::Result<Vec<Val>> {
+    let mut params = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            Arg::Val(val) => params.push(val),
+            Arg::Str(string) => {
+                params.push(Val::I32(write_string(instance, cpu_limit, &string)?));
+                params.push(Val::I32(i32::try_from(string.len())?));
+            }
+        }
+    }
+    Ok(params)
+}
+
+/// Copies a string into memory allocated by the module, returning a pointer to it.
+fn write_string(
+    instance: &wasmtime::Instance,
+    cpu_limit: &Option<CpuLimit>,
+    string: &str,
+) -> anyhow::Result<i32> {
+    let ptr = allocate(instance, cpu_limit, string.len())?;
+    let start = ptr as u32 as usize;
+    let memory = module_memory(instance)?;
+    // Safety: the module isn't running while the slice is in use, so nothing else can access or
+    // grow its memory
+    let data = unsafe { memory.data_unchecked_mut() };
+    data.get_mut(start..start + string.len())
+        .ok_or_else(|| anyhow::anyhow!("module allocated memory out of bounds"))?
+        .copy_from_slice(string.as_bytes());
+    Ok(ptr)
+}
+
+/// Allocates `size` bytes of memory in the module with one of the [`ALLOCATOR_EXPORTS`],
+/// returning a pointer to them.
+fn allocate(
+    instance: &wasmtime::Instance,
+    cpu_limit: &Option<CpuLimit>,
+    size: usize,
+) -> anyhow::Result<i32> {
+    let alloc = ALLOCATOR_EXPORTS
+        .iter()
+        .find_map(|name| instance.get_func(name))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "module does not export an allocator ({}), so strings can't be passed to or from it",
+                ALLOCATOR_EXPORTS.join(", ")
+            )
+        })?
+        .typed::<i32, i32>()?;
+    let size = i32::try_from(size)?;
+    Ok(run(instance.store(), cpu_limit, alloc.call_async(size))?)
+}
+
+/// The memory a module exports as `memory`, which strings are passed through.
+fn module_memory(instance: &wasmtime::Instance) -> anyhow::Result<wasmtime::Memory> {
+    instance.get_memory("memory").ok_or_else(|| {
+        anyhow::anyhow!(
+            "module does not export its memory, so strings can't be passed to or from it"
+        )
+    })
+}
+
+/// The size of the area a function writes a returned pointer and length to.
+const RETURN_AREA_SIZE: usize = 8;
+
+/// Reads the pointer and length a function wrote to its return area.
+fn read_return_area(instance: &wasmtime::Instance, return_area: i32) -> anyhow::Result<[Val; 2]> {
+    let memory = module_memory(instance)?;
+    let start = return_area as u32 as usize;
+    // Safety: the module isn't running while the slice is in use
+    let data = unsafe { memory.data_unchecked() };
+    let area = data
+        .get(start..start + RETURN_AREA_SIZE)
+        .ok_or_else(|| anyhow::anyhow!("function return area is out of bounds"))?;
+    let word = |bytes: &[u8]| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    Ok([Val::I32(word(&area[..4])), Val::I32(word(&area[4..]))])
+}
+
+/// Reads a string returned by a function as a pointer and a length, if they point to valid UTF-8
+/// in the module's memory. Functions that return two integers which aren't a string give `None`.
+fn read_string(instance: &wasmtime::Instance, ptr: i32, len: i32) -> Option<String> {
+    if ptr == 0 {
+        return None;
+    }
+    let memory = instance.get_memory("memory")?;
+    let start = ptr as u32 as usize;
+    let len = usize::try_from(len).ok()?;
+    // Safety: the module isn't running while the slice is in use
+    let data = unsafe { memory.data_unchecked() };
+    let bytes = data.get(start..start.checked_add(len)?)?;
+    std::str::from_utf8(bytes).ok().map(str::to_owned)
+}
+
+fn stringify_val(val: &Val) -> String {
+    match val {
+        Val::I32(v) => v.to_string(),
+        Val::I64(v) => v.to_string(),
+        Val::F32(bits) => f32::from_bits(*bits).to_string(),
+        Val::F64(bits) => f64::from_bits(*bits).to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+#[cfg(unix)]
+fn into_file<P: std::os::unix::io::IntoRawFd>(pipe: P) -> std::fs::File {
+    use std::os::unix::io::FromRawFd;
+    // Safety: the descriptor is owned by the pipe, which is consumed here
+    unsafe { std::fs::File::from_raw_fd(pipe.into_raw_fd()) }
+}
+
+#[cfg(windows)]
+fn into_file<P: std::os::windows::io::IntoRawHandle>(pipe: P) -> std::fs::File {
+    use std::os::windows::io::FromRawHandle;
+    // Safety: the handle is owned by the pipe, which is consumed here
+    unsafe { std::fs::File::from_raw_handle(pipe.into_raw_handle()) }
+}
+
+/// Sends a status update for the container from the thread running its module. The thread isn't
+/// part of the Tokio runtime, so it blocks until the channel has room rather than awaiting it.
+fn send(mut sender: Sender<Status>, name: String, status: Status) {
+    if futures::executor::block_on(sender.send(status)).is_err() {
         trace!(
-            "Channel for container {} not ready for send. Attempting again",
+            "Receiver for status of container {} has gone away, dropping status",
             name
         );
     }
@@ -0,0 +1,102 @@
+use kubelet::config::Config;
+use kubelet::provider::registry::ProviderRegistry;
+use kubelet::store::composite::ComposableStore;
+use kubelet::store::oci::FileStore;
+use kubelet::store::signature::CosignVerifier;
+use kubelet::Kubelet;
+use process_provider::ProcessProvider;
+use std::sync::Arc;
+use structopt::StructOpt;
+use wascc_provider::WasccProvider;
+use wasi_provider::{WasiConfig, WasiOpts, WasiProvider};
+
+#[cfg(feature = "profiling")]
+#[global_allocator]
+static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+/// The kubelet's flags along with the WASI provider's.
+#[derive(StructOpt)]
+struct Opts {
+    #[structopt(flatten)]
+    kubelet: kubelet::config::Opts,
+    #[structopt(flatten)]
+    wasi: WasiOpts,
+}
+
+/// The runtime class whose provider runs pods that don't name one, unless another is chosen
+/// through `KRUSTLET_DEFAULT_RUNTIME_CLASS`.
+const DEFAULT_RUNTIME_CLASS: &str = "wasi";
+
+#[tokio::main(threaded_scheduler)]
+async fn main() -> anyhow::Result<()> {
+    // Each provider runs the pods of the runtime class it is registered under, so one node can
+    // run modules and processes alike
+    let opts = Opts::from_clap(
+        &Opts::clap()
+            .version(env!("CARGO_PKG_VERSION"))
+            .get_matches(),
+    );
+    let config = Config::new_from_file_and_opts(opts.kubelet, None);
+    let wasi_config = WasiConfig::new(opts.wasi, config.config_file.as_deref())?;
+
+    // Log, and export traces if configured to, until krustlet exits
+    let _telemetry = kubelet::telemetry::init(&config)?;
+
+    let kubeconfig = kubelet::bootstrap(&config, &config.bootstrap_file, notify_bootstrap).await?;
+
+    let store = make_store(&config)?;
+
+    let default_runtime_class = std::env::var("KRUSTLET_DEFAULT_RUNTIME_CLASS")
+        .unwrap_or_else(|_| DEFAULT_RUNTIME_CLASS.to_owned());
+    if !["wasi", "wascc", "process"].contains(&default_runtime_class.as_str()) {
+        anyhow::bail!(
+            "unknown default runtime class {}, expected wasi, wascc or process",
+            default_runtime_class
+        );
+    }
+    let registry = ProviderRegistry::new()
+        .with_provider(
+            "wasi",
+            WasiProvider::new(store.clone(), &config, &wasi_config, kubeconfig.clone()).await?,
+        )
+        .with_provider(
+            "wascc",
+            WasccProvider::new(store.clone(), &config, kubeconfig.clone()).await?,
+        )
+        .with_provider(
+            "process",
+            ProcessProvider::new(store, &config, kubeconfig.clone()).await?,
+        )
+        .with_default(&default_runtime_class);
+    let kubelet = Kubelet::new(registry, kubeconfig, config).await?;
+    kubelet.start().await
+}
+
+fn make_store(config: &Config) -> anyhow::Result<Arc<dyn kubelet::store::Store + Send + Sync>> {
+    let client = oci_distribution::Client::from_source(config);
+    let mut store_path = config.data_dir.join(".oci");
+    store_path.push("modules");
+    let mut file_store = match config.module_cache_size_limit {
+        Some(size_limit) => FileStore::with_size_limit(client, &store_path, size_limit),
+        None => FileStore::new(client, &store_path),
+    };
+    if !config.image_verification_keys.is_empty() {
+        let verifier = CosignVerifier::from_key_files(
+            oci_distribution::Client::from_source(config),
+            &config.image_verification_keys,
+        )?;
+        file_store = file_store.with_verifier(Arc::new(verifier));
+    }
+    let file_store = Arc::new(file_store);
+
+    let store: Arc<dyn kubelet::store::Store + Send + Sync> = if config.allow_local_modules {
+        file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {}))
+    } else {
+        file_store
+    };
+    Ok(store)
+}
+
+fn notify_bootstrap(message: String) {
+    println!("BOOTSTRAP: {}", message);
+}
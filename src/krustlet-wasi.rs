@@ -1,39 +1,72 @@
 use kubelet::config::Config;
 use kubelet::store::composite::ComposableStore;
 use kubelet::store::oci::FileStore;
+use kubelet::store::signature::CosignVerifier;
 use kubelet::Kubelet;
 use std::sync::Arc;
-use wasi_provider::WasiProvider;
+use structopt::StructOpt;
+use wasi_provider::{WasiConfig, WasiOpts, WasiProvider};
+
+#[cfg(feature = "profiling")]
+#[global_allocator]
+static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+/// The kubelet's flags along with the WASI provider's.
+#[derive(StructOpt)]
+struct Opts {
+    #[structopt(flatten)]
+    kubelet: kubelet::config::Opts,
+    #[structopt(flatten)]
+    wasi: WasiOpts,
+}
 
 #[tokio::main(threaded_scheduler)]
 async fn main() -> anyhow::Result<()> {
     // The provider is responsible for all the "back end" logic. If you are creating
     // a new Kubelet, all you need to implement is a provider.
-    let config = Config::new_from_file_and_flags(env!("CARGO_PKG_VERSION"), None);
+    let opts = Opts::from_clap(
+        &Opts::clap()
+            .version(env!("CARGO_PKG_VERSION"))
+            .get_matches(),
+    );
+    let config = Config::new_from_file_and_opts(opts.kubelet, None);
+    let wasi_config = WasiConfig::new(opts.wasi, config.config_file.as_deref())?;
 
-    // Initialize the logger
-    env_logger::init();
+    // Log, and export traces if configured to, until krustlet exits
+    let _telemetry = kubelet::telemetry::init(&config)?;
 
     let kubeconfig = kubelet::bootstrap(&config, &config.bootstrap_file, notify_bootstrap).await?;
 
-    let store = make_store(&config);
+    let store = make_store(&config)?;
 
-    let provider = WasiProvider::new(store, &config, kubeconfig.clone()).await?;
+    let provider = WasiProvider::new(store, &config, &wasi_config, kubeconfig.clone()).await?;
     let kubelet = Kubelet::new(provider, kubeconfig, config).await?;
     kubelet.start().await
 }
 
-fn make_store(config: &Config) -> Arc<dyn kubelet::store::Store + Send + Sync> {
+fn make_store(config: &Config) -> anyhow::Result<Arc<dyn kubelet::store::Store + Send + Sync>> {
     let client = oci_distribution::Client::from_source(config);
     let mut store_path = config.data_dir.join(".oci");
     store_path.push("modules");
-    let file_store = Arc::new(FileStore::new(client, &store_path));
+    let mut file_store = match config.module_cache_size_limit {
+        Some(size_limit) => FileStore::with_size_limit(client, &store_path, size_limit),
+        None => FileStore::new(client, &store_path),
+    };
+    if !config.image_verification_keys.is_empty() {
+        let verifier = CosignVerifier::from_key_files(
+            oci_distribution::Client::from_source(config),
+            &config.image_verification_keys,
+        )?;
+        file_store = file_store.with_verifier(Arc::new(verifier));
+    }
+    let file_store = Arc::new(file_store);
 
-    if config.allow_local_modules {
+    let store: Arc<dyn kubelet::store::Store + Send + Sync> = if config.allow_local_modules {
         file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {}))
     } else {
         file_store
-    }
+    };
+    Ok(store)
 }
 
 fn notify_bootstrap(message: String) {
@@ -273,6 +273,8 @@ fn launch_kubelet(
     let private_key = config_file_path_str(format!("{}.key", name));
     let kubeconfig = config_file_path_str(format!("kubeconfig-{}", kubeconfig_suffix));
     let port_arg = format!("{}", kubelet_port);
+    // The integration tests mount host path volumes from temporary directories
+    let allowed_host_paths = std::env::temp_dir().to_string_lossy().into_owned();
 
     let repo_root = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     let bin_path = repo_root.join("target/debug").join(name);
@@ -291,6 +293,8 @@ fn launch_kubelet(
             &private_key,
             "--x-allow-local-modules",
             "true",
+            "--allowed-host-paths",
+            &allowed_host_paths,
         ])
         .env("KUBECONFIG", kubeconfig)
         .env(